@@ -1 +1,2 @@
 #importif camera projective camera/projective.wgsl
+#importif camera probe camera/probe.wgsl
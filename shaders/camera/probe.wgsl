@@ -0,0 +1,68 @@
+#import /ray.wgsl
+#import /util/distr.wgsl
+#import /film.wgsl
+
+// Reflection probes for real-time engines, one row of the film per probe. Cube maps are laid out
+// as horizontal strips of +X, -X, +Y, -Y, +Z, -Z faces with the usual orientation of each face;
+// planar captures are a single 90 degree view along `direction`.
+
+@group(1) @binding(18)
+var<storage, read> probe_data: Probes;
+
+struct Probes {
+    // zero for cube maps
+    direction: vec4f,
+    points: array<vec4f>,
+}
+
+struct CameraSample {
+    ray: Ray,
+    // fraction of light reaching the film along the ray
+    weight: f32,
+}
+
+fn camera_is_dispersive() -> bool {
+    return false;
+}
+
+fn camera_is_planar() -> bool {
+    return any(probe_data.direction.xyz != vec3f());
+}
+
+// footprint of a pixel at the center of a face
+fn camera_ray_cone() -> RayCone {
+    let face_size = film_size().y / arrayLength(&probe_data.points);
+    return RayCone(0, 2 / f32(face_size));
+}
+
+fn camera_sample_ray(film_ndc: vec2f, lambda: f32) -> CameraSample {
+    let count = arrayLength(&probe_data.points);
+    let faces = select(6u, 1u, camera_is_planar());
+
+    // position in units of faces, with y pointing down
+    let p = vec2f(film_ndc.x + 1, 1 - film_ndc.y) / 2 * vec2f(f32(faces), f32(count));
+    let cell = min(vec2u(max(floor(p), vec2f())), vec2u(faces - 1, count - 1));
+    let uv = 2 * (p - vec2f(cell)) - 1;
+
+    var d: vec3f;
+    if camera_is_planar() {
+        let forward = normalize(probe_data.direction.xyz);
+        let up_hint = select(vec3f(0, 1, 0), vec3f(0, 0, 1), abs(forward.y) > 0.999);
+        let right = normalize(cross(forward, up_hint));
+        let up = cross(right, forward);
+        d = forward + uv.x * right - uv.y * up;
+    } else {
+        switch cell.x {
+            case 0u { d = vec3f(1, -uv.y, -uv.x); }
+            case 1u { d = vec3f(-1, -uv.y, uv.x); }
+            case 2u { d = vec3f(uv.x, 1, uv.y); }
+            case 3u { d = vec3f(uv.x, -1, -uv.y); }
+            case 4u { d = vec3f(uv.x, -uv.y, 1); }
+            default { d = vec3f(-uv.x, -uv.y, -1); }
+        }
+    }
+
+    let time = sample_1d();
+    let ray = Ray(probe_data.points[cell.y].xyz, normalize(d), time);
+    return CameraSample(ray, 1);
+}
@@ -1,5 +1,6 @@
 #import /transform.wgsl
 #import /util/distr.wgsl
+#import /film.wgsl
 
 @group(1) @binding(16)
 var<storage, read> camera_data: ProjectiveCamera;
@@ -10,9 +11,48 @@ struct ProjectiveCamera {
     lens_radius: f32,
     focal_distance: f32,
     orthographic: u32,
+    distortion: f32,
+    chromatic_aberration: f32,
+    vignetting: u32,
+    // whether the camera follows `motion` from camera to world space while the shutter is open
+    moving: u32,
+    // pixels from corner to corner of the frame, not counting the overscan border
+    frame_diagonal: f32,
+    motion: AnimatedTransform,
 }
 
-fn camera_sample_ray(film_ndc: vec2f) -> Ray {
+struct CameraSample {
+    ray: Ray,
+    // fraction of light reaching the film along the ray
+    weight: f32,
+}
+
+// each wavelength needs its own ray when there is chromatic aberration
+fn camera_is_dispersive() -> bool {
+    return camera_data.chromatic_aberration != 0;
+}
+
+// radial distortion with the frame's corners at radius 1; the magnification differs by
+// chromatic_aberration between 650nm and 450nm
+fn camera_lens_distort(film_ndc: vec2f, lambda: f32) -> vec2f {
+    let n = film_ndc * vec2f(film_size()) / camera_data.frame_diagonal;
+    let r2 = dot(n, n);
+    let ca = 1 + camera_data.chromatic_aberration * (lambda - 550) / 200;
+    return film_ndc * (1 + camera_data.distortion * r2) * ca;
+}
+
+// footprint of a pixel at the center of the film
+fn camera_ray_cone() -> RayCone {
+    let p0 = transform_point(camera_data.ndc_to_camera, vec3f());
+    let p1 = transform_point(camera_data.ndc_to_camera, vec3f(0, 2 / f32(film_size().y), 0));
+    if camera_data.orthographic != 0 {
+        return RayCone(length(p1 - p0), 0);
+    }
+    return RayCone(0, length(normalize(p1) - normalize(p0)));
+}
+
+fn camera_sample_ray(film_ndc_: vec2f, lambda: f32) -> CameraSample {
+    let film_ndc = camera_lens_distort(film_ndc_, lambda);
     let projected = transform_point(camera_data.ndc_to_camera, vec3(film_ndc, 0));
     let time = sample_1d();
     var ray: Ray;
@@ -22,12 +62,28 @@ fn camera_sample_ray(film_ndc: vec2f) -> Ray {
         ray = Ray(vec3f(), normalize(projected), time);
     }
 
+    var weight = 1.0;
+    if camera_data.vignetting != 0 && camera_data.orthographic == 0 {
+        // natural vignetting: irradiance on the film falls off with cos^4 of the angle to the axis
+        let cos2 = ray.d.z * ray.d.z;
+        weight = cos2 * cos2;
+    }
+
     if camera_data.lens_radius > 0 {
         let lens_sample = camera_data.lens_radius * sample_uniform_disk(sample_2d());
 
         let focal_t = camera_data.focal_distance / ray.d.z;
         let focal_p = ray.o + ray.d * focal_t;
 
+        if camera_data.vignetting != 0 {
+            // mechanical vignetting: a lens barrel one aperture diameter in front of the lens
+            // clips off-axis bundles into a cat's eye shape
+            let barrel = lens_sample + ray.d.xy / ray.d.z * (2 * camera_data.lens_radius);
+            if length(barrel) > camera_data.lens_radius {
+                weight = 0;
+            }
+        }
+
         // note: pbr-book simply sets the ray origin to the lens position instead of offsetting it.
         //       IMO this doesn't make any sense for orthographic projections: as the lens
         //       radius goes to zero, this approaches a pinhole perspective projection.
@@ -39,5 +95,8 @@ fn camera_sample_ray(film_ndc: vec2f) -> Ray {
         ray.d = normalize(focal_p - ray.o);
     }
 
-    return transform_ray_inv(camera_data.world_to_camera, ray);
+    if camera_data.moving != 0 {
+        return CameraSample(transform_ray(animated_transform_at(camera_data.motion, time), ray), weight);
+    }
+    return CameraSample(transform_ray_inv(camera_data.world_to_camera, ray), weight);
 }
@@ -0,0 +1,24 @@
+// Splats into small trees the way the guided integrator trains, timed at startup to pick between
+// float atomics and compare-exchange loops for the additions
+#import /integrator/guided/atomics.wgsl
+
+@group(0) @binding(0)
+var<storage, read_write> BSP_STATS: array<BspStats>;
+@group(0) @binding(1)
+var<storage, read_write> DIR_TREE_TRAIN: array<array<DirTreeNodeAtomic, 4>>;
+
+const SPLATS = 16u;
+
+@compute @workgroup_size(64)
+fn main(@builtin(global_invocation_id) id: vec3u) {
+    var state = id.x;
+    for (var i = 0u; i < SPLATS; i++) {
+        state = state * 747796405u + 2891336453u;
+        let node = (state >> 8) % arrayLength(&BSP_STATS);
+        for (var stat = 0u; stat < 9; stat++) {
+            bsp_stats_add(node, stat, 0.25);
+        }
+        let dir_node = (state >> 4) % arrayLength(&DIR_TREE_TRAIN);
+        dir_tree_add_flux(dir_node, state >> 30, 0.25);
+    }
+}
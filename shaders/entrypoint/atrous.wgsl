@@ -0,0 +1,101 @@
+// Edge-avoiding à-trous wavelet filter of the film for previews, in the style of SVGF. The color is
+// divided by the albedo so that textures survive the blur, filtered with a 5x5 kernel spread
+// further apart each pass while the normals, distances and variance of luminance keep it from
+// crossing edges, then multiplied by the albedo again.
+
+@group(0) @binding(0)
+var mean_texture: texture_storage_2d<rgba32float, read_write>;
+@group(0) @binding(1)
+var variance_texture: texture_storage_2d<rgba32float, read_write>;
+// albedo, and normal and distance, as written by the film
+@group(0) @binding(2)
+var feature_texture: texture_storage_2d_array<rgba32float, read_write>;
+// color without albedo and its variance of luminance, ping-ponged between passes
+@group(0) @binding(3)
+var src_texture: texture_storage_2d<rgba32float, read_write>;
+@group(0) @binding(4)
+var dst_texture: texture_storage_2d<rgba32float, read_write>;
+
+const MODE_PREPARE = 0u;
+const MODE_FILTER = 1u;
+// the last filter pass, which puts the albedo back
+const MODE_FINISH = 2u;
+
+struct Immediates {
+    mode: u32,
+    // distance between the taps of the kernel in pixels
+    step: u32,
+}
+
+var<immediate> imm: Immediates;
+
+const SIGMA_LUMINANCE = 4.0;
+const SIGMA_DISTANCE = 0.02;
+const NORMAL_POWER = 64.0;
+
+// B3 spline weights by distance in taps
+fn _atrous_kernel(i: i32) -> f32 {
+    return select(select(0.0625, 0.25, i == 1), 0.375, i == 0);
+}
+
+// keeps dark and black surfaces from blowing up
+fn _atrous_albedo(px: vec2i) -> vec3f {
+    return max(textureLoad(feature_texture, px, 0).xyz, vec3f(0.01));
+}
+
+@compute
+@workgroup_size(8, 4)
+fn main(@builtin(global_invocation_id) id: vec3u) {
+    let size = vec2i(textureDimensions(mean_texture));
+    let px = vec2i(id.xy);
+    if any(px >= size) {
+        return;
+    }
+
+    if imm.mode == MODE_PREPARE {
+        let mean = textureLoad(mean_texture, px);
+        let s = textureLoad(variance_texture, px).y;
+        // variance of the mean, which is unknown for a single sample
+        var variance = 0.0;
+        if mean.w > 1 {
+            variance = s / (mean.w - 1) / mean.w;
+        }
+        let albedo = _atrous_albedo(px);
+        textureStore(dst_texture, px, vec4f(mean.xyz / albedo, variance / (albedo.y * albedo.y)));
+        return;
+    }
+
+    let center = textureLoad(src_texture, px);
+    let geometry = textureLoad(feature_texture, px, 1);
+    let luminance_scale = SIGMA_LUMINANCE * sqrt(max(center.w, 0.0)) + 1e-6;
+    let distance_scale = SIGMA_DISTANCE * geometry.w * f32(imm.step) + 1e-6;
+
+    var sum = vec3f();
+    var variance = 0.0;
+    var weight_sum = 0.0;
+    for (var dy = -2; dy <= 2; dy++) {
+        for (var dx = -2; dx <= 2; dx++) {
+            let q = px + vec2i(dx, dy) * i32(imm.step);
+            if any(q < vec2i()) || any(q >= size) {
+                continue;
+            }
+            let tap = textureLoad(src_texture, q);
+            var w = _atrous_kernel(abs(dx)) * _atrous_kernel(abs(dy));
+            if dx != 0 || dy != 0 {
+                let other = textureLoad(feature_texture, q, 1);
+                w *= pow(max(dot(geometry.xyz, other.xyz), 0.0), NORMAL_POWER);
+                w *= exp(-abs(geometry.w - other.w) / distance_scale);
+                w *= exp(-abs(center.y - tap.y) / luminance_scale);
+            }
+            sum += w * tap.xyz;
+            variance += w * w * tap.w;
+            weight_sum += w;
+        }
+    }
+
+    var result = vec4f(sum / weight_sum, variance / (weight_sum * weight_sum));
+    if imm.mode == MODE_FINISH {
+        result = vec4f(result.xyz * _atrous_albedo(px), textureLoad(mean_texture, px).w);
+    }
+    textureStore(dst_texture, px, result);
+}
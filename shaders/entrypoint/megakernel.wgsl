@@ -1,4 +1,5 @@
 #import /scene.wgsl
+#import /stats.wgsl
 #import /sampler/meta.wgsl
 #import /camera.wgsl
 #import /film.wgsl
@@ -6,7 +7,18 @@
 #import /integrator/meta.wgsl
 
 struct Immediates {
-    sample_number: u32
+    sample_number: u32,
+    // pixel rectangle covered by the dispatch
+    min_x: u32,
+    min_y: u32,
+    max_x: u32,
+    max_y: u32,
+    // longest path, or ~0 for the integrator's default
+    max_depth: u32,
+    // most diffuse, glossy and specular bounces, ~0 for no limit
+    max_diffuse_bounces: u32,
+    max_glossy_bounces: u32,
+    max_specular_bounces: u32,
 }
 
 var<immediate> imm: Immediates;
@@ -16,21 +28,31 @@ var<immediate> imm: Immediates;
 fn main(
     @builtin(global_invocation_id) id: vec3<u32>
 ) {
-    if any(id.xy >= film_size()) {
+    let px = id.xy + vec2u(imm.min_x, imm.min_y);
+    if any(px >= vec2u(imm.max_x, imm.max_y)) {
         return;
     }
 
-    sample_init(id.xy, imm.sample_number);
+    sample_init(px, imm.sample_number);
 
-    let wavelengths = film_wavelengths_sample();
+    var wavelengths = film_wavelengths_sample();
+    if camera_is_dispersive() {
+        wavelengths.l = vec4f(wavelengths.l.x);
+    }
     let fs = filter_sample();
-    var film_position_norm = (vec2f(id.xy) + fs.p + 0.5) / vec2f(film_size());
+    var film_position_norm = (vec2f(px) + fs.p + 0.5) / vec2f(film_size());
     film_position_norm.y = 1 - film_position_norm.y;
     let film_position_ndc = 2 * film_position_norm - 1;
 
-    let ray = camera_sample_ray(film_position_ndc);
+    let camera_sample = camera_sample_ray(film_position_ndc, wavelengths.l.x);
 
-    let radiance = integrate_ray(wavelengths, ray);
+    var path = PathResult(vec4f(), 0, film_first_hit_none());
+    if camera_sample.weight > 0 {
+        path = integrate_ray(wavelengths, camera_sample.ray, camera_ray_cone());
+    }
 
-    film_add_sample(id.xy, wavelengths, radiance / film_wavelengths_pdf(wavelengths));
+    let weight = camera_sample.weight * fs.f / fs.pdf;
+    let radiance = weight * path.radiance / film_wavelengths_pdf(wavelengths);
+    film_add_sample(px, fs.p, wavelengths, radiance, path.length, path.first);
+    stats_flush();
 }
@@ -0,0 +1,31 @@
+#import /scene.wgsl
+#import /sampler/meta.wgsl
+#import /camera.wgsl
+#import /film.wgsl
+#import /light.wgsl
+
+// Shading normals of what the camera ray through the center of each pixel hits, mapped to colors
+// with w set where there was a hit, for spotting bad transforms
+@group(2) @binding(0)
+var normal_texture: texture_storage_2d<rgba32float, read_write>;
+
+@compute
+@workgroup_size(8, 4)
+fn main(@builtin(global_invocation_id) id: vec3u) {
+    let pixel = id.xy;
+    if any(pixel >= film_size()) {
+        return;
+    }
+    sample_init(pixel, 0);
+
+    var film_position_norm = (vec2f(pixel) + 0.5) / vec2f(film_size());
+    film_position_norm.y = 1 - film_position_norm.y;
+    let camera_sample = camera_sample_ray(2 * film_position_norm - 1, 550);
+
+    let result = scene_raycast(camera_sample.ray, 1e30);
+    var color = vec4f();
+    if result.hit && camera_sample.weight > 0 {
+        color = vec4f(result.n * 0.5 + 0.5, 1);
+    }
+    textureStore(normal_texture, pixel, color);
+}
@@ -0,0 +1,46 @@
+#import /scene.wgsl
+#import /sampler/meta.wgsl
+#import /camera.wgsl
+#import /film.wgsl
+#import /light.wgsl
+
+// What the camera ray through the center of a pixel hits, for inspecting the scene
+struct PickResult {
+    hit: u32,
+    primitive: u32,
+    instance: u32,
+    object: u32,
+    material: u32,
+    light: u32,
+    t: f32,
+    p: vec3f,
+    n: vec3f,
+    uv: vec2f,
+}
+
+@group(2) @binding(0)
+var<storage, read_write> pick: PickResult;
+
+var<immediate> pixel: vec2u;
+
+@compute
+@workgroup_size(1)
+fn main() {
+    sample_init(pixel, 0);
+
+    var film_position_norm = (vec2f(pixel) + 0.5) / vec2f(film_size());
+    film_position_norm.y = 1 - film_position_norm.y;
+    let camera_sample = camera_sample_ray(2 * film_position_norm - 1, 550);
+
+    let result = scene_raycast(camera_sample.ray, 1e30);
+    pick.hit = u32(result.hit && camera_sample.weight > 0);
+    pick.primitive = result.ids.primitive;
+    pick.instance = result.ids.instance;
+    pick.object = result.ids.object;
+    pick.material = result.material.id;
+    pick.light = result.light.id;
+    pick.t = result.t;
+    pick.p = result.p;
+    pick.n = result.n;
+    pick.uv = result.uv;
+}
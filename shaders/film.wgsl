@@ -1,19 +1,119 @@
 #import /sampler/meta.wgsl
 #import /spectrum.wgsl
+#import /util/table_sample.wgsl
 
 @group(1) @binding(0)
 var mean_texture: texture_storage_2d<rgba32float, read_write>;
 @group(1) @binding(1)
 var variance_texture: texture_storage_2d<rgba32float, read_write>;
+// x: mean path length, y and z: material and instance of the first surface hit in the first sample
+@group(1) @binding(2)
+var aov_texture: texture_storage_2d<rgba32float, read_write>;
+// mean and sample count of each group of samples for median-of-means, with samples assigned to
+// groups in turn
+@group(1) @binding(3)
+var group_texture: texture_storage_2d_array<rgba32float, read_write>;
+// mean XYZ albedo, and normal and distance of the first surface hit, for the denoisers
+@group(1) @binding(4)
+var feature_texture: texture_storage_2d_array<rgba32float, read_write>;
+// coverage of the objects and then the materials of the first surface hit, as pairs of id and
+// mean coverage, two to a texel and two texels each
+@group(1) @binding(5)
+var matte_texture: texture_storage_2d_array<rgba32float, read_write>;
+@group(1) @binding(17)
+var<storage> film_params: FilmParams;
+@group(1) @binding(20)
+var<storage, read_write> sample_records: SampleRecords;
+
+struct FilmParams {
+    wavelength_min: f32,
+    wavelength_max: f32,
+    // sample the range uniformly and record the mean spectral radiance as grayscale
+    band: u32,
+    filter_x: FilterTable,
+    filter_y: FilterTable,
+    // distribution following the scene's light spectra, used in place of the default one
+    use_wavelength_table: u32,
+    wavelength_table: TableSampler1d,
+    // write every sample to `sample_records`
+    record_samples: u32,
+    // layers of `group_texture` in use, 0 without median-of-means
+    groups: u32,
+    // write the first hit's albedo and normal to `feature_texture`
+    features: u32,
+    // write the coverage of objects and materials to `matte_texture`
+    mattes: u32,
+    // most luminance of a sample, 0 for no limit
+    clamp: f32,
+}
+
+// samples of the current pass, overwriting the oldest ones if there are more than fit
+struct SampleRecords {
+    count: atomic<u32>,
+    records: array<SampleRecord>,
+}
+
+struct SampleRecord {
+    px: vec2u,
+    // from the pixel center
+    offset: vec2f,
+    xyz: vec3f,
+    path_length: u32,
+}
+
+// What a path saw at its first surface hit, for the denoisers and AOVs
+struct FirstHit {
+    // reflectance, estimated from the direction sampled there
+    albedo: vec4f,
+    // shading normal, facing the camera
+    normal: vec3f,
+    // along the ray, 0 if nothing was hit
+    distance: f32,
+    material: u32,
+    // ~0 if nothing was hit or the surface is not instanced
+    instance: u32,
+}
+
+fn film_first_hit_none() -> FirstHit {
+    return FirstHit(vec4f(), vec3f(), 0, ~0u, ~0u);
+}
+
+// pixel filter along one axis, with values normalized to integrate to 1
+struct FilterTable {
+    table: TableSampler1d,
+    values: u32,
+}
 
 fn film_wavelengths_sample() -> Wavelengths {
     let first = sample_1d();
     let stratified = fract(vec4f(first, first + 0.25, first + 0.5, first + 0.75));
+    if film_params.band != 0 {
+        return Wavelengths(mix(vec4f(film_params.wavelength_min), vec4f(film_params.wavelength_max), stratified));
+    }
+    if film_params.use_wavelength_table != 0 {
+        return Wavelengths(vec4f(
+            table_1d_sample(film_params.wavelength_table, stratified.x).value,
+            table_1d_sample(film_params.wavelength_table, stratified.y).value,
+            table_1d_sample(film_params.wavelength_table, stratified.z).value,
+            table_1d_sample(film_params.wavelength_table, stratified.w).value,
+        ));
+    }
     let lambda = 538 + atanh(1.8279163271 * stratified - 0.8569106254) / 0.0072;
     return Wavelengths(lambda);
 }
 
 fn film_wavelengths_pdf(wl: Wavelengths) -> vec4f {
+    if film_params.band != 0 {
+        return vec4f(1 / (film_params.wavelength_max - film_params.wavelength_min));
+    }
+    if film_params.use_wavelength_table != 0 {
+        return vec4f(
+            table_1d_pdf(film_params.wavelength_table, wl.l.x).pdf,
+            table_1d_pdf(film_params.wavelength_table, wl.l.y).pdf,
+            table_1d_pdf(film_params.wavelength_table, wl.l.z).pdf,
+            table_1d_pdf(film_params.wavelength_table, wl.l.w).pdf,
+        );
+    }
     let d = cosh(0.0072 * (wl.l - 538));
     return 0.00393891114869 / (d * d);
 }
@@ -22,17 +122,72 @@ fn film_size() -> vec2u {
     return textureDimensions(mean_texture);
 }
 
-fn film_add_sample(px: vec2u, wl: Wavelengths, radiance: vec4f) {
+fn film_sample_xyz(wl: Wavelengths, radiance: vec4f) -> vec3f {
+    if film_params.band != 0 {
+        let range = film_params.wavelength_max - film_params.wavelength_min;
+        // scaled by the D65 white point so that it comes out gray
+        return dot(radiance, vec4f(0.25)) / range * vec3f(0.9505, 1, 1.089);
+    }
+    return vec3f(
+        dot(spectrum_sample(SPECTRUM_CIE_X, wl) * radiance, vec4f(0.25)),
+        dot(spectrum_sample(SPECTRUM_CIE_Y, wl) * radiance, vec4f(0.25)),
+        dot(spectrum_sample(SPECTRUM_CIE_Z, wl) * radiance, vec4f(0.25)),
+    );
+}
+
+// relative to a white surface under D65, so that gray albedos come out gray
+fn film_albedo_xyz(wl: Wavelengths, albedo: vec4f) -> vec3f {
+    let white = spectrum_sample(SPECTRUM_D65_1NIT, wl) / film_wavelengths_pdf(wl);
+    return film_sample_xyz(wl, albedo * white) / film_sample_xyz(wl, white).y;
+}
+
+// Updates the coverage of the ids in two layers of `matte_texture` with a sample that saw `id`,
+// if `hit`. The first free slot goes to a new id, and once all are taken other ids are left out.
+fn _film_add_matte(px: vec2u, layer: u32, id: u32, hit: bool, samples: f32) {
+    let a = textureLoad(matte_texture, px, layer);
+    let b = textureLoad(matte_texture, px, layer + 1);
+    var ids = vec4u(bitcast<u32>(a.x), bitcast<u32>(a.z), bitcast<u32>(b.x), bitcast<u32>(b.z));
+    var coverage = vec4f(a.y, a.w, b.y, b.w);
+
+    var seen = ids == vec4u(id) & coverage > vec4f() & vec4(hit);
+    if hit && !any(seen) {
+        for (var i = 0; i < 4; i++) {
+            if coverage[i] == 0 {
+                ids[i] = id;
+                seen[i] = true;
+                break;
+            }
+        }
+    }
+    // a first sample clears out ids left from before a restart
+    coverage += (select(vec4f(), vec4f(1), seen) - coverage) / samples;
+
+    let fid = bitcast<vec4f>(ids);
+    textureStore(matte_texture, px, layer, vec4f(fid.x, coverage.x, fid.y, coverage.y));
+    textureStore(matte_texture, px, layer + 1, vec4f(fid.z, coverage.z, fid.w, coverage.w));
+}
+
+fn film_add_sample(
+    px: vec2u,
+    offset: vec2f,
+    wl: Wavelengths,
+    radiance: vec4f,
+    path_length: u32,
+    first: FirstHit,
+) {
     let old = textureLoad(mean_texture, px);
     var s = textureLoad(variance_texture, px).xyz;
     var mean = old.xyz;
     let samples = old.w + 1;
 
-    let x = vec3f(
-        dot(spectrum_sample(SPECTRUM_CIE_X, wl) * radiance, vec4f(0.25)),
-        dot(spectrum_sample(SPECTRUM_CIE_Y, wl) * radiance, vec4f(0.25)),
-        dot(spectrum_sample(SPECTRUM_CIE_Z, wl) * radiance, vec4f(0.25)),
-    );
+    var x = film_sample_xyz(wl, radiance);
+    if film_params.clamp > 0 && x.y > film_params.clamp {
+        x *= film_params.clamp / x.y;
+    }
+    if film_params.record_samples != 0 {
+        let i = atomicAdd(&sample_records.count, 1u) % arrayLength(&sample_records.records);
+        sample_records.records[i] = SampleRecord(px, offset, x, path_length);
+    }
 
     let delta = x - mean;
     mean += delta / samples;
@@ -41,4 +196,39 @@ fn film_add_sample(px: vec2u, wl: Wavelengths, radiance: vec4f) {
 
     textureStore(mean_texture, px, vec4f(mean, samples));
     textureStore(variance_texture, px, vec4f(s, 0));
+
+    if film_params.groups != 0 {
+        let group = u32(old.w) % film_params.groups;
+        var g = textureLoad(group_texture, px, group);
+        // the first round of samples starts each group over, so clearing the mean clears them too
+        if u32(old.w) < film_params.groups {
+            g = vec4f();
+        }
+        let n = g.w + 1;
+        textureStore(group_texture, px, group, vec4f(g.xyz + (x - g.xyz) / n, n));
+    }
+
+    var aov = textureLoad(aov_texture, px);
+    aov.x += (f32(path_length) - aov.x) / samples;
+    // ids can't be averaged, so the first sample's are kept
+    if old.w == 0 {
+        aov.y = bitcast<f32>(first.material);
+        aov.z = bitcast<f32>(first.instance);
+    }
+    textureStore(aov_texture, px, aov);
+
+    if film_params.mattes != 0 {
+        let hit = first.material != ~0u;
+        _film_add_matte(px, 0u, first.instance, hit, samples);
+        _film_add_matte(px, 2u, first.material, hit, samples);
+    }
+
+    if film_params.features != 0 {
+        let old_albedo = textureLoad(feature_texture, px, 0).xyz;
+        let old_geometry = textureLoad(feature_texture, px, 1);
+        let new_albedo = old_albedo + (film_albedo_xyz(wl, first.albedo) - old_albedo) / samples;
+        let geometry = vec4f(first.normal, first.distance);
+        textureStore(feature_texture, px, 0, vec4f(new_albedo, 0));
+        textureStore(feature_texture, px, 1, old_geometry + (geometry - old_geometry) / samples);
+    }
 }
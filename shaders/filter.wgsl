@@ -1,4 +1,6 @@
 #import /sampler/meta.wgsl
+#import /film.wgsl
+#import /util/table_sample.wgsl
 
 struct FilterSample {
     p: vec2f,
@@ -6,8 +8,13 @@ struct FilterSample {
     pdf: f32,
 }
 
+// Offset from the pixel center sampled proportional to the absolute value of the filter. The
+// sample's weight is f / pdf, which is negative where the filter is.
 fn filter_sample() -> FilterSample {
     let u = sample_pixel();
-    // box filter
-    return FilterSample(u - 0.5, 1, 1);
+    let x = table_1d_sample(film_params.filter_x.table, u.x);
+    let y = table_1d_sample(film_params.filter_y.table, u.y);
+    let f = FLOAT_DATA[film_params.filter_x.values + x.index]
+        * FLOAT_DATA[film_params.filter_y.values + y.index];
+    return FilterSample(vec2f(x.value, y.value), f, x.pdf * y.pdf);
 }
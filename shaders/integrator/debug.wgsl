@@ -0,0 +1,48 @@
+#import /scene.wgsl
+#import /ray.wgsl
+#import /util/misc.wgsl
+#import /light.wgsl
+#import /spectrum.wgsl
+
+// Flat shading with a stable random color per primitive, object or instance, for spotting
+// instancing and BVH grouping problems. Geometry which isn't instanced is gray in the object and
+// instance modes.
+
+const DEBUG_PRIMITIVE = 0;
+const DEBUG_OBJECT = 1;
+const DEBUG_INSTANCE = 2;
+
+fn integrate_ray(wl: Wavelengths, ray: Ray, cone: RayCone) -> PathResult {
+    let result = scene_raycast(ray, FLOAT_MAX);
+    if !result.hit {
+        return PathResult(vec4f(), 0, film_first_hit_none());
+    }
+
+    var id: u32;
+    switch DEBUG_MODE {
+        case DEBUG_OBJECT {
+            id = result.ids.object;
+        }
+        case DEBUG_INSTANCE {
+            id = result.ids.instance;
+        }
+        default {
+            id = result.ids.primitive;
+        }
+    }
+
+    var rgb = vec3f(0.5);
+    if id != ~0u {
+        let h = hash_3d(vec3u(id, 0x9e3779b9u, DEBUG_MODE));
+        rgb = 0.15 + 0.85 * vec3f(bits_to_f32(h.x), bits_to_f32(h.y), bits_to_f32(h.z));
+    }
+
+    // darken grazing angles so that shapes stay readable
+    let shade = 0.4 + 0.6 * abs(dot(result.n, ray.d));
+    let radiance = spectrum_rgb_illuminant_sample(RgbIlluminantSpectrum(rgb * shade, SPECTRUM_D65_1NIT), wl);
+    let albedo = spectrum_rgb_illuminant_sample(RgbIlluminantSpectrum(rgb, SPECTRUM_D65_1NIT), wl)
+        / spectrum_sample(SPECTRUM_D65_1NIT, wl);
+    let normal = faceForward(result.n, ray.d, result.n);
+    let first = FirstHit(albedo, normal, result.t, result.material.id, result.ids.instance);
+    return PathResult(radiance, 1, first);
+}
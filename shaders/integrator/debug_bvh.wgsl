@@ -0,0 +1,21 @@
+#import /scene.wgsl
+#import /ray.wgsl
+#import /util/misc.wgsl
+#import /light.wgsl
+#import /spectrum.wgsl
+
+// The number of BVH nodes the camera ray visits as a gray level, one nit per node, which is also
+// saved in false color for spotting the regions that are slow to trace
+fn integrate_ray(wl: Wavelengths, ray: Ray, cone: RayCone) -> PathResult {
+    let before = bvh_node_visits;
+    let result = scene_raycast(ray, FLOAT_MAX);
+    let visits = f32(bvh_node_visits - before);
+
+    let radiance = spectrum_rgb_illuminant_sample(RgbIlluminantSpectrum(vec3f(visits), SPECTRUM_D65_1NIT), wl);
+    var first = film_first_hit_none();
+    if result.hit {
+        let normal = faceForward(result.n, ray.d, result.n);
+        first = FirstHit(vec4f(), normal, result.t, result.material.id, result.ids.instance);
+    }
+    return PathResult(radiance, 1, first);
+}
@@ -0,0 +1,4 @@
+#import debug.wgsl
+
+// random color for each object instance
+const DEBUG_MODE = DEBUG_INSTANCE;
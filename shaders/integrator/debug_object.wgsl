@@ -0,0 +1,4 @@
+#import debug.wgsl
+
+// random color for each instanced object, shared by its instances
+const DEBUG_MODE = DEBUG_OBJECT;
@@ -0,0 +1,4 @@
+#import debug.wgsl
+
+// random color for each primitive
+const DEBUG_MODE = DEBUG_PRIMITIVE;
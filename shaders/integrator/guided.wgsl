@@ -4,10 +4,16 @@
 #import /material.wgsl
 #import /light.wgsl
 #import /light_sampler.wgsl
+#import guided/atomics.wgsl
 
 const MAX_DEPTH = 250;
 const MAX_LPV = 10;
-const PR_BSDF: f32 = 0.5;
+// paths waiting to continue from a split, and the weight window of adrrs relative to the expected
+// contribution at the first vertex, as in "Adjoint-Driven Russian Roulette and Splitting in Light
+// Transport Simulation" (Vorba 2016)
+const MAX_SPLITS = 4;
+const WINDOW_SIZE = 5.0;
+const MIN_SURVIVAL = 0.05;
 
 struct PathVertex {
     pos: vec3f,
@@ -15,6 +21,11 @@ struct PathVertex {
     pos_filter_size: f32,
     radiance: f32,
     prefix_tp: f32,
+    // leaf the direction was sampled in and the terms of the gradient of its bsdf fraction,
+    // which scale with the radiance arriving from the direction
+    node: u32,
+    mis_weight: f32,
+    mis_diff: f32,
 }
 
 const LEAF_SENTINEL: u32 = ~0u;
@@ -24,6 +35,13 @@ struct BspNode {
     left: u32,
     right: u32,
     count: atomic<u32>,
+    // split plane of interior nodes
+    axis: u32,
+    split: f32,
+    // chance of sampling the bsdf rather than the guide in leaves
+    bsdf_fraction: f32,
+    // mean radiance arriving in leaves, by the samples the guide was trained on
+    radiance: f32,
 }
 
 struct DirTreeNode {
@@ -31,16 +49,25 @@ struct DirTreeNode {
     child: u32,
 }
 
-struct DirTreeNodeAtomic {
-    flux: atomic<f32>,
-    child: u32,
-}
-
 struct BoundingVolume {
     min: vec3f,
+    // whether to use adrrs, in what would be padding
+    adrrs: u32,
     max: vec3f,
 }
 
+// state at the start of a vertex for the other paths of a split, which trace the same ray again
+struct PathSplit {
+    ray: Ray,
+    cone: RayCone,
+    throughput: vec4f,
+    depth: i32,
+    bounces: vec3u,
+    secondary_terminated: bool,
+    // first vertex recorded by paths after the split
+    pv_i: i32,
+}
+
 @group(2) @binding(0)
 var<storage, read_write> BSP_TREE: array<BspNode>;
 @group(2) @binding(1)
@@ -49,6 +76,8 @@ var<storage> DIR_TREE_GUIDE: array<array<DirTreeNode, 4>>;
 var<storage, read_write> DIR_TREE_TRAIN: array<array<DirTreeNodeAtomic, 4>>;
 @group(2) @binding(3)
 var<storage> BSP_VOLUME: BoundingVolume;
+@group(2) @binding(4)
+var<storage, read_write> BSP_STATS: array<BspStats>;
 
 const POS_STRAT = array(
     vec3f(0, 0, 0),
@@ -63,7 +92,7 @@ const DIR_STRAT = array(
     vec2f(0.75, 0.25),
 );
 
-fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
+fn integrate_ray(wl: Wavelengths, ray_: Ray, cone_: RayCone) -> PathResult {
     var radiance = vec4f();
     var throughput = vec4f(1);
 
@@ -71,155 +100,287 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
     var pv_i = 0;
 
     var ray = ray_;
+    var cone = cone_;
 
     var secondary_terminated = false;
 
+    var first = film_first_hit_none();
+
     var depth = 0;
-    while any(throughput > vec4f()) {
-        let result = scene_raycast(ray, FLOAT_MAX);
+    var bounces = vec3u();
+    var path_length = 0;
+
+    var splits: array<PathSplit, MAX_SPLITS>;
+    var n_splits = 0;
+    // expected contribution of the path at its first vertex, which the window is relative to
+    var reference = 0.0;
+    // the first vertex of a path continuing from a split was already seen by the path it split from
+    var resumed = false;
+
+    loop {
+        while any(throughput > vec4f()) {
+            let ray_in = ray;
+            let cone_in = cone;
+            var result = scene_raycast(ray, FLOAT_MAX);
+
+            if !result.hit {
+                // add infinite lights and finish
+                for (var i = 1u; i < arrayLength(&INFINITE_LIGHTS); i++) {
+                    let emission = inf_light_emission(INFINITE_LIGHTS[i], ray, wl)
+                        * integrator_lighting(depth);
+                    radiance += throughput * emission;
+                    let power = dot(throughput, vec4f(1)) * dot(emission, vec4f(1));
+                    for (var j = 0; j < pv_i; j++) {
+                        path_vertices[j].radiance += power / path_vertices[j].prefix_tp;
+                    }
+                }
+                break;
+            }
+
+            cone.width += cone.spread * result.t;
+            result.cone_width = cone.width;
 
-        if !result.hit {
-            // add infinite lights and finish
-            for (var i = 1u; i < arrayLength(&INFINITE_LIGHTS); i++) {
-                let emission = inf_light_emission(INFINITE_LIGHTS[i], ray, wl);
+            // add light emitted by surface
+            if !resumed {
+                let emission = light_emission(result.light, ray, result, wl)
+                    * integrator_lighting(depth);
                 radiance += throughput * emission;
                 let power = dot(throughput, vec4f(1)) * dot(emission, vec4f(1));
                 for (var j = 0; j < pv_i; j++) {
                     path_vertices[j].radiance += power / path_vertices[j].prefix_tp;
                 }
             }
-            break;
-        }
+            if integrator_lighting_done(depth + 1) {
+                break;
+            }
 
-        // add light emitted by surface
-        {
-            let emission = light_emission(result.light, ray, result, wl);
-            radiance += throughput * emission;
-            let power = dot(throughput, vec4f(1)) * dot(emission, vec4f(1));
-            for (var j = 0; j < pv_i; j++) {
-                path_vertices[j].radiance += power / path_vertices[j].prefix_tp;
+            // enforce termination
+            depth += 1;
+            path_length = max(path_length, depth);
+            if depth > integrator_max_depth(MAX_DEPTH) {
+                break;
             }
-        }
 
-        // enforce termination
-        depth += 1;
-        if depth > MAX_DEPTH {
-            break;
-        }
+            let spatial_node = guide_locate(result.p);
+            let guide = BSP_TREE[spatial_node.node].left;
+            let train = BSP_TREE[spatial_node.node].right;
 
-        let spatial_node = guide_locate(result.p);
-        let guide = BSP_TREE[spatial_node.node].left;
-        let train = BSP_TREE[spatial_node.node].right;
+            // russian roulette and splitting to keep the expected contribution inside the window
+            let expected = dot(throughput, vec4f(0.25)) * BSP_TREE[spatial_node.node].radiance;
+            if ROULETTE && BSP_VOLUME.adrrs != 0 && !resumed && expected > 0 {
+                if reference == 0 {
+                    reference = expected;
+                }
+                let ratio = expected / reference;
+                let lower = 2 / (1 + WINDOW_SIZE);
+                if ratio < lower {
+                    let survival = max(ratio, MIN_SURVIVAL);
+                    if sample_1d() >= survival {
+                        break;
+                    }
+                    throughput /= survival;
+                } else if ratio > lower * WINDOW_SIZE {
+                    let n = min(u32(round(ratio)), u32(MAX_SPLITS - n_splits) + 1);
+                    if n > 1 {
+                        throughput /= f32(n);
+                        for (var i = 1u; i < n; i++) {
+                            splits[n_splits] = PathSplit(
+                                ray_in,
+                                cone_in,
+                                throughput,
+                                depth - 1,
+                                bounces,
+                                secondary_terminated,
+                                pv_i,
+                            );
+                            n_splits++;
+                        }
+                    }
+                }
+            }
+            resumed = false;
 
-        let bsdf = material_evaluate(result.material, result, wl);
+            let bsdf = material_evaluate(result.material, result, wl);
 
-        if !secondary_terminated && bsdf_terminates_secondary_wavelengths(bsdf) {
-            secondary_terminated = true;
-            throughput *= vec4f(4, 0, 0, 0);
-        }
+            if !secondary_terminated && bsdf_terminates_secondary_wavelengths(bsdf) {
+                secondary_terminated = true;
+                throughput *= vec4f(4, 0, 0, 0);
+            }
 
-        var pr_bsdf = PR_BSDF;
-        if bsdf_is_highly_specular(bsdf) || guide == LEAF_SENTINEL {
-            pr_bsdf = 1;
-        }
+            if depth == 1 {
+                first.normal = faceForward(bsdf_normal(bsdf), ray.d, bsdf_normal(bsdf));
+                first.distance = result.t;
+                first.material = result.material.id;
+                first.instance = result.ids.instance;
+            }
 
-        var sample: BsdfSample;
+            var pr_bsdf = BSP_TREE[spatial_node.node].bsdf_fraction;
+            if bsdf_is_highly_specular(bsdf) || guide == LEAF_SENTINEL {
+                pr_bsdf = 1;
+            }
 
-        let u = sample_1d();
-        if u < pr_bsdf {
-            // sample bsdf
-            sample = bsdf_sample(bsdf, -ray.d, vec3f(sample_2d(), sample_1d()));
-            if sample.pdf > 0 {
-                var guide_pdf = 0.0;
-                if !sample.specular && pr_bsdf < 1 {
-                    guide_pdf = guide_pdf(guide, sample.dir);
+            var sample: BsdfSample;
+            var pdf_bsdf = 0.0;
+            var pdf_guide = 0.0;
+
+            let u = sample_1d();
+            if u < pr_bsdf {
+                // sample bsdf
+                sample = bsdf_sample(bsdf, -ray.d, vec3f(sample_2d(), sample_1d()));
+                pdf_bsdf = sample.pdf;
+                if sample.pdf > 0 && !sample.specular && pr_bsdf < 1 {
+                    pdf_guide = guide_pdf(guide, sample.dir);
+                }
+            } else {
+                // sample path guidance
+                sample = guide_sample(guide, vec3f(sample_2d(), sample_1d()));
+                pdf_guide = sample.pdf;
+                if sample.pdf > 0 {
+                    sample.f = bsdf_f(bsdf, -ray.d, sample.dir);
+                    pdf_bsdf = bsdf_pdf(bsdf, -ray.d, sample.dir);
                 }
-                sample.pdf = pr_bsdf * (sample.pdf + guide_pdf);
-            }
-        } else {
-            // sample path guidance
-            sample = guide_sample(guide, vec3f(sample_2d(), sample_1d()));
-            if sample.pdf > 0 {
-                sample.f = bsdf_f(bsdf, -ray.d, sample.dir);
-                sample.pdf = (1 - pr_bsdf) * (sample.pdf + bsdf_pdf(bsdf, -ray.d, sample.dir));
             }
-        }
 
-        if sample.pdf == 0 {
-            break;
-        }
+            if sample.pdf == 0 {
+                break;
+            }
+            bounces[bsdf_event(bsdf, sample.specular)] += 1;
+            if integrator_bounces_exceeded(bounces) {
+                break;
+            }
+            // one-sample MIS with the balance heuristic, which is sampling the mixture
+            sample.pdf = pr_bsdf * pdf_bsdf + (1 - pr_bsdf) * pdf_guide;
 
-        throughput *= sample.f * abs(dot(bsdf_normal(bsdf), sample.dir)) / sample.pdf;
+            // d(pdf)/d(logit of pr_bsdf), relative to the pdf
+            var mis_diff = 0.0;
+            if pr_bsdf < 1 && !sample.specular {
+                mis_diff = (pdf_bsdf - pdf_guide) / sample.pdf * pr_bsdf * (1 - pr_bsdf);
+            }
+            let mis_weight = dot(sample.f, vec4f(0.25))
+                * abs(dot(bsdf_normal(bsdf), sample.dir)) / sample.pdf;
 
-        if all(throughput == vec4f()) {
-            break;
-        }
+            let weight = sample.f * abs(dot(bsdf_normal(bsdf), sample.dir)) / sample.pdf;
+            if depth == 1 {
+                first.albedo = weight;
+            }
+            throughput *= weight;
 
-        if !sample.specular {
-            if pv_i == MAX_LPV {
+            if all(throughput == vec4f()) {
                 break;
             }
-            let duv = equal_area_dir_to_square(sample.dir);
-            path_vertices[pv_i] = PathVertex(
-                result.p,
-                duv,
-                dot(spatial_node.filter_size, vec3f(1)) / 3.0,
-                0,
-                dot(throughput, vec4f(1))
-            );
-            pv_i++;
+
+            if !sample.specular {
+                if pv_i == MAX_LPV {
+                    break;
+                }
+                let duv = equal_area_dir_to_square(sample.dir);
+                path_vertices[pv_i] = PathVertex(
+                    result.p,
+                    duv,
+                    dot(spatial_node.filter_size, vec3f(1)) / 3.0,
+                    0,
+                    dot(throughput, vec4f(1)),
+                    spatial_node.node,
+                    mis_weight,
+                    mis_diff,
+                );
+                pv_i++;
+            }
+
+            // spawn new ray
+            let offset = 10 * EPSILON * (1 + length(result.p));
+            ray.d = sample.dir;
+            ray.o = result.p + ray.d * offset;
         }
 
-        // spawn new ray
-        let offset = 10 * EPSILON * (1 + length(result.p));
-        ray.d = sample.dir;
-        ray.o = result.p + ray.d * offset;
+        // vertices after the latest split have all the radiance they will get, so record them and
+        // carry on with the next path of the split
+        var first_pv = 0;
+        if n_splits > 0 {
+            first_pv = splits[n_splits - 1].pv_i;
+        }
+        for (var i = first_pv; i < pv_i; i++) {
+            guide_record_vertex(path_vertices[i]);
+        }
+        if n_splits == 0 {
+            break;
+        }
+        n_splits--;
+        let split = splits[n_splits];
+        ray = split.ray;
+        cone = split.cone;
+        throughput = split.throughput;
+        depth = split.depth;
+        bounces = split.bounces;
+        secondary_terminated = split.secondary_terminated;
+        pv_i = split.pv_i;
+        resumed = true;
     }
 
-    for (var i = 0; i < pv_i; i++) {
-        let v = path_vertices[i];
-        let pos_jitter = vec3f(sample_2d(), sample_1d());
-        for (var j = 0; j < 4; j++) {
-            let node = guide_locate(v.pos + (fract(pos_jitter + POS_STRAT[j]) - 0.5) * v.pos_filter_size).node;
-            atomicAdd(&BSP_TREE[node].count, 1);
-            let dir_node = BSP_TREE[node].right;
-            let dir_jitter = sample_2d();
-            let dir_filter_size = guide_filter_size(dir_node, v.dir);
-            for (var k = 0; k < 4; k++) {
-                let offset_dir = v.dir + (fract(dir_jitter + DIR_STRAT[k]) - 0.5) * dir_filter_size;
-                guide_splat(dir_node, wrap_equal_area_square(offset_dir), v.radiance / 4);
-            }
+    return PathResult(radiance, u32(path_length), first);
+}
+
+fn guide_record_vertex(v: PathVertex) {
+    if v.mis_diff != 0 && v.radiance > 0 {
+        let contribution = v.radiance * v.mis_weight;
+        bsp_stats_add(v.node, STAT_FRACTION_GRAD, -contribution * v.mis_diff);
+        bsp_stats_add(v.node, STAT_FRACTION_NORM, contribution);
+    }
+    let pos_jitter = vec3f(sample_2d(), sample_1d());
+    for (var j = 0; j < 4; j++) {
+        let pos = v.pos + (fract(pos_jitter + POS_STRAT[j]) - 0.5) * v.pos_filter_size;
+        let spatial = guide_locate(pos);
+        let node = spatial.node;
+        atomicAdd(&BSP_TREE[node].count, 1);
+        guide_record_position(node, (pos - spatial.min) / spatial.filter_size, v.radiance / 4);
+        let dir_node = BSP_TREE[node].right;
+        let dir_jitter = sample_2d();
+        let dir_filter_size = guide_filter_size(dir_node, v.dir);
+        for (var k = 0; k < 4; k++) {
+            let offset_dir = v.dir + (fract(dir_jitter + DIR_STRAT[k]) - 0.5) * dir_filter_size;
+            guide_splat(dir_node, wrap_equal_area_square(offset_dir), v.radiance / 4);
         }
     }
-
-    return radiance;
 }
 
 struct SpatialInfo {
     node: u32,
     filter_size: vec3f,
+    // corner of the leaf's bounds
+    min: vec3f,
 }
 
 fn guide_locate(p_: vec3f) -> SpatialInfo {
-    var size = BSP_VOLUME.max - BSP_VOLUME.min;
-    var p = (p_ - BSP_VOLUME.min) / size;
-    p = clamp(p, vec3f(0), vec3f(1));
+    var min = BSP_VOLUME.min;
+    var max = BSP_VOLUME.max;
+    let p = clamp(p_, min, max);
 
     var node = 0u;
-    var axis = 0;
     while BSP_TREE[node].is_leaf == 0 {
-        if p[axis] < 0.5 {
+        let axis = BSP_TREE[node].axis;
+        let split = BSP_TREE[node].split;
+        if p[axis] < split {
             node = BSP_TREE[node].left;
+            max[axis] = split;
         } else {
             node = BSP_TREE[node].right;
-            p[axis] -= 0.5;
+            min[axis] = split;
         }
-        p[axis] *= 2;
-        size[axis] *= 0.5;
-        axis = (axis + 1) % 3;
     }
 
-    return SpatialInfo(node, size);
+    return SpatialInfo(node, max - min, min);
+}
+
+fn guide_record_position(node: u32, p: vec3f, weight: f32) {
+    if !(weight > 0) {
+        return;
+    }
+    let q = clamp(p, vec3f(0), vec3f(1));
+    bsp_stats_add(node, STAT_WEIGHT, weight);
+    for (var axis = 0u; axis < 3; axis++) {
+        bsp_stats_add(node, STAT_SUM + axis, weight * q[axis]);
+        bsp_stats_add(node, STAT_SUM_SQ + axis, weight * q[axis] * q[axis]);
+    }
 }
 
 fn guide_sample(dir_node: u32, random: vec3f) -> BsdfSample {
@@ -313,7 +474,7 @@ fn guide_splat(dir_node: u32, dir: vec2f, flux: f32) {
     var pos = dir;
     while node != LEAF_SENTINEL {
         let child = u32(pos.x >= 0.5) + 2 * u32(pos.y >= 0.5);
-        atomicAdd(&DIR_TREE_TRAIN[node][child].flux, flux);
+        dir_tree_add_flux(node, child, flux);
         pos = fract(2 * pos);
         node = DIR_TREE_TRAIN[node][child].child;
     }
@@ -0,0 +1,23 @@
+// The sums the guided integrator trains its structures with, added with float atomics or with
+// compare-exchange loops on their bits as picked by the `atomics` flag. Both have the same layout.
+// WGSL can't pass pointers to storage into functions, so the additions are written for each buffer.
+#importif atomics native atomics_native.wgsl
+#importif atomics cas atomics_cas.wgsl
+
+// radiance weighted moments of the positions recorded in a leaf, relative to its bounds, then the
+// radiance weighted gradient of the sampling cost with respect to the logit of the bsdf fraction
+// and the weight to normalize it by
+struct BspStats {
+    values: array<AtomicF32, 9>,
+}
+
+const STAT_WEIGHT = 0u;
+const STAT_SUM = 1u;
+const STAT_SUM_SQ = 4u;
+const STAT_FRACTION_GRAD = 7u;
+const STAT_FRACTION_NORM = 8u;
+
+struct DirTreeNodeAtomic {
+    flux: AtomicF32,
+    child: u32,
+}
@@ -0,0 +1,26 @@
+// for drivers without float atomics, or with slow ones
+alias AtomicF32 = atomic<u32>;
+
+fn bsp_stats_add(node: u32, stat: u32, v: f32) {
+    let p = &BSP_STATS[node].values[stat];
+    var old = atomicLoad(p);
+    loop {
+        let result = atomicCompareExchangeWeak(p, old, bitcast<u32>(bitcast<f32>(old) + v));
+        if result.exchanged {
+            return;
+        }
+        old = result.old_value;
+    }
+}
+
+fn dir_tree_add_flux(node: u32, child: u32, v: f32) {
+    let p = &DIR_TREE_TRAIN[node][child].flux;
+    var old = atomicLoad(p);
+    loop {
+        let result = atomicCompareExchangeWeak(p, old, bitcast<u32>(bitcast<f32>(old) + v));
+        if result.exchanged {
+            return;
+        }
+        old = result.old_value;
+    }
+}
@@ -0,0 +1,9 @@
+alias AtomicF32 = atomic<f32>;
+
+fn bsp_stats_add(node: u32, stat: u32, v: f32) {
+    atomicAdd(&BSP_STATS[node].values[stat], v);
+}
+
+fn dir_tree_add_flux(node: u32, child: u32, v: f32) {
+    atomicAdd(&DIR_TREE_TRAIN[node][child].flux, v);
+}
@@ -0,0 +1 @@
+const LIGHTING_MASK = 0xffffffffu;
@@ -0,0 +1,2 @@
+// emitters seen by the camera and light scattered once, like the light path expression C D? L
+const LIGHTING_MASK = 0x3u;
@@ -0,0 +1,2 @@
+// light scattered twice or more, like the light path expression C D D+ L
+const LIGHTING_MASK = ~0x3u;
@@ -1,3 +1,45 @@
 #importif integrator randomwalk randomwalk.wgsl
 #importif integrator simple simple.wgsl
 #importif integrator guided guided.wgsl
+#importif integrator debug-primitive debug_primitive.wgsl
+#importif integrator debug-object debug_object.wgsl
+#importif integrator debug-instance debug_instance.wgsl
+#importif integrator debug-bvh debug_bvh.wgsl
+#importif lighting all lighting/all.wgsl
+#importif lighting direct lighting/direct.wgsl
+#importif lighting indirect lighting/indirect.wgsl
+#importif roulette on roulette/on.wgsl
+#importif roulette off roulette/off.wgsl
+#import /film.wgsl
+
+struct PathResult {
+    radiance: vec4f,
+    // number of surface interactions
+    length: u32,
+    first: FirstHit,
+}
+
+// limit from the scene or command line, or the integrator's own if there is none. `imm` is
+// declared by the entry point.
+fn integrator_max_depth(default_depth: i32) -> i32 {
+    return select(default_depth, i32(min(imm.max_depth, 0x7fffffffu)), imm.max_depth != ~0u);
+}
+
+// whether a path has taken more bounces of some kind than allowed, with the counts indexed by
+// `bsdf_event`
+fn integrator_bounces_exceeded(bounces: vec3u) -> bool {
+    let limits = vec3u(imm.max_diffuse_bounces, imm.max_glossy_bounces, imm.max_specular_bounces);
+    return any(bounces > limits);
+}
+
+// Weight of light reaching the camera after `bounces` scattering events, for isolating direct or
+// indirect lighting. Bit n of `LIGHTING_MASK` keeps light after n events, with the last bit
+// standing for any more.
+fn integrator_lighting(bounces: i32) -> f32 {
+    return f32((LIGHTING_MASK >> u32(min(bounces, 31))) & 1);
+}
+
+// whether nothing after `bounces` or more scattering events is kept, so paths can stop early
+fn integrator_lighting_done(bounces: i32) -> bool {
+    return (LIGHTING_MASK >> u32(min(bounces, 31))) == 0;
+}
@@ -6,41 +6,68 @@
 
 const MAX_DEPTH = 25;
 
-fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
+fn integrate_ray(wl: Wavelengths, ray_: Ray, cone_: RayCone) -> PathResult {
     var radiance = vec4f();
     var throughput = vec4f(1);
 
     var ray = ray_;
+    var cone = cone_;
+
+    var first = film_first_hit_none();
 
     var depth = 0;
+    var bounces = vec3u();
     while any(throughput > vec4f()) {
-        let result = scene_raycast(ray, FLOAT_MAX);
+        var result = scene_raycast(ray, FLOAT_MAX);
 
         if !result.hit {
             // add infinite lights and finish
             for (var i = 1u; i < arrayLength(&INFINITE_LIGHTS); i++) {
-                radiance += throughput * inf_light_emission(INFINITE_LIGHTS[i], ray, wl);
+                radiance += throughput
+                    * inf_light_emission(INFINITE_LIGHTS[i], ray, wl)
+                    * integrator_lighting(depth);
             }
             break;
         }
 
+        cone.width += cone.spread * result.t;
+        result.cone_width = cone.width;
+
         // add light emitted by surface
-        radiance += throughput * light_emission(result.light, ray, result, wl);
+        radiance += throughput
+            * light_emission(result.light, ray, result, wl)
+            * integrator_lighting(depth);
+        if integrator_lighting_done(depth + 1) {
+            break;
+        }
 
         // enforce termination
         depth += 1;
-        if depth > MAX_DEPTH {
+        if depth > integrator_max_depth(MAX_DEPTH) {
             break;
         }
 
         let bsdf = material_evaluate(result.material, result, wl);
 
         let new_dir = sample_uniform_sphere(sample_2d());
+        // a random direction never follows a specular lobe
+        bounces[bsdf_event(bsdf, false)] += 1;
+        if integrator_bounces_exceeded(bounces) {
+            break;
+        }
 
         // evaluate bsdf
-        throughput *= bsdf_f(bsdf, -ray.d, new_dir)
+        let weight = bsdf_f(bsdf, -ray.d, new_dir)
             * abs(dot(bsdf_normal(bsdf), new_dir))
             / (1 / (2 * TWO_PI));
+        if depth == 1 {
+            first.albedo = weight;
+            first.normal = faceForward(bsdf_normal(bsdf), ray.d, bsdf_normal(bsdf));
+            first.distance = result.t;
+            first.material = result.material.id;
+            first.instance = result.ids.instance;
+        }
+        throughput *= weight;
 
         // spawn new ray
         let offset = 10 * EPSILON * (1 + length(result.p));
@@ -48,5 +75,5 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
         ray.o = result.p + ray.d * offset;
     }
 
-    return radiance;
+    return PathResult(radiance, u32(depth), first);
 }
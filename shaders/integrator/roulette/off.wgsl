@@ -0,0 +1,2 @@
+// every path runs until it escapes or hits the depth limit, for the furnace audit
+const ROULETTE = false;
@@ -0,0 +1 @@
+const ROULETTE = true;
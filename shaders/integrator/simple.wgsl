@@ -11,25 +11,31 @@ const LS_LIGHT = 1;
 const LS_MIS = 2;
 const LS_MODE = LS_MIS;
 
-fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
+fn integrate_ray(wl: Wavelengths, ray_: Ray, cone_: RayCone) -> PathResult {
     var radiance = vec4f();
     var throughput = vec4f(1);
 
     var ray = ray_;
+    var cone = cone_;
 
     var specular_bounce = false;
     var secondary_terminated = false;
     var bsdf_pdf = 0.0;
 
+    var first = film_first_hit_none();
+
     var depth = 0;
+    var bounces = vec3u();
     while any(throughput > vec4f()) {
-        let result = scene_raycast(ray, FLOAT_MAX);
+        var result = scene_raycast(ray, FLOAT_MAX);
 
         if !result.hit {
             // add infinite lights and finish
             if depth == 0 || specular_bounce || LS_MODE == LS_BSDF {
                 for (var i = 1u; i < arrayLength(&INFINITE_LIGHTS); i++) {
-                    radiance += throughput * inf_light_emission(INFINITE_LIGHTS[i], ray, wl);
+                    radiance += throughput
+                        * inf_light_emission(INFINITE_LIGHTS[i], ray, wl)
+                        * integrator_lighting(depth);
                 }
             }
             if depth > 0 && !specular_bounce && LS_MODE == LS_MIS {
@@ -39,15 +45,21 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
                         * light_pdf(INFINITE_LIGHTS[i], ray.o, ray.d);
                     radiance += throughput
                         * inf_light_emission(INFINITE_LIGHTS[i], ray, wl)
-                        * mis_weight(bsdf_pdf, ls_pdf);
+                        * mis_weight(bsdf_pdf, ls_pdf)
+                        * integrator_lighting(depth);
                 }
             }
             break;
         }
 
+        cone.width += cone.spread * result.t;
+        result.cone_width = cone.width;
+
         // add light emitted by surface
         if depth == 0 || specular_bounce || LS_MODE == LS_BSDF {
-            radiance += throughput * light_emission(result.light, ray, result, wl);
+            radiance += throughput
+                * light_emission(result.light, ray, result, wl)
+                * integrator_lighting(depth);
         }
         if depth > 0 && !specular_bounce && LS_MODE == LS_MIS {
             // direct lighting MIS
@@ -55,12 +67,16 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
                 * light_pdf(result.light, ray.o, ray.d);
             radiance += throughput
                 * light_emission(result.light, ray, result, wl)
-                * mis_weight(bsdf_pdf, ls_pdf);
+                * mis_weight(bsdf_pdf, ls_pdf)
+                * integrator_lighting(depth);
+        }
+        if integrator_lighting_done(depth + 1) {
+            break;
         }
 
         // enforce termination
         depth += 1;
-        if depth > MAX_DEPTH {
+        if depth > integrator_max_depth(MAX_DEPTH) {
             break;
         }
 
@@ -71,9 +87,16 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
             throughput *= vec4f(4, 0, 0, 0);
         }
 
+        if depth == 1 {
+            first.normal = faceForward(bsdf_normal(bsdf), ray.d, bsdf_normal(bsdf));
+            first.distance = result.t;
+            first.material = result.material.id;
+            first.instance = result.ids.instance;
+        }
+
         if LS_MODE != LS_BSDF {
             // sample direct lighting
-            radiance += throughput * _sample_direct_light(
+            radiance += throughput * integrator_lighting(depth) * _sample_direct_light(
                 bsdf,
                 result,
                 ray,
@@ -90,14 +113,22 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
         if bsdf_s.pdf == 0 {
             break;
         }
+        bounces[bsdf_event(bsdf, bsdf_s.specular)] += 1;
+        if integrator_bounces_exceeded(bounces) {
+            break;
+        }
 
         bsdf_pdf = bsdf_s.pdf;
 
-        throughput *= bsdf_s.f * abs(dot(bsdf_normal(bsdf), bsdf_s.dir)) / bsdf_s.pdf;
+        let weight = bsdf_s.f * abs(dot(bsdf_normal(bsdf), bsdf_s.dir)) / bsdf_s.pdf;
+        if depth == 1 {
+            first.albedo = weight;
+        }
+        throughput *= weight;
 
         // russian roulette
         let rr = max(max(throughput.x, throughput.y), max(throughput.z, throughput.w));
-        if rr < 1 && depth > 1 {
+        if ROULETTE && rr < 1 && depth > 1 {
             if sample_1d() > rr {
                 break;
             }
@@ -111,7 +142,7 @@ fn integrate_ray(wl: Wavelengths, ray_: Ray) -> vec4f {
         specular_bounce = bsdf_s.specular;
     }
 
-    return radiance;
+    return PathResult(radiance, u32(depth), first);
 }
 
 fn _sample_direct_light(
@@ -149,6 +180,7 @@ fn _sample_direct_light(
     ray.d = light_sample.dir;
     ray.o = hit.p + ray.d * offset;
 
+    count_stat(STAT_SHADOW_RAYS);
     if scene_raycast(ray, light_sample.t_max - offset - 0.0001).hit {
         return vec4f();
     }
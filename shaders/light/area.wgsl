@@ -28,7 +28,7 @@ fn light_area_sample(light: AreaLight, ref_p: vec3f, wl: Wavelengths, random: ve
         return LightSample();
     }
 
-    let alpha = texture_evaluate(light.alpha, shape_sample.uv, Wavelengths()).x;
+    let alpha = texture_evaluate(light.alpha, TextureCoords(shape_sample.uv, shape_sample.p, 0, 0, 1e30, vec3f(1)), Wavelengths()).x;
     if alpha < 1 {
         let h = hash_4d(vec4u(980736245, bitcast<vec3u>(shape_sample.p))).z;
         let u = bits_to_f32(h);
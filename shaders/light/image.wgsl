@@ -12,13 +12,16 @@ struct ImageLight {
     samp: TableSampler2d
 }
 
-fn inf_light_image_emission(light: ImageLight, ray_: Ray, wl: Wavelengths) -> vec4f {
-    let ray = transform_ray_inv(light.transform, ray_);
-    let uv = equal_area_dir_to_square(normalize(ray.d));
-    let texel = vec2u(fract(uv) * vec2f(textureDimensions(IMAGES[light.image])));
-    let rgb = textureLoad(IMAGES[light.image], texel, 0).xyz;
+fn inf_light_image_emission(light: ImageLight, ray: Ray, wl: Wavelengths) -> vec4f {
+    return image_light_radiance(light.transform, light.image, light.scale, ray.d, wl);
+}
+
+fn image_light_radiance(transform: Transform, image: u32, scale: f32, d: vec3f, wl: Wavelengths) -> vec4f {
+    let uv = equal_area_dir_to_square(normalize(transform_vector_inv(transform, d)));
+    let texel = vec2u(fract(uv) * vec2f(textureDimensions(IMAGES[image])));
+    let rgb = textureLoad(IMAGES[image], texel, 0).xyz;
     let spectrum = RgbIlluminantSpectrum(rgb, SPECTRUM_D65_1NIT);
-    return spectrum_rgb_illuminant_sample(spectrum, wl) * light.scale;
+    return spectrum_rgb_illuminant_sample(spectrum, wl) * scale;
 }
 
 fn light_image_sample(light: ImageLight, ref_p: vec3f, wl: Wavelengths, random: vec2f) -> LightSample {
@@ -0,0 +1,150 @@
+#import /spectrum.wgsl
+#import /transform.wgsl
+#import /util/misc.wgsl
+#import /util/table_sample.wgsl
+#import image.wgsl
+
+// Image light restricted to the directions through a rectangular window, following pbrt-v4's
+// PortalImageInfiniteLight. Directions outside the portal are parameterized by their angles around
+// the portal's axes, so the window seen from any point inside is an axis-aligned rectangle of that
+// parameterization and samples only need to be drawn from a window of the distribution.
+
+struct PortalLight {
+    transform: Transform,
+    portal: Transform,
+    size: vec2f,
+    image: u32,
+    scale: f32,
+    light_sampling_path: u32,
+    sat_ptr: u32,
+    resolution: u32,
+}
+
+fn portal_dir_to_uv(w: vec3f) -> vec2f {
+    return (atan2(w.xy, w.zz) + PI / 2) / PI;
+}
+
+fn portal_uv_to_dir(uv: vec2f) -> vec3f {
+    return normalize(vec3f(tan((uv - 0.5) * PI), 1));
+}
+
+// solid angle per unit area of the parameterization
+fn portal_jacobian(w: vec3f) -> f32 {
+    return PI * PI * (1 - w.x * w.x) * (1 - w.y * w.y) / w.z;
+}
+
+// integral of the distribution over [0, p]
+fn portal_sat_lookup(light: PortalLight, p: vec2f) -> f32 {
+    let n = light.resolution;
+    let x = clamp(p * f32(n), vec2f(0), vec2f(f32(n)));
+    let i = min(vec2u(x), vec2u(n - 1));
+    let t = x - vec2f(i);
+    let base = light.sat_ptr + i.y * (n + 1) + i.x;
+    let s00 = FLOAT_DATA[base];
+    let s10 = FLOAT_DATA[base + 1];
+    let s01 = FLOAT_DATA[base + n + 1];
+    let s11 = FLOAT_DATA[base + n + 2];
+    return mix(mix(s00, s10, t.x), mix(s01, s11, t.x), t.y);
+}
+
+fn portal_integral(light: PortalLight, lo: vec2f, hi: vec2f) -> f32 {
+    return portal_sat_lookup(light, hi) - portal_sat_lookup(light, vec2f(lo.x, hi.y))
+        - portal_sat_lookup(light, vec2f(hi.x, lo.y)) + portal_sat_lookup(light, lo);
+}
+
+fn portal_density(light: PortalLight, uv: vec2f) -> f32 {
+    let n = f32(light.resolution);
+    let lo = min(floor(uv * n), vec2f(n - 1)) / n;
+    return portal_integral(light, lo, lo + 1 / n) * n * n;
+}
+
+// Position along `axis` where the integral over [lo, hi], with the upper bound on that axis moved
+// there, reaches `goal`. The integral is linear between texel boundaries, so find the texel with
+// a binary search and interpolate within it.
+fn portal_invert_integral(light: PortalLight, lo: vec2f, hi: vec2f, axis: u32, goal: f32) -> f32 {
+    let n = f32(light.resolution);
+    var a = u32(floor(lo[axis] * n));
+    var b = max(u32(ceil(hi[axis] * n)), a + 1);
+    while a + 1 < b {
+        let mid = (a + b) / 2;
+        var h = hi;
+        h[axis] = f32(mid) / n;
+        if portal_integral(light, lo, h) <= goal {
+            a = mid;
+        } else {
+            b = mid;
+        }
+    }
+
+    var h0 = hi;
+    var h1 = hi;
+    h0[axis] = max(f32(a) / n, lo[axis]);
+    h1[axis] = min(f32(a + 1) / n, hi[axis]);
+    let f0 = portal_integral(light, lo, h0);
+    let f1 = portal_integral(light, lo, h1);
+    if f1 <= f0 {
+        return h0[axis];
+    }
+    return mix(h0[axis], h1[axis], saturate((goal - f0) / (f1 - f0)));
+}
+
+fn inf_light_portal_emission(light: PortalLight, ray: Ray, wl: Wavelengths) -> vec4f {
+    if transform_vector_inv(light.portal, ray.d).z <= 0 {
+        return vec4f();
+    }
+    return image_light_radiance(light.transform, light.image, light.scale, ray.d, wl);
+}
+
+fn light_portal_sample(light: PortalLight, ref_p: vec3f, wl: Wavelengths, random: vec2f) -> LightSample {
+    let p = transform_point_inv(light.portal, ref_p);
+    if p.z >= 0 {
+        return LightSample();
+    }
+    let lo = portal_dir_to_uv(-p);
+    let hi = portal_dir_to_uv(vec3f(light.size, 0) - p);
+    let total = portal_integral(light, lo, hi);
+    if total <= 0 {
+        return LightSample();
+    }
+
+    let n = f32(light.resolution);
+    let y = portal_invert_integral(light, lo, hi, 1, random.y * total);
+    let row_lo = vec2f(lo.x, min(floor(y * n), n - 1) / n);
+    let row_hi = vec2f(hi.x, row_lo.y + 1 / n);
+    let row_total = portal_integral(light, row_lo, row_hi);
+    if row_total <= 0 {
+        return LightSample();
+    }
+    let x = portal_invert_integral(light, row_lo, row_hi, 0, random.x * row_total);
+
+    let uv = vec2f(x, y);
+    let w = portal_uv_to_dir(uv);
+    let pdf = portal_density(light, uv) / total / portal_jacobian(w);
+    if pdf == 0 {
+        return LightSample();
+    }
+
+    let dir = normalize(transform_vector(light.portal, w));
+    let emission = image_light_radiance(light.transform, light.image, light.scale, dir, wl);
+    return LightSample(emission, dir, FLOAT_MAX, pdf);
+}
+
+fn light_portal_pdf(light: PortalLight, ref_p: vec3f, d: vec3f) -> f32 {
+    let p = transform_point_inv(light.portal, ref_p);
+    let w = normalize(transform_vector_inv(light.portal, d));
+    if p.z >= 0 || w.z <= 0 {
+        return 0;
+    }
+
+    let lo = portal_dir_to_uv(-p);
+    let hi = portal_dir_to_uv(vec3f(light.size, 0) - p);
+    let uv = portal_dir_to_uv(w);
+    if any(uv < lo) || any(uv > hi) {
+        return 0;
+    }
+    let total = portal_integral(light, lo, hi);
+    if total <= 0 {
+        return 0;
+    }
+    return portal_density(light, uv) / total / portal_jacobian(w);
+}
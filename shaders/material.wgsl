@@ -6,6 +6,8 @@
 #import material/dielectric.wgsl
 #import material/thin_dielectric.wgsl
 #import material/metallic_workflow.wgsl
+#import material/measured.wgsl
+#import material/principled.wgsl
 
 @group(0) @binding(96)
 var<storage> DIFFUSE_MATERIALS: array<DiffuseMaterial>;
@@ -21,12 +23,16 @@ var<storage> THIN_DIELECTRIC_MATERIALS: array<ThinDielectricMaterial>;
 var<storage> METALLIC_WORKFLOW_MATERIALS: array<MetallicWorkflowMaterial>;
 @group(0) @binding(102)
 var<storage> MIX_MATERIALS: array<MixMaterial>;
+@group(0) @binding(103)
+var<storage> MEASURED_MATERIALS: array<MeasuredMaterial>;
+@group(0) @binding(104)
+var<storage> PRINCIPLED_MATERIALS: array<PrincipledMaterial>;
 
 struct MaterialId {
     id: u32,
 }
 
-const MATERIAL_TAG_BITS: u32 = 3;
+const MATERIAL_TAG_BITS: u32 = 4;
 const MATERIAL_TAG_SHIFT: u32 = 32 - MATERIAL_TAG_BITS;
 const MATERIAL_IDX_MASK: u32 = (1 << MATERIAL_TAG_SHIFT) - 1;
 const MATERIAL_TAG_MASK: u32 = ~MATERIAL_IDX_MASK;
@@ -38,6 +44,8 @@ const MATERIAL_DIELECTRIC: u32 = 3 << MATERIAL_TAG_SHIFT;
 const MATERIAL_THIN_DIELECTRIC: u32 = 4 << MATERIAL_TAG_SHIFT;
 const MATERIAL_METALLIC_WORKFLOW: u32 = 5 << MATERIAL_TAG_SHIFT;
 const MATERIAL_MIX: u32 = 6 << MATERIAL_TAG_SHIFT;
+const MATERIAL_MEASURED: u32 = 7 << MATERIAL_TAG_SHIFT;
+const MATERIAL_PRINCIPLED: u32 = 8 << MATERIAL_TAG_SHIFT;
 
 struct BsdfParams {
     id: u32,
@@ -52,6 +60,8 @@ const BSDF_CONDUCTOR: u32 = 3;
 const BSDF_DIELECTRIC: u32 = 4;
 const BSDF_THIN_DIELECTRIC: u32 = 5;
 const BSDF_METALLIC_WORKFLOW: u32 = 6;
+const BSDF_MEASURED: u32 = 7;
+const BSDF_PRINCIPLED: u32 = 8;
 
 struct BsdfSample {
     f: vec4f,
@@ -72,12 +82,20 @@ struct MixMaterial {
 }
 
 fn material_evaluate(material_: MaterialId, hit: RaycastResult, wl: Wavelengths) -> Bsdf {
+    // the tangent is dp/du, so its length converts the footprint from world to uv space
+    let dpdu = length(hit.tangent);
+    let uv_width = select(0, hit.cone_width / dpdu, dpdu > 0);
+    let tc = TextureCoords(hit.uv, hit.p, hit.cone_width, uv_width, length(hit.edge), hit.color);
+
     var material = material_;
+    // the choice must be deterministic per intersection, but shouldn't be shared by surfaces
+    // without texture coordinates or correlated between levels of nested mixes
+    var h = hash_3d(bitcast<vec3u>(hit.p));
     while (material.id & MATERIAL_TAG_MASK) == MATERIAL_MIX {
         let mix = MIX_MATERIALS[material.id & MATERIAL_IDX_MASK];
-        let amount = texture_evaluate(mix.amount, hit.uv, wl).x;
-        let h = hash_4d(vec4u(bitcast<vec2u>(hit.uv), mix.m1.id, mix.m2.id)).w;
-        let u = bits_to_f32(h);
+        let amount = texture_evaluate(mix.amount, tc, wl).x;
+        h = hash_4d(vec4u(h.xy, mix.m1.id, mix.m2.id)).xyw;
+        let u = bits_to_f32(h.z);
         if u < amount {
             material = mix.m2;
         } else {
@@ -124,22 +142,28 @@ fn material_evaluate(material_: MaterialId, hit: RaycastResult, wl: Wavelengths)
     let idx = material.id & MATERIAL_IDX_MASK;
     switch material.id & MATERIAL_TAG_MASK {
         case MATERIAL_DIFFUSE {
-            bsdf.params = material_diffuse_evaluate(DIFFUSE_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_diffuse_evaluate(DIFFUSE_MATERIALS[idx], tc, wl);
         }
         case MATERIAL_DIFFUSE_TRANSMIT {
-            bsdf.params = material_diffuse_transmit_evaluate(DIFFUSE_TRANSMIT_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_diffuse_transmit_evaluate(DIFFUSE_TRANSMIT_MATERIALS[idx], tc, wl);
         }
         case MATERIAL_CONDUCTOR {
-            bsdf.params = material_conductor_evaluate(CONDUCTOR_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_conductor_evaluate(CONDUCTOR_MATERIALS[idx], tc, wl);
         }
         case MATERIAL_DIELECTRIC {
-            bsdf.params = material_dielectric_evaluate(DIELECTRIC_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_dielectric_evaluate(DIELECTRIC_MATERIALS[idx], tc, wl);
         }
         case MATERIAL_THIN_DIELECTRIC {
-            bsdf.params = material_thin_dielectric_evaluate(THIN_DIELECTRIC_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_thin_dielectric_evaluate(THIN_DIELECTRIC_MATERIALS[idx], tc, wl);
         }
         case MATERIAL_METALLIC_WORKFLOW {
-            bsdf.params = material_metallic_workflow_evaluate(METALLIC_WORKFLOW_MATERIALS[idx], hit.uv, wl);
+            bsdf.params = material_metallic_workflow_evaluate(METALLIC_WORKFLOW_MATERIALS[idx], tc, wl);
+        }
+        case MATERIAL_MEASURED {
+            bsdf.params = material_measured_evaluate(idx, wl);
+        }
+        case MATERIAL_PRINCIPLED {
+            bsdf.params = material_principled_evaluate(PRINCIPLED_MATERIALS[idx], tc, wl);
         }
         default {}
     }
@@ -168,6 +192,12 @@ fn material_get_normal_map(material: MaterialId) -> u32 {
         case MATERIAL_METALLIC_WORKFLOW {
             return METALLIC_WORKFLOW_MATERIALS[idx].normal_map;
         }
+        case MATERIAL_MEASURED {
+            return MEASURED_MATERIALS[idx].normal_map;
+        }
+        case MATERIAL_PRINCIPLED {
+            return PRINCIPLED_MATERIALS[idx].normal_map;
+        }
         default {
             return ~0u;
         }
@@ -179,10 +209,27 @@ fn bsdf_terminates_secondary_wavelengths(bsdf: Bsdf) -> bool {
         || bsdf.params.id == BSDF_THIN_DIELECTRIC && any(bsdf.params.v0 != vec4f(bsdf.params.v0.x));
 }
 
+const BSDF_EVENT_DIFFUSE = 0u;
+const BSDF_EVENT_GLOSSY = 1u;
+const BSDF_EVENT_SPECULAR = 2u;
+
+// kind of bounce for the separate depth limits, where materials with several lobes count as glossy
+fn bsdf_event(bsdf: Bsdf, specular: bool) -> u32 {
+    if specular {
+        return BSDF_EVENT_SPECULAR;
+    }
+    if bsdf.params.id == BSDF_DIFFUSE || bsdf.params.id == BSDF_DIFFUSE_TRANSMIT {
+        return BSDF_EVENT_DIFFUSE;
+    }
+    return BSDF_EVENT_GLOSSY;
+}
+
 fn bsdf_is_highly_specular(bsdf: Bsdf) -> bool {
     return bsdf.params.id == BSDF_DIELECTRIC && trowbridge_reitz_is_smooth(bsdf.params.v1.xy)
         || bsdf.params.id == BSDF_CONDUCTOR && trowbridge_reitz_is_smooth(bsdf.params.v2.xy)
-        || bsdf.params.id == BSDF_THIN_DIELECTRIC;
+        || bsdf.params.id == BSDF_THIN_DIELECTRIC
+        || bsdf.params.id == BSDF_PRINCIPLED && trowbridge_reitz_is_smooth(bsdf.params.v1.xx)
+            && principled_lobe_probabilities(bsdf.params).x == 0 && bsdf.params.v2.x == 0;
 }
 
 fn bsdf_f(bsdf: Bsdf, wo_: vec3f, wi_: vec3f) -> vec4f {
@@ -208,6 +255,12 @@ fn bsdf_f(bsdf: Bsdf, wo_: vec3f, wi_: vec3f) -> vec4f {
         case BSDF_METALLIC_WORKFLOW {
             return bsdf_metallic_workflow_f(bsdf.params, wo, wi);
         }
+        case BSDF_MEASURED {
+            return bsdf_measured_f(bsdf.params, wo, wi);
+        }
+        case BSDF_PRINCIPLED {
+            return bsdf_principled_f(bsdf.params, wo, wi);
+        }
         default {
             return vec4f();
         }
@@ -234,6 +287,12 @@ fn bsdf_sample(bsdf: Bsdf, wo_: vec3f, random: vec3f) -> BsdfSample {
         case BSDF_METALLIC_WORKFLOW {
             sample = bsdf_metallic_workflow_sample(bsdf.params, wo, random);
         }
+        case BSDF_MEASURED {
+            sample = bsdf_measured_sample(bsdf.params, wo, random);
+        }
+        case BSDF_PRINCIPLED {
+            sample = bsdf_principled_sample(bsdf.params, wo, random);
+        }
         default {
             // this is also the BSDF_DIFFUSE_TRANSMIT case
             var dir = sample_cosine_hemisphere(random.xy);
@@ -274,6 +333,12 @@ fn bsdf_pdf(bsdf: Bsdf, wo_: vec3f, wi_: vec3f) -> f32 {
         case BSDF_METALLIC_WORKFLOW {
             return bsdf_metallic_workflow_pdf(bsdf.params, wo, wi);
         }
+        case BSDF_MEASURED {
+            return bsdf_measured_pdf(bsdf.params, wo, wi);
+        }
+        case BSDF_PRINCIPLED {
+            return bsdf_principled_pdf(bsdf.params, wo, wi);
+        }
         default {
             // this is also the BSDF_DIFFUSE_TRANSMIT case
             let pdf = pdf_cosine_hemisphere(vec3f(wi.xy, copysign(wi.z, 1)));
@@ -14,14 +14,14 @@ struct ConductorMaterial {
     roughness_v: TextureId,
 }
 
-fn material_conductor_evaluate(material: ConductorMaterial, uv: vec2f, wl: Wavelengths) -> BsdfParams {
+fn material_conductor_evaluate(material: ConductorMaterial, tc: TextureCoords, wl: Wavelengths) -> BsdfParams {
     var bsdf: BsdfParams;
     bsdf.id = BSDF_CONDUCTOR;
-    bsdf.v0 = texture_evaluate(material.ior_re, uv, wl);
-    bsdf.v1 = -texture_evaluate(material.ior_im, uv, wl);
+    bsdf.v0 = texture_evaluate(material.ior_re, tc, wl);
+    bsdf.v1 = -texture_evaluate(material.ior_im, tc, wl);
     let alpha = trowbridge_reitz_adjust_alpha(vec2f(
-        texture_evaluate(material.roughness_u, uv, wl).x,
-        texture_evaluate(material.roughness_v, uv, wl).x,
+        texture_evaluate(material.roughness_u, tc, wl).x,
+        texture_evaluate(material.roughness_v, tc, wl).x,
     ));
     bsdf.v2 = vec4f(alpha, 0, 0);
     return bsdf;
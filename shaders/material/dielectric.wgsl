@@ -13,13 +13,13 @@ struct DielectricMaterial {
     roughness_v: TextureId,
 }
 
-fn material_dielectric_evaluate(material: DielectricMaterial, uv: vec2f, wl: Wavelengths) -> BsdfParams {
+fn material_dielectric_evaluate(material: DielectricMaterial, tc: TextureCoords, wl: Wavelengths) -> BsdfParams {
     var bsdf: BsdfParams;
     bsdf.id = BSDF_DIELECTRIC;
     bsdf.v0 = spectrum_sample(material.ior, wl);
     let alpha = trowbridge_reitz_adjust_alpha(vec2f(
-        texture_evaluate(material.roughness_u, uv, wl).x,
-        texture_evaluate(material.roughness_v, uv, wl).x,
+        texture_evaluate(material.roughness_u, tc, wl).x,
+        texture_evaluate(material.roughness_v, tc, wl).x,
     ));
     bsdf.v1 = vec4f(alpha, 0, 0);
     return bsdf;
@@ -8,10 +8,10 @@ struct DiffuseMaterial {
     texture: TextureId
 }
 
-fn material_diffuse_evaluate(material: DiffuseMaterial, uv: vec2f, wl: Wavelengths) -> BsdfParams {
+fn material_diffuse_evaluate(material: DiffuseMaterial, tc: TextureCoords, wl: Wavelengths) -> BsdfParams {
     var bsdf: BsdfParams;
     bsdf.id = BSDF_DIFFUSE;
-    bsdf.v0 = texture_evaluate(material.texture, uv, wl);
+    bsdf.v0 = texture_evaluate(material.texture, tc, wl);
     return bsdf;
 }
 
@@ -9,12 +9,12 @@ struct DiffuseTransmitMaterial {
     scale: TextureId,
 }
 
-fn material_diffuse_transmit_evaluate(material: DiffuseTransmitMaterial, uv: vec2f, wl: Wavelengths) -> BsdfParams {
+fn material_diffuse_transmit_evaluate(material: DiffuseTransmitMaterial, tc: TextureCoords, wl: Wavelengths) -> BsdfParams {
     var bsdf: BsdfParams;
     bsdf.id = BSDF_DIFFUSE_TRANSMIT;
-    let scale = texture_evaluate(material.scale, uv, wl);
-    bsdf.v0 = texture_evaluate(material.reflectance, uv, wl) * scale;
-    bsdf.v1 = texture_evaluate(material.transmittance, uv, wl) * scale;
+    let scale = texture_evaluate(material.scale, tc, wl);
+    bsdf.v0 = texture_evaluate(material.reflectance, tc, wl) * scale;
+    bsdf.v1 = texture_evaluate(material.transmittance, tc, wl) * scale;
     return bsdf;
 }
 
@@ -0,0 +1,170 @@
+#import /material.wgsl
+#import /spectrum.wgsl
+#import /util/misc.wgsl
+#import /util/spherical.wgsl
+#import /util/piecewise_linear_2d.wgsl
+
+// Tabulated BRDF from the RGL material database, following pbrt-v4's MeasuredBxDF
+
+struct MeasuredMaterial {
+    normal_map: u32,
+    isotropic: u32,
+    ndf: PiecewiseLinear2d,
+    sigma: PiecewiseLinear2d,
+    vndf: PiecewiseLinear2d,
+    luminance: PiecewiseLinear2d,
+    spectra: PiecewiseLinear2d,
+}
+
+fn material_measured_evaluate(idx: u32, wl: Wavelengths) -> BsdfParams {
+    var bsdf: BsdfParams;
+    bsdf.id = BSDF_MEASURED;
+    bsdf.v0 = wl.l;
+    bsdf.v1.x = bitcast<f32>(idx);
+    return bsdf;
+}
+
+fn measured_theta_to_u(theta: f32) -> f32 {
+    return sqrt(theta * (2 / PI));
+}
+
+fn measured_phi_to_u(phi: f32) -> f32 {
+    return phi * (1 / TWO_PI) + 0.5;
+}
+
+fn measured_u_to_theta(u: f32) -> f32 {
+    return u * u * (PI / 2);
+}
+
+fn measured_u_to_phi(u: f32) -> f32 {
+    return (2 * u - 1) * PI;
+}
+
+fn measured_spectra(material: MeasuredMaterial, u: vec2f, phi_o: f32, theta_o: f32, wl: vec4f) -> vec4f {
+    var fr: vec4f;
+    for (var i = 0; i < 4; i++) {
+        fr[i] = max(0, pl2d_evaluate(material.spectra, u, vec3f(phi_o, theta_o, wl[i])));
+    }
+    return fr;
+}
+
+fn bsdf_measured_f(bsdf: BsdfParams, wo_: vec3f, wi_: vec3f) -> vec4f {
+    if wo_.z * wi_.z <= 0 {
+        return vec4f();
+    }
+    var wo = wo_;
+    var wi = wi_;
+    if wo.z < 0 {
+        wo = -wo;
+        wi = -wi;
+    }
+
+    let wm_ = wi + wo;
+    if dot(wm_, wm_) == 0 {
+        return vec4f();
+    }
+    let wm = normalize(wm_);
+
+    let material = MEASURED_MATERIALS[bitcast<u32>(bsdf.v1.x)];
+
+    let theta_o = acos(clamp(wo.z, -1, 1));
+    let phi_o = atan2(wo.y, wo.x);
+    let theta_m = acos(clamp(wm.z, -1, 1));
+    let phi_m = atan2(wm.y, wm.x);
+
+    let u_wo = vec2f(measured_theta_to_u(theta_o), measured_phi_to_u(phi_o));
+    var u_wm = vec2f(
+        measured_theta_to_u(theta_m),
+        measured_phi_to_u(select(phi_m, phi_m - phi_o, material.isotropic != 0)),
+    );
+    u_wm.y = fract(u_wm.y);
+
+    let ui = pl2d_invert(material.vndf, u_wm, vec3f(phi_o, theta_o, 0));
+    let fr = measured_spectra(material, ui.p, phi_o, theta_o, bsdf.v0);
+
+    return fr * pl2d_evaluate(material.ndf, u_wm, vec3f())
+        / (4 * pl2d_evaluate(material.sigma, u_wo, vec3f()) * abs_cos_theta(wi));
+}
+
+fn bsdf_measured_sample(bsdf: BsdfParams, wo_: vec3f, random: vec3f) -> BsdfSample {
+    var wo = wo_;
+    let flip = wo.z < 0;
+    if flip {
+        wo = -wo;
+    }
+
+    let material = MEASURED_MATERIALS[bitcast<u32>(bsdf.v1.x)];
+
+    let theta_o = acos(clamp(wo.z, -1, 1));
+    let phi_o = atan2(wo.y, wo.x);
+    let params = vec3f(phi_o, theta_o, 0);
+
+    let lum = pl2d_sample(material.luminance, random.xy, params);
+    let vndf = pl2d_sample(material.vndf, lum.p, params);
+    let u_wm = vndf.p;
+
+    var phi_m = measured_u_to_phi(u_wm.y);
+    let theta_m = measured_u_to_theta(u_wm.x);
+    if material.isotropic != 0 {
+        phi_m += phi_o;
+    }
+
+    let sin_theta_m = sin(theta_m);
+    let wm = vec3f(sin_theta_m * cos(phi_m), sin_theta_m * sin(phi_m), cos(theta_m));
+    var wi = -reflect(wo, wm);
+    if wi.z <= 0 {
+        return BsdfSample();
+    }
+
+    let u_wo = vec2f(measured_theta_to_u(theta_o), measured_phi_to_u(phi_o));
+    let fr = measured_spectra(material, lum.p, phi_o, theta_o, bsdf.v0);
+    let f = fr * pl2d_evaluate(material.ndf, u_wm, vec3f())
+        / (4 * pl2d_evaluate(material.sigma, u_wo, vec3f()) * abs_cos_theta(wi));
+
+    let pdf = vndf.pdf * lum.pdf
+        / (4 * dot(wo, wm) * max(2 * PI * PI * u_wm.x * sin_theta_m, 1e-6));
+
+    if flip {
+        wi = -wi;
+    }
+    return BsdfSample(f, wi, pdf, false);
+}
+
+fn bsdf_measured_pdf(bsdf: BsdfParams, wo_: vec3f, wi_: vec3f) -> f32 {
+    if wo_.z * wi_.z <= 0 {
+        return 0;
+    }
+    var wo = wo_;
+    var wi = wi_;
+    if wo.z < 0 {
+        wo = -wo;
+        wi = -wi;
+    }
+
+    let wm_ = wi + wo;
+    if dot(wm_, wm_) == 0 {
+        return 0;
+    }
+    let wm = normalize(wm_);
+
+    let material = MEASURED_MATERIALS[bitcast<u32>(bsdf.v1.x)];
+
+    let theta_o = acos(clamp(wo.z, -1, 1));
+    let phi_o = atan2(wo.y, wo.x);
+    let theta_m = acos(clamp(wm.z, -1, 1));
+    let phi_m = atan2(wm.y, wm.x);
+
+    var u_wm = vec2f(
+        measured_theta_to_u(theta_m),
+        measured_phi_to_u(select(phi_m, phi_m - phi_o, material.isotropic != 0)),
+    );
+    u_wm.y = fract(u_wm.y);
+
+    let params = vec3f(phi_o, theta_o, 0);
+    let ui = pl2d_invert(material.vndf, u_wm, params);
+    let lum_pdf = pl2d_evaluate(material.luminance, ui.p, params);
+
+    let sin_theta_m = length(wm.xy);
+    let jacobian = 4 * dot(wi, wm) * max(2 * PI * PI * u_wm.x * sin_theta_m, 1e-6);
+    return ui.pdf * lum_pdf / jacobian;
+}
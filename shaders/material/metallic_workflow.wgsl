@@ -16,17 +16,17 @@ struct MetallicWorkflowMaterial {
 
 fn material_metallic_workflow_evaluate(
     material: MetallicWorkflowMaterial,
-    uv: vec2f,
+    tc: TextureCoords,
     wl: Wavelengths
 ) -> BsdfParams {
     var bsdf: BsdfParams;
     bsdf.id = BSDF_METALLIC_WORKFLOW;
-    bsdf.v0 = texture_evaluate(material.base_color, uv, wl);
+    bsdf.v0 = texture_evaluate(material.base_color, tc, wl);
     let alpha = trowbridge_reitz_adjust_alpha(vec2f(
-        texture_evaluate(material.roughness_u, uv, wl).x,
-        texture_evaluate(material.roughness_v, uv, wl).x,
+        texture_evaluate(material.roughness_u, tc, wl).x,
+        texture_evaluate(material.roughness_v, tc, wl).x,
     ));
-    let metallic = texture_evaluate(material.metallic, uv, wl).x;
+    let metallic = texture_evaluate(material.metallic, tc, wl).x;
     bsdf.v1 = vec4f(alpha, metallic, 0);
     return bsdf;
 }
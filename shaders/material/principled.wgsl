@@ -0,0 +1,216 @@
+#import /material.wgsl
+#import /texture.wgsl
+#import /spectrum.wgsl
+#import /util/distr.wgsl
+#import /util/misc.wgsl
+#import /util/spherical.wgsl
+#import trowbridge_reitz.wgsl
+#import dielectric.wgsl
+
+// Disney-style principled BSDF. Roughness values are perceptual (alpha = roughness^2), as in glTF
+// and Blender.
+
+struct PrincipledMaterial {
+    normal_map: u32,
+    base_color: TextureId,
+    metallic: TextureId,
+    roughness: TextureId,
+    specular_tint: TextureId,
+    clearcoat: TextureId,
+    clearcoat_roughness: TextureId,
+    sheen: TextureId,
+    transmission: TextureId,
+    eta: TextureId,
+}
+
+fn material_principled_evaluate(material: PrincipledMaterial, tc: TextureCoords, wl: Wavelengths) -> BsdfParams {
+    var bsdf: BsdfParams;
+    bsdf.id = BSDF_PRINCIPLED;
+    bsdf.v0 = texture_evaluate(material.base_color, tc, wl);
+
+    let roughness = texture_evaluate(material.roughness, tc, wl).x;
+    let alpha = trowbridge_reitz_adjust_alpha(vec2f(roughness * roughness)).x;
+    let eta = texture_evaluate(material.eta, tc, wl).x;
+    let metallic = saturate(texture_evaluate(material.metallic, tc, wl).x);
+    let specular_tint = saturate(texture_evaluate(material.specular_tint, tc, wl).x);
+    bsdf.v1 = vec4f(alpha, eta, metallic, specular_tint);
+
+    let clearcoat = saturate(texture_evaluate(material.clearcoat, tc, wl).x);
+    let cc_roughness = texture_evaluate(material.clearcoat_roughness, tc, wl).x;
+    // a perfectly smooth coat would need its own delta lobe, so keep it just above the threshold
+    let cc_alpha = max(cc_roughness * cc_roughness, 1.0e-3);
+    let sheen = max(texture_evaluate(material.sheen, tc, wl).x, 0);
+    let transmission = saturate(texture_evaluate(material.transmission, tc, wl).x);
+    bsdf.v2 = vec4f(clearcoat, cc_alpha, sheen, transmission);
+    return bsdf;
+}
+
+// selection probabilities of the diffuse, specular, clearcoat and transmission lobes
+fn principled_lobe_probabilities(bsdf: BsdfParams) -> vec4f {
+    let metallic = bsdf.v1.z;
+    let transmission = (1 - metallic) * bsdf.v2.w;
+    let w = vec4f((1 - transmission) * (1 - metallic), 1 - transmission, 0.25 * bsdf.v2.x, transmission);
+    return w / (w.x + w.y + w.z + w.w);
+}
+
+fn principled_dielectric(bsdf: BsdfParams) -> BsdfParams {
+    var dielectric: BsdfParams;
+    dielectric.id = BSDF_DIELECTRIC;
+    dielectric.v0 = vec4f(bsdf.v1.y);
+    dielectric.v1 = vec4f(bsdf.v1.xx, 0, 0);
+    return dielectric;
+}
+
+fn principled_schlick_weight(cos_theta: f32) -> f32 {
+    let v = saturate(1 - cos_theta);
+    let v2 = v * v;
+    return v2 * v2 * v;
+}
+
+fn principled_specular_color(bsdf: BsdfParams, cos_theta: f32) -> vec4f {
+    let eta = bsdf.v1.y;
+    let r0 = (eta - 1) * (eta - 1) / ((eta + 1) * (eta + 1));
+    let tint = mix(vec4f(1), bsdf.v0, bsdf.v1.w);
+    let f0 = mix(r0 * tint, bsdf.v0, bsdf.v1.z);
+    return f0 + (1 - f0) * principled_schlick_weight(cos_theta);
+}
+
+// fraction of light passing through the clearcoat
+fn principled_coat_transmittance(bsdf: BsdfParams, wo: vec3f) -> f32 {
+    return 1 - bsdf.v2.x * (0.04 + 0.96 * principled_schlick_weight(abs_cos_theta(wo)));
+}
+
+fn bsdf_principled_f(bsdf: BsdfParams, wo_: vec3f, wi_: vec3f) -> vec4f {
+    let base_color = bsdf.v0;
+    let alpha = bsdf.v1.xx;
+    let metallic = bsdf.v1.z;
+    let clearcoat = bsdf.v2.x;
+    let cc_alpha = bsdf.v2.yy;
+    let transmission = (1 - metallic) * bsdf.v2.w;
+
+    var base = vec4f();
+    var coat = vec4f();
+
+    if wo_.z * wi_.z > 0 {
+        var wo = wo_;
+        var wi = wi_;
+        if wo.z < 0 {
+            wo.z = -wo.z;
+            wi.z = -wi.z;
+        }
+        let nm = normalize(wo + wi);
+        let cos_d = dot(wi, nm);
+
+        var opaque = vec4f();
+        if !trowbridge_reitz_is_smooth(alpha) {
+            opaque = principled_specular_color(bsdf, cos_d)
+                * trowbridge_reitz_ndf(alpha, nm)
+                * trowbridge_reitz_masking_shadowing(alpha, wo, wi)
+                / (4 * cos_theta(wi) * cos_theta(wo));
+        }
+
+        let fd90 = 0.5 + 2 * sqrt(alpha.x) * cos_d * cos_d;
+        let fl = principled_schlick_weight(cos_theta(wi));
+        let fv = principled_schlick_weight(cos_theta(wo));
+        let diffuse = base_color / PI * mix(1, fd90, fl) * mix(1, fd90, fv);
+        let sheen = vec4f(bsdf.v2.z * principled_schlick_weight(cos_d));
+        opaque += (1 - metallic) * (diffuse + sheen);
+
+        base = (1 - transmission) * opaque;
+
+        if clearcoat > 0 {
+            let fcc = 0.04 + 0.96 * principled_schlick_weight(cos_d);
+            coat = vec4f(clearcoat * fcc
+                * trowbridge_reitz_ndf(cc_alpha, nm)
+                * trowbridge_reitz_masking_shadowing(cc_alpha, wo, wi)
+                / (4 * cos_theta(wi) * cos_theta(wo)));
+        }
+    }
+
+    if transmission > 0 {
+        let f = bsdf_dielectric_f(principled_dielectric(bsdf), wo_, wi_);
+        base += transmission * f * select(vec4f(1), base_color, wo_.z * wi_.z < 0);
+    }
+
+    return base * principled_coat_transmittance(bsdf, wo_) + coat;
+}
+
+fn bsdf_principled_sample(bsdf: BsdfParams, wo: vec3f, random: vec3f) -> BsdfSample {
+    let alpha = bsdf.v1.xx;
+    let metallic = bsdf.v1.z;
+    let transmission = (1 - metallic) * bsdf.v2.w;
+    let pr = principled_lobe_probabilities(bsdf);
+
+    var wi: vec3f;
+    if random.z < pr.x {
+        wi = sample_cosine_hemisphere(random.xy);
+        wi.z = copysign(wi.z, wo.z);
+    } else if random.z < pr.x + pr.y {
+        if trowbridge_reitz_is_smooth(alpha) {
+            wi = vec3f(-wo.xy, wo.z);
+            let f = (1 - transmission)
+                * principled_specular_color(bsdf, abs_cos_theta(wo))
+                * principled_coat_transmittance(bsdf, wo)
+                / abs_cos_theta(wi);
+            return BsdfSample(f, wi, pr.y, true);
+        }
+        let nm = trowbridge_reitz_sample(alpha, wo, random.xy);
+        wi = -reflect(wo, nm);
+    } else if random.z < pr.x + pr.y + pr.z {
+        let nm = trowbridge_reitz_sample(bsdf.v2.yy, wo, random.xy);
+        wi = -reflect(wo, nm);
+    } else {
+        // reuse the remainder of the lobe selection number for the dielectric's own choice
+        let u = (random.z - (1 - pr.w)) / pr.w;
+        let s = bsdf_dielectric_sample(principled_dielectric(bsdf), wo, vec3f(random.xy, u));
+        if s.pdf == 0 {
+            return BsdfSample();
+        }
+        if s.specular {
+            let f = transmission
+                * s.f
+                * select(vec4f(1), bsdf.v0, s.dir.z * wo.z < 0)
+                * principled_coat_transmittance(bsdf, wo);
+            return BsdfSample(f, s.dir, pr.w * s.pdf, true);
+        }
+        wi = s.dir;
+    }
+
+    let pdf = bsdf_principled_pdf(bsdf, wo, wi);
+    if pdf == 0 {
+        return BsdfSample();
+    }
+
+    let f = bsdf_principled_f(bsdf, wo, wi);
+    return BsdfSample(f, wi, pdf, false);
+}
+
+fn bsdf_principled_pdf(bsdf: BsdfParams, wo_: vec3f, wi_: vec3f) -> f32 {
+    let alpha = bsdf.v1.xx;
+    let pr = principled_lobe_probabilities(bsdf);
+
+    var pdf = 0.0;
+    if wo_.z * wi_.z > 0 {
+        var wo = wo_;
+        var wi = wi_;
+        if wo.z < 0 {
+            wo.z = -wo.z;
+            wi.z = -wi.z;
+        }
+        let nm = normalize(wo + wi);
+
+        pdf += pr.x * pdf_cosine_hemisphere(wi);
+        if !trowbridge_reitz_is_smooth(alpha) {
+            pdf += pr.y * trowbridge_reitz_visible_ndf(alpha, wo, nm) / (4 * abs(dot(wo, nm)));
+        }
+        if pr.z > 0 {
+            pdf += pr.z * trowbridge_reitz_visible_ndf(bsdf.v2.yy, wo, nm) / (4 * abs(dot(wo, nm)));
+        }
+    }
+
+    if pr.w > 0 {
+        pdf += pr.w * bsdf_dielectric_pdf(principled_dielectric(bsdf), wo_, wi_);
+    }
+
+    return pdf;
+}
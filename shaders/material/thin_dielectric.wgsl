@@ -13,7 +13,7 @@ struct ThinDielectricMaterial {
 
 fn material_thin_dielectric_evaluate(
     material: ThinDielectricMaterial,
-    uv: vec2f,
+    tc: TextureCoords,
     wl: Wavelengths
 ) -> BsdfParams {
     var bsdf: BsdfParams;
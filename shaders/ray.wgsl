@@ -15,5 +15,28 @@ struct RaycastResult {
     t: f32,
     material: MaterialId,
     light: LightId,
+    ids: HitIds,
     uv: vec2f,
+    // offset from the hit to the closest point on a triangle edge
+    edge: vec3f,
+    // reflectance of point cloud splats, white for other shapes
+    color: vec3f,
+    // width of the ray cone at the hit, set by the integrator
+    cone_width: f32,
+}
+
+// Which part of the scene was hit, for debug shading
+struct HitIds {
+    // index of the primitive node
+    primitive: u32,
+    // index of the outermost transform node and the node it transforms, or ~0 if there is none
+    instance: u32,
+    object: u32,
+}
+
+// Ray cones for texture level of detail (Akenine-Möller et al. 2019). The spread angle stays at
+// the camera's pixel spread after bounces, similar to pbrt-v4's approximate differentials.
+struct RayCone {
+    width: f32,
+    spread: f32,
 }
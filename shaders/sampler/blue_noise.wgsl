@@ -0,0 +1,51 @@
+#import /util/misc.wgsl
+#import data.wgsl
+
+// Rank-1 lattice samples (golden ratio in 1D, R2 in 2D) offset per pixel by a blue noise tile,
+// which spreads the error of low sample counts as high frequency noise (Heitz et al., 2019). Each
+// dimension reads the tile with its own toroidal shift.
+
+struct SamplerState {
+    px: vec2u,
+    index: u32,
+    dimension: u32,
+}
+
+var<private> SAMPLER: SamplerState;
+
+const BLUE_NOISE_PIXEL_DIMENSION: u32 = 0xffffffffu;
+
+fn sample_init(px: vec2u, sample: u32) {
+    SAMPLER.px = px;
+    SAMPLER.index = sample;
+    SAMPLER.dimension = 0;
+}
+
+fn _blue_noise_offset(dimension: u32, channel: u32) -> u32 {
+    let size = SAMPLER_DATA.tile_size;
+    // the same shift for every pixel, so neighbouring pixels keep their blue noise relation
+    let shift = hash_3d(vec3(dimension, channel, 0xb10e)).xy % size;
+    let p = (SAMPLER.px + shift) % size;
+    return SAMPLER_DATA.tile[p.y * size + p.x];
+}
+
+fn _blue_noise_sample(dimension: u32) -> vec2f {
+    // additive recurrences in 32-bit fixed point, which wrap around like fract
+    let x = _blue_noise_offset(dimension, 0) + SAMPLER.index * 0xc13fa9a9u;
+    let y = _blue_noise_offset(dimension, 1) + SAMPLER.index * 0x91e10da6u;
+    return vec2(bits_to_f32(x), bits_to_f32(y));
+}
+
+fn sample_1d() -> f32 {
+    SAMPLER.dimension += 1;
+    return bits_to_f32(_blue_noise_offset(SAMPLER.dimension, 0) + SAMPLER.index * 0x9e3779b9u);
+}
+
+fn sample_2d() -> vec2f {
+    SAMPLER.dimension += 1;
+    return _blue_noise_sample(SAMPLER.dimension);
+}
+
+fn sample_pixel() -> vec2f {
+    return _blue_noise_sample(BLUE_NOISE_PIXEL_DIMENSION);
+}
@@ -0,0 +1,16 @@
+// Parameters of the samplers which need more than a hash of the pixel and sample number
+struct SamplerData {
+    // samples per pixel, or 1 if unknown
+    samples: u32,
+    // width and height of the blue noise tile
+    tile_size: u32,
+    // prime factors of `samples` in the order the stratified sampler reverses the sample index
+    // by, each twice in a row when `samples` is square
+    radix_count: u32,
+    radices: array<u32, 32>,
+    // rank of each pixel of the tile as a fraction in 32-bit fixed point
+    tile: array<u32>,
+}
+
+@group(1) @binding(19)
+var<storage> SAMPLER_DATA: SamplerData;
@@ -1 +1,4 @@
 #importif sampler independent independent.wgsl
+#importif sampler sobol sobol.wgsl
+#importif sampler stratified stratified.wgsl
+#importif sampler blue-noise blue_noise.wgsl
@@ -0,0 +1,73 @@
+#import /util/misc.wgsl
+
+// Owen-scrambled Sobol points, padded to any number of dimensions by shuffling the sample index
+// separately for each 1D or 2D sample (Burley, 2020). Only the first two Sobol dimensions are
+// needed, and every sample is as well stratified as the first pair.
+
+struct SamplerState {
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+var<private> SAMPLER: SamplerState;
+
+// the pixel gets its own dimension so it does not depend on how many samples came before
+const SOBOL_PIXEL_DIMENSION: u32 = 0xffffffffu;
+
+fn sample_init(px: vec2u, sample: u32) {
+    SAMPLER.seed = hash_3d(vec3(px, 0x50b01u)).x;
+    SAMPLER.index = sample;
+    SAMPLER.dimension = 0;
+}
+
+// direction numbers of the second dimension follow v[i + 1] = v[i] ^ (v[i] >> 1)
+fn _sobol_dim1(index: u32) -> u32 {
+    var result = 0u;
+    var v = 1u << 31;
+    for (var i = index; i != 0; i >>= 1) {
+        if (i & 1) != 0 {
+            result ^= v;
+        }
+        v ^= v >> 1;
+    }
+    return result;
+}
+
+// Laine-Karras style hash with Vegdahl's constants, which scrambles each bit using only the
+// lower bits
+fn _sobol_lk_hash(x: u32, seed: u32) -> u32 {
+    var v = x;
+    v ^= v * 0x3d20adeau;
+    v += seed;
+    v *= (seed >> 16) | 1;
+    v ^= v * 0x05526c56u;
+    v ^= v * 0x53a22864u;
+    return v;
+}
+
+fn _sobol_owen_scramble(x: u32, seed: u32) -> u32 {
+    return reverseBits(_sobol_lk_hash(reverseBits(x), seed));
+}
+
+fn _sobol_sample(dimension: u32) -> vec2f {
+    let seeds = hash_3d(vec3(SAMPLER.seed, dimension, 0));
+    let index = _sobol_owen_scramble(SAMPLER.index, seeds.x);
+    let x = _sobol_owen_scramble(reverseBits(index), seeds.y);
+    let y = _sobol_owen_scramble(_sobol_dim1(index), seeds.z);
+    return vec2(bits_to_f32(x), bits_to_f32(y));
+}
+
+fn sample_1d() -> f32 {
+    SAMPLER.dimension += 1;
+    return _sobol_sample(SAMPLER.dimension).x;
+}
+
+fn sample_2d() -> vec2f {
+    SAMPLER.dimension += 1;
+    return _sobol_sample(SAMPLER.dimension);
+}
+
+fn sample_pixel() -> vec2f {
+    return _sobol_sample(SOBOL_PIXEL_DIMENSION);
+}
@@ -0,0 +1,92 @@
+#import /util/misc.wgsl
+#import data.wgsl
+
+// Jittered samples with the strata of each dimension visited in a progressive order: the sample
+// index is written in the mixed radix of the factors of `SAMPLER_DATA.samples` and its digits are
+// reversed, so every aligned run of samples covers the coarse strata evenly and the first few
+// samples already resemble the finished image. A random shift of each digit, depending on the
+// digits before it, decorrelates the dimensions. Every run of `SAMPLER_DATA.samples` samples of a
+// pixel covers all the strata, so renders longer than the sample count stay unbiased. 2D samples
+// use a grid when the sample count is square and Latin hypercube samples otherwise.
+
+struct SamplerState {
+    seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+var<private> SAMPLER: SamplerState;
+
+const STRATIFIED_PIXEL_DIMENSION: u32 = 0xffffffffu;
+
+fn sample_init(px: vec2u, sample: u32) {
+    let n = max(SAMPLER_DATA.samples, 1);
+    SAMPLER.seed = hash_3d(vec3(px, sample / n)).x;
+    SAMPLER.index = sample % n;
+    SAMPLER.dimension = 0;
+}
+
+// Stratum of the sample in a scrambled digit reversal of the sample index. With `grid`, the
+// radices come in pairs and alternate between the x and y strata of an m by m grid.
+fn _stratified_stratum(seed: u32, grid: bool) -> vec2u {
+    var index = SAMPLER.index;
+    var scale = vec2u(max(SAMPLER_DATA.samples, 1));
+    if grid {
+        scale = vec2u(u32(round(sqrt(f32(scale.x)))));
+    }
+    var stratum = vec2u();
+    var node = seed;
+    for (var i = 0u; i < SAMPLER_DATA.radix_count; i++) {
+        let radix = SAMPLER_DATA.radices[i];
+        let digit = index % radix;
+        index /= radix;
+        let shifted = (digit + hash_3d(vec3(node, i, 0x5ca7u)).x % radix) % radix;
+        node = hash_3d(vec3(node, digit, i)).x;
+
+        let axis = select(0u, i % 2, grid);
+        scale[axis] /= radix;
+        stratum[axis] += shifted * scale[axis];
+    }
+    return stratum;
+}
+
+fn _stratified_sample(dimension: u32) -> vec2f {
+    let n = max(SAMPLER_DATA.samples, 1);
+    // the order of the strata is shared by the samples of a pixel, the jitter is not
+    let seeds = hash_3d(vec3(SAMPLER.seed, dimension, 0xffffffffu));
+    let bits = hash_3d(vec3(SAMPLER.seed, dimension, SAMPLER.index));
+    let jitter = vec2(bits_to_f32(bits.x), bits_to_f32(bits.y));
+
+    let m = u32(round(sqrt(f32(n))));
+    var stratum: vec2u;
+    var count: vec2u;
+    if m * m == n {
+        stratum = _stratified_stratum(seeds.x, true);
+        count = vec2(m);
+    } else {
+        stratum = vec2(
+            _stratified_stratum(seeds.x, false).x,
+            _stratified_stratum(seeds.y, false).x,
+        );
+        count = vec2(n);
+    }
+    return min((vec2f(stratum) + jitter) / vec2f(count), vec2f(1 - EPSILON / 2));
+}
+
+fn sample_1d() -> f32 {
+    SAMPLER.dimension += 1;
+    let n = max(SAMPLER_DATA.samples, 1);
+    let seed = hash_3d(vec3(SAMPLER.seed, SAMPLER.dimension, 0xffffffffu)).x;
+    let jitter = bits_to_f32(hash_3d(vec3(SAMPLER.seed, SAMPLER.dimension, SAMPLER.index)).x);
+    let stratum = _stratified_stratum(seed, false).x;
+    return min((f32(stratum) + jitter) / f32(n), 1 - EPSILON / 2);
+}
+
+fn sample_2d() -> vec2f {
+    SAMPLER.dimension += 1;
+    return _stratified_sample(SAMPLER.dimension);
+}
+
+fn sample_pixel() -> vec2f {
+    return _stratified_sample(STRATIFIED_PIXEL_DIMENSION);
+}
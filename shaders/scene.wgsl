@@ -1,14 +1,19 @@
 #import /shapes.wgsl
+#import /stats.wgsl
 #import /transform.wgsl
 
 @group(0) @binding(32)
 var<storage> BVH_ROOT: u32;
 @group(0) @binding(33)
-var<storage> BVH_NODES: array<BvhNode>;
+var<storage> BVH_NODES: array<WideBvhNode>;
 @group(0) @binding(34)
 var<storage> TRANSFORM_NODES: array<TransformNode>;
 @group(0) @binding(35)
 var<storage> PRIMITIVE_NODES: array<PrimitiveNode>;
+@group(0) @binding(36)
+var<storage> ANIMATED_TRANSFORMS: array<AnimatedTransform>;
+@group(0) @binding(37)
+var<storage> LEAF_PRIMITIVES: array<u32>;
 
 const NODE_TAG_BITS: u32 = 2;
 const NODE_TAG_SHIFT: u32 = 32 - NODE_TAG_BITS;
@@ -18,21 +23,32 @@ const NODE_TAG_MASK: u32 = ~NODE_IDX_MASK;
 const NODE_BVH: u32 = 0 << NODE_TAG_SHIFT;
 const NODE_TRANSFORM: u32 = 1 << NODE_TAG_SHIFT;
 const NODE_PRIMITIVE: u32 = 2 << NODE_TAG_SHIFT;
+// the first of the leaf's LEAF_PRIMITIVES, then the number of them less one in the lowest bits
+const NODE_LEAF: u32 = 3 << NODE_TAG_SHIFT;
+const LEAF_COUNT_BITS: u32 = 2;
 
 struct NodeId {
     id: u32,
 }
 
-struct BvhNode {
-    min: vec3f,
-    flags: u32,
-    max: vec3f,
-    far_node: NodeId,
+const WIDE_BVH_WIDTH: u32 = 4;
+
+// Children's bounds are a byte per axis and child, as steps of a power of two from `origin`
+struct WideBvhNode {
+    origin: vec3f,
+    // biased exponent of the step on each axis, a byte each
+    exponents: u32,
+    // unused children are last, and ~0
+    children: array<NodeId, WIDE_BVH_WIDTH>,
+    // lower bounds on x, y and z, then upper bounds
+    bounds: array<u32, 6>,
 }
 
 struct TransformNode {
     transform: Transform,
     object: NodeId,
+    // index into ANIMATED_TRANSFORMS, or ~0 if the node doesn't move
+    motion: u32,
 }
 
 struct PrimitiveNode {
@@ -47,15 +63,51 @@ struct TransformStackEntry {
     idx: u32,
 }
 
+// the transform from world to the node's object at `time`, the animated transform following the
+// node's own for nodes that move
+fn transform_node_at(idx: u32, time: f32) -> Transform {
+    let node = TRANSFORM_NODES[idx];
+    if node.motion == ~0u {
+        return node.transform;
+    }
+    let motion = animated_transform_at(ANIMATED_TRANSFORMS[node.motion], time);
+    return Transform(node.transform.m * motion.m_inv, motion.m * node.transform.m_inv);
+}
+
+// the hit with a primitive in the space of the instance it's in, if any
+fn primitive_raycast(idx: u32, ray: Ray, ray_: Ray, max_t: f32) -> RaycastResult {
+    let node = PRIMITIVE_NODES[idx];
+    var result = shape_raycast(node.shape, ray, max_t);
+    if result.hit {
+        let alpha = texture_evaluate(node.alpha, TextureCoords(result.uv, result.p, 0, 0, length(result.edge), result.color), Wavelengths()).x;
+        if alpha < 1 {
+            var h = bitcast<u32>(result.t);
+            h = hash_4d(vec4u(h, bitcast<vec3u>(ray_.o))).w;
+            h = hash_4d(vec4u(h, bitcast<vec3u>(ray_.d))).w;
+            let u = bits_to_f32(h);
+
+            result.hit = u < alpha;
+        }
+    }
+    if result.hit {
+        result.material = node.material;
+        result.light = node.light;
+        result.ids = HitIds(idx, ~0u, ~0u);
+    }
+    return result;
+}
+
 fn scene_raycast(ray_: Ray, max_t: f32) -> RaycastResult {
+    count_stat(STAT_RAYS);
+
     var closest: RaycastResult;
     closest.t = max_t;
 
     var ray = ray_;
     var inv_ray_dir = 1 / ray.d;
-    var mask = u32(ray.d.x < 0) | u32(ray.d.y < 0) << 1 | u32(ray.d.z < 0) << 2;
 
-    var bvh_stack: array<NodeId, 64>;
+    // each BVH node can add a child for every level of the stack it takes
+    var bvh_stack: array<NodeId, 96>;
     var i = 0;
     bvh_stack[0] = NodeId(BVH_ROOT);
 
@@ -69,36 +121,57 @@ fn scene_raycast(ray_: Ray, max_t: f32) -> RaycastResult {
             transform_i -= 1;
             ray = transform_stack[transform_i].old_ray;
             inv_ray_dir = 1 / ray.d;
-            mask = u32(ray.d.x < 0) | u32(ray.d.y < 0) << 1 | u32(ray.d.z < 0) << 2;
             i -= 1;
             continue;
         }
 
         switch bvh_stack[i].id & NODE_TAG_MASK {
             case NODE_BVH {
+                count_stat(STAT_BVH_NODES);
+                bvh_node_visits += 1;
                 let node = BVH_NODES[bvh_stack[i].id];
-                let t0 = (node.min - ray.o) * inv_ray_dir;
-                let t1 = (node.max - ray.o) * inv_ray_dir;
-                let t_near = min(t0, t1);
-                let t_far = max(t0, t1);
-                let t_enter = max(max(t_near.x, t_near.y), t_near.z);
-                let t_exit = min(min(t_far.x, t_far.y), t_far.z);
-
-                if t_enter >= closest.t || t_enter > t_exit || t_exit <= 0 {
-                    i -= 1;
-                } else if (node.far_node.id & NODE_TAG_MASK) == NODE_BVH {
-                    let left = NodeId(bvh_stack[i].id + 1);
-                    if (mask & node.flags) == 0 {
-                        bvh_stack[i] = node.far_node;
-                        bvh_stack[i + 1] = left;
-                    } else {
-                        bvh_stack[i] = left;
-                        bvh_stack[i + 1] = node.far_node;
+                let exponents = vec3u(node.exponents) >> vec3u(0, 8, 16) & vec3u(0xff);
+                let scale = bitcast<vec3f>(exponents << vec3u(23));
+                let lo_bytes = vec3u(node.bounds[0], node.bounds[1], node.bounds[2]);
+                let hi_bytes = vec3u(node.bounds[3], node.bounds[4], node.bounds[5]);
+
+                // the children hit, farthest first
+                var hits: array<NodeId, WIDE_BVH_WIDTH>;
+                var hit_t: array<f32, WIDE_BVH_WIDTH>;
+                var n_hits = 0;
+                for (var c = 0u; c < WIDE_BVH_WIDTH; c++) {
+                    let child = node.children[c];
+                    if child.id == ~0u {
+                        break;
+                    }
+                    let lo = lo_bytes >> vec3u(8 * c) & vec3u(0xff);
+                    let hi = hi_bytes >> vec3u(8 * c) & vec3u(0xff);
+                    let t0 = (node.origin + vec3f(lo) * scale - ray.o) * inv_ray_dir;
+                    let t1 = (node.origin + vec3f(hi) * scale - ray.o) * inv_ray_dir;
+                    let t_near = min(t0, t1);
+                    let t_far = max(t0, t1);
+                    let t_enter = max(max(t_near.x, t_near.y), t_near.z);
+                    let t_exit = min(min(t_far.x, t_far.y), t_far.z);
+
+                    if t_enter >= closest.t || t_enter > t_exit || t_exit <= 0 {
+                        continue;
                     }
-                    i += 1;
-                } else {
-                    bvh_stack[i] = node.far_node;
+                    var j = n_hits;
+                    while j > 0 && hit_t[j - 1] < t_enter {
+                        hits[j] = hits[j - 1];
+                        hit_t[j] = hit_t[j - 1];
+                        j -= 1;
+                    }
+                    hits[j] = child;
+                    hit_t[j] = t_enter;
+                    n_hits += 1;
+                }
+
+                // replaces the node, with the nearest child on top
+                for (var j = 0; j < n_hits; j++) {
+                    bvh_stack[i + j] = hits[j];
                 }
+                i += n_hits - 1;
             }
             case NODE_TRANSFORM {
                 let node = TRANSFORM_NODES[bvh_stack[i].id & NODE_IDX_MASK];
@@ -109,37 +182,44 @@ fn scene_raycast(ray_: Ray, max_t: f32) -> RaycastResult {
                 );
                 transform_i += 1;
 
-                ray = transform_ray(node.transform, ray);
+                ray = transform_ray(transform_node_at(bvh_stack[i].id & NODE_IDX_MASK, ray.time), ray);
                 inv_ray_dir = 1 / ray.d;
-                mask = u32(ray.d.x < 0) | u32(ray.d.y < 0) << 1 | u32(ray.d.z < 0) << 2;
 
                 bvh_stack[i] = NodeId(POP_TRANSFORM_SENTINEL);
                 bvh_stack[i + 1] = node.object;
                 i += 1;
             }
-            case NODE_PRIMITIVE {
-                let node = PRIMITIVE_NODES[bvh_stack[i].id & NODE_IDX_MASK];
-                var result = shape_raycast(node.shape, ray, closest.t);
-                if result.hit {
-                    let alpha = texture_evaluate(node.alpha, result.uv, Wavelengths()).x;
-                    if alpha < 1 {
-                        var h = bitcast<u32>(result.t);
-                        h = hash_4d(vec4u(h, bitcast<vec3u>(ray_.o))).w;
-                        h = hash_4d(vec4u(h, bitcast<vec3u>(ray_.d))).w;
-                        let u = bits_to_f32(h);
-
-                        result.hit = u < alpha;
+            case NODE_PRIMITIVE, NODE_LEAF {
+                let leaf = (bvh_stack[i].id & NODE_TAG_MASK) == NODE_LEAF;
+                var first = bvh_stack[i].id & NODE_IDX_MASK;
+                var count = 1u;
+                if leaf {
+                    count = (first & ((1u << LEAF_COUNT_BITS) - 1)) + 1;
+                    first >>= LEAF_COUNT_BITS;
+                }
+                var hit = false;
+                for (var j = 0u; j < count; j++) {
+                    var prim = first;
+                    if leaf {
+                        prim = LEAF_PRIMITIVES[first + j];
+                    }
+                    let result = primitive_raycast(prim, ray, ray_, closest.t);
+                    if result.hit {
+                        closest = result;
+                        hit = true;
                     }
                 }
-                if result.hit {
-                    closest = result;
-                    closest.material = node.material;
-                    closest.light = node.light;
+                if hit {
+                    if transform_i > 0 {
+                        closest.ids.instance = transform_stack[0].idx;
+                        closest.ids.object = TRANSFORM_NODES[transform_stack[0].idx].object.id;
+                    }
                     for (var j = transform_i; j > 0; j--) {
-                        let t = TRANSFORM_NODES[transform_stack[j - 1].idx].transform;
+                        let t = transform_node_at(transform_stack[j - 1].idx, ray_.time);
                         closest.p = transform_point_inv(t, closest.p);
                         closest.n = transform_normal_inv(t, closest.n);
                         closest.tangent = transform_vector_inv(t, closest.tangent);
+                        closest.edge = transform_vector_inv(t, closest.edge);
                         // todo: transform tangents
                     }
                 }
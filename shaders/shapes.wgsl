@@ -1,19 +1,39 @@
 #import /ray.wgsl
 #import shapes/sphere.wgsl
 #import shapes/triangle.wgsl
+#import shapes/splat.wgsl
+#import shapes/sdf.wgsl
+#import shapes/csg.wgsl
+#import shapes/heightfield.wgsl
 
 @group(0) @binding(0)
 var<storage> SPHERES: array<Sphere>;
 @group(0) @binding(1)
 var<storage> TRIANGLES: array<Triangle>;
+@group(0) @binding(3)
+var<storage> SPLATS: array<Splat>;
+@group(0) @binding(4)
+var<storage> SDFS: array<Sdf>;
+@group(0) @binding(5)
+var<storage> SDF_NODES: array<SdfNode>;
+@group(0) @binding(6)
+var<storage> CSGS: array<Csg>;
+@group(0) @binding(7)
+var<storage> CSG_NODES: array<CsgNode>;
+@group(0) @binding(8)
+var<storage> HEIGHTFIELDS: array<Heightfield>;
 
-const SHAPE_TAG_BITS: u32 = 1;
+const SHAPE_TAG_BITS: u32 = 3;
 const SHAPE_TAG_SHIFT: u32 = 32 - SHAPE_TAG_BITS;
 const SHAPE_IDX_MASK: u32 = (1 << SHAPE_TAG_SHIFT) - 1;
 const SHAPE_TAG_MASK: u32 = ~SHAPE_IDX_MASK;
 
 const SHAPE_SPHERE: u32 = 0 << SHAPE_TAG_SHIFT;
 const SHAPE_TRIANGLE: u32 = 1 << SHAPE_TAG_SHIFT;
+const SHAPE_SPLAT: u32 = 2 << SHAPE_TAG_SHIFT;
+const SHAPE_SDF: u32 = 3 << SHAPE_TAG_SHIFT;
+const SHAPE_CSG: u32 = 4 << SHAPE_TAG_SHIFT;
+const SHAPE_HEIGHTFIELD: u32 = 5 << SHAPE_TAG_SHIFT;
 
 struct ShapeId {
     id: u32
@@ -27,6 +47,18 @@ fn shape_raycast(shape: ShapeId, ray: Ray, t_max: f32) -> RaycastResult {
         case SHAPE_TRIANGLE {
             return triangle_raycast(TRIANGLES[shape.id & SHAPE_IDX_MASK], ray, t_max);
         }
+        case SHAPE_SPLAT {
+            return splat_raycast(SPLATS[shape.id & SHAPE_IDX_MASK], ray, t_max);
+        }
+        case SHAPE_SDF {
+            return sdf_raycast(SDFS[shape.id & SHAPE_IDX_MASK], ray, t_max);
+        }
+        case SHAPE_CSG {
+            return csg_raycast(CSGS[shape.id & SHAPE_IDX_MASK], ray, t_max);
+        }
+        case SHAPE_HEIGHTFIELD {
+            return heightfield_raycast(HEIGHTFIELDS[shape.id & SHAPE_IDX_MASK], ray, t_max);
+        }
         default {
             // unreachable
             return RaycastResult();
@@ -49,6 +81,18 @@ fn shape_sample(shape: ShapeId, ref_p: vec3f, random: vec2f) -> ShapeSample {
         case SHAPE_TRIANGLE {
             return triangle_sample(TRIANGLES[shape.id & SHAPE_IDX_MASK], ref_p, random);
         }
+        case SHAPE_SPLAT {
+            return splat_sample(SPLATS[shape.id & SHAPE_IDX_MASK], ref_p, random);
+        }
+        case SHAPE_SDF {
+            return sdf_sample(SDFS[shape.id & SHAPE_IDX_MASK], ref_p, random);
+        }
+        case SHAPE_CSG {
+            return csg_sample(CSGS[shape.id & SHAPE_IDX_MASK], ref_p, random);
+        }
+        case SHAPE_HEIGHTFIELD {
+            return heightfield_sample(HEIGHTFIELDS[shape.id & SHAPE_IDX_MASK], ref_p, random);
+        }
         default {
             // unreachable
             return ShapeSample();
@@ -64,6 +108,18 @@ fn shape_pdf(shape: ShapeId, ref_p: vec3f, p: vec3f) -> f32 {
         case SHAPE_TRIANGLE {
             return triangle_pdf(TRIANGLES[shape.id & SHAPE_IDX_MASK], ref_p, p);
         }
+        case SHAPE_SPLAT {
+            return splat_pdf(SPLATS[shape.id & SHAPE_IDX_MASK], ref_p, p);
+        }
+        case SHAPE_SDF {
+            return sdf_pdf(SDFS[shape.id & SHAPE_IDX_MASK], ref_p, p);
+        }
+        case SHAPE_CSG {
+            return csg_pdf(CSGS[shape.id & SHAPE_IDX_MASK], ref_p, p);
+        }
+        case SHAPE_HEIGHTFIELD {
+            return heightfield_pdf(HEIGHTFIELDS[shape.id & SHAPE_IDX_MASK], ref_p, p);
+        }
         default {
             // unreachable
             return 0;
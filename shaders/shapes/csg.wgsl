@@ -0,0 +1,272 @@
+#import /util/misc.wgsl
+#import /ray.wgsl
+#import /transform.wgsl
+
+// Constructive solid geometry over quadrics, given by a postfix program of `len` nodes starting
+// at `start`. Each solid is reduced to the spans of the ray inside it, which boolean operators
+// merge, so the ray is never marched.
+struct Csg {
+    min: vec3f,
+    start: u32,
+    max: vec3f,
+    len: u32,
+}
+
+struct CsgNode {
+    op: u32,
+    // from the space of the shape to the unit solid of the leaf
+    transform: Transform,
+}
+
+// unit sphere, cylinder of radius 1 between z = -1 and 1, and the cube [-1, 1]^3
+const CSG_SPHERE: u32 = 0;
+const CSG_CYLINDER: u32 = 1;
+const CSG_BOX: u32 = 2;
+const CSG_UNION: u32 = 3;
+const CSG_INTERSECTION: u32 = 4;
+const CSG_DIFFERENCE: u32 = 5;
+
+const CSG_STACK_SIZE: u32 = 4;
+// spans past this many are dropped, hiding the surfaces behind them
+const CSG_MAX_SPANS: u32 = 4;
+
+// Spans of the ray inside a solid in increasing order. Each endpoint records the surface it lies
+// on as the node index << 3 | face << 1 | whether the normal is flipped.
+struct _CsgSpans {
+    count: u32,
+    t: array<vec2f, CSG_MAX_SPANS>,
+    surface: array<vec2u, CSG_MAX_SPANS>,
+}
+
+fn _csg_leaf(node: CsgNode, index: u32, ray_: Ray) -> _CsgSpans {
+    let ray = transform_ray(node.transform, ray_);
+    let o = ray.o;
+    let d = ray.d;
+
+    var t = vec2f(-FLOAT_MAX, FLOAT_MAX);
+    var face = vec2u(0);
+    switch node.op {
+        case CSG_SPHERE {
+            let a = dot(d, d);
+            let b = dot(o, d);
+            let disc = b * b - a * (dot(o, o) - 1);
+            if disc < 0 {
+                return _CsgSpans();
+            }
+            let s = sqrt(disc);
+            t = vec2f(-b - s, -b + s) / a;
+        }
+        case CSG_CYLINDER {
+            let a = dot(d.xy, d.xy);
+            let b = dot(o.xy, d.xy);
+            let c = dot(o.xy, o.xy) - 1;
+            if a > 0 {
+                let disc = b * b - a * c;
+                if disc < 0 {
+                    return _CsgSpans();
+                }
+                let s = sqrt(disc);
+                t = vec2f(-b - s, -b + s) / a;
+            } else if c > 0 {
+                return _CsgSpans();
+            }
+            // caps are face 1
+            let t0 = (-1 - o.z) / d.z;
+            let t1 = (1 - o.z) / d.z;
+            let near = min(t0, t1);
+            let far = max(t0, t1);
+            if near > t.x {
+                t.x = near;
+                face.x = 1;
+            }
+            if far < t.y {
+                t.y = far;
+                face.y = 1;
+            }
+        }
+        default {
+            // face is the axis of the slab
+            let t0 = (-1 - o) / d;
+            let t1 = (1 - o) / d;
+            let near = min(t0, t1);
+            let far = max(t0, t1);
+            t = vec2f(max(max(near.x, near.y), near.z), min(min(far.x, far.y), far.z));
+            face.x = select(select(2u, 1u, near.y == t.x), 0u, near.x == t.x);
+            face.y = select(select(2u, 1u, far.y == t.y), 0u, far.x == t.y);
+        }
+    }
+
+    if !(t.x <= t.y) {
+        return _CsgSpans();
+    }
+    var spans: _CsgSpans;
+    spans.count = 1;
+    spans.t[0] = t;
+    spans.surface[0] = vec2u(index << 3) | face << vec2u(1);
+    return spans;
+}
+
+fn _csg_combine(op: u32, a: _CsgSpans, b: _CsgSpans) -> _CsgSpans {
+    var result: _CsgSpans;
+    var ia = 0u;
+    var ib = 0u;
+    var in_a = false;
+    var in_b = false;
+    var inside = false;
+    // walk the endpoints of both in order, tracking which solids the ray is in
+    while ia < 2 * a.count || ib < 2 * b.count {
+        var t: f32;
+        var surface: u32;
+        let ta = select(FLOAT_MAX, a.t[ia / 2][ia % 2], ia < 2 * a.count);
+        let tb = select(FLOAT_MAX, b.t[ib / 2][ib % 2], ib < 2 * b.count);
+        if ia < 2 * a.count && (ib == 2 * b.count || ta <= tb) {
+            t = ta;
+            surface = a.surface[ia / 2][ia % 2];
+            in_a = !in_a;
+            ia += 1;
+        } else {
+            t = tb;
+            surface = b.surface[ib / 2][ib % 2];
+            // the surface of a subtracted solid faces into it
+            if op == CSG_DIFFERENCE {
+                surface ^= 1u;
+            }
+            in_b = !in_b;
+            ib += 1;
+        }
+
+        var now: bool;
+        switch op {
+            case CSG_UNION {
+                now = in_a || in_b;
+            }
+            case CSG_INTERSECTION {
+                now = in_a && in_b;
+            }
+            default {
+                now = in_a && !in_b;
+            }
+        }
+        if now == inside {
+            continue;
+        }
+        inside = now;
+        if inside {
+            if result.count == CSG_MAX_SPANS {
+                break;
+            }
+            result.t[result.count].x = t;
+            result.surface[result.count].x = surface;
+        } else {
+            result.t[result.count].y = t;
+            result.surface[result.count].y = surface;
+            result.count += 1;
+        }
+    }
+    return result;
+}
+
+fn csg_raycast(csg: Csg, ray: Ray, t_max: f32) -> RaycastResult {
+    let t0 = (csg.min - ray.o) / ray.d;
+    let t1 = (csg.max - ray.o) / ray.d;
+    let t_enter = max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z));
+    let t_exit = min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z));
+    if t_enter > t_exit || t_exit <= 0 || t_enter > t_max {
+        return RaycastResult();
+    }
+
+    var stack: array<_CsgSpans, CSG_STACK_SIZE>;
+    var top = 0u;
+    for (var i = 0u; i < csg.len; i++) {
+        let node = CSG_NODES[csg.start + i];
+        if node.op <= CSG_BOX {
+            stack[top] = _csg_leaf(node, i, ray);
+            top += 1;
+        } else {
+            top -= 1;
+            stack[top - 1] = _csg_combine(node.op, stack[top - 1], stack[top]);
+        }
+    }
+
+    // first surface in front of the ray
+    var t = 0.0;
+    var surface = 0u;
+    var hit = false;
+    for (var i = 0u; i < stack[0].count && !hit; i++) {
+        for (var j = 0; j < 2; j++) {
+            if stack[0].t[i][j] > 0 {
+                t = stack[0].t[i][j];
+                surface = stack[0].surface[i][j];
+                hit = true;
+                break;
+            }
+        }
+    }
+    if !hit || t > t_max {
+        return RaycastResult();
+    }
+
+    let p = ray.o + ray.d * t;
+    let node = CSG_NODES[csg.start + (surface >> 3)];
+    let face = (surface >> 1) & 3;
+    let lp = transform_point(node.transform, p);
+
+    var n: vec3f;
+    var tangent: vec3f;
+    var uv: vec2f;
+    switch node.op {
+        case CSG_SPHERE {
+            n = lp;
+            tangent = vec3f(-lp.y, lp.x, 0);
+            uv = vec2f(atan2(lp.y, lp.x) / TWO_PI + 0.5, acos(clamp(lp.z, -1, 1)) / PI);
+        }
+        case CSG_CYLINDER {
+            if face == 0 {
+                n = vec3f(lp.xy, 0);
+                tangent = vec3f(-lp.y, lp.x, 0);
+                uv = vec2f(atan2(lp.y, lp.x) / TWO_PI + 0.5, lp.z * 0.5 + 0.5);
+            } else {
+                n = vec3f(0, 0, sign(lp.z));
+                tangent = vec3f(1, 0, 0);
+                uv = lp.xy * 0.5 + 0.5;
+            }
+        }
+        default {
+            n[face] = sign(lp[face]);
+            let u_axis = (face + 1) % 3;
+            tangent[u_axis] = 1;
+            uv = vec2f(lp[u_axis], lp[(face + 2) % 3]) * 0.5 + 0.5;
+        }
+    }
+    // back from the space of the leaf, which transforms normals by the transpose
+    n = normalize((transpose(node.transform.m) * vec4(n, 0)).xyz);
+    if (surface & 1) != 0 {
+        n = -n;
+    }
+    tangent = (node.transform.m_inv * vec4(tangent, 0)).xyz;
+
+    return RaycastResult(
+        true,
+        p,
+        n,
+        n,
+        tangent,
+        t,
+        MaterialId(),
+        LightId(),
+        HitIds(),
+        uv,
+        // no edges
+        vec3f(1e30),
+        vec3f(1),
+        0,
+    );
+}
+
+fn csg_sample(csg: Csg, ref_p: vec3f, random: vec2f) -> ShapeSample {
+    return ShapeSample();
+}
+
+fn csg_pdf(csg: Csg, ref_p: vec3f, p: vec3f) -> f32 {
+    return 0;
+}
@@ -0,0 +1,185 @@
+#import /ray.wgsl
+#import /util/table_sample.wgsl
+#import triangle.wgsl
+
+// Grid of `nx` by `ny` heights covering [0,1]^2, with each cell split into two triangles like
+// pbrt's heightfield. Heights are stored row by row at `heights` in FLOAT_DATA, followed at
+// `blocks` by the minimum and maximum height of each block of cells, which lets the ray skip over
+// blocks it passes above or below.
+struct Heightfield {
+    nx: u32,
+    ny: u32,
+    heights: u32,
+    blocks: u32,
+    min_z: f32,
+    max_z: f32,
+}
+
+const HEIGHTFIELD_BLOCK_SIZE: u32 = 16;
+
+// 2D DDA over a grid with cells of `size` in the cell space of the heightfield
+struct _HeightfieldDda {
+    cell: vec2i,
+    step: vec2i,
+    t_next: vec2f,
+    t_delta: vec2f,
+}
+
+fn _heightfield_dda_init(o: vec2f, d: vec2f, t: f32, size: f32, lo: vec2i, hi: vec2i) -> _HeightfieldDda {
+    let p = o + d * t;
+    var dda: _HeightfieldDda;
+    dda.cell = clamp(vec2i(floor(p / size)), lo, hi);
+    dda.step = vec2i(sign(d));
+    let boundary = (vec2f(dda.cell) + select(vec2f(0), vec2f(1), d > vec2f(0))) * size;
+    dda.t_next = select((boundary - o) / d, vec2f(1e30), d == vec2f(0));
+    dda.t_delta = select(size / abs(d), vec2f(1e30), d == vec2f(0));
+    return dda;
+}
+
+fn _heightfield_dda_advance(dda: ptr<function, _HeightfieldDda>) {
+    if (*dda).t_next.x < (*dda).t_next.y {
+        (*dda).cell.x += (*dda).step.x;
+        (*dda).t_next.x += (*dda).t_delta.x;
+    } else {
+        (*dda).cell.y += (*dda).step.y;
+        (*dda).t_next.y += (*dda).t_delta.y;
+    }
+}
+
+fn _heightfield_point(hf: Heightfield, cell: vec2i) -> vec3f {
+    let cells = vec2f(f32(hf.nx - 1), f32(hf.ny - 1));
+    let z = FLOAT_DATA[hf.heights + u32(cell.y) * hf.nx + u32(cell.x)];
+    return vec3f(vec2f(cell) / cells, z);
+}
+
+// smooth normal at a grid point from central differences of the heights
+fn _heightfield_vertex_normal(hf: Heightfield, cell: vec2i) -> vec3f {
+    let hi = vec2i(i32(hf.nx) - 1, i32(hf.ny) - 1);
+    let x0 = _heightfield_point(hf, vec2i(max(cell.x - 1, 0), cell.y));
+    let x1 = _heightfield_point(hf, vec2i(min(cell.x + 1, hi.x), cell.y));
+    let y0 = _heightfield_point(hf, vec2i(cell.x, max(cell.y - 1, 0)));
+    let y1 = _heightfield_point(hf, vec2i(cell.x, min(cell.y + 1, hi.y)));
+    return normalize(cross(x1 - x0, y1 - y0));
+}
+
+fn heightfield_raycast(hf: Heightfield, ray: Ray, t_max: f32) -> RaycastResult {
+    let t0 = (vec3f(0, 0, hf.min_z) - ray.o) / ray.d;
+    let t1 = (vec3f(1, 1, hf.max_z) - ray.o) / ray.d;
+    let t_enter = max(max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z)), 0);
+    let t_exit = min(min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z)), t_max);
+    if t_enter > t_exit {
+        return RaycastResult();
+    }
+
+    // traverse in cell space, where cells are unit squares
+    let cells = vec2i(i32(hf.nx) - 1, i32(hf.ny) - 1);
+    let o = ray.o.xy * vec2f(cells);
+    let d = ray.d.xy * vec2f(cells);
+    let block_size = i32(HEIGHTFIELD_BLOCK_SIZE);
+    let blocks = (cells + block_size - 1) / block_size;
+
+    var best = TriHit();
+    var best_cell: vec2i;
+    var best_upper = false;
+    var t_best = t_exit;
+
+    var block = _heightfield_dda_init(o, d, t_enter, f32(block_size), vec2i(0), blocks - 1);
+    var t_block = t_enter;
+    for (var i = 0; i < blocks.x + blocks.y; i++) {
+        let t_block_exit = min(min(block.t_next.x, block.t_next.y), t_exit);
+
+        // skip blocks whose height range the ray does not pass through
+        let range_ptr = hf.blocks + 2 * u32(block.cell.y * blocks.x + block.cell.x);
+        let za = ray.o.z + ray.d.z * t_block;
+        let zb = ray.o.z + ray.d.z * t_block_exit;
+        if max(za, zb) >= FLOAT_DATA[range_ptr] && min(za, zb) <= FLOAT_DATA[range_ptr + 1] {
+            let lo = block.cell * block_size;
+            let hi = min(lo + block_size, cells) - 1;
+            var cell = _heightfield_dda_init(o, d, t_block, 1, lo, hi);
+            for (var j = 0; j < 2 * block_size; j++) {
+                let p00 = _heightfield_point(hf, cell.cell);
+                let p10 = _heightfield_point(hf, cell.cell + vec2i(1, 0));
+                let p01 = _heightfield_point(hf, cell.cell + vec2i(0, 1));
+                let p11 = _heightfield_point(hf, cell.cell + vec2i(1, 1));
+                let lower = triangle_hit(p00, p10, p11, ray, t_best);
+                if lower.hit {
+                    best = lower;
+                    best_cell = cell.cell;
+                    best_upper = false;
+                    t_best = lower.t;
+                }
+                let upper = triangle_hit(p00, p11, p01, ray, t_best);
+                if upper.hit {
+                    best = upper;
+                    best_cell = cell.cell;
+                    best_upper = true;
+                    t_best = upper.t;
+                }
+                // the triangles are inside the cell, so the first cell with a hit has the closest
+                if best.hit || min(cell.t_next.x, cell.t_next.y) >= t_block_exit {
+                    break;
+                }
+                _heightfield_dda_advance(&cell);
+                if any(cell.cell < lo) || any(cell.cell > hi) {
+                    break;
+                }
+            }
+            if best.hit {
+                break;
+            }
+        }
+
+        if t_block_exit >= t_exit {
+            break;
+        }
+        _heightfield_dda_advance(&block);
+        t_block = t_block_exit;
+        if any(block.cell < vec2i(0)) || any(block.cell >= blocks) {
+            break;
+        }
+    }
+    if !best.hit {
+        return RaycastResult();
+    }
+
+    let c0 = best_cell;
+    let c1 = select(best_cell + vec2i(1, 0), best_cell + vec2i(1, 1), best_upper);
+    let c2 = select(best_cell + vec2i(1, 1), best_cell + vec2i(0, 1), best_upper);
+    let v0 = _heightfield_point(hf, c0);
+    let v1 = _heightfield_point(hf, c1);
+    let v2 = _heightfield_point(hf, c2);
+
+    let p = best.b.x * v0 + best.b.y * v1 + best.b.z * v2;
+    let ng = normalize(cross(v1 - v0, v2 - v0));
+    var n = normalize(
+        best.b.x * _heightfield_vertex_normal(hf, c0)
+        + best.b.y * _heightfield_vertex_normal(hf, c1)
+        + best.b.z * _heightfield_vertex_normal(hf, c2)
+    );
+    if any(n != n) {
+        n = ng;
+    }
+
+    // uv is the position on the grid, so dp/du lies along x in the triangle's plane
+    let tangent = vec3f(1, 0, -ng.x / ng.z);
+
+    var edge = triangle_edge_offset(p, v0, v1);
+    let e12 = triangle_edge_offset(p, v1, v2);
+    if dot(e12, e12) < dot(edge, edge) {
+        edge = e12;
+    }
+    let e20 = triangle_edge_offset(p, v2, v0);
+    if dot(e20, e20) < dot(edge, edge) {
+        edge = e20;
+    }
+
+    return RaycastResult(true, p, n, ng, tangent, best.t, MaterialId(), LightId(), HitIds(), p.xy, edge, vec3f(1), 0);
+}
+
+fn heightfield_sample(hf: Heightfield, ref_p: vec3f, random: vec2f) -> ShapeSample {
+    return ShapeSample();
+}
+
+fn heightfield_pdf(hf: Heightfield, ref_p: vec3f, p: vec3f) -> f32 {
+    return 0;
+}
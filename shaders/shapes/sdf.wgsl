@@ -0,0 +1,184 @@
+#import /util/misc.wgsl
+#import /ray.wgsl
+
+// Signed distance function given by a postfix program of `len` nodes starting at `start`,
+// intersected by sphere tracing. Primitives push their distance and operators combine the top
+// two entries of the stack, with smooth blending when `smoothness` is non-zero.
+struct Sdf {
+    min: vec3f,
+    start: u32,
+    max: vec3f,
+    len: u32,
+}
+
+struct SdfNode {
+    op: u32,
+    smoothness: f32,
+    // sphere: center, radius
+    // box: center, rounding, then half extents
+    // torus: center, then major and minor radii, around the z axis
+    a: vec4f,
+    b: vec4f,
+}
+
+const SDF_SPHERE: u32 = 0;
+const SDF_BOX: u32 = 1;
+const SDF_TORUS: u32 = 2;
+const SDF_UNION: u32 = 3;
+const SDF_INTERSECTION: u32 = 4;
+const SDF_SUBTRACTION: u32 = 5;
+
+const SDF_STACK_SIZE: u32 = 8;
+const SDF_MAX_STEPS: u32 = 256;
+
+// polynomial smooth minimum, which is at most smoothness / 4 below the regular minimum
+fn _sdf_smooth_min(a: f32, b: f32, smoothness: f32) -> f32 {
+    if smoothness <= 0 {
+        return min(a, b);
+    }
+    let h = max(smoothness - abs(a - b), 0) / smoothness;
+    return min(a, b) - h * h * smoothness * 0.25;
+}
+
+fn sdf_distance(sdf: Sdf, p: vec3f) -> f32 {
+    var stack: array<f32, SDF_STACK_SIZE>;
+    var top = 0u;
+    for (var i = sdf.start; i < sdf.start + sdf.len; i++) {
+        let node = SDF_NODES[i];
+        switch node.op {
+            case SDF_SPHERE {
+                stack[top] = length(p - node.a.xyz) - node.a.w;
+                top += 1;
+            }
+            case SDF_BOX {
+                let q = abs(p - node.a.xyz) - node.b.xyz + node.a.w;
+                stack[top] = length(max(q, vec3f())) + min(max(q.x, max(q.y, q.z)), 0) - node.a.w;
+                top += 1;
+            }
+            case SDF_TORUS {
+                let q = p - node.a.xyz;
+                stack[top] = length(vec2f(length(q.xy) - node.b.x, q.z)) - node.b.y;
+                top += 1;
+            }
+            default {
+                top -= 1;
+                let a = stack[top - 1];
+                let b = stack[top];
+                let k = node.smoothness;
+                switch node.op {
+                    case SDF_UNION {
+                        stack[top - 1] = _sdf_smooth_min(a, b, k);
+                    }
+                    case SDF_INTERSECTION {
+                        stack[top - 1] = -_sdf_smooth_min(-a, -b, k);
+                    }
+                    default {
+                        stack[top - 1] = -_sdf_smooth_min(-a, b, k);
+                    }
+                }
+            }
+        }
+    }
+    return stack[0];
+}
+
+fn sdf_normal(sdf: Sdf, p: vec3f, h: f32) -> vec3f {
+    // tetrahedral central differences
+    let k = vec2f(1, -1);
+    return normalize(
+        k.xyy * sdf_distance(sdf, p + k.xyy * h)
+        + k.yyx * sdf_distance(sdf, p + k.yyx * h)
+        + k.yxy * sdf_distance(sdf, p + k.yxy * h)
+        + k.xxx * sdf_distance(sdf, p + k.xxx * h)
+    );
+}
+
+fn sdf_raycast(sdf: Sdf, ray: Ray, t_max: f32) -> RaycastResult {
+    let t0 = (sdf.min - ray.o) / ray.d;
+    let t1 = (sdf.max - ray.o) / ray.d;
+    let t_enter = max(max(max(min(t0.x, t1.x), min(t0.y, t1.y)), min(t0.z, t1.z)), 0);
+    let t_exit = min(min(min(max(t0.x, t1.x), max(t0.y, t1.y)), max(t0.z, t1.z)), t_max);
+    if t_enter > t_exit {
+        return RaycastResult();
+    }
+
+    let inv_len = 1 / length(ray.d);
+    let eps = 1e-5 * length(sdf.max - sdf.min);
+
+    // which side of the surface the ray starts on, so rays continuing from a hit find the next
+    // crossing instead of the surface they left
+    var t = t_enter;
+    let side = select(1.0, -1.0, sdf_distance(sdf, ray.o + ray.d * t) < 0);
+    var t_prev = t;
+    var crossed = false;
+    for (var i = 0u; i < SDF_MAX_STEPS; i++) {
+        let d = side * sdf_distance(sdf, ray.o + ray.d * t);
+        if d < 0 {
+            crossed = true;
+            break;
+        }
+        if t >= t_exit {
+            break;
+        }
+        t_prev = t;
+        t = min(t + max(d, eps) * inv_len, t_exit);
+    }
+    if !crossed {
+        return RaycastResult();
+    }
+
+    // bisect the last step, which overshot the surface by at most eps
+    var lo = t_prev;
+    var hi = t;
+    for (var i = 0; i < 8; i++) {
+        let mid = (lo + hi) / 2;
+        if side * sdf_distance(sdf, ray.o + ray.d * mid) < 0 {
+            hi = mid;
+        } else {
+            lo = mid;
+        }
+    }
+    let t_hit = hi;
+    let p = ray.o + ray.d * t_hit;
+    let n = sdf_normal(sdf, p, eps);
+
+    // box projection along the dominant axis of the normal
+    let axis = abs(n);
+    var uv: vec2f;
+    var tangent: vec3f;
+    if axis.x >= axis.y && axis.x >= axis.z {
+        uv = p.yz;
+        tangent = vec3f(0, 1, 0);
+    } else if axis.y >= axis.z {
+        uv = p.zx;
+        tangent = vec3f(0, 0, 1);
+    } else {
+        uv = p.xy;
+        tangent = vec3f(1, 0, 0);
+    }
+
+    return RaycastResult(
+        true,
+        p,
+        n,
+        n,
+        tangent,
+        t_hit,
+        MaterialId(),
+        LightId(),
+        HitIds(),
+        uv,
+        // no edges
+        vec3f(1e30),
+        vec3f(1),
+        0,
+    );
+}
+
+fn sdf_sample(sdf: Sdf, ref_p: vec3f, random: vec2f) -> ShapeSample {
+    return ShapeSample();
+}
+
+fn sdf_pdf(sdf: Sdf, ref_p: vec3f, p: vec3f) -> f32 {
+    return 0;
+}
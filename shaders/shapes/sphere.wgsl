@@ -77,7 +77,12 @@ fn sphere_raycast(sphere: Sphere, ray: Ray, t_max: f32) -> RaycastResult {
         hit.t,
         MaterialId(),
         LightId(),
+        HitIds(),
         vec2f(),
+        // no edges
+        vec3f(1e30),
+        vec3f(1),
+        0,
     );
 }
 
@@ -0,0 +1,85 @@
+#import /util/misc.wgsl
+#import /util/distr.wgsl
+#import /ray.wgsl
+
+// Disc of a point cloud. Splats without a normal always face the incoming ray.
+struct Splat {
+    p: vec3f,
+    radius: f32,
+    n: vec3f,
+    // sRGB encoded, packed as 8-bit unorm
+    color: u32,
+}
+
+fn splat_color(splat: Splat) -> vec3f {
+    let srgb = unpack4x8unorm(splat.color).xyz;
+    let low = srgb / 12.92;
+    let high = pow((srgb + 0.055) / 1.055, vec3f(2.4));
+    return select(high, low, srgb <= vec3f(0.04045));
+}
+
+fn splat_normal(splat: Splat, d: vec3f) -> vec3f {
+    if all(splat.n == vec3f()) {
+        return -normalize(d);
+    }
+    return splat.n;
+}
+
+fn splat_raycast(splat: Splat, ray: Ray, t_max: f32) -> RaycastResult {
+    let n = splat_normal(splat, ray.d);
+    let denom = dot(ray.d, n);
+    if denom == 0 {
+        return RaycastResult();
+    }
+    let t = dot(splat.p - ray.o, n) / denom;
+    if t <= 0 || t > t_max {
+        return RaycastResult();
+    }
+
+    let p = ray.o + ray.d * t;
+    let offset = p - splat.p;
+    let r = length(offset);
+    if r > splat.radius {
+        return RaycastResult();
+    }
+
+    let frame = any_orthonormal_frame(n);
+    let uv = vec2f(dot(offset, frame[0]), dot(offset, frame[1])) / (2 * splat.radius) + 0.5;
+    // the rim is the only edge
+    let edge = select(frame[0], offset / r, r > 0) * (splat.radius - r);
+
+    return RaycastResult(
+        true,
+        p,
+        n,
+        n,
+        frame[0] * 2 * splat.radius,
+        t,
+        MaterialId(),
+        LightId(),
+        HitIds(),
+        uv,
+        edge,
+        splat_color(splat),
+        0,
+    );
+}
+
+fn splat_sample(splat: Splat, ref_p: vec3f, random: vec2f) -> ShapeSample {
+    // facing splats change with the viewer, so they can't be sampled by area
+    if all(splat.n == vec3f()) {
+        return ShapeSample();
+    }
+    let frame = any_orthonormal_frame(splat.n);
+    let d = sample_uniform_disk(random) * splat.radius;
+    let p = splat.p + d.x * frame[0] + d.y * frame[1];
+    let uv = d / (2 * splat.radius) + 0.5;
+    return ShapeSample(p, splat.n, uv, 1 / (PI * splat.radius * splat.radius));
+}
+
+fn splat_pdf(splat: Splat, ref_p: vec3f, p: vec3f) -> f32 {
+    if all(splat.n == vec3f()) {
+        return 0;
+    }
+    return 1 / (PI * splat.radius * splat.radius);
+}
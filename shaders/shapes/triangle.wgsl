@@ -1,7 +1,7 @@
 #import /ray.wgsl
-
-@group(0) @binding(2)
-var<storage> TRI_VERTICES: array<TriVertex>;
+#import /stats.wgsl
+#importif geometry full triangle/full.wgsl
+#importif geometry packed triangle/packed.wgsl
 
 struct Triangle {
     v0: u32,
@@ -56,9 +56,11 @@ fn triangle_hit(v0: vec3f, v1: vec3f, v2: vec3f, ray: Ray, t_max: f32) -> TriHit
 }
 
 fn triangle_raycast(tri: Triangle, ray: Ray, t_max: f32) -> RaycastResult {
-    let v0 = TRI_VERTICES[tri.v0];
-    let v1 = TRI_VERTICES[tri.v1];
-    let v2 = TRI_VERTICES[tri.v2];
+    count_stat(STAT_TRIANGLE_TESTS);
+
+    let v0 = triangle_vertex(tri.v0);
+    let v1 = triangle_vertex(tri.v1);
+    let v2 = triangle_vertex(tri.v2);
 
     let hit = triangle_hit(v0.p, v1.p, v2.p, ray, t_max);
     if !hit.hit {
@@ -97,7 +99,24 @@ fn triangle_raycast(tri: Triangle, ray: Ray, t_max: f32) -> RaycastResult {
         tangent = (duv12.y * (v0.p - v2.p) - duv02.y * (v1.p - v2.p)) / det;
     }
 
-    return RaycastResult(true, p, n_shade, n_geo, tangent, hit.t, MaterialId(), LightId(), uv);
+    var edge = triangle_edge_offset(p, v0.p, v1.p);
+    let e12 = triangle_edge_offset(p, v1.p, v2.p);
+    if dot(e12, e12) < dot(edge, edge) {
+        edge = e12;
+    }
+    let e20 = triangle_edge_offset(p, v2.p, v0.p);
+    if dot(e20, e20) < dot(edge, edge) {
+        edge = e20;
+    }
+
+    return RaycastResult(true, p, n_shade, n_geo, tangent, hit.t, MaterialId(), LightId(), HitIds(), uv, edge, vec3f(1), 0);
+}
+
+// offset from p to the closest point on the segment from a to b
+fn triangle_edge_offset(p: vec3f, a: vec3f, b: vec3f) -> vec3f {
+    let e = b - a;
+    let t = saturate(dot(p - a, e) / max(dot(e, e), 1.0e-30));
+    return a + t * e - p;
 }
 
 fn edge_function(p0: vec3f, p1: vec3f) -> f32 {
@@ -151,9 +170,9 @@ fn triangle_ray_transform(ray: Ray) -> mat4x4f {
 }
 
 fn triangle_sample(tri: Triangle, ref_p: vec3f, random: vec2f) -> ShapeSample {
-    let v0 = TRI_VERTICES[tri.v0];
-    let v1 = TRI_VERTICES[tri.v1];
-    let v2 = TRI_VERTICES[tri.v2];
+    let v0 = triangle_vertex(tri.v0);
+    let v1 = triangle_vertex(tri.v1);
+    let v2 = triangle_vertex(tri.v2);
 
     // better distribution than mirroring across y=1-x wrt low-discrepancy sampling (via pbr-book)
     var b: vec3f;
@@ -193,9 +212,9 @@ fn triangle_sample(tri: Triangle, ref_p: vec3f, random: vec2f) -> ShapeSample {
 }
 
 fn triangle_pdf(tri: Triangle, ref_p: vec3f, p: vec3f) -> f32 {
-    let v0 = TRI_VERTICES[tri.v0];
-    let v1 = TRI_VERTICES[tri.v1];
-    let v2 = TRI_VERTICES[tri.v2];
+    let v0 = triangle_vertex(tri.v0);
+    let v1 = triangle_vertex(tri.v1);
+    let v2 = triangle_vertex(tri.v2);
 
     let d = cross(v1.p - v0.p, v2.p - v0.p);
     let area = length(d) / 2;
@@ -0,0 +1,6 @@
+@group(0) @binding(2)
+var<storage> TRI_VERTICES: array<TriVertex>;
+
+fn triangle_vertex(idx: u32) -> TriVertex {
+    return TRI_VERTICES[idx];
+}
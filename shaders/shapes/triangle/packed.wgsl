@@ -0,0 +1,49 @@
+@group(0) @binding(2)
+var<storage> TRI_VERTICES: array<PackedVertex>;
+@group(0) @binding(9)
+var<storage> POSITION_GRIDS: array<PositionGrid>;
+
+// octahedral normals never use -32768, which snorm16 reads as -1 too
+const NO_NORMAL: u32 = 0x80008000;
+
+// The position as steps of its mesh's grid, the normal as a point on an octahedron and the texture
+// coordinates as half floats
+struct PackedVertex {
+    // x steps, then y steps in the upper half
+    xy: u32,
+    // z steps, then the index of the grid in the upper half
+    z_grid: u32,
+    // two snorm16, or NO_NORMAL
+    n: u32,
+    uv: u32,
+}
+
+struct PositionGrid {
+    origin: vec3f,
+    first_vertex: u32,
+    step: vec3f,
+}
+
+fn triangle_vertex(idx: u32) -> TriVertex {
+    let packed = TRI_VERTICES[idx];
+    let grid = POSITION_GRIDS[packed.z_grid >> 16];
+    let steps = vec3u(packed.xy, packed.xy >> 16, packed.z_grid) & vec3u(0xffff);
+    // exact, as the step is a power of two no finer than the spacing of floats at the mesh
+    let p = grid.origin + vec3f(steps) * grid.step;
+    let uv = unpack2x16float(packed.uv);
+    return TriVertex(p, uv.x, unpack_normal(packed.n), uv.y);
+}
+
+fn unpack_normal(bits: u32) -> vec3f {
+    if bits == NO_NORMAL {
+        return vec3f();
+    }
+    let p = unpack2x16snorm(bits);
+    let z = 1 - abs(p.x) - abs(p.y);
+    if z < 0 {
+        // the lower half folds over the diagonals
+        let sign = select(vec2f(-1), vec2f(1), p >= vec2f());
+        return normalize(vec3f((1 - abs(p.yx)) * sign, z));
+    }
+    return normalize(vec3f(p, z));
+}
@@ -100,7 +100,8 @@ fn spectrum_sample(spectrum: SpectrumId, wl: Wavelengths) -> vec4f {
 }
 
 fn spectrum_table_sample(idx: u32, wl: Wavelengths) -> vec4f {
-    let i = vec4u(wl.l - WAVELENGTH_MIN);
+    // tables are extended with their end values outside the visible range
+    let i = vec4u(clamp(wl.l, vec4f(WAVELENGTH_MIN), vec4f(WAVELENGTH_MAX - 1)) - WAVELENGTH_MIN);
     return vec4(
         TABLE_SPECTRA[idx].data[i.x],
         TABLE_SPECTRA[idx].data[i.y],
@@ -112,7 +113,7 @@ fn spectrum_table_sample(idx: u32, wl: Wavelengths) -> vec4f {
 fn spectrum_rgb_albedo_sample(spectrum: RgbAlbedoSpectrum, wl: Wavelengths) -> vec4f {
     let coeffs = textureSampleLevel(RGB_TO_COEFF, LINEAR_FILTER_CLAMP, spectrum.rgb, 0);
 
-    let l = (wl.l - WAVELENGTH_MIN) / (WAVELENGTH_MAX - WAVELENGTH_MIN);
+    let l = saturate((wl.l - WAVELENGTH_MIN) / (WAVELENGTH_MAX - WAVELENGTH_MIN));
     let poly = coeffs.x * l * l + coeffs.y * l + coeffs.z;
 
     return 0.5 + poly / (2 * sqrt(1 + poly * poly));
@@ -0,0 +1,16 @@
+// Counts of the work rendering takes, for --render-stats. Each invocation tallies its own and
+// `stats_flush` adds them to the totals once it's done. Both do nothing unless the `stats` flag is
+// on.
+#importif stats on stats/on.wgsl
+#importif stats off stats/off.wgsl
+
+const STAT_RAYS: u32 = 0;
+const STAT_BVH_NODES: u32 = 1;
+const STAT_TRIANGLE_TESTS: u32 = 2;
+const STAT_SHADOW_RAYS: u32 = 3;
+const STAT_TEXTURE_LOOKUPS: u32 = 4;
+const STAT_COUNT: u32 = 5;
+
+// BVH nodes this invocation has visited, for the debug-bvh integrator whatever the `stats` flag.
+// It's never read otherwise, so the compiler can drop it.
+var<private> bvh_node_visits: u32;
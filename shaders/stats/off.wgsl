@@ -0,0 +1,3 @@
+fn count_stat(stat: u32) {}
+
+fn stats_flush() {}
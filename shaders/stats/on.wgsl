@@ -0,0 +1,22 @@
+var<private> stat_counts: array<u32, STAT_COUNT>;
+
+fn count_stat(stat: u32) {
+    stat_counts[stat] += 1;
+}
+
+// the totals as 64-bit counts, low word first
+@group(1) @binding(21)
+var<storage, read_write> RENDER_STATS: array<atomic<u32>, 2 * STAT_COUNT>;
+
+fn stats_flush() {
+    for (var i = 0u; i < STAT_COUNT; i++) {
+        let n = stat_counts[i];
+        if n == 0 {
+            continue;
+        }
+        let old = atomicAdd(&RENDER_STATS[2 * i], n);
+        if old + n < old {
+            atomicAdd(&RENDER_STATS[2 * i + 1], 1u);
+        }
+    }
+}
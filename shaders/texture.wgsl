@@ -1,21 +1,26 @@
 #import /spectrum.wgsl
+#import /stats.wgsl
+#import /util/misc.wgsl
 
 struct TextureId {
     id: u32
 }
 
-const TEXTURE_TAG_BITS: u32 = 3;
+const TEXTURE_TAG_BITS: u32 = 4;
 const TEXTURE_TAG_SHIFT: u32 = 32 - TEXTURE_TAG_BITS;
 const TEXTURE_IDX_MASK: u32 = (1 << TEXTURE_TAG_SHIFT) - 1;
 const TEXTURE_TAG_MASK: u32 = ~TEXTURE_IDX_MASK;
 
 const TEXTURE_CONSTANT: u32 = 0 << TEXTURE_TAG_SHIFT;
+const TEXTURE_WIREFRAME: u32 = 1 << TEXTURE_TAG_SHIFT;
 const TEXTURE_IMAGE_FLOAT: u32 = 2 << TEXTURE_TAG_SHIFT;
 const TEXTURE_IMAGE_RGB: u32 = 3 << TEXTURE_TAG_SHIFT;
 const TEXTURE_SCALE: u32 = 4 << TEXTURE_TAG_SHIFT;
 const TEXTURE_MIX: u32 = 5 << TEXTURE_TAG_SHIFT;
 const TEXTURE_CHECKERBOARD: u32 = 6 << TEXTURE_TAG_SHIFT;
 const TEXTURE_CONDUCTOR_REFL: u32 = 7 << TEXTURE_TAG_SHIFT;
+const TEXTURE_UV_CHECKER: u32 = 8 << TEXTURE_TAG_SHIFT;
+const TEXTURE_VERTEX_COLOR: u32 = 9 << TEXTURE_TAG_SHIFT;
 
 @group(0) @binding(64)
 var<storage> CONSTANT_TEXTURES: array<ConstantTexture>;
@@ -33,6 +38,10 @@ var<storage> MIX_TEXTURES: array<MixTexture>;
 var<storage> CHECKERBOARD_TEXTURES: array<CheckerboardTexture>;
 @group(0) @binding(72)
 var<storage> CONDUCTOR_REFL_TEXTURES: array<ConductorReflTexture>;
+@group(0) @binding(73)
+var<storage> WIREFRAME_TEXTURES: array<WireframeTexture>;
+@group(0) @binding(74)
+var<storage> UV_CHECKER_TEXTURES: array<UvCheckerTexture>;
 
 @group(1) @binding(25)
 var LINEAR_FILTER_WRAP: sampler;
@@ -45,14 +54,16 @@ struct ImageRgbTexture {
     image_index: u32,
     scale: f32,
     invert: u32,
-    uvmap: UvMappingParams,
+    wrap: u32,
+    mapping: TextureMapping,
 }
 
 struct ImageFloatTexture {
     image_index: u32,
     scale: f32,
     invert: u32,
-    uvmap: UvMappingParams,
+    wrap: u32,
+    mapping: TextureMapping,
 }
 
 struct ScaleTexture {
@@ -69,19 +80,56 @@ struct MixTexture {
 struct CheckerboardTexture {
     even: TextureId,
     odd: TextureId,
-    uvmap: UvMappingParams,
+    mapping: TextureMapping,
 }
 
 struct ConductorReflTexture {
     tex: TextureId,
 }
 
-struct UvMappingParams {
+struct WireframeTexture {
+    wire: TextureId,
+    fill: TextureId,
+    width: f32,
+    pixel_width: f32,
+}
+
+struct UvCheckerTexture {
+    cells: f32,
+    mapping: TextureMapping,
+}
+
+const TEXTURE_WRAP_REPEAT: u32 = 0;
+const TEXTURE_WRAP_CLAMP: u32 = 1;
+const TEXTURE_WRAP_BLACK: u32 = 2;
+
+const TEXTURE_MAPPING_UV: u32 = 0;
+const TEXTURE_MAPPING_SPHERICAL: u32 = 1;
+const TEXTURE_MAPPING_CYLINDRICAL: u32 = 2;
+const TEXTURE_MAPPING_PLANAR: u32 = 3;
+
+struct TextureMapping {
+    world_to_texture: mat4x4f,
+    v1: vec3f,
+    ty: u32,
+    v2: vec3f,
     scale: vec2f,
     delta: vec2f,
 }
 
-fn texture_evaluate(texture_id: TextureId, uv: vec2f, wl: Wavelengths) -> vec4f {
+struct TextureCoords {
+    uv: vec2f,
+    p: vec3f,
+    // filter footprint in world space and in uv space, zero to sample the full resolution image
+    width: f32,
+    uv_width: f32,
+    // distance to the closest triangle edge in world space
+    edge_distance: f32,
+    // per-point color of splats
+    color: vec3f,
+}
+
+fn texture_evaluate(texture_id: TextureId, tc: TextureCoords, wl: Wavelengths) -> vec4f {
     var tex_stack: array<TextureId, 8>;
     var data: array<vec4f, 8>;
 
@@ -102,22 +150,20 @@ fn texture_evaluate(texture_id: TextureId, uv: vec2f, wl: Wavelengths) -> vec4f
                 }
                 case TEXTURE_IMAGE_FLOAT {
                     let tex = IMAGE_FLOAT_TEXTURES[idx];
-                    let mapped = uv_map(tex.uvmap, uv);
-                    var value = textureSampleLevel(
-                        IMAGES[tex.image_index], LINEAR_FILTER_WRAP, vec2(mapped.x, 1 - mapped.y), 0
-                    ).x * tex.scale;
+                    let mapped = texture_map(tex.mapping, tc);
+                    let width = texture_map_width(tex.mapping, tc);
+                    var value = texture_image_sample(tex.image_index, tex.wrap, mapped, width).x * tex.scale;
                     if tex.invert != 0 {
-                        value = 1 - value;
+                        value = max(0, 1 - value);
                     }
                     data[data_i] = vec4f(value);
                     data_i++;
                 }
                 case TEXTURE_IMAGE_RGB {
                     let tex = IMAGE_RGB_TEXTURES[idx];
-                    let mapped = uv_map(tex.uvmap, uv);
-                    var rgb = textureSampleLevel(
-                        IMAGES[tex.image_index], LINEAR_FILTER_WRAP, vec2(mapped.x, 1 - mapped.y), 0
-                    ).xyz * tex.scale;
+                    let mapped = texture_map(tex.mapping, tc);
+                    let width = texture_map_width(tex.mapping, tc);
+                    var rgb = texture_image_sample(tex.image_index, tex.wrap, mapped, width).xyz * tex.scale;
                     if tex.invert != 0 {
                         rgb = max(vec3f(), vec3f(1) - rgb);
                     }
@@ -145,7 +191,7 @@ fn texture_evaluate(texture_id: TextureId, uv: vec2f, wl: Wavelengths) -> vec4f
                     tex_i++;
                 }
                 case TEXTURE_CHECKERBOARD {
-                    let mapped = vec2i(floor(uv_map(CHECKERBOARD_TEXTURES[idx].uvmap, uv)));
+                    let mapped = vec2i(floor(texture_map(CHECKERBOARD_TEXTURES[idx].mapping, tc)));
                     let odd = (mapped.x + mapped.y) % 2 != 0;
                     if odd {
                         tex_stack[tex_i] = CHECKERBOARD_TEXTURES[idx].odd;
@@ -154,6 +200,32 @@ fn texture_evaluate(texture_id: TextureId, uv: vec2f, wl: Wavelengths) -> vec4f
                     }
                     tex_i++;
                 }
+                case TEXTURE_WIREFRAME {
+                    let tex = WIREFRAME_TEXTURES[idx];
+                    // each of the two triangles sharing an edge draws half of the line
+                    let width = max(tex.width, tex.pixel_width * tc.width);
+                    if tc.edge_distance < width / 2 {
+                        tex_stack[tex_i] = tex.wire;
+                    } else {
+                        tex_stack[tex_i] = tex.fill;
+                    }
+                    tex_i++;
+                }
+                case TEXTURE_UV_CHECKER {
+                    let tex = UV_CHECKER_TEXTURES[idx];
+                    let st = texture_map(tex.mapping, tc);
+                    let cell = vec2i(floor(st * tex.cells));
+                    let odd = (cell.x + cell.y) % 2 != 0;
+                    // red increases along u and green along v, showing orientation and seams
+                    let f = fract(st);
+                    let rgb = mix(vec3f(0.1), vec3f(0.9), vec3f(f, 0.5)) * select(1, 0.5, odd);
+                    data[data_i] = spectrum_rgb_albedo_sample(RgbAlbedoSpectrum(rgb), wl);
+                    data_i++;
+                }
+                case TEXTURE_VERTEX_COLOR {
+                    data[data_i] = spectrum_rgb_albedo_sample(RgbAlbedoSpectrum(tc.color), wl);
+                    data_i++;
+                }
                 case TEXTURE_CONDUCTOR_REFL {
                     tex_stack[tex_i].id |= TEXTURE_IDX_MASK;
                     tex_i++;
@@ -208,6 +280,68 @@ fn texture_evaluate(texture_id: TextureId, uv: vec2f, wl: Wavelengths) -> vec4f
     return data[0];
 }
 
-fn uv_map(uvmap: UvMappingParams, uv: vec2f) -> vec2f {
-    return uv * uvmap.scale + uvmap.delta;
+// trilinear filtering with the mip level chosen so a texel covers the footprint
+fn texture_image_sample(image_index: u32, wrap: u32, st: vec2f, width: f32) -> vec4f {
+    count_stat(STAT_TEXTURE_LOOKUPS);
+    let uv = vec2(st.x, 1 - st.y);
+    let size = vec2f(textureDimensions(IMAGES[image_index]));
+    let lod = max(log2(width * max(size.x, size.y)), 0);
+    switch wrap {
+        case TEXTURE_WRAP_CLAMP {
+            return textureSampleLevel(IMAGES[image_index], LINEAR_FILTER_CLAMP, uv, lod);
+        }
+        case TEXTURE_WRAP_BLACK {
+            if any(uv < vec2f(0)) || any(uv > vec2f(1)) {
+                return vec4f();
+            }
+            return textureSampleLevel(IMAGES[image_index], LINEAR_FILTER_CLAMP, uv, lod);
+        }
+        default {
+            return textureSampleLevel(IMAGES[image_index], LINEAR_FILTER_WRAP, uv, lod);
+        }
+    }
+}
+
+fn texture_map(mapping: TextureMapping, tc: TextureCoords) -> vec2f {
+    let p = (mapping.world_to_texture * vec4f(tc.p, 1)).xyz;
+    var st: vec2f;
+    switch mapping.ty {
+        case TEXTURE_MAPPING_SPHERICAL {
+            let d = normalize(p);
+            st = vec2f(acos(clamp(d.z, -1, 1)) / PI, fract(atan2(d.y, d.x) / TWO_PI));
+        }
+        case TEXTURE_MAPPING_CYLINDRICAL {
+            st = vec2f((PI + atan2(p.y, p.x)) / TWO_PI, p.z);
+        }
+        case TEXTURE_MAPPING_PLANAR {
+            st = vec2f(dot(p, mapping.v1), dot(p, mapping.v2));
+        }
+        default {
+            st = tc.uv;
+        }
+    }
+    return st * mapping.scale + mapping.delta;
+}
+
+// approximate size of the footprint after mapping, matching texture_map
+fn texture_map_width(mapping: TextureMapping, tc: TextureCoords) -> f32 {
+    let m = mat3x3f(mapping.world_to_texture[0].xyz, mapping.world_to_texture[1].xyz, mapping.world_to_texture[2].xyz);
+    let width = tc.width * pow(abs(determinant(m)), 1.0 / 3);
+    let p = (mapping.world_to_texture * vec4f(tc.p, 1)).xyz;
+    var st_width: f32;
+    switch mapping.ty {
+        case TEXTURE_MAPPING_SPHERICAL {
+            st_width = width / (PI * length(p));
+        }
+        case TEXTURE_MAPPING_CYLINDRICAL {
+            st_width = max(width / (TWO_PI * length(p.xy)), width);
+        }
+        case TEXTURE_MAPPING_PLANAR {
+            st_width = width * max(length(mapping.v1), length(mapping.v2));
+        }
+        default {
+            st_width = tc.uv_width;
+        }
+    }
+    return st_width * max(abs(mapping.scale.x), abs(mapping.scale.y));
 }
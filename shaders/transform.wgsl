@@ -31,6 +31,58 @@ fn transform_ray(transform: Transform, ray: Ray) -> Ray {
 }
 
 
+// a transform which changes while the shutter is open, see AnimatedTransform in main.rs
+struct AnimatedTransform {
+    start: Decomposed,
+    end: Decomposed,
+}
+
+struct Decomposed {
+    rotation: vec4f,
+    translation: vec3f,
+    scale: vec3f,
+}
+
+// the transform from local to world space at `time` in [0, 1) of the shutter interval
+fn animated_transform_at(a: AnimatedTransform, time: f32) -> Transform {
+    let r = quat_to_mat3(quat_slerp(a.start.rotation, a.end.rotation, time));
+    let t = mix(a.start.translation, a.end.translation, time);
+    let s = mix(a.start.scale, a.end.scale, time);
+    let m = mat4x4f(vec4(r[0] * s.x, 0), vec4(r[1] * s.y, 0), vec4(r[2] * s.z, 0), vec4(t, 1));
+    // the inverse of T R S is S^-1 R^T T^-1
+    let r_inv = transpose(r);
+    let rs_inv = mat3x3f(r_inv[0] / s, r_inv[1] / s, r_inv[2] / s);
+    let m_inv = mat4x4f(vec4(rs_inv[0], 0), vec4(rs_inv[1], 0), vec4(rs_inv[2], 0), vec4(-(rs_inv * t), 1));
+    return Transform(m, m_inv);
+}
+
+// along the shorter arc, like glam's Quat::slerp
+fn quat_slerp(a: vec4f, b_: vec4f, t: f32) -> vec4f {
+    var b = b_;
+    var d = dot(a, b);
+    if d < 0 {
+        b = -b;
+        d = -d;
+    }
+    if d > 0.9995 {
+        return normalize(mix(a, b, t));
+    }
+    let theta = acos(d);
+    return (a * sin((1 - t) * theta) + b * sin(t * theta)) / sin(theta);
+}
+
+fn quat_to_mat3(q: vec4f) -> mat3x3f {
+    let x = q.x;
+    let y = q.y;
+    let z = q.z;
+    let w = q.w;
+    return mat3x3f(
+        1 - 2 * (y * y + z * z), 2 * (x * y + w * z), 2 * (x * z - w * y),
+        2 * (x * y - w * z), 1 - 2 * (x * x + z * z), 2 * (y * z + w * x),
+        2 * (x * z + w * y), 2 * (y * z - w * x), 1 - 2 * (x * x + y * y),
+    );
+}
+
 fn transform_point_inv(transform: Transform, p: vec3f) -> vec3f {
     return transform_point(Transform(transform.m_inv, transform.m), p);
 }
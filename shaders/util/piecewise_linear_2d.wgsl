@@ -0,0 +1,213 @@
+#import table_sample.wgsl
+
+// Piecewise bilinear 2D distribution with linear interpolation over up to three
+// additional parameters (after Mitsuba's Marginal2D)
+
+struct PiecewiseLinear2d {
+    width: u32,
+    height: u32,
+    data_ptr: u32,
+    marginal_cdf_ptr: u32,
+    conditional_cdf_ptr: u32,
+    dims: u32,
+    param_size: array<u32, 3>,
+    param_values_ptr: array<u32, 3>,
+    param_strides: array<u32, 3>,
+}
+
+struct Pl2dSample {
+    p: vec2f,
+    pdf: f32,
+}
+
+struct Pl2dSlice {
+    dims: u32,
+    offset: u32,
+    weights: array<f32, 3>,
+    strides: array<u32, 3>,
+}
+
+fn pl2d_slice(table_: PiecewiseLinear2d, params: vec3f) -> Pl2dSlice {
+    var table = table_;
+    var p = array(params.x, params.y, params.z);
+
+    var slice: Pl2dSlice;
+    slice.dims = table.dims;
+    for (var d = 0u; d < table.dims; d++) {
+        slice.strides[d] = table.param_strides[d];
+        let n = table.param_size[d];
+        let values = table.param_values_ptr[d];
+        if n < 2 {
+            continue;
+        }
+
+        var lo = 0u;
+        var hi = n - 2;
+        while lo < hi {
+            let mid = (lo + hi + 1) / 2;
+            if FLOAT_DATA[values + mid] <= p[d] {
+                lo = mid;
+            } else {
+                hi = mid - 1;
+            }
+        }
+
+        let v0 = FLOAT_DATA[values + lo];
+        let v1 = FLOAT_DATA[values + lo + 1];
+        slice.offset += lo * table.param_strides[d];
+        slice.weights[d] = clamp((p[d] - v0) / (v1 - v0), 0, 1);
+    }
+    return slice;
+}
+
+fn pl2d_lookup(slice_: Pl2dSlice, base: u32, slice_size: u32, i: u32) -> f32 {
+    var slice = slice_;
+    var result = 0.0;
+    for (var corner = 0u; corner < 1u << slice.dims; corner++) {
+        var w = 1.0;
+        var offset = slice.offset;
+        for (var d = 0u; d < slice.dims; d++) {
+            if ((corner >> d) & 1) != 0 {
+                w *= slice.weights[d];
+                offset += slice.strides[d];
+            } else {
+                w *= 1 - slice.weights[d];
+            }
+        }
+        if w != 0 {
+            result += w * FLOAT_DATA[base + offset * slice_size + i];
+        }
+    }
+    return result;
+}
+
+fn pl2d_evaluate(table: PiecewiseLinear2d, p: vec2f, params: vec3f) -> f32 {
+    let slice = pl2d_slice(table, params);
+    let n = table.width * table.height;
+
+    let x = clamp(p, vec2f(0), vec2f(1)) * vec2f(f32(table.width - 1), f32(table.height - 1));
+    let pos = min(vec2u(x), vec2u(table.width - 2, table.height - 2));
+    let t = x - vec2f(pos);
+
+    let i = pos.y * table.width + pos.x;
+    let v00 = pl2d_lookup(slice, table.data_ptr, n, i);
+    let v10 = pl2d_lookup(slice, table.data_ptr, n, i + 1);
+    let v01 = pl2d_lookup(slice, table.data_ptr, n, i + table.width);
+    let v11 = pl2d_lookup(slice, table.data_ptr, n, i + table.width + 1);
+
+    return mix(mix(v00, v10, t.x), mix(v01, v11, t.x), t.y);
+}
+
+fn pl2d_sample(table: PiecewiseLinear2d, random: vec2f, params: vec3f) -> Pl2dSample {
+    let slice = pl2d_slice(table, params);
+    let w = table.width;
+    let h = table.height;
+    let n = w * h;
+
+    let total = pl2d_lookup(slice, table.marginal_cdf_ptr, h, h - 1);
+    if !(total > 0) {
+        return Pl2dSample();
+    }
+
+    // select row from the marginal
+    var u = random;
+    u.y *= total;
+    var lo = 0u;
+    var hi = h - 2;
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        if pl2d_lookup(slice, table.marginal_cdf_ptr, h, mid) <= u.y {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    let row = lo;
+    u.y -= pl2d_lookup(slice, table.marginal_cdf_ptr, h, row);
+
+    let r0 = pl2d_lookup(slice, table.conditional_cdf_ptr, n, row * w + w - 1);
+    let r1 = pl2d_lookup(slice, table.conditional_cdf_ptr, n, (row + 1) * w + w - 1);
+    let ty = select(
+        (r0 - sqrt(max(0, r0 * r0 + 2 * u.y * (r1 - r0)))) / (r0 - r1),
+        2 * u.y / (r0 + r1),
+        abs(r0 - r1) < 1e-4 * (r0 + r1),
+    );
+
+    // select column from the conditional, interpolated between the two rows
+    u.x *= mix(r0, r1, ty);
+    lo = 0u;
+    hi = w - 2;
+    while lo < hi {
+        let mid = (lo + hi + 1) / 2;
+        let c = mix(
+            pl2d_lookup(slice, table.conditional_cdf_ptr, n, row * w + mid),
+            pl2d_lookup(slice, table.conditional_cdf_ptr, n, (row + 1) * w + mid),
+            ty,
+        );
+        if c <= u.x {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    let col = lo;
+    u.x -= mix(
+        pl2d_lookup(slice, table.conditional_cdf_ptr, n, row * w + col),
+        pl2d_lookup(slice, table.conditional_cdf_ptr, n, (row + 1) * w + col),
+        ty,
+    );
+
+    let i = row * w + col;
+    let v00 = pl2d_lookup(slice, table.data_ptr, n, i);
+    let v10 = pl2d_lookup(slice, table.data_ptr, n, i + 1);
+    let v01 = pl2d_lookup(slice, table.data_ptr, n, i + w);
+    let v11 = pl2d_lookup(slice, table.data_ptr, n, i + w + 1);
+    let c0 = mix(v00, v01, ty);
+    let c1 = mix(v10, v11, ty);
+    let tx = select(
+        (c0 - sqrt(max(0, c0 * c0 + 2 * u.x * (c1 - c0)))) / (c0 - c1),
+        2 * u.x / (c0 + c1),
+        abs(c0 - c1) < 1e-4 * (c0 + c1),
+    );
+
+    return Pl2dSample(
+        (vec2f(f32(col), f32(row)) + vec2f(tx, ty)) / vec2f(f32(w - 1), f32(h - 1)),
+        mix(c0, c1, tx),
+    );
+}
+
+fn pl2d_invert(table: PiecewiseLinear2d, p: vec2f, params: vec3f) -> Pl2dSample {
+    let slice = pl2d_slice(table, params);
+    let w = table.width;
+    let h = table.height;
+    let n = w * h;
+
+    let x = clamp(p, vec2f(0), vec2f(1)) * vec2f(f32(w - 1), f32(h - 1));
+    let pos = min(vec2u(x), vec2u(w - 2, h - 2));
+    let t = x - vec2f(pos);
+
+    let i = pos.y * w + pos.x;
+    let v00 = pl2d_lookup(slice, table.data_ptr, n, i);
+    let v10 = pl2d_lookup(slice, table.data_ptr, n, i + 1);
+    let v01 = pl2d_lookup(slice, table.data_ptr, n, i + w);
+    let v11 = pl2d_lookup(slice, table.data_ptr, n, i + w + 1);
+    let c0 = mix(v00, v01, t.y);
+    let c1 = mix(v10, v11, t.y);
+
+    var u = t;
+    u.x *= c0 + 0.5 * u.x * (c1 - c0);
+    u.x += mix(
+        pl2d_lookup(slice, table.conditional_cdf_ptr, n, i),
+        pl2d_lookup(slice, table.conditional_cdf_ptr, n, i + w),
+        t.y,
+    );
+    let r0 = pl2d_lookup(slice, table.conditional_cdf_ptr, n, pos.y * w + w - 1);
+    let r1 = pl2d_lookup(slice, table.conditional_cdf_ptr, n, (pos.y + 1) * w + w - 1);
+    u.x /= mix(r0, r1, t.y);
+
+    u.y *= r0 + 0.5 * u.y * (r1 - r0);
+    u.y += pl2d_lookup(slice, table.marginal_cdf_ptr, h, pos.y);
+    u.y /= pl2d_lookup(slice, table.marginal_cdf_ptr, h, h - 1);
+
+    return Pl2dSample(u, mix(c0, c1, t.x));
+}
@@ -0,0 +1,72 @@
+use glam::{DMat4, Mat4, Vec3};
+
+use crate::scene::{NodeId, Scene};
+use crate::{ProjectiveCamera, Transform};
+
+// Moves the camera and objects of an already built scene from frame to frame, so a sequence can
+// be rendered without loading the scene or building its BVH again. Frames are placed by their
+// number rather than by the frame before them, so any range of an animation comes out the same.
+pub struct Animation {
+    camera: ProjectiveCamera,
+    center: Vec3,
+    // degrees per frame
+    turntable: Option<f32>,
+    // instances with their transforms in the scene file and their movement per frame
+    moves: Vec<(NodeId, DMat4, Vec3)>,
+}
+
+impl Animation {
+    pub fn new(
+        scene: &Scene,
+        camera: ProjectiveCamera,
+        turntable: Option<f32>,
+        translations: &[(String, Vec3)],
+    ) -> Self {
+        let bounds = scene.node_bounds(scene.root.unwrap());
+        let mut moves = vec![];
+        for (name, offset) in translations {
+            let Some(instances) = scene.named_instances.get(name) else {
+                println!("Warning: No instances of object {name} to move");
+                continue;
+            };
+            for &instance in instances {
+                moves.push((instance, scene.instance_transform(instance), *offset));
+            }
+        }
+        Animation {
+            camera,
+            center: (bounds.min + bounds.max) / 2.0,
+            turntable,
+            moves,
+        }
+    }
+
+    pub fn set_frame(&self, frame: u32, scene: &mut Scene, camera: &mut ProjectiveCamera) {
+        // orbiting the camera about the center of the scene, around the camera's up axis
+        if let Some(degrees) = self.turntable {
+            let up = self
+                .camera
+                .world_to_camera
+                .m_inv
+                .transform_vector3(Vec3::Y)
+                .normalize();
+            let orbit = Mat4::from_translation(self.center)
+                * Mat4::from_axis_angle(up, (degrees * frame as f32).to_radians())
+                * Mat4::from_translation(-self.center);
+            camera.world_to_camera =
+                Transform::from_mat4(self.camera.world_to_camera.m * orbit.inverse());
+        }
+        if !self.moves.is_empty() {
+            let moves: Vec<_> = self
+                .moves
+                .iter()
+                .map(|&(instance, transform, offset)| {
+                    let moved =
+                        DMat4::from_translation(offset.as_dvec3() * frame as f64) * transform;
+                    (instance, moved)
+                })
+                .collect();
+            scene.set_instance_transforms(&moves);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::options::FloatAtomics;
+use crate::shader;
+
+// size of the trees splatted into, small enough that invocations collide often
+const BSP_NODES: u64 = 256;
+const DIR_NODES: u64 = 1024;
+const WORKGROUPS: u32 = 4096;
+const RUNS: usize = 3;
+
+// Times the guided integrator's additions with float atomics and with compare-exchange loops, and
+// returns the faster. The device must have been created with float atomics.
+pub fn benchmark(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<FloatAtomics> {
+    let stats = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: BSP_NODES * 9 * 4,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+    let tree = device.create_buffer(&wgpu::BufferDescriptor {
+        label: None,
+        size: DIR_NODES * 4 * 8,
+        usage: wgpu::BufferUsages::STORAGE,
+        mapped_at_creation: false,
+    });
+
+    let time = |atomics: FloatAtomics| -> anyhow::Result<Duration> {
+        let flags = HashMap::from([("atomics".to_owned(), atomics.flag().to_owned())]);
+        let shader = shader::load_shader(device, "entrypoint/atomic_bench.wgsl", &flags)?;
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: None,
+            layout: None,
+            module: &shader,
+            entry_point: None,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: stats.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: tree.as_entire_binding(),
+                },
+            ],
+        });
+
+        // the first run includes compiling the pipeline, so it isn't counted
+        let mut best = Duration::MAX;
+        for run in 0..=RUNS {
+            let t = Instant::now();
+            let mut encoder = device.create_command_encoder(&Default::default());
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            pass.dispatch_workgroups(WORKGROUPS, 1, 1);
+            drop(pass);
+            queue.submit([encoder.finish()]);
+            device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+            if run > 0 {
+                best = best.min(t.elapsed());
+            }
+        }
+        Ok(best)
+    };
+
+    let native = time(FloatAtomics::Native)?;
+    let cas = time(FloatAtomics::Cas)?;
+    let faster = match native <= cas {
+        true => FloatAtomics::Native,
+        false => FloatAtomics::Cas,
+    };
+    println!(
+        "Float atomics took {:.2} ms, compare-exchange loops {:.2} ms; using {}",
+        native.as_secs_f64() * 1000.0,
+        cas.as_secs_f64() * 1000.0,
+        faster.flag()
+    );
+    Ok(faster)
+}
@@ -0,0 +1,114 @@
+use crate::options::splitmix64;
+
+// Blue noise tiles made with void-and-cluster (Ulichney, 1993)
+
+// Width and height of the tile used by the blue-noise sampler
+pub const TILE_SIZE: usize = 64;
+
+// Rank of every pixel of a `size` by `size` tile which wraps around at the edges, such that the
+// pixels of any rank or lower are evenly spread out
+pub fn tile(size: usize, seed: u64) -> Vec<u32> {
+    let n = size * size;
+    // energy contributed by a point at each toroidal offset
+    let sigma = 1.9f32;
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let wrap = |v: usize| v.min(size - v) as f32;
+            let (x, y) = (wrap(i % size), wrap(i / size));
+            (-(x * x + y * y) / (2.0 * sigma * sigma)).exp()
+        })
+        .collect();
+
+    let mut pattern = Pattern {
+        size,
+        kernel,
+        points: vec![false; n],
+        energy: vec![0.0; n],
+    };
+
+    // initial pattern of about a tenth of the pixels
+    let mut state = seed;
+    let initial = (n / 10).max(1);
+    while pattern.count() < initial {
+        let i = (splitmix64(&mut state) % n as u64) as usize;
+        if !pattern.points[i] {
+            pattern.toggle(i);
+        }
+    }
+
+    // move points from the tightest cluster to the largest void until they are evenly spread
+    for _ in 0..n {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        if void == cluster {
+            break;
+        }
+    }
+
+    let mut ranks = vec![0; n];
+    let initial_points = pattern.points.clone();
+    let initial_energy = pattern.energy.clone();
+
+    // the initial points get the lowest ranks, removing the most clustered ones first
+    for rank in (0..initial).rev() {
+        let cluster = pattern.tightest_cluster();
+        pattern.toggle(cluster);
+        ranks[cluster] = rank as u32;
+    }
+
+    // then the remaining pixels in order of filling the largest void
+    pattern.points = initial_points;
+    pattern.energy = initial_energy;
+    for rank in initial..n {
+        let void = pattern.largest_void();
+        pattern.toggle(void);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+struct Pattern {
+    size: usize,
+    kernel: Vec<f32>,
+    points: Vec<bool>,
+    energy: Vec<f32>,
+}
+
+impl Pattern {
+    fn count(&self) -> usize {
+        self.points.iter().filter(|&&p| p).count()
+    }
+
+    fn toggle(&mut self, i: usize) {
+        self.points[i] = !self.points[i];
+        let sign = if self.points[i] { 1.0 } else { -1.0 };
+        let (x, y) = (i % self.size, i / self.size);
+        for (j, e) in self.energy.iter_mut().enumerate() {
+            let dx = (j % self.size + self.size - x) % self.size;
+            let dy = (j / self.size + self.size - y) % self.size;
+            *e += sign * self.kernel[dy * self.size + dx];
+        }
+    }
+
+    fn tightest_cluster(&self) -> usize {
+        self.extreme(true, |a, b| a > b)
+    }
+
+    fn largest_void(&self) -> usize {
+        self.extreme(false, |a, b| a < b)
+    }
+
+    // pixel with or without a point whose energy is best by `better`
+    fn extreme(&self, point: bool, better: impl Fn(f32, f32) -> bool) -> usize {
+        let mut best = None;
+        for (i, (&p, &e)) in self.points.iter().zip(&self.energy).enumerate() {
+            if p == point && best.is_none_or(|(_, b)| better(e, b)) {
+                best = Some((i, e));
+            }
+        }
+        best.unwrap().0
+    }
+}
@@ -0,0 +1,367 @@
+use std::f32::consts::PI;
+use std::path::PathBuf;
+
+use anyhow::{Context, bail};
+use glam::Vec3;
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+
+use crate::scene::load_image;
+use crate::{heatmap, xyz_to_linear_srgb};
+
+// 0.7 m from a 0.7 m wide 4K monitor, the default viewing conditions of FLIP
+const FLIP_PIXELS_PER_DEGREE: f32 = 67.02;
+// added to the squared reference in the relative MSE so black pixels don't dominate it
+const REL_MSE_EPSILON: f32 = 0.01;
+
+#[derive(clap::Args)]
+pub struct CompareOptions {
+    // converged render to compare against, EXR or PFM
+    reference: PathBuf,
+    // render being checked, the same size as the reference
+    image: PathBuf,
+    // write the FLIP error of each pixel in false color to this png
+    #[clap(long)]
+    heatmap: Option<PathBuf>,
+    // viewing distance for FLIP, in pixels per degree of the viewer's field of view
+    #[clap(long, default_value_t = FLIP_PIXELS_PER_DEGREE)]
+    ppd: f32,
+}
+
+// Reports the error of a render against a reference image, as the MSE and relative MSE of the
+// linear values and the mean HDR-FLIP, which weights errors by how visible they are
+pub fn compare_images(options: &CompareOptions) -> anyhow::Result<()> {
+    let load = |path: &PathBuf| -> anyhow::Result<_> {
+        let img = load_image(path)
+            .with_context(|| format!("failed to load {}", path.display()))?
+            .to_rgb32f();
+        let size = (img.width(), img.height());
+        let mut non_finite = 0;
+        let pixels: Vec<Vec3> = img
+            .pixels()
+            .map(|p| {
+                let v = Vec3::from_array(p.0);
+                // zeroed so one bad sample doesn't make every metric NaN
+                match v.is_finite() {
+                    true => v,
+                    false => {
+                        non_finite += 1;
+                        Vec3::ZERO
+                    }
+                }
+            })
+            .collect();
+        if non_finite > 0 {
+            println!(
+                "Warning: {non_finite} pixels of {} are not finite",
+                path.display()
+            );
+        }
+        Ok((size, pixels))
+    };
+    let ((width, height), reference) = load(&options.reference)?;
+    let (size, image) = load(&options.image)?;
+    if size != (width, height) {
+        bail!(
+            "{} is {}x{}, but the reference is {width}x{height}",
+            options.image.display(),
+            size.0,
+            size.1
+        );
+    }
+
+    let n = (reference.len() * 3) as f32;
+    let mse = reference
+        .iter()
+        .zip(&image)
+        .map(|(&r, &t)| (t - r).powf(2.0).element_sum())
+        .sum::<f32>()
+        / n;
+    let rel_mse = reference
+        .iter()
+        .zip(&image)
+        .map(|(&r, &t)| ((t - r).powf(2.0) / (r * r + REL_MSE_EPSILON)).element_sum())
+        .sum::<f32>()
+        / n;
+
+    let flip = hdr_flip(
+        width as usize,
+        height as usize,
+        &reference,
+        &image,
+        options.ppd,
+    );
+    let mean_flip = flip.iter().sum::<f32>() / flip.len() as f32;
+    let max_flip = flip.iter().copied().fold(0.0, f32::max);
+
+    println!("MSE     {mse:.6e}");
+    println!("relMSE  {rel_mse:.6e}");
+    println!("FLIP    {mean_flip:.6} (max {max_flip:.4})");
+
+    if let Some(path) = &options.heatmap {
+        let image = heatmap::heatmap(width, height, &flip, Some);
+        heatmap::save(&image, path)?;
+    }
+    Ok(())
+}
+
+// FLIP's approximation of the ACES filmic curve, as a ratio of quadratics
+const K: [f32; 6] = [
+    0.6 * 0.6 * 2.51,
+    0.6 * 0.03,
+    0.0,
+    0.6 * 0.6 * 2.43,
+    0.6 * 0.59,
+    0.14,
+];
+
+fn tone_map(x: f32) -> f32 {
+    ((x * (K[0] * x + K[1]) + K[2]) / (x * (K[3] * x + K[4]) + K[5])).clamp(0.0, 1.0)
+}
+
+// the value the tone curve maps to `t`
+fn inverse_tone_map(t: f32) -> f32 {
+    let a = K[0] - t * K[3];
+    let b = K[1] - t * K[4];
+    let c = K[2] - t * K[5];
+    (-b + (b * b - 4.0 * a * c).sqrt()) / (2.0 * a)
+}
+
+// The largest LDR-FLIP error of each pixel over a range of exposures, from the one taking the
+// brightest pixel of the reference to 85% of white to the one doing that to the median pixel
+fn hdr_flip(width: usize, height: usize, reference: &[Vec3], test: &[Vec3], ppd: f32) -> Vec<f32> {
+    let srgb_to_xyz = xyz_to_linear_srgb().inverse();
+    let mut luminance: Vec<f32> = reference.iter().map(|&c| (srgb_to_xyz * c).y).collect();
+    luminance.sort_unstable_by(f32::total_cmp);
+    let max = luminance.last().copied().unwrap_or(0.0).max(1e-6);
+    let median = luminance[luminance.len() / 2].max(1e-6);
+
+    let x = inverse_tone_map(0.85);
+    let start = (x / max).log2();
+    let stop = (x / median).log2();
+    let count = ((stop - start).ceil() as usize).max(2);
+    println!("FLIP over {count} exposures from {start:.2} to {stop:.2}");
+
+    let filters = Filters::new(ppd);
+    let mut flip = vec![0.0f32; reference.len()];
+    for i in 0..count {
+        let exposure = (start + (stop - start) * i as f32 / (count - 1) as f32).exp2();
+        let expose = |img: &[Vec3]| -> Vec<Vec3> {
+            img.iter()
+                .map(|&c| (c * exposure).to_array().map(tone_map).into())
+                .collect()
+        };
+        let ldr = ldr_flip(width, height, &expose(reference), &expose(test), &filters);
+        for (f, e) in flip.iter_mut().zip(ldr) {
+            *f = f.max(e);
+        }
+    }
+    flip
+}
+
+// FLIP parameters from the paper
+const QC: f32 = 0.7;
+const QF: f32 = 0.5;
+const PC: f32 = 0.4;
+const PT: f32 = 0.95;
+const GW: f32 = 0.082;
+
+// Separable kernels for the contrast sensitivity of the opponent channels and for detecting edges
+// and points in the luminance
+struct Filters {
+    // weight and kernel of each Gaussian in the sum for Y, Cx and Cz
+    csf: [Vec<(f32, Vec<f32>)>; 3],
+    blur: Vec<f32>,
+    edge: Vec<f32>,
+    point: Vec<f32>,
+}
+
+impl Filters {
+    fn new(ppd: f32) -> Self {
+        // the 2D Gaussian a * pi / b * exp(-pi^2 r^2 / b), as a product of 1D ones normalized
+        // together so each channel's whole kernel sums to one
+        let csf = |gaussians: &[(f32, f32)]| {
+            let radius = (3.0 * (0.04 / (2.0 * PI * PI)).sqrt() * ppd).ceil() as i32;
+            let kernels: Vec<(f32, Vec<f32>)> = gaussians
+                .iter()
+                .map(|&(a, b)| {
+                    let k: Vec<f32> = (-radius..=radius)
+                        .map(|i| {
+                            let x = i as f32 / ppd;
+                            (PI / b).sqrt() * (-PI * PI * x * x / b).exp()
+                        })
+                        .collect();
+                    (a, k)
+                })
+                .collect();
+            let total: f32 = kernels
+                .iter()
+                .map(|(a, k)| a * k.iter().sum::<f32>().powi(2))
+                .sum();
+            kernels.into_iter().map(|(a, k)| (a / total, k)).collect()
+        };
+
+        let sd = 0.5 * GW * ppd;
+        let radius = (3.0 * sd).ceil() as i32;
+        let g = |x: f32| (-x * x / (2.0 * sd * sd)).exp();
+        let blur: Vec<f32> = (-radius..=radius).map(|i| g(i as f32)).collect();
+        let total: f32 = blur.iter().sum();
+        let blur = blur.into_iter().map(|w| w / total).collect();
+        // positive and negative weights each sum to one
+        let balanced = |f: &dyn Fn(f32) -> f32| {
+            let k: Vec<f32> = (-radius..=radius).map(|i| f(i as f32)).collect();
+            let pos: f32 = k.iter().filter(|&&w| w > 0.0).sum();
+            let neg: f32 = -k.iter().filter(|&&w| w < 0.0).sum::<f32>();
+            k.into_iter()
+                .map(|w| if w > 0.0 { w / pos } else { w / neg })
+                .collect()
+        };
+
+        Filters {
+            csf: [
+                csf(&[(1.0, 0.0047)]),
+                csf(&[(1.0, 0.0053)]),
+                csf(&[(34.1, 0.04), (13.5, 0.025)]),
+            ],
+            blur,
+            edge: balanced(&|x| -x * g(x)),
+            point: balanced(&|x| (x * x / (sd * sd) - 1.0) * g(x)),
+        }
+    }
+}
+
+// Per pixel FLIP error between two linear sRGB images in 0..1
+fn ldr_flip(
+    width: usize,
+    height: usize,
+    reference: &[Vec3],
+    test: &[Vec3],
+    filters: &Filters,
+) -> Vec<f32> {
+    let srgb_to_xyz = xyz_to_linear_srgb().inverse();
+    let white = srgb_to_xyz * Vec3::ONE;
+
+    // colors as they are seen after spatial filtering, in Hunt adjusted L*a*b*
+    let prepare = |img: &[Vec3]| {
+        let ycxcz: Vec<Vec3> = img
+            .iter()
+            .map(|&c| xyz_to_ycxcz(srgb_to_xyz * c / white))
+            .collect();
+        let channels: [Vec<f32>; 3] = std::array::from_fn(|ch| {
+            let plane: Vec<f32> = ycxcz.iter().map(|c| c[ch]).collect();
+            let mut filtered = vec![0.0; plane.len()];
+            for (a, k) in &filters.csf[ch] {
+                let f = convolve(width, height, &plane, k, k);
+                for (o, v) in filtered.iter_mut().zip(f) {
+                    *o += a * v;
+                }
+            }
+            filtered
+        });
+        let colors: Vec<Vec3> = (0..ycxcz.len())
+            .map(|i| {
+                let xyz = ycxcz_to_xyz(Vec3::new(channels[0][i], channels[1][i], channels[2][i]));
+                let rgb = srgb_to_xyz.inverse() * (xyz * white);
+                hunt(xyz_to_lab(
+                    srgb_to_xyz * rgb.clamp(Vec3::ZERO, Vec3::ONE) / white,
+                ))
+            })
+            .collect();
+
+        // features are found in the unfiltered luminance
+        let y: Vec<f32> = ycxcz.iter().map(|c| (c.x + 16.0) / 116.0).collect();
+        let magnitude = |k: &[f32]| {
+            let dx = convolve(width, height, &y, k, &filters.blur);
+            let dy = convolve(width, height, &y, &filters.blur, k);
+            dx.iter()
+                .zip(dy)
+                .map(|(x, y)| x.hypot(y))
+                .collect::<Vec<_>>()
+        };
+        (colors, magnitude(&filters.edge), magnitude(&filters.point))
+    };
+    let (ref_color, ref_edge, ref_point) = prepare(reference);
+    let (test_color, test_edge, test_point) = prepare(test);
+
+    let to_lab = |rgb: Vec3| hunt(xyz_to_lab(srgb_to_xyz * rgb / white));
+    let cmax = hyab(to_lab(Vec3::Y), to_lab(Vec3::Z)).powf(QC);
+    let pccmax = PC * cmax;
+
+    (0..reference.len())
+        .map(|i| {
+            let color = hyab(ref_color[i], test_color[i]).powf(QC);
+            let color = match color < pccmax {
+                true => PT / pccmax * color,
+                false => PT + (color - pccmax) / (cmax - pccmax) * (1.0 - PT),
+            };
+            let feature = (ref_edge[i] - test_edge[i])
+                .abs()
+                .max((ref_point[i] - test_point[i]).abs());
+            let feature = (feature / 2.0f32.sqrt()).powf(QF);
+            color.powf(1.0 - feature)
+        })
+        .collect()
+}
+
+// convolution with `kx` along rows and then `ky` along columns, clamping at the edges
+fn convolve(width: usize, height: usize, plane: &[f32], kx: &[f32], ky: &[f32]) -> Vec<f32> {
+    let pass = |src: &[f32], k: &[f32], step: [usize; 2], size: [usize; 2]| -> Vec<f32> {
+        let r = (k.len() / 2) as isize;
+        let rows: Vec<Vec<f32>> = (0..size[1])
+            .into_par_iter()
+            .map(|j| {
+                (0..size[0])
+                    .map(|i| {
+                        k.iter()
+                            .enumerate()
+                            .map(|(o, w)| {
+                                let i =
+                                    (i as isize + o as isize - r).clamp(0, size[0] as isize - 1);
+                                w * src[i as usize * step[0] + j * step[1]]
+                            })
+                            .sum()
+                    })
+                    .collect()
+            })
+            .collect();
+        // back in row major order
+        let mut out = vec![0.0; src.len()];
+        for (j, row) in rows.into_iter().enumerate() {
+            for (i, v) in row.into_iter().enumerate() {
+                out[i * step[0] + j * step[1]] = v;
+            }
+        }
+        out
+    };
+    let horizontal = pass(plane, kx, [1, width], [width, height]);
+    pass(&horizontal, ky, [width, 1], [height, width])
+}
+
+// the linearized opponent space FLIP filters in, from XYZ relative to white
+fn xyz_to_ycxcz(c: Vec3) -> Vec3 {
+    Vec3::new(116.0 * c.y - 16.0, 500.0 * (c.x - c.y), 200.0 * (c.y - c.z))
+}
+
+fn ycxcz_to_xyz(c: Vec3) -> Vec3 {
+    let y = (c.x + 16.0) / 116.0;
+    Vec3::new(y + c.y / 500.0, y, y - c.z / 200.0)
+}
+
+fn xyz_to_lab(c: Vec3) -> Vec3 {
+    const DELTA: f32 = 6.0 / 29.0;
+    let f = |t: f32| match t > DELTA.powi(3) {
+        true => t.cbrt(),
+        false => t / (3.0 * DELTA * DELTA) + 4.0 / 29.0,
+    };
+    let [x, y, z] = c.to_array().map(f);
+    Vec3::new(116.0 * y - 16.0, 500.0 * (x - y), 200.0 * (y - z))
+}
+
+// chroma scaled down in the dark, where differences in it are harder to see
+fn hunt(lab: Vec3) -> Vec3 {
+    Vec3::new(lab.x, lab.y * 0.01 * lab.x, lab.z * 0.01 * lab.x)
+}
+
+fn hyab(a: Vec3, b: Vec3) -> f32 {
+    let d = a - b;
+    d.x.abs() + d.y.hypot(d.z)
+}
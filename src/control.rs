@@ -0,0 +1,136 @@
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use std::sync::mpsc::{Receiver, Sender, channel};
+
+use glam::DVec3;
+
+pub enum Command {
+    SetExposure(f32),
+    SetIntegrator(String),
+    Restart,
+    Save,
+    Stop,
+    // stop submitting passes until resumed, keeping what has been rendered
+    Pause,
+    Resume,
+    // scene edits by the names used in the scene file
+    ReplaceMaterial(String, String),
+    TranslateObject(String, DVec3),
+    SetTexture(String, PathBuf),
+    // describe what is seen through a pixel
+    Pick(u32, u32),
+    // debug overlays drawn over `<output>-overlay` when the image is saved
+    ShowNormals(bool),
+    // bounds of the named object's instances, or of the whole scene, and the BVH levels below
+    ShowBounds(Option<(String, u32)>),
+}
+
+impl Command {
+    fn parse(line: &str) -> Result<Command, String> {
+        let words: Vec<_> = line.split_whitespace().collect();
+        match words[..] {
+            ["set", "exposure", v] => v
+                .parse()
+                .map(Command::SetExposure)
+                .map_err(|e| format!("Invalid exposure {v}: {e}")),
+            ["set", "integrator", name] => Ok(Command::SetIntegrator(name.to_owned())),
+            ["restart"] => Ok(Command::Restart),
+            ["save"] => Ok(Command::Save),
+            ["stop"] => Ok(Command::Stop),
+            ["pause"] => Ok(Command::Pause),
+            ["resume"] => Ok(Command::Resume),
+            ["replace", "material", old, new] => {
+                Ok(Command::ReplaceMaterial(old.to_owned(), new.to_owned()))
+            }
+            ["translate", name, x, y, z] => {
+                let parse = |v: &str| {
+                    v.parse::<f64>()
+                        .map_err(|e| format!("Invalid offset {v}: {e}"))
+                };
+                let offset = DVec3::new(parse(x)?, parse(y)?, parse(z)?);
+                Ok(Command::TranslateObject(name.to_owned(), offset))
+            }
+            ["pick", x, y] => match (x.parse(), y.parse()) {
+                (Ok(x), Ok(y)) => Ok(Command::Pick(x, y)),
+                _ => Err(format!("Invalid pixel {x} {y}")),
+            },
+            ["set", "texture", name, path] => {
+                Ok(Command::SetTexture(name.to_owned(), PathBuf::from(path)))
+            }
+            ["overlay", "normals", "on"] => Ok(Command::ShowNormals(true)),
+            ["overlay", "normals", "off"] => Ok(Command::ShowNormals(false)),
+            ["overlay", "bounds", "off"] => Ok(Command::ShowBounds(None)),
+            ["overlay", "bounds", name] => Ok(Command::ShowBounds(Some((name.to_owned(), 3)))),
+            ["overlay", "bounds", name, depth] => depth
+                .parse()
+                .map(|depth| Command::ShowBounds(Some((name.to_owned(), depth))))
+                .map_err(|e| format!("Invalid depth {depth}: {e}")),
+            _ => Err(format!("Unrecognized command {line}")),
+        }
+    }
+}
+
+pub fn spawn(source: &str) -> anyhow::Result<Receiver<Command>> {
+    let (send, recv) = channel();
+    if source == "-" {
+        std::thread::spawn(move || read_commands(std::io::stdin().lock(), send));
+    } else {
+        listen(source, send)?;
+    }
+    Ok(recv)
+}
+
+#[cfg(unix)]
+fn listen(path: &str, send: Sender<Command>) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::net::UnixListener;
+
+    use anyhow::Context;
+
+    // clean up a socket left behind by a previous run, but never anything else
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("failed to bind control socket {path}"))?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let send = send.clone();
+                    std::thread::spawn(move || read_commands(BufReader::new(stream), send));
+                }
+                Err(e) => println!("Warning: Control socket: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn listen(_path: &str, _send: Sender<Command>) -> anyhow::Result<()> {
+    anyhow::bail!("control sockets are only supported on unix; use `--control -` for stdin")
+}
+
+fn read_commands(reader: impl BufRead, send: Sender<Command>) {
+    for line in reader.lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        match Command::parse(line) {
+            Ok(command) => {
+                if send.send(command).is_err() {
+                    break;
+                }
+            }
+            Err(e) => println!("Warning: {e}"),
+        }
+    }
+}
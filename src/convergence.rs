@@ -0,0 +1,52 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::Context;
+
+use crate::ImageStats;
+
+// A CSV row for every sample of the render, for plotting how the error of integrators goes down
+// over time without any other tools. A render restarted with another integrator carries on in the
+// same file, with the integrator's name telling the runs apart.
+pub struct ConvergenceLog {
+    file: BufWriter<File>,
+    path: PathBuf,
+}
+
+impl ConvergenceLog {
+    pub fn new(path: &Path) -> anyhow::Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+        let mut log = ConvergenceLog {
+            file: BufWriter::new(file),
+            path: path.to_owned(),
+        };
+        log.write("integrator,sample,seconds,rel_variance,efficiency,spp")?;
+        Ok(log)
+    }
+
+    pub fn add(
+        &mut self,
+        integrator: &str,
+        sample: u32,
+        time: Duration,
+        stats: &ImageStats,
+    ) -> anyhow::Result<()> {
+        self.write(&format!(
+            "{integrator},{sample},{},{},{},{}",
+            time.as_secs_f64(),
+            stats.avg_rel_variance,
+            stats.efficiency,
+            stats.avg_spp,
+        ))
+    }
+
+    // flushed as it goes so the log can be plotted while the render is still running
+    fn write(&mut self, row: &str) -> anyhow::Result<()> {
+        writeln!(self.file, "{row}")
+            .and_then(|_| self.file.flush())
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
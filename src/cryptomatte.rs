@@ -0,0 +1,193 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use anyhow::Context;
+use exr::meta::attribute::{AttributeValue, Text};
+use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, WritableImage};
+
+use crate::scene::Scene;
+use crate::write_atomic;
+
+// ids and coverage of the most common ids in a pixel, as kept by the film
+pub const RANKS: usize = 4;
+
+// Names of the ids the GPU reports for objects or materials, hashed the way Cryptomatte does so
+// that ids stay the same from run to run and match between renders of the same scene
+pub struct IdNames {
+    names: HashMap<u32, String>,
+    // for ids without a name, followed by the id
+    unnamed: &'static str,
+}
+
+impl IdNames {
+    // top level instances by the name of their object
+    pub fn objects(scene: &Scene) -> Self {
+        let mut names = HashMap::new();
+        for (name, instances) in &scene.named_instances {
+            for &instance in instances {
+                names.insert(scene.instance_index(instance), name.clone());
+            }
+        }
+        // shapes outside of any instance all count as one object
+        names.insert(!0, "(not instanced)".to_owned());
+        IdNames {
+            names,
+            unnamed: "instance",
+        }
+    }
+
+    pub fn materials(scene: &Scene) -> Self {
+        IdNames {
+            names: scene
+                .named_materials
+                .iter()
+                .map(|(name, &id)| (bytemuck::cast(id), name.clone()))
+                .collect(),
+            unnamed: "material",
+        }
+    }
+
+    pub fn name(&self, id: u32) -> String {
+        match self.names.get(&id) {
+            Some(name) => name.clone(),
+            None => format!("({} {id})", self.unnamed),
+        }
+    }
+
+    pub fn hash(&self, id: u32) -> u32 {
+        murmur3_32(self.name(id).as_bytes(), 0)
+    }
+}
+
+// MurmurHash3 x86_32, which Cryptomatte uses for names
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in &mut chunks {
+        let k = u32::from_le_bytes(chunk.try_into().unwrap());
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0;
+        for (i, &b) in tail.iter().enumerate() {
+            k |= (b as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85ebca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2ae35);
+    h ^ (h >> 16)
+}
+
+// the hash as the bits of a float, flipping an exponent bit so that it is never a denormal, inf or
+// NaN that could be mangled on the way through a compositor
+fn hash_to_float(hash: u32) -> f32 {
+    let exponent = hash >> 23 & 0xff;
+    match exponent {
+        0 | 0xff => f32::from_bits(hash ^ 1 << 23),
+        _ => f32::from_bits(hash),
+    }
+}
+
+fn escape_json(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// A matte layer, with the coverage of each pixel's ids from most to least
+pub struct Layer<'a> {
+    pub name: &'a str,
+    pub ids: &'a IdNames,
+    pub pixels: Vec<[(u32, f32); RANKS]>,
+}
+
+// Writes the layers as a Cryptomatte EXR, with two ranks in each of the RGBA channel sets
+// `<name>00`, `<name>01` and so on, and the names of the ids in the manifest
+pub fn write(path: &Path, width: u32, height: u32, layers: &[Layer]) -> anyhow::Result<()> {
+    let mut channels = vec![];
+    let mut attributes = vec![];
+    for layer in layers {
+        let mut manifest = BTreeMap::new();
+        let mut hashes = HashMap::new();
+        for pixel in &layer.pixels {
+            for &(id, coverage) in pixel {
+                if coverage > 0.0 && !hashes.contains_key(&id) {
+                    let hash = hash_to_float(layer.ids.hash(id));
+                    hashes.insert(id, hash);
+                    // with the bits as they are in the image
+                    manifest.insert(layer.ids.name(id), hash.to_bits());
+                }
+            }
+        }
+
+        for rank in 0..RANKS {
+            let (ids, coverages) = layer
+                .pixels
+                .iter()
+                .map(|p| match p[rank] {
+                    (id, coverage) if coverage > 0.0 => (hashes[&id], coverage),
+                    _ => (0.0, 0.0),
+                })
+                .unzip();
+            let set = format!("{}{:02}", layer.name, rank / 2);
+            let [id_channel, coverage_channel] = match rank % 2 {
+                0 => ["R", "G"],
+                _ => ["B", "A"],
+            };
+            channels.push(AnyChannel::new(
+                format!("{set}.{id_channel}").as_str(),
+                FlatSamples::F32(ids),
+            ));
+            channels.push(AnyChannel::new(
+                format!("{set}.{coverage_channel}").as_str(),
+                FlatSamples::F32(coverages),
+            ));
+        }
+
+        let manifest: Vec<_> = manifest
+            .iter()
+            .map(|(name, hash)| format!("\"{}\":\"{hash:08x}\"", escape_json(name)))
+            .collect();
+        // readers find layers by name, so the key only has to be unique within the file
+        let key = format!("{:08x}", murmur3_32(layer.name.as_bytes(), 0));
+        let key = format!("cryptomatte/{}", &key[..7]);
+        attributes.push((format!("{key}/name"), layer.name.to_owned()));
+        attributes.push((format!("{key}/hash"), "MurmurHash3_32".to_owned()));
+        attributes.push((format!("{key}/conversion"), "uint32_to_float32".to_owned()));
+        attributes.push((
+            format!("{key}/manifest"),
+            format!("{{{}}}", manifest.join(",")),
+        ));
+    }
+
+    let mut image = exr::prelude::Image::from_channels(
+        (width as usize, height as usize),
+        AnyChannels::sort(channels.into_iter().collect()),
+    );
+    for (name, value) in attributes {
+        image.attributes.other.insert(
+            Text::from(name.as_str()),
+            // names are UTF-8, which the Cryptomatte readers expect
+            AttributeValue::Text(Text::from_slice_unchecked(value.as_bytes())),
+        );
+    }
+    write_atomic(path, |tmp| Ok(image.write().to_file(tmp)?))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
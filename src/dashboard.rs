@@ -0,0 +1,136 @@
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::scene::human_size;
+
+// Progress display of several lines for long renders, redrawn in place on stderr in place of the
+// sample counter
+pub struct Dashboard {
+    last_draw: Option<Instant>,
+    last_stats: Instant,
+    // number of lines drawn last time, to move back over them
+    lines: usize,
+    // average relative variance at each stats interval, oldest first
+    variance: Vec<f64>,
+    gpu_memory: usize,
+    pixels: u64,
+}
+
+impl Dashboard {
+    const REDRAW_INTERVAL: Duration = Duration::from_millis(250);
+    // downloading the film is not free, so the variance is only checked every so often
+    const STATS_INTERVAL: Duration = Duration::from_secs(10);
+    const HISTORY: usize = 30;
+
+    pub fn new(gpu_memory: usize, pixels: u64) -> Self {
+        Dashboard {
+            last_draw: None,
+            last_stats: Instant::now(),
+            lines: 0,
+            variance: vec![],
+            gpu_memory,
+            pixels,
+        }
+    }
+
+    pub fn restart(&mut self) {
+        self.last_stats = Instant::now();
+        self.variance.clear();
+    }
+
+    pub fn wants_stats(&self) -> bool {
+        self.last_stats.elapsed() >= Self::STATS_INTERVAL
+    }
+
+    pub fn add_variance(&mut self, avg_rel_variance: f64) {
+        self.last_stats = Instant::now();
+        if self.variance.len() == Self::HISTORY {
+            self.variance.remove(0);
+        }
+        self.variance.push(avg_rel_variance);
+    }
+
+    // `samples` were taken in `elapsed` toward `target` samples or the `time_limit`
+    pub fn draw(&mut self, samples: u32, target: u32, elapsed: Duration, time_limit: Duration) {
+        let done = samples >= target || elapsed >= time_limit;
+        if !done
+            && self
+                .last_draw
+                .is_some_and(|t| t.elapsed() < Self::REDRAW_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+
+        let per_sample = elapsed.as_secs_f64() / samples.max(1) as f64;
+        let by_samples = target.saturating_sub(samples) as f64 * per_sample;
+        let by_time = time_limit.saturating_sub(elapsed).as_secs_f64();
+        let eta = by_samples.min(by_time);
+        let progress = match time_limit == Duration::MAX {
+            true => samples as f64 / target.max(1) as f64,
+            false => elapsed.as_secs_f64() / (elapsed.as_secs_f64() + eta),
+        };
+        let progress = progress.clamp(0.0, 1.0);
+
+        let width = 30;
+        let filled = (progress * width as f64).round() as usize;
+        let bar = "#".repeat(filled) + &" ".repeat(width - filled);
+
+        let variance = match self.variance.last() {
+            Some(v) => format!("{v:.4e} {}", sparkline(&self.variance)),
+            None => "waiting".to_owned(),
+        };
+
+        let lines = [
+            format!("[{bar}] {:5.1}%", progress * 100.0),
+            format!("Samples    {samples} / {target}"),
+            format!(
+                "Elapsed    {}   ETA {}",
+                format_duration(elapsed.as_secs_f64()),
+                format_duration(eta)
+            ),
+            format!(
+                "Speed      {:.2} samples/s   {:.3e} paths/s",
+                1.0 / per_sample,
+                self.pixels as f64 / per_sample
+            ),
+            format!("Rel. var.  {variance}"),
+            format!("GPU memory {}", human_size(self.gpu_memory).trim()),
+        ];
+
+        let mut stderr = std::io::stderr().lock();
+        if self.lines > 0 {
+            let _ = write!(stderr, "\x1b[{}A", self.lines);
+        }
+        for line in &lines {
+            let _ = writeln!(stderr, "\r\x1b[2K{line}");
+        }
+        let _ = stderr.flush();
+        self.lines = lines.len();
+    }
+}
+
+// relative shape of a series on a log scale
+fn sparkline(values: &[f64]) -> String {
+    const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+    let logs: Vec<f64> = values.iter().map(|v| v.max(1e-30).ln()).collect();
+    let min = logs.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = logs.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    logs.iter()
+        .map(|&l| {
+            let t = match max > min {
+                true => (l - min) / (max - min),
+                false => 0.5,
+            };
+            BARS[(t * 7.0).round() as usize]
+        })
+        .collect()
+}
+
+fn format_duration(secs: f64) -> String {
+    if !secs.is_finite() {
+        return "-".to_owned();
+    }
+    let secs = secs.round() as u64;
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
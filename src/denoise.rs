@@ -0,0 +1,82 @@
+use std::sync::{Arc, Mutex};
+
+use glam::{Vec3, Vec4Swizzles};
+use image::Rgba32FImage;
+
+use crate::{download_texture_layer, xyz_to_linear_srgb};
+
+pub const AVAILABLE: bool = cfg!(feature = "denoise");
+
+// Denoises the XYZ mean image with the albedo and normal layers of `features`, returning it in XYZ
+// so the exposure and response apply the same as to the raw image
+pub fn denoise(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    xyz: &Rgba32FImage,
+    features: &wgpu::Texture,
+) -> anyhow::Result<Rgba32FImage> {
+    let downloaded = Arc::new(Mutex::new([vec![], vec![]]));
+    let mut encoder = device.create_command_encoder(&Default::default());
+    for layer in 0..2 {
+        let dl = downloaded.clone();
+        download_texture_layer(device, &mut encoder, features, layer, move |data| {
+            dl.lock().unwrap()[layer as usize] = data;
+        });
+    }
+    queue.submit([encoder.finish()]);
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    let [albedo, normal] = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+    // the filter works on linear RGB, with albedos between 0 and 1
+    let to_rgb = xyz_to_linear_srgb();
+    let color: Vec<f32> = xyz
+        .pixels()
+        .flat_map(|p| {
+            (to_rgb * Vec3::from_slice(&p.0[..3]))
+                .max(Vec3::ZERO)
+                .to_array()
+        })
+        .collect();
+    let albedo: Vec<f32> = albedo
+        .iter()
+        .flat_map(|a| (to_rgb * a.xyz()).clamp(Vec3::ZERO, Vec3::ONE).to_array())
+        .collect();
+    let normal: Vec<f32> = normal
+        .iter()
+        .flat_map(|n| n.xyz().normalize_or_zero().to_array())
+        .collect();
+
+    let rgb = filter(xyz.width(), xyz.height(), &color, &albedo, &normal)?;
+
+    let to_xyz = to_rgb.inverse();
+    let mut denoised = xyz.clone();
+    for (p, rgb) in denoised.pixels_mut().zip(rgb.chunks_exact(3)) {
+        p.0[..3].copy_from_slice(&(to_xyz * Vec3::from_slice(rgb)).to_array());
+    }
+    Ok(denoised)
+}
+
+#[cfg(feature = "denoise")]
+fn filter(
+    width: u32,
+    height: u32,
+    color: &[f32],
+    albedo: &[f32],
+    normal: &[f32],
+) -> anyhow::Result<Vec<f32>> {
+    let device = oidn::Device::new()?;
+    let mut output = vec![0.0; color.len()];
+    oidn::RayTracing::try_new(&device)?
+        .hdr(true)
+        .filter_quality(oidn::Quality::High)
+        .albedo_normal(albedo, normal)
+        .image_dimensions(width as usize, height as usize)
+        .filter(color, &mut output)?;
+    device.get_error()?;
+    Ok(output)
+}
+
+#[cfg(not(feature = "denoise"))]
+fn filter(_: u32, _: u32, _: &[f32], _: &[f32], _: &[f32]) -> anyhow::Result<Vec<f32>> {
+    anyhow::bail!("built without the `denoise` feature")
+}
@@ -0,0 +1,832 @@
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use glam::{Vec2, Vec3};
+use image::{DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+
+use crate::write_atomic;
+
+#[derive(clap::Args)]
+pub struct ExampleOptions {
+    // directory to write the scenes and the files they use to
+    #[clap(short, long, default_value = "examples")]
+    output: PathBuf,
+    #[clap(long, default_value = "256")]
+    resolution: u32,
+    #[clap(long, default_value = "64")]
+    spp: u32,
+}
+
+// A small scene showing off one feature, in or around a Cornell box
+struct Example {
+    name: &'static str,
+    about: &'static str,
+    camera: Camera,
+    // the box's walls and ceiling light, left out of scenes lit or framed some other way
+    walls: bool,
+    ceiling_light: bool,
+    world: String,
+}
+
+#[derive(Copy, Clone)]
+enum Camera {
+    Perspective,
+    Orthographic,
+    DepthOfField,
+}
+
+// Writes a scene for each material, texture, light and shape the loader supports to the output
+// directory, along with the images and meshes they use and an index of what each one is for. The
+// scenes come out the same every time, so renders of them can be compared between versions.
+pub fn generate_examples(options: &ExampleOptions) -> anyhow::Result<()> {
+    let dir = &options.output;
+    for sub in ["textures", "meshes"] {
+        std::fs::create_dir_all(dir.join(sub))
+            .with_context(|| format!("failed to create {}", dir.join(sub).display()))?;
+    }
+
+    save_image(grid_image(), &dir.join("textures/grid.png"))?;
+    save_image(rings_image(), &dir.join("textures/rings.png"))?;
+    save_image(bumps_normal_map(), &dir.join("textures/bumps.png"))?;
+    save_image(sky_image(), &dir.join("textures/sky.png"))?;
+    write_file(&dir.join("meshes/icosphere.ply"), &icosphere_ply())?;
+    write_file(&dir.join("meshes/points.ply"), &points_ply())?;
+
+    let examples = examples();
+    let mut index = String::new();
+    for example in &examples {
+        let path = dir.join(format!("{}.pbrt", example.name));
+        write_file(&path, scene_file(example, options).as_bytes())?;
+        writeln!(index, "{}.pbrt\t{}", example.name, example.about).unwrap();
+    }
+    write_file(&dir.join("index.txt"), index.as_bytes())?;
+
+    println!("Wrote {} scenes to {}", examples.len(), dir.display());
+    println!("Note: measured materials need a measured BSDF file and have no example");
+    Ok(())
+}
+
+fn scene_file(example: &Example, options: &ExampleOptions) -> String {
+    let mut s = String::new();
+    writeln!(s, "# {}", example.about).unwrap();
+    writeln!(s, "# written by `pbr-gpu gen-examples`\n").unwrap();
+    writeln!(
+        s,
+        "Film \"rgb\" \"integer xresolution\" [{0}] \"integer yresolution\" [{0}]",
+        options.resolution
+    )
+    .unwrap();
+    writeln!(s, "    \"string filename\" \"{}.exr\"", example.name).unwrap();
+    writeln!(
+        s,
+        "Sampler \"zsobol\" \"integer pixelsamples\" [{}]",
+        options.spp
+    )
+    .unwrap();
+    writeln!(s, "Integrator \"path\" \"integer maxdepth\" [8]\n").unwrap();
+    // pbrt's look at mirrors the image, so the red wall would be on the right without the scale
+    s += "Scale -1 1 1\nLookAt 0 1 3.9  0 1 0  0 1 0\n";
+    s += match example.camera {
+        Camera::Perspective => "Camera \"perspective\" \"float fov\" [38]\n",
+        Camera::Orthographic => "Camera \"orthographic\"\n",
+        Camera::DepthOfField => {
+            "Camera \"perspective\" \"float fov\" [38]\n    \
+             \"float lensradius\" [0.08] \"float focaldistance\" [3.9]\n"
+        }
+    };
+    s += "\nWorldBegin\n\n";
+    if example.walls {
+        s += &cornell_box();
+    }
+    if example.ceiling_light {
+        s += "AttributeBegin\n";
+        s += "    AreaLightSource \"diffuse\" \"rgb L\" [17 12 4]\n";
+        s += &quad(Vec3::new(-0.25, 1.99, -0.25), Vec3::X * 0.5, Vec3::Z * 0.5);
+        s += "AttributeEnd\n\n";
+    }
+    s += &example.world;
+    s
+}
+
+// white floor, ceiling and back, red on the left and green on the right, two units on a side with
+// the floor at y = 0 and the open side facing the camera
+fn cornell_box() -> String {
+    let mut s = String::new();
+    s += "MakeNamedMaterial \"white\" \"string type\" \"diffuse\" \"rgb reflectance\" [0.73 0.73 0.73]\n";
+    s += "MakeNamedMaterial \"red\" \"string type\" \"diffuse\" \"rgb reflectance\" [0.65 0.05 0.05]\n";
+    s += "MakeNamedMaterial \"green\" \"string type\" \"diffuse\" \"rgb reflectance\" [0.12 0.45 0.15]\n\n";
+    s += "AttributeBegin\n    NamedMaterial \"white\"\n";
+    s += &quad(Vec3::new(-1.0, 0.0, 1.0), Vec3::X * 2.0, Vec3::NEG_Z * 2.0);
+    s += &quad(Vec3::new(-1.0, 2.0, -1.0), Vec3::X * 2.0, Vec3::Z * 2.0);
+    s += &quad(Vec3::new(-1.0, 0.0, -1.0), Vec3::X * 2.0, Vec3::Y * 2.0);
+    s += "    NamedMaterial \"red\"\n";
+    s += &quad(Vec3::new(-1.0, 0.0, 1.0), Vec3::NEG_Z * 2.0, Vec3::Y * 2.0);
+    s += "    NamedMaterial \"green\"\n";
+    s += &quad(Vec3::new(1.0, 0.0, -1.0), Vec3::Z * 2.0, Vec3::Y * 2.0);
+    s += "AttributeEnd\n\n";
+    s
+}
+
+// a rectangle facing along `u` cross `v`, with uvs running along the edges
+fn quad(corner: Vec3, u: Vec3, v: Vec3) -> String {
+    let p = [corner, corner + u, corner + u + v, corner + v];
+    let n = u.cross(v).normalize();
+    format!(
+        "    Shape \"trianglemesh\" \"integer indices\" [0 1 2 0 2 3]\n        \
+         \"point3 P\" [{}]\n        \"normal N\" [{}]\n        \
+         \"point2 uv\" [0 0 1 0 1 1 0 1]\n",
+        join(p.map(fmt_vec3)),
+        join([n; 4].map(fmt_vec3)),
+    )
+}
+
+fn sphere(material: &str, center: Vec3, radius: f32) -> String {
+    format!(
+        "AttributeBegin\n    {material}\n    Translate {}\n    \
+         Shape \"sphere\" \"float radius\" [{radius}]\nAttributeEnd\n",
+        fmt_vec3(center)
+    )
+}
+
+fn fmt_vec3(v: Vec3) -> String {
+    // without negative zeros
+    let v = v + Vec3::ZERO;
+    format!("{} {} {}", v.x, v.y, v.z)
+}
+
+fn join<const N: usize>(parts: [String; N]) -> String {
+    parts.join("  ")
+}
+
+// three spheres in a row on the floor, for comparing variations of a material
+fn three_spheres(materials: [impl AsRef<str>; 3]) -> String {
+    let mut s = String::new();
+    for (x, material) in [-0.6, 0.0, 0.6].into_iter().zip(materials) {
+        s += &sphere(material.as_ref(), Vec3::new(x, 0.28, 0.0), 0.28);
+    }
+    s
+}
+
+fn examples() -> Vec<Example> {
+    let example = |name, about, world: String| Example {
+        name,
+        about,
+        camera: Camera::Perspective,
+        walls: true,
+        ceiling_light: true,
+        world,
+    };
+    let one_sphere = |material: &str| sphere(material, Vec3::new(0.0, 0.5, 0.0), 0.5);
+
+    let mut examples = vec![
+        // materials
+        example(
+            "material-diffuse",
+            "diffuse material with an rgb reflectance",
+            one_sphere("Material \"diffuse\" \"rgb reflectance\" [0.2 0.4 0.8]"),
+        ),
+        example(
+            "material-diffusetransmission",
+            "diffusetransmission material lit from behind",
+            one_sphere(
+                "Material \"diffusetransmission\" \"rgb reflectance\" [0.3 0.3 0.3]\n    \
+                 \"rgb transmittance\" [0.7 0.5 0.2] \"float scale\" [1]",
+            ),
+        ),
+        example(
+            "material-conductor",
+            "conductor with measured gold, copper and aluminium spectra at increasing roughness",
+            three_spheres([
+                "Material \"conductor\" \"spectrum eta\" \"metal-Au-eta\" \"spectrum k\" \"metal-Au-k\" \"float roughness\" [0]",
+                "Material \"conductor\" \"spectrum eta\" \"metal-Cu-eta\" \"spectrum k\" \"metal-Cu-k\" \"float roughness\" [0.1]",
+                "Material \"conductor\" \"spectrum eta\" \"metal-Al-eta\" \"spectrum k\" \"metal-Al-k\" \"float roughness\" [0.3]",
+            ]),
+        ),
+        example(
+            "material-conductor-anisotropic",
+            "conductor with different roughness along u and v",
+            one_sphere(
+                "Material \"conductor\" \"spectrum eta\" \"metal-Ag-eta\" \"spectrum k\" \"metal-Ag-k\"\n    \
+                 \"float uroughness\" [0.05] \"float vroughness\" [0.4]",
+            ),
+        ),
+        example(
+            "material-conductor-reflectance",
+            "conductor given by its rgb reflectance instead of eta and k",
+            one_sphere(
+                "Material \"conductor\" \"rgb reflectance\" [0.9 0.6 0.3] \"float roughness\" [0.1]",
+            ),
+        ),
+        example(
+            "material-dielectric",
+            "smooth dielectrics with a constant and a dispersive measured index of refraction",
+            sphere(
+                "Material \"dielectric\" \"float eta\" [1.5]",
+                Vec3::new(-0.45, 0.4, 0.0),
+                0.4,
+            ) + &sphere(
+                "Material \"dielectric\" \"spectrum eta\" \"glass-F11\"",
+                Vec3::new(0.45, 0.4, 0.0),
+                0.4,
+            ),
+        ),
+        example(
+            "material-dielectric-rough",
+            "rough dielectric",
+            one_sphere("Material \"dielectric\" \"float eta\" [1.5] \"float roughness\" [0.3]"),
+        ),
+        example(
+            "material-thindielectric",
+            "thindielectric pane standing in the box",
+            format!(
+                "AttributeBegin\n    Material \"thindielectric\" \"float eta\" [1.5]\n{}AttributeEnd\n",
+                quad(Vec3::new(-0.6, 0.0, 0.2), Vec3::X * 1.2, Vec3::Y * 1.2)
+            ),
+        ),
+        example(
+            "material-metallicworkflow",
+            "metallicworkflow at metallic 0, 0.5 and 1",
+            three_spheres([0.0, 0.5, 1.0].map(|m| {
+                format!(
+                    "Material \"metallicworkflow\" \"rgb reflectance\" [0.8 0.3 0.1] \"float metallic\" [{m}] \"float roughness\" [0.2]"
+                )
+            })),
+        ),
+        example(
+            "material-principled",
+            "principled material with a clearcoat and sheen",
+            one_sphere(
+                "Material \"principled\" \"rgb basecolor\" [0.1 0.3 0.7] \"float roughness\" [0.4]\n    \
+                 \"float clearcoat\" [1] \"float clearcoatroughness\" [0.05] \"float sheen\" [0.5]",
+            ),
+        ),
+        example(
+            "material-principled-transmission",
+            "principled material with full transmission",
+            one_sphere(
+                "Material \"principled\" \"rgb basecolor\" [0.9 0.9 0.9] \"float roughness\" [0.1]\n    \
+                 \"float transmission\" [1] \"float eta\" [1.45]",
+            ),
+        ),
+        example(
+            "material-coateddiffuse",
+            "coateddiffuse, which loads as a metallic workflow material",
+            one_sphere(
+                "Material \"coateddiffuse\" \"rgb reflectance\" [0.7 0.1 0.1] \"float roughness\" [0.05]",
+            ),
+        ),
+        example(
+            "material-coatedconductor",
+            "coatedconductor, which loads as its conductor",
+            one_sphere(
+                "Material \"coatedconductor\" \"spectrum conductor.eta\" \"metal-CuZn-eta\"\n    \
+                 \"spectrum conductor.k\" \"metal-CuZn-k\" \"float conductor.roughness\" [0.1]",
+            ),
+        ),
+        example(
+            "material-mix",
+            "mix of gold and a diffuse material by a checkerboard",
+            "MakeNamedMaterial \"gold\" \"string type\" \"conductor\"\n    \
+             \"spectrum eta\" \"metal-Au-eta\" \"spectrum k\" \"metal-Au-k\" \"float roughness\" [0.05]\n\
+             MakeNamedMaterial \"matte\" \"string type\" \"diffuse\" \"rgb reflectance\" [0.1 0.1 0.1]\n\
+             Texture \"amount\" \"float\" \"checkerboard\" \"float uscale\" [8] \"float vscale\" [8]\n\
+             MakeNamedMaterial \"mixed\" \"string type\" \"mix\" \"string materials\" [\"gold\" \"matte\"]\n    \
+             \"texture amount\" \"amount\"\n"
+                .to_owned()
+                + &one_sphere("NamedMaterial \"mixed\""),
+        ),
+        // lit by the glowing meshes alone, which the ceiling light would drown out
+        Example {
+            ceiling_light: false,
+            ..example(
+            "material-temperature",
+            "materials glowing by their temperature, at 2500 K and 3500 K",
+            // meshes rather than spheres, which can't be sampled as lights
+            [(-0.45, 2500), (0.45, 3500)]
+                .map(|(x, kelvin)| {
+                    format!(
+                        "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.2 0.2 0.2] \
+                         \"float temperature\" [{kelvin}]\n    Translate {x} 0.3 0\n    \
+                         Scale 0.3 0.3 0.3\n    \
+                         Shape \"plymesh\" \"string filename\" \"meshes/icosphere.ply\"\nAttributeEnd\n"
+                    )
+                })
+                .concat(),
+        )},
+        // textures
+        example(
+            "texture-constant",
+            "constant spectrum texture",
+            "Texture \"orange\" \"spectrum\" \"constant\" \"rgb value\" [0.8 0.4 0.1]\n".to_owned()
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"orange\""),
+        ),
+        example(
+            "texture-scale",
+            "scale texture darkening a checkerboard",
+            "Texture \"checks\" \"spectrum\" \"checkerboard\" \"float uscale\" [8] \"float vscale\" [8]\n    \
+             \"rgb tex1\" [0.9 0.9 0.9] \"rgb tex2\" [0.1 0.3 0.8]\n\
+             Texture \"dark\" \"spectrum\" \"scale\" \"texture tex\" \"checks\" \"float scale\" [0.4]\n"
+                .to_owned()
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"dark\""),
+        ),
+        example(
+            "texture-mix",
+            "mix texture blending two colors by an image",
+            "Texture \"rings\" \"float\" \"imagemap\" \"string filename\" \"textures/rings.png\"\n\
+             Texture \"blend\" \"spectrum\" \"mix\" \"rgb tex1\" [0.8 0.1 0.1] \"rgb tex2\" [0.1 0.1 0.8]\n    \
+             \"texture amount\" \"rings\"\n"
+                .to_owned()
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"blend\""),
+        ),
+        example(
+            "texture-checkerboard",
+            "checkerboards with uv and planar mappings",
+            "Texture \"uvchecks\" \"spectrum\" \"checkerboard\" \"float uscale\" [10] \"float vscale\" [10]\n    \
+             \"rgb tex1\" [0.8 0.8 0.8] \"rgb tex2\" [0.2 0.2 0.2]\n\
+             Texture \"planarchecks\" \"spectrum\" \"checkerboard\" \"string mapping\" \"planar\"\n    \
+             \"vector3 v1\" [4 0 0] \"vector3 v2\" [0 4 0] \"rgb tex1\" [0.8 0.6 0.1] \"rgb tex2\" [0.1 0.2 0.5]\n"
+                .to_owned()
+                + &format!(
+                    "AttributeBegin\n    Material \"diffuse\" \"texture reflectance\" \"uvchecks\"\n{}AttributeEnd\n",
+                    quad(Vec3::new(-0.8, 0.001, 0.8), Vec3::X * 1.6, Vec3::NEG_Z * 1.6)
+                )
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"planarchecks\""),
+        ),
+        example(
+            "texture-imagemap",
+            "image texture with spherical mapping",
+            "AttributeBegin\n    Translate 0 0.5 0\n    \
+             Texture \"grid\" \"spectrum\" \"imagemap\" \"string filename\" \"textures/grid.png\"\n        \
+             \"string mapping\" \"spherical\"\nAttributeEnd\n"
+                .to_owned()
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"grid\""),
+        ),
+        example(
+            "texture-wireframe",
+            "wireframe texture over an icosphere",
+            "Texture \"wires\" \"spectrum\" \"wireframe\" \"rgb wire\" [0.05 0.05 0.05] \"rgb fill\" [0.8 0.8 0.8]\n    \
+             \"float width\" [0.01]\n\
+             AttributeBegin\n    Material \"diffuse\" \"texture reflectance\" \"wires\"\n    \
+             Translate 0 0.5 0\n    Scale 0.5 0.5 0.5\n    \
+             Shape \"plymesh\" \"string filename\" \"meshes/icosphere.ply\"\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "texture-uvchecker",
+            "uv checker texture for checking texture coordinates",
+            "Texture \"uvs\" \"spectrum\" \"uvchecker\" \"float cells\" [8]\n".to_owned()
+                + &one_sphere("Material \"diffuse\" \"texture reflectance\" \"uvs\""),
+        ),
+        example(
+            "texture-vertexcolor",
+            "colored point cloud shaded by its vertex colors",
+            "Texture \"colors\" \"spectrum\" \"vertexcolor\"\n\
+             AttributeBegin\n    Material \"diffuse\" \"texture reflectance\" \"colors\"\n    \
+             Translate 0 0.5 0\n    Scale 0.5 0.5 0.5\n    \
+             Shape \"plymesh\" \"string filename\" \"meshes/points.ply\" \"float radius\" [0.04]\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "texture-normalmap",
+            "normal map of bumps on a diffuse wall",
+            format!(
+                "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.7 0.7 0.7] \
+                 \"string normalmap\" \"textures/bumps.png\"\n{}AttributeEnd\n",
+                quad(Vec3::new(-0.7, 0.2, -0.5), Vec3::X * 1.4, Vec3::Y * 1.4)
+            ),
+        ),
+        example(
+            "texture-alpha",
+            "alpha texture cutting holes in a mesh",
+            "Texture \"holes\" \"float\" \"checkerboard\" \"float uscale\" [6] \"float vscale\" [6]\n\
+             AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.8 0.5 0.2]\n"
+                .to_owned()
+                + &quad(Vec3::new(-0.6, 0.2, 0.0), Vec3::X * 1.2, Vec3::Y * 1.2)
+                    .replacen("Shape \"trianglemesh\"", "Shape \"trianglemesh\" \"texture alpha\" \"holes\"", 1)
+                + "AttributeEnd\n",
+        ),
+        // lights
+        example(
+            "light-area",
+            "the plain box lit by a one-sided diffuse area light",
+            String::new(),
+        ),
+        Example {
+            ceiling_light: false,
+            ..example(
+                "light-area-twosided",
+                "two-sided area light hanging in the middle of the box",
+                "AttributeBegin\n    \
+                 AreaLightSource \"diffuse\" \"rgb L\" [8 8 8] \"bool twosided\" true\n"
+                    .to_owned()
+                    + &quad(Vec3::new(-0.3, 0.7, 0.0), Vec3::X * 0.6, Vec3::Y * 0.6)
+                    + "AttributeEnd\n",
+            )
+        },
+        Example {
+            ceiling_light: false,
+            ..example(
+                "light-blackbody",
+                "area light with a blackbody emission spectrum",
+                "AttributeBegin\n    \
+                 AreaLightSource \"diffuse\" \"blackbody L\" [2700] \"float scale\" [15]\n"
+                    .to_owned()
+                    + &quad(Vec3::new(-0.25, 1.99, -0.25), Vec3::X * 0.5, Vec3::Z * 0.5)
+                    + "AttributeEnd\n",
+            )
+        },
+        Example {
+            ceiling_light: false,
+            ..example(
+                "light-spectrum",
+                "area light with a piecewise linear emission spectrum",
+                "AttributeBegin\n    \
+                 AreaLightSource \"diffuse\" \"spectrum L\" [400 0  480 20  520 20  560 0  600 0  640 15  680 15  700 0]\n"
+                    .to_owned()
+                    + &quad(Vec3::new(-0.25, 1.99, -0.25), Vec3::X * 0.5, Vec3::Z * 0.5)
+                    + "AttributeEnd\n",
+            )
+        },
+        Example {
+            walls: false,
+            ceiling_light: false,
+            ..example(
+                "light-infinite-uniform",
+                "uniform infinite light over spheres on a ground plane",
+                ground() + &three_spheres(["Material \"diffuse\" \"rgb reflectance\" [0.7 0.2 0.2]", "Material \"conductor\" \"float roughness\" [0.1]", "Material \"dielectric\""])
+                    + "LightSource \"infinite\" \"rgb L\" [0.8 0.9 1]\n",
+            )
+        },
+        Example {
+            walls: false,
+            ceiling_light: false,
+            ..example(
+                "light-infinite-image",
+                "image infinite light with a sun over spheres on a ground plane",
+                ground() + &three_spheres(["Material \"diffuse\" \"rgb reflectance\" [0.7 0.2 0.2]", "Material \"conductor\" \"float roughness\" [0.1]", "Material \"dielectric\""])
+                    + &sky_light(""),
+            )
+        },
+        Example {
+            ceiling_light: false,
+            ..example(
+                "light-portal",
+                "image infinite light entering the box through a portal over its open side",
+                one_sphere("Material \"diffuse\" \"rgb reflectance\" [0.7 0.7 0.7]")
+                    // the open side of the box, in the sky's z-up space
+                    + &sky_light(" \"point3 portal\" [-1 -1 0  1 -1 0  1 -1 2  -1 -1 2]"),
+            )
+        },
+        // shapes
+        example(
+            "shape-sphere",
+            "full and partial spheres",
+            sphere(
+                "Material \"diffuse\" \"rgb reflectance\" [0.7 0.7 0.2]",
+                Vec3::new(-0.45, 0.35, 0.0),
+                0.35,
+            ) + "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.2 0.6 0.7]\n    \
+                 Translate 0.45 0.35 0\n    Rotate -60 1 0 0\n    \
+                 Shape \"sphere\" \"float radius\" [0.35] \"float zmin\" [-0.2] \"float zmax\" [0.25]\nAttributeEnd\n",
+        ),
+        example(
+            "shape-trianglemesh",
+            "triangle mesh with shading normals and uvs",
+            "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.6 0.6 0.6]\n    \
+             Translate 0 0.5 0\n    Rotate 30 0 1 0\n    Scale 0.45 0.45 0.45\n"
+                .to_owned()
+                + &octahedron("trianglemesh")
+                + "AttributeEnd\n",
+        ),
+        example(
+            "shape-loopsubdiv",
+            "loop subdivision surface, which loads as its control mesh",
+            "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.3 0.6 0.3]\n    \
+             Translate 0 0.5 0\n    Rotate 30 0 1 0\n    Scale 0.45 0.45 0.45\n"
+                .to_owned()
+                + &octahedron("loopsubdiv")
+                + "AttributeEnd\n",
+        ),
+        example(
+            "shape-plymesh",
+            "binary ply triangle mesh",
+            "AttributeBegin\n    Material \"conductor\" \"float roughness\" [0.15]\n    \
+             Translate 0 0.5 0\n    Scale 0.5 0.5 0.5\n    \
+             Shape \"plymesh\" \"string filename\" \"meshes/icosphere.ply\"\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "shape-sdf",
+            "signed distance field of a rounded box smoothly joined with a torus",
+            "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.7 0.4 0.2]\n    \
+             Shape \"sdf\" \"string ops\" [\"box\" \"torus\" \"union\"]\n        \
+             \"float params\" [0 0.3 0  0.3 0.3 0.3 0.05  0 0.6 0  0.45 0.08  0.1]\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "shape-csg",
+            "box with a sphere and a cylinder cut out of it",
+            "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.3 0.4 0.7]\n    \
+             Shape \"csg\" \"string ops\" [\"box\" \"sphere\" \"difference\" \"cylinder\" \"difference\"]\n        \
+             \"float params\" [-0.4 0 -0.4 0.4 0.8 0.4  0 0.8 0 0.5  -0.6 0.35 0 0.6 0.35 0 0.15]\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "shape-heightfield",
+            "heightfield given inline",
+            "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.5 0.6 0.4]\n    \
+             Translate -0.8 0.01 0.8\n    Rotate -90 1 0 0\n    Scale 1.6 1.6 0.5\n"
+                .to_owned()
+                + &heightfield()
+                + "AttributeEnd\n",
+        ),
+        example(
+            "shape-scatter",
+            "copies of an object scattered over the floor",
+            "ObjectBegin \"pebble\"\n    Material \"diffuse\" \"rgb reflectance\" [0.5 0.5 0.6]\n    \
+             Scale 1 0.6 1\n    Shape \"sphere\" \"float radius\" [0.05]\nObjectEnd\n\
+             AttributeBegin\n    \
+             Shape \"scatter\" \"string object\" \"pebble\" \"integer count\" [150] \"integer seed\" [1]\n        \
+             \"float scale\" [0.5 1.5] \"integer indices\" [0 1 2 0 2 3]\n        \
+             \"point3 P\" [-0.9 0 0.9  0.9 0 0.9  0.9 0 -0.9  -0.9 0 -0.9]\nAttributeEnd\n"
+                .to_owned(),
+        ),
+        example(
+            "shape-instances",
+            "one object instanced with different transforms",
+            "ObjectBegin \"ring\"\n    Material \"conductor\" \"float roughness\" [0.2]\n    \
+             Shape \"sdf\" \"string ops\" [\"torus\"] \"float params\" [0 0 0  0.2 0.05]\nObjectEnd\n"
+                .to_owned()
+                + &(0..5)
+                    .map(|i| {
+                        format!(
+                            "AttributeBegin\n    Translate {} {} 0\n    Rotate {} 1 0 0\n    \
+                             ObjectInstance \"ring\"\nAttributeEnd\n",
+                            -0.6 + 0.3 * i as f32,
+                            0.3 + 0.25 * i as f32,
+                            30 * i,
+                        )
+                    })
+                    .collect::<String>(),
+        ),
+    ];
+
+    // cameras
+    examples.push(Example {
+        camera: Camera::Orthographic,
+        ..example(
+            "camera-orthographic",
+            "orthographic camera",
+            three_spheres(["Material \"diffuse\" \"rgb reflectance\" [0.7 0.7 0.7]"; 3]),
+        )
+    });
+    examples.push(Example {
+        camera: Camera::DepthOfField,
+        ..example(
+            "camera-depth-of-field",
+            "thin lens camera focused on the middle of three staggered spheres",
+            ["-0.6 0.25 -0.6", "0 0.25 0", "0.6 0.25 0.6"]
+                .map(|p| {
+                    format!(
+                        "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.7 0.7 0.7]\n    \
+                         Translate {p}\n    Shape \"sphere\" \"float radius\" [0.25]\nAttributeEnd\n"
+                    )
+                })
+                .concat(),
+        )
+    });
+    examples
+}
+
+fn ground() -> String {
+    format!(
+        "AttributeBegin\n    Material \"diffuse\" \"rgb reflectance\" [0.5 0.5 0.5]\n{}AttributeEnd\n",
+        quad(
+            Vec3::new(-20.0, 0.0, 20.0),
+            Vec3::X * 40.0,
+            Vec3::NEG_Z * 40.0
+        )
+    )
+}
+
+// the sky image is z-up, like most environment maps
+fn sky_light(extra: &str) -> String {
+    format!(
+        "AttributeBegin\n    Rotate -90 1 0 0\n    \
+         LightSource \"infinite\" \"string filename\" \"textures/sky.png\"{extra}\nAttributeEnd\n"
+    )
+}
+
+fn octahedron(shape: &str) -> String {
+    let p = [
+        Vec3::X,
+        Vec3::NEG_X,
+        Vec3::Y,
+        Vec3::NEG_Y,
+        Vec3::Z,
+        Vec3::NEG_Z,
+    ];
+    let uv = p.map(|p| {
+        let uv = Vec2::new(
+            p.x.atan2(p.z) / std::f32::consts::TAU + 0.5,
+            p.y * 0.5 + 0.5,
+        );
+        format!("{} {}", uv.x, uv.y)
+    });
+    format!(
+        "    Shape \"{shape}\" \"integer indices\" [0 2 4  4 2 1  1 2 5  5 2 0  4 3 0  1 3 4  5 3 1  0 3 5]\n        \
+         \"point3 P\" [{}]\n        \"normal N\" [{}]\n        \"point2 uv\" [{}]\n",
+        join(p.map(fmt_vec3)),
+        join(p.map(fmt_vec3)),
+        join(uv),
+    )
+}
+
+fn heightfield() -> String {
+    const N: u32 = 24;
+    let heights: Vec<String> = (0..N * N)
+        .map(|i| {
+            let p = Vec2::new((i % N) as f32, (i / N) as f32) / (N - 1) as f32 * 2.0 - 1.0;
+            let h = (p.x * 5.0).sin() * (p.y * 4.0).cos() * 0.2
+                + 0.3 * (1.0 - p.length_squared()).max(0.0);
+            format!("{h:.4}")
+        })
+        .collect();
+    format!(
+        "    Shape \"heightfield\" \"integer nu\" [{N}] \"integer nv\" [{N}]\n        \"float Pz\" [{}]\n",
+        heights.join(" ")
+    )
+}
+
+fn grid_image() -> RgbImage {
+    RgbImage::from_fn(256, 256, |x, y| {
+        let line = x % 32 < 3 || y % 32 < 3;
+        match line {
+            true => Rgb([20, 20, 20]),
+            false => Rgb([(x * 255 / 256) as u8, (y * 255 / 256) as u8, 160]),
+        }
+    })
+}
+
+// grayscale, for float textures
+fn rings_image() -> GrayImage {
+    GrayImage::from_fn(256, 256, |x, y| {
+        let r = (Vec2::new(x as f32, y as f32) - 127.5).length();
+        Luma([((r * 0.15).sin() * 127.5 + 127.5) as u8])
+    })
+}
+
+// tangent space normals of a grid of round bumps
+fn bumps_normal_map() -> RgbImage {
+    RgbImage::from_fn(256, 256, |x, y| {
+        let p = Vec2::new((x % 32) as f32, (y % 32) as f32) / 16.0 - 1.0;
+        let r2 = p.length_squared();
+        let n = match r2 < 0.8 {
+            true => Vec3::new(p.x, p.y, (1.0 - r2).sqrt()).normalize(),
+            false => Vec3::Z,
+        };
+        Rgb((n * 0.5 + 0.5).map(|c| c * 255.0).as_u8vec3().to_array())
+    })
+}
+
+// an equal-area square environment with z up: a blue sky brighter towards the horizon, a small
+// sun, and dark ground
+fn sky_image() -> RgbImage {
+    const SIZE: u32 = 256;
+    let sun = Vec2::new(0.25, 0.15);
+    RgbImage::from_fn(SIZE, SIZE, |x, y| {
+        let p = (Vec2::new(x as f32, y as f32) + 0.5) / SIZE as f32 * 2.0 - 1.0;
+        // distance from straight up, reaching 1 at the horizon
+        let r = p.x.abs() + p.y.abs();
+        if r > 1.0 {
+            return Rgb([40, 35, 30]);
+        }
+        if p.distance(sun) < 0.03 {
+            return Rgb([255, 250, 235]);
+        }
+        let sky = Vec3::new(0.25, 0.45, 0.85).lerp(Vec3::new(0.8, 0.85, 0.9), r * r);
+        Rgb((sky * 255.0).as_u8vec3().to_array())
+    })
+}
+
+// an icosahedron subdivided twice and pushed out onto the unit sphere, with normals
+fn icosphere_ply() -> Vec<u8> {
+    let t = (1.0 + 5f32.sqrt()) / 2.0;
+    let mut vertices: Vec<Vec3> = [
+        [-1.0, t, 0.0],
+        [1.0, t, 0.0],
+        [-1.0, -t, 0.0],
+        [1.0, -t, 0.0],
+        [0.0, -1.0, t],
+        [0.0, 1.0, t],
+        [0.0, -1.0, -t],
+        [0.0, 1.0, -t],
+        [t, 0.0, -1.0],
+        [t, 0.0, 1.0],
+        [-t, 0.0, -1.0],
+        [-t, 0.0, 1.0],
+    ]
+    .into_iter()
+    .map(|p| Vec3::from(p).normalize())
+    .collect();
+    let mut faces: Vec<[u32; 3]> = vec![
+        [0, 11, 5],
+        [0, 5, 1],
+        [0, 1, 7],
+        [0, 7, 10],
+        [0, 10, 11],
+        [1, 5, 9],
+        [5, 11, 4],
+        [11, 10, 2],
+        [10, 7, 6],
+        [7, 1, 8],
+        [3, 9, 4],
+        [3, 4, 2],
+        [3, 2, 6],
+        [3, 6, 8],
+        [3, 8, 9],
+        [4, 9, 5],
+        [2, 4, 11],
+        [6, 2, 10],
+        [8, 6, 7],
+        [9, 8, 1],
+    ];
+    for _ in 0..2 {
+        let mut midpoints = std::collections::HashMap::new();
+        let mut midpoint = |a: u32, b: u32, vertices: &mut Vec<Vec3>| {
+            *midpoints.entry((a.min(b), a.max(b))).or_insert_with(|| {
+                vertices.push((vertices[a as usize] + vertices[b as usize]).normalize());
+                vertices.len() as u32 - 1
+            })
+        };
+        faces = faces
+            .into_iter()
+            .flat_map(|[a, b, c]| {
+                let ab = midpoint(a, b, &mut vertices);
+                let bc = midpoint(b, c, &mut vertices);
+                let ca = midpoint(c, a, &mut vertices);
+                [[a, ab, ca], [b, bc, ab], [c, ca, bc], [ab, bc, ca]]
+            })
+            .collect();
+    }
+
+    let mut data = format!(
+        "ply\nformat binary_little_endian 1.0\nelement vertex {}\n\
+         property float x\nproperty float y\nproperty float z\n\
+         property float nx\nproperty float ny\nproperty float nz\n\
+         element face {}\nproperty list uchar int vertex_indices\nend_header\n",
+        vertices.len(),
+        faces.len()
+    )
+    .into_bytes();
+    for v in &vertices {
+        // on the unit sphere, the position is the normal
+        for c in v.to_array().into_iter().chain(v.to_array()) {
+            data.extend(c.to_le_bytes());
+        }
+    }
+    for face in &faces {
+        data.push(3);
+        for i in face {
+            data.extend(i.to_le_bytes());
+        }
+    }
+    data
+}
+
+// points spread evenly over the unit sphere on a Fibonacci spiral, colored by direction
+fn points_ply() -> Vec<u8> {
+    const COUNT: u32 = 1500;
+    let mut data = format!(
+        "ply\nformat binary_little_endian 1.0\nelement vertex {COUNT}\n\
+         property float x\nproperty float y\nproperty float z\n\
+         property uchar red\nproperty uchar green\nproperty uchar blue\nend_header\n"
+    )
+    .into_bytes();
+    let golden_angle = std::f32::consts::PI * (3.0 - 5f32.sqrt());
+    for i in 0..COUNT {
+        let y = 1.0 - 2.0 * (i as f32 + 0.5) / COUNT as f32;
+        let r = (1.0 - y * y).sqrt();
+        let phi = golden_angle * i as f32;
+        let p = Vec3::new(r * phi.cos(), y, r * phi.sin());
+        for c in p.to_array() {
+            data.extend(c.to_le_bytes());
+        }
+        data.extend((p * 0.5 + 0.5).map(|c| c * 255.0).as_u8vec3().to_array());
+    }
+    data
+}
+
+fn save_image(image: impl Into<DynamicImage>, path: &Path) -> anyhow::Result<()> {
+    let image = image.into();
+    write_atomic(path, |tmp| {
+        Ok(image.save_with_format(tmp, image::ImageFormat::Png)?)
+    })
+    .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn write_file(path: &Path, data: &[u8]) -> anyhow::Result<()> {
+    write_atomic(path, |tmp| Ok(std::fs::write(tmp, data)?))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
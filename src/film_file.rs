@@ -0,0 +1,233 @@
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use glam::{Vec4, Vec4Swizzles};
+
+use crate::response::Response;
+use crate::{film_stats, save_image, write_atomic};
+
+const MAGIC: &[u8; 8] = b"PBRFILM\0";
+const VERSION: u32 = 1;
+
+// The accumulated mean and variance of every pixel of a render, saved so that renders of disjoint
+// sample ranges, such as from different machines using --sample-offset, can be combined exactly.
+// Stored little-endian as the magic, version, size, first sample, sample count, render time in
+// seconds and exposure scale, then the pixels of the mean and variance.
+pub struct FilmData {
+    pub width: u32,
+    pub height: u32,
+    pub first_sample: u32,
+    pub samples: u32,
+    pub time: f64,
+    pub scale: f32,
+    // XYZ and the sample count
+    pub mean: Vec<Vec4>,
+    // sum of squared differences from the mean in XYZ
+    pub variance: Vec<Vec4>,
+}
+
+impl FilmData {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut data = MAGIC.to_vec();
+        for v in [
+            VERSION,
+            self.width,
+            self.height,
+            self.first_sample,
+            self.samples,
+        ] {
+            data.extend(v.to_le_bytes());
+        }
+        data.extend(self.time.to_le_bytes());
+        data.extend(self.scale.to_le_bytes());
+        for pixels in [&self.mean, &self.variance] {
+            data.extend(
+                pixels
+                    .iter()
+                    .flat_map(|p| p.to_array().map(f32::to_le_bytes))
+                    .flatten(),
+            );
+        }
+
+        write_atomic(path, |tmp| Ok(std::fs::write(tmp, &data)?))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::read(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+            .with_context(|| format!("failed to load film from {}", path.display()))
+    }
+
+    fn read(r: &mut impl Read) -> anyhow::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a film file");
+        }
+        let mut word = || -> anyhow::Result<[u8; 4]> {
+            let mut bytes = [0; 4];
+            r.read_exact(&mut bytes)?;
+            Ok(bytes)
+        };
+        let version = u32::from_le_bytes(word()?);
+        if version != VERSION {
+            bail!("unsupported version {version}, expected {VERSION}");
+        }
+        let width = u32::from_le_bytes(word()?);
+        let height = u32::from_le_bytes(word()?);
+        let first_sample = u32::from_le_bytes(word()?);
+        let samples = u32::from_le_bytes(word()?);
+        let time = f64::from_le_bytes([word()?, word()?].concat().try_into().unwrap());
+        let scale = f32::from_le_bytes(word()?);
+
+        let pixels = width as usize * height as usize;
+        let mut read_pixels = || -> anyhow::Result<Vec<Vec4>> {
+            let mut data = vec![];
+            r.take(pixels as u64 * 16).read_to_end(&mut data)?;
+            if data.len() != pixels * 16 {
+                bail!("file is truncated");
+            }
+            Ok(data
+                .chunks_exact(16)
+                .map(|p| {
+                    Vec4::from_array(std::array::from_fn(|i| {
+                        f32::from_le_bytes(p[i * 4..i * 4 + 4].try_into().unwrap())
+                    }))
+                })
+                .collect())
+        };
+        let mean = read_pixels()?;
+        let variance = read_pixels()?;
+
+        Ok(FilmData {
+            width,
+            height,
+            first_sample,
+            samples,
+            time,
+            scale,
+            mean,
+            variance,
+        })
+    }
+
+    // Chan et al.'s parallel update of the mean and variance of each pixel, which is exact however
+    // the samples were split up
+    fn merge(&mut self, other: &FilmData) {
+        for i in 0..self.mean.len() {
+            let (a, b) = (self.mean[i], other.mean[i]);
+            let n = a.w + b.w;
+            if b.w == 0.0 {
+                continue;
+            }
+            let delta = b.xyz() - a.xyz();
+            let mean = a.xyz() + delta * (b.w / n);
+            let s =
+                self.variance[i].xyz() + other.variance[i].xyz() + delta * delta * (a.w * b.w / n);
+            self.mean[i] = mean.extend(n);
+            self.variance[i] = s.extend(0.0);
+        }
+        self.samples += other.samples;
+        self.time += other.time;
+    }
+}
+
+#[derive(clap::Args)]
+pub struct MergeOptions {
+    // films written by renders with --save-film
+    #[clap(required = true)]
+    films: Vec<PathBuf>,
+    // the combined image, in any format a render can write
+    #[clap(short, long)]
+    output: PathBuf,
+    // also write the combined film, to merge with more later
+    #[clap(long)]
+    save_film: Option<PathBuf>,
+}
+
+// Combines the films of several renders of the same scene into one image, as if it had been
+// rendered with all of their samples
+pub fn merge_films(options: &MergeOptions) -> anyhow::Result<()> {
+    let mut films = options
+        .films
+        .iter()
+        .map(|path| Ok((path, FilmData::load(path)?)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    films.sort_by_key(|(_, film)| film.first_sample);
+
+    let (first_path, first) = &films[0];
+    for pair in films.windows(2) {
+        let [(a_path, a), (b_path, b)] = pair else {
+            unreachable!()
+        };
+        if (b.width, b.height) != (first.width, first.height) {
+            bail!(
+                "{} is {}x{}, but {} is {}x{}",
+                b_path.display(),
+                b.width,
+                b.height,
+                first_path.display(),
+                first.width,
+                first.height
+            );
+        }
+        // the same sample numbers give the same samples, which would count twice
+        if a.first_sample + a.samples > b.first_sample {
+            println!(
+                "Warning: {} and {} both rendered sample {}; use --sample-offset to render \
+                 disjoint ranges",
+                a_path.display(),
+                b_path.display(),
+                b.first_sample
+            );
+        }
+        if b.scale != first.scale {
+            println!(
+                "Warning: {} has exposure scale {}, but {} has {}; using {}",
+                b_path.display(),
+                b.scale,
+                first_path.display(),
+                first.scale,
+                first.scale
+            );
+        }
+    }
+
+    let mut films = films.into_iter().map(|(_, film)| film);
+    let mut merged = films.next().unwrap();
+    for film in films {
+        merged.merge(&film);
+    }
+
+    let stats = film_stats(
+        merged.width,
+        merged.height,
+        merged.mean.clone(),
+        &merged.variance,
+        Duration::from_secs_f64(merged.time),
+    );
+    println!(
+        "Merged {} films, {} samples in {:.2} seconds of rendering",
+        options.films.len(),
+        merged.samples,
+        merged.time
+    );
+    println!("Average samples per pixel: {}", stats.avg_spp);
+    println!("Average relative variance: {}", stats.avg_rel_variance);
+    println!("Average relative error: {}", stats.avg_rel_error.sqrt());
+    println!("Efficiency: {}", stats.efficiency);
+
+    // without the response curves and lens effects of the renders, which films don't keep
+    save_image(
+        &stats.mean_image,
+        merged.scale,
+        &Response::default(),
+        &options.output,
+    )?;
+    if let Some(path) = &options.save_film {
+        merged.save(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use glam::Vec2;
+use serde::{Deserialize, Serialize};
+
+use crate::scene::{Scene, TableSampler1d};
+
+// Pixel reconstruction filters, applied by sampling film positions proportional to the filter
+// and weighting samples by its sign. All filters are separable.
+
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum, Serialize, Deserialize)]
+pub enum FilterType {
+    Box,
+    Gaussian,
+    Mitchell,
+    // windowed by a wider sinc
+    Sinc,
+    Triangle,
+    BlackmanHarris,
+}
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    pub ty: FilterType,
+    pub radius: Vec2,
+    // sigma for gaussian, B and C for mitchell, tau for sinc
+    pub params: [f32; 2],
+}
+
+impl Filter {
+    // table entries per pixel of filter width
+    const TABLE_DENSITY: f32 = 32.0;
+
+    // pbrt's defaults
+    pub fn new(ty: FilterType) -> Self {
+        let (radius, params) = match ty {
+            FilterType::Box => (0.5, [0.0; 2]),
+            FilterType::Gaussian => (1.5, [0.5, 0.0]),
+            FilterType::Mitchell => (2.0, [1.0 / 3.0; 2]),
+            FilterType::Sinc => (4.0, [3.0, 0.0]),
+            FilterType::Triangle => (2.0, [0.0; 2]),
+            FilterType::BlackmanHarris => (1.5, [0.0; 2]),
+        };
+        Filter {
+            ty,
+            radius: Vec2::splat(radius),
+            params,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self.ty {
+            FilterType::Box => "box",
+            FilterType::Gaussian => "gaussian",
+            FilterType::Mitchell => "mitchell",
+            FilterType::Sinc => "sinc",
+            FilterType::Triangle => "triangle",
+            FilterType::BlackmanHarris => "blackman-harris",
+        }
+    }
+
+    // value along one axis with the given radius
+    fn evaluate(&self, x: f32, radius: f32) -> f32 {
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        match self.ty {
+            FilterType::Box => 1.0,
+            FilterType::Gaussian => {
+                let sigma = self.params[0];
+                let g = |x: f32| (-x * x / (2.0 * sigma * sigma)).exp();
+                (g(x) - g(radius)).max(0.0)
+            }
+            FilterType::Mitchell => {
+                let [b, c] = self.params;
+                let x = 2.0 * x / radius;
+                let v = match x <= 1.0 {
+                    true => {
+                        (12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                            + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                            + (6.0 - 2.0 * b)
+                    }
+                    false => {
+                        (-b - 6.0 * c) * x * x * x
+                            + (6.0 * b + 30.0 * c) * x * x
+                            + (-12.0 * b - 48.0 * c) * x
+                            + (8.0 * b + 24.0 * c)
+                    }
+                };
+                v / 6.0
+            }
+            FilterType::Sinc => {
+                let sinc = |x: f32| match x < 1e-5 {
+                    true => 1.0,
+                    false => (std::f32::consts::PI * x).sin() / (std::f32::consts::PI * x),
+                };
+                sinc(x) * sinc(x / self.params[0])
+            }
+            FilterType::Triangle => radius - x,
+            FilterType::BlackmanHarris => {
+                let t = std::f32::consts::PI * (x / radius + 1.0);
+                0.35875 - 0.48829 * t.cos() + 0.14128 * (2.0 * t).cos() - 0.01168 * (3.0 * t).cos()
+            }
+        }
+    }
+
+    // Sampling table and normalized values for each axis. The table follows the absolute value of
+    // the filter, and the values are divided by the filter's integral so that the weight of a
+    // sample is value / pdf.
+    pub fn tabulate(&self, scene: &mut Scene) -> [FilterTable; 2] {
+        [self.radius.x, self.radius.y].map(|radius| {
+            let radius = radius.max(1e-3);
+            let len = (2.0 * radius * Self::TABLE_DENSITY).ceil().max(1.0) as usize;
+            let values: Vec<f32> = (0..len)
+                .map(|i| {
+                    let x = ((i as f32 + 0.5) / len as f32 * 2.0 - 1.0) * radius;
+                    self.evaluate(x, radius)
+                })
+                .collect();
+            let integral = values.iter().sum::<f32>() * 2.0 * radius / len as f32;
+            let normalized: Vec<f32> = values.iter().map(|v| v / integral).collect();
+            FilterTable {
+                table: scene.add_1d_table_sampler(-radius, radius, &values),
+                values: scene.add_float_data(&normalized),
+            }
+        })
+    }
+}
+
+#[derive(Copy, Clone, Debug, bytemuck::NoUninit)]
+#[repr(C)]
+pub struct FilterTable {
+    table: TableSampler1d,
+    values: u32,
+}
@@ -0,0 +1,115 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use image::{Rgb, RgbImage};
+
+use crate::scene::MaterialId;
+use crate::{download_texture, heatmap, suffixed_path};
+
+// how far a pixel has to be past what it should be to count, beyond 3 standard errors
+const TOLERANCE: f32 = 0.01;
+
+// The white furnace audit: with every light replaced by a uniform environment and every color of
+// every material set to the albedo, a pixel can be no brighter than the environment, and at albedo
+// 1 no darker either, whatever the geometry. Reports the pixels that gain or lose energy grouped
+// by the type of the material first hit, and writes them to `<output>-furnace.png`, red where
+// energy was gained and blue where it was lost.
+pub fn audit(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    [mean, variance, aov]: [&wgpu::Texture; 3],
+    albedo: f32,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let (width, height) = (mean.width(), mean.height());
+    let downloaded = Arc::new(Mutex::new([const { vec![] }; 3]));
+    let mut encoder = device.create_command_encoder(&Default::default());
+    for (i, texture) in [mean, variance, aov].into_iter().enumerate() {
+        let dl = downloaded.clone();
+        download_texture(device, &mut encoder, texture, move |data| {
+            dl.lock().unwrap()[i] = data;
+        });
+    }
+    queue.submit([encoder.finish()]);
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    let [mean, s, aov] = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+    let hit = |i: usize| aov[i].y.to_bits() != u32::MAX;
+
+    // the environment as seen directly, which is the brightness every pixel should have at albedo 1
+    let (sum, misses) = (0..mean.len())
+        .filter(|&i| !hit(i))
+        .fold((0.0, 0), |(sum, n), i| (sum + mean[i].y as f64, n + 1));
+    let environment = match misses {
+        0 => {
+            println!("Note: The environment isn't visible, assuming it has luminance 1");
+            1.0
+        }
+        _ => (sum / misses as f64) as f32,
+    };
+
+    #[derive(Default)]
+    struct Counts {
+        pixels: u32,
+        ratio: f64,
+        gain: u32,
+        loss: u32,
+    }
+    let mut types: BTreeMap<&str, Counts> = BTreeMap::new();
+    let mut image = RgbImage::new(width, height);
+    for i in (0..mean.len()).filter(|&i| hit(i)) {
+        let y = mean[i].y;
+        let n = mean[i].w;
+        let stderr = (s[i].y / (n - 1.0) / n).sqrt();
+        let margin = match stderr.is_finite() {
+            true => 3.0 * stderr,
+            false => 0.0,
+        } + TOLERANCE * environment;
+        let ratio = y / environment;
+
+        let counts = types
+            .entry(bytemuck::cast::<_, MaterialId>(aov[i].y).type_name())
+            .or_default();
+        counts.pixels += 1;
+        counts.ratio += ratio as f64;
+        let gray = (ratio.clamp(0.0, 1.0) * 255.0) as u8;
+        let color = if y > environment + margin {
+            counts.gain += 1;
+            Rgb([255, 0, 0])
+        } else if y < albedo * environment - margin {
+            // light bouncing between surfaces is taken by the albedo each time, so below an
+            // albedo of 1 concave geometry can look like a loss too
+            counts.loss += 1;
+            Rgb([0, 0, 255])
+        } else {
+            Rgb([gray; 3])
+        };
+        image.put_pixel(i as u32 % width, i as u32 / width, color);
+    }
+
+    println!("White furnace at albedo {albedo}, environment luminance {environment:.4}");
+    println!(
+        "{:<20} {:>8} {:>10} {:>8} {:>8}",
+        "material", "pixels", "mean ratio", "gain", "loss"
+    );
+    for (name, counts) in &types {
+        println!(
+            "{:<20} {:>8} {:>10.4} {:>8} {:>8}",
+            name,
+            counts.pixels,
+            counts.ratio / counts.pixels as f64,
+            counts.gain,
+            counts.loss
+        );
+    }
+    let gain: u32 = types.values().map(|c| c.gain).sum();
+    if gain > 0 {
+        println!("Warning: {gain} pixels gained energy");
+    }
+    if albedo < 1.0 {
+        println!("Note: Below albedo 1, losses can come from light bouncing between surfaces");
+    }
+
+    heatmap::save(&image, &suffixed_path(output, "furnace"))
+}
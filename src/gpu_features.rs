@@ -0,0 +1,205 @@
+use anyhow::bail;
+
+use crate::atomics;
+use crate::options::FloatAtomics;
+use crate::scene::{Scene, human_size};
+
+// the most the renderer asks for, just under the 2 GiB that bindings can address
+const MAX_BUFFER: u32 = (2 << 30) - 4;
+const MAX_TEXTURES: u32 = 4096;
+
+// The optional parts of rendering the adapter has or lacks and the fallbacks picked where it lacks
+// them, decided once at startup and reported so that performance differences between machines can
+// be explained
+pub struct GpuFeatures {
+    pub float_atomics: FloatAtomics,
+    atomics_reason: &'static str,
+    pub compress_textures: bool,
+    compression_reason: &'static str,
+    // whether the scene's triangle vertices are uploaded packed, set once the scene is loaded
+    pub packed_vertices: bool,
+    // whether the megakernel counts its work for --render-stats
+    pub render_stats: bool,
+    // the smaller of what the adapter allows and what the renderer asks for
+    pub max_buffer: u32,
+    pub max_textures: u32,
+}
+
+impl GpuFeatures {
+    pub fn negotiate(
+        adapter: &wgpu::Adapter,
+        float_atomics: FloatAtomics,
+        compress_textures: bool,
+    ) -> Self {
+        let features = adapter.features();
+        let limits = adapter.limits();
+
+        let has_atomics = features.contains(wgpu::Features::SHADER_FLOAT32_ATOMIC);
+        let (float_atomics, atomics_reason) = match float_atomics {
+            FloatAtomics::Auto if !has_atomics => (FloatAtomics::Cas, "the adapter has none"),
+            FloatAtomics::Auto => (FloatAtomics::Auto, ""),
+            picked => (picked, "picked with --float-atomics"),
+        };
+
+        let has_bc = features.contains(wgpu::Features::TEXTURE_COMPRESSION_BC);
+        let compression_reason = match (compress_textures, has_bc) {
+            (false, _) => "not asked for with --compress-textures",
+            (true, false) => "the adapter has no BC formats",
+            (true, true) => "",
+        };
+
+        GpuFeatures {
+            float_atomics,
+            atomics_reason,
+            compress_textures: compress_textures && has_bc,
+            compression_reason,
+            packed_vertices: false,
+            render_stats: false,
+            max_buffer: limits.max_storage_buffer_binding_size.min(MAX_BUFFER),
+            max_textures: limits
+                .max_binding_array_elements_per_shader_stage
+                .min(MAX_TEXTURES),
+        }
+    }
+
+    // records whether compressing the scene's textures left anything compressed
+    pub fn textures_compressed(&mut self, compressed: bool) {
+        if self.compress_textures && !compressed {
+            self.compression_reason = "no texture could be compressed";
+        }
+        self.compress_textures = compressed;
+    }
+
+    // Creating the device fails with the missing ones listed if the adapter lacks any of these
+    pub fn required_features(&self) -> wgpu::Features {
+        // the shaders have no fallback for these
+        let mut features = wgpu::Features::SHADER_INT64
+            | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
+            | wgpu::Features::TEXTURE_BINDING_ARRAY
+            | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
+            | wgpu::Features::FLOAT32_FILTERABLE
+            | wgpu::Features::CLEAR_TEXTURE
+            | wgpu::Features::IMMEDIATES;
+        if self.float_atomics != FloatAtomics::Cas {
+            features |= wgpu::Features::SHADER_FLOAT32_ATOMIC;
+        }
+        if self.compress_textures {
+            features |= wgpu::Features::TEXTURE_COMPRESSION_BC;
+        }
+        features
+    }
+
+    pub fn required_limits(&self, adapter: &wgpu::Adapter) -> wgpu::Limits {
+        wgpu::Limits {
+            max_immediate_size: 64,
+            max_storage_buffer_binding_size: self.max_buffer,
+            max_buffer_size: self.max_buffer as u64,
+            max_storage_buffers_per_shader_stage: 128,
+            // the film textures, plus the ones of the preview and debug passes
+            max_storage_textures_per_shader_stage: 8,
+            max_binding_array_elements_per_shader_stage: self.max_textures,
+            ..wgpu::Limits::default().using_resolution(adapter.limits())
+        }
+    }
+
+    // Times the two kinds of float additions if that was left to the benchmark. Only the guided
+    // integrator adds floats atomically, so there's nothing to time for the others.
+    pub fn pick_float_atomics(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        integrator: &str,
+    ) -> anyhow::Result<()> {
+        if self.float_atomics != FloatAtomics::Auto {
+            return Ok(());
+        }
+        (self.float_atomics, self.atomics_reason) = match integrator {
+            "guided" => (
+                atomics::benchmark(device, queue)?,
+                "faster in a benchmark at startup",
+            ),
+            _ => (FloatAtomics::Native, "not benchmarked for this integrator"),
+        };
+        Ok(())
+    }
+
+    // Fails if the scene needs more than the adapter allows, which would otherwise only show up as
+    // a validation error while uploading it
+    pub fn check_scene(&self, scene: &Scene) -> anyhow::Result<()> {
+        let (name, size) = scene.largest_buffer();
+        if size > self.max_buffer as usize {
+            bail!(
+                "the scene's {name} take {}, but the adapter's storage buffers are limited to {}",
+                human_size(size).trim(),
+                human_size(self.max_buffer as usize).trim()
+            );
+        }
+        if scene.images.len() > self.max_textures as usize {
+            bail!(
+                "the scene has {} textures, but the adapter can bind only {}",
+                scene.images.len(),
+                self.max_textures
+            );
+        }
+        Ok(())
+    }
+
+    pub fn report(&self, adapter: &wgpu::Adapter, scene: &Scene) {
+        let info = adapter.get_info();
+        let with_reason = |what: &str, reason: &str| match reason {
+            "" => what.to_owned(),
+            _ => format!("{what}, {reason}"),
+        };
+        let (name, size) = scene.largest_buffer();
+
+        println!("GPU features of {} ({:?}):", info.name, info.backend);
+        println!(
+            "  binding arrays       up to {} textures, {} used",
+            self.max_textures,
+            scene.images.len()
+        );
+        let atomics = match self.float_atomics {
+            FloatAtomics::Cas => "compare-exchange loops",
+            _ => "native",
+        };
+        println!(
+            "  float atomics        {}",
+            with_reason(atomics, self.atomics_reason)
+        );
+        let compression = match self.compress_textures {
+            true => "BC6H and BC7",
+            false => "off",
+        };
+        println!(
+            "  texture compression  {}",
+            with_reason(compression, self.compression_reason)
+        );
+        let vertices = match self.packed_vertices {
+            true => "packed in 16 bytes",
+            false => "32 bytes, unpacked",
+        };
+        println!("  triangle vertices    {vertices}");
+        println!(
+            "  storage buffers      up to {}, largest {} of {name}",
+            human_size(self.max_buffer as usize).trim(),
+            human_size(size).trim()
+        );
+    }
+
+    // the decisions as shader flags, for `#importif`
+    pub fn shader_flags(&self) -> [(String, String); 3] {
+        let geometry = match self.packed_vertices {
+            true => "packed",
+            false => "full",
+        };
+        let stats = match self.render_stats {
+            true => "on",
+            false => "off",
+        };
+        [
+            ("atomics".to_owned(), self.float_atomics.flag().to_owned()),
+            ("geometry".to_owned(), geometry.to_owned()),
+            ("stats".to_owned(), stats.to_owned()),
+        ]
+    }
+}
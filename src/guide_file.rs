@@ -0,0 +1,146 @@
+use std::io::Read;
+use std::path::Path;
+
+use anyhow::{Context, bail};
+use bytemuck::{AnyBitPattern, NoUninit};
+use glam::Vec3;
+
+use crate::{BspNode, DirTreeNode, write_atomic};
+
+const MAGIC: &[u8; 8] = b"PBRGUIDE";
+const VERSION: u32 = 4;
+
+// Trained spatial and directional trees of the guided integrator, saved so later renders of the
+// same scene can skip training. Stored little-endian as the magic, version, iteration count, scene
+// bounds, then each array as a length and its elements.
+pub struct GuideData {
+    pub iter: u32,
+    pub bounds: [Vec3; 2],
+    pub bsp: Vec<BspNode>,
+    // quadtrees guiding samples, indexed by the `left` of bsp leaves
+    pub guide: Vec<[DirTreeNode; 4]>,
+    // quadtrees being trained, indexed by the `right` of bsp leaves
+    pub train: Vec<[DirTreeNode; 4]>,
+}
+
+impl GuideData {
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut data = MAGIC.to_vec();
+        data.extend(VERSION.to_le_bytes());
+        data.extend(self.iter.to_le_bytes());
+        for v in self.bounds {
+            data.extend(v.to_array().map(f32::to_le_bytes).concat());
+        }
+        write_array(&mut data, &self.bsp);
+        write_array(&mut data, &self.guide);
+        write_array(&mut data, &self.train);
+
+        write_atomic(path, |tmp| Ok(std::fs::write(tmp, &data)?))
+            .with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::read(&mut std::io::BufReader::new(std::fs::File::open(path)?))
+            .with_context(|| format!("failed to load guiding structures from {}", path.display()))
+    }
+
+    fn read(r: &mut impl Read) -> anyhow::Result<Self> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("not a guide file");
+        }
+        let version = read_u32(r)?;
+        if version != VERSION {
+            bail!("unsupported version {version}, expected {VERSION}");
+        }
+        let iter = read_u32(r)?;
+        let mut bounds = [Vec3::ZERO; 2];
+        for v in &mut bounds {
+            for i in 0..3 {
+                v[i] = f32::from_bits(read_u32(r)?);
+            }
+        }
+        let bsp = read_array(r)?;
+        let guide = read_array(r)?;
+        let train = read_array(r)?;
+
+        let data = GuideData {
+            iter,
+            bounds,
+            bsp,
+            guide,
+            train,
+        };
+        data.validate()?;
+        Ok(data)
+    }
+
+    // the shader trusts the indices, so check them before they get near the GPU. Children always
+    // come after their parent in the spatial tree and before it in the directional trees, which
+    // also rules out cycles that would never finish descending.
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.bsp.is_empty() || self.guide.is_empty() || self.train.is_empty() {
+            bail!("empty tree");
+        }
+        let in_range = |i: u32, len: usize| i == !0 || (i as usize) < len;
+        for (index, node) in self.bsp.iter().enumerate() {
+            let after = |i: u32| i as usize > index && (i as usize) < self.bsp.len();
+            let ok = match node.is_leaf != 0 {
+                true => {
+                    in_range(node.left, self.guide.len())
+                        && in_range(node.right, self.train.len())
+                        && (0.0..=1.0).contains(&node.bsdf_fraction)
+                }
+                false => after(node.left) && after(node.right) && node.axis < 3,
+            };
+            if !ok {
+                bail!("spatial tree index out of range or out of order");
+            }
+        }
+        for (nodes, name) in [(&self.guide, "guiding"), (&self.train, "training")] {
+            let before = |i: u32, index: usize| i == !0 || (i as usize) < index;
+            if nodes
+                .iter()
+                .enumerate()
+                .any(|(index, node)| node.iter().any(|n| !before(n.child, index)))
+            {
+                bail!("{name} directional tree index out of range or out of order");
+            }
+        }
+        Ok(())
+    }
+}
+
+// elements are plain u32 and f32, so converting each 4 byte word covers endianness
+fn write_array<T: NoUninit>(data: &mut Vec<u8>, values: &[T]) {
+    data.extend((values.len() as u64).to_le_bytes());
+    let words: &[u32] = bytemuck::cast_slice(values);
+    data.extend(words.iter().flat_map(|w| w.to_le_bytes()));
+}
+
+fn read_array<T: NoUninit + AnyBitPattern>(r: &mut impl Read) -> anyhow::Result<Vec<T>> {
+    let mut len = [0; 8];
+    r.read_exact(&mut len)?;
+    let len = u64::from_le_bytes(len) as usize;
+    let bytes = len
+        .checked_mul(size_of::<T>())
+        .context("array length out of range")?;
+
+    let mut data = vec![];
+    r.take(bytes as u64).read_to_end(&mut data)?;
+    if data.len() != bytes {
+        bail!("file is truncated");
+    }
+    let words: Vec<u32> = data
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    Ok(bytemuck::pod_collect_to_vec(&words))
+}
+
+fn read_u32(r: &mut impl Read) -> anyhow::Result<u32> {
+    let mut bytes = [0; 4];
+    r.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
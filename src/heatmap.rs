@@ -0,0 +1,123 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use glam::{Vec3, Vec4Swizzles};
+use image::{Rgb, RgbImage, Rgba32FImage};
+
+use crate::{download_texture, suffixed_path, write_atomic};
+
+// Writes the sample count of each pixel to `<output>-samples.png` and its relative variance to
+// `<output>-variance.png` in false color, to show where samples went and where more are needed
+pub fn save_debug_images(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mean: &wgpu::Texture,
+    variance: &wgpu::Texture,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let (width, height) = (mean.width(), mean.height());
+    let downloaded = Arc::new(Mutex::new([vec![], vec![]]));
+    let mut encoder = device.create_command_encoder(&Default::default());
+    for (i, texture) in [mean, variance].into_iter().enumerate() {
+        let dl = downloaded.clone();
+        download_texture(device, &mut encoder, texture, move |data| {
+            dl.lock().unwrap()[i] = data;
+        });
+    }
+    queue.submit([encoder.finish()]);
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    let [mean, s] = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+    let counts: Vec<f32> = mean.iter().map(|m| m.w).collect();
+    // averaged over the channels the same way as the render statistics
+    let rel_variance: Vec<f32> = mean
+        .iter()
+        .zip(&s)
+        .map(|(m, s)| {
+            let rel_var = s.xyz() / (m.w - 1.0) / m.xyz();
+            let rel_var = Vec3::select(rel_var.is_finite_mask(), rel_var, Vec3::ZERO);
+            rel_var.element_sum() / 3.0
+        })
+        .collect();
+
+    let max_count = counts.iter().copied().fold(0.0, f32::max);
+    let min_count = counts.iter().copied().fold(max_count, f32::min);
+    println!("Samples per pixel: {min_count} to {max_count}");
+    let image = heatmap(width, height, &counts, |c| Some(c / max_count.max(1.0)));
+    save(&image, &suffixed_path(output, "samples"))?;
+
+    // on a log scale between the 1st and 99th percentiles, leaving pixels without light black
+    let mut logs: Vec<f32> = rel_variance
+        .iter()
+        .filter(|&&v| v > 0.0)
+        .map(|v| v.log10())
+        .collect();
+    logs.sort_unstable_by(f32::total_cmp);
+    let (lo, hi) = match logs.is_empty() {
+        true => (0.0, 1.0),
+        false => (
+            logs[logs.len() / 100],
+            logs[logs.len() - 1 - logs.len() / 100],
+        ),
+    };
+    println!("Relative variance: 1e{lo:.2} to 1e{hi:.2}");
+    let image = heatmap(width, height, &rel_variance, |v| {
+        (v > 0.0).then(|| (v.log10() - lo) / (hi - lo).max(1e-3))
+    });
+    save(&image, &suffixed_path(output, "variance"))
+}
+
+// Writes the BVH nodes visited by each pixel's camera rays, which the debug-bvh integrator renders
+// as one nit of gray per node, to `<output>-bvh.png` in false color, up to the most visited pixel
+pub fn save_bvh_heatmap(xyz: &Rgba32FImage, output: &Path) -> anyhow::Result<()> {
+    let visits: Vec<f32> = xyz.pixels().map(|p| p[1]).collect();
+    let max = visits.iter().copied().fold(0.0, f32::max);
+    let mean = visits.iter().sum::<f32>() / visits.len().max(1) as f32;
+    println!("BVH nodes visited: {mean:.1} on average, {max:.0} at most");
+    let image = heatmap(xyz.width(), xyz.height(), &visits, |v| {
+        Some(v / max.max(1.0))
+    });
+    save(&image, &suffixed_path(output, "bvh"))
+}
+
+// `scale` maps values to 0..1, or to none for black
+pub fn heatmap(
+    width: u32,
+    height: u32,
+    values: &[f32],
+    scale: impl Fn(f32) -> Option<f32>,
+) -> RgbImage {
+    RgbImage::from_fn(width, height, |x, y| {
+        match scale(values[(y * width + x) as usize]) {
+            Some(t) => viridis(t),
+            None => Rgb([0; 3]),
+        }
+    })
+}
+
+// polynomial fit of the viridis color map, which reads the same in grayscale
+fn viridis(t: f32) -> Rgb<u8> {
+    const C: [Vec3; 7] = [
+        Vec3::new(0.277_727_33, 0.005_407_344_5, 0.334_099_8),
+        Vec3::new(0.105_093_04, 1.404_613_5, 1.384_590_1),
+        Vec3::new(-0.330_861_83, 0.214_847_56, 0.095_095_165),
+        Vec3::new(-4.634_230_6, -5.799_101, -19.332_441),
+        Vec3::new(6.228_27, 14.179_933, 56.690_55),
+        Vec3::new(4.776_385, -13.745_146, -65.353_035),
+        Vec3::new(-5.435_456, 4.645_852_6, 26.312_435),
+    ];
+    let t = t.clamp(0.0, 1.0);
+    let rgb = C.iter().rev().fold(Vec3::ZERO, |acc, &c| acc * t + c);
+    Rgb((rgb.clamp(Vec3::ZERO, Vec3::ONE) * 255.0)
+        .round()
+        .as_u8vec3()
+        .to_array())
+}
+
+// always a png, whatever the format of the output
+pub fn save(image: &RgbImage, path: &Path) -> anyhow::Result<()> {
+    let path = path.with_extension("png");
+    write_atomic(&path, |tmp| Ok(image.save(tmp)?))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
@@ -0,0 +1,55 @@
+use glam::{Vec2, Vec4};
+use image::Rgba32FImage;
+
+// Lens distortion applied to the finished image, matching camera_lens_distort in
+// shaders/camera/projective.wgsl
+
+#[derive(Copy, Clone)]
+pub struct Lens {
+    // positive for barrel and negative for pincushion distortion
+    pub distortion: f32,
+    // difference in magnification between 650nm and 450nm
+    pub chromatic_aberration: f32,
+    // pixels from corner to corner of the image without its overscan border, so that the frame's
+    // corners are at radius 1
+    pub frame_diagonal: f32,
+}
+
+impl Lens {
+    // approximate peak wavelengths of the X, Y and Z matching functions
+    const CHANNEL_WAVELENGTHS: [f32; 3] = [600.0, 555.0, 450.0];
+
+    pub fn apply(&self, xyz: &Rgba32FImage) -> Rgba32FImage {
+        if xyz.width() < 2 || xyz.height() < 2 {
+            return xyz.clone();
+        }
+        let size = Vec2::new(xyz.width() as f32, xyz.height() as f32);
+        let sample = |p: Vec2, c: usize| {
+            let p = p - 0.5;
+            if p.cmplt(Vec2::ZERO).any() || p.cmpgt(size - 1.0).any() {
+                return 0.0;
+            }
+            let i = p.as_uvec2().min(size.as_uvec2() - 2);
+            let t = p - i.as_vec2();
+            let texel = |dx, dy| xyz.get_pixel(i.x + dx, i.y + dy)[c];
+            let top = texel(0, 0) + (texel(1, 0) - texel(0, 0)) * t.x;
+            let bottom = texel(0, 1) + (texel(1, 1) - texel(0, 1)) * t.x;
+            top + (bottom - top) * t.y
+        };
+
+        Rgba32FImage::from_fn(xyz.width(), xyz.height(), |x, y| {
+            let ndc = (Vec2::new(x as f32, y as f32) + 0.5) / size * 2.0 - 1.0;
+            let n = ndc * size / self.frame_diagonal;
+            let distortion = 1.0 + self.distortion * n.length_squared();
+
+            let mut result = Vec4::ZERO;
+            for (c, lambda) in Self::CHANNEL_WAVELENGTHS.into_iter().enumerate() {
+                let ca = 1.0 + self.chromatic_aberration * (lambda - 550.0) / 200.0;
+                let source = (ndc * distortion * ca + 1.0) / 2.0 * size;
+                result[c] = sample(source, c);
+            }
+            result.w = xyz.get_pixel(x, y)[3];
+            image::Rgba(result.to_array())
+        })
+    }
+}
@@ -1,2 +1,7 @@
+mod cleanup;
+pub mod diagnostics;
+mod orient;
 pub mod pbrt;
 mod ply;
+mod scatter;
+mod tensor;
@@ -0,0 +1,95 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::Vec3;
+
+use crate::scene::TriVertex;
+
+// Triangles dropped by `clean_triangles`
+#[derive(Copy, Clone, Default)]
+pub struct Cleanup {
+    pub degenerate: usize,
+    // with an index out of range or a position that isn't finite
+    pub invalid: usize,
+    pub duplicate: usize,
+}
+
+impl Cleanup {
+    pub fn add(&mut self, other: Cleanup) {
+        self.degenerate += other.degenerate;
+        self.invalid += other.invalid;
+        self.duplicate += other.duplicate;
+    }
+
+    pub fn total(&self) -> usize {
+        self.degenerate + self.invalid + self.duplicate
+    }
+}
+
+// Replaces normals that aren't finite by none, and texture coordinates that aren't finite by zero
+pub fn sanitize_vertices(verts: &mut [TriVertex]) {
+    for v in verts.iter_mut() {
+        if !v.n.is_finite() {
+            v.n = Vec3::ZERO;
+        }
+        if !v.u.is_finite() || !v.v.is_finite() {
+            (v.u, v.v) = (0.0, 0.0);
+        }
+    }
+}
+
+// Drops triangles with no area, invalid vertices or the same corners as an earlier triangle, which
+// would otherwise give NaN sampling pdfs. Runs after welding, so that it sees the final indices.
+pub fn clean_triangles(verts: &[TriVertex], tris: &mut Vec<[u32; 3]>) -> Cleanup {
+    let mut cleanup = Cleanup::default();
+    let mut seen = HashSet::new();
+    tris.retain(|tri| {
+        if tri.iter().any(|&i| i as usize >= verts.len()) {
+            cleanup.invalid += 1;
+            return false;
+        }
+        let [p0, p1, p2] = tri.map(|i| verts[i as usize].p);
+        if !(p0.is_finite() && p1.is_finite() && p2.is_finite()) {
+            cleanup.invalid += 1;
+            return false;
+        }
+        let area = (p1 - p0).cross(p2 - p0).length();
+        if area.is_nan() || area <= 0.0 {
+            cleanup.degenerate += 1;
+            return false;
+        }
+        // either winding of the same corners
+        let mut corners = [p0, p1, p2].map(|p| p.to_array().map(f32::to_bits));
+        corners.sort_unstable();
+        if !seen.insert(corners) {
+            cleanup.duplicate += 1;
+            return false;
+        }
+        true
+    });
+    cleanup
+}
+
+// Merges vertices with the same position, normal and texture coordinates, keeping the first of
+// each, and returns how many were removed. Expects the vertices to be finite, as
+// `sanitize_vertices` leaves them. Indices out of range stay that way for `clean_triangles`.
+pub fn weld_vertices(verts: &mut Vec<TriVertex>, tris: &mut [[u32; 3]]) -> usize {
+    let mut first = HashMap::with_capacity(verts.len());
+    let mut remap = Vec::with_capacity(verts.len());
+    let mut welded = Vec::with_capacity(verts.len());
+    for v in verts.iter() {
+        // + 0.0 makes -0.0 the same as 0.0
+        let key = [v.p.x, v.p.y, v.p.z, v.n.x, v.n.y, v.n.z, v.u, v.v].map(|x| (x + 0.0).to_bits());
+        let idx = *first.entry(key).or_insert_with(|| {
+            welded.push(*v);
+            welded.len() as u32 - 1
+        });
+        remap.push(idx);
+    }
+
+    for tri in tris {
+        *tri = tri.map(|i| remap.get(i as usize).copied().unwrap_or(u32::MAX));
+    }
+    let removed = verts.len() - welded.len();
+    *verts = welded;
+    removed
+}
@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Severity {
+    // a file that couldn't be loaded; fails the load with --strict
+    Error,
+    Warning,
+    Note,
+}
+
+// The directive that was being loaded when something was reported
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Location {
+    pub file: PathBuf,
+    pub line: usize,
+    pub directive: String,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    // none for things found after all the files were parsed
+    pub location: Option<Location>,
+    pub message: String,
+}
+
+// Everything the loader had to say about a scene, kept until the end so that it can be summarized
+// in one place instead of interleaved with progress output
+#[derive(Default, Serialize, Deserialize)]
+pub struct Diagnostics {
+    list: Vec<Diagnostic>,
+}
+
+impl Diagnostics {
+    pub fn push(&mut self, severity: Severity, location: Option<Location>, message: String) {
+        self.list.push(Diagnostic {
+            severity,
+            location,
+            message,
+        });
+    }
+
+    pub fn count(&self, severity: Severity) -> usize {
+        self.list.iter().filter(|d| d.severity == severity).count()
+    }
+
+    // Repeats of the same message are printed once with the first place they came from, since big
+    // scenes tend to repeat the same few problems thousands of times
+    pub fn print_summary(&self) {
+        if self.list.is_empty() {
+            return;
+        }
+        println!(
+            "Loader report: {} errors, {} warnings, {} notes",
+            self.count(Severity::Error),
+            self.count(Severity::Warning),
+            self.count(Severity::Note),
+        );
+
+        let mut groups: Vec<(&Diagnostic, usize)> = vec![];
+        let mut index: HashMap<_, usize> = HashMap::new();
+        for d in &self.list {
+            match index.get(&(d.severity, &d.message)) {
+                Some(&i) => groups[i].1 += 1,
+                None => {
+                    index.insert((d.severity, &d.message), groups.len());
+                    groups.push((d, 1));
+                }
+            }
+        }
+        // stable, so each severity stays in the order things were found
+        groups.sort_by_key(|(d, _)| d.severity);
+
+        for (d, count) in groups {
+            print!("  {}: {}", d.severity, d.message);
+            if let Some(location) = &d.location {
+                print!(" ({location})");
+            }
+            match count {
+                1 => println!(),
+                _ => println!(" and {} more", count - 1),
+            }
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+            Severity::Note => "Note",
+        })
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{} {}",
+            self.file.display(),
+            self.line,
+            self.directive
+        )
+    }
+}
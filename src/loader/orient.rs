@@ -0,0 +1,141 @@
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use glam::Vec3;
+
+use crate::scene::TriVertex;
+
+// Fixes made to a mesh by `repair_orientation`
+#[derive(Copy, Clone, Default)]
+pub struct Repairs {
+    pub triangles: usize,
+    pub normals: usize,
+}
+
+// Makes the winding of the triangles of a mesh agree across shared edges, then turns each piece of
+// the mesh the way most of it faces, and flips vertex normals pointing away from their faces. Pieces
+// face the way of their vertex normals if there are any, outward if they are closed, and otherwise
+// the way of most of their area. Vertices are matched by position, so seams in the texture
+// coordinates or normals still connect.
+pub fn repair_orientation(verts: &mut [TriVertex], tris: &mut [[u32; 3]]) -> Repairs {
+    let mut welded = HashMap::new();
+    let ids: Vec<u32> = verts
+        .iter()
+        .map(|v| {
+            let next = welded.len() as u32;
+            *welded
+                .entry(v.p.to_array().map(f32::to_bits))
+                .or_insert(next)
+        })
+        .collect();
+
+    // each edge once per triangle, with whether it runs from the lower to the higher id
+    let mut edges = Vec::with_capacity(tris.len() * 3);
+    for (t, tri) in tris.iter().enumerate() {
+        for k in 0..3 {
+            let a = ids[tri[k] as usize];
+            let b = ids[tri[(k + 1) % 3] as usize];
+            if a != b {
+                edges.push((a.min(b), a.max(b), t as u32, a < b));
+            }
+        }
+    }
+    edges.sort_unstable();
+
+    // neighbors across manifold edges, and whether they wind the other way than the triangle
+    let mut neighbors = vec![vec![]; tris.len()];
+    let mut open = vec![false; tris.len()];
+    for group in edges.chunk_by(|a, b| (a.0, a.1) == (b.0, b.1)) {
+        match *group {
+            [(_, _, t0, d0), (_, _, t1, d1)] => {
+                neighbors[t0 as usize].push((t1, d0 == d1));
+                neighbors[t1 as usize].push((t0, d0 == d1));
+            }
+            [(_, _, t, _)] => open[t as usize] = true,
+            // non-manifold edges don't say which way the triangles should go
+            _ => {}
+        }
+    }
+
+    let face_normal = |tri: &[u32; 3]| {
+        let [p0, p1, p2] = tri.map(|i| verts[i as usize].p);
+        (p1 - p0).cross(p2 - p0)
+    };
+
+    let mut flip = vec![None; tris.len()];
+    let mut component = vec![];
+    let mut queue = VecDeque::new();
+    let mut repairs = Repairs::default();
+    for seed in 0..tris.len() {
+        if flip[seed].is_some() {
+            continue;
+        }
+        flip[seed] = Some(false);
+        queue.push_back(seed);
+        component.clear();
+        while let Some(t) = queue.pop_front() {
+            component.push(t);
+            let flipped = flip[t].unwrap();
+            for &(n, inconsistent) in &neighbors[t] {
+                // the first choice wins where a non-orientable surface disagrees with itself
+                if flip[n as usize].is_none() {
+                    flip[n as usize] = Some(flipped ^ inconsistent);
+                    queue.push_back(n as usize);
+                }
+            }
+        }
+
+        let mut normal_vote = 0.0;
+        let mut has_normals = false;
+        let mut volume = 0.0;
+        let mut flipped_area = 0.0;
+        let mut area = 0.0;
+        for &t in &component {
+            let sign = match flip[t].unwrap() {
+                true => -1.0,
+                false => 1.0,
+            };
+            let n = face_normal(&tris[t]);
+            let shading: Vec3 = tris[t].iter().map(|&i| verts[i as usize].n).sum();
+            has_normals |= shading != Vec3::ZERO;
+            normal_vote += sign * n.dot(shading) as f64;
+            let [p0, p1, p2] = tris[t].map(|i| verts[i as usize].p.as_dvec3());
+            volume += sign * p0.dot(p1.cross(p2));
+            area += n.length() as f64;
+            if flip[t].unwrap() {
+                flipped_area += n.length() as f64;
+            }
+        }
+        let closed = component.iter().all(|&t| !open[t]);
+        let invert = if has_normals {
+            normal_vote < 0.0
+        } else if closed {
+            volume < 0.0
+        } else {
+            flipped_area > area / 2.0
+        };
+
+        for &t in &component {
+            if flip[t].unwrap() != invert {
+                tris[t].swap(1, 2);
+                repairs.triangles += 1;
+            }
+        }
+    }
+
+    let mut vertex_faces = vec![Vec3::ZERO; verts.len()];
+    for tri in tris.iter() {
+        let n = face_normal(tri);
+        for &i in tri {
+            vertex_faces[i as usize] += n;
+        }
+    }
+    for (v, faces) in verts.iter_mut().zip(vertex_faces) {
+        if v.n.dot(faces) < 0.0 {
+            v.n = -v.n;
+            repairs.normals += 1;
+        }
+    }
+
+    repairs
+}
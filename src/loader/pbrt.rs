@@ -4,77 +4,314 @@ use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::time::Instant;
 
+use anyhow::{Context, bail, ensure};
 use flate2::read::GzDecoder;
-use glam::{DMat3, DMat4, DVec2, DVec3, Vec2, Vec3};
-use lalrpop_util::{ErrorRecovery, lalrpop_mod, lexer::Token};
-
-use crate::options::RenderOptions;
+use glam::{DMat3, DMat4, DQuat, DVec2, DVec3, Mat4, Vec2, Vec3};
+use lalrpop_util::{ErrorRecovery, ParseError, lalrpop_mod, lexer::Token};
+use ordered_float::OrderedFloat;
+use rayon::prelude::*;
+
+use crate::filter::{Filter, FilterType};
+use crate::loader::cleanup::{Cleanup, clean_triangles, sanitize_vertices, weld_vertices};
+use crate::loader::diagnostics::{Diagnostics, Location, Severity};
+use crate::loader::orient::{Repairs, repair_orientation};
+use crate::loader::ply::PlyMesh;
+use crate::loader::scatter::Scatter;
+use crate::loader::tensor::load_tensor_file;
+use crate::options::{
+    BvhQuality, EnvironmentOverride, MaterialOverride, RenderOptions, SamplerType, SceneConvention,
+};
 use crate::scene::{
-    LightId, MaterialId, NodeId, PrimitiveNode, Scene, ShapeId, SpectrumId, Sphere, TextureId,
-    TriVertex, UvMappingParams,
+    CsgOp, LightId, MappingType, MaterialId, MeasuredMaterial, NodeId, PrimitiveNode,
+    PrincipledMaterial, Scene, SdfOp, ShapeId, SpectrumId, Sphere, TextureId, TextureMapping,
+    TriVertex, WrapMode,
 };
+use crate::scene_cache::BvhCache;
 use crate::spectrum::SpectrumData;
-use crate::{ProjectiveCamera, Transform};
+use crate::{AnimatedTransform, ProjectiveCamera, Transform};
 
 lalrpop_mod!(grammar, "/loader/pbrt.rs");
 
-pub fn load_pbrt_scene(spectrum_data: &SpectrumData, path: &Path) -> (RenderOptions, Scene) {
+// Smaller meshes are quick enough to build into the BVH they're in that caching them isn't worth
+// reading their file again to hash it
+const BVH_CACHE_MIN_SHAPES: usize = 65536;
+
+pub struct LoadedScene {
+    pub render_options: RenderOptions,
+    pub scene: Scene,
+    pub diagnostics: Diagnostics,
+    // every file the scene refers to, including those which couldn't be read
+    pub inputs: Vec<PathBuf>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn load_pbrt_scene(
+    spectrum_data: &SpectrumData,
+    path: &Path,
+    environment: EnvironmentOverride,
+    material_override: Option<MaterialOverride>,
+    material_replacements: toml::Table,
+    light_overrides: toml::Table,
+    convention: SceneConvention,
+    repair_orientation: bool,
+    weld: bool,
+    compress_geometry: bool,
+    low_memory: bool,
+    furnace: Option<f32>,
+    bvh_quality: BvhQuality,
+    bvh_cache: Option<BvhCache>,
+    strict: bool,
+) -> anyhow::Result<LoadedScene> {
+    // the scene file itself has to be there, unlike the files it refers to
+    std::fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+
     let mut scene = Scene::new(spectrum_data);
+    scene.bvh_quality = bvh_quality;
+    scene.compress_geometry = compress_geometry;
+    if low_memory {
+        scene
+            .spill_geometry()
+            .context("failed to create temporary files for geometry")?;
+    }
     let spectrum = scene.add_rgb_albedo_spectrum(Vec3::new(1.0, 0.0, 1.0));
     let error_texture = scene.add_constant_texture(spectrum);
     let error_material = scene.add_diffuse_material(error_texture, None);
 
+    let material_override = material_override.map(|o| match o {
+        MaterialOverride::Diffuse(albedo) => {
+            let spectrum = scene.add_constant_spectrum(albedo);
+            let texture = scene.add_constant_texture(spectrum);
+            scene.add_diffuse_material(texture, None)
+        }
+        MaterialOverride::UvChecker => {
+            let mapping = TextureMapping {
+                world_to_texture: Mat4::IDENTITY,
+                v1: Vec3::X,
+                ty: MappingType::Uv,
+                v2: Vec3::Y,
+                _padding: 0,
+                scale: Vec2::ONE,
+                delta: Vec2::ZERO,
+            };
+            let texture = scene.add_uv_checker_texture(8.0, mapping);
+            scene.add_diffuse_material(texture, None)
+        }
+        MaterialOverride::Wireframe => {
+            let wire = scene.add_constant_spectrum(0.02);
+            let wire = scene.add_constant_texture(wire);
+            let fill = scene.add_constant_spectrum(0.5);
+            let fill = scene.add_constant_texture(fill);
+            let texture = scene.add_wireframe_texture(wire, fill, 0.0, 1.5);
+            scene.add_diffuse_material(texture, None)
+        }
+    });
+    let furnace = furnace.map(|albedo| {
+        let spectrum = scene.add_constant_spectrum(albedo);
+        scene.add_constant_texture(spectrum)
+    });
+
     let mut builder = SceneBuilder {
         base: path.parent().unwrap().to_path_buf(),
         state: State {
             transform: DMat4::IDENTITY,
+            end_transform: DMat4::IDENTITY,
+            active_transforms: [true; 2],
             material: error_material,
             area_light: None,
         },
         stack: vec![],
+        convention,
+        root: DMat4::IDENTITY,
+        repair_orientation,
+        weld,
+        bvh_cache,
+        repairs: Repairs::default(),
+        cleanup: Cleanup::default(),
         render_options: RenderOptions::default(),
+        camera_projection: None,
+        transform_times: [0.0, 1.0],
+        shutter: [0.0, 1.0],
+        environment,
+        material_override,
+        furnace,
+        material_replacements,
+        light_overrides,
+        applied_light_overrides: HashSet::new(),
+        light_directives: 0,
         scene,
         current_prims: vec![],
+        pending_plymeshes: vec![],
         lights: vec![],
+        pending_mixes: vec![],
         objects: HashMap::new(),
         textures: HashMap::new(),
         materials: HashMap::new(),
+        material_temperatures: HashMap::new(),
+        blackbody_spectra: HashMap::new(),
         object_state: None,
         error_material,
         error_texture,
+        reporter: Rc::default(),
+        inputs: RefCell::default(),
     };
     let t = Instant::now();
     builder.include(Path::new(path.file_name().unwrap()));
+    builder.finish_camera();
+    builder.flush_plymeshes();
+    builder.resolve_mixes();
 
     let root = builder.scene.add_bvh(&builder.current_prims);
     builder.scene.root = Some(root);
+    builder.check_convention();
+    let cleanup = builder.cleanup;
+    if cleanup.total() > 0 {
+        builder.warn(format!(
+            "Removed {} zero-area, {} invalid and {} duplicate triangles",
+            cleanup.degenerate, cleanup.invalid, cleanup.duplicate
+        ));
+    }
+    if repair_orientation {
+        println!(
+            "Repaired the winding of {} triangles and flipped {} vertex normals",
+            builder.repairs.triangles, builder.repairs.normals
+        );
+    }
 
+    // a unit white environment in place of the scene's lights, which were left out
+    if builder.furnace.is_some() {
+        let white = builder
+            .scene
+            .add_rgb_illuminant_spectrum(Vec3::ONE, SpectrumId::D65);
+        let light = builder.scene.add_uniform_light(white);
+        builder.lights.push(light);
+    }
     let root_ls = builder.scene.add_power_light_sampler(&builder.lights);
     builder.scene.root_ls = Some(root_ls);
+    // textures have been decoding while the geometry was parsed and the BVH built
+    builder.scene.resolve_images();
+    for name in builder.material_replacements.keys() {
+        if !builder.materials.contains_key(name) {
+            builder.warn(format!(
+                "Material replacement {name} matches no named material"
+            ));
+        }
+    }
+    for name in builder.light_overrides.keys() {
+        if !builder.applied_light_overrides.contains(name) {
+            builder.warn(format!("Light override {name} matches no light"));
+        }
+    }
+    builder.scene.named_materials = std::mem::take(&mut builder.materials);
+    for h in builder.scene.power_light_sampler_health() {
+        if h.unsampled > 0 {
+            builder.warn(format!(
+                "{} of {} lights can never be picked by the light sampler",
+                h.unsampled, h.lights
+            ));
+        }
+        if h.max_error > 1e-3 {
+            builder.warn(format!(
+                "Light sampler alias table is off by {:.1e} of a light's probability",
+                h.max_error
+            ));
+        }
+    }
 
     eprintln!("Build scene in {:.3?}", t.elapsed());
 
-    (builder.render_options, builder.scene)
+    let diagnostics = builder.reporter.diagnostics.take();
+    diagnostics.print_summary();
+    if diagnostics.count(Severity::Error) > 0 {
+        if strict {
+            bail!("failed to load the scene");
+        }
+        println!("Rendering with what could be loaded; use --strict to fail instead");
+    }
+
+    let mut inputs = builder.inputs.take();
+    inputs.sort();
+    inputs.dedup();
+    Ok(LoadedScene {
+        render_options: builder.render_options,
+        scene: builder.scene,
+        diagnostics,
+        inputs,
+    })
+}
+
+// wavelength and value pairs, one per line
+fn read_spectrum_file(path: &Path) -> anyhow::Result<Vec<[f32; 2]>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    content
+        .lines()
+        .filter(|l| !l.contains('#'))
+        .filter_map(|l| l.split_once(char::is_whitespace))
+        .map(|(l, v)| Ok([l.trim().parse()?, v.trim().parse()?]))
+        .collect::<anyhow::Result<_>>()
+        .with_context(|| format!("bad spectrum in {}", path.display()))
 }
 
 pub struct SceneBuilder {
     base: PathBuf,
     state: State,
     stack: Vec<State>,
+    convention: SceneConvention,
+    // transform of the world block before any directives, from `convention`
+    root: DMat4,
+    // fix the orientation of triangle meshes as they are loaded
+    repair_orientation: bool,
+    // merge the identical vertices of triangle meshes as they are loaded
+    weld: bool,
+    // for the BVHs of meshes with at least `BVH_CACHE_MIN_SHAPES` shapes
+    bvh_cache: Option<BvhCache>,
+    repairs: Repairs,
+    // triangles dropped from meshes as they are loaded
+    cleanup: Cleanup,
     error_material: MaterialId,
     error_texture: TextureId,
+    // shared with the properties of each directive, which report the ones nothing used
+    reporter: Rc<Reporter>,
+    // files read so far, for the scene cache to check
+    inputs: RefCell<Vec<PathBuf>>,
 
     render_options: RenderOptions,
+    // orthographic, field of view and frame aspect ratio of the camera
+    camera_projection: Option<(bool, f64, Option<f64>)>,
+    // times of the start and end transforms, and when the camera's shutter opens and closes
+    transform_times: [f64; 2],
+    shutter: [f64; 2],
+    environment: EnvironmentOverride,
+    // replaces the material of every shape
+    material_override: Option<MaterialId>,
+    // for the white furnace audit, the albedo texture used for every color parameter of materials,
+    // and the lights are all replaced by a uniform environment
+    furnace: Option<TextureId>,
+    // parameters used in place of those given for named materials
+    material_replacements: toml::Table,
+    // scales and disables lights by name or directive index
+    light_overrides: toml::Table,
+    applied_light_overrides: HashSet<String>,
+    light_directives: usize,
     scene: Scene,
 
     current_prims: Vec<NodeId>,
+    // PLY files are parsed together in parallel before the prims they belong to are needed
+    pending_plymeshes: Vec<PendingPlymesh>,
     lights: Vec<LightId>,
+    // mixes refer to materials by name, which are looked up once every material is defined
+    pending_mixes: Vec<PendingMix>,
 
     objects: HashMap<String, NodeId>,
     textures: HashMap<String, TextureId>,
     materials: HashMap<String, MaterialId>,
+    // materials which glow with blackbody emission at the given temperature in kelvin
+    material_temperatures: HashMap<MaterialId, f32>,
+    // their emission spectra, shared by every shape of the same temperature
+    blackbody_spectra: HashMap<OrderedFloat<f32>, SpectrumId>,
 
     object_state: Option<(String, Vec<NodeId>)>,
 }
@@ -82,24 +319,172 @@ pub struct SceneBuilder {
 #[derive(Clone)]
 struct State {
     transform: DMat4,
+    // the transform when the shutter closes, which is the same unless things move
+    end_transform: DMat4,
+    // which of the start and end transforms transform directives apply to
+    active_transforms: [bool; 2],
     material: MaterialId,
     area_light: Option<(SpectrumId, bool)>,
 }
 
+struct PendingMix {
+    material: MaterialId,
+    materials: [MixSlot; 2],
+    location: Option<Location>,
+}
+
+// a material of a mix, by name only if it wasn't defined yet where the mix was
+enum MixSlot {
+    Material(MaterialId),
+    Named(String),
+}
+
+struct PendingPlymesh {
+    path: PathBuf,
+    // of the Shape directive, since the file is only loaded later
+    location: Option<Location>,
+    // point clouds only
+    radius: Option<f32>,
+    alpha: TextureId,
+    state: State,
+}
+
+// Where the builder is in the scene files, and everything reported so far
+#[derive(Default)]
+struct Reporter {
+    // the files being parsed, innermost include last
+    sources: RefCell<Vec<Source>>,
+    diagnostics: RefCell<Diagnostics>,
+}
+
+struct Source {
+    file: PathBuf,
+    // byte offset of the start of each line
+    line_starts: Vec<usize>,
+    // the directive being loaded and the byte offset it starts at
+    directive: String,
+    offset: usize,
+}
+
+impl Reporter {
+    fn enter(&self, file: &Path, content: &str) {
+        let newlines = content.match_indices('\n').map(|(i, _)| i + 1);
+        self.sources.borrow_mut().push(Source {
+            file: file.to_path_buf(),
+            line_starts: std::iter::once(0).chain(newlines).collect(),
+            directive: String::new(),
+            offset: 0,
+        });
+    }
+
+    fn leave(&self) {
+        self.sources.borrow_mut().pop();
+    }
+
+    fn locate(&self, offset: usize, directive: &str) {
+        let mut sources = self.sources.borrow_mut();
+        let source = sources.last_mut().unwrap();
+        source.offset = offset;
+        source.directive.clear();
+        source.directive.push_str(directive);
+    }
+
+    fn seek(&self, offset: usize) {
+        self.sources.borrow_mut().last_mut().unwrap().offset = offset;
+    }
+
+    fn location(&self) -> Option<Location> {
+        let sources = self.sources.borrow();
+        let source = sources.last()?;
+        Some(Location {
+            file: source.file.clone(),
+            line: source.line_starts.partition_point(|&s| s <= source.offset),
+            directive: source.directive.clone(),
+        })
+    }
+
+    fn report(&self, severity: Severity, message: String) {
+        self.report_at(severity, self.location(), message);
+    }
+
+    fn report_at(&self, severity: Severity, location: Option<Location>, message: String) {
+        self.diagnostics
+            .borrow_mut()
+            .push(severity, location, message);
+    }
+}
+
 impl SceneBuilder {
     fn include(&mut self, path: &Path) {
-        let content = std::fs::read_to_string(self.base.join(path)).unwrap();
-        grammar::TopLevelParser::new()
-            .parse(self, &content)
-            .unwrap_or_else(|e| panic!("In file {}: {e}", path.display()));
+        let content = match std::fs::read_to_string(self.input(path)) {
+            Ok(content) => content,
+            Err(e) => return self.error(format!("Failed to read {}: {e}", path.display())),
+        };
+        self.reporter.enter(path, &content);
+        // directives before the error have already been applied
+        if let Err(e) = grammar::TopLevelParser::new().parse(self, &content) {
+            let offset = match &e {
+                &ParseError::InvalidToken { location } => location,
+                &ParseError::UnrecognizedEof { location, .. } => location,
+                ParseError::UnrecognizedToken { token, .. } => token.0,
+                ParseError::ExtraToken { token } => token.0,
+                ParseError::User { .. } => content.len(),
+            };
+            self.reporter.seek(offset);
+            self.error(e.to_string().replace('\n', "; "));
+        }
+        self.reporter.leave();
+    }
+
+    // path of a file the scene refers to, which is noted as an input of the scene
+    fn input(&self, file: impl AsRef<Path>) -> PathBuf {
+        let path = self.base.join(file);
+        self.inputs.borrow_mut().push(path.clone());
+        path
+    }
+
+    fn locate(&self, offset: usize, directive: &str) {
+        self.reporter.locate(offset, directive);
+    }
+
+    fn error(&self, message: String) {
+        self.reporter.report(Severity::Error, message);
     }
 
-    fn unrecognized(&mut self, directive: &str, _err: ErrorRecovery<usize, Token<'_>, &'_ str>) {
-        println!("Unrecognized directive {directive}");
+    fn warn(&self, message: String) {
+        self.reporter.report(Severity::Warning, message);
+    }
+
+    fn note(&self, message: String) {
+        self.reporter.report(Severity::Note, message);
+    }
+
+    fn unrecognized(
+        &mut self,
+        offset: usize,
+        directive: &str,
+        _err: ErrorRecovery<usize, Token<'_>, &'_ str>,
+    ) {
+        self.locate(offset, directive);
+        self.warn(format!("Unrecognized directive {directive}"));
     }
 
     fn world_begin(&mut self) {
-        self.state.transform = DMat4::IDENTITY;
+        let camera_to_world = self.render_options.camera.world_to_camera.m_inv;
+        let camera_up = camera_to_world.transform_vector3(Vec3::Y).as_dvec3();
+        // the world axis closest to up in the camera's view
+        let axis = camera_up.abs().max_position();
+        let mut snapped = DVec3::ZERO;
+        snapped[axis] = camera_up[axis].signum();
+
+        let rotation = match self.convention.up_axis {
+            Some(up) => DMat4::from_quat(DQuat::from_rotation_arc(up.vector(), snapped)),
+            None => DMat4::IDENTITY,
+        };
+        let scale = DMat4::from_scale(DVec3::splat(self.convention.scale.unwrap_or(1.0)));
+        self.root = scale * rotation;
+        self.state.transform = self.root;
+        self.state.end_transform = self.root;
     }
 
     fn push(&mut self) {
@@ -112,6 +497,7 @@ impl SceneBuilder {
 
     fn begin_object(&mut self, name: &str) {
         assert!(self.object_state.is_none());
+        self.flush_plymeshes();
         let name = name.to_owned();
         let old_prims = std::mem::take(&mut self.current_prims);
         self.object_state = Some((name, old_prims));
@@ -121,8 +507,9 @@ impl SceneBuilder {
         let Some((name, old_prims)) = self.object_state.take() else {
             panic!("ended object which was never started");
         };
+        self.flush_plymeshes();
         if self.current_prims.is_empty() {
-            println!("Warning: Object {name} contains no primitives");
+            self.warn(format!("Object {name} contains no primitives"));
             return;
         }
         let obj_bvh = self.scene.add_bvh(&self.current_prims);
@@ -132,107 +519,386 @@ impl SceneBuilder {
 
     fn instance_object(&mut self, name: &str) {
         let Some(&obj) = self.objects.get(name) else {
-            println!("Warning: Attempt to instance object {name} which does not exist");
+            self.warn(format!(
+                "Attempt to instance object {name} which does not exist"
+            ));
             return;
         };
+        // the object's shapes already went through the root transform when they were defined
+        let unroot = self.root.inverse();
+        let instance = match self.motion() {
+            Some(_) => {
+                let motion = self.motion_between(
+                    self.state.transform * unroot,
+                    self.state.end_transform * unroot,
+                );
+                let identity = Transform::from_mat4(Mat4::IDENTITY);
+                let instance = self.scene.add_moving_transform(identity, motion, obj);
+                self.current_prims.push(instance);
+                instance
+            }
+            None => self.add_instance(obj, self.state.transform),
+        };
+        if self.object_state.is_none() {
+            let instances = self.scene.named_instances.entry(name.to_owned());
+            instances.or_default().push(instance);
+        }
+    }
+
+    // `transform` is a world transform as built from the current one, which includes the root
+    // transform that the object's shapes were already placed with
+    fn add_instance(&mut self, obj: NodeId, transform: DMat4) -> NodeId {
+        let transform = transform * self.root.inverse();
         let transformed = self.scene.add_transform(
             Transform {
-                m: self.state.transform.inverse().as_mat4(),
-                m_inv: self.state.transform.as_mat4(),
+                m: transform.inverse().as_mat4(),
+                m_inv: transform.as_mat4(),
             },
             obj,
         );
         self.current_prims.push(transformed);
+        transformed
+    }
+
+    // applies a transform directive to the transforms selected by ActiveTransform
+    fn update_transform(&mut self, f: impl Fn(DMat4) -> DMat4) {
+        let [start, end] = self.state.active_transforms;
+        if start {
+            self.state.transform = f(self.state.transform);
+        }
+        if end {
+            self.state.end_transform = f(self.state.end_transform);
+        }
     }
 
     fn identity(&mut self) {
-        self.state.transform = DMat4::IDENTITY;
+        let root = self.root;
+        self.update_transform(|_| root);
     }
 
     fn look_at(&mut self, (eye, look, up): (DVec3, DVec3, DVec3)) {
-        self.state.transform *= DMat4::look_at_lh(eye, look, up);
+        self.update_transform(|m| m * DMat4::look_at_lh(eye, look, up));
     }
 
     fn rotate(&mut self, (angle, axis): (f64, DVec3)) {
-        self.state.transform *= DMat4::from_axis_angle(axis.normalize(), angle.to_radians());
+        self.update_transform(|m| m * DMat4::from_axis_angle(axis.normalize(), angle.to_radians()));
     }
 
     fn translate(&mut self, offset: DVec3) {
-        self.state.transform *= DMat4::from_translation(offset);
+        self.update_transform(|m| m * DMat4::from_translation(offset));
     }
 
     fn scale(&mut self, scale: DVec3) {
-        self.state.transform *= DMat4::from_scale(scale);
+        self.update_transform(|m| m * DMat4::from_scale(scale));
     }
 
     fn set_transform(&mut self, mat: DMat4) {
-        self.state.transform = mat;
+        let root = self.root;
+        self.update_transform(|_| root * mat);
     }
 
     fn apply_transform(&mut self, mat: DMat4) {
-        self.state.transform *= mat;
+        self.update_transform(|m| m * mat);
+    }
+
+    fn transform_times(&mut self, start: f64, end: f64) {
+        self.transform_times = [start, end];
+    }
+
+    fn active_transform(&mut self, which: &str) {
+        self.state.active_transforms = match which {
+            "StartTime" => [true, false],
+            "EndTime" => [false, true],
+            "All" => [true, true],
+            _ => return self.warn(format!("Unrecognized ActiveTransform {which}")),
+        };
+    }
+
+    // how the current transform moves while the shutter is open, if it does
+    fn motion(&self) -> Option<AnimatedTransform> {
+        if self.state.transform == self.state.end_transform {
+            return None;
+        }
+        Some(self.motion_between(self.state.transform, self.state.end_transform))
+    }
+
+    fn motion_between(&self, start: DMat4, end: DMat4) -> AnimatedTransform {
+        let [t0, t1] = self.transform_times;
+        let shutter = self.shutter.map(|t| match t1 > t0 {
+            true => (t - t0) / (t1 - t0),
+            false => 0.0,
+        });
+        AnimatedTransform::new(start, end, shutter)
     }
 
     fn camera(&mut self, kind: &str, props: Props) {
-        let aspect_ratio = props
-            .get_float("frameaspectratio")
-            .unwrap_or(self.render_options.width as f64 / self.render_options.height as f64);
-        let (ortho, mat) = match kind {
-            "orhographic" => (
-                true,
-                DMat4::orthographic_lh(-aspect_ratio, aspect_ratio, -1.0, 1.0, 0.0, 1.0),
-            ),
-            "perspective" => (
-                false,
-                DMat4::perspective_infinite_lh(
-                    props.get_float("fov").unwrap_or(90.0).to_radians(),
-                    aspect_ratio,
-                    0.01,
-                ),
-            ),
-            _ => return println!("Unrecognized camera type {kind}"),
+        let ortho = match kind {
+            "orthographic" => true,
+            "perspective" => false,
+            _ => return self.warn(format!("Unrecognized camera type {kind}")),
         };
+        // the projection depends on the film, which may come after the camera
+        self.camera_projection = Some((
+            ortho,
+            props.get_float("fov").unwrap_or(90.0),
+            props.get_float("frameaspectratio"),
+        ));
+
+        self.shutter = [
+            props.get_float("shutteropen").unwrap_or(0.0),
+            props.get_float("shutterclose").unwrap_or(1.0),
+        ];
+        // moves from camera to world space, opposite to the transforms given
+        let motion = self.motion().map(|_| {
+            let [t0, t1] = self.transform_times;
+            let shutter = self.shutter.map(|t| match t1 > t0 {
+                true => (t - t0) / (t1 - t0),
+                false => 0.0,
+            });
+            AnimatedTransform::new(
+                self.state.transform.inverse(),
+                self.state.end_transform.inverse(),
+                shutter,
+            )
+        });
 
         self.render_options.camera = ProjectiveCamera {
-            ndc_to_camera: Transform::from_mat4_inverse(mat.as_mat4()),
+            ndc_to_camera: Transform::from_mat4(Mat4::IDENTITY),
             world_to_camera: Transform::from_mat4(self.state.transform.as_mat4()),
             lens_radius: props.get_float("lensradius").unwrap_or(0.0) as f32,
             focal_distance: props.get_float("focaldistance").unwrap_or(1e30) as f32,
             orthographic: ortho as u32,
-            _padding: 0,
+            distortion: 0.0,
+            chromatic_aberration: 0.0,
+            vignetting: false as u32,
+            moving: motion.is_some() as u32,
+            frame_diagonal: 0.0,
+            motion: motion.unwrap_or_default(),
+        };
+    }
+
+    // Notes on geometry that looks to be in other units or another up axis than the camera
+    // expects, for scenes assembled from assets of different tools
+    fn check_convention(&self) {
+        let bounds = self.scene.node_bounds(self.scene.root.unwrap());
+        let size = (bounds.max - bounds.min).as_dvec3();
+        if !size.is_finite() || size.max_element() <= 0.0 {
+            return;
+        }
+        let camera_to_world = self.render_options.camera.world_to_camera.m_inv;
+        let eye = camera_to_world.transform_point3(Vec3::ZERO).as_dvec3();
+        let view = camera_to_world
+            .transform_vector3(Vec3::Z)
+            .as_dvec3()
+            .normalize();
+        let up = camera_to_world
+            .transform_vector3(Vec3::Y)
+            .as_dvec3()
+            .normalize();
+
+        // a camera set up for meters looking at a scene in centimeters sees it from far inside
+        // or far outside of it
+        let center = (bounds.min + bounds.max).as_dvec3() / 2.0;
+        let ratio = size.length() / eye.distance(center).max(1e-9);
+        if self.convention.scale.is_none() && !(1e-3..=1e3).contains(&ratio) {
+            let suggestion = 10f64.powf((1.0 / ratio).log10().round());
+            self.note(format!(
+                "The scene is {:.3} across but the camera is {:.3} from its center; if it \
+                 was modeled in other units, try --scene-scale {suggestion}",
+                size.length(),
+                eye.distance(center)
+            ));
+        }
+
+        // flat scenes are usually ground that should be level in the view, not seen edge on
+        // from the side
+        let thin = size.min_position();
+        let mut others = size.to_array();
+        others[thin] = f64::INFINITY;
+        let flat = size[thin] < 0.05 * others.iter().copied().fold(f64::INFINITY, f64::min);
+        let mut normal = DVec3::ZERO;
+        normal[thin] = 1.0;
+        if self.convention.up_axis.is_none()
+            && flat
+            && up.dot(normal).abs() < 0.5
+            && view.dot(normal).abs() < 0.5
+        {
+            let axis = ["x", "y", "z"][thin];
+            self.note(format!(
+                "The scene is flat along {axis}, which is not up in the camera's view; if \
+                 it was modeled {axis}-up, try --up-axis {axis}"
+            ));
+        }
+    }
+
+    // like pbrt, the field of view and the screen window span the shorter side of the image
+    fn finish_camera(&mut self) {
+        let Some((ortho, fov, aspect_ratio)) = self.camera_projection else {
+            return;
+        };
+        let aspect_ratio = aspect_ratio
+            .unwrap_or(self.render_options.width as f64 / self.render_options.height as f64);
+        let mat = match ortho {
+            true => {
+                let (x, y) = match aspect_ratio > 1.0 {
+                    true => (aspect_ratio, 1.0),
+                    false => (1.0, 1.0 / aspect_ratio),
+                };
+                DMat4::orthographic_lh(-x, x, -y, y, 0.0, 1.0)
+            }
+            false => {
+                let mut fov_y = fov.to_radians();
+                if aspect_ratio < 1.0 {
+                    fov_y = 2.0 * ((fov_y / 2.0).tan() / aspect_ratio).atan();
+                }
+                DMat4::perspective_infinite_lh(fov_y, aspect_ratio, 0.01)
+            }
+        };
+        self.render_options.camera.ndc_to_camera = Transform::from_mat4_inverse(mat.as_mat4());
+    }
+
+    fn film(&mut self, kind: &str, props: Props) {
+        if !matches!(kind, "rgb" | "gbuffer" | "spectral") {
+            self.warn(format!("Unrecognized film type {kind}"));
+        }
+        if let Some(&[width]) = props.get_uint_list("xresolution").as_deref() {
+            self.render_options.width = width.max(1);
+        }
+        if let Some(&[height]) = props.get_uint_list("yresolution").as_deref() {
+            self.render_options.height = height.max(1);
+        }
+        if let Some(filename) = props.get_string("filename") {
+            self.render_options.filename = Some(PathBuf::from(filename));
+        }
+        if let Some(scale) = props.get_float("scale") {
+            self.render_options.scale = Some(scale as f32);
+        }
+        if let Some(crop) = props.get_float_list("cropwindow") {
+            match crop[..] {
+                [x0, x1, y0, y1] if x0 < x1 && y0 < y1 => {
+                    let clamp = |v: f64| v.clamp(0.0, 1.0) as f32;
+                    self.render_options.crop_window =
+                        Some([clamp(x0), clamp(x1), clamp(y0), clamp(y1)]);
+                }
+                _ => self.warn(format!(
+                    "Invalid crop window {crop:?}; rendering the whole image"
+                )),
+            }
+        }
+    }
+
+    fn pixel_filter(&mut self, kind: &str, props: Props) {
+        let ty = match kind {
+            "box" => FilterType::Box,
+            "gaussian" => FilterType::Gaussian,
+            "mitchell" => FilterType::Mitchell,
+            "sinc" => FilterType::Sinc,
+            "triangle" => FilterType::Triangle,
+            "blackmanharris" => FilterType::BlackmanHarris,
+            _ => return self.warn(format!("Unrecognized pixel filter {kind}")),
+        };
+        let mut filter = Filter::new(ty);
+        if let Some(radius) = props.get_float("xradius") {
+            filter.radius.x = radius as f32;
+        }
+        if let Some(radius) = props.get_float("yradius") {
+            filter.radius.y = radius as f32;
+        }
+        let params = match ty {
+            FilterType::Gaussian => ["sigma", ""],
+            FilterType::Mitchell => ["B", "C"],
+            FilterType::Sinc => ["tau", ""],
+            _ => ["", ""],
+        };
+        for (value, name) in filter.params.iter_mut().zip(params) {
+            if let Some(v) = props.get_float(name) {
+                *value = v as f32;
+            }
+        }
+        self.render_options.filter = filter;
+    }
+
+    fn sampler(&mut self, kind: &str, props: Props) {
+        if let Some(&[samples]) = props.get_uint_list("pixelsamples").as_deref() {
+            self.render_options.samples = samples;
+        }
+        if kind == "stratified" {
+            let x = props.get_uint_list("xsamples").map_or(4, |v| v[0]);
+            let y = props.get_uint_list("ysamples").map_or(4, |v| v[0]);
+            self.render_options.samples = x * y;
+        }
+        self.render_options.sampler = match kind {
+            "independent" => SamplerType::Independent,
+            "sobol" | "zsobol" | "paddedsobol" => SamplerType::Sobol,
+            "stratified" => SamplerType::Stratified,
+            // the closest we have to other low discrepancy samplers
+            "halton" | "pmj02bn" => {
+                self.warn(format!("Unsupported sampler {kind}, using sobol"));
+                SamplerType::Sobol
+            }
+            _ => return self.warn(format!("Unrecognized sampler type {kind}")),
         };
     }
 
+    fn integrator(&mut self, kind: &str, props: Props) {
+        if let Some(&[max_depth]) = props.get_uint_list("maxdepth").as_deref() {
+            self.render_options.max_depth = Some(max_depth);
+        }
+        let integrator = match kind {
+            "path" | "volpath" => "simple",
+            "randomwalk" => "randomwalk",
+            "bdpt" | "mlt" | "sppm" | "lightpath" | "simplepath" | "simplevolpath" => {
+                self.warn(format!("Unsupported integrator {kind}, using simple"));
+                "simple"
+            }
+            _ => return self.warn(format!("Unrecognized integrator type {kind}")),
+        };
+        self.render_options.integrator = Some(integrator.to_owned());
+    }
+
     fn image_texture(&mut self, name: &str, kind: &str, props: Props) {
-        let filename = props.get_string("filename").unwrap();
+        let Some(filename) = props.get_string("filename") else {
+            self.error(format!("Image texture {name} needs a filename"));
+            self.textures.insert(name.to_owned(), self.error_texture);
+            return;
+        };
 
         let is_float = match kind {
             "spectrum" => false,
             "float" => true,
             _ => {
-                println!("Unrecognized texture kind {kind}");
+                self.warn(format!("Unrecognized texture kind {kind}"));
                 return;
             }
         };
 
         let scale = props.get_float("scale").unwrap_or(1.0) as f32;
         let invert = props.get_bool("invert").unwrap_or(false);
+        let wrap = match props.get_string("wrap").unwrap_or("repeat") {
+            "repeat" => WrapMode::Repeat,
+            "clamp" => WrapMode::Clamp,
+            "black" => WrapMode::Black,
+            other => {
+                self.warn(format!("Unrecognized wrap mode {other}"));
+                WrapMode::Repeat
+            }
+        };
 
-        let uv_map = self.uv_mapping(&props);
+        let mapping = self.texture_mapping(&props);
 
-        let Some(img) = self
-            .scene
-            .add_image(&self.base.join(filename), is_float, false)
-        else {
+        let Some(img) = self.scene.add_image(&self.input(filename), is_float, false) else {
             return;
         };
 
         let id = match is_float {
             true => self
                 .scene
-                .add_float_image_texture(img, scale, invert, uv_map),
-            false => self.scene.add_rgb_image_texture(img, scale, invert, uv_map),
+                .add_float_image_texture(img, scale, invert, wrap, mapping),
+            false => self
+                .scene
+                .add_rgb_image_texture(img, scale, invert, wrap, mapping),
         };
+        self.scene.named_images.insert(name.to_owned(), img);
         self.textures.insert(name.to_owned(), id);
     }
 
@@ -280,38 +946,89 @@ impl SceneBuilder {
             let spec = self.scene.add_constant_spectrum(0.0);
             self.scene.add_constant_texture(spec)
         });
-        let uv_map = self.uv_mapping(&props);
-        let id = self.scene.add_checkerboard_texture(even, odd, uv_map);
+        let mapping = self.texture_mapping(&props);
+        let id = self.scene.add_checkerboard_texture(even, odd, mapping);
+        self.textures.insert(name.to_owned(), id);
+    }
+
+    fn wireframe_texture(&mut self, name: &str, props: Props) {
+        let wire = self.texture_property(&props, "wire").unwrap_or_else(|| {
+            let spec = self.scene.add_constant_spectrum(0.0);
+            self.scene.add_constant_texture(spec)
+        });
+        let fill = self.texture_property(&props, "fill").unwrap_or_else(|| {
+            let spec = self.scene.add_constant_spectrum(1.0);
+            self.scene.add_constant_texture(spec)
+        });
+        let width = props.get_float("width").unwrap_or(0.0) as f32;
+        let pixel_width = props.get_float("pixelwidth").unwrap_or(1.0) as f32;
+        let id = self
+            .scene
+            .add_wireframe_texture(wire, fill, width, pixel_width);
+        self.textures.insert(name.to_owned(), id);
+    }
+
+    fn vertex_color_texture(&mut self, name: &str) {
+        let id = self.scene.add_vertex_color_texture();
+        self.textures.insert(name.to_owned(), id);
+    }
+
+    fn uv_checker_texture(&mut self, name: &str, props: Props) {
+        let cells = props.get_float("cells").unwrap_or(8.0) as f32;
+        let mapping = self.texture_mapping(&props);
+        let id = self.scene.add_uv_checker_texture(cells, mapping);
         self.textures.insert(name.to_owned(), id);
     }
 
     fn unrecognized_texture(&mut self, ty: &str) {
-        println!("Unrecognized texture type {ty}");
+        self.warn(format!("Unrecognized texture type {ty}"));
     }
 
-    fn uv_mapping(&self, props: &Props) -> UvMappingParams {
-        if let Some(mapping) = props.get_string("mapping")
-            && mapping != "uv"
-        {
-            println!("Warning: Unsupported texture mapping mode {mapping}");
-        }
-        let mut uv_map = UvMappingParams {
+    fn texture_mapping(&self, props: &Props) -> TextureMapping {
+        let ty = match props.get_string("mapping").unwrap_or("uv") {
+            "uv" => MappingType::Uv,
+            "spherical" => MappingType::Spherical,
+            "cylindrical" => MappingType::Cylindrical,
+            "planar" => MappingType::Planar,
+            mapping => {
+                self.warn(format!("Unsupported texture mapping mode {mapping}"));
+                MappingType::Uv
+            }
+        };
+        let mut mapping = TextureMapping {
+            world_to_texture: self.state.transform.inverse().as_mat4(),
+            v1: Vec3::X,
+            ty,
+            v2: Vec3::Y,
+            _padding: 0,
             scale: Vec2::ONE,
             delta: Vec2::ZERO,
         };
-        if let Some(u_scale) = props.get_float("uscale") {
-            uv_map.scale.x = u_scale as f32;
-        }
-        if let Some(v_scale) = props.get_float("vscale") {
-            uv_map.scale.y = v_scale as f32;
+        if let MappingType::Uv = ty {
+            if let Some(u_scale) = props.get_float("uscale") {
+                mapping.scale.x = u_scale as f32;
+            }
+            if let Some(v_scale) = props.get_float("vscale") {
+                mapping.scale.y = v_scale as f32;
+            }
         }
-        if let Some(u_delta) = props.get_float("udelta") {
-            uv_map.delta.x = u_delta as f32;
+        if let MappingType::Uv | MappingType::Planar = ty {
+            if let Some(u_delta) = props.get_float("udelta") {
+                mapping.delta.x = u_delta as f32;
+            }
+            if let Some(v_delta) = props.get_float("vdelta") {
+                mapping.delta.y = v_delta as f32;
+            }
         }
-        if let Some(v_delta) = props.get_float("vdelta") {
-            uv_map.delta.y = v_delta as f32;
+        if let MappingType::Planar = ty {
+            if let Some(&v1) = props.get_vec3_list("v1").as_deref().and_then(|v| v.first()) {
+                mapping.v1 = v1.as_vec3();
+            }
+            if let Some(&v2) = props.get_vec3_list("v2").as_deref().and_then(|v| v.first()) {
+                mapping.v2 = v2.as_vec3();
+            }
         }
-        uv_map
+        mapping
     }
 
     fn spectrum_property(
@@ -328,7 +1045,7 @@ impl SceneBuilder {
             )),
             "rgb" => {
                 if scale != 1.0 {
-                    println!("Cannot scale rgb albedo spectrum");
+                    self.warn("Cannot scale rgb albedo spectrum".to_owned());
                 }
                 Some(
                     self.scene
@@ -346,7 +1063,7 @@ impl SceneBuilder {
             )),
             "spectrum" => {
                 if scale != 1.0 {
-                    println!("Cannot scale named spectrum");
+                    self.warn("Cannot scale named spectrum".to_owned());
                 }
                 if let Some(&spectrum) = props
                     .get_string(name)
@@ -354,16 +1071,14 @@ impl SceneBuilder {
                 {
                     Some(spectrum)
                 } else if let Some(file) = props.get_string(name) {
-                    let path = self.base.join(file);
-                    let content = std::fs::read_to_string(&path)
-                        .unwrap_or_else(|e| panic!("{e}: {}", path.display()));
-                    let data: Vec<_> = content
-                        .lines()
-                        .filter(|l| !l.contains('#'))
-                        .filter_map(|l| l.split_once(char::is_whitespace))
-                        .map(|(l, v)| [l.trim().parse().unwrap(), v.trim().parse().unwrap()])
-                        .collect();
-                    Some(self.scene.add_piecewise_linear_spectrum(&data))
+                    match read_spectrum_file(&self.input(file)) {
+                        Ok(data) => Some(self.scene.add_piecewise_linear_spectrum(&data)),
+                        Err(e) => {
+                            // left out, as if the parameter wasn't given
+                            self.error(format!("{e:#}"));
+                            None
+                        }
+                    }
                 } else if let Some(data) = props.get_float_list(name) {
                     let data: Vec<_> = data
                         .chunks_exact(2)
@@ -371,39 +1086,57 @@ impl SceneBuilder {
                         .collect();
                     Some(self.scene.add_piecewise_linear_spectrum(&data))
                 } else {
-                    println!("Could not interpret spectrum");
+                    self.warn("Could not interpret spectrum".to_owned());
                     None
                 }
             }
             ty => {
-                println!("Unrecognized spectrum property type {ty}");
+                self.warn(format!("Unrecognized spectrum property type {ty}"));
                 None
             }
         }
     }
 
     fn texture_property(&mut self, props: &Props, name: &str) -> Option<TextureId> {
-        match props.type_of(name)? {
-            "texture" => Some(
+        let texture = match props.type_of(name) {
+            None => None,
+            Some("texture") => Some(
                 self.textures
                     .get(props.get_string(name).unwrap())
                     .copied()
                     .unwrap_or_else(|| {
-                        println!("Texture {} doesn't exist?", props.get_string(name).unwrap());
+                        self.warn(format!(
+                            "Texture {} doesn't exist?",
+                            props.get_string(name).unwrap()
+                        ));
                         self.error_texture
                     }),
             ),
-            _ => self
+            Some(_) => self
                 .spectrum_property(props, name, 1.0, false)
                 .map(|spectrum| self.scene.add_constant_texture(spectrum)),
+        };
+        // given or not, so that conductors use their reflectance rather than eta and k
+        match self.furnace {
+            Some(albedo) if ["reflectance", "transmittance", "basecolor"].contains(&name) => {
+                Some(albedo)
+            }
+            _ => texture,
         }
     }
 
+    fn texture_property_or(&mut self, props: &Props, name: &str, default: f32) -> TextureId {
+        self.texture_property(props, name).unwrap_or_else(|| {
+            let spec = self.scene.add_constant_spectrum(default);
+            self.scene.add_constant_texture(spec)
+        })
+    }
+
     fn make_material(&mut self, ty: &str, props: Props) -> MaterialId {
         match ty {
             "coateddiffuse" => self.make_material("metallicworkflow", props),
             "coatedconductor" => {
-                println!("Note: coatedconductor material will be regular conductor");
+                self.note("coatedconductor material will be regular conductor".to_owned());
                 let mut props = props;
                 if let Some(data) = props.map.remove("conductor.eta") {
                     props.map.insert("eta", data);
@@ -429,9 +1162,9 @@ impl SceneBuilder {
                         let spec = self.scene.add_constant_spectrum(0.5);
                         self.scene.add_constant_texture(spec)
                     });
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
                 self.scene.add_diffuse_material(texture, normal_map)
             }
             "diffusetransmission" => {
@@ -451,9 +1184,9 @@ impl SceneBuilder {
                     let spec = self.scene.add_constant_spectrum(1.0);
                     self.scene.add_constant_texture(spec)
                 });
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
                 self.scene.add_diffuse_transmit_material(
                     reflectance,
@@ -464,6 +1197,11 @@ impl SceneBuilder {
             }
             "conductor" => {
                 let refl = self.texture_property(&props, "reflectance");
+                // eta and k give way to the furnace albedo
+                if self.furnace.is_some() {
+                    props.type_of("eta");
+                    props.type_of("k");
+                }
 
                 let (ior_re, ior_im) = match refl {
                     Some(refl) => {
@@ -498,9 +1236,9 @@ impl SceneBuilder {
                         (roughness, roughness)
                     });
 
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
                 self.scene.add_conductor_material(
                     ior_re,
@@ -528,9 +1266,9 @@ impl SceneBuilder {
                         (roughness, roughness)
                     });
 
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
                 self.scene
                     .add_dielectric_material(ior, u_roughness, v_roughness, normal_map)
@@ -539,9 +1277,9 @@ impl SceneBuilder {
                 let ior = self
                     .spectrum_property(&props, "eta", 1.0, false)
                     .unwrap_or_else(|| self.scene.add_constant_spectrum(1.5));
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
                 self.scene.add_thin_dielectric_material(ior, normal_map)
             }
@@ -573,9 +1311,9 @@ impl SceneBuilder {
                         (roughness, roughness)
                     });
 
-                let normal_map = props.get_string("normalmap").and_then(|filename| {
-                    self.scene.add_image(&self.base.join(filename), false, true)
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
                 self.scene.add_metallic_workflow_material(
                     base_color,
@@ -585,77 +1323,419 @@ impl SceneBuilder {
                     normal_map,
                 )
             }
-            "mix" => {
-                let materials = props.get_string_list("materials").unwrap();
-                let m1 = materials[0];
-                let m2 = materials[1];
+            "principled" => {
+                let base_color = match self.texture_property(&props, "basecolor") {
+                    Some(texture) => texture,
+                    None => self.texture_property_or(&props, "reflectance", 0.5),
+                };
+                let material = PrincipledMaterial {
+                    normal_map: u32::MAX,
+                    base_color,
+                    metallic: self.texture_property_or(&props, "metallic", 0.0),
+                    roughness: self.texture_property_or(&props, "roughness", 0.5),
+                    specular_tint: self.texture_property_or(&props, "speculartint", 0.0),
+                    clearcoat: self.texture_property_or(&props, "clearcoat", 0.0),
+                    clearcoat_roughness: self.texture_property_or(
+                        &props,
+                        "clearcoatroughness",
+                        0.03,
+                    ),
+                    sheen: self.texture_property_or(&props, "sheen", 0.0),
+                    transmission: self.texture_property_or(&props, "transmission", 0.0),
+                    eta: self.texture_property_or(&props, "eta", 1.5),
+                };
 
-                let m1 = self.materials.get(m1).copied().unwrap_or_else(|| {
-                    println!("Material {m1} does not exist?");
-                    self.error_material
-                });
-                let m2 = self.materials.get(m2).copied().unwrap_or_else(|| {
-                    println!("Material {m2} does not exist?");
-                    self.error_material
-                });
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
 
-                let amount = self.texture_property(&props, "amount").unwrap_or_else(|| {
-                    let spec = self.scene.add_constant_spectrum(0.5);
-                    self.scene.add_constant_texture(spec)
-                });
+                self.scene.add_principled_material(material, normal_map)
+            }
+            "mix" => {
+                let Some(materials) = props.get_string_list("materials") else {
+                    self.warn("Mix material requires a list of materials".to_owned());
+                    return self.error_material;
+                };
+                if materials.len() < 2 {
+                    self.warn(format!(
+                        "Mix material requires at least 2 materials, got {}",
+                        materials.len()
+                    ));
+                    return self.error_material;
+                }
+                let amount = self.texture_property(&props, "amount");
+                if amount.is_some() && materials.len() > 2 {
+                    self.warn(format!(
+                        "Mix amount ignored for {} materials; they are mixed evenly",
+                        materials.len()
+                    ));
+                }
 
-                self.scene.add_mix_material(m1, m2, amount)
+                // names are looked up as they are now, so a later redefinition doesn't change
+                // this mix; only names defined later are left for resolve_mixes
+                let mut slots: Vec<MixSlot> = materials
+                    .iter()
+                    .map(|&name| match self.materials.get(name) {
+                        Some(&material) => MixSlot::Material(material),
+                        None => MixSlot::Named(name.to_owned()),
+                    })
+                    .collect();
+
+                // more than two become a chain of mixes, each choosing its first material with
+                // the probability that gives all of them the same weight
+                let n = slots.len();
+                let mut rest = slots.pop().unwrap();
+                for (i, first) in slots.into_iter().enumerate().rev() {
+                    let amount = match amount {
+                        Some(amount) if n == 2 => amount,
+                        _ => {
+                            let rest_weight = (n - i - 1) as f32 / (n - i) as f32;
+                            let spec = self.scene.add_constant_spectrum(rest_weight);
+                            self.scene.add_constant_texture(spec)
+                        }
+                    };
+                    let error = self.error_material;
+                    let material = self.scene.add_mix_material(error, error, amount);
+                    self.pending_mixes.push(PendingMix {
+                        material,
+                        materials: [first, rest],
+                        location: self.reporter.location(),
+                    });
+                    rest = MixSlot::Material(material);
+                }
+                let MixSlot::Material(material) = rest else {
+                    unreachable!()
+                };
+                material
+            }
+            "measured" => {
+                let normal_map = props
+                    .get_string("normalmap")
+                    .and_then(|filename| self.scene.add_image(&self.input(filename), false, true));
+                let Some(filename) = props.get_string("filename") else {
+                    self.warn("Measured material requires a filename".to_owned());
+                    return self.error_material;
+                };
+                let path = self.input(filename);
+                match self.load_measured_bsdf(&path) {
+                    Ok(material) => self.scene.add_measured_material(material, normal_map),
+                    Err(e) => {
+                        self.error(format!(
+                            "Could not load measured BSDF {}: {e}",
+                            path.display()
+                        ));
+                        self.error_material
+                    }
+                }
             }
             _ => {
-                println!("Unrecognized material type {ty}");
+                self.warn(format!("Unrecognized material type {ty}"));
                 self.error_material
             }
         }
     }
 
+    fn load_measured_bsdf(&mut self, path: &Path) -> anyhow::Result<MeasuredMaterial> {
+        let fields = load_tensor_file(path)?;
+        let field = |name: &str, ndim: usize| {
+            fields
+                .get(name)
+                .filter(|f| f.shape.len() == ndim)
+                .with_context(|| format!("missing or malformed field {name}"))
+        };
+
+        let theta_i = field("theta_i", 1)?;
+        let phi_i = field("phi_i", 1)?;
+        let ndf = field("ndf", 2)?;
+        let sigma = field("sigma", 2)?;
+        let vndf = field("vndf", 4)?;
+        let luminance = field("luminance", 4)?;
+        let spectra = field("spectra", 5)?;
+        let wavelengths = field("wavelengths", 1)?;
+
+        let angles = [phi_i.shape[0], theta_i.shape[0]];
+        ensure!(
+            vndf.shape[..2] == angles
+                && luminance.shape[..2] == angles
+                && spectra.shape[..3] == [angles[0], angles[1], wavelengths.shape[0]]
+                && spectra.shape[3..] == luminance.shape[2..],
+            "inconsistent field shapes"
+        );
+
+        let params = [&*phi_i.data, &*theta_i.data];
+        let spectra_params = [&*phi_i.data, &*theta_i.data, &*wavelengths.data];
+        Ok(MeasuredMaterial {
+            normal_map: u32::MAX,
+            isotropic: (phi_i.shape[0] <= 2) as u32,
+            ndf: self.scene.add_piecewise_linear_2d(
+                ndf.shape[1],
+                ndf.shape[0],
+                &[],
+                &ndf.data,
+                false,
+            )?,
+            sigma: self.scene.add_piecewise_linear_2d(
+                sigma.shape[1],
+                sigma.shape[0],
+                &[],
+                &sigma.data,
+                false,
+            )?,
+            vndf: self.scene.add_piecewise_linear_2d(
+                vndf.shape[3],
+                vndf.shape[2],
+                &params,
+                &vndf.data,
+                true,
+            )?,
+            luminance: self.scene.add_piecewise_linear_2d(
+                luminance.shape[3],
+                luminance.shape[2],
+                &params,
+                &luminance.data,
+                true,
+            )?,
+            spectra: self.scene.add_piecewise_linear_2d(
+                spectra.shape[4],
+                spectra.shape[3],
+                &spectra_params,
+                &spectra.data,
+                false,
+            )?,
+        })
+    }
+
     fn material(&mut self, ty: &str, props: Props) {
+        let temperature = props.get_float("temperature");
         self.state.material = self.make_material(ty, props);
+        self.set_material_temperature(self.state.material, temperature);
+    }
+
+    fn set_material_temperature(&mut self, material: MaterialId, temperature: Option<f64>) {
+        match temperature {
+            Some(t) if t > 0.0 => {
+                self.material_temperatures.insert(material, t as f32);
+            }
+            Some(t) => self.warn(format!("Ignoring invalid material temperature {t}")),
+            None => {}
+        }
     }
 
     fn make_named_material(&mut self, name: &str, props: Props) {
-        let material = self.make_material(props.get_string("type").unwrap(), props);
+        let Some(value) = self.material_replacements.get(name).cloned() else {
+            return self.define_named_material(name, props);
+        };
+        props.discard();
+        let Some(table) = value.as_table() else {
+            self.warn(format!("Material replacement {name} is not a table"));
+            self.materials.insert(name.to_owned(), self.error_material);
+            return;
+        };
+        self.note(format!("Replacing material {name}"));
+        let ty = table
+            .get("type")
+            .and_then(toml::Value::as_str)
+            .unwrap_or("");
+        self.define_named_material(
+            name,
+            Props::from_toml(table, &self.reporter).with_ctx("material", ty),
+        );
+    }
+
+    fn define_named_material(&mut self, name: &str, props: Props) {
+        let Some(ty) = props.get_string("type") else {
+            self.warn(format!("Named material {name} has no type"));
+            self.materials.insert(name.to_owned(), self.error_material);
+            return;
+        };
+        let temperature = props.get_float("temperature");
+        let material = self.make_material(ty, props);
+        self.set_material_temperature(material, temperature);
         self.materials.insert(name.to_owned(), material);
     }
 
+    // Points each mix at the materials it names, which may be defined after it
+    fn resolve_mixes(&mut self) {
+        let mut locations = HashMap::new();
+        for pending in std::mem::take(&mut self.pending_mixes) {
+            let materials = pending.materials.map(|slot| match slot {
+                MixSlot::Material(material) => material,
+                MixSlot::Named(name) => self.materials.get(&name).copied().unwrap_or_else(|| {
+                    self.reporter.report_at(
+                        Severity::Warning,
+                        pending.location.clone(),
+                        format!("Material {name} does not exist?"),
+                    );
+                    self.error_material
+                }),
+            });
+            self.scene.set_mix_materials(pending.material, materials);
+            locations.insert(pending.material, pending.location);
+        }
+
+        // every reference is by name, so a cycle only passes through named materials
+        let mut names: Vec<(String, MaterialId)> = self
+            .materials
+            .iter()
+            .map(|(name, &material)| (name.clone(), material))
+            .collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        // the inner mixes of a chain have no name
+        let name_of = |material: MaterialId| {
+            names
+                .iter()
+                .find(|&&(_, m)| m == material)
+                .map(|(name, _)| name.as_str())
+        };
+
+        let mut finished = HashSet::new();
+        for &(_, start) in &names {
+            if finished.contains(&start) || self.scene.mix_materials(start).is_none() {
+                continue;
+            }
+            // the mixes being resolved, each with how many of its materials have been visited
+            let mut stack = vec![(start, 0)];
+            while let Some((mix, next)) = stack.last_mut() {
+                let (mix, mut materials) = (*mix, self.scene.mix_materials(*mix).unwrap());
+                if *next == materials.len() {
+                    finished.insert(mix);
+                    stack.pop();
+                    continue;
+                }
+                let child = materials[*next];
+                *next += 1;
+                if finished.contains(&child) || self.scene.mix_materials(child).is_none() {
+                    continue;
+                }
+                let Some(pos) = stack.iter().position(|&(m, _)| m == child) else {
+                    stack.push((child, 0));
+                    continue;
+                };
+
+                let cycle: Vec<&str> = stack[pos..]
+                    .iter()
+                    .filter_map(|&(m, _)| name_of(m))
+                    .chain(name_of(child))
+                    .collect();
+                self.reporter.report_at(
+                    Severity::Error,
+                    locations.get(&mix).cloned().flatten(),
+                    format!("Mix materials form a cycle: {}", cycle.join(" -> ")),
+                );
+                // cut the reference that closes the cycle
+                materials[stack.last().unwrap().1 - 1] = self.error_material;
+                self.scene.set_mix_materials(mix, materials);
+            }
+        }
+    }
+
     fn named_material(&mut self, name: &str) {
         self.state.material = self.materials.get(name).copied().unwrap_or_else(|| {
-            println!("Material {name} does not exist?");
+            self.warn(format!("Material {name} does not exist?"));
             self.error_material
         });
     }
 
     fn infinite_light(&mut self, props: Props) {
-        let scale = props.get_float("scale").unwrap_or(1.0) as f32;
+        let Some(override_scale) = self.light_override(&props) else {
+            return props.discard();
+        };
+        let scale = props.get_float("scale").unwrap_or(1.0) as f32
+            * self.environment.scale
+            * override_scale;
         if let Some(filename) = props.get_string("filename") {
             let Some(image) = self
                 .scene
-                .add_image(&self.base.join(filename), false, false)
+                .add_image_now(&self.input(filename), false, false)
             else {
                 return;
             };
-            let light = self
-                .scene
-                .add_image_light(self.state.transform, image, scale);
+            let rotation = DMat4::from_rotation_z((self.environment.rotation as f64).to_radians());
+            let transform = self.state.transform * rotation;
+            let light = match self.portal(&props) {
+                Some(portal) => self.scene.add_portal_light(transform, image, scale, portal),
+                None => self.scene.add_image_light(transform, image, scale),
+            };
             self.lights.push(light);
         } else if let Some(spectrum) = self.spectrum_property(&props, "L", scale, true) {
+            if props.get_vec3_list("portal").is_some() {
+                self.warn("Infinite light portals are only supported with images".to_owned());
+            }
             let light = self.scene.add_uniform_light(spectrum);
             self.lights.push(light);
         } else {
-            println!("Infinite light specifies neither image nor spectrum?");
+            self.warn("Infinite light specifies neither image nor spectrum?".to_owned());
         }
     }
 
+    // the portal of an infinite light in world space, if it is a rectangle
+    fn portal(&self, props: &Props) -> Option<[DVec3; 4]> {
+        let points = props.get_vec3_list("portal")?;
+        let Ok(portal) = <[DVec3; 4]>::try_from(points) else {
+            self.warn("Infinite light portal needs 4 points; ignoring it".to_owned());
+            return None;
+        };
+        let portal = portal.map(|p| self.state.transform.transform_point3(p));
+
+        let edges = [0, 1, 2, 3].map(|i| portal[(i + 1) % 4] - portal[i]);
+        let rectangle = (0..4).all(|i| {
+            let (a, b) = (edges[i], edges[(i + 1) % 4]);
+            a.length() > 0.0 && a.normalize().dot(b.normalize()).abs() < 1e-3
+        }) && (edges[0] + edges[2]).length() < 1e-3 * edges[0].length();
+        if !rectangle {
+            self.warn("Infinite light portal is not a rectangle; ignoring it".to_owned());
+            return None;
+        }
+        Some(portal)
+    }
+
     fn unrecognized_light(&mut self, ty: &str) {
-        println!("Unrecognized light type {ty}");
+        self.light_directives += 1;
+        self.warn(format!("Unrecognized light type {ty}"));
+    }
+
+    // Scale from the light override file for the light being defined, or None if it is disabled
+    fn light_override(&mut self, props: &Props) -> Option<f32> {
+        let index = self.light_directives.to_string();
+        self.light_directives += 1;
+        if self.furnace.is_some() {
+            return None;
+        }
+        let name = props.get_string("name");
+        let Some((key, value)) = name
+            .and_then(|name| self.light_overrides.get_key_value(name))
+            .or_else(|| self.light_overrides.get_key_value(&index))
+        else {
+            return Some(1.0);
+        };
+        self.applied_light_overrides.insert(key.clone());
+
+        let Some(table) = value.as_table() else {
+            self.warn(format!("Light override {key} is not a table"));
+            return Some(1.0);
+        };
+        let mut scale = 1.0;
+        for (setting, value) in table {
+            match (setting.as_str(), value) {
+                ("enabled", &toml::Value::Boolean(enabled)) => {
+                    if !enabled {
+                        return None;
+                    }
+                }
+                ("scale", &toml::Value::Float(v)) => scale = v as f32,
+                ("scale", &toml::Value::Integer(v)) => scale = v as f32,
+                _ => self.warn(format!("Unknown setting {setting} in light override {key}")),
+            }
+        }
+        Some(scale)
     }
 
     fn diffuse_area_light(&mut self, props: Props) {
-        let scale = props.get_float("scale").unwrap_or(1.0) as f32;
+        let Some(override_scale) = self.light_override(&props) else {
+            self.state.area_light = None;
+            return props.discard();
+        };
+        let scale = props.get_float("scale").unwrap_or(1.0) as f32 * override_scale;
         let two_sided = props.get_bool("twosided").unwrap_or(false);
         self.state.area_light = self
             .spectrum_property(&props, "L", scale, true)
@@ -663,7 +1743,21 @@ impl SceneBuilder {
     }
 
     fn unrecognized_area_light(&mut self, ty: &str) {
-        println!("Unrecognized area light type {ty}");
+        self.light_directives += 1;
+        self.warn(format!("Unrecognized area light type {ty}"));
+    }
+
+    // explicit area lights take precedence over thermal emission from the material
+    fn current_area_light(&mut self) -> Option<(SpectrumId, bool)> {
+        if self.state.area_light.is_some() || self.furnace.is_some() {
+            return self.state.area_light;
+        }
+        let &temperature = self.material_temperatures.get(&self.state.material)?;
+        let spectrum = *self
+            .blackbody_spectra
+            .entry(OrderedFloat(temperature))
+            .or_insert_with(|| self.scene.add_blackbody_spectrum(temperature, 1.0, false));
+        Some((spectrum, true))
     }
 
     fn sphere(&mut self, props: Props) {
@@ -678,13 +1772,184 @@ impl SceneBuilder {
         });
 
         let transform = self.state.transform * DMat4::from_scale(DVec3::splat(radius));
+        self.transformed_shape(shape_id, transform, "spheres");
+    }
+
+    fn sdf(&mut self, props: Props) {
+        let ops = props.get_string_list("ops").unwrap_or_default();
+        let mut params = props
+            .get_float_list("params")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| v as f32);
+
+        let mut program = vec![];
+        for op in ops {
+            let arity = match op {
+                "sphere" => 4,
+                "box" => 7,
+                "torus" => 5,
+                "union" | "intersection" | "subtraction" => 1,
+                _ => return self.warn(format!("Unrecognized sdf operation {op}; skipping shape")),
+            };
+            let p: Vec<_> = params.by_ref().take(arity).collect();
+            if p.len() < arity {
+                return self.warn(format!("sdf {op} needs {arity} params; skipping shape"));
+            }
+            let center = || Vec3::from_slice(&p);
+            program.push(match op {
+                "sphere" => SdfOp::Sphere {
+                    center: center(),
+                    radius: p[3],
+                },
+                "box" => SdfOp::Box {
+                    center: center(),
+                    half_extents: Vec3::from_slice(&p[3..]),
+                    rounding: p[6],
+                },
+                "torus" => SdfOp::Torus {
+                    center: center(),
+                    major: p[3],
+                    minor: p[4],
+                },
+                "union" => SdfOp::Union(p[0]),
+                "intersection" => SdfOp::Intersection(p[0]),
+                _ => SdfOp::Subtraction(p[0]),
+            });
+        }
+        if params.next().is_some() {
+            self.warn("sdf has unused params".to_owned());
+        }
+
+        match SdfOp::stack_depth(&program) {
+            Some(depth) if depth <= SdfOp::STACK_SIZE => {}
+            Some(depth) => {
+                return self.warn(format!(
+                    "sdf needs a stack of {depth} but at most {} is supported; skipping shape",
+                    SdfOp::STACK_SIZE
+                ));
+            }
+            None => {
+                return self
+                    .warn("sdf does not produce a single distance; skipping shape".to_owned());
+            }
+        }
+
+        let shape_id = self.scene.add_sdf(&program);
+        self.transformed_shape(shape_id, self.state.transform, "sdfs");
+    }
+
+    fn csg(&mut self, props: Props) {
+        let ops = props.get_string_list("ops").unwrap_or_default();
+        let mut params = props
+            .get_float_list("params")
+            .unwrap_or_default()
+            .into_iter()
+            .map(|v| v as f32);
+
+        let mut program = vec![];
+        for op in ops {
+            let arity = match op {
+                "sphere" => 4,
+                "cylinder" => 7,
+                "box" => 6,
+                "union" | "intersection" | "difference" => 0,
+                _ => return self.warn(format!("Unrecognized csg operation {op}; skipping shape")),
+            };
+            let p: Vec<_> = params.by_ref().take(arity).collect();
+            if p.len() < arity {
+                return self.warn(format!("csg {op} needs {arity} params; skipping shape"));
+            }
+            program.push(match op {
+                "sphere" => CsgOp::Sphere {
+                    center: Vec3::from_slice(&p),
+                    radius: p[3],
+                },
+                "cylinder" => CsgOp::Cylinder {
+                    p0: Vec3::from_slice(&p),
+                    p1: Vec3::from_slice(&p[3..]),
+                    radius: p[6],
+                },
+                "box" => CsgOp::Box {
+                    min: Vec3::from_slice(&p),
+                    max: Vec3::from_slice(&p[3..]),
+                },
+                "union" => CsgOp::Union,
+                "intersection" => CsgOp::Intersection,
+                _ => CsgOp::Difference,
+            });
+            if program.last().unwrap().is_degenerate() {
+                return self.warn(format!("csg {op} has no volume; skipping shape"));
+            }
+        }
+        if params.next().is_some() {
+            self.warn("csg has unused params".to_owned());
+        }
+
+        match CsgOp::stack_depth(&program) {
+            Some(depth) if depth <= CsgOp::STACK_SIZE => {}
+            Some(depth) => {
+                return self.warn(format!(
+                    "csg needs a stack of {depth} but at most {} is supported; skipping shape",
+                    CsgOp::STACK_SIZE
+                ));
+            }
+            None => {
+                return self.warn("csg does not produce a single solid; skipping shape".to_owned());
+            }
+        }
+
+        let shape_id = self.scene.add_csg(&program);
+        self.transformed_shape(shape_id, self.state.transform, "csg shapes");
+    }
+
+    fn heightfield(&mut self, props: Props) {
+        let (nx, ny, heights) = match props.get_string("filename") {
+            // heights from the luminance of an image, with the top row at y = 1
+            Some(file) => {
+                let path = self.input(file);
+                let Ok(img) = crate::scene::load_image(&path).inspect_err(|e| {
+                    self.error(format!(
+                        "Could not load heightfield {}: {e}",
+                        path.display()
+                    ))
+                }) else {
+                    return;
+                };
+                let img = img.to_luma32f();
+                let heights = img.rows().rev().flatten().map(|p| p.0[0]).collect();
+                (img.width(), img.height(), heights)
+            }
+            None => {
+                let nx = props.get_uint_list("nu").map_or(0, |v| v[0]);
+                let ny = props.get_uint_list("nv").map_or(0, |v| v[0]);
+                let heights = props.get_float_list("Pz").unwrap_or_default();
+                let heights: Vec<_> = heights.into_iter().map(|z| z as f32).collect();
+                if heights.len() != (nx * ny) as usize {
+                    return self.warn(format!(
+                        "Heightfield needs {nx}x{ny} heights but has {}; skipping shape",
+                        heights.len()
+                    ));
+                }
+                (nx, ny, heights)
+            }
+        };
+        if nx < 2 || ny < 2 {
+            return self.warn("Heightfield needs at least 2x2 heights; skipping shape".to_owned());
+        }
 
+        let shape_id = self.scene.add_heightfield(nx, ny, &heights);
+        self.transformed_shape(shape_id, self.state.transform, "heightfields");
+    }
+
+    // primitive for a shape in its own space, placed by `transform`
+    fn transformed_shape(&mut self, shape_id: ShapeId, transform: DMat4, kind: &str) {
         let one = self.scene.add_constant_spectrum(1.0);
         let one = self.scene.add_constant_texture(one);
 
-        let light = match self.state.area_light {
+        let light = match self.current_area_light() {
             Some((spectrum, two_sided)) => {
-                println!("Note: light sampling spheres is currently not supported");
+                self.note(format!("Light sampling {kind} is currently not supported"));
                 self.scene
                     .add_area_light(shape_id, spectrum, two_sided, one)
             }
@@ -693,17 +1958,34 @@ impl SceneBuilder {
 
         let primitive = self.scene.add_primitive(PrimitiveNode {
             shape: shape_id,
-            material: self.state.material,
+            material: self.material_override.unwrap_or(self.state.material),
             light,
             alpha: one,
         });
-        let transformed = self.scene.add_transform(
-            Transform {
-                m: transform.inverse().as_mat4(),
-                m_inv: transform.as_mat4(),
-            },
-            primitive,
-        );
+        let motion = self.motion();
+        if motion.is_some() && light != LightId::ZERO {
+            self.note(
+                "Moving area lights are currently not supported; rendering it static".to_owned(),
+            );
+        }
+        let transformed = match motion.filter(|_| light == LightId::ZERO) {
+            // from the shape's space to the space the motion starts from
+            Some(motion) => {
+                let local = self.state.transform.inverse() * transform;
+                let local = Transform {
+                    m: local.inverse().as_mat4(),
+                    m_inv: local.as_mat4(),
+                };
+                self.scene.add_moving_transform(local, motion, primitive)
+            }
+            None => self.scene.add_transform(
+                Transform {
+                    m: transform.inverse().as_mat4(),
+                    m_inv: transform.as_mat4(),
+                },
+                primitive,
+            ),
+        };
 
         if light != LightId::ZERO {
             self.scene.set_area_light_transform(light, transformed);
@@ -716,7 +1998,7 @@ impl SceneBuilder {
     fn triangle_mesh(&mut self, props: Props) {
         let transform_dir = DMat3::from_mat4(self.state.transform);
         if transform_dir.determinant() < 0.0 {
-            println!("Creating mesh with transform which swaps handedness");
+            self.note("Creating mesh with transform which swaps handedness".to_owned());
         }
 
         let alpha = self.texture_property(&props, "alpha").unwrap_or_else(|| {
@@ -743,7 +2025,7 @@ impl SceneBuilder {
 
         let uvs = props.get_vec2_list("uv").unwrap_or(vec![]);
 
-        let verts: Vec<_> = positions
+        let mut verts: Vec<_> = positions
             .into_iter()
             .zip(normals.into_iter().chain(std::iter::repeat(Vec3::ZERO)))
             .zip(uvs.into_iter().chain(std::iter::repeat(DVec2::ZERO)))
@@ -755,58 +2037,277 @@ impl SceneBuilder {
             })
             .collect();
 
-        let tris = indices
+        let mut tris = indices
             .chunks_exact(3)
             .map(|is| is.try_into().unwrap())
             .collect::<Vec<_>>();
 
-        let iter = self.scene.add_triangles(&verts, &tris);
-        self.create_primitives(alpha, iter);
+        sanitize_vertices(&mut verts);
+        if self.weld {
+            self.scene.welded_vertices += weld_vertices(&mut verts, &mut tris);
+        }
+        self.cleanup.add(clean_triangles(&verts, &mut tris));
+        if self.repair_orientation {
+            self.add_repairs(repair_orientation(&mut verts, &mut tris));
+        }
+
+        match self.scene.add_triangles(&verts, &tris) {
+            Ok(iter) => self.create_primitives(alpha, iter),
+            Err(e) => self.error(format!("Failed to store triangle mesh: {e}")),
+        }
+    }
+
+    // Copies of an object placed over a triangle mesh given like a trianglemesh, which is not
+    // rendered itself
+    fn scatter(&mut self, props: Props) {
+        let Some(name) = props.get_string("object") else {
+            return self.warn("Scatter needs an object; skipping shape".to_owned());
+        };
+        let Some(&obj) = self.objects.get(name) else {
+            return self.warn(format!(
+                "Attempt to scatter object {name} which does not exist"
+            ));
+        };
+        let Some(positions) = props.get_vec3_list("P") else {
+            return self.warn("Scatter needs a surface; skipping shape".to_owned());
+        };
+        let uvs = props.get_vec2_list("uv").unwrap_or_default();
+        let indices = props
+            .get_uint_list("indices")
+            .unwrap_or_else(|| vec![0, 1, 2]);
+        let tris: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|is| is.try_into().unwrap())
+            .collect();
+        if tris
+            .iter()
+            .flatten()
+            .any(|&i| i as usize >= positions.len())
+            || !uvs.is_empty() && uvs.len() != positions.len()
+        {
+            return self.warn("Scatter surface is invalid; skipping shape".to_owned());
+        }
+
+        let map = |param| {
+            let file = props.get_string(param)?;
+            let path = self.input(file);
+            crate::scene::load_image(&path)
+                .inspect_err(|e| {
+                    self.error(format!(
+                        "Could not load scatter map {}: {e}",
+                        path.display()
+                    ))
+                })
+                .ok()
+                .map(|img| img.to_luma32f())
+        };
+        let scale = match props.get_float_list("scale").as_deref() {
+            Some(&[s]) => [s, s],
+            Some(&[min, max]) => [min, max],
+            _ => [1.0, 1.0],
+        };
+        let scatter = Scatter {
+            count: props.get_uint_list("count").map_or(100, |v| v[0]),
+            seed: props.get_uint_list("seed").map_or(0, |v| v[0] as u64),
+            scale,
+            rotation: props.get_float("rotation").unwrap_or(360.0).to_radians(),
+            align_to_normal: props.get_bool("alignnormal").unwrap_or(true),
+            density_map: map("densitymap"),
+            scale_map: map("scalemap"),
+            rotation_map: map("rotationmap"),
+        };
+
+        let placed = scatter.place(&positions, &uvs, &tris);
+        println!("Scattered {} instances of {name}", placed.len());
+        for m in placed {
+            self.add_instance(obj, self.state.transform * m);
+        }
     }
 
     fn loop_subdivision_surface(&mut self, props: Props) {
-        println!("Note: loop subdivision surface will not be subdivided.");
+        self.note("Loop subdivision surface will not be subdivided.".to_owned());
         self.triangle_mesh(props);
     }
 
     fn plymesh(&mut self, props: Props) {
-        let file = props
-            .get_string("filename")
-            .expect("plymesh shape requires file name");
-        let path = self.base.join(file);
+        let Some(file) = props.get_string("filename") else {
+            return self.error("Plymesh shape without a filename".to_owned());
+        };
+        let path = self.input(file);
 
         let alpha = self.texture_property(&props, "alpha").unwrap_or_else(|| {
             let one = self.scene.add_constant_spectrum(1.0);
             self.scene.add_constant_texture(one)
         });
 
-        match path.extension().and_then(OsStr::to_str) {
-            Some("gz") => {
-                let iter = super::ply::load_plymesh(
-                    &mut self.scene,
-                    &mut BufReader::new(GzDecoder::new(File::open(path).unwrap())),
-                    self.state.transform,
-                );
-                self.create_primitives(alpha, iter);
+        self.pending_plymeshes.push(PendingPlymesh {
+            path,
+            location: self.reporter.location(),
+            radius: props.get_float("radius").map(|r| r as f32),
+            alpha,
+            state: self.state.clone(),
+        });
+    }
+
+    // Parse the pending PLY files on the thread pool, then add them to the scene in the order they
+    // appeared so that the scene is the same regardless of which file finishes first
+    fn flush_plymeshes(&mut self) {
+        let pending = std::mem::take(&mut self.pending_plymeshes);
+        let repair = self.repair_orientation;
+        let weld = self.weld;
+        let bvh_quality = self.scene.bvh_quality;
+        let compress = self.scene.compress_geometry;
+        let bvh_cache = self.bvh_cache.as_ref();
+        let meshes: Vec<_> = pending
+            .par_iter()
+            .map(|ply| -> anyhow::Result<_> {
+                let file = File::open(&ply.path)?;
+                let mut mesh = match ply.path.extension().and_then(OsStr::to_str) {
+                    Some("gz") => super::ply::parse_plymesh(
+                        &mut BufReader::new(GzDecoder::new(file)),
+                        ply.state.transform,
+                        ply.radius,
+                    ),
+                    _ => super::ply::parse_plymesh(
+                        &mut BufReader::new(file),
+                        ply.state.transform,
+                        ply.radius,
+                    ),
+                }?;
+                let mut cleanup = Cleanup::default();
+                let mut repairs = Repairs::default();
+                let mut welded = 0;
+                if let PlyMesh::Triangles { vertices, indices } = &mut mesh {
+                    sanitize_vertices(vertices);
+                    if weld {
+                        welded = weld_vertices(vertices, indices);
+                    }
+                    cleanup = clean_triangles(vertices, indices);
+                    if repair {
+                        repairs = repair_orientation(vertices, indices);
+                    }
+                }
+                // the shapes are in world space, so the BVH depends on where the mesh is placed
+                let entry = bvh_cache
+                    .filter(|_| mesh.len() >= BVH_CACHE_MIN_SHAPES)
+                    .map(|cache| {
+                        let settings = format!(
+                            "{:?} {:?} {repair} {compress} {bvh_quality:?}",
+                            ply.state.transform, ply.radius
+                        );
+                        cache.entry(&ply.path, &settings)
+                    });
+                Ok((mesh, cleanup, repairs, welded, entry))
+            })
+            .collect();
+
+        for (ply, result) in pending.into_iter().zip(meshes) {
+            // the mesh is left out
+            let (mesh, cleanup, repairs, welded, entry) = match result {
+                Ok(loaded) => loaded,
+                Err(e) => {
+                    let message = format!("Failed to load {}: {e:#}", ply.path.display());
+                    self.reporter
+                        .report_at(Severity::Error, ply.location, message);
+                    continue;
+                }
+            };
+            self.cleanup.add(cleanup);
+            self.add_repairs(repairs);
+            self.scene.welded_vertices += welded;
+            let shapes = match mesh.add_to_scene(&mut self.scene) {
+                Ok(shapes) => shapes,
+                Err(e) => {
+                    let message = format!("Failed to store {}: {e}", ply.path.display());
+                    self.reporter
+                        .report_at(Severity::Error, ply.location, message);
+                    continue;
+                }
+            };
+            let state = std::mem::replace(&mut self.state, ply.state);
+
+            // show the colors of scans unless a material was given
+            if shapes.colored && self.state.material == self.error_material {
+                let color = self.scene.add_vertex_color_texture();
+                self.state.material = self.scene.add_diffuse_material(color, None);
             }
-            _ => {
-                let iter = super::ply::load_plymesh(
-                    &mut self.scene,
-                    &mut BufReader::new(File::open(path).unwrap()),
-                    self.state.transform,
+            let first = self.current_prims.len();
+            let count = shapes.shapes.len();
+            self.create_primitives(ply.alpha, shapes.shapes.into_iter());
+            // moving meshes have a BVH of their own already
+            if let Some(entry) = entry
+                && self.current_prims.len() == first + count
+            {
+                self.add_cached_bvh(&ply.path, &entry, first);
+            }
+            self.state = state;
+        }
+    }
+
+    // Replace the primitives from `first` on with a BVH of their own, taken from the BVH cache if
+    // it has `entry`
+    fn add_cached_bvh(&mut self, mesh: &Path, entry: &Path, first: usize) {
+        let t = Instant::now();
+        let prims = self.current_prims.split_off(first);
+        let nodes = match self.bvh_cache.as_ref().unwrap().load(entry, prims.len()) {
+            Ok(nodes) => nodes,
+            Err(e) => {
+                self.warn(format!("{e:#}"));
+                None
+            }
+        };
+        let cached = nodes.as_ref().and_then(|nodes| self.scene.add_relative_bvh(nodes, &prims));
+        if nodes.is_some() && cached.is_none() {
+            self.warn(format!(
+                "ignoring BVH cache {}: the nodes don't form a BVH",
+                entry.display()
+            ));
+        }
+        let bvh = match cached {
+            Some(bvh) => {
+                println!(
+                    "Load BVH of {} from cache in {:.3?}",
+                    mesh.display(),
+                    t.elapsed()
                 );
-                self.create_primitives(alpha, iter);
+                bvh
+            }
+            None => {
+                let bvh = self.scene.add_bvh(&prims);
+                let nodes = self.scene.relative_bvh(bvh, &prims);
+                let saved = self.bvh_cache.as_ref().unwrap().save(entry, &nodes, prims.len());
+                if let Err(e) = saved {
+                    self.warn(format!("{e:#}"));
+                }
+                bvh
             }
         };
+        self.current_prims.push(bvh);
+    }
+
+    fn add_repairs(&mut self, repairs: Repairs) {
+        self.repairs.triangles += repairs.triangles;
+        self.repairs.normals += repairs.normals;
     }
 
     fn unrecognized_shape(&mut self, ty: &str) {
-        println!("Unrecognized shape type {ty}");
+        self.warn(format!("Unrecognized shape type {ty}"));
     }
 
     fn create_primitives(&mut self, alpha: TextureId, shapes: impl Iterator<Item = ShapeId>) {
+        let area_light = self.current_area_light();
+        // meshes are in world space as placed at the start of the shutter, so moving ones get a
+        // BVH of their own taken back to the space the motion starts from
+        let motion = self.motion();
+        if motion.is_some() && area_light.is_some() {
+            self.note(
+                "Moving area lights are currently not supported; rendering it static".to_owned(),
+            );
+        }
+        let motion = motion.filter(|_| area_light.is_none());
+        let first = self.current_prims.len();
         self.current_prims.extend(shapes.map(|shape| {
-            let light = match self.state.area_light {
+            let light = match area_light {
                 Some((rgb, two_sided)) => self.scene.add_area_light(shape, rgb, two_sided, alpha),
                 None => LightId::ZERO,
             };
@@ -815,11 +2316,21 @@ impl SceneBuilder {
             }
             self.scene.add_primitive(PrimitiveNode {
                 shape,
-                material: self.state.material,
+                material: self.material_override.unwrap_or(self.state.material),
                 light,
                 alpha,
             })
         }));
+        if let Some(motion) = motion {
+            let prims = self.current_prims.split_off(first);
+            let bvh = self.scene.add_bvh(&prims);
+            let start = Transform {
+                m: self.state.transform.as_mat4(),
+                m_inv: self.state.transform.inverse().as_mat4(),
+            };
+            let moving = self.scene.add_moving_transform(start, motion, bvh);
+            self.current_prims.push(moving);
+        }
     }
 }
 
@@ -829,15 +2340,65 @@ struct Props<'a> {
     used: RefCell<HashSet<&'a str>>,
     ctx: &'a str,
     domain: &'a str,
+    // unused properties are reported here when dropped
+    reporter: Option<Rc<Reporter>>,
 }
 
 impl<'a> Props<'a> {
+    // Keys are written like in pbrt ("float roughness"), except that the type can be left out
+    // for strings, booleans and single numbers
+    fn from_toml(table: &'a toml::Table, reporter: &Rc<Reporter>) -> Self {
+        let mut map = HashMap::new();
+        for (key, value) in table {
+            let (ty, name) = match key.split_once(' ') {
+                Some((ty, name)) => (ty, name.trim()),
+                None => match value {
+                    toml::Value::String(_) => ("string", key.as_str()),
+                    toml::Value::Boolean(_) => ("bool", key.as_str()),
+                    _ => ("float", key.as_str()),
+                },
+            };
+            let values = match value {
+                toml::Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            };
+            let Some(values) = values
+                .into_iter()
+                .map(|v| match v {
+                    toml::Value::String(v) => Some(Value::String(v)),
+                    &toml::Value::Integer(v) => Some(Value::Number(v as f64)),
+                    &toml::Value::Float(v) => Some(Value::Number(v)),
+                    &toml::Value::Boolean(v) => Some(Value::Boolean(v)),
+                    _ => None,
+                })
+                .collect()
+            else {
+                reporter.report(Severity::Warning, format!("Unsupported value for {key}"));
+                continue;
+            };
+            map.insert(name, (ty, values));
+        }
+        Props {
+            map,
+            used: Default::default(),
+            ctx: "",
+            domain: "",
+            reporter: Some(reporter.clone()),
+        }
+    }
+
+    // drop without warning about unused properties
+    fn discard(mut self) {
+        self.ctx = "";
+    }
+
     fn with_ctx(mut self, domain: &'a str, ctx: &'a str) -> Self {
         Props {
             map: std::mem::take(&mut self.map),
             used: std::mem::take(&mut self.used),
             ctx,
             domain,
+            reporter: self.reporter.take(),
         }
     }
 
@@ -876,7 +2437,9 @@ impl<'a> Props<'a> {
 
     fn get_vec3_list(&self, name: &str) -> Option<Vec<DVec3>> {
         self.lookup(name)
-            .filter(|&&(ty, _)| ty == "point3" || ty == "vector3" || ty == "normal" || ty == "rgb")
+            .filter(|&&(ty, _)| {
+                ty == "point3" || ty == "vector3" || ty == "vector" || ty == "normal" || ty == "rgb"
+            })
             .map(|(_, v)| {
                 v.chunks_exact(3)
                     .map(|vs| {
@@ -929,10 +2492,14 @@ impl Drop for Props<'_> {
         if ctx.is_empty() {
             return;
         }
+        let Some(reporter) = &self.reporter else {
+            return;
+        };
         let used = self.used.borrow();
         for &name in self.map.keys() {
             if !used.contains(name) {
-                println!("Warning: Unknown property {name} in {ctx} {domain}");
+                let message = format!("Unknown property {name} in {ctx} {domain}");
+                reporter.report(Severity::Warning, message);
             }
         }
     }
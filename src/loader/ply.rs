@@ -1,9 +1,10 @@
 use std::io::BufRead;
 
+use anyhow::{Context, bail};
 use bytemuck::Zeroable;
-use glam::{DMat3, DMat4};
+use glam::{DMat3, DMat4, Vec3};
 
-use crate::scene::{Scene, ShapeId, TriVertex};
+use crate::scene::{Bounds, Scene, ShapeId, Splat, TriVertex};
 
 enum Format {
     BinaryLe,
@@ -38,77 +39,120 @@ enum Property {
     NormalZ,
     U,
     V,
+    Color(usize, PrimType),
+    Radius,
     Indices(PrimType, PrimType),
     Unknown(Type),
 }
 
-pub fn load_plymesh<R: BufRead>(
-    scene: &mut Scene,
+pub struct PlyShapes {
+    pub shapes: Vec<ShapeId>,
+    // the file is a point cloud with colors
+    pub colored: bool,
+}
+
+// Contents of a PLY file, parsed without touching the scene so that files can be loaded in parallel
+pub enum PlyMesh {
+    Triangles {
+        vertices: Vec<TriVertex>,
+        indices: Vec<[u32; 3]>,
+    },
+    Splats {
+        splats: Vec<Splat>,
+        colored: bool,
+    },
+}
+
+impl PlyMesh {
+    // number of shapes `add_to_scene` adds
+    pub fn len(&self) -> usize {
+        match self {
+            PlyMesh::Triangles { indices, .. } => indices.len(),
+            PlyMesh::Splats { splats, .. } => splats.len(),
+        }
+    }
+
+    pub fn add_to_scene(&self, scene: &mut Scene) -> std::io::Result<PlyShapes> {
+        Ok(match self {
+            PlyMesh::Triangles { vertices, indices } => PlyShapes {
+                shapes: scene.add_triangles(vertices, indices)?.collect(),
+                colored: false,
+            },
+            &PlyMesh::Splats {
+                ref splats,
+                colored,
+            } => PlyShapes {
+                shapes: scene.add_splats(splats).collect(),
+                colored,
+            },
+        })
+    }
+}
+
+// Files without faces are loaded as point clouds of splats with radius `radius` unless the points
+// have their own
+pub fn parse_plymesh<R: BufRead>(
     data: &mut R,
     transform: DMat4,
-) -> impl Iterator<Item = ShapeId> + use<R> {
+    radius: Option<f32>,
+) -> anyhow::Result<PlyMesh> {
     let mut format = None;
     let mut elements = vec![];
 
     let mut line = String::new();
     loop {
         if line.is_empty() {
-            if data.read_line(&mut line).unwrap() == 0 {
+            if data.read_line(&mut line)? == 0 {
                 break;
             }
         }
 
         let mut words = line.split_whitespace();
+        let mut word = || words.next().context("unexpected end of line in header");
 
-        match words.next().unwrap() {
+        match word()? {
             "ply" | "comment" => {}
             "end_header" => break,
             "format" => {
-                format = Some(match words.next().unwrap() {
+                format = Some(match word()? {
                     "binary_little_endian" => {
-                        assert_eq!(
-                            words.next().unwrap(),
-                            "1.0",
-                            "only version 1.0 of binary_little_endian is supported"
-                        );
+                        if word()? != "1.0" {
+                            bail!("only version 1.0 of binary_little_endian is supported");
+                        }
                         Format::BinaryLe
                     }
                     "binary_big_endian" => {
-                        assert_eq!(
-                            words.next().unwrap(),
-                            "1.0",
-                            "only version 1.0 of binary_big_endian is supported"
-                        );
+                        if word()? != "1.0" {
+                            bail!("only version 1.0 of binary_big_endian is supported");
+                        }
                         Format::BinaryBe
                     }
-                    s => panic!("Unrecognized ply format: {s}"),
+                    s => bail!("unrecognized ply format {s}"),
                 })
             }
             "element" => {
-                let name = words.next().unwrap().to_owned();
-                let count = words.next().unwrap().parse().unwrap();
+                let name = word()?.to_owned();
+                let count = word()?.parse().context("invalid element count")?;
                 let mut properties = vec![];
 
                 loop {
                     line.clear();
-                    if data.read_line(&mut line).unwrap() == 0 {
+                    if data.read_line(&mut line)? == 0 {
                         break;
                     }
 
                     let mut words = line.split_whitespace();
-                    if words.next().unwrap() != "property" {
+                    let mut word = || words.next().context("unexpected end of line in header");
+                    if word()? != "property" {
                         break;
                     }
 
-                    let ty = match words.next().unwrap() {
-                        "list" => Type::List(
-                            prim_type(words.next().unwrap()),
-                            prim_type(words.next().unwrap()),
-                        ),
-                        ty => Type::Prim(prim_type(ty)),
+                    let ty = match word()? {
+                        "list" => Type::List(prim_type(word()?)?, prim_type(word()?)?),
+                        ty => Type::Prim(prim_type(ty)?),
                     };
 
-                    let prop = match (ty, words.next().unwrap()) {
+                    let prop = match (ty, word()?) {
                         (Type::Prim(PrimType::Float), "x") => Property::X,
                         (Type::Prim(PrimType::Float), "y") => Property::Y,
                         (Type::Prim(PrimType::Float), "z") => Property::Z,
@@ -119,6 +163,10 @@ pub fn load_plymesh<R: BufRead>(
                         (Type::Prim(PrimType::Float), "v") => Property::V,
                         (Type::Prim(PrimType::Float), "s") => Property::U,
                         (Type::Prim(PrimType::Float), "t") => Property::V,
+                        (Type::Prim(ty), "red" | "diffuse_red") => Property::Color(0, ty),
+                        (Type::Prim(ty), "green" | "diffuse_green") => Property::Color(1, ty),
+                        (Type::Prim(ty), "blue" | "diffuse_blue") => Property::Color(2, ty),
+                        (Type::Prim(PrimType::Float), "radius") => Property::Radius,
                         (Type::List(count, elem), "vertex_indices") => {
                             Property::Indices(count, elem)
                         }
@@ -139,19 +187,22 @@ pub fn load_plymesh<R: BufRead>(
 
                 continue;
             }
-            s => panic!("Unrecognized ply directive: {s}"),
+            s => bail!("unrecognized ply directive {s}"),
         }
 
         line.clear();
     }
 
-    let mut format: Box<dyn FormatReader> = match format.unwrap() {
+    let mut format: Box<dyn FormatReader> = match format.context("no format in header")? {
         Format::BinaryLe => Box::new(BinaryLeFormat(data)),
         Format::BinaryBe => Box::new(BinaryBeFormat(data)),
     };
 
     let mut vertices = vec![];
     let mut indices = vec![];
+    let mut colors = vec![];
+    let mut radii = vec![];
+    let mut has_faces = false;
 
     for element in elements {
         match &*element.name {
@@ -161,19 +212,36 @@ pub fn load_plymesh<R: BufRead>(
                     println!("Creating mesh with transform which swaps handedness");
                 }
                 let transform_normal = transform_dir.inverse().transpose();
+                let has_color = element
+                    .properties
+                    .iter()
+                    .any(|p| matches!(p, Property::Color(..)));
+                let has_radius = element
+                    .properties
+                    .iter()
+                    .any(|p| matches!(p, Property::Radius));
                 for _ in 0..element.count {
                     let mut data = TriVertex::zeroed();
+                    let mut color = Vec3::ONE;
+                    let mut radius = 0.0;
                     for prop in &element.properties {
                         match prop {
-                            Property::X => data.p.x = format.read_float(),
-                            Property::Y => data.p.y = format.read_float(),
-                            Property::Z => data.p.z = format.read_float(),
-                            Property::NormalX => data.n.x = format.read_float(),
-                            Property::NormalY => data.n.y = format.read_float(),
-                            Property::NormalZ => data.n.z = format.read_float(),
-                            Property::U => data.u = format.read_float(),
-                            Property::V => data.v = format.read_float(),
-                            _ => format.skip(prop.ty()),
+                            Property::X => data.p.x = format.read_float()?,
+                            Property::Y => data.p.y = format.read_float()?,
+                            Property::Z => data.p.z = format.read_float()?,
+                            Property::NormalX => data.n.x = format.read_float()?,
+                            Property::NormalY => data.n.y = format.read_float()?,
+                            Property::NormalZ => data.n.z = format.read_float()?,
+                            Property::U => data.u = format.read_float()?,
+                            Property::V => data.v = format.read_float()?,
+                            &Property::Color(c, PrimType::Float) => {
+                                color[c] = format.read_float()?
+                            }
+                            &Property::Color(c, ty) => {
+                                color[c] = format.read_int(ty)? as f32 / 255.0
+                            }
+                            Property::Radius => radius = format.read_float()?,
+                            _ => format.skip(prop.ty())?,
                         }
                     }
                     vertices.push(TriVertex {
@@ -185,21 +253,29 @@ pub fn load_plymesh<R: BufRead>(
                             .as_vec3(),
                         v: data.v,
                     });
+                    if has_color {
+                        colors.push(color);
+                    }
+                    if has_radius {
+                        radii.push(radius);
+                    }
                 }
             }
             "face" => {
+                has_faces |= element.count > 0;
                 for _ in 0..element.count {
                     for prop in &element.properties {
                         match prop {
                             &Property::Indices(count_ty, elem_ty) => {
-                                let count = format.read_int(count_ty);
-                                let idx: Vec<_> =
-                                    (0..count).map(|_| format.read_int(elem_ty)).collect();
+                                let count = format.read_int(count_ty)?;
+                                let idx = (0..count)
+                                    .map(|_| format.read_int(elem_ty))
+                                    .collect::<std::io::Result<Vec<_>>>()?;
                                 for i in 2..count as usize {
                                     indices.push([idx[0], idx[i - 1], idx[i]]);
                                 }
                             }
-                            _ => format.skip(prop.ty()),
+                            _ => format.skip(prop.ty())?,
                         }
                     }
                 }
@@ -208,23 +284,57 @@ pub fn load_plymesh<R: BufRead>(
                 println!("Unrecognized ply element {s}");
                 for _ in 0..element.count {
                     for prop in &element.properties {
-                        format.skip(prop.ty());
+                        format.skip(prop.ty())?;
                     }
                 }
             }
         }
     }
 
-    scene.add_triangles(&vertices, &indices)
+    if has_faces || vertices.is_empty() {
+        return Ok(PlyMesh::Triangles { vertices, indices });
+    }
+
+    // radii scale with the cube root of the volume scale of the transform
+    let scale = DMat3::from_mat4(transform).determinant().abs().cbrt() as f32;
+    let radius = radius.map(|r| r * scale).unwrap_or_else(|| {
+        // assume the points cover a surface about the size of their bounds
+        let bounds = Bounds::from_points(vertices.iter().map(|v| v.p));
+        let r = (bounds.max - bounds.min).length() / (vertices.len() as f32).sqrt();
+        if radii.is_empty() {
+            println!("Note: point cloud has no radii; using {r:.3e}");
+        }
+        r
+    });
+
+    let splats: Vec<_> = vertices
+        .iter()
+        .enumerate()
+        .map(|(i, v)| {
+            let color = colors.get(i).copied().unwrap_or(Vec3::ONE);
+            let srgb = color.clamp(Vec3::ZERO, Vec3::ONE) * 255.0;
+            Splat {
+                p: v.p,
+                radius: radii.get(i).map_or(radius, |&r| r * scale),
+                n: v.n,
+                color: u32::from_le_bytes([srgb.x as u8, srgb.y as u8, srgb.z as u8, 255]),
+            }
+        })
+        .collect();
+
+    Ok(PlyMesh::Splats {
+        splats,
+        colored: !colors.is_empty(),
+    })
 }
 
-fn prim_type(name: &str) -> PrimType {
-    match name {
+fn prim_type(name: &str) -> anyhow::Result<PrimType> {
+    Ok(match name {
         "float" => PrimType::Float,
         "uint8" | "uchar" => PrimType::Byte,
         "int" | "uint" => PrimType::Int,
-        _ => panic!("Unrecognized ply type: {name}"),
-    }
+        _ => bail!("unrecognized ply type {name}"),
+    })
 }
 
 impl Property {
@@ -237,7 +347,9 @@ impl Property {
             | Property::NormalY
             | Property::NormalZ
             | Property::U
-            | Property::V => Type::Prim(PrimType::Float),
+            | Property::V
+            | Property::Radius => Type::Prim(PrimType::Float),
+            Property::Color(_, ty) => Type::Prim(*ty),
             Property::Indices(count, elem) => Type::List(*count, *elem),
             Property::Unknown(ty) => *ty,
         }
@@ -245,79 +357,80 @@ impl Property {
 }
 
 trait FormatReader {
-    fn read_float(&mut self) -> f32;
-    fn read_u8(&mut self) -> u8;
-    fn read_u32(&mut self) -> u32;
+    fn read_float(&mut self) -> std::io::Result<f32>;
+    fn read_u8(&mut self) -> std::io::Result<u8>;
+    fn read_u32(&mut self) -> std::io::Result<u32>;
 
-    fn read_int(&mut self, ty: PrimType) -> u32 {
+    fn read_int(&mut self, ty: PrimType) -> std::io::Result<u32> {
         match ty {
-            PrimType::Float => self.read_float() as u32,
-            PrimType::Byte => self.read_u8() as u32,
+            PrimType::Float => Ok(self.read_float()? as u32),
+            PrimType::Byte => Ok(self.read_u8()? as u32),
             PrimType::Int => self.read_u32(),
         }
     }
 
-    fn skip(&mut self, ty: Type) {
+    fn skip(&mut self, ty: Type) -> std::io::Result<()> {
         match ty {
             Type::Prim(PrimType::Byte) => {
-                self.read_u8();
+                self.read_u8()?;
             }
             Type::Prim(PrimType::Int) => {
-                self.read_u32();
+                self.read_u32()?;
             }
             Type::Prim(PrimType::Float) => {
-                self.read_float();
+                self.read_float()?;
             }
             Type::List(count_ty, elem_ty) => {
-                let count = self.read_int(count_ty);
+                let count = self.read_int(count_ty)?;
                 for _ in 0..count {
-                    self.skip(Type::Prim(elem_ty));
+                    self.skip(Type::Prim(elem_ty))?;
                 }
             }
         }
+        Ok(())
     }
 }
 
 struct BinaryLeFormat<R>(R);
 
 impl<R: BufRead> FormatReader for BinaryLeFormat<R> {
-    fn read_float(&mut self) -> f32 {
+    fn read_float(&mut self) -> std::io::Result<f32> {
         let mut buf = [0; 4];
-        self.0.read_exact(&mut buf).unwrap();
-        f32::from_le_bytes(buf)
+        self.0.read_exact(&mut buf)?;
+        Ok(f32::from_le_bytes(buf))
     }
 
-    fn read_u8(&mut self) -> u8 {
+    fn read_u8(&mut self) -> std::io::Result<u8> {
         let mut buf = [0; 1];
-        self.0.read_exact(&mut buf).unwrap();
-        buf[0]
+        self.0.read_exact(&mut buf)?;
+        Ok(buf[0])
     }
 
-    fn read_u32(&mut self) -> u32 {
+    fn read_u32(&mut self) -> std::io::Result<u32> {
         let mut buf = [0; 4];
-        self.0.read_exact(&mut buf).unwrap();
-        u32::from_le_bytes(buf)
+        self.0.read_exact(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
     }
 }
 
 struct BinaryBeFormat<R>(R);
 
 impl<R: BufRead> FormatReader for BinaryBeFormat<R> {
-    fn read_float(&mut self) -> f32 {
+    fn read_float(&mut self) -> std::io::Result<f32> {
         let mut buf = [0; 4];
-        self.0.read_exact(&mut buf).unwrap();
-        f32::from_be_bytes(buf)
+        self.0.read_exact(&mut buf)?;
+        Ok(f32::from_be_bytes(buf))
     }
 
-    fn read_u8(&mut self) -> u8 {
+    fn read_u8(&mut self) -> std::io::Result<u8> {
         let mut buf = [0; 1];
-        self.0.read_exact(&mut buf).unwrap();
-        buf[0]
+        self.0.read_exact(&mut buf)?;
+        Ok(buf[0])
     }
 
-    fn read_u32(&mut self) -> u32 {
+    fn read_u32(&mut self) -> std::io::Result<u32> {
         let mut buf = [0; 4];
-        self.0.read_exact(&mut buf).unwrap();
-        u32::from_be_bytes(buf)
+        self.0.read_exact(&mut buf)?;
+        Ok(u32::from_be_bytes(buf))
     }
 }
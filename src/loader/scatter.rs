@@ -0,0 +1,106 @@
+use glam::{DMat4, DQuat, DVec2, DVec3};
+use image::{ImageBuffer, Luma};
+
+use crate::options::splitmix64;
+
+pub type ScatterMap = ImageBuffer<Luma<f32>, Vec<f32>>;
+
+// Placement of copies of an object over a triangle mesh, for grass, fur and similar. The maps are
+// looked up by the texture coordinates of the mesh.
+pub struct Scatter {
+    // number of candidate positions, which are kept with the probability given by the density
+    pub count: u32,
+    pub seed: u64,
+    // range of the random uniform scale
+    pub scale: [f64; 2],
+    // largest random rotation around the up axis, in radians
+    pub rotation: f64,
+    // orient the object's +z along the surface normal instead of the mesh's +z
+    pub align_to_normal: bool,
+    pub density_map: Option<ScatterMap>,
+    // multiplies the random scale
+    pub scale_map: Option<ScatterMap>,
+    // added to the random rotation, with 1 being a full turn
+    pub rotation_map: Option<ScatterMap>,
+}
+
+impl Scatter {
+    // transforms from the space of the object to the space of the mesh
+    pub fn place(&self, positions: &[DVec3], uvs: &[DVec2], tris: &[[u32; 3]]) -> Vec<DMat4> {
+        let mut cdf = Vec::with_capacity(tris.len());
+        let mut total = 0.0;
+        for tri in tris {
+            let [p0, p1, p2] = tri.map(|i| positions[i as usize]);
+            total += (p1 - p0).cross(p2 - p0).length() / 2.0;
+            cdf.push(total);
+        }
+        if total <= 0.0 {
+            return vec![];
+        }
+
+        let mut state = self.seed;
+        let mut next = || (splitmix64(&mut state) >> 11) as f64 / (1u64 << 53) as f64;
+
+        let mut placed = vec![];
+        for _ in 0..self.count {
+            let target = next() * total;
+            let tri = tris[cdf.partition_point(|&c| c < target).min(tris.len() - 1)];
+
+            // uniform point on the triangle
+            let su = next().sqrt();
+            let (b0, b1) = (1.0 - su, next() * su);
+            let b = DVec3::new(b0, b1, 1.0 - b0 - b1);
+            let [p0, p1, p2] = tri.map(|i| positions[i as usize]);
+            let p = b.x * p0 + b.y * p1 + b.z * p2;
+            // barycentrics stand in for missing texture coordinates
+            let uv = match uvs.is_empty() {
+                true => b.truncate(),
+                false => tri
+                    .map(|i| uvs[i as usize])
+                    .iter()
+                    .zip(b.to_array())
+                    .map(|(&uv, b)| uv * b)
+                    .sum(),
+            };
+
+            let density = self.density_map.as_ref().map_or(1.0, |m| lookup(m, uv));
+            if next() >= density {
+                continue;
+            }
+
+            let scale_range = self.scale[0] + (self.scale[1] - self.scale[0]) * next();
+            let scale = scale_range * self.scale_map.as_ref().map_or(1.0, |m| lookup(m, uv));
+            let turns = self.rotation_map.as_ref().map_or(0.0, |m| lookup(m, uv));
+            let angle = next() * self.rotation + turns * std::f64::consts::TAU;
+
+            let up = match self.align_to_normal {
+                true => (p1 - p0).cross(p2 - p0).normalize_or(DVec3::Z),
+                false => DVec3::Z,
+            };
+            let rotation = DQuat::from_rotation_arc(DVec3::Z, up) * DQuat::from_rotation_z(angle);
+            placed.push(DMat4::from_scale_rotation_translation(
+                DVec3::splat(scale),
+                rotation,
+                p,
+            ));
+        }
+        placed
+    }
+}
+
+// bilinear and wrapping, with v = 0 at the bottom of the image like image textures
+fn lookup(map: &ScatterMap, uv: DVec2) -> f64 {
+    let (w, h) = (map.width() as i64, map.height() as i64);
+    let x = uv.x * w as f64 - 0.5;
+    let y = (1.0 - uv.y) * h as f64 - 0.5;
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let texel = |dx: i64, dy: i64| {
+        let px = (x0 as i64 + dx).rem_euclid(w) as u32;
+        let py = (y0 as i64 + dy).rem_euclid(h) as u32;
+        map.get_pixel(px, py).0[0] as f64
+    };
+    let top = texel(0, 0) + (texel(1, 0) - texel(0, 0)) * tx;
+    let bottom = texel(0, 1) + (texel(1, 1) - texel(0, 1)) * tx;
+    top + (bottom - top) * ty
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{Context, Result, bail, ensure};
+
+// Reader for the tensor file format used by the RGL material database (.bsdf files)
+
+pub struct TensorField {
+    pub shape: Vec<usize>,
+    pub data: Vec<f32>,
+}
+
+pub fn load_tensor_file(path: &Path) -> Result<HashMap<String, TensorField>> {
+    let bytes = std::fs::read(path)?;
+    let mut r = Reader {
+        bytes: &bytes,
+        pos: 0,
+    };
+
+    ensure!(r.take(12)? == b"tensor_file\0", "not a tensor file");
+    let version = r.take(2)?;
+    ensure!(
+        version == [1, 0],
+        "unsupported tensor file version {}.{}",
+        version[0],
+        version[1]
+    );
+
+    let count = r.u32()?;
+    let mut fields = HashMap::new();
+    for _ in 0..count {
+        let name_len = r.u16()? as usize;
+        let name = String::from_utf8_lossy(r.take(name_len)?).into_owned();
+        let ndim = r.u16()? as usize;
+        let dtype = r.take(1)?[0];
+        let offset = r.u64()? as usize;
+        let shape = (0..ndim)
+            .map(|_| r.u64().map(|v| v as usize))
+            .collect::<Result<Vec<_>>>()?;
+
+        let elem_size = match dtype {
+            1 | 2 => 1,
+            3 | 4 | 9 => 2,
+            5 | 6 | 10 => 4,
+            7 | 8 | 11 => 8,
+            _ => bail!("field {name} has invalid type {dtype}"),
+        };
+        // sizes come straight from the file, so a bogus one must not wrap around into range
+        let end = shape
+            .iter()
+            .try_fold(elem_size, |size: usize, &dim| size.checked_mul(dim))
+            .and_then(|size| offset.checked_add(size));
+        let raw = end
+            .and_then(|end| bytes.get(offset..end))
+            .with_context(|| format!("field {name} out of bounds"))?;
+
+        let data = raw
+            .chunks_exact(elem_size)
+            .map(|b| match dtype {
+                1 => b[0] as f32,
+                2 => b[0] as i8 as f32,
+                3 => u16::from_le_bytes([b[0], b[1]]) as f32,
+                4 => i16::from_le_bytes([b[0], b[1]]) as f32,
+                5 => u32::from_le_bytes(b.try_into().unwrap()) as f32,
+                6 => i32::from_le_bytes(b.try_into().unwrap()) as f32,
+                7 => u64::from_le_bytes(b.try_into().unwrap()) as f32,
+                8 => i64::from_le_bytes(b.try_into().unwrap()) as f32,
+                9 => half_to_f32(u16::from_le_bytes([b[0], b[1]])),
+                10 => f32::from_le_bytes(b.try_into().unwrap()),
+                _ => f64::from_le_bytes(b.try_into().unwrap()) as f32,
+            })
+            .collect();
+
+        fields.insert(name, TensorField { shape, data });
+    }
+
+    Ok(fields)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        let data = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .context("unexpected end of tensor file")?;
+        self.pos += n;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+fn half_to_f32(h: u16) -> f32 {
+    let sign = ((h >> 15) as u32) << 31;
+    let exp = ((h >> 10) & 0x1f) as u32;
+    let mantissa = (h & 0x3ff) as u32;
+    match exp {
+        0 => (mantissa as f32 * 2f32.powi(-24)).copysign(f32::from_bits(sign | 0x3f800000)),
+        0x1f => f32::from_bits(sign | 0x7f800000 | (mantissa << 13)),
+        _ => f32::from_bits(sign | ((exp + 127 - 15) << 23) | (mantissa << 13)),
+    }
+}
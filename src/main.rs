@@ -1,122 +1,735 @@
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
-use std::path::PathBuf;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex, OnceLock};
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime};
 
+use anyhow::Context;
 use bytemuck::{AnyBitPattern, NoUninit, Pod, Zeroable};
-use clap::Parser;
 use clap::builder::{StringValueParser, TypedValueParser};
-use glam::{Mat3, Mat4, Vec2, Vec3, Vec4, Vec4Swizzles};
-use image::{Rgb, RgbImage, Rgba32FImage};
+use clap::{Parser, ValueEnum};
+use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, WritableImage};
+use glam::{DMat4, Mat3, Mat4, Quat, Vec2, Vec3, Vec4, Vec4Swizzles};
+use image::{GrayImage, Luma, Rgb, RgbImage, Rgba32FImage};
 use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
 use wgpu::PollType;
 use wgpu::util::DeviceExt;
 
-use crate::scene::Scene;
-
+use crate::filter::{Filter, FilterTable, FilterType};
+use crate::gpu_features::GpuFeatures;
+use crate::guide_file::GuideData;
+use crate::lens::Lens;
+use crate::loader::diagnostics::{Diagnostics, Severity};
+use crate::loader::pbrt::LoadedScene;
+use crate::metadata::Metadata;
+use crate::options::{
+    Accumulation, Aov, Axis, BvhQuality, EnvironmentOverride, FloatAtomics, LensMode,
+    MaterialOverride, Metering, Preset, RenderOptions, Roi, SamplerType, SceneConvention,
+    splitmix64,
+};
+use crate::render_stats::RenderStats;
+use crate::response::{Response, ResponseCurve};
+use crate::scene::{GpuScene, NodeId, Scene, TableSampler1d};
+use crate::scene_cache::{BvhCache, SceneCache};
+
+mod animation;
+mod atomics;
+mod blue_noise;
+mod compare;
+mod control;
+mod convergence;
+mod cryptomatte;
+mod dashboard;
+mod denoise;
+mod examples;
+mod film_file;
+mod filter;
+mod furnace;
+mod gpu_features;
+mod guide_file;
+mod heatmap;
+mod lens;
 mod loader;
+mod metadata;
 mod options;
+mod overlay;
+mod pick;
+mod plot;
+mod preview;
+mod render_stats;
+mod response;
+mod sample_dump;
 mod scene;
+mod scene_cache;
 mod shader;
+mod signals;
 mod spectrum;
 
 #[derive(Parser)]
+#[clap(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Options {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(short = 'W', long)]
     width: Option<u32>,
     #[clap(short = 'H', long)]
     height: Option<u32>,
 
+    #[clap(long)]
+    resolution_scale: Option<f32>,
+
+    // extra pixels rendered on each side of the frame
+    #[clap(long, default_value = "0")]
+    overscan: u32,
+
     #[clap(short, long)]
     samples: Option<u32>,
     #[clap(short, long, value_parser = StringValueParser::new().try_map(parse_time))]
     time: Option<Duration>,
+    // with --time, start the sample that would end past the limit and finish it, rather than
+    // stopping before it
+    #[clap(long)]
+    finish_sample: bool,
+    // stop once the average relative error of the pixels falls below this, such as 0.01
+    #[clap(long)]
+    target_error: Option<f64>,
+    // samples left running on the GPU while the next is submitted, waiting only once more are
+    // queued. More keep the GPU busy between samples, fewer make pausing and restarts respond
+    // sooner.
+    #[clap(long, default_value = "2")]
+    in_flight: u32,
+    // samples dispatched together in each submission, for small or fast renders where the cost of
+    // submitting each sample on its own and waiting on it dominates
+    #[clap(long, default_value = "1")]
+    samples_per_pass: u32,
+    // splits each pass into bands of rows submitted separately, sized to take about this long on
+    // the GPU, so that huge renders don't run into the driver's watchdog, such as 100ms. Each tile
+    // is timed by waiting on the one before it, so at most two tiles are queued at once and
+    // --in-flight has no effect.
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_time))]
+    tile_time: Option<Duration>,
 
-    #[clap(long, default_value = "simple")]
-    integrator: String,
+    #[clap(long)]
+    integrator: Option<String>,
+    // overrides the scene file's sampler
+    #[clap(long, value_enum)]
+    sampler: Option<SamplerType>,
+    // longest path, overriding the scene file and the integrator's default
+    #[clap(long)]
+    max_depth: Option<u32>,
+    // most bounces of each kind a path may take, on top of --max-depth, so that glass can be
+    // followed deep without paying for as many diffuse bounces. Materials with both diffuse and
+    // glossy lobes count as glossy.
+    #[clap(long)]
+    max_diffuse_depth: Option<u32>,
+    #[clap(long)]
+    max_glossy_depth: Option<u32>,
+    #[clap(long)]
+    max_specular_depth: Option<u32>,
+    // only render light scattered at most once, including emitters seen directly, or only light
+    // scattered more than once; the two add up to the full image
+    #[clap(long, conflicts_with = "indirect_only")]
+    direct_only: bool,
+    #[clap(long)]
+    indirect_only: bool,
+    // pixel reconstruction filter with pbrt's default parameters, overriding the scene file's
+    #[clap(long, value_enum)]
+    filter: Option<FilterType>,
+    #[clap(long)]
+    filter_radius: Option<f32>,
 
-    #[clap(long, default_value = "1")]
-    scale: f32,
+    // write the guided integrator's trained structures to a file at the end of the render, or
+    // load them to skip training
+    #[clap(long)]
+    guide_save: Option<PathBuf>,
+    #[clap(long)]
+    guide_load: Option<PathBuf>,
+    // chance of the guided integrator sampling the BSDF rather than the guide, 0.5 by default
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_bsdf_fraction))]
+    guide_bsdf_fraction: Option<f32>,
+    // adjust the BSDF fraction for each region of the scene while training the guide
+    #[clap(long)]
+    guide_learn_fraction: bool,
+    // adjoint-driven russian roulette and splitting: end paths expected to contribute little to
+    // the pixel under the guide's radiance estimates, and split those expected to contribute a lot
+    #[clap(long)]
+    guide_adrrs: bool,
+
+    // write every sample's pixel, filter offset, XYZ radiance and path length to a file, for
+    // trying reconstruction and denoising methods outside the renderer
+    #[clap(long)]
+    sample_dump: Option<PathBuf>,
+    // write the elapsed time, average relative variance, efficiency and samples per pixel after
+    // every sample to a CSV file, for plotting how integrators converge
+    #[clap(long)]
+    log_convergence: Option<PathBuf>,
+    // write the mean, variance and sample count of each pixel when the render finishes or is
+    // saved, for combining renders of different sample ranges with `merge`
+    #[clap(long)]
+    save_film: Option<PathBuf>,
+
+    #[clap(long, value_enum)]
+    preset: Option<Preset>,
+
+    // render a wavelength band in nanometers as grayscale instead of visible light as color
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_range))]
+    wavelengths: Option<[f32; 2]>,
+
+    // `diffuse`, `diffuse:<albedo>`, `uv-checker` or `wireframe` in place of every material
+    #[clap(long, value_parser = StringValueParser::new().try_map(MaterialOverride::parse))]
+    override_material: Option<MaterialOverride>,
+
+    // TOML file of named materials to define differently than the scene does, as tables of pbrt
+    // parameters keyed by material name
+    #[clap(long)]
+    material_replace: Option<PathBuf>,
+
+    // TOML file of `scale` and `enabled` settings for lights, keyed by the light's `string name`
+    // parameter or the index of its LightSource or AreaLightSource directive in the scene
+    #[clap(long)]
+    light_override: Option<PathBuf>,
+
+    // axis the scene's geometry was modeled with pointing up, turned to point up for the camera
+    #[clap(long, value_enum)]
+    up_axis: Option<Axis>,
+    // factor scaling the scene's geometry, such as 0.01 for a scene modeled in centimeters
+    #[clap(long)]
+    scene_scale: Option<f64>,
+    // make the winding of triangle meshes consistent and flip vertex normals that point away from
+    // their faces, for downloaded meshes that render with black patches
+    #[clap(long)]
+    repair_orientation: bool,
+    // merge vertices of triangle meshes with the same position, normal and texture coordinates,
+    // which shrinks meshes exported with a separate copy of each vertex for every face
+    #[clap(long)]
+    weld_vertices: bool,
+    // white furnace audit: replace the lights with a uniform white environment and every color of
+    // every material with this albedo, then report the pixels gaining or losing energy. Paths are
+    // followed without a depth limit or russian roulette.
+    #[clap(long, num_args = 0..=1, default_missing_value = "1")]
+    furnace: Option<f32>,
+    // fail when files the scene refers to can't be loaded, rather than rendering without them
+    #[clap(long)]
+    strict: bool,
+    // `high` adds spatial splits to the BVHs, which helps scenes with long thin triangles render
+    // faster but takes longer to build
+    #[clap(long, value_enum, default_value = "fast")]
+    bvh_quality: BvhQuality,
+    // directory of fully built scenes, reused while the scene's files and loader options are
+    // unchanged
+    #[clap(long)]
+    scene_cache: Option<PathBuf>,
+    // directory of the BVHs of large PLY meshes, reused while the mesh file is unchanged so that
+    // editing the rest of the scene doesn't rebuild them
+    #[clap(long)]
+    bvh_cache: Option<PathBuf>,
+
+    // largest width or height of textures, which are downscaled at load
+    #[clap(long)]
+    texture_max_res: Option<u32>,
+    // MiB of GPU memory for textures, including mips
+    #[clap(long)]
+    texture_budget: Option<f64>,
+    // flatten texture networks of materials with many scale and mix nodes into an image of this
+    // many texels on a side each, trading their detail for a single lookup when shading
+    #[clap(long)]
+    bake_textures: Option<u32>,
+    // BC7 for 8-bit and BC6H for HDR textures, cutting their memory 4-8x
+    #[clap(long)]
+    compress_textures: bool,
+    // pack triangle vertices into 16 bytes rather than 32 by rounding each mesh's positions to a
+    // 16-bit grid over it, normals to 32-bit octahedral ones and texture coordinates to half floats
+    #[clap(long)]
+    compress_geometry: bool,
+    // build triangle geometry in memory mapped temporary files, which the OS can page out, and
+    // upload it from there in chunks, for machines with little memory
+    #[clap(long)]
+    low_memory: bool,
+
+    // overrides the scene file's output, which defaults to img.png. EXR files are written as linear
+    // sRGB without the response curve.
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+    // `start..end`: render each frame to `<output>-<frame>`, moved by --turntable and
+    // --frame-translate, keeping the scene loaded between frames
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_frames))]
+    frames: Option<Range<u32>>,
+    // degrees per frame to orbit the camera about the center of the scene, around its up axis
+    #[clap(long, allow_hyphen_values = true)]
+    turntable: Option<f32>,
+    // `name=x,y,z`: move the instances of an object by this much per frame
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_frame_translate), allow_hyphen_values = true)]
+    frame_translate: Vec<(String, Vec3)>,
+
+    // also write `<output>-denoised` by running Open Image Denoise on the mean image, guided by
+    // the albedo and normal of the first surface hit. Needs the `denoise` feature.
+    #[clap(long)]
+    denoise: bool,
+    // filter the film on the GPU after every pass with an edge-avoiding à-trous wavelet, and write
+    // it to `<output>-preview` whenever the image is saved
+    #[clap(long)]
+    preview_denoise: bool,
+
+    // extra images written next to the output
+    #[clap(long, value_enum, value_delimiter = ',')]
+    aovs: Vec<Aov>,
+    // write false color images of the sample count and relative variance of each pixel to
+    // `<output>-samples.png` and `<output>-variance.png` whenever the image is saved
+    #[clap(long)]
+    debug_images: bool,
+
+    // `mean`, or `median-of-means` or `median-of-means:<k>` to take the median of k interleaved
+    // groups of samples in each pixel, suppressing fireflies without clamping
+    #[clap(
+        long,
+        value_parser = StringValueParser::new().try_map(Accumulation::parse),
+        default_value = "mean"
+    )]
+    accumulation: Accumulation,
+    // scale down samples brighter than this luminance, trading a little energy for fewer
+    // fireflies
+    #[clap(long)]
+    clamp: Option<f32>,
+
+    // overrides the scene file's scale, which defaults to 1
+    #[clap(long)]
+    scale: Option<f32>,
+    // pick the scale from the first samples so the metered region averages to middle gray, with
+    // --scale as exposure compensation
+    #[clap(long, value_enum)]
+    metering: Option<Metering>,
+    #[clap(long, default_value = "4")]
+    metering_samples: u32,
+
+    // `srgb`, `log`, `log:<strength>` or a LUT file with one or three values per line
+    #[clap(
+        long,
+        value_parser = StringValueParser::new().try_map(ResponseCurve::parse),
+        default_value = "srgb"
+    )]
+    response: ResponseCurve,
+    // standard deviation of film grain in the encoded image
+    #[clap(long, default_value = "0")]
+    grain: f32,
+    #[clap(long, default_value = "0")]
+    grain_seed: u64,
+
+    // radial lens distortion, positive for barrel and negative for pincushion
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    distortion: f32,
+    // difference in magnification between red (650nm) and blue (450nm) light
+    #[clap(long, default_value = "0", allow_negative_numbers = true)]
+    chromatic_aberration: f32,
+    #[clap(long, value_enum, default_value = "ray")]
+    lens_mode: LensMode,
+    // cos^4 falloff and clipping by the lens barrel
+    #[clap(long)]
+    vignetting: bool,
 
     #[clap(long, default_value = "0")]
     sample_offset: u32,
 
+    // `x0,y0,x1,y1,weight`: render `weight` times as many samples in a pixel rectangle
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_roi))]
+    roi: Option<Roi>,
+
+    // render reflection probes at these points instead of the camera view, written to
+    // probe<i>.exr as cube map strips of +X, -X, +Y, -Y, +Z, -Z faces
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_vec3), allow_hyphen_values = true)]
+    probe: Vec<Vec3>,
+    // width and height of each probe face
+    #[clap(long, default_value = "128")]
+    probe_size: u32,
+    // capture a single 90 degree view along this direction instead of a cube map
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_vec3), allow_hyphen_values = true)]
+    probe_direction: Option<Vec3>,
+
     #[clap(long)]
     scene_stats: bool,
+    // count the rays, BVH nodes, triangle tests, shadow rays and texture lookups of the render and
+    // print them once it's done, at the cost of slowing it down
+    #[clap(long)]
+    render_stats: bool,
 
-    scene: PathBuf,
+    #[clap(long, value_parser = StringValueParser::new().try_map(parse_backend))]
+    backend: Option<wgpu::Backends>,
+
+    #[clap(long)]
+    gpu_info: bool,
+    // how the guided integrator adds up the data it trains on, since some drivers implement
+    // float atomics poorly or not at all
+    #[clap(long, value_enum, default_value = "auto")]
+    float_atomics: FloatAtomics,
+
+    // show a multi-line progress display instead of the sample counter
+    #[clap(long)]
+    dashboard: bool,
+
+    // `-` for stdin, otherwise the path of a unix socket to listen on
+    #[clap(long)]
+    control: Option<String>,
+
+    // randomize the rotation and intensity of environment lights, drawn anew for each frame with
+    // --frames
+    #[clap(long)]
+    randomize_env: bool,
+    #[clap(long)]
+    env_seed: Option<u64>,
+    // degrees
+    #[clap(long, default_value = "360")]
+    env_rotation_max: f32,
+    #[clap(
+        long,
+        value_parser = StringValueParser::new().try_map(parse_range),
+        default_value = "0.5:2"
+    )]
+    env_intensity_range: [f32; 2],
+
+    #[clap(required = true)]
+    scene: Option<PathBuf>,
+}
+
+#[derive(clap::Subcommand)]
+enum Command {
+    // plot spectra as they are evaluated when rendering
+    Spectrum(plot::SpectrumOptions),
+    // check the RGB to spectrum coefficient table for colors it doesn't reproduce
+    RgbAudit(spectrum::RgbAuditOptions),
+    // write small scenes exercising each supported material, texture, light and shape
+    GenExamples(examples::ExampleOptions),
+    // report the error of a render against a reference image
+    Compare(compare::CompareOptions),
+    // combine the films of renders of disjoint sample ranges
+    Merge(film_file::MergeOptions),
 }
 
 fn main() -> anyhow::Result<()> {
-    let options = Options::parse();
+    let mut options = Options::parse();
 
     let spectrum_data = spectrum::load_data().unwrap();
 
-    let (mut render_options, scene) = loader::pbrt::load_pbrt_scene(&spectrum_data, &options.scene);
+    match &options.command {
+        Some(Command::Spectrum(spectrum_options)) => {
+            return plot::plot_spectra(spectrum_options, &spectrum_data);
+        }
+        Some(Command::RgbAudit(audit_options)) => {
+            return spectrum::audit_rgb_coeffs(audit_options, &spectrum_data);
+        }
+        Some(Command::GenExamples(example_options)) => {
+            return examples::generate_examples(example_options);
+        }
+        Some(Command::Compare(compare_options)) => {
+            return compare::compare_images(compare_options);
+        }
+        Some(Command::Merge(merge_options)) => {
+            return film_file::merge_films(merge_options);
+        }
+        None => {}
+    }
+    // required without a subcommand
+    let scene_path = options.scene.as_deref().unwrap();
+
+    let mut env_seed = None;
+    let mut environment = EnvironmentOverride::default();
+    if options.randomize_env {
+        let seed = options.env_seed.unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_nanos() as u64
+        });
+        let first_frame = options.frames.as_ref().map_or(0, |frames| frames.start);
+        environment = frame_environment(&options, seed, first_frame);
+        env_seed = Some(seed);
+        println!(
+            "Environment: seed {seed}, rotation {:.1} degrees, intensity {:.3}",
+            environment.rotation, environment.scale
+        );
+    }
+
+    let material_replacements = load_overrides(options.material_replace.as_deref())
+        .context("failed to load material replacements")?;
+    let light_overrides = load_overrides(options.light_override.as_deref())
+        .context("failed to load light overrides")?;
 
-    let mut time_limit = Duration::MAX;
-    if let Some(width) = options.width {
-        render_options.width = width;
+    check_options(&options)?;
+    let convention = SceneConvention {
+        up_axis: options.up_axis,
+        scale: options.scene_scale,
+    };
+    let scene_cache = match &options.scene_cache {
+        Some(dir) => {
+            let settings = format!(
+                "{environment:?} {:?} {convention:?} {} {} {} {:?} {:?} {material_replacements} {light_overrides}",
+                options.override_material,
+                options.repair_orientation,
+                options.weld_vertices,
+                options.compress_geometry,
+                options.furnace,
+                options.bvh_quality,
+            );
+            Some(SceneCache::new(dir, scene_path, &settings)?)
+        }
+        None => None,
+    };
+    let loaded = match scene_cache
+        .as_ref()
+        .and_then(|cache| cache.load(&spectrum_data))
+    {
+        Some(loaded) => loaded,
+        None => {
+            let loaded = loader::pbrt::load_pbrt_scene(
+                &spectrum_data,
+                scene_path,
+                environment,
+                options.override_material,
+                material_replacements,
+                light_overrides,
+                convention,
+                options.repair_orientation,
+                options.weld_vertices,
+                options.compress_geometry,
+                options.low_memory,
+                options.furnace,
+                options.bvh_quality,
+                options
+                    .bvh_cache
+                    .as_deref()
+                    .map(BvhCache::new)
+                    .transpose()?,
+                options.strict,
+            )?;
+            if let Some(Err(e)) = scene_cache.map(|cache| cache.save(&loaded)) {
+                println!("Warning: {e:#}");
+            }
+            loaded
+        }
+    };
+    let LoadedScene {
+        mut render_options,
+        mut scene,
+        diagnostics,
+        ..
+    } = loaded;
+    // ids follow the order of the scene file, so the id AOVs go by names instead
+    let id_names = [
+        cryptomatte::IdNames::objects(&scene),
+        cryptomatte::IdNames::materials(&scene),
+    ];
+
+    let preset = options.preset.map(Preset::settings).unwrap_or_default();
+    if let Some(samples) = preset.samples {
+        render_options.samples = samples;
+    }
+    options.preview_denoise |= preset.preview_denoise;
+
+    let resolution_scale = options.resolution_scale.unwrap_or(preset.resolution_scale);
+    set_resolution(&options, resolution_scale, &mut render_options);
+
+    let probe_faces = match options.probe_direction {
+        Some(_) => 1,
+        None => 6,
+    };
+    if !options.probe.is_empty() {
+        render_options.width = probe_faces * options.probe_size;
+        render_options.height = options.probe.len() as u32 * options.probe_size;
     }
-    if let Some(height) = options.height {
-        render_options.height = height;
+
+    // the overscan border is outside of the lens's image circle
+    let frame_diagonal =
+        Vec2::new(render_options.width as f32, render_options.height as f32).length();
+    if options.overscan > 0 && options.probe.is_empty() {
+        let size = Vec2::new(render_options.width as f32, render_options.height as f32);
+        let padded = size + 2.0 * options.overscan as f32;
+        let camera = &mut render_options.camera;
+        camera.ndc_to_camera = Transform::from_mat4(
+            camera.ndc_to_camera.m * Mat4::from_scale((padded / size).extend(1.0)),
+        );
+        render_options.width += 2 * options.overscan;
+        render_options.height += 2 * options.overscan;
+        println!(
+            "Overscan: frame is {}x{} at offset {},{}",
+            size.x, size.y, options.overscan, options.overscan
+        );
     }
+
+    let lens = Lens {
+        distortion: options.distortion,
+        chromatic_aberration: options.chromatic_aberration,
+        frame_diagonal,
+    };
+    render_options.camera.frame_diagonal = lens.frame_diagonal;
+    if options.lens_mode == LensMode::Ray {
+        render_options.camera.distortion = lens.distortion;
+        render_options.camera.chromatic_aberration = lens.chromatic_aberration;
+    }
+    render_options.camera.vignetting = options.vignetting as u32;
+
+    // the region is given in frame pixels, so shift it past the overscan border
+    let roi = options.roi.and_then(|mut roi| {
+        let size = [render_options.width, render_options.height];
+        for ((min, max), size) in roi.min.iter_mut().zip(&mut roi.max).zip(size) {
+            *min = (*min + options.overscan).min(size);
+            *max = (*max + options.overscan).min(size);
+        }
+        if roi.min[0] >= roi.max[0] || roi.min[1] >= roi.max[1] {
+            println!("Warning: region of interest is outside the image");
+            return None;
+        }
+        Some(roi)
+    });
+
+    let mut time_limit = Duration::MAX;
     if let Some(time) = options.time {
         render_options.samples = u32::MAX;
         time_limit = time;
     }
+    if options.target_error.is_some() {
+        render_options.samples = u32::MAX;
+    }
     if let Some(samples) = options.samples {
         render_options.samples = samples;
     }
 
+    let mut filter = options
+        .filter
+        .map(Filter::new)
+        .unwrap_or(render_options.filter);
+    if let Some(radius) = options.filter_radius {
+        filter.radius = Vec2::splat(radius);
+    }
+    let [filter_x, filter_y] = filter.tabulate(&mut scene);
+
+    if let Some(resolution) = options.bake_textures {
+        let baked = scene.bake_textures(resolution);
+        println!("Baked {baked} texture networks to {resolution}x{resolution} images");
+    }
+    let mut instance_desc = wgpu::InstanceDescriptor::default();
+    if let Some(backends) = options.backend {
+        instance_desc.backends = backends;
+    }
+    let instance = wgpu::Instance::new(&instance_desc);
+    let adapter = pollster::block_on(instance.request_adapter(&Default::default()))
+        .with_context(|| format!("no adapter for backends {:?}", instance_desc.backends))?;
+
+    let mut gpu_features =
+        GpuFeatures::negotiate(&adapter, options.float_atomics, options.compress_textures);
+    gpu_features.textures_compressed(gpu_features.compress_textures && scene.compress_textures());
+    scene.limit_texture_memory(
+        options.texture_max_res,
+        options
+            .texture_budget
+            .map(|mib| (mib * 1024.0 * 1024.0) as usize),
+    );
+    scene.collapse_bvh();
+    scene.pack_vertices().context("failed to pack vertices")?;
+    gpu_features.packed_vertices = scene.compress_geometry;
+    gpu_features.render_stats = options.render_stats;
+
     if options.scene_stats {
         scene.print_stats();
     }
 
-    let instance = wgpu::Instance::new(&Default::default());
-    let adapter = pollster::block_on(instance.request_adapter(&Default::default()))?;
+    if options.low_memory {
+        // scenes from the cache are loaded into memory first
+        scene
+            .spill_geometry()
+            .context("failed to move geometry to a temporary file")?;
+        println!(
+            "Keeping {} of geometry on disk",
+            scene::human_size(scene.spilled_size()).trim()
+        );
+    }
+
+    let required_features = gpu_features.required_features();
+    if options.gpu_info {
+        print_gpu_info(&adapter, required_features);
+    }
+
     let (device, queue) = pollster::block_on(adapter.request_device(&wgpu::DeviceDescriptor {
-        required_features: wgpu::Features::SHADER_INT64
-            | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES
-            | wgpu::Features::TEXTURE_BINDING_ARRAY
-            | wgpu::Features::SAMPLED_TEXTURE_AND_STORAGE_BUFFER_ARRAY_NON_UNIFORM_INDEXING
-            | wgpu::Features::FLOAT32_FILTERABLE
-            | wgpu::Features::SHADER_FLOAT32_ATOMIC
-            | wgpu::Features::CLEAR_TEXTURE
-            | wgpu::Features::IMMEDIATES,
-        required_limits: wgpu::Limits {
-            max_immediate_size: 64,
-            max_storage_buffer_binding_size: (2 << 30) - 4,
-            max_buffer_size: (2 << 30) - 4,
-            max_storage_buffers_per_shader_stage: 128,
-            max_binding_array_elements_per_shader_stage: 4096,
-            ..wgpu::Limits::default().using_resolution(adapter.limits())
-        },
+        required_features,
+        required_limits: gpu_features.required_limits(&adapter),
         ..Default::default()
-    }))?;
-
-    let mut extra_state = match options.integrator.as_str() {
-        "guided" => Box::new(GuidedState::new(
-            &device,
-            &scene,
-            options.scale,
-            render_options.samples,
-            time_limit,
-        )) as Box<dyn ExtraState>,
-        _ => Box::new(()),
+    }))
+    .with_context(|| {
+        let info = adapter.get_info();
+        format!(
+            "failed to create device on {} ({:?}); missing features: {:?} (run with --gpu-info for details)",
+            info.name,
+            info.backend,
+            required_features.difference(adapter.features()),
+        )
+    })?;
+
+    // the command line, then the preset, then the scene file
+    let integrator = options
+        .integrator
+        .clone()
+        .or(options.preset.map(|_| preset.integrator.to_owned()))
+        .or(render_options.integrator.clone())
+        .unwrap_or_else(|| preset.integrator.to_owned());
+    gpu_features.check_scene(&scene)?;
+    gpu_features.pick_float_atomics(&device, &queue, &integrator)?;
+    gpu_features.report(&adapter, &scene);
+    // the furnace audit follows every bounce, since a depth limit loses the energy of longer paths
+    let max_depth = match options.furnace {
+        Some(_) => Some(i32::MAX as u32),
+        None => options.max_depth.or(render_options.max_depth),
+    };
+    let max_bounces = [
+        options.max_diffuse_depth,
+        options.max_glossy_depth,
+        options.max_specular_depth,
+    ];
+    let output = options
+        .output
+        .clone()
+        .or(render_options.filename.clone())
+        .unwrap_or_else(|| PathBuf::from("img.png"));
+    let base_scale = options.scale.or(render_options.scale).unwrap_or(1.0);
+    // the preset's clamp is on the exposed image, so it clips the same part of every scene
+    options.clamp = options.clamp.or(preset.clamp.map(|clamp| clamp / base_scale));
+    let scale = base_scale;
+    let response = Response {
+        curve: options.response.clone(),
+        grain: options.grain,
+        grain_seed: options.grain_seed,
+        lens: (options.lens_mode == LensMode::Post
+            && (lens.distortion != 0.0 || lens.chromatic_aberration != 0.0))
+            .then_some(lens),
+    };
+    let wavelengths = options.wavelengths.or(preset.wavelengths);
+    let wavelength_table = match wavelengths {
+        Some(_) => None,
+        None => scene.add_wavelength_sampler(),
     };
+    if wavelength_table.is_some() {
+        println!("Sampling wavelengths according to the light spectra");
+    }
 
-    let flags = [
-        ("sampler".to_owned(), "independent".to_owned()),
-        ("camera".to_owned(), "projective".to_owned()),
-        ("integrator".to_owned(), options.integrator),
-    ]
-    .into_iter()
-    .collect();
-    let shader = shader::load_shader(&device, "entrypoint/megakernel.wgsl", &flags)?;
+    let control = options.control.as_deref().map(control::spawn).transpose()?;
+
+    // a single frame as the scene file has it without --frames
+    let frames = options.frames.clone().unwrap_or(0..1);
+    let animation = options.frames.as_ref().map(|_| {
+        let camera = render_options.camera;
+        animation::Animation::new(&scene, camera, options.turntable, &options.frame_translate)
+    });
+    if let Some(animation) = &animation {
+        animation.set_frame(frames.start, &mut scene, &mut render_options.camera);
+    }
+    let frame_output = |frame: u32| match animation {
+        Some(_) => suffixed_path(&output, &format!("{frame:04}")),
+        None => output.clone(),
+    };
 
     let scene_bg_layout = scene.make_bind_group_layout(&device);
-    let scene_bg = scene.make_bind_group(&device, &queue, &scene_bg_layout);
+    let mut gpu_scene = scene.upload(&device, &queue, &scene_bg_layout);
+    // clears the changes from moving to the first frame, which the upload already has
+    gpu_scene.update(&mut scene, &device, &queue, &scene_bg_layout);
 
     let film_desc = wgpu::TextureDescriptor {
         label: None,
@@ -134,10 +747,180 @@ fn main() -> anyhow::Result<()> {
     };
     let mean = device.create_texture(&film_desc);
     let variance = device.create_texture(&film_desc);
+    let aov = device.create_texture(&film_desc);
+    // a single texel stands in when groups aren't kept
+    let groups = options.accumulation.groups();
+    let max_groups = device.limits().max_texture_array_layers;
+    if groups > max_groups {
+        anyhow::bail!("median-of-means can keep at most {max_groups} groups on this device");
+    }
+    let group_texture = device.create_texture(&wgpu::TextureDescriptor {
+        size: match groups {
+            0 => wgpu::Extent3d::default(),
+            _ => wgpu::Extent3d {
+                depth_or_array_layers: groups,
+                ..film_desc.size
+            },
+        },
+        ..film_desc
+    });
+    let features = options.denoise
+        || options.preview_denoise
+        || options.aovs.iter().any(|aov| aov.uses_features());
+    let feature_texture = device.create_texture(&wgpu::TextureDescriptor {
+        size: match features {
+            false => wgpu::Extent3d::default(),
+            true => wgpu::Extent3d {
+                depth_or_array_layers: 2,
+                ..film_desc.size
+            },
+        },
+        ..film_desc
+    });
+    let mattes = options.aovs.contains(&Aov::Cryptomatte);
+    // objects then materials, with two ranks to a layer
+    let matte_texture = device.create_texture(&wgpu::TextureDescriptor {
+        size: match mattes {
+            false => wgpu::Extent3d::default(),
+            true => wgpu::Extent3d {
+                depth_or_array_layers: cryptomatte::RANKS as u32,
+                ..film_desc.size
+            },
+        },
+        ..film_desc
+    });
+
+    let sample_dump = match &options.sample_dump {
+        Some(path) => {
+            // the full frame and the most extra passes a sample takes over the region of
+            // interest, for each of the samples in a submission
+            let mut capacity = render_options.width as u64 * render_options.height as u64;
+            if let Some(roi) = roi {
+                let area = (roi.max[0] - roi.min[0]) as u64 * (roi.max[1] - roi.min[1]) as u64;
+                capacity += area * roi.passes_per_sample() as u64;
+            }
+            capacity *= options.samples_per_pass as u64;
+            println!(
+                "Dumping samples to {}, {} per submission",
+                path.display(),
+                scene::human_size(capacity as usize * sample_dump::RECORD_SIZE).trim()
+            );
+            Some(sample_dump::SampleDump::new(
+                &device,
+                path,
+                render_options.width,
+                render_options.height,
+                capacity,
+            )?)
+        }
+        None => None,
+    };
+    let convergence_log = match &options.log_convergence {
+        Some(path) => Some(convergence::ConvergenceLog::new(path)?),
+        None => None,
+    };
+    let no_sample_records;
+    let sample_records = match &sample_dump {
+        Some(dump) => &dump.buffer,
+        None => {
+            no_sample_records = sample_dump::SampleDump::make_buffer(&device, 1);
+            &no_sample_records
+        }
+    };
+
+    let render_stats = RenderStats::new(&device);
 
     let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
         label: None,
         contents: bytemuck::bytes_of(&render_options.camera),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    });
+
+    let mut probes = vec![options.probe_direction.unwrap_or(Vec3::ZERO).extend(0.0)];
+    probes.extend(options.probe.iter().map(|p| p.extend(1.0)));
+    if probes.len() == 1 {
+        probes.push(Vec4::ZERO);
+    }
+    let probe_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&probes),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+    let camera = match options.probe.is_empty() {
+        true => "projective",
+        false => "probe",
+    };
+    let sampler_type = options.sampler.unwrap_or(render_options.sampler);
+    let sampler = sampler_type.to_possible_value().unwrap();
+    let sampler = sampler.get_name();
+    let lighting = match (options.direct_only, options.indirect_only) {
+        (true, _) => "direct",
+        (_, true) => "indirect",
+        _ => "all",
+    };
+
+    // the strata are spread over the sample count, which is unknown for time limited renders
+    let strata = match render_options.samples {
+        u32::MAX => 1,
+        samples => samples,
+    };
+    let (tile_size, tile) = match sampler_type {
+        SamplerType::BlueNoise => {
+            let size = blue_noise::TILE_SIZE;
+            let tile = blue_noise::tile(size, 0);
+            // centers of the ranks as fractions in 32-bit fixed point
+            let n = (size * size) as u64;
+            let tile = tile
+                .iter()
+                .map(|&r| (((2 * r as u64 + 1) << 31) / n) as u32);
+            (size as u32, tile.collect())
+        }
+        _ => (1, vec![0]),
+    };
+    let radices = stratum_radices(strata);
+    let mut sampler_data = vec![strata, tile_size, radices.len() as u32];
+    sampler_data.extend(radices);
+    sampler_data.resize(3 + 32, 0);
+    sampler_data.extend(tile);
+    let sampler_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::cast_slice(&sampler_data),
+        usage: wgpu::BufferUsages::STORAGE,
+    });
+
+    let film_params = match wavelengths {
+        Some([min, max]) => FilmParams {
+            wavelength_min: min,
+            wavelength_max: max,
+            band: 1,
+            filter_x,
+            filter_y,
+            use_wavelength_table: 0,
+            wavelength_table: TableSampler1d::zeroed(),
+            record_samples: sample_dump.is_some() as u32,
+            groups,
+            features: features as u32,
+            mattes: mattes as u32,
+            clamp: options.clamp.unwrap_or(0.0),
+        },
+        None => FilmParams {
+            wavelength_min: 360.0,
+            wavelength_max: 830.0,
+            band: 0,
+            filter_x,
+            filter_y,
+            use_wavelength_table: wavelength_table.is_some() as u32,
+            wavelength_table: wavelength_table.unwrap_or(TableSampler1d::zeroed()),
+            record_samples: sample_dump.is_some() as u32,
+            groups,
+            features: features as u32,
+            mattes: mattes as u32,
+            clamp: options.clamp.unwrap_or(0.0),
+        },
+    };
+    let film_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: None,
+        contents: bytemuck::bytes_of(&film_params),
         usage: wgpu::BufferUsages::STORAGE,
     });
 
@@ -214,7 +997,52 @@ fn main() -> anyhow::Result<()> {
                 },
                 count: None,
             },
+            wgpu::BindGroupLayoutEntry {
+                binding: 2,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::ReadWrite,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 3,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::ReadWrite,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 4,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::ReadWrite,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                },
+                count: None,
+            },
+            wgpu::BindGroupLayoutEntry {
+                binding: 5,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::ReadWrite,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2Array,
+                },
+                count: None,
+            },
             storage_buffer_entry(16),
+            storage_buffer_entry(17),
+            storage_buffer_entry(18),
+            storage_buffer_entry(19),
+            writable_storage_buffer_entry(20),
+            writable_storage_buffer_entry(21),
             wgpu::BindGroupLayoutEntry {
                 binding: 24,
                 visibility: wgpu::ShaderStages::COMPUTE,
@@ -256,10 +1084,61 @@ fn main() -> anyhow::Result<()> {
                     &variance.create_view(&Default::default()),
                 ),
             },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::TextureView(&aov.create_view(&Default::default())),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&group_texture.create_view(
+                    &wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2Array),
+                        ..Default::default()
+                    },
+                )),
+            },
+            wgpu::BindGroupEntry {
+                binding: 4,
+                resource: wgpu::BindingResource::TextureView(&feature_texture.create_view(
+                    &wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2Array),
+                        ..Default::default()
+                    },
+                )),
+            },
+            wgpu::BindGroupEntry {
+                binding: 5,
+                resource: wgpu::BindingResource::TextureView(&matte_texture.create_view(
+                    &wgpu::TextureViewDescriptor {
+                        dimension: Some(wgpu::TextureViewDimension::D2Array),
+                        ..Default::default()
+                    },
+                )),
+            },
             wgpu::BindGroupEntry {
                 binding: 16,
                 resource: camera_buffer.as_entire_binding(),
             },
+            wgpu::BindGroupEntry {
+                binding: 17,
+                resource: film_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 18,
+                resource: probe_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 19,
+                resource: sampler_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 20,
+                resource: sample_records.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 21,
+                resource: render_stats.buffer.as_entire_binding(),
+            },
             wgpu::BindGroupEntry {
                 binding: 24,
                 resource: wgpu::BindingResource::Sampler(&linear_clamp_sampler),
@@ -277,105 +1156,1420 @@ fn main() -> anyhow::Result<()> {
         ],
     });
 
-    let mut bg_layouts = vec![&scene_bg_layout, &statics_bg_layout];
-    extra_state.add_bind_group_layouts(&mut bg_layouts);
+    let bg_layouts = [&scene_bg_layout, &statics_bg_layout];
 
-    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-        label: None,
-        bind_group_layouts: &bg_layouts,
-        immediate_size: 4,
-    });
+    let guide = match &options.guide_load {
+        Some(path) => {
+            let guide = GuideData::load(path)?;
+            let bounds = scene.node_bounds(scene.root.unwrap());
+            if guide.bounds != [bounds.min, bounds.max] {
+                anyhow::bail!("{} was trained on a different scene", path.display());
+            }
+            if integrator != "guided" {
+                println!("Warning: --guide-load has no effect with the {integrator} integrator");
+            }
+            if options.guide_learn_fraction {
+                println!("Warning: --guide-learn-fraction has no effect on a loaded guide");
+            }
+            Some(guide)
+        }
+        None => None,
+    };
+    let guide_options = GuideOptions {
+        bsdf_fraction: options.guide_bsdf_fraction,
+        learn_fraction: options.guide_learn_fraction,
+        adrrs: options.guide_adrrs,
+    };
+    if options.guide_adrrs && integrator != "guided" {
+        println!("Warning: --guide-adrrs has no effect with the {integrator} integrator");
+    }
 
-    drop(bg_layouts);
+    let mut extra_state = make_extra_state(
+        &integrator,
+        &device,
+        &scene,
+        scale,
+        &response,
+        &frame_output(frames.start),
+        render_options.samples,
+        time_limit,
+        guide.as_ref(),
+        guide_options,
+    );
+    let pipeline = make_pipeline(
+        &device,
+        &integrator,
+        sampler,
+        camera,
+        lighting,
+        options.furnace.is_none(),
+        &gpu_features,
+        &bg_layouts,
+        &mut *extra_state,
+    )?;
+
+    let picker = None;
+    let normals_overlay = None;
+    let show_normals = false;
+    let show_bounds: Option<(Vec<_>, u32)> = None;
+    let preview = match options.preview_denoise {
+        true => Some(preview::PreviewDenoiser::new(
+            &device,
+            &mean,
+            &variance,
+            &feature_texture,
+        )?),
+        false => None,
+    };
 
-    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
-        label: None,
-        layout: Some(&pipeline_layout),
-        module: &shader,
-        entry_point: None,
-        compilation_options: Default::default(),
-        cache: None,
+    let dashboard = options.dashboard.then(|| {
+        let film_size = film_desc.size.width as usize * film_desc.size.height as usize * 16;
+        let mut pixels = render_options.width as f64 * render_options.height as f64;
+        if let Some(roi) = roi {
+            let area = (roi.max[0] - roi.min[0]) as f64 * (roi.max[1] - roi.min[1]) as f64;
+            pixels += area * (roi.weight as f64 - 1.0);
+        }
+        dashboard::Dashboard::new(
+            gpu_scene.memory(&scene)
+                + (3 + groups as usize
+                    + 2 * features as usize
+                    + cryptomatte::RANKS * mattes as usize
+                    + 2 * preview.is_some() as usize)
+                    * film_size,
+            pixels as u64,
+        )
     });
 
-    let mut last = queue.submit([]);
+    signals::install();
+    let context = FrameContext {
+        options: &options,
+        scene_path,
+        env_seed,
+        diagnostics: &diagnostics,
+        id_names: &id_names,
+        device: &device,
+        queue: &queue,
+        gpu_features: &gpu_features,
+        scene_bg_layout: &scene_bg_layout,
+        bg_layouts,
+        statics_bg: &statics_bg,
+        camera_buffer: &camera_buffer,
+        mean: &mean,
+        variance: &variance,
+        aov: &aov,
+        groups,
+        group_texture: &group_texture,
+        feature_texture: &feature_texture,
+        matte_texture: &matte_texture,
+        render_stats: &render_stats,
+        preview: &preview,
+        control: &control,
+        animation: &animation,
+        first_frame: frames.start,
+        camera,
+        sampler,
+        lighting,
+        filter,
+        lens,
+        roi,
+        time_limit,
+        max_depth,
+        max_bounces,
+        base_scale,
+        response: &response,
+        wavelengths,
+        probe_faces,
+        guide: &guide,
+        guide_options,
+    };
+    let mut state = FrameState {
+        scene,
+        render_options,
+        gpu_scene,
+        integrator,
+        scale,
+        extra_state,
+        pipeline,
+        picker,
+        normals_overlay,
+        show_normals,
+        show_bounds,
+        dashboard,
+        sample_dump,
+        convergence_log,
+        // rows in each tile with --tile-time, starting small and adapting to the time they take
+        tile_rows: 16,
+        environment,
+    };
+    for frame in frames {
+        let stopped = render_frame(&context, &mut state, frame, &frame_output(frame))?;
+        if stopped {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Rejects option values that can't be rendered with, and warns about options that do nothing
+fn check_options(options: &Options) -> anyhow::Result<()> {
+    if options.denoise && !denoise::AVAILABLE {
+        anyhow::bail!("--denoise needs pbr-gpu to be built with the `denoise` feature");
+    }
+    if options
+        .target_error
+        .is_some_and(|error| !error.is_finite() || error <= 0.0)
+    {
+        anyhow::bail!("--target-error must be positive");
+    }
+    if options
+        .clamp
+        .is_some_and(|clamp| clamp.is_nan() || clamp <= 0.0)
+    {
+        anyhow::bail!("--clamp must be positive");
+    }
+    if options.in_flight == 0 {
+        anyhow::bail!("--in-flight must be at least 1");
+    }
+    if options.samples_per_pass == 0 {
+        anyhow::bail!("--samples-per-pass must be at least 1");
+    }
+    if options.finish_sample && options.time.is_none() {
+        println!("Warning: --finish-sample has no effect without --time");
+    }
+    if options.frames.is_some() {
+        if options.sample_dump.is_some() {
+            anyhow::bail!("--sample-dump can't be used with --frames");
+        }
+        if !options.probe.is_empty() {
+            anyhow::bail!("--probe can't be used with --frames");
+        }
+    } else if options.turntable.is_some() || !options.frame_translate.is_empty() {
+        println!("Warning: --turntable and --frame-translate have no effect without --frames");
+    }
+    if options
+        .scene_scale
+        .is_some_and(|scale| !scale.is_finite() || scale <= 0.0)
+    {
+        anyhow::bail!("--scene-scale must be positive");
+    }
+    if options
+        .furnace
+        .is_some_and(|albedo| !(albedo > 0.0 && albedo <= 1.0))
+    {
+        anyhow::bail!("--furnace albedo must be in (0, 1]");
+    }
+    Ok(())
+}
+
+// Sets the image size from the options and the scene's crop window, moving the camera frame to
+// match
+fn set_resolution(options: &Options, resolution_scale: f32, render_options: &mut RenderOptions) {
+    // the camera frame was set up for the scene's resolution, so keep its aspect ratio
+    // unless both dimensions are given explicitly
+    let aspect = render_options.width as f32 / render_options.height as f32;
+    match (options.width, options.height) {
+        (Some(width), Some(height)) => {
+            if (width as f32 / height as f32 - aspect).abs() > 0.01 {
+                println!(
+                    "Warning: {width}x{height} does not match the scene aspect ratio \
+                     {aspect:.3}; the image will be stretched"
+                );
+            }
+            render_options.width = width;
+            render_options.height = height;
+        }
+        (Some(width), None) => {
+            render_options.width = width;
+            render_options.height = (width as f32 / aspect).round().max(1.0) as u32;
+        }
+        (None, Some(height)) => {
+            render_options.width = (height as f32 * aspect).round().max(1.0) as u32;
+            render_options.height = height;
+        }
+        (None, None) => {
+            render_options.width = (render_options.width as f32 * resolution_scale)
+                .round()
+                .max(1.0) as u32;
+            render_options.height = (render_options.height as f32 * resolution_scale)
+                .round()
+                .max(1.0) as u32;
+        }
+    }
+
+    if let Some([x0, x1, y0, y1]) = render_options.crop_window
+        && options.probe.is_empty()
+    {
+        // pixels whose centers are inside the window, as in pbrt
+        let size = Vec2::new(render_options.width as f32, render_options.height as f32);
+        let min = (Vec2::new(x0, y0) * size).ceil();
+        let max = (Vec2::new(x1, y1) * size).ceil().max(min + 1.0).min(size);
+        let min = min.min(max - 1.0);
+        let crop = max - min;
+        let center = (min + max) / size - 1.0;
+        let camera = &mut render_options.camera;
+        camera.ndc_to_camera = Transform::from_mat4(
+            camera.ndc_to_camera.m
+                * Mat4::from_translation(Vec3::new(center.x, -center.y, 0.0))
+                * Mat4::from_scale((crop / size).extend(1.0)),
+        );
+        render_options.width = crop.x as u32;
+        render_options.height = crop.y as u32;
+        println!(
+            "Crop window: {}x{} at offset {},{} of {}x{}",
+            crop.x, crop.y, min.x, min.y, size.x, size.y
+        );
+    }
+}
+
+// What rendering a frame needs which stays the same for the whole run
+struct FrameContext<'a> {
+    options: &'a Options,
+    scene_path: &'a Path,
+    env_seed: Option<u64>,
+    diagnostics: &'a Diagnostics,
+    id_names: &'a [cryptomatte::IdNames; 2],
+    device: &'a wgpu::Device,
+    queue: &'a wgpu::Queue,
+    gpu_features: &'a GpuFeatures,
+    scene_bg_layout: &'a wgpu::BindGroupLayout,
+    bg_layouts: [&'a wgpu::BindGroupLayout; 2],
+    statics_bg: &'a wgpu::BindGroup,
+    camera_buffer: &'a wgpu::Buffer,
+    mean: &'a wgpu::Texture,
+    variance: &'a wgpu::Texture,
+    aov: &'a wgpu::Texture,
+    groups: u32,
+    group_texture: &'a wgpu::Texture,
+    feature_texture: &'a wgpu::Texture,
+    matte_texture: &'a wgpu::Texture,
+    render_stats: &'a RenderStats,
+    preview: &'a Option<preview::PreviewDenoiser>,
+    control: &'a Option<std::sync::mpsc::Receiver<control::Command>>,
+    animation: &'a Option<animation::Animation>,
+    first_frame: u32,
+    camera: &'a str,
+    sampler: &'a str,
+    lighting: &'a str,
+    filter: Filter,
+    lens: Lens,
+    roi: Option<Roi>,
+    time_limit: Duration,
+    max_depth: Option<u32>,
+    max_bounces: [Option<u32>; 3],
+    base_scale: f32,
+    response: &'a Response,
+    wavelengths: Option<[f32; 2]>,
+    probe_faces: u32,
+    guide: &'a Option<GuideData>,
+    guide_options: GuideOptions,
+}
+
+// What rendering a frame changes, carried over to the frames after it
+struct FrameState {
+    scene: Scene,
+    render_options: RenderOptions,
+    gpu_scene: GpuScene,
+    integrator: String,
+    scale: f32,
+    extra_state: Box<dyn ExtraState>,
+    pipeline: wgpu::ComputePipeline,
+    picker: Option<pick::Picker>,
+    normals_overlay: Option<overlay::NormalsOverlay>,
+    show_normals: bool,
+    show_bounds: Option<(Vec<NodeId>, u32)>,
+    dashboard: Option<dashboard::Dashboard>,
+    sample_dump: Option<sample_dump::SampleDump>,
+    convergence_log: Option<convergence::ConvergenceLog>,
+    tile_rows: u32,
+    // applied to the scene's environment lights
+    environment: EnvironmentOverride,
+}
+
+// The environment override of a frame with --randomize-env, seeded by the frame number so that
+// frames get different ones but rendering a frame again gets the same
+fn frame_environment(options: &Options, seed: u64, frame: u32) -> EnvironmentOverride {
+    EnvironmentOverride::random(
+        seed.wrapping_add(frame as u64),
+        options.env_rotation_max,
+        options.env_intensity_range,
+    )
+}
+
+// Renders, accumulates and outputs one frame to `output`. Returns whether rendering was stopped.
+fn render_frame(
+    context: &FrameContext,
+    state: &mut FrameState,
+    frame: u32,
+    output: &Path,
+) -> anyhow::Result<bool> {
+    let FrameContext {
+        options,
+        env_seed,
+        device,
+        queue,
+        scene_bg_layout,
+        camera_buffer,
+        animation,
+        first_frame,
+        ..
+    } = *context;
+
+    // each frame starts over with an empty film, like a restart
+    if let Some(animation) = animation
+        && frame != first_frame
+    {
+        animation.set_frame(frame, &mut state.scene, &mut state.render_options.camera);
+        if let Some(seed) = env_seed
+            && options.furnace.is_none()
+        {
+            let next = frame_environment(options, seed, frame);
+            state.scene.change_environment(state.environment, next);
+            state.environment = next;
+            println!(
+                "Environment: rotation {:.1} degrees, intensity {:.3}",
+                next.rotation, next.scale
+            );
+        }
+        let camera = &state.render_options.camera;
+        queue.write_buffer(camera_buffer, 0, bytemuck::bytes_of(camera));
+        state
+            .gpu_scene
+            .update(&mut state.scene, device, queue, scene_bg_layout);
+        restart_render(context, state, output)?;
+        if let Some(dashboard) = &mut state.dashboard {
+            dashboard.restart();
+        }
+    }
+    if animation.is_some() {
+        println!("\rFrame {frame}");
+    }
+    mark_partial(output, None)?;
+
+    let progress = accumulate_frame(context, state, output)?;
+    save_frame(context, state, frame, output, &progress)?;
+    Ok(progress.stopped)
+}
+
+// How far accumulating a frame got
+struct Progress {
+    num_samples: u32,
+    took: Duration,
+    // whether the exposure has been metered yet
+    metered: bool,
+    stopped: bool,
+}
+
+// Makes the integrator's state and pipeline again and clears the film, for starting the
+// accumulation over
+fn restart_render(
+    context: &FrameContext,
+    state: &mut FrameState,
+    output: &Path,
+) -> anyhow::Result<()> {
+    let FrameContext {
+        options,
+        device,
+        queue,
+        gpu_features,
+        bg_layouts,
+        mean,
+        variance,
+        render_stats,
+        camera,
+        sampler,
+        lighting,
+        time_limit,
+        response,
+        guide,
+        guide_options,
+        ..
+    } = *context;
 
-    let start = Instant::now();
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    state.extra_state = make_extra_state(
+        &state.integrator,
+        device,
+        &state.scene,
+        state.scale,
+        response,
+        output,
+        state.render_options.samples,
+        time_limit,
+        guide.as_ref(),
+        guide_options,
+    );
+    state.pipeline = make_pipeline(
+        device,
+        &state.integrator,
+        sampler,
+        camera,
+        lighting,
+        options.furnace.is_none(),
+        gpu_features,
+        &bg_layouts,
+        &mut *state.extra_state,
+    )?;
+
+    let mut encoder = device.create_command_encoder(&Default::default());
+    encoder.clear_texture(mean, &Default::default());
+    encoder.clear_texture(variance, &Default::default());
+    render_stats.clear(&mut encoder);
+    queue.submit([encoder.finish()]);
+    Ok(())
+}
+
+// Renders samples into the film until the frame is done, the time limit is reached or rendering
+// is stopped, following commands from the control socket in between
+fn accumulate_frame(
+    context: &FrameContext,
+    state: &mut FrameState,
+    output: &Path,
+) -> anyhow::Result<Progress> {
+    let FrameContext {
+        options,
+        device,
+        queue,
+        gpu_features,
+        scene_bg_layout,
+        bg_layouts,
+        statics_bg,
+        mean,
+        variance,
+        control,
+        camera,
+        sampler,
+        roi,
+        time_limit,
+        base_scale,
+        ..
+    } = *context;
+    let mut stopped = false;
+
+    let mut start = Instant::now();
     let mut num_samples = 0;
+    let mut roi_credit = 0.0;
+    let mut metered = options.metering.is_none();
+    let mut paused_at = None;
+    let mut last_error_check = Instant::now();
+
+    // completion of the last sample to finish and a running average of the time between
+    // completions, for telling whether another sample fits in the time limit. Timing starts once
+    // the first submission finishes, since that one also waits for setup.
+    let mut last_done: Option<Instant> = None;
+    let mut sample_time: Option<Duration> = None;
+    // submissions which may still be running and their numbers of samples, oldest first
+    let mut in_flight = VecDeque::new();
+    let mut tiles = TileTiming {
+        queued: None,
+        done: start,
+    };
+
+    let mut i = options.sample_offset;
+    while i < state.render_options.samples {
+        let mut restart = false;
+        let mut stop = false;
+        let commands = control.iter().flat_map(|c| c.try_iter());
+        for command in commands.chain(signals::pending()) {
+            let scene = &mut state.scene;
+            match command {
+                control::Command::SetExposure(v) => state.scale = v,
+                control::Command::SetIntegrator(name) => {
+                    if !INTEGRATORS.contains(&name.as_str()) {
+                        println!("\rWarning: Unknown integrator {name}");
+                        continue;
+                    }
+                    state.integrator = name;
+                    restart = true;
+                }
+                control::Command::Restart => restart = true,
+                control::Command::Save => {
+                    save_checkpoint(context, state, output, num_samples, start.elapsed())?;
+                    mark_partial(output, Some(i))?;
+                    println!("\rSaved {} at sample {i}", output.display());
+                }
+                control::Command::Stop => stop = true,
+                control::Command::Pause => {
+                    if paused_at.is_none() {
+                        paused_at = Some(Instant::now());
+                        println!("\rPaused at sample {i}");
+                    }
+                }
+                control::Command::Resume => {
+                    // the time limit only counts time spent rendering
+                    if let Some(paused) = paused_at.take() {
+                        start += paused.elapsed();
+                        if let Some(last_done) = &mut last_done {
+                            *last_done += paused.elapsed();
+                        }
+                        println!("\rResumed at sample {i}");
+                    }
+                }
+                control::Command::ReplaceMaterial(old, new) => {
+                    let materials = &scene.named_materials;
+                    let (Some(&old_id), Some(&new_id)) = (materials.get(&old), materials.get(&new))
+                    else {
+                        println!("\rWarning: Unknown material {old} or {new}");
+                        continue;
+                    };
+                    let count = scene.replace_material(old_id, new_id);
+                    println!("\rReplaced material {old} with {new} on {count} primitives");
+                }
+                control::Command::TranslateObject(name, offset) => {
+                    let Some(instances) = scene.named_instances.get(&name).cloned() else {
+                        println!("\rWarning: No instances of object {name}");
+                        continue;
+                    };
+                    let moves: Vec<_> = instances
+                        .into_iter()
+                        .map(|instance| {
+                            let transform = scene.instance_transform(instance);
+                            (instance, DMat4::from_translation(offset) * transform)
+                        })
+                        .collect();
+                    scene.set_instance_transforms(&moves);
+                }
+                control::Command::Pick(x, y) => {
+                    let render_options = &state.render_options;
+                    if x >= render_options.width || y >= render_options.height {
+                        println!("\rWarning: Pixel {x},{y} is outside the image");
+                        continue;
+                    }
+                    if state.picker.is_none() {
+                        state.picker = Some(pick::Picker::new(
+                            device,
+                            sampler,
+                            camera,
+                            gpu_features,
+                            &bg_layouts,
+                        )?);
+                    }
+                    let bind_groups = [&state.gpu_scene.bind_group, statics_bg];
+                    let picker = state.picker.as_ref().unwrap();
+                    picker.pick(device, queue, &bind_groups, scene, [x, y]);
+                }
+                control::Command::ShowNormals(show) => state.show_normals = show,
+                control::Command::ShowBounds(None) => state.show_bounds = None,
+                control::Command::ShowBounds(Some((name, depth))) => {
+                    let roots = match name.as_str() {
+                        "scene" => scene.root.into_iter().collect(),
+                        _ => match scene.named_instances.get(&name) {
+                            Some(instances) => instances.clone(),
+                            None => {
+                                println!("\rWarning: No instances of object {name}");
+                                continue;
+                            }
+                        },
+                    };
+                    state.show_bounds = Some((roots, depth));
+                }
+                control::Command::SetTexture(name, path) => {
+                    let Some(&image) = scene.named_images.get(&name) else {
+                        println!("\rWarning: Unknown image texture {name}");
+                        continue;
+                    };
+                    if let Err(e) = scene.replace_image(image, &path) {
+                        println!("\rWarning: Could not load image {}: {e}", path.display());
+                    }
+                }
+            }
+        }
+
+        // accumulated samples are stale once the scene changes
+        if state
+            .gpu_scene
+            .update(&mut state.scene, device, queue, scene_bg_layout)
+        {
+            restart = true;
+        }
+
+        if stop {
+            if let Some(paused) = paused_at.take() {
+                start += paused.elapsed();
+            }
+            stopped = true;
+            break;
+        }
+
+        if restart {
+            restart_render(context, state, output)?;
+            in_flight.clear();
+            tiles.queued = None;
+
+            i = options.sample_offset;
+            num_samples = 0;
+            roi_credit = 0.0;
+            metered = options.metering.is_none();
+            start = Instant::now();
+            last_error_check = start;
+            last_done = None;
+            sample_time = None;
+            if paused_at.is_some() {
+                paused_at = Some(start);
+            }
+            if let Some(dashboard) = &mut state.dashboard {
+                dashboard.restart();
+            }
+            if let Some(dump) = &state.sample_dump {
+                dump.restart();
+            }
+            println!("\rRestarted with {} integrator", state.integrator);
+        }
+
+        if paused_at.is_some() {
+            std::thread::sleep(Duration::from_millis(50));
+            continue;
+        }
 
-    for i in options.sample_offset..render_options.samples {
         let time = start.elapsed();
-        if start.elapsed() >= time_limit {
+        if time >= time_limit {
+            break;
+        }
+        // the passes in flight have been running since the last one finished, and the next
+        // one can only start once they are done
+        if let Some(sample_time) = sample_time
+            && let Some(last_done) = last_done
+            && !options.finish_sample
+        {
+            let queued = sample_time * in_flight.len() as u32;
+            let remaining = queued.saturating_sub(last_done.elapsed());
+            if time + remaining + sample_time > time_limit {
+                println!("\rStopped at sample {i}, as another would not finish in time");
+                break;
+            }
+        }
+
+        let roi_limit = roi.map_or(u32::MAX, Roi::sample_limit);
+        if i >= roi_limit {
+            println!("\rStopped at sample {i}, the most the region of interest can number");
             break;
         }
 
-        num_samples += 1;
+        let pass_samples = options
+            .samples_per_pass
+            .min(state.render_options.samples - i)
+            .min(roi_limit - i);
+        num_samples += pass_samples;
+
+        state
+            .extra_state
+            .before_sample(i, time, device, queue, mean, variance)?;
+
+        // extra passes over the region of interest each sample gets
+        let roi_passes: Vec<u32> = (i..i + pass_samples)
+            .map(|_| {
+                let Some(roi) = roi else { return 0 };
+                roi_credit += roi.weight - 1.0;
+                let passes = roi_credit.floor();
+                roi_credit -= passes;
+                passes as u32
+            })
+            .collect();
+
+        let submission = submit_pass(context, state, &mut tiles, i, &roi_passes);
+        in_flight.push_back((submission, pass_samples));
 
-        extra_state.before_sample(i, time, &device, &queue, &mean, &variance);
+        while in_flight.len() > options.in_flight as usize {
+            let (oldest, _) = in_flight.pop_front().unwrap();
+            device
+                .poll(PollType::Wait {
+                    submission_index: Some(oldest),
+                    timeout: None,
+                })
+                .unwrap();
+
+            let now = Instant::now();
+            if let Some(last_done) = last_done {
+                let took = now - last_done;
+                sample_time = Some(match sample_time {
+                    Some(t) => t.mul_f32(0.8) + took.mul_f32(0.2),
+                    None => took,
+                });
+            }
+            last_done = Some(now);
+        }
+        i += pass_samples;
+        match &mut state.dashboard {
+            Some(dashboard) => {
+                if dashboard.wants_stats() {
+                    let stats = collect_stats(device, queue, mean, variance, start.elapsed());
+                    dashboard.add_variance(stats.avg_rel_variance);
+                }
+                // the samples in flight are still running
+                let running: u32 = in_flight.iter().map(|&(_, samples)| samples).sum();
+                dashboard.draw(
+                    num_samples - running,
+                    state.render_options.samples - options.sample_offset,
+                    start.elapsed(),
+                    time_limit,
+                );
+            }
+            None => {
+                eprint!("\r{}         ", i);
+                std::io::stderr().flush().unwrap();
+            }
+        }
+
+        if let Some(log) = &mut state.convergence_log {
+            device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+            let time = start.elapsed();
+            let logging = Instant::now();
+            let stats = collect_stats(device, queue, mean, variance, time);
+            log.add(&state.integrator, num_samples, time, &stats)?;
+            // downloading the film after every sample is slow and not part of the render
+            start += logging.elapsed();
+        }
+
+        if !metered && num_samples >= options.metering_samples {
+            let stats = collect_stats(device, queue, mean, variance, start.elapsed());
+            let metering = options.metering.unwrap();
+            if let Some(s) = meter_exposure(&stats.mean_image, metering, options.overscan) {
+                state.scale = base_scale * s;
+            }
+            metered = true;
+        }
+
+        // the film has to be downloaded to estimate the error, so only check every so often
+        if let Some(target) = options.target_error
+            && num_samples >= TARGET_ERROR_MIN_SAMPLES
+            && last_error_check.elapsed() >= TARGET_ERROR_INTERVAL
+        {
+            last_error_check = Instant::now();
+            let stats = collect_stats(device, queue, mean, variance, start.elapsed());
+            if let Some(dashboard) = &mut state.dashboard {
+                dashboard.add_variance(stats.avg_rel_variance);
+            }
+            if stats.avg_rel_error.sqrt() <= target {
+                println!("\rReached relative error {target} after {num_samples} samples");
+                break;
+            }
+        }
+    }
+    eprintln!();
+
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+
+    Ok(Progress {
+        num_samples,
+        took: start.elapsed(),
+        metered,
+        stopped,
+    })
+}
+
+// With --tile-time, the last tile submitted and its rows, and when the one before it finished
+struct TileTiming {
+    queued: Option<(wgpu::SubmissionIndex, u32)>,
+    done: Instant,
+}
+
+// Dispatches the samples from `first` on, with `roi_passes` extra passes over the region of
+// interest for each. Without --tile-time the whole frame is one tile, and otherwise the rows of
+// a tile follow what fit in the time before. Returns the submission of the last tile.
+fn submit_pass(
+    context: &FrameContext,
+    state: &mut FrameState,
+    tiles: &mut TileTiming,
+    first: u32,
+    roi_passes: &[u32],
+) -> wgpu::SubmissionIndex {
+    let FrameContext {
+        options,
+        device,
+        queue,
+        statics_bg,
+        preview,
+        roi,
+        max_depth,
+        max_bounces,
+        ..
+    } = *context;
+    let FrameState {
+        render_options,
+        gpu_scene,
+        extra_state,
+        pipeline,
+        sample_dump,
+        tile_rows,
+        ..
+    } = state;
+
+    let mut row = 0;
+    loop {
+        let rows = match options.tile_time {
+            Some(_) => (*tile_rows).min(render_options.height - row),
+            None => render_options.height,
+        };
+        let (min, max) = ([0, row], [render_options.width, row + rows]);
 
         let mut encoder = device.create_command_encoder(&Default::default());
+        if row == 0
+            && let Some(dump) = &sample_dump
+        {
+            dump.begin(&mut encoder);
+        }
 
         {
             let mut pass = encoder.begin_compute_pass(&Default::default());
 
-            pass.set_pipeline(&pipeline);
-            pass.set_bind_group(0, &scene_bg, &[]);
-            pass.set_bind_group(1, &statics_bg, &[]);
-            pass.set_immediates(0, bytemuck::bytes_of(&i));
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, &gpu_scene.bind_group, &[]);
+            pass.set_bind_group(1, statics_bg, &[]);
 
             extra_state.setup_pass(&mut pass);
 
-            pass.dispatch_workgroups(
-                (render_options.width + 7) / 8,
-                (render_options.height + 3) / 4,
-                1,
-            );
+            for (sample, &extra) in (first..).zip(roi_passes) {
+                let imm = Immediates {
+                    sample_number: sample,
+                    min,
+                    max,
+                    max_depth: max_depth.unwrap_or(u32::MAX),
+                    max_bounces: max_bounces.map(|b| b.unwrap_or(u32::MAX)),
+                };
+                pass.set_immediates(0, bytemuck::bytes_of(&imm));
+                pass.dispatch_workgroups(render_options.width.div_ceil(8), rows.div_ceil(4), 1);
+
+                let Some(roi) = roi else { continue };
+                // the part of the region of interest in this tile
+                let roi_min = [roi.min[0], roi.min[1].max(min[1])];
+                let roi_max = [roi.max[0], roi.max[1].min(max[1])];
+                if roi_min[1] >= roi_max[1] {
+                    continue;
+                }
+                // extra passes get their own sample numbers so they don't repeat the full
+                // frame passes
+                let per_pass = roi.passes_per_sample();
+                for k in 0..extra {
+                    let imm = Immediates {
+                        sample_number: 1 << 31 | (sample * per_pass + k),
+                        min: roi_min,
+                        max: roi_max,
+                        max_depth: max_depth.unwrap_or(u32::MAX),
+                        max_bounces: max_bounces.map(|b| b.unwrap_or(u32::MAX)),
+                    };
+                    pass.set_immediates(0, bytemuck::bytes_of(&imm));
+                    pass.dispatch_workgroups(
+                        (roi_max[0] - roi_min[0]).div_ceil(8),
+                        (roi_max[1] - roi_min[1]).div_ceil(4),
+                        1,
+                    );
+                }
+            }
+        }
+        row += rows;
+        let last_tile = row == render_options.height;
+        if last_tile && let Some(preview) = preview {
+            preview.run(&mut encoder);
+        }
+        if last_tile && let Some(dump) = &sample_dump {
+            dump.download(device, &mut encoder);
+        }
+        let submission = queue.submit([encoder.finish()]);
+
+        if let Some(budget) = options.tile_time {
+            // the previous tile ran from when the one before it finished, keeping a tile
+            // queued behind it so the GPU isn't left idle while measuring
+            match tiles.queued.replace((submission.clone(), rows)) {
+                Some((previous, previous_rows)) => {
+                    device
+                        .poll(PollType::Wait {
+                            submission_index: Some(previous),
+                            timeout: None,
+                        })
+                        .unwrap();
+                    let now = Instant::now();
+                    let row_time = (now - tiles.done).as_secs_f64() / previous_rows as f64;
+                    tiles.done = now;
+                    // at most doubling, in case the tile measured was unusually cheap
+                    let fit = (budget.as_secs_f64() / row_time) as u32;
+                    *tile_rows = (fit.min(2 * *tile_rows) / 4 * 4).max(4);
+                }
+                None => tiles.done = Instant::now(),
+            }
         }
 
-        let new = queue.submit([encoder.finish()]);
-        device
-            .poll(PollType::Wait {
-                submission_index: Some(last),
-                timeout: None,
-            })
-            .unwrap();
-
-        last = new;
-        eprint!("\r{}         ", i + 1);
-        std::io::stderr().flush().unwrap();
+        if last_tile {
+            return submission;
+        }
     }
-    eprintln!();
+}
 
-    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+// Writes the image so far and what goes with it, for the save command
+fn save_checkpoint(
+    context: &FrameContext,
+    state: &mut FrameState,
+    output: &Path,
+    num_samples: u32,
+    elapsed: Duration,
+) -> anyhow::Result<()> {
+    let FrameContext {
+        options,
+        device,
+        queue,
+        gpu_features,
+        bg_layouts,
+        statics_bg,
+        mean,
+        variance,
+        groups,
+        group_texture,
+        preview,
+        camera,
+        sampler,
+        response,
+        ..
+    } = *context;
+    let scale = state.scale;
+
+    let mut stats = collect_stats(device, queue, mean, variance, elapsed);
+    if groups != 0 {
+        median_of_means(device, queue, group_texture, &mut stats.mean_image);
+    }
+    save_image(&stats.mean_image, scale, response, output)?;
+    if state.integrator == "debug-bvh" {
+        heatmap::save_bvh_heatmap(&stats.mean_image, output)?;
+    }
+    if let Some(preview) = preview {
+        let path = suffixed_path(output, "preview");
+        save_image(&preview.download(device, queue), scale, response, &path)?;
+    }
+    if state.show_normals || state.show_bounds.is_some() {
+        let mut img = xyz_to_srgb(&stats.mean_image, scale, response);
+        if state.show_normals {
+            if state.normals_overlay.is_none() {
+                state.normals_overlay = Some(overlay::NormalsOverlay::new(
+                    device,
+                    sampler,
+                    camera,
+                    gpu_features,
+                    &bg_layouts,
+                    [state.render_options.width, state.render_options.height],
+                )?);
+            }
+            let bind_groups = [&state.gpu_scene.bind_group, statics_bg];
+            let normals_overlay = state.normals_overlay.as_ref().unwrap();
+            normals_overlay.draw(device, queue, &bind_groups, &mut img);
+        }
+        if let Some((roots, depth)) = &state.show_bounds {
+            let camera = &state.render_options.camera;
+            overlay::draw_bounds(&mut img, &state.scene, camera, roots, *depth);
+        }
+        // always a png, since the overlay has been tone mapped
+        let path = suffixed_path(output, "overlay").with_extension("png");
+        write_atomic(&path, |tmp| img.save(tmp).map_err(anyhow::Error::from))?;
+    }
+    if options.debug_images {
+        heatmap::save_debug_images(device, queue, mean, variance, output)?;
+    }
+    if let Some(path) = &options.save_film {
+        let film = (device, queue, mean, variance);
+        save_film(film, options, num_samples, elapsed, scale, path)?;
+    }
+    Ok(())
+}
 
-    let took = start.elapsed();
+// Writes the finished frame, everything asked to go with it and its metadata, then removes the
+// partial marker
+fn save_frame(
+    context: &FrameContext,
+    state: &mut FrameState,
+    frame: u32,
+    output: &Path,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let FrameContext {
+        options,
+        id_names,
+        device,
+        queue,
+        mean,
+        variance,
+        aov,
+        groups,
+        group_texture,
+        feature_texture,
+        matte_texture,
+        render_stats,
+        preview,
+        base_scale,
+        response,
+        probe_faces,
+        ..
+    } = *context;
+    let &Progress {
+        num_samples,
+        took,
+        metered,
+        ..
+    } = progress;
 
     if std::env::var_os("MESA_VK_TRACE_PER_SUBMIT").is_some() {
         std::thread::sleep(Duration::from_secs(1));
     }
 
-    let stats = collect_stats(&device, &queue, &mean, &variance, took);
+    let mut stats = collect_stats(device, queue, mean, variance, took);
+    if groups != 0 {
+        median_of_means(device, queue, group_texture, &mut stats.mean_image);
+    }
 
-    println!(
-        "Took {:.2} seconds ({:.3?} / sample)",
-        took.as_secs_f64(),
-        took / num_samples,
-    );
+    // the render was shorter than the metering prepass
+    if !metered {
+        let metering = options.metering.unwrap();
+        if let Some(s) = meter_exposure(&stats.mean_image, metering, options.overscan) {
+            state.scale = base_scale * s;
+        }
+    }
+    let scale = state.scale;
+
+    match num_samples {
+        // stopped before the first sample
+        0 => println!("Took {:.2} seconds", took.as_secs_f64()),
+        _ => println!(
+            "Took {:.2} seconds ({:.3?} / sample)",
+            took.as_secs_f64(),
+            took / num_samples,
+        ),
+    }
     println!("Average relative variance: {}", stats.avg_rel_variance);
     println!("Average relative error: {}", stats.avg_rel_error.sqrt());
     println!("Efficiency: {}", stats.efficiency);
+    if options.render_stats {
+        RenderStats::print(render_stats.download(device, queue), num_samples);
+    }
 
-    xyz_to_srgb(&stats.mean_image, options.scale)
-        .save("img.png")
-        .unwrap();
+    save_image(&stats.mean_image, scale, response, output)?;
+    if state.integrator == "debug-bvh" {
+        heatmap::save_bvh_heatmap(&stats.mean_image, output)?;
+    }
+
+    if let Some(preview) = preview {
+        let path = suffixed_path(output, "preview");
+        save_image(&preview.download(device, queue), scale, response, &path)?;
+    }
+
+    if options.denoise {
+        let started = Instant::now();
+        let denoised = denoise::denoise(device, queue, &stats.mean_image, feature_texture)?;
+        let path = suffixed_path(output, "denoised");
+        save_image(&denoised, scale, response, &path)?;
+        println!(
+            "Saved denoised image to {} in {:.2} seconds",
+            path.display(),
+            started.elapsed().as_secs_f64()
+        );
+    }
+
+    if !options.aovs.is_empty() {
+        let textures = [aov, feature_texture, matte_texture];
+        save_aovs(device, queue, textures, id_names, &options.aovs)?;
+    }
+    if options.debug_images {
+        heatmap::save_debug_images(device, queue, mean, variance, output)?;
+    }
+    if let Some(albedo) = options.furnace {
+        let textures = [mean, variance, aov];
+        furnace::audit(device, queue, textures, albedo, output)?;
+    }
+    if let Some(path) = &options.save_film {
+        let film = (device, queue, mean, variance);
+        save_film(film, options, num_samples, took, scale, path)?;
+        println!("Saved film to {}", path.display());
+    }
+
+    let sample_records = match state.sample_dump.take() {
+        Some(dump) => {
+            let records = dump.finish()?;
+            println!(
+                "Saved {records} samples to {}",
+                options.sample_dump.as_ref().unwrap().display()
+            );
+            Some(records)
+        }
+        None => None,
+    };
+
+    if let Some(path) = &options.guide_save {
+        match state.extra_state.guide_data(device, queue) {
+            Some(guide) => {
+                guide.save(path)?;
+                println!("Saved guiding structures to {}", path.display());
+            }
+            None => println!(
+                "Warning: --guide-save has no effect with the {} integrator",
+                state.integrator
+            ),
+        }
+    }
+
+    for i in 0..options.probe.len() as u32 {
+        let path = format!("probe{i}.exr");
+        let size = options.probe_size;
+        let width = (probe_faces * size) as usize;
+        let xyz_to_rgb = xyz_to_linear_srgb() * scale;
+        write_atomic(Path::new(&path), |tmp| {
+            exr::prelude::write_rgb_file(tmp, width, size as usize, |x, y| {
+                let xyz = stats.mean_image.get_pixel(x as u32, i * size + y as u32).0;
+                let rgb = xyz_to_rgb * Vec3::from_slice(&xyz[..3]);
+                (rgb.x, rgb.y, rgb.z)
+            })
+            .map_err(anyhow::Error::from)
+        })
+        .with_context(|| format!("failed to write {path}"))?;
+    }
+    if !options.probe.is_empty() {
+        println!("Saved {} probes", options.probe.len());
+    }
 
+    let metadata = frame_metadata(context, state, frame, output, progress, sample_records);
+    let metadata_path = output.with_extension("json");
+    write_atomic(&metadata_path, |tmp| Ok(metadata.write(tmp)?))
+        .with_context(|| format!("failed to write {}", metadata_path.display()))?;
+    std::fs::remove_file(partial_path(output))
+        .with_context(|| format!("failed to remove {}", partial_path(output).display()))?;
     Ok(())
 }
 
+// What a finished frame was rendered from and with, written next to it
+fn frame_metadata(
+    context: &FrameContext,
+    state: &FrameState,
+    frame: u32,
+    output: &Path,
+    progress: &Progress,
+    sample_records: Option<u64>,
+) -> Metadata {
+    let FrameContext {
+        options,
+        scene_path,
+        env_seed,
+        diagnostics,
+        animation,
+        sampler,
+        lighting,
+        filter,
+        lens,
+        roi,
+        max_depth,
+        max_bounces,
+        response,
+        wavelengths,
+        guide_options,
+        ..
+    } = *context;
+    let FrameState {
+        render_options,
+        integrator,
+        scale,
+        environment,
+        ..
+    } = state;
+
+    let mut metadata = Metadata::default();
+    metadata.string("scene", &scene_path.display().to_string());
+    if animation.is_some() {
+        metadata.number("frame", frame);
+    }
+    metadata.number("width", render_options.width);
+    metadata.number("height", render_options.height);
+    metadata.number("samples", progress.num_samples);
+    metadata.string("integrator", integrator);
+    if lighting != "all" {
+        metadata.string("lighting", lighting);
+    }
+    metadata.string("sampler", sampler);
+    metadata.string("filter", filter.name());
+    if let Some(max_depth) = max_depth {
+        metadata.number("max_depth", max_depth);
+    }
+    let bounce_keys = [
+        "max_diffuse_depth",
+        "max_glossy_depth",
+        "max_specular_depth",
+    ];
+    for (key, bounces) in bounce_keys.into_iter().zip(max_bounces) {
+        if let Some(bounces) = bounces {
+            metadata.number(key, bounces);
+        }
+    }
+    metadata.number("scale", *scale);
+    if let Some(metering) = options.metering {
+        let name = metering.to_possible_value().unwrap();
+        metadata.string("metering", name.get_name());
+    }
+    metadata.string("response", &response.curve.name());
+    if options.accumulation != Accumulation::Mean {
+        metadata.string("accumulation", &options.accumulation.name());
+    }
+    if lens.distortion != 0.0 || lens.chromatic_aberration != 0.0 {
+        metadata.number("distortion", lens.distortion);
+        metadata.number("chromatic_aberration", lens.chromatic_aberration);
+    }
+    if response.grain > 0.0 {
+        metadata.number("grain", response.grain);
+        metadata.string("grain_seed", &response.grain_seed.to_string());
+    }
+    metadata.number("seconds", progress.took.as_secs_f64());
+    // renders of scenes that didn't fully load shouldn't be mistaken for the real thing
+    let loader_errors = diagnostics.count(Severity::Error);
+    if loader_errors > 0 {
+        metadata.number("loader_errors", loader_errors as f64);
+    }
+    if let Some(roi) = roi {
+        metadata.number("roi_weight", roi.weight);
+    }
+    if !options.probe.is_empty() {
+        metadata.number("probes", options.probe.len() as u32);
+        metadata.number("probe_size", options.probe_size);
+    }
+    if integrator == "guided" {
+        if let Some(fraction) = guide_options.bsdf_fraction {
+            metadata.number("guide_bsdf_fraction", fraction);
+        }
+        if guide_options.learn_fraction {
+            metadata.string("guide_fraction", "learned");
+        }
+        if guide_options.adrrs {
+            metadata.string("guide_adrrs", "on");
+        }
+    }
+    if let Some(records) = sample_records {
+        metadata.string(
+            "sample_dump",
+            &options.sample_dump.as_ref().unwrap().display().to_string(),
+        );
+        metadata.number("sample_records", records as f64);
+    }
+    if options.denoise {
+        metadata.string(
+            "denoised",
+            &suffixed_path(output, "denoised").display().to_string(),
+        );
+    }
+    if let Some(error) = options.target_error {
+        metadata.number("target_error", error);
+    }
+    if let Some(axis) = options.up_axis {
+        metadata.string("up_axis", axis.name());
+    }
+    if let Some(scale) = options.scene_scale {
+        metadata.number("scene_scale", scale);
+    }
+    if let Some([min, max]) = wavelengths {
+        metadata.number("wavelength_min", min);
+        metadata.number("wavelength_max", max);
+    }
+    if let Some(seed) = env_seed {
+        metadata.string("env_seed", &seed.to_string());
+        metadata.number("env_rotation", environment.rotation);
+        metadata.number("env_intensity", environment.scale);
+    }
+    metadata
+}
+
+const INTEGRATORS: &[&str] = &[
+    "randomwalk",
+    "simple",
+    "guided",
+    "debug-primitive",
+    "debug-object",
+    "debug-instance",
+    "debug-bvh",
+];
+
+#[allow(clippy::too_many_arguments)]
+fn make_extra_state(
+    integrator: &str,
+    device: &wgpu::Device,
+    scene: &Scene,
+    scale: f32,
+    response: &Response,
+    output: &Path,
+    samples: u32,
+    time_limit: Duration,
+    guide: Option<&GuideData>,
+    guide_options: GuideOptions,
+) -> Box<dyn ExtraState> {
+    match integrator {
+        "guided" => Box::new(GuidedState::new(
+            device,
+            scene,
+            scale,
+            response,
+            output,
+            samples,
+            time_limit,
+            guide,
+            guide_options,
+        )),
+        _ => Box::new(()),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn make_pipeline(
+    device: &wgpu::Device,
+    integrator: &str,
+    sampler: &str,
+    camera: &str,
+    lighting: &str,
+    // off for the furnace audit, where ending paths early would hide energy gained or lost
+    roulette: bool,
+    gpu_features: &GpuFeatures,
+    bg_layouts: &[&wgpu::BindGroupLayout],
+    extra_state: &mut dyn ExtraState,
+) -> anyhow::Result<wgpu::ComputePipeline> {
+    let roulette = match roulette {
+        true => "on",
+        false => "off",
+    };
+    let flags = [
+        ("sampler".to_owned(), sampler.to_owned()),
+        ("camera".to_owned(), camera.to_owned()),
+        ("integrator".to_owned(), integrator.to_owned()),
+        ("lighting".to_owned(), lighting.to_owned()),
+        ("roulette".to_owned(), roulette.to_owned()),
+    ]
+    .into_iter()
+    .chain(gpu_features.shader_flags())
+    .collect();
+    let shader = shader::load_shader(device, "entrypoint/megakernel.wgsl", &flags)?;
+
+    let mut bg_layouts = bg_layouts.to_vec();
+    extra_state.add_bind_group_layouts(&mut bg_layouts);
+
+    let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: None,
+        bind_group_layouts: &bg_layouts,
+        immediate_size: size_of::<Immediates>() as u32,
+    });
+
+    let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+        label: None,
+        layout: Some(&pipeline_layout),
+        module: &shader,
+        entry_point: None,
+        compilation_options: Default::default(),
+        cache: None,
+    });
+
+    Ok(pipeline)
+}
+
 fn download_texture(
     device: &wgpu::Device,
     encoder: &mut wgpu::CommandEncoder,
     texture: &wgpu::Texture,
     downloaded: impl FnOnce(Vec<Vec4>) + Send + 'static,
+) {
+    download_texture_layer(device, encoder, texture, 0, downloaded);
+}
+
+fn download_texture_layer(
+    device: &wgpu::Device,
+    encoder: &mut wgpu::CommandEncoder,
+    texture: &wgpu::Texture,
+    layer: u32,
+    downloaded: impl FnOnce(Vec<Vec4>) + Send + 'static,
 ) {
     let bytes_per_row = (texture.width() * 16).next_multiple_of(256);
 
@@ -387,7 +2581,13 @@ fn download_texture(
     });
 
     encoder.copy_texture_to_buffer(
-        texture.as_image_copy(),
+        wgpu::TexelCopyTextureInfo {
+            origin: wgpu::Origin3d {
+                z: layer,
+                ..Default::default()
+            },
+            ..texture.as_image_copy()
+        },
         wgpu::TexelCopyBufferInfo {
             buffer: &buffer,
             layout: wgpu::TexelCopyBufferLayout {
@@ -396,7 +2596,10 @@ fn download_texture(
                 rows_per_image: None,
             },
         },
-        texture.size(),
+        wgpu::Extent3d {
+            depth_or_array_layers: 1,
+            ..texture.size()
+        },
     );
 
     let buf = buffer.clone();
@@ -437,6 +2640,32 @@ fn download_buffer(
     });
 }
 
+// Prime factors of the sample count, smallest first so the stratified sampler's ordering refines
+// in small steps. Square counts are stratified on a grid, so the factors of the side come in pairs
+// for the two axes.
+fn stratum_radices(samples: u32) -> Vec<u32> {
+    let factor = |mut n: u32| {
+        let mut factors = vec![];
+        let mut p = 2;
+        while p <= n / p {
+            while n.is_multiple_of(p) {
+                factors.push(p);
+                n /= p;
+            }
+            p += 1;
+        }
+        if n > 1 {
+            factors.push(n);
+        }
+        factors
+    };
+    let side = samples.isqrt();
+    match side * side == samples {
+        true => factor(side).into_iter().flat_map(|p| [p, p]).collect(),
+        false => factor(samples),
+    }
+}
+
 fn storage_buffer_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
     wgpu::BindGroupLayoutEntry {
         binding,
@@ -463,7 +2692,7 @@ fn writable_storage_buffer_entry(binding: u32) -> wgpu::BindGroupLayoutEntry {
     }
 }
 
-#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 struct ProjectiveCamera {
     ndc_to_camera: Transform,
@@ -471,11 +2700,50 @@ struct ProjectiveCamera {
     lens_radius: f32,
     focal_distance: f32,
     orthographic: u32,
-    _padding: u32,
+    distortion: f32,
+    chromatic_aberration: f32,
+    vignetting: u32,
+    // whether the camera moves while the shutter is open, following `motion` from camera to world
+    moving: u32,
+    // pixels from corner to corner of the frame, which distortion is relative to
+    frame_diagonal: f32,
+    motion: AnimatedTransform,
 }
 
 #[derive(Copy, Clone, Debug, Zeroable, Pod)]
 #[repr(C)]
+struct Immediates {
+    sample_number: u32,
+    // pixel rectangle covered by the dispatch
+    min: [u32; 2],
+    max: [u32; 2],
+    // u32::MAX for the integrator's default
+    max_depth: u32,
+    // diffuse, glossy and specular, u32::MAX for no limit
+    max_bounces: [u32; 3],
+}
+
+#[derive(Copy, Clone, Debug, NoUninit)]
+#[repr(C)]
+struct FilmParams {
+    wavelength_min: f32,
+    wavelength_max: f32,
+    band: u32,
+    filter_x: FilterTable,
+    filter_y: FilterTable,
+    // whether `wavelength_table` replaces the default distribution of visible wavelengths
+    use_wavelength_table: u32,
+    wavelength_table: TableSampler1d,
+    record_samples: u32,
+    groups: u32,
+    features: u32,
+    mattes: u32,
+    // 0 for no limit
+    clamp: f32,
+}
+
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
 struct Transform {
     m: Mat4,
     m_inv: Mat4,
@@ -497,6 +2765,55 @@ impl Transform {
     }
 }
 
+// A transform which changes while the shutter is open, as the scale, rotation and translation it
+// has when the shutter opens and closes. These are interpolated separately, as in pbrt, so that
+// objects don't shrink partway through a rotation.
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+struct AnimatedTransform {
+    start: Decomposed,
+    end: Decomposed,
+}
+
+#[derive(Copy, Clone, Debug, Default, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+struct Decomposed {
+    rotation: Quat,
+    translation: Vec3,
+    _padding0: u32,
+    scale: Vec3,
+    _padding1: u32,
+}
+
+impl AnimatedTransform {
+    // `start` at time 0 and `end` at time 1, over the part of that time the shutter is open
+    fn new(start: DMat4, end: DMat4, shutter: [f64; 2]) -> Self {
+        let (s0, r0, t0) = start.to_scale_rotation_translation();
+        let (s1, r1, t1) = end.to_scale_rotation_translation();
+        let at = |time: f64| Decomposed {
+            rotation: r0.slerp(r1, time).as_quat(),
+            translation: t0.lerp(t1, time).as_vec3(),
+            _padding0: 0,
+            scale: s0.lerp(s1, time).as_vec3(),
+            _padding1: 0,
+        };
+        AnimatedTransform {
+            start: at(shutter[0]),
+            end: at(shutter[1]),
+        }
+    }
+
+    // the transform at `time` of the shutter interval, like `animated_transform_at` in transform.wgsl
+    fn at(&self, time: f32) -> Mat4 {
+        let (a, b) = (&self.start, &self.end);
+        Mat4::from_scale_rotation_translation(
+            a.scale.lerp(b.scale, time),
+            a.rotation.slerp(b.rotation, time),
+            a.translation.lerp(b.translation, time),
+        )
+    }
+}
+
 trait ExtraState {
     fn add_bind_group_layouts<'a>(&'a mut self, bg_layouts: &mut Vec<&'a wgpu::BindGroupLayout>);
     fn setup_pass(&mut self, pass: &mut wgpu::ComputePass);
@@ -508,7 +2825,9 @@ trait ExtraState {
         queue: &wgpu::Queue,
         mean: &wgpu::Texture,
         variance: &wgpu::Texture,
-    );
+    ) -> anyhow::Result<()>;
+    // the trained guiding structures, for integrators that have them
+    fn guide_data(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Option<GuideData>;
 }
 
 impl ExtraState for () {
@@ -522,14 +2841,21 @@ impl ExtraState for () {
         _queue: &wgpu::Queue,
         _mean: &wgpu::Texture,
         _variance: &wgpu::Texture,
-    ) {
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+    fn guide_data(&self, _device: &wgpu::Device, _queue: &wgpu::Queue) -> Option<GuideData> {
+        None
     }
 }
 
 struct GuidedState {
     bsp: wgpu::Buffer,
+    bsp_stats: wgpu::Buffer,
+    guide: wgpu::Buffer,
     dir_tree: wgpu::Buffer,
     bounds: wgpu::Buffer,
+    scene_bounds: [Vec3; 2],
     bg_layout: wgpu::BindGroupLayout,
     bg: wgpu::BindGroup,
     iter: u32,
@@ -537,6 +2863,17 @@ struct GuidedState {
     train_budget_samples: u32,
     train_budget_time: Duration,
     scale: f32,
+    response: Response,
+    output: PathBuf,
+    learn_fraction: bool,
+}
+
+#[derive(Copy, Clone, Debug)]
+struct GuideOptions {
+    // overrides the default and the fractions of a loaded guide
+    bsdf_fraction: Option<f32>,
+    learn_fraction: bool,
+    adrrs: bool,
 }
 
 #[derive(Copy, Clone, Debug, NoUninit, AnyBitPattern)]
@@ -546,6 +2883,21 @@ struct BspNode {
     left: u32,
     right: u32,
     count: u32,
+    axis: u32,
+    split: f32,
+    bsdf_fraction: f32,
+    // mean radiance arriving in the leaf when its guide was trained, for adrrs
+    radiance: f32,
+}
+
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+#[repr(C)]
+struct BspStats {
+    weight: f32,
+    sum: [f32; 3],
+    sum_sq: [f32; 3],
+    fraction_grad: f32,
+    fraction_norm: f32,
 }
 
 #[derive(Copy, Clone, Debug, Pod, Zeroable)]
@@ -559,7 +2911,7 @@ struct DirTreeNode {
 #[repr(C)]
 struct SceneBounds {
     min: Vec3,
-    _padding0: u32,
+    adrrs: u32,
     max: Vec3,
     _padding1: u32,
 }
@@ -581,8 +2933,9 @@ impl ExtraState for GuidedState {
         queue: &wgpu::Queue,
         mean: &wgpu::Texture,
         variance: &wgpu::Texture,
-    ) {
-        if sample == self.next_iter
+    ) -> anyhow::Result<()> {
+        // passes of several samples can step over the sample an update was planned for
+        if sample >= self.next_iter
             && sample < self.train_budget_samples
             && time < self.train_budget_time
         {
@@ -594,88 +2947,65 @@ impl ExtraState for GuidedState {
             println!("Relative variance: {}", stats.avg_rel_variance);
 
             let preview_path = format!("preview-{}.png", self.iter);
-            xyz_to_srgb(&stats.mean_image, self.scale)
-                .save(&preview_path)
-                .unwrap();
-            std::fs::copy(&preview_path, "img.png").unwrap();
-
-            let bsp = Arc::new(OnceLock::new());
-            let bsp2 = bsp.clone();
-            wgpu::util::DownloadBuffer::read_buffer(
-                device,
-                queue,
-                &self.bsp.slice(..),
-                move |result| {
-                    bsp2.set(bytemuck::pod_collect_to_vec(&result.unwrap()))
-                        .unwrap();
-                },
-            );
-
-            let dir_tree = Arc::new(OnceLock::new());
-            let dir_tree2 = dir_tree.clone();
-            wgpu::util::DownloadBuffer::read_buffer(
-                device,
-                queue,
-                &self.dir_tree.slice(..),
-                move |result| {
-                    dir_tree2
-                        .set(bytemuck::pod_collect_to_vec(&result.unwrap()))
-                        .unwrap();
-                },
-            );
+            let preview = xyz_to_srgb(&stats.mean_image, self.scale, &self.response);
+            write_png(Path::new(&preview_path), &preview)?;
+            save_image(&stats.mean_image, self.scale, &self.response, &self.output)?;
+            mark_partial(&self.output, Some(sample))?;
 
-            device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+            let mut bsp = Self::download(device, queue, &self.bsp);
+            let stats = Self::download(device, queue, &self.bsp_stats);
+            let dir_tree = Self::download(device, queue, &self.dir_tree);
 
-            let mut bsp = Arc::into_inner(bsp).unwrap().into_inner().unwrap();
-            let dir_tree = Arc::into_inner(dir_tree).unwrap().into_inner().unwrap();
+            if self.learn_fraction {
+                Self::learn_fractions(&mut bsp, &stats);
+            }
 
             let mut new_dir_tree = vec![];
 
             let split_threshold = Self::C * (1u32 << self.iter).isqrt();
 
-            Self::refine_bsp(&mut bsp, &dir_tree, &mut new_dir_tree, split_threshold, 0);
+            Self::refine_bsp(
+                &mut bsp,
+                &stats,
+                &dir_tree,
+                &mut new_dir_tree,
+                split_threshold,
+                0,
+                self.scene_bounds,
+            );
 
             self.bsp = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: None,
                 contents: bytemuck::cast_slice(&bsp),
                 usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
             });
+            self.bsp_stats = Self::make_stats_buffer(device, bsp.len());
 
             let train = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: None,
                 contents: bytemuck::cast_slice(&new_dir_tree),
                 usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
             });
-            let guide = std::mem::replace(&mut self.dir_tree, train);
+            self.guide = std::mem::replace(&mut self.dir_tree, train);
 
-            self.bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &self.bg_layout,
-                entries: &[
-                    wgpu::BindGroupEntry {
-                        binding: 0,
-                        resource: self.bsp.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 1,
-                        resource: guide.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 2,
-                        resource: self.dir_tree.as_entire_binding(),
-                    },
-                    wgpu::BindGroupEntry {
-                        binding: 3,
-                        resource: self.bounds.as_entire_binding(),
-                    },
-                ],
-            });
+            self.bg = self.make_bind_group(device);
 
             let mut cmd = device.create_command_encoder(&Default::default());
             cmd.clear_texture(mean, &wgpu::ImageSubresourceRange::default());
             cmd.clear_texture(variance, &wgpu::ImageSubresourceRange::default());
             queue.submit([cmd.finish()]);
         }
+        Ok(())
+    }
+
+    fn guide_data(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Option<GuideData> {
+        Some(GuideData {
+            iter: self.iter,
+            bounds: self.scene_bounds,
+            bsp: Self::download(device, queue, &self.bsp),
+            guide: Self::download(device, queue, &self.guide),
+            train: Self::download(device, queue, &self.dir_tree),
+        })
     }
 }
 
@@ -683,42 +3013,78 @@ impl GuidedState {
     const LEAF_ENERGY_PORTION: f32 = 0.01;
     const C: u32 = 32000;
     const INITIAL_SAMPLES: u32 = 4;
+    const DEFAULT_BSDF_FRACTION: f32 = 0.5;
+    // both strategies are kept around so neither can miss light entirely
+    const MIN_BSDF_FRACTION: f32 = 0.05;
+    const FRACTION_LEARNING_RATE: f32 = 2.0;
 
-    fn new(device: &wgpu::Device, scene: &Scene, scale: f32, samples: u32, time: Duration) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        device: &wgpu::Device,
+        scene: &Scene,
+        scale: f32,
+        response: &Response,
+        output: &Path,
+        samples: u32,
+        time: Duration,
+        loaded: Option<&GuideData>,
+        options: GuideOptions,
+    ) -> Self {
         let mut qt_nodes = vec![];
+        let scene_bounds = scene.node_bounds(scene.root.unwrap());
         let mut initial_bsp = vec![BspNode {
                 is_leaf: 1,
                 left: !0,
                 right: !0,
                 count: 8*8,
+                axis: 0,
+                split: 0.0,
+                bsdf_fraction: options.bsdf_fraction.unwrap_or(Self::DEFAULT_BSDF_FRACTION),
+                radiance: 0.0,
             }];
-        Self::refine_bsp(&mut initial_bsp, &[], &mut qt_nodes, 0, 0);
-
-        let bsp = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(&initial_bsp),
-            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-        });
-
-        let initial_guide = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: &[0; std::mem::size_of::<[DirTreeNode; 4]>()],
-            usage: wgpu::BufferUsages::STORAGE,
-        });
+        Self::refine_bsp(
+            &mut initial_bsp,
+            &[],
+            &[],
+            &mut qt_nodes,
+            0,
+            0,
+            [scene_bounds.min, scene_bounds.max],
+        );
+        // no flux, so sampling stops here; the leaf children keep saved guide files valid
+        let leaf = DirTreeNode {
+            flux: 0.0,
+            child: !0,
+        };
+        let initial_guide = [[leaf; 4]];
 
-        let initial_train = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-            label: None,
-            contents: bytemuck::cast_slice(qt_nodes.as_flattened()),
-            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
-        });
+        let (mut bsp_nodes, guide_nodes, train_nodes) = match loaded {
+            Some(loaded) => (loaded.bsp.clone(), &loaded.guide[..], &loaded.train[..]),
+            None => (initial_bsp, &initial_guide[..], &qt_nodes[..]),
+        };
+        if let Some(bsdf_fraction) = options.bsdf_fraction {
+            for node in &mut bsp_nodes {
+                node.bsdf_fraction = bsdf_fraction;
+            }
+        }
+        let make_buffer = |contents: &[u8]| {
+            device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: None,
+                contents,
+                usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+            })
+        };
+        let bsp = make_buffer(bytemuck::cast_slice(&bsp_nodes));
+        let bsp_stats = Self::make_stats_buffer(device, bsp_nodes.len());
+        let guide = make_buffer(bytemuck::cast_slice(guide_nodes.as_flattened()));
+        let train = make_buffer(bytemuck::cast_slice(train_nodes.as_flattened()));
 
-        let bounds = scene.node_bounds(scene.root.unwrap());
         let bounds = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
             contents: bytemuck::bytes_of(&SceneBounds {
-                min: bounds.min,
-                max: bounds.max,
-                _padding0: 0,
+                min: scene_bounds.min,
+                max: scene_bounds.max,
+                adrrs: options.adrrs as u32,
                 _padding1: 0,
             }),
             usage: wgpu::BufferUsages::STORAGE,
@@ -731,44 +3097,94 @@ impl GuidedState {
                 storage_buffer_entry(1),
                 writable_storage_buffer_entry(2),
                 storage_buffer_entry(3),
+                writable_storage_buffer_entry(4),
             ],
         });
 
-        let bg = device.create_bind_group(&wgpu::BindGroupDescriptor {
+        // a loaded guide is used as is rather than trained further
+        let (iter, next_iter) = match loaded {
+            Some(loaded) => {
+                println!("Loaded guiding structures after {} iterations", loaded.iter);
+                (loaded.iter, u32::MAX)
+            }
+            None => (0, Self::INITIAL_SAMPLES),
+        };
+
+        let mut state = GuidedState {
+            bsp,
+            bsp_stats,
+            guide,
+            dir_tree: train,
+            bounds,
+            scene_bounds: [scene_bounds.min, scene_bounds.max],
+            bg: device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &bg_layout,
+                entries: &[],
+            }),
+            bg_layout,
+            iter,
+            next_iter,
+            train_budget_samples: (samples as f64 * 0.15) as u32,
+            train_budget_time: time.mul_f64(0.15),
+            scale,
+            response: response.clone(),
+            output: output.to_path_buf(),
+            learn_fraction: options.learn_fraction,
+        };
+        state.bg = state.make_bind_group(device);
+        state
+    }
+
+    fn make_bind_group(&self, device: &wgpu::Device) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
             label: None,
-            layout: &bg_layout,
+            layout: &self.bg_layout,
             entries: &[
                 wgpu::BindGroupEntry {
                     binding: 0,
-                    resource: bsp.as_entire_binding(),
+                    resource: self.bsp.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 1,
-                    resource: initial_guide.as_entire_binding(),
+                    resource: self.guide.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 2,
-                    resource: initial_train.as_entire_binding(),
+                    resource: self.dir_tree.as_entire_binding(),
                 },
                 wgpu::BindGroupEntry {
                     binding: 3,
-                    resource: bounds.as_entire_binding(),
+                    resource: self.bounds.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: self.bsp_stats.as_entire_binding(),
                 },
             ],
-        });
+        })
+    }
 
-        GuidedState {
-            bsp,
-            dir_tree: initial_train,
-            bounds,
-            bg_layout,
-            bg,
-            iter: 0,
-            next_iter: Self::INITIAL_SAMPLES,
-            train_budget_samples: (samples as f64 * 0.15) as u32,
-            train_budget_time: time.mul_f64(0.15),
-            scale,
-        }
+    fn make_stats_buffer(device: &wgpu::Device, nodes: usize) -> wgpu::Buffer {
+        device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(&vec![BspStats::zeroed(); nodes]),
+            usage: wgpu::BufferUsages::COPY_SRC | wgpu::BufferUsages::STORAGE,
+        })
+    }
+
+    fn download<T: NoUninit + AnyBitPattern>(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        buffer: &wgpu::Buffer,
+    ) -> Vec<T> {
+        let data = Arc::new(OnceLock::new());
+        let data2 = data.clone();
+        wgpu::util::DownloadBuffer::read_buffer(device, queue, &buffer.slice(..), move |result| {
+            data2.set(result.unwrap().to_vec()).unwrap();
+        });
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        bytemuck::pod_collect_to_vec(&Arc::into_inner(data).unwrap().into_inner().unwrap())
     }
 
     fn refine_quadtree(
@@ -814,46 +3230,130 @@ impl GuidedState {
         id
     }
 
+    // A step of gradient descent on the logit of each leaf's bsdf fraction, minimizing the KL
+    // divergence between the mixture and the recorded radiance as in "Practical Path Guiding in
+    // Production" (Müller 2019)
+    fn learn_fractions(bsp: &mut [BspNode], stats: &[BspStats]) {
+        for (node, stats) in bsp.iter_mut().zip(stats) {
+            if node.is_leaf == 0 || stats.fraction_norm.is_nan() || stats.fraction_norm <= 0.0 {
+                continue;
+            }
+            let grad = stats.fraction_grad / stats.fraction_norm;
+            let fraction = node.bsdf_fraction.clamp(1e-6, 1.0 - 1e-6);
+            let logit = (fraction / (1.0 - fraction)).ln() - Self::FRACTION_LEARNING_RATE * grad;
+            node.bsdf_fraction = (1.0 / (1.0 + (-logit).exp()))
+                .clamp(Self::MIN_BSDF_FRACTION, 1.0 - Self::MIN_BSDF_FRACTION);
+        }
+    }
+
+    // `stats` covers the nodes from the last iteration; nodes split here have none yet
     fn refine_bsp(
         bsp: &mut Vec<BspNode>,
+        stats: &[BspStats],
         dir_tree: &[[DirTreeNode; 4]],
         new_dir_tree: &mut Vec<[DirTreeNode; 4]>,
         split_threshold: u32,
         node: u32,
+        [min, max]: [Vec3; 2],
     ) {
         let bsp_len = bsp.len() as u32;
         let n = &mut bsp[node as usize];
         if n.is_leaf == 0 {
-            let left = n.left;
-            let right = n.right;
-            Self::refine_bsp(bsp, dir_tree, new_dir_tree, split_threshold, left);
-            Self::refine_bsp(bsp, dir_tree, new_dir_tree, split_threshold, right);
+            let (left, right) = (n.left, n.right);
+            let (axis, split) = (n.axis as usize, n.split);
+            let (mut left_max, mut right_min) = (max, min);
+            left_max[axis] = split;
+            right_min[axis] = split;
+            Self::refine_bsp(
+                bsp,
+                stats,
+                dir_tree,
+                new_dir_tree,
+                split_threshold,
+                left,
+                [min, left_max],
+            );
+            Self::refine_bsp(
+                bsp,
+                stats,
+                dir_tree,
+                new_dir_tree,
+                split_threshold,
+                right,
+                [right_min, max],
+            );
             return;
         }
 
+        // the training tree becomes the guide, so its mean is the radiance the guide has seen.
+        // each recorded position counts once and splats its radiance once across directions.
+        // nodes split off below have no stats and keep the estimate of the node they came from.
+        if (node as usize) < stats.len() && n.count > 0 {
+            let flux: f32 = dir_tree[n.right as usize].iter().map(|c| c.flux).sum();
+            n.radiance = flux / n.count as f32;
+        }
+
         if n.count > split_threshold {
             let guide_dt = n.left;
             let train_dt = n.right;
             let count = n.count / 2;
+            let bsdf_fraction = n.bsdf_fraction;
+            let radiance = n.radiance;
+            let size = max - min;
+
+            // split through the radiance weighted centroid along the axis it is most spread on,
+            // or the middle of the longest axis without any recorded radiance
+            let (axis, t) = match stats.get(node as usize) {
+                Some(s) if s.weight > 0.0 => {
+                    let mean = Vec3::from(s.sum) / s.weight;
+                    let variance = Vec3::from(s.sum_sq) / s.weight - mean * mean;
+                    let axis = (variance * size * size).max_position();
+                    (axis, mean[axis].clamp(0.1, 0.9))
+                }
+                _ => (size.max_position(), 0.5),
+            };
+            let split = min[axis] + t * size[axis];
+
             n.left = bsp_len;
             n.right = bsp_len + 1;
             n.is_leaf = 0;
+            n.axis = axis as u32;
+            n.split = split;
+
+            for _ in 0..2 {
+                bsp.push(BspNode {
+                    is_leaf: 1,
+                    left: guide_dt,
+                    right: train_dt,
+                    count,
+                    axis: 0,
+                    split: 0.0,
+                    bsdf_fraction,
+                    radiance,
+                });
+            }
 
-            bsp.push(BspNode {
-                is_leaf: 1,
-                left: guide_dt,
-                right: train_dt,
-                count,
-            });
-            bsp.push(BspNode {
-                is_leaf: 1,
-                left: guide_dt,
-                right: train_dt,
-                count,
-            });
-
-            Self::refine_bsp(bsp, dir_tree, new_dir_tree, split_threshold, bsp_len);
-            Self::refine_bsp(bsp, dir_tree, new_dir_tree, split_threshold, bsp_len + 1);
+            let (mut left_max, mut right_min) = (max, min);
+            left_max[axis] = split;
+            right_min[axis] = split;
+            Self::refine_bsp(
+                bsp,
+                stats,
+                dir_tree,
+                new_dir_tree,
+                split_threshold,
+                bsp_len,
+                [min, left_max],
+            );
+            Self::refine_bsp(
+                bsp,
+                stats,
+                dir_tree,
+                new_dir_tree,
+                split_threshold,
+                bsp_len + 1,
+                [right_min, max],
+            );
             return;
         }
 
@@ -902,6 +3402,102 @@ impl GuidedState {
     }
 }
 
+fn parse_backend(s: String) -> Result<wgpu::Backends, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "vulkan" | "vk" => Ok(wgpu::Backends::VULKAN),
+        "dx12" | "d3d12" => Ok(wgpu::Backends::DX12),
+        "metal" | "mtl" => Ok(wgpu::Backends::METAL),
+        "gl" | "gles" | "opengl" => Ok(wgpu::Backends::GL),
+        _ => Err(format!("unknown backend `{s}`")),
+    }
+}
+
+fn print_gpu_info(adapter: &wgpu::Adapter, required_features: wgpu::Features) {
+    let info = adapter.get_info();
+    println!("Adapter: {} ({:?})", info.name, info.device_type);
+    println!("Backend: {:?}", info.backend);
+    println!("Driver: {} {}", info.driver, info.driver_info);
+    println!("Vendor/Device: {:#06x}/{:#06x}", info.vendor, info.device);
+    println!("Features: {:?}", adapter.features());
+    println!(
+        "Missing required features: {:?}",
+        required_features.difference(adapter.features())
+    );
+    println!("Limits: {:#?}", adapter.limits());
+}
+
+fn parse_range(s: String) -> Result<[f32; 2], String> {
+    let (min, max) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected `min:max`, got `{s}`"))?;
+    let min = min.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    let max = max.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    if !(0.0 < min && min <= max) {
+        return Err(format!("invalid range `{s}`"));
+    }
+    Ok([min, max])
+}
+
+// without some BSDF sampling, directions the guide gives no density would never be sampled
+fn parse_bsdf_fraction(s: String) -> Result<f32, String> {
+    let fraction = s.trim().parse::<f32>().map_err(|e| e.to_string())?;
+    if !(0.0 < fraction && fraction <= 1.0) {
+        return Err(format!("expected a fraction in (0, 1], got `{s}`"));
+    }
+    Ok(fraction)
+}
+
+fn parse_frames(s: String) -> Result<Range<u32>, String> {
+    let (start, end) = s
+        .split_once("..")
+        .ok_or_else(|| format!("expected `start..end`, got `{s}`"))?;
+    let start = start.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    let end = end.trim().parse::<u32>().map_err(|e| e.to_string())?;
+    if start >= end {
+        return Err(format!("no frames in `{s}`"));
+    }
+    Ok(start..end)
+}
+
+fn parse_frame_translate(s: String) -> Result<(String, Vec3), String> {
+    let (name, offset) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected `name=x,y,z`, got `{s}`"))?;
+    Ok((name.to_owned(), parse_vec3(offset.to_owned())?))
+}
+
+fn parse_vec3(s: String) -> Result<Vec3, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| e.to_string())?;
+    match values[..] {
+        [x, y, z] => Ok(Vec3::new(x, y, z)),
+        _ => Err(format!("expected `x,y,z`, got `{s}`")),
+    }
+}
+
+fn parse_roi(s: String) -> Result<Roi, String> {
+    let values: Vec<_> = s.split(',').map(str::trim).collect();
+    let [x0, y0, x1, y1, weight] = values[..] else {
+        return Err(format!("expected `x0,y0,x1,y1,weight`, got `{s}`"));
+    };
+    let coord = |v: &str| v.parse::<u32>().map_err(|e| e.to_string());
+    let roi = Roi {
+        min: [coord(x0)?, coord(y0)?],
+        max: [coord(x1)?, coord(y1)?],
+        weight: weight.parse::<f32>().map_err(|e| e.to_string())?,
+    };
+    if roi.min[0] >= roi.max[0] || roi.min[1] >= roi.max[1] {
+        return Err(format!("empty region of interest `{s}`"));
+    }
+    if !roi.weight.is_finite() || roi.weight < 1.0 {
+        return Err(format!("invalid region of interest weight `{weight}`"));
+    }
+    Ok(roi)
+}
+
 fn parse_time(mut s: String) -> Result<Duration, std::num::ParseFloatError> {
     s.make_ascii_lowercase();
     let number = s.trim_end_matches(char::is_alphabetic);
@@ -920,13 +3516,56 @@ fn parse_time(mut s: String) -> Result<Duration, std::num::ParseFloatError> {
     Ok(Duration::from_secs_f64(number * unit_seconds))
 }
 
+// variance estimates from fewer samples are too noisy to stop on
+const TARGET_ERROR_MIN_SAMPLES: u32 = 16;
+const TARGET_ERROR_INTERVAL: Duration = Duration::from_secs(5);
+
 struct ImageStats {
     mean_image: Rgba32FImage,
     avg_rel_variance: f64,
     avg_rel_error: f64,
+    avg_spp: f64,
     efficiency: f64,
 }
 
+// Replaces each pixel with the median by luminance of the means of its groups of samples
+fn median_of_means(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    group_texture: &wgpu::Texture,
+    mean_image: &mut Rgba32FImage,
+) {
+    let groups = group_texture.depth_or_array_layers();
+    let downloaded = Arc::new(Mutex::new(vec![vec![]; groups as usize]));
+    let mut encoder = device.create_command_encoder(&Default::default());
+    for layer in 0..groups {
+        let dl = downloaded.clone();
+        download_texture_layer(device, &mut encoder, group_texture, layer, move |data| {
+            dl.lock().unwrap()[layer as usize] = data;
+        });
+    }
+    queue.submit([encoder.finish()]);
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    let layers = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+    let mut means = vec![];
+    for (i, pixel) in mean_image.pixels_mut().enumerate() {
+        // groups past the sample count hold samples from before the film was last cleared
+        let filled = (pixel.0[3] as usize).min(groups as usize);
+        if filled < 2 {
+            continue;
+        }
+        means.clear();
+        means.extend(layers[..filled].iter().map(|layer| layer[i].xyz()));
+        means.sort_by_key(|xyz| OrderedFloat(xyz.y));
+        let median = match filled % 2 {
+            1 => means[filled / 2],
+            _ => (means[filled / 2 - 1] + means[filled / 2]) / 2.0,
+        };
+        pixel.0[..3].copy_from_slice(&median.to_array());
+    }
+}
+
 fn collect_stats(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
@@ -934,9 +3573,17 @@ fn collect_stats(
     variance: &wgpu::Texture,
     time: Duration,
 ) -> ImageStats {
-    let width = mean.width();
-    let height = mean.height();
+    let (width, height) = (mean.width(), mean.height());
+    let (mean, variance) = download_film(device, queue, mean, variance);
+    film_stats(width, height, mean, &variance, time)
+}
 
+fn download_film(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    mean: &wgpu::Texture,
+    variance: &wgpu::Texture,
+) -> (Vec<Vec4>, Vec<Vec4>) {
     let mut encoder = device.create_command_encoder(&Default::default());
 
     let downloaded = Arc::new(Mutex::new((vec![], vec![])));
@@ -955,12 +3602,44 @@ fn collect_stats(
 
     device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
 
-    let (mean, variance) = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+    Arc::into_inner(downloaded).unwrap().into_inner().unwrap()
+}
+
+fn save_film(
+    (device, queue, mean, variance): (&wgpu::Device, &wgpu::Queue, &wgpu::Texture, &wgpu::Texture),
+    options: &Options,
+    samples: u32,
+    time: Duration,
+    scale: f32,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let (width, height) = (mean.width(), mean.height());
+    let (mean, variance) = download_film(device, queue, mean, variance);
+    let film = film_file::FilmData {
+        width,
+        height,
+        first_sample: options.sample_offset,
+        samples,
+        time: time.as_secs_f64(),
+        scale,
+        mean,
+        variance,
+    };
+    film.save(path)
+}
 
+// `mean` has the sample count in w and `variance` the sum of squared differences from the mean
+fn film_stats(
+    width: u32,
+    height: u32,
+    mean: Vec<Vec4>,
+    variance: &[Vec4],
+    time: Duration,
+) -> ImageStats {
     let mut avg_rel_variance = 0.0;
     let mut avg_rel_error = 0.0;
     let mut avg_spp = 0.0;
-    for (&mean, &s) in mean.iter().zip(&variance) {
+    for (&mean, &s) in mean.iter().zip(variance) {
         let samples = mean.w;
         let mean = mean.xyz();
         let s = s.xyz();
@@ -1012,23 +3691,328 @@ fn collect_stats(
         mean_image,
         avg_rel_variance,
         avg_rel_error,
+        avg_spp,
         efficiency,
     }
 }
 
-fn xyz_to_srgb(xyz: &Rgba32FImage, scale: f32) -> RgbImage {
+// Writes each AOV to `aov_<name>` in the working directory, as EXR for data and PNG for the ones
+// that are only looked at. Takes the aov, feature and matte textures, and the names of the object
+// and material ids.
+fn save_aovs(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    [aov, features, mattes]: [&wgpu::Texture; 3],
+    [object_names, material_names]: &[cryptomatte::IdNames; 2],
+    aovs: &[Aov],
+) -> anyhow::Result<()> {
+    let mut encoder = device.create_command_encoder(&Default::default());
+    let downloaded = Arc::new(Mutex::new([const { vec![] }; 3 + cryptomatte::RANKS]));
+    let dl = downloaded.clone();
+    download_texture(device, &mut encoder, aov, move |data| {
+        dl.lock().unwrap()[0] = data;
+    });
+    if aovs.iter().any(|aov| aov.uses_features()) {
+        for layer in 0..2 {
+            let dl = downloaded.clone();
+            download_texture_layer(device, &mut encoder, features, layer, move |data| {
+                dl.lock().unwrap()[layer as usize + 1] = data;
+            });
+        }
+    }
+    if aovs.contains(&Aov::Cryptomatte) {
+        for layer in 0..cryptomatte::RANKS as u32 {
+            let dl = downloaded.clone();
+            download_texture_layer(device, &mut encoder, mattes, layer, move |data| {
+                dl.lock().unwrap()[layer as usize + 3] = data;
+            });
+        }
+    }
+    queue.submit([encoder.finish()]);
+    device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    let [data, albedo, geometry, matte_layers @ ..] =
+        Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+    let (width, height) = (aov.width(), aov.height());
+    let write_exr = |name: &str, channels: Vec<(&str, Vec<f32>)>| {
+        let channels = channels
+            .into_iter()
+            .map(|(channel, values)| AnyChannel::new(channel, FlatSamples::F32(values)))
+            .collect();
+        let image = exr::prelude::Image::from_channels(
+            (width as usize, height as usize),
+            AnyChannels::sort(channels),
+        );
+        let path = Path::new(name);
+        write_atomic(path, |tmp| Ok(image.write().to_file(tmp)?))
+            .with_context(|| format!("failed to write {name}"))
+    };
+    let write_rgb = |name: &str, values: Vec<Vec3>| {
+        write_exr(
+            name,
+            vec![
+                ("R", values.iter().map(|v| v.x).collect()),
+                ("G", values.iter().map(|v| v.y).collect()),
+                ("B", values.iter().map(|v| v.z).collect()),
+            ],
+        )
+    };
+    // a random color for each name, black where nothing was hit
+    let hits: Vec<bool> = data.iter().map(|v| v.y.to_bits() != u32::MAX).collect();
+    let write_ids = |name: &str, ids: Vec<u32>, names: &cryptomatte::IdNames| {
+        let mut colors = HashMap::new();
+        let image = RgbImage::from_fn(width, height, |x, y| {
+            let i = (y * width + x) as usize;
+            if !hits[i] {
+                return Rgb([0; 3]);
+            }
+            *colors.entry(ids[i]).or_insert_with(|| {
+                let h = splitmix64(&mut (names.hash(ids[i]) as u64)).to_le_bytes();
+                Rgb([h[0] / 2 + 64, h[1] / 2 + 64, h[2] / 2 + 64])
+            })
+        });
+        write_atomic(Path::new(name), |tmp| Ok(image.save(tmp)?))
+            .with_context(|| format!("failed to write {name}"))
+    };
+
+    for &kind in aovs {
+        match kind {
+            Aov::PathLength => {
+                let lengths: Vec<f32> = data.iter().map(|v| v.x).collect();
+                let max = lengths.iter().copied().fold(0.0, f32::max);
+                let avg = lengths.iter().sum::<f32>() / lengths.len() as f32;
+                println!("Path length: average {avg:.2}, max {max:.2}");
+
+                let image = GrayImage::from_fn(aov.width(), aov.height(), |x, y| {
+                    let v = lengths[(y * aov.width() + x) as usize] / max.max(1.0);
+                    Luma([(v * 255.0).round() as u8])
+                });
+                write_png(Path::new("aov_path_length.png"), &image)?;
+            }
+            Aov::Albedo => {
+                let to_rgb = xyz_to_linear_srgb();
+                write_rgb(
+                    "aov_albedo.exr",
+                    albedo.iter().map(|a| to_rgb * a.xyz()).collect(),
+                )?
+            }
+            Aov::Normal => write_rgb(
+                "aov_normal.exr",
+                geometry
+                    .iter()
+                    .map(|g| g.xyz().normalize_or_zero())
+                    .collect(),
+            )?,
+            Aov::Depth => write_exr(
+                "aov_depth.exr",
+                vec![("Z", geometry.iter().map(|g| g.w).collect())],
+            )?,
+            Aov::MaterialId => write_ids(
+                "aov_material_id.png",
+                data.iter().map(|v| v.y.to_bits()).collect(),
+                material_names,
+            )?,
+            Aov::InstanceId => write_ids(
+                "aov_instance_id.png",
+                data.iter().map(|v| v.z.to_bits()).collect(),
+                object_names,
+            )?,
+            Aov::Cryptomatte => {
+                // the two texels of each kind of id hold the ranks in the order they were seen
+                let ranked = |layers: &[Vec<Vec4>]| -> Vec<[(u32, f32); cryptomatte::RANKS]> {
+                    (0..layers[0].len())
+                        .map(|i| {
+                            let [a, b] = [layers[0][i], layers[1][i]];
+                            let mut ranks = [
+                                (a.x.to_bits(), a.y),
+                                (a.z.to_bits(), a.w),
+                                (b.x.to_bits(), b.y),
+                                (b.z.to_bits(), b.w),
+                            ];
+                            ranks.sort_by(|p, q| q.1.total_cmp(&p.1));
+                            ranks
+                        })
+                        .collect()
+                };
+                let layers = [
+                    cryptomatte::Layer {
+                        name: "CryptoObject",
+                        ids: object_names,
+                        pixels: ranked(&matte_layers[..2]),
+                    },
+                    cryptomatte::Layer {
+                        name: "CryptoMaterial",
+                        ids: material_names,
+                        pixels: ranked(&matte_layers[2..]),
+                    },
+                ];
+                cryptomatte::write(Path::new("aov_cryptomatte.exr"), width, height, &layers)?
+            }
+        }
+    }
+    Ok(())
+}
+
+// Scale which brings the weighted log-average luminance of the frame to middle gray. Pixels with
+// no light are skipped so that empty backgrounds don't blow out the exposure.
+fn meter_exposure(xyz: &Rgba32FImage, metering: Metering, overscan: u32) -> Option<f32> {
+    let size = Vec2::new(xyz.width() as f32, xyz.height() as f32);
+    let half = (size.min_element() - 2.0 * overscan as f32).max(1.0) / 2.0;
+
+    let mut total_weight = 0.0;
+    let mut total = 0.0;
+    for (x, y, p) in xyz.enumerate_pixels() {
+        let luminance = p[1];
+        if !luminance.is_finite() || luminance <= 0.0 {
+            continue;
+        }
+        let offset = (Vec2::new(x as f32, y as f32) + 0.5 - size / 2.0) / half;
+        let w = metering.weight(offset) as f64;
+        total_weight += w;
+        total += w * (luminance as f64).ln();
+    }
+
+    if total_weight == 0.0 {
+        println!("\rWarning: nothing lit in the metered region; keeping the exposure");
+        return None;
+    }
+    let average = (total / total_weight).exp() as f32;
+    let scale = 0.18 / average;
+    println!("\rMetered log-average luminance {average:.4}, scale {scale:.4}");
+    Some(scale)
+}
+
+fn xyz_to_linear_srgb() -> Mat3 {
     const SRGB_TO_XYZ_T: Mat3 = Mat3::from_cols_array_2d(&[
         [0.4124, 0.3576, 0.1805],
         [0.2126, 0.7152, 0.0722],
         [0.0193, 0.1192, 0.9505],
     ]);
-    let xyz_to_srgb = SRGB_TO_XYZ_T.transpose().inverse();
+    SRGB_TO_XYZ_T.transpose().inverse()
+}
+
+// EXR images get linear sRGB and other formats the response curve
+fn save_image(
+    xyz: &Rgba32FImage,
+    scale: f32,
+    response: &Response,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let exr = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("exr"));
+    let result = match exr {
+        true => {
+            let distorted;
+            let xyz = match response.lens {
+                Some(lens) => {
+                    distorted = lens.apply(xyz);
+                    &distorted
+                }
+                None => xyz,
+            };
+            let xyz_to_rgb = xyz_to_linear_srgb() * scale;
+            let (width, height) = (xyz.width() as usize, xyz.height() as usize);
+            write_atomic(path, |tmp| {
+                exr::prelude::write_rgb_file(tmp, width, height, |x, y| {
+                    let rgb =
+                        xyz_to_rgb * Vec4::from_array(xyz.get_pixel(x as u32, y as u32).0).xyz();
+                    (rgb.x, rgb.y, rgb.z)
+                })
+                .map_err(anyhow::Error::from)
+            })
+        }
+        false => {
+            let img = xyz_to_srgb(xyz, scale, response);
+            write_atomic(path, |tmp| img.save(tmp).map_err(anyhow::Error::from))
+        }
+    };
+    result.with_context(|| format!("failed to write {}", path.display()))
+}
+
+// TOML table from --material-replace or --light-override, empty if the option wasn't given
+fn load_overrides(path: Option<&Path>) -> anyhow::Result<toml::Table> {
+    let Some(path) = path else {
+        return Ok(toml::Table::new());
+    };
+    let text = std::fs::read_to_string(path).with_context(|| path.display().to_string())?;
+    text.parse()
+        .with_context(|| format!("failed to parse {}", path.display()))
+}
+
+// Writes to a temporary file next to `path` and renames it into place, so an interrupted write
+// never leaves a truncated file behind
+fn write_atomic(
+    path: &Path,
+    write: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    // keeps the extension so the format is still detected from it
+    let name = path.file_name().context("output path has no file name")?;
+    let tmp = path.with_file_name(format!(".tmp-{}", name.to_string_lossy()));
+    let result = write(&tmp).and_then(|()| Ok(std::fs::rename(&tmp, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp);
+    }
+    result
+}
+
+// Encodes in memory first, so a failed encode leaves nothing behind
+fn write_png<P, C>(path: &Path, image: &image::ImageBuffer<P, C>) -> anyhow::Result<()>
+where
+    P: image::PixelWithColorType,
+    [P::Subpixel]: image::EncodableLayout,
+    C: std::ops::Deref<Target = [P::Subpixel]>,
+{
+    let mut data = std::io::Cursor::new(vec![]);
+    image.write_to(&mut data, image::ImageFormat::Png)?;
+    write_atomic(path, |tmp| Ok(std::fs::write(tmp, data.get_ref())?))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+// `img.png` with suffix `denoised` is `img-denoised.png`, keeping the format
+fn suffixed_path(output: &Path, suffix: &str) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{stem}-{suffix}");
+    if let Some(ext) = output.extension() {
+        name += &format!(".{}", ext.to_string_lossy());
+    }
+    output.with_file_name(name)
+}
+
+fn partial_path(output: &Path) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(".partial");
+    PathBuf::from(path)
+}
+
+// `<output>.partial` exists while a render is unfinished and records the last checkpoint written to
+// the output path, if any
+fn mark_partial(output: &Path, checkpoint: Option<u32>) -> anyhow::Result<()> {
+    let mut text = format!("output = {:?}\n", output.display().to_string());
+    if let Some(sample) = checkpoint {
+        text += &format!("checkpoint = {:?}\n", output.display().to_string());
+        text += &format!("checkpoint_samples = {sample}\n");
+    }
+    let path = partial_path(output);
+    write_atomic(&path, |tmp| Ok(std::fs::write(tmp, text)?))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn xyz_to_srgb(xyz: &Rgba32FImage, scale: f32, response: &Response) -> RgbImage {
+    let distorted;
+    let xyz = match response.lens {
+        Some(lens) => {
+            distorted = lens.apply(xyz);
+            &distorted
+        }
+        None => xyz,
+    };
+
+    let xyz_to_srgb = xyz_to_linear_srgb();
 
     RgbImage::from_fn(xyz.width(), xyz.height(), |x, y| {
         let rgb = xyz_to_srgb * Vec4::from_array(xyz.get_pixel(x, y).0).xyz() * scale;
-        let low = rgb * 12.92;
-        let high = rgb.powf(1.0 / 2.4) * 1.055 - 0.055;
-        let srgb = Vec3::select(rgb.cmplt(Vec3::splat(0.0031308)), low, high);
-        Rgb((srgb * 255.0).as_u8vec3().to_array())
+        let encoded = response.encode(rgb, x, y);
+        Rgb((encoded * 255.0).as_u8vec3().to_array())
     })
 }
@@ -0,0 +1,49 @@
+use std::fmt::Write;
+use std::path::Path;
+
+// JSON sidecar describing how an image was rendered
+
+#[derive(Default)]
+pub struct Metadata {
+    entries: Vec<(&'static str, String)>,
+}
+
+impl Metadata {
+    pub fn number(&mut self, key: &'static str, value: impl Into<f64>) {
+        let value = value.into();
+        let value = match value.is_finite() {
+            true => value.to_string(),
+            false => "null".to_owned(),
+        };
+        self.entries.push((key, value));
+    }
+
+    pub fn string(&mut self, key: &'static str, value: &str) {
+        self.entries.push((key, quote(value)));
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        let mut json = "{\n".to_owned();
+        for (i, (key, value)) in self.entries.iter().enumerate() {
+            let comma = if i + 1 < self.entries.len() { "," } else { "" };
+            writeln!(json, "  {}: {value}{comma}", quote(key)).unwrap();
+        }
+        json.push_str("}\n");
+        std::fs::write(path, json)
+    }
+}
+
+fn quote(s: &str) -> String {
+    let mut result = "\"".to_owned();
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if c < ' ' => write!(result, "\\u{:04x}", c as u32).unwrap(),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
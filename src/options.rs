@@ -1,12 +1,27 @@
-use glam::Mat4;
+use std::path::PathBuf;
 
+use glam::{DVec3, Mat4, Vec2};
+use serde::{Deserialize, Serialize};
+
+use crate::filter::{Filter, FilterType};
 use crate::{ProjectiveCamera, Transform};
 
+#[derive(Serialize, Deserialize)]
 pub struct RenderOptions {
     pub camera: ProjectiveCamera,
     pub width: u32,
     pub height: u32,
     pub samples: u32,
+    pub sampler: SamplerType,
+    pub integrator: Option<String>,
+    // longest path, if the scene sets one
+    pub max_depth: Option<u32>,
+    pub filename: Option<PathBuf>,
+    // exposure scale of the image
+    pub scale: Option<f32>,
+    // x0, x1, y0, y1 in [0,1] with y down, like pbrt
+    pub crop_window: Option<[f32; 4]>,
+    pub filter: Filter,
 }
 
 impl Default for RenderOptions {
@@ -22,11 +37,366 @@ impl Default for RenderOptions {
                 lens_radius: 0.0,
                 focal_distance: 1e30,
                 orthographic: false as u32,
-                _padding: 0,
+                distortion: 0.0,
+                chromatic_aberration: 0.0,
+                vignetting: false as u32,
+                moving: false as u32,
+                frame_diagonal: 0.0,
+                motion: Default::default(),
             },
             width: 1280,
             height: 720,
             samples: 16,
+            sampler: SamplerType::Independent,
+            integrator: None,
+            max_depth: None,
+            filename: None,
+            scale: None,
+            crop_window: None,
+            filter: Filter::new(FilterType::Box),
+        }
+    }
+}
+
+#[derive(Copy, Clone, clap::ValueEnum, Serialize, Deserialize)]
+pub enum SamplerType {
+    // uncorrelated random numbers
+    Independent,
+    // Owen-scrambled Sobol points, which converge faster for smooth integrands
+    Sobol,
+    // jittered strata for each dimension, spread over the sample count
+    Stratified,
+    // low discrepancy sequences offset by blue noise, so the error of low sample counts is
+    // spread out as high frequency noise
+    BlueNoise,
+}
+
+// How much time goes into building BVHs
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum BvhQuality {
+    // splits between objects only
+    #[default]
+    Fast,
+    // also splits shapes which straddle a plane, mostly long thin triangles, so that they end up
+    // in several leaves with tighter bounds
+    High,
+}
+
+// How the guided integrator adds up the data it trains on
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum FloatAtomics {
+    // whichever of the two is faster in a quick benchmark at startup
+    Auto,
+    // the GPU's float atomics
+    Native,
+    // compare-exchange loops on the bits of the floats, for drivers without float atomics or
+    // with slow ones
+    Cas,
+}
+
+impl FloatAtomics {
+    // the shader flag selecting the additions
+    pub fn flag(self) -> &'static str {
+        match self {
+            FloatAtomics::Auto => unreachable!("float atomics should have been picked"),
+            FloatAtomics::Native => "native",
+            FloatAtomics::Cas => "cas",
+        }
+    }
+}
+
+#[derive(Copy, Clone, clap::ValueEnum)]
+pub enum Preset {
+    // quick look at half resolution, clamped and filtered on the GPU
+    Preview,
+    // scene file sample count with path guiding, lightly clamped
+    Production,
+    // fixed workload so timings are comparable between runs
+    Benchmark,
+    // near-infrared band with a flat sensor response
+    Infrared,
+}
+
+// Region of the image used to pick the exposure
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Metering {
+    // the whole image
+    Matrix,
+    // the whole image, with most of the weight in the middle
+    Center,
+    // a small circle in the middle
+    Spot,
+}
+
+impl Metering {
+    // `p` is the offset from the image center in units of half the smaller dimension
+    pub fn weight(self, p: Vec2) -> f32 {
+        match self {
+            Metering::Matrix => 1.0,
+            Metering::Center => (-p.length_squared() / (2.0 * 0.4 * 0.4)).exp(),
+            Metering::Spot => (p.length() < 0.1) as u32 as f32,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum LensMode {
+    // when generating camera rays, which is correct but traces one wavelength per path when
+    // there is chromatic aberration
+    Ray,
+    // by resampling the finished image, in both PNG and EXR output; AOVs, probes and --save-film
+    // are left undistorted
+    Post,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum Aov {
+    // average number of surface interactions per path
+    PathLength,
+    // the rest are of the first surface hit
+    Albedo,
+    Normal,
+    // distance from the camera
+    Depth,
+    // colored by a hash of the name, from the first sample of each pixel
+    MaterialId,
+    InstanceId,
+    // coverage of each object and material for compositing, written as a Cryptomatte EXR
+    Cryptomatte,
+}
+
+impl Aov {
+    // needs the albedo, normals and distances the film only keeps when asked to
+    pub fn uses_features(self) -> bool {
+        matches!(self, Aov::Albedo | Aov::Normal | Aov::Depth)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn vector(self) -> DVec3 {
+        match self {
+            Axis::X => DVec3::X,
+            Axis::Y => DVec3::Y,
+            Axis::Z => DVec3::Z,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Axis::X => "x",
+            Axis::Y => "y",
+            Axis::Z => "z",
+        }
+    }
+}
+
+// Up axis and units the scene's geometry was modeled in, when they differ from what its camera
+// was set up for
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SceneConvention {
+    // turned to point up in the camera's view
+    pub up_axis: Option<Axis>,
+    pub scale: Option<f64>,
+}
+
+// How the samples of each pixel are combined
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Accumulation {
+    Mean,
+    // median of the means of `k` interleaved groups of samples, which rejects fireflies without
+    // clamping every sample
+    MedianOfMeans(u32),
+}
+
+impl Accumulation {
+    const DEFAULT_GROUPS: u32 = 5;
+
+    // `mean`, `median-of-means` or `median-of-means:<k>`
+    pub fn parse(s: String) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "mean" => Ok(Accumulation::Mean),
+            None if s == "median-of-means" => Ok(Accumulation::MedianOfMeans(Self::DEFAULT_GROUPS)),
+            Some(("median-of-means", k)) => match k.parse() {
+                Ok(k) if k >= 2 => Ok(Accumulation::MedianOfMeans(k)),
+                Ok(_) => Err("median-of-means needs at least 2 groups".to_owned()),
+                Err(e) => Err(format!("invalid group count {k}: {e}")),
+            },
+            _ => Err(format!("unknown accumulation `{s}`")),
+        }
+    }
+
+    pub fn name(self) -> String {
+        match self {
+            Accumulation::Mean => "mean".to_owned(),
+            Accumulation::MedianOfMeans(k) => format!("median-of-means:{k}"),
+        }
+    }
+
+    // groups kept for each pixel, 0 if the samples are only averaged
+    pub fn groups(self) -> u32 {
+        match self {
+            Accumulation::Mean => 0,
+            Accumulation::MedianOfMeans(k) => k,
+        }
+    }
+}
+
+pub struct PresetSettings {
+    pub resolution_scale: f32,
+    pub samples: Option<u32>,
+    pub integrator: &'static str,
+    pub wavelengths: Option<[f32; 2]>,
+    // most luminance of a sample after scaling by the exposure, unlike --clamp which is before
+    pub clamp: Option<f32>,
+    pub preview_denoise: bool,
+}
+
+impl Preset {
+    pub fn settings(self) -> PresetSettings {
+        match self {
+            Preset::Preview => PresetSettings {
+                resolution_scale: 0.5,
+                samples: Some(4),
+                integrator: "simple",
+                wavelengths: None,
+                // so few samples are noisy enough already, and the fast GPU filter cleans them up
+                clamp: Some(10.0),
+                preview_denoise: true,
+            },
+            Preset::Production => PresetSettings {
+                resolution_scale: 1.0,
+                samples: None,
+                integrator: "guided",
+                wavelengths: None,
+                // only the rarest paths are clipped
+                clamp: Some(100.0),
+                preview_denoise: false,
+            },
+            Preset::Benchmark => PresetSettings {
+                resolution_scale: 1.0,
+                samples: Some(64),
+                integrator: "simple",
+                wavelengths: None,
+                // the work done should not depend on the image
+                clamp: None,
+                preview_denoise: false,
+            },
+            Preset::Infrared => PresetSettings {
+                resolution_scale: 1.0,
+                samples: None,
+                integrator: "simple",
+                wavelengths: Some([750.0, 1400.0]),
+                // sensor simulation wants the measured radiance as is
+                clamp: None,
+                preview_denoise: false,
+            },
+        }
+    }
+}
+
+impl Default for PresetSettings {
+    fn default() -> Self {
+        Self {
+            resolution_scale: 1.0,
+            samples: None,
+            integrator: "simple",
+            wavelengths: None,
+            clamp: None,
+            preview_denoise: false,
+        }
+    }
+}
+
+// Replacement for every material in the scene, for checking lighting
+#[derive(Copy, Clone, Debug)]
+pub enum MaterialOverride {
+    // gray diffuse with the given albedo
+    Diffuse(f32),
+    UvChecker,
+    // dark triangle edges over gray diffuse
+    Wireframe,
+}
+
+impl MaterialOverride {
+    // `diffuse`, `diffuse:<albedo>` with albedo in [0, 1], `uv-checker` or `wireframe`
+    pub fn parse(s: String) -> Result<Self, String> {
+        match s.split_once(':') {
+            None if s == "diffuse" => Ok(MaterialOverride::Diffuse(0.18)),
+            None if s == "uv-checker" => Ok(MaterialOverride::UvChecker),
+            None if s == "wireframe" => Ok(MaterialOverride::Wireframe),
+            Some(("diffuse", albedo)) => match albedo.parse::<f32>() {
+                Ok(value) if (0.0..=1.0).contains(&value) => Ok(MaterialOverride::Diffuse(value)),
+                Ok(_) => Err(format!("albedo {albedo} is not between 0 and 1")),
+                Err(e) => Err(format!("invalid albedo {albedo}: {e}")),
+            },
+            _ => Err(format!("unknown material override `{s}`")),
+        }
+    }
+}
+
+// Pixel rectangle which gets `weight` times as many samples as the rest of the image. Each pixel
+// averages its own samples, so the image stays unbiased.
+#[derive(Copy, Clone)]
+pub struct Roi {
+    pub min: [u32; 2],
+    pub max: [u32; 2],
+    pub weight: f32,
+}
+
+impl Roi {
+    // most extra passes over the region made for one full frame sample
+    pub fn passes_per_sample(self) -> u32 {
+        (self.weight - 1.0).ceil() as u32
+    }
+
+    // The extra passes number their samples below the high bit, which keeps them apart from the
+    // full frame samples, so only this many full frame samples can be taken
+    pub fn sample_limit(self) -> u32 {
+        (1 << 31) / self.passes_per_sample().max(1)
+    }
+}
+
+// Adjustments to environment lights applied while loading, for augmenting datasets
+#[derive(Copy, Clone, Debug)]
+pub struct EnvironmentOverride {
+    // degrees about the environment map's pole
+    pub rotation: f32,
+    pub scale: f32,
+}
+
+impl EnvironmentOverride {
+    pub fn random(seed: u64, max_rotation: f32, [min_scale, max_scale]: [f32; 2]) -> Self {
+        let mut state = seed;
+        let mut next = || (splitmix64(&mut state) >> 40) as f32 / (1 << 24) as f32;
+
+        EnvironmentOverride {
+            rotation: next() * max_rotation,
+            // log-uniform so that the range is symmetric around 1 for e.g. 0.5:2
+            scale: min_scale * (max_scale / min_scale).powf(next()),
+        }
+    }
+}
+
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+impl Default for EnvironmentOverride {
+    fn default() -> Self {
+        Self {
+            rotation: 0.0,
+            scale: 1.0,
         }
     }
 }
@@ -0,0 +1,257 @@
+use std::sync::{Arc, Mutex};
+
+use glam::{Mat4, Vec2, Vec3, Vec3Swizzles, Vec4Swizzles};
+use image::{Rgb, RgbImage};
+
+use crate::gpu_features::GpuFeatures;
+use crate::scene::{NodeId, Scene};
+use crate::{ProjectiveCamera, download_texture, shader};
+
+// colors of the bounds by level below the selected node
+const LEVEL_COLORS: [[u8; 3]; 6] = [
+    [255, 255, 255],
+    [255, 64, 64],
+    [64, 255, 64],
+    [64, 128, 255],
+    [255, 255, 64],
+    [255, 64, 255],
+];
+
+// Renders the shading normals seen by the camera, to be drawn over the image along with the bounds
+// of parts of the BVH, for debugging bad transforms and BVH quality
+pub struct NormalsOverlay {
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    texture: wgpu::Texture,
+}
+
+impl NormalsOverlay {
+    pub fn new(
+        device: &wgpu::Device,
+        sampler: &str,
+        camera: &str,
+        gpu_features: &GpuFeatures,
+        bg_layouts: &[&wgpu::BindGroupLayout],
+        size: [u32; 2],
+    ) -> anyhow::Result<Self> {
+        let flags = [
+            ("sampler".to_owned(), sampler.to_owned()),
+            ("camera".to_owned(), camera.to_owned()),
+        ]
+        .into_iter()
+        .chain(gpu_features.shader_flags())
+        .collect();
+        let shader = shader::load_shader(device, "entrypoint/overlay.wgsl", &flags)?;
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::COMPUTE,
+                ty: wgpu::BindingType::StorageTexture {
+                    access: wgpu::StorageTextureAccess::ReadWrite,
+                    format: wgpu::TextureFormat::Rgba32Float,
+                    view_dimension: wgpu::TextureViewDimension::D2,
+                },
+                count: None,
+            }],
+        });
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("overlay normals"),
+            size: wgpu::Extent3d {
+                width: size[0],
+                height: size[1],
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+            view_formats: &[],
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(
+                    &texture.create_view(&Default::default()),
+                ),
+            }],
+        });
+
+        let mut bg_layouts = bg_layouts.to_vec();
+        bg_layouts.push(&layout);
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &bg_layouts,
+            immediate_size: 0,
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("overlay normals"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: None,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Ok(NormalsOverlay {
+            pipeline,
+            bind_group,
+            texture,
+        })
+    }
+
+    // replaces the pixels of `img` where the camera sees something with the color of the normal
+    pub fn draw(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bind_groups: &[&wgpu::BindGroup],
+        img: &mut RgbImage,
+    ) {
+        let size = self.texture.size();
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&self.pipeline);
+            for (i, bind_group) in bind_groups.iter().enumerate() {
+                pass.set_bind_group(i as u32, *bind_group, &[]);
+            }
+            pass.set_bind_group(bind_groups.len() as u32, &self.bind_group, &[]);
+            pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(4), 1);
+        }
+
+        let downloaded = Arc::new(Mutex::new(vec![]));
+        let dl = downloaded.clone();
+        download_texture(device, &mut encoder, &self.texture, move |data| {
+            *dl.lock().unwrap() = data;
+        });
+        queue.submit([encoder.finish()]);
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        let normals = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+
+        for (p, n) in img.pixels_mut().zip(normals) {
+            if n.w > 0.0 {
+                *p = Rgb((n.xyz().clamp(Vec3::ZERO, Vec3::ONE) * 255.0)
+                    .as_u8vec3()
+                    .to_array());
+            }
+        }
+    }
+}
+
+// Draws the bounds of `roots`, which are in world space, and of the BVH nodes and primitives below
+// them down to `depth` levels. Transforms and BVH leaves have the bounds of their only child, so
+// they don't count as levels.
+pub fn draw_bounds(
+    img: &mut RgbImage,
+    scene: &Scene,
+    camera: &ProjectiveCamera,
+    roots: &[NodeId],
+    depth: u32,
+) {
+    let mut stack: Vec<_> = roots
+        .iter()
+        .map(|&node| (node, Mat4::IDENTITY, 0))
+        .collect();
+    while let Some((node, to_world, level)) = stack.pop() {
+        let children = scene.node_children(node);
+        let mut next = level;
+        if children.len() != 1 {
+            let color = Rgb(LEVEL_COLORS[level as usize % LEVEL_COLORS.len()]);
+            draw_box(
+                img,
+                camera,
+                to_world,
+                scene.node_bounds(node).corners(),
+                color,
+            );
+            next += 1;
+        }
+        if next < depth {
+            stack.extend(children.into_iter().map(|(c, m)| (c, to_world * m, next)));
+        }
+    }
+}
+
+fn draw_box(
+    img: &mut RgbImage,
+    camera: &ProjectiveCamera,
+    to_world: Mat4,
+    corners: [Vec3; 8],
+    color: Rgb<u8>,
+) {
+    let to_camera = camera.world_to_camera.m * to_world;
+    let corners = corners.map(|p| to_camera.transform_point3(p));
+    // corners differing in one bit of their index share an edge
+    for i in 0..8 {
+        for bit in [1, 2, 4] {
+            if i & bit == 0 {
+                draw_line(img, camera, corners[i], corners[i | bit], color);
+            }
+        }
+    }
+}
+
+// camera space line, cut off where it goes behind the camera
+fn draw_line(img: &mut RgbImage, camera: &ProjectiveCamera, a: Vec3, b: Vec3, color: Rgb<u8>) {
+    const NEAR: f32 = 1e-3;
+    if a.z < NEAR && b.z < NEAR {
+        return;
+    }
+    let clip = |p: Vec3, q: Vec3| match p.z < NEAR {
+        true => p.lerp(q, (NEAR - p.z) / (q.z - p.z)),
+        false => p,
+    };
+    let (a, b) = (clip(a, b), clip(b, a));
+
+    let size = Vec2::new(img.width() as f32, img.height() as f32);
+    let to_raster = |p: Vec3| {
+        let ndc = camera.ndc_to_camera.m_inv.project_point3(p).xy();
+        Vec2::new(ndc.x + 1.0, 1.0 - ndc.y) / 2.0 * size
+    };
+    let Some((a, b)) = clip_to_rect(to_raster(a), to_raster(b), size) else {
+        return;
+    };
+
+    let steps = (b - a).abs().max_element().ceil().max(1.0) as u32;
+    for s in 0..=steps {
+        let p = a.lerp(b, s as f32 / steps as f32);
+        let (x, y) = (p.x as u32, p.y as u32);
+        if x < img.width() && y < img.height() {
+            img.put_pixel(x, y, color);
+        }
+    }
+}
+
+// Liang-Barsky clipping of a segment to the image, so lines from corners just in front of the
+// camera don't take forever to draw
+fn clip_to_rect(a: Vec2, b: Vec2, size: Vec2) -> Option<(Vec2, Vec2)> {
+    if !a.is_finite() || !b.is_finite() {
+        return None;
+    }
+    let d = b - a;
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+    for (p, q) in [
+        (-d.x, a.x),
+        (d.x, size.x - a.x),
+        (-d.y, a.y),
+        (d.y, size.y - a.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+            continue;
+        }
+        let t = q / p;
+        match p < 0.0 {
+            true => t0 = t0.max(t),
+            false => t1 = t1.min(t),
+        }
+    }
+    (t0 <= t1).then(|| (a + d * t0, a + d * t1))
+}
@@ -0,0 +1,163 @@
+use std::sync::mpsc;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec2, Vec3};
+
+use crate::gpu_features::GpuFeatures;
+use crate::scene::{LightId, MaterialId, Scene};
+use crate::{download_buffer, shader, writable_storage_buffer_entry};
+
+// Traces the camera ray through a pixel and reports what it hits, for tracking down things like
+// shapes with the wrong material
+pub struct Picker {
+    pipeline: wgpu::ComputePipeline,
+    bind_group: wgpu::BindGroup,
+    result: wgpu::Buffer,
+}
+
+#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[repr(C)]
+struct PickResult {
+    hit: u32,
+    primitive: u32,
+    // ~0 if the primitive is not instanced
+    instance: u32,
+    object: u32,
+    material: MaterialId,
+    light: LightId,
+    t: f32,
+    _padding0: u32,
+    p: Vec3,
+    _padding1: u32,
+    n: Vec3,
+    _padding2: u32,
+    uv: Vec2,
+    _padding3: [u32; 2],
+}
+
+impl Picker {
+    pub fn new(
+        device: &wgpu::Device,
+        sampler: &str,
+        camera: &str,
+        gpu_features: &GpuFeatures,
+        bg_layouts: &[&wgpu::BindGroupLayout],
+    ) -> anyhow::Result<Self> {
+        let flags = [
+            ("sampler".to_owned(), sampler.to_owned()),
+            ("camera".to_owned(), camera.to_owned()),
+        ]
+        .into_iter()
+        .chain(gpu_features.shader_flags())
+        .collect();
+        let shader = shader::load_shader(device, "entrypoint/pick.wgsl", &flags)?;
+
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[writable_storage_buffer_entry(0)],
+        });
+        let result = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("pick"),
+            size: size_of::<PickResult>() as u64,
+            usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: result.as_entire_binding(),
+            }],
+        });
+
+        let mut bg_layouts = bg_layouts.to_vec();
+        bg_layouts.push(&layout);
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &bg_layouts,
+            immediate_size: size_of::<[u32; 2]>() as u32,
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("pick"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: None,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Ok(Picker {
+            pipeline,
+            bind_group,
+            result,
+        })
+    }
+
+    pub fn pick(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        bind_groups: &[&wgpu::BindGroup],
+        scene: &Scene,
+        pixel: [u32; 2],
+    ) {
+        let mut encoder = device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&self.pipeline);
+            for (i, bind_group) in bind_groups.iter().enumerate() {
+                pass.set_bind_group(i as u32, *bind_group, &[]);
+            }
+            pass.set_bind_group(bind_groups.len() as u32, &self.bind_group, &[]);
+            pass.set_immediates(0, bytemuck::bytes_of(&pixel));
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        let (send, recv) = mpsc::channel();
+        download_buffer(device, &mut encoder, &self.result, move |data| {
+            let _ = send.send(bytemuck::pod_read_unaligned::<PickResult>(data));
+        });
+        queue.submit([encoder.finish()]);
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        let Ok(result) = recv.recv() else {
+            return;
+        };
+
+        let [x, y] = pixel;
+        if result.hit == 0 {
+            println!("\rPixel {x},{y}: no hit");
+            return;
+        }
+        println!(
+            "\rPixel {x},{y}: hit at distance {:.4} at {:.4} with normal {:.3} and uv {:.3}",
+            result.t, result.p, result.n, result.uv
+        );
+        println!(
+            "  Primitive {}: {:?}",
+            result.primitive, scene.primitive_nodes[result.primitive as usize]
+        );
+        if result.instance != !0 {
+            let name = scene.instance_name(result.instance).unwrap_or("(unnamed)");
+            println!("  Instance {} of object {name}", result.instance);
+        }
+
+        let material_names: Vec<_> = scene
+            .named_materials
+            .iter()
+            .filter(|&(_, &m)| m == result.material)
+            .map(|(name, _)| name.as_str())
+            .collect();
+        println!(
+            "  Material {}: {}",
+            match material_names.is_empty() {
+                true => "(unnamed)".to_owned(),
+                false => material_names.join(", "),
+            },
+            scene.describe_material(result.material)
+        );
+        if result.light != LightId::ZERO {
+            println!("  Light: {}", scene.describe_light(result.light));
+        }
+    }
+}
@@ -0,0 +1,186 @@
+use std::fmt::Write as _;
+use std::path::PathBuf;
+
+use anyhow::{Context, bail};
+use glam::Vec3;
+use image::{Rgb, RgbImage};
+
+use crate::scene::{Scene, SpectrumId};
+use crate::spectrum::SpectrumData;
+use crate::{parse_vec3, write_atomic, xyz_to_linear_srgb};
+
+#[derive(clap::Args)]
+pub struct SpectrumOptions {
+    // a named spectrum such as `metal-Cu-eta`, `blackbody:<kelvin>`, `rgb:r,g,b` for an albedo
+    // or `illuminant:r,g,b` for an emission color, upsampled the same way as in scenes
+    #[clap(required = true)]
+    spectra: Vec<String>,
+    // `.csv` for a table of values, otherwise an image of the plot
+    #[clap(short, long, default_value = "spectrum.png")]
+    output: PathBuf,
+    // scale each spectrum in the plot to a peak of 1, for comparing the shapes of illuminants
+    // and reflectances
+    #[clap(long)]
+    normalize: bool,
+}
+
+const WAVELENGTHS: std::ops::RangeInclusive<u32> = 360..=830;
+
+// Evaluates spectra over the visible range on the CPU the same way the shaders do, for checking
+// RGB upsampling and imported spectral data
+pub fn plot_spectra(options: &SpectrumOptions, data: &SpectrumData) -> anyhow::Result<()> {
+    let mut scene = Scene::new(data);
+    let spectra = options
+        .spectra
+        .iter()
+        .map(|name| {
+            parse_spectrum(&mut scene, name).with_context(|| format!("invalid spectrum `{name}`"))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let values: Vec<Vec<f32>> = spectra
+        .iter()
+        .map(|&spectrum| {
+            WAVELENGTHS
+                .map(|wl| scene.spectrum_value(spectrum, wl as f32, &data.rgb_coeffs))
+                .collect()
+        })
+        .collect();
+
+    for (name, values) in options.spectra.iter().zip(&values) {
+        let max = values.iter().copied().fold(0.0, f32::max);
+        let peak = WAVELENGTHS.start() + values.iter().position(|&v| v == max).unwrap_or(0) as u32;
+        print!("{name}: max {max} at {peak} nm");
+        if name.starts_with("rgb:") {
+            // reflectance under D65, which should reproduce the requested color
+            let mut xyz = Vec3::ZERO;
+            let mut white_y = 0.0;
+            for (i, &v) in values.iter().enumerate() {
+                let cie = Vec3::new(data.cie_x.data[i], data.cie_y.data[i], data.cie_z.data[i]);
+                xyz += v * data.d65.data[i] * cie;
+                white_y += data.d65.data[i] * cie.y;
+            }
+            let rgb = xyz_to_linear_srgb() * (xyz / white_y);
+            print!(", reflects {:.4} {:.4} {:.4}", rgb.x, rgb.y, rgb.z);
+        }
+        println!();
+    }
+
+    let csv = options
+        .output
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("csv"));
+    let result = match csv {
+        true => {
+            let mut text = "wavelength".to_owned();
+            for name in &options.spectra {
+                write!(text, ",\"{}\"", name.replace('"', "\"\"")).unwrap();
+            }
+            text.push('\n');
+            for (i, wl) in WAVELENGTHS.enumerate() {
+                write!(text, "{wl}").unwrap();
+                for values in &values {
+                    write!(text, ",{}", values[i]).unwrap();
+                }
+                text.push('\n');
+            }
+            write_atomic(&options.output, |tmp| Ok(std::fs::write(tmp, text)?))
+        }
+        false => {
+            let img = draw_plot(&values, options.normalize);
+            write_atomic(&options.output, |tmp| Ok(img.save(tmp)?))
+        }
+    };
+    result.with_context(|| format!("failed to write {}", options.output.display()))?;
+    println!("Saved {}", options.output.display());
+    Ok(())
+}
+
+fn parse_spectrum(scene: &mut Scene, name: &str) -> anyhow::Result<SpectrumId> {
+    if let Some(temperature) = name.strip_prefix("blackbody:") {
+        let temperature: f32 = temperature.trim().parse()?;
+        if !temperature.is_finite() || temperature <= 0.0 {
+            bail!("temperature must be positive");
+        }
+        return Ok(scene.add_blackbody_spectrum(temperature, 1.0, true));
+    }
+    if let Some(rgb) = name.strip_prefix("rgb:") {
+        let rgb = parse_vec3(rgb.to_owned()).map_err(anyhow::Error::msg)?;
+        if rgb.cmplt(Vec3::ZERO).any() || rgb.cmpgt(Vec3::ONE).any() {
+            bail!("albedo components must be between 0 and 1");
+        }
+        return Ok(scene.add_rgb_albedo_spectrum(rgb));
+    }
+    if let Some(rgb) = name.strip_prefix("illuminant:") {
+        let rgb = parse_vec3(rgb.to_owned()).map_err(anyhow::Error::msg)?;
+        if rgb.cmplt(Vec3::ZERO).any() {
+            bail!("illuminant components must not be negative");
+        }
+        return Ok(scene.add_rgb_illuminant_spectrum(rgb, SpectrumId::D65));
+    }
+    match scene.named_spectra.get(name) {
+        Some(&spectrum) => Ok(spectrum),
+        None => {
+            let mut names: Vec<_> = scene.named_spectra.keys().copied().collect();
+            names.sort();
+            bail!("unknown name, expected one of {}", names.join(", "))
+        }
+    }
+}
+
+// Every spectrum on one linear scale from 0 to the largest value, with a line every 50 nm
+fn draw_plot(values: &[Vec<f32>], normalize: bool) -> RgbImage {
+    const COLORS: [[u8; 3]; 6] = [
+        [31, 119, 180],
+        [255, 127, 14],
+        [44, 160, 44],
+        [214, 39, 40],
+        [148, 103, 189],
+        [140, 86, 75],
+    ];
+    const MARGIN: u32 = 10;
+    let width = WAVELENGTHS.count() as u32 * 2 + 2 * MARGIN;
+    let height = 400 + 2 * MARGIN;
+
+    let mut img = RgbImage::from_pixel(width, height, Rgb([255; 3]));
+    for wl in WAVELENGTHS.step_by(50) {
+        let x = MARGIN + (wl - WAVELENGTHS.start()) * 2;
+        for y in MARGIN..height - MARGIN {
+            img.put_pixel(x, y, Rgb([220; 3]));
+        }
+    }
+    for x in MARGIN..width - MARGIN {
+        img.put_pixel(x, height - MARGIN, Rgb([0; 3]));
+    }
+
+    let peak = |values: &[f32]| {
+        let max = values.iter().copied().fold(0.0, f32::max);
+        match max > 0.0 {
+            true => max,
+            false => 1.0,
+        }
+    };
+    let overall_max = peak(values.concat().as_slice());
+
+    for (values, color) in values.iter().zip(COLORS.iter().cycle()) {
+        let max = match normalize {
+            true => peak(values),
+            false => overall_max,
+        };
+        let to_y = |v: f32| (height - MARGIN) as f32 - v / max * (height - 2 * MARGIN) as f32;
+        for (i, w) in values.windows(2).enumerate() {
+            let x = (MARGIN + i as u32 * 2) as f32;
+            let (y0, y1) = (to_y(w[0]), to_y(w[1]));
+            // enough steps that steep segments stay connected
+            let steps = ((y1 - y0).abs().ceil() as u32).max(2);
+            for s in 0..=steps {
+                let t = s as f32 / steps as f32;
+                let (x, y) = (x + 2.0 * t, y0 + (y1 - y0) * t);
+                if y >= 0.0 && (y as u32) < height {
+                    img.put_pixel(x as u32, y as u32, Rgb(*color));
+                }
+            }
+        }
+    }
+    img
+}
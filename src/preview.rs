@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytemuck::{Pod, Zeroable};
+use image::Rgba32FImage;
+
+use crate::{download_texture, shader};
+
+// filter passes after the prepare pass, with the taps 1, 2, 4, 8 and 16 pixels apart
+const PASSES: u32 = 5;
+
+#[derive(Copy, Clone, Zeroable, Pod)]
+#[repr(C)]
+struct Immediates {
+    mode: u32,
+    step: u32,
+}
+
+// Edge-avoiding à-trous filter of the film, run on the GPU after every pass so that previews look
+// clean after a few samples without reading the film back
+pub struct PreviewDenoiser {
+    pipeline: wgpu::ComputePipeline,
+    // the prepare pass writes the first texture, then the passes go back and forth between them
+    bind_groups: [wgpu::BindGroup; 2],
+    textures: [wgpu::Texture; 2],
+}
+
+impl PreviewDenoiser {
+    pub fn new(
+        device: &wgpu::Device,
+        mean: &wgpu::Texture,
+        variance: &wgpu::Texture,
+        features: &wgpu::Texture,
+    ) -> anyhow::Result<Self> {
+        let shader = shader::load_shader(device, "entrypoint/atrous.wgsl", &HashMap::new())?;
+
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::COMPUTE,
+            ty: wgpu::BindingType::StorageTexture {
+                access: wgpu::StorageTextureAccess::ReadWrite,
+                format: wgpu::TextureFormat::Rgba32Float,
+                view_dimension,
+            },
+            count: None,
+        };
+        let layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            label: None,
+            entries: &[
+                texture_entry(0, wgpu::TextureViewDimension::D2),
+                texture_entry(1, wgpu::TextureViewDimension::D2),
+                texture_entry(2, wgpu::TextureViewDimension::D2Array),
+                texture_entry(3, wgpu::TextureViewDimension::D2),
+                texture_entry(4, wgpu::TextureViewDimension::D2),
+            ],
+        });
+
+        let textures = [0, 1].map(|_| {
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: Some("preview"),
+                size: mean.size(),
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                format: wgpu::TextureFormat::Rgba32Float,
+                usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::COPY_SRC,
+                view_formats: &[],
+            })
+        });
+        let features = features.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let bind_groups = [0, 1].map(|dst| {
+            let mean = mean.create_view(&Default::default());
+            let variance = variance.create_view(&Default::default());
+            let src = textures[1 - dst].create_view(&Default::default());
+            let dst = textures[dst].create_view(&Default::default());
+            device.create_bind_group(&wgpu::BindGroupDescriptor {
+                label: None,
+                layout: &layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&mean),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::TextureView(&variance),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: wgpu::BindingResource::TextureView(&features),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 3,
+                        resource: wgpu::BindingResource::TextureView(&src),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 4,
+                        resource: wgpu::BindingResource::TextureView(&dst),
+                    },
+                ],
+            })
+        });
+
+        let pipeline_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: None,
+            bind_group_layouts: &[&layout],
+            immediate_size: size_of::<Immediates>() as u32,
+        });
+        let pipeline = device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+            label: Some("preview denoiser"),
+            layout: Some(&pipeline_layout),
+            module: &shader,
+            entry_point: None,
+            compilation_options: Default::default(),
+            cache: None,
+        });
+
+        Ok(PreviewDenoiser {
+            pipeline,
+            bind_groups,
+            textures,
+        })
+    }
+
+    pub fn run(&self, encoder: &mut wgpu::CommandEncoder) {
+        let size = self.textures[0].size();
+        let mut pass = encoder.begin_compute_pass(&Default::default());
+        pass.set_pipeline(&self.pipeline);
+        for i in 0..=PASSES {
+            let mode = match i {
+                0 => 0,
+                PASSES => 2,
+                _ => 1,
+            };
+            let imm = Immediates {
+                mode,
+                step: 1 << i.saturating_sub(1),
+            };
+            pass.set_bind_group(0, &self.bind_groups[i as usize % 2], &[]);
+            pass.set_immediates(0, bytemuck::bytes_of(&imm));
+            pass.dispatch_workgroups(size.width.div_ceil(8), size.height.div_ceil(4), 1);
+        }
+    }
+
+    // the filtered film in XYZ like the mean
+    pub fn download(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> Rgba32FImage {
+        let output = &self.textures[PASSES as usize % 2];
+        let downloaded = Arc::new(Mutex::new(vec![]));
+        let dl = downloaded.clone();
+        let mut encoder = device.create_command_encoder(&Default::default());
+        download_texture(device, &mut encoder, output, move |data| {
+            *dl.lock().unwrap() = data;
+        });
+        queue.submit([encoder.finish()]);
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        let data = Arc::into_inner(downloaded).unwrap().into_inner().unwrap();
+        let size = output.size();
+        Rgba32FImage::from_vec(
+            size.width,
+            size.height,
+            data.iter().flat_map(|v| v.to_array()).collect(),
+        )
+        .unwrap()
+    }
+}
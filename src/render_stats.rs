@@ -0,0 +1,69 @@
+use std::sync::{Arc, Mutex};
+
+use crate::download_buffer;
+
+// in the order of the shaders' STAT_ constants
+const NAMES: [&str; 5] = [
+    "Rays traced",
+    "BVH nodes visited",
+    "Triangle tests",
+    "Shadow rays",
+    "Texture lookups",
+];
+
+// Totals of the work the megakernel does, counted in the shaders with the `stats` flag on, as
+// 64-bit counts split into pairs of words
+pub struct RenderStats {
+    pub buffer: wgpu::Buffer,
+}
+
+impl RenderStats {
+    // also bound in place of the counters when they're off, so the layouts stay the same
+    pub fn new(device: &wgpu::Device) -> Self {
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("render stats"),
+            size: (NAMES.len() * 8) as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        RenderStats { buffer }
+    }
+
+    pub fn clear(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.buffer, 0, None);
+    }
+
+    pub fn download(&self, device: &wgpu::Device, queue: &wgpu::Queue) -> [u64; NAMES.len()] {
+        let mut encoder = device.create_command_encoder(&Default::default());
+        let counts = Arc::new(Mutex::new([0; NAMES.len()]));
+        let downloaded = counts.clone();
+        download_buffer(device, &mut encoder, &self.buffer, move |data| {
+            let words: &[u32] = bytemuck::cast_slice(data);
+            for (count, pair) in downloaded.lock().unwrap().iter_mut().zip(words.chunks(2)) {
+                *count = pair[0] as u64 | (pair[1] as u64) << 32;
+            }
+        });
+        queue.submit([encoder.finish()]);
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+        *counts.lock().unwrap()
+    }
+
+    pub fn print(counts: [u64; NAMES.len()], samples: u32) {
+        let rays = counts[0].max(1) as f64;
+        println!("Render stats");
+        for (name, count) in NAMES.into_iter().zip(counts) {
+            print!(
+                "  {name:<19} {count:>16} ({:.1} / sample",
+                count as f64 / samples.max(1) as f64
+            );
+            match name {
+                "BVH nodes visited" | "Triangle tests" => {
+                    println!(", {:.2} / ray)", count as f64 / rays)
+                }
+                _ => println!(")"),
+            }
+        }
+    }
+}
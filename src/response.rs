@@ -0,0 +1,140 @@
+use std::path::{Path, PathBuf};
+
+use glam::Vec3;
+
+use crate::lens::Lens;
+use crate::options::splitmix64;
+
+// Camera response applied when encoding linear sRGB for LDR images
+
+#[derive(Clone)]
+pub enum ResponseCurve {
+    Srgb,
+    // log2(1 + a x) / log2(1 + a)
+    Log(f32),
+    // measured curve, with rows sampled uniformly over linear values in [0, 1]
+    Lut { path: PathBuf, table: Vec<Vec3> },
+}
+
+#[derive(Clone)]
+pub struct Response {
+    pub curve: ResponseCurve,
+    // standard deviation of the grain at mid gray, in encoded values
+    pub grain: f32,
+    pub grain_seed: u64,
+    // lens effects applied to the image instead of the camera rays
+    pub lens: Option<Lens>,
+}
+
+impl ResponseCurve {
+    // `srgb`, `log`, `log:<a>` or the path of a LUT file
+    pub fn parse(s: String) -> Result<Self, String> {
+        match s.as_str() {
+            "srgb" => return Ok(ResponseCurve::Srgb),
+            "log" => return Ok(ResponseCurve::Log(64.0)),
+            _ => {}
+        }
+        if let Some(a) = s.strip_prefix("log:") {
+            let a = a.parse::<f32>().map_err(|e| e.to_string())?;
+            if a.is_nan() || a <= 0.0 {
+                return Err(format!("invalid log curve `{s}`"));
+            }
+            return Ok(ResponseCurve::Log(a));
+        }
+        let path = PathBuf::from(s);
+        let table = load_lut(&path)?;
+        Ok(ResponseCurve::Lut { path, table })
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            ResponseCurve::Srgb => "srgb".to_owned(),
+            ResponseCurve::Log(a) => format!("log:{a}"),
+            ResponseCurve::Lut { path, .. } => path.display().to_string(),
+        }
+    }
+
+    fn apply(&self, rgb: Vec3) -> Vec3 {
+        match self {
+            ResponseCurve::Srgb => {
+                let low = rgb * 12.92;
+                let high = rgb.powf(1.0 / 2.4) * 1.055 - 0.055;
+                Vec3::select(rgb.cmplt(Vec3::splat(0.0031308)), low, high)
+            }
+            ResponseCurve::Log(a) => {
+                (rgb.max(Vec3::ZERO) * a + 1.0).map(f32::log2) / (1.0 + a).log2()
+            }
+            ResponseCurve::Lut { table, .. } => {
+                let x = rgb.clamp(Vec3::ZERO, Vec3::ONE) * (table.len() - 1) as f32;
+                let i = x.as_uvec3().min(glam::UVec3::splat(table.len() as u32 - 2));
+                let t = x - i.as_vec3();
+                let lookup = |c: usize| {
+                    let i = i[c] as usize;
+                    table[i][c] + (table[i + 1][c] - table[i][c]) * t[c]
+                };
+                Vec3::new(lookup(0), lookup(1), lookup(2))
+            }
+        }
+    }
+}
+
+impl Response {
+    pub fn encode(&self, rgb: Vec3, x: u32, y: u32) -> Vec3 {
+        let v = self.curve.apply(rgb).clamp(Vec3::ZERO, Vec3::ONE);
+        if self.grain <= 0.0 {
+            return v;
+        }
+
+        // gaussian noise from a hash of the pixel position, so reruns produce the same grain
+        let mut state = self.grain_seed ^ ((y as u64) << 32 | x as u64);
+        let mut next = || (splitmix64(&mut state) >> 40) as f32 / (1 << 24) as f32;
+        let (u1, u2) = (1.0 - next(), next());
+        let n = (-2.0 * u1.ln()).sqrt() * (std::f32::consts::TAU * u2).cos();
+
+        // strongest in the midtones and fading out towards black and white
+        let amount = 2.0 * (v * (1.0 - v)).max(Vec3::ZERO).powf(0.5);
+        v + amount * self.grain * n
+    }
+}
+
+impl Default for Response {
+    fn default() -> Self {
+        Self {
+            curve: ResponseCurve::Srgb,
+            grain: 0.0,
+            grain_seed: 0,
+            lens: None,
+        }
+    }
+}
+
+// One row per line with either one value for all channels or three values for red, green and
+// blue. Lines starting with `#` are comments.
+fn load_lut(path: &Path) -> Result<Vec<Vec3>, String> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+
+    let mut table = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let values = line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|v| !v.is_empty())
+            .map(|v| v.parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("in {}: {e}", path.display()))?;
+        match values[..] {
+            [v] => table.push(Vec3::splat(v)),
+            [r, g, b] => table.push(Vec3::new(r, g, b)),
+            _ => return Err(format!("in {}: invalid row `{line}`", path.display())),
+        }
+    }
+
+    if table.len() < 2 {
+        return Err(format!("{} needs at least 2 rows", path.display()));
+    }
+    Ok(table)
+}
@@ -0,0 +1,148 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+
+use anyhow::Context;
+
+use crate::{download_buffer, write_atomic};
+
+const MAGIC: &[u8; 8] = b"PBRSMPLS";
+const VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 8 + 3 * 4;
+
+// pixel x and y, filter offset x and y, XYZ radiance and path length
+pub const RECORD_SIZE: usize = 32;
+// the record array is aligned to 16 bytes after the counter
+const RECORDS_OFFSET: usize = 16;
+// downloads waiting to be written before the render waits for the disk
+const QUEUED_PASSES: usize = 4;
+
+enum Message {
+    Records(Vec<u8>),
+    Restart,
+}
+
+// Every sample's contribution streamed to a file as it is rendered, for trying out reconstruction
+// and denoising on real sample data. The file is the magic, version, film width and height, then
+// records of little-endian 4 byte words in the order of RECORD_SIZE.
+pub struct SampleDump {
+    pub buffer: wgpu::Buffer,
+    capacity: u64,
+    sender: Option<mpsc::SyncSender<Message>>,
+    writer: Option<JoinHandle<anyhow::Result<u64>>>,
+    // records lost to a pass writing more samples than the buffer holds
+    dropped: Arc<AtomicU64>,
+    path: PathBuf,
+}
+
+impl SampleDump {
+    // `capacity` is the most samples taken in one pass
+    pub fn new(
+        device: &wgpu::Device,
+        path: &Path,
+        width: u32,
+        height: u32,
+        capacity: u64,
+    ) -> anyhow::Result<Self> {
+        let buffer = Self::make_buffer(device, capacity);
+
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_PASSES);
+        let path = path.to_owned();
+        let writer_path = path.clone();
+        let writer = std::thread::spawn(move || {
+            let mut records = 0;
+            write_atomic(&writer_path, |tmp| {
+                let mut w = BufWriter::new(File::create(tmp)?);
+                w.write_all(MAGIC)?;
+                for word in [VERSION, width, height] {
+                    w.write_all(&word.to_le_bytes())?;
+                }
+                for message in receiver {
+                    match message {
+                        Message::Records(data) => {
+                            for word in data.chunks_exact(4) {
+                                let word = u32::from_ne_bytes(word.try_into().unwrap());
+                                w.write_all(&word.to_le_bytes())?;
+                            }
+                            records += (data.len() / RECORD_SIZE) as u64;
+                        }
+                        Message::Restart => {
+                            w.seek(SeekFrom::Start(HEADER_SIZE))?;
+                            w.get_ref().set_len(HEADER_SIZE)?;
+                            records = 0;
+                        }
+                    }
+                }
+                w.flush()?;
+                Ok(())
+            })?;
+            Ok(records)
+        });
+
+        Ok(SampleDump {
+            buffer,
+            capacity,
+            sender: Some(sender),
+            writer: Some(writer),
+            dropped: Arc::new(AtomicU64::new(0)),
+            path,
+        })
+    }
+
+    // bound in place of the record buffer when samples aren't being dumped
+    pub fn make_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("sample records"),
+            size: RECORDS_OFFSET as u64 + capacity.max(1) * RECORD_SIZE as u64,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    // resets the counter, before the passes of a sample
+    pub fn begin(&self, encoder: &mut wgpu::CommandEncoder) {
+        encoder.clear_buffer(&self.buffer, 0, Some(RECORDS_OFFSET as u64));
+    }
+
+    // queues the records of the passes since `begin` to be written once they are downloaded
+    pub fn download(&self, device: &wgpu::Device, encoder: &mut wgpu::CommandEncoder) {
+        let sender = self.sender.clone().unwrap();
+        let dropped = self.dropped.clone();
+        let capacity = self.capacity;
+        download_buffer(device, encoder, &self.buffer, move |data| {
+            let count = u32::from_ne_bytes(data[..4].try_into().unwrap()) as u64;
+            dropped.fetch_add(count.saturating_sub(capacity), Ordering::Relaxed);
+            let end = RECORDS_OFFSET + count.min(capacity) as usize * RECORD_SIZE;
+            // the writer only goes away on an error, which `finish` reports
+            let _ = sender.send(Message::Records(data[RECORDS_OFFSET..end].to_vec()));
+        });
+    }
+
+    // drops the samples written so far
+    pub fn restart(&self) {
+        let _ = self.sender.as_ref().unwrap().send(Message::Restart);
+    }
+
+    // waits for the queued records to be written, returning how many there are
+    pub fn finish(mut self) -> anyhow::Result<u64> {
+        drop(self.sender.take());
+        let records = self
+            .writer
+            .take()
+            .unwrap()
+            .join()
+            .unwrap()
+            .with_context(|| format!("failed to write {}", self.path.display()))?;
+        let dropped = self.dropped.load(Ordering::Relaxed);
+        if dropped > 0 {
+            println!("Warning: {dropped} samples did not fit in the record buffer and were lost");
+        }
+        Ok(records)
+    }
+}
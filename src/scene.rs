@@ -4,10 +4,10 @@ use std::io::BufRead;
 use std::io::BufReader;
 use std::io::Read;
 use std::num::NonZero;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
 
-use bytemuck::NoUninit;
-use glam::{BVec3, Vec3};
+use glam::{BVec3, Vec2, Vec3, Vec4};
 use image::DynamicImage;
 use image::ImageBuffer;
 use image::Luma;
@@ -15,40 +15,72 @@ use image::Pixel;
 use image::Rgb32FImage;
 use image::Rgba32FImage;
 use image::RgbaImage;
-use wgpu::util::DeviceExt;
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
+use crate::options::BvhQuality;
 use crate::spectrum::SpectrumData;
-use crate::storage_buffer_entry;
+use crate::{AnimatedTransform, storage_buffer_entry};
 
+mod compress;
+mod gpu;
 mod light;
 mod light_sampler;
 mod material;
 mod node;
 mod other;
+mod packed;
 mod shapes;
 mod spectra;
+mod spill;
 mod texture;
 
+pub use self::gpu::*;
 pub use self::light::*;
 pub use self::light_sampler::*;
 pub use self::material::*;
 pub use self::node::*;
 pub use self::other::*;
+pub use self::packed::*;
 pub use self::shapes::*;
 pub use self::spectra::*;
+pub use self::spill::*;
 pub use self::texture::*;
 
 type Luma32FImage = ImageBuffer<Luma<f32>, Vec<f32>>;
 
-#[derive(Default)]
+// Serialized for the scene cache, leaving out what is made for uploading or only used while loading
+#[derive(Default, Serialize, Deserialize)]
 pub struct Scene {
     pub spheres: Vec<Sphere>,
-    pub triangles: Vec<Triangle>,
+    pub triangles: SpillVec<Triangle>,
+    pub splats: Vec<Splat>,
+    pub sdfs: Vec<Sdf>,
+    pub sdf_nodes: Vec<SdfNode>,
+    pub csgs: Vec<Csg>,
+    pub csg_nodes: Vec<CsgNode>,
+    pub heightfields: Vec<Heightfield>,
 
-    pub triangle_vertices: Vec<TriVertex>,
+    pub triangle_vertices: SpillVec<TriVertex>,
+    // vertices merged into an identical one as meshes were loaded
+    pub welded_vertices: usize,
+    // round the vertices of meshes added from now on to a grid of their own, to upload them packed
+    pub compress_geometry: bool,
+    // a grid for each mesh, when geometry is compressed
+    pub position_grids: Vec<PositionGrid>,
+    // made from the vertices before uploading
+    #[serde(skip)]
+    pub packed_vertices: SpillVec<PackedVertex>,
 
     pub bvh_nodes: Vec<BvhNode>,
+    // for the BVHs built from now on
+    #[serde(skip)]
+    pub bvh_quality: BvhQuality,
+    // made from the BVH nodes before uploading
+    #[serde(skip)]
+    pub wide_bvh: WideBvh,
     pub transform_nodes: Vec<TransformNode>,
+    pub animated_transforms: Vec<AnimatedTransform>,
     pub primitive_nodes: Vec<PrimitiveNode>,
 
     pub constant_tex: Vec<ConstantTexture>,
@@ -58,8 +90,19 @@ pub struct Scene {
     pub mix_tex: Vec<MixTexture>,
     pub checkerboard_tex: Vec<CheckerboardTexture>,
     pub conductor_refl_tex: Vec<ConductorReflTexture>,
+    pub wireframe_tex: Vec<WireframeTexture>,
+    pub uv_checker_tex: Vec<UvCheckerTexture>,
 
     pub images: Vec<ImageData>,
+    pub image_paths: Vec<PathBuf>,
+    // keyed on canonical path and the float and no_gamma flags
+    pub image_cache: HashMap<(PathBuf, bool, bool), u32>,
+    // number of loads served from the cache and the image data they would have added
+    pub image_cache_hits: usize,
+    pub image_cache_saved: usize,
+    // images still being decoded on the thread pool
+    #[serde(skip)]
+    pub pending_images: Vec<PendingImage>,
 
     pub diffuse_mat: Vec<DiffuseMaterial>,
     pub diffuse_transmit_mat: Vec<DiffuseTransmitMaterial>,
@@ -68,11 +111,14 @@ pub struct Scene {
     pub thin_dielectric_mat: Vec<ThinDielectricMaterial>,
     pub metallic_workflow_mat: Vec<MetallicWorkflowMaterial>,
     pub mix_mat: Vec<MixMaterial>,
+    pub measured_mat: Vec<MeasuredMaterial>,
+    pub principled_mat: Vec<PrincipledMaterial>,
 
     pub infinite_lights: Vec<LightId>,
 
     pub uniform_lights: Vec<UniformLight>,
     pub image_lights: Vec<ImageLight>,
+    pub portal_lights: Vec<PortalLight>,
     pub area_lights: Vec<AreaLight>,
 
     pub table_spectra: Vec<TableSpectrum>,
@@ -90,9 +136,22 @@ pub struct Scene {
     pub power_light_sampler_data: Vec<PlsAliasBucket>,
 
     pub root: Option<NodeId>,
+    // once instances have moved
+    #[serde(skip)]
+    pub top_level: Option<TopLevel>,
     pub root_ls: Option<LightSamplerId>,
 
+    #[serde(skip)]
     pub named_spectra: HashMap<&'static str, SpectrumId>,
+    // names from the scene file, for editing the scene while it renders
+    pub named_materials: HashMap<String, MaterialId>,
+    // image of each image texture
+    pub named_images: HashMap<String, u32>,
+    // top level instances of each object
+    pub named_instances: HashMap<String, Vec<NodeId>>,
+
+    #[serde(skip)]
+    pub dirty: Dirty,
 }
 
 pub enum ImageData {
@@ -100,6 +159,258 @@ pub enum ImageData {
     FloatRgb(Rgba32FImage),
     Srgb(RgbaImage),
     UnormRgb(RgbaImage),
+    Compressed(CompressedImage),
+}
+
+// Images are all decoded by the end of loading, and only compressed after it
+#[derive(Serialize, Deserialize)]
+enum CachedImage<F, U> {
+    Float(u32, u32, F),
+    FloatRgb(u32, u32, F),
+    Srgb(u32, u32, U),
+    UnormRgb(u32, u32, U),
+}
+
+impl Serialize for ImageData {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        let image: CachedImage<&[f32], &[u8]> = match self {
+            ImageData::Float(img) => CachedImage::Float(img.width(), img.height(), img.as_raw()),
+            ImageData::FloatRgb(img) => {
+                CachedImage::FloatRgb(img.width(), img.height(), img.as_raw())
+            }
+            ImageData::Srgb(img) => CachedImage::Srgb(img.width(), img.height(), img.as_raw()),
+            ImageData::UnormRgb(img) => {
+                CachedImage::UnormRgb(img.width(), img.height(), img.as_raw())
+            }
+            ImageData::Compressed(_) => {
+                return Err(serde::ser::Error::custom(
+                    "compressed images can't be cached",
+                ));
+            }
+        };
+        image.serialize(s)
+    }
+}
+
+impl<'de> Deserialize<'de> for ImageData {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let size_mismatch =
+            |w, h| serde::de::Error::custom(format!("image data doesn't match its {w}x{h} size"));
+        Ok(match CachedImage::<Vec<f32>, Vec<u8>>::deserialize(d)? {
+            CachedImage::Float(w, h, data) => ImageData::Float(
+                ImageBuffer::from_raw(w, h, data).ok_or_else(|| size_mismatch(w, h))?,
+            ),
+            CachedImage::FloatRgb(w, h, data) => ImageData::FloatRgb(
+                ImageBuffer::from_raw(w, h, data).ok_or_else(|| size_mismatch(w, h))?,
+            ),
+            CachedImage::Srgb(w, h, data) => ImageData::Srgb(
+                ImageBuffer::from_raw(w, h, data).ok_or_else(|| size_mismatch(w, h))?,
+            ),
+            CachedImage::UnormRgb(w, h, data) => ImageData::UnormRgb(
+                ImageBuffer::from_raw(w, h, data).ok_or_else(|| size_mismatch(w, h))?,
+            ),
+        })
+    }
+}
+
+// Image decoding in the background. Its slot in `images` holds a placeholder until resolved.
+pub struct PendingImage {
+    id: u32,
+    // cache hits on the image before its size was known
+    hits: usize,
+    result: mpsc::Receiver<image::ImageResult<ImageData>>,
+}
+
+// Block compressed image with its mip chain
+pub struct CompressedImage {
+    format: wgpu::TextureFormat,
+    width: u32,
+    height: u32,
+    levels: Vec<Vec<u8>>,
+}
+
+impl ImageData {
+    // stands in for images which are still decoding or failed to decode
+    fn placeholder(float: bool) -> Self {
+        match float {
+            true => ImageData::Float(ImageBuffer::from_pixel(1, 1, Luma([1.0]))),
+            false => ImageData::Srgb(RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 255, 255]))),
+        }
+    }
+
+    fn dimensions(&self) -> (u32, u32) {
+        match self {
+            ImageData::Float(img) => img.dimensions(),
+            ImageData::FloatRgb(img) => img.dimensions(),
+            ImageData::UnormRgb(img) => img.dimensions(),
+            ImageData::Srgb(img) => img.dimensions(),
+            ImageData::Compressed(img) => (img.width, img.height),
+        }
+    }
+
+    // writes the luminance of row `y` into `out`, one value per pixel
+    fn luminance_row(&self, y: u32, out: &mut [f32]) {
+        if let ImageData::Compressed(img) = self {
+            // a block at a time, in the range of the image it was compressed from
+            for (bx, texels) in (0..).zip(out.chunks_mut(4)) {
+                let block = img.block(bx, y / 4);
+                for (o, t) in texels.iter_mut().zip(&block[(y % 4 * 4) as usize..]) {
+                    *o = t.truncate().dot(Vec3::new(0.2126, 0.7152, 0.0722));
+                }
+            }
+            return;
+        }
+        let xs = (0..).zip(out);
+        match self {
+            ImageData::Float(img) => xs.for_each(|(x, o)| *o = img.get_pixel(x, y).0[0]),
+            ImageData::FloatRgb(img) => {
+                xs.for_each(|(x, o)| *o = img.get_pixel(x, y).to_luma().0[0]);
+            }
+            ImageData::Srgb(img) | ImageData::UnormRgb(img) => {
+                xs.for_each(|(x, o)| *o = img.get_pixel(x, y).to_luma().0[0] as f32);
+            }
+            ImageData::Compressed(_) => unreachable!(),
+        }
+    }
+
+    // CPU counterpart of `texture_image_sample` in texture.wgsl at full resolution, as linear values
+    fn sample(&self, wrap: WrapMode, st: Vec2) -> Vec4 {
+        let (width, height) = self.dimensions();
+        let uv = Vec2::new(st.x, 1.0 - st.y);
+        if matches!(wrap, WrapMode::Black)
+            && (uv.cmplt(Vec2::ZERO).any() || uv.cmpgt(Vec2::ONE).any())
+        {
+            return Vec4::ZERO;
+        }
+        let texel = |x: i64, y: i64| {
+            let (x, y) = match wrap {
+                WrapMode::Repeat => (x.rem_euclid(width as i64), y.rem_euclid(height as i64)),
+                _ => (x.clamp(0, width as i64 - 1), y.clamp(0, height as i64 - 1)),
+            };
+            let (x, y) = (x as u32, y as u32);
+            match self {
+                ImageData::Float(img) => Vec4::splat(img.get_pixel(x, y).0[0]),
+                ImageData::FloatRgb(img) => Vec4::from_array(img.get_pixel(x, y).0),
+                ImageData::UnormRgb(img) => {
+                    Vec4::from_array(img.get_pixel(x, y).0.map(|v| v as f32 / 255.0))
+                }
+                ImageData::Srgb(img) => {
+                    let [r, g, b, a] = img.get_pixel(x, y).0.map(|v| v as f32 / 255.0);
+                    Vec4::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a)
+                }
+                ImageData::Compressed(img) => {
+                    let texel = img.block(x / 4, y / 4)[(y % 4 * 4 + x % 4) as usize];
+                    match img.format {
+                        wgpu::TextureFormat::Bc7RgbaUnormSrgb => {
+                            let [r, g, b, a] = (texel / 255.0).to_array();
+                            Vec4::new(srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a)
+                        }
+                        wgpu::TextureFormat::Bc7RgbaUnorm => texel / 255.0,
+                        _ => texel,
+                    }
+                }
+            }
+        };
+        let p = uv * Vec2::new(width as f32, height as f32) - 0.5;
+        let (x, y) = (p.x.floor() as i64, p.y.floor() as i64);
+        let f = p - p.floor();
+        let top = texel(x, y).lerp(texel(x + 1, y), f.x);
+        let bottom = texel(x, y + 1).lerp(texel(x + 1, y + 1), f.x);
+        top.lerp(bottom, f.y)
+    }
+
+    // uploaded size including the mip chain
+    fn gpu_size(&self) -> usize {
+        if let ImageData::Compressed(img) = self {
+            return img.levels.iter().map(Vec::len).sum();
+        }
+        let (mut width, mut height) = self.dimensions();
+        let texel_size = self.size() / (width * height) as usize;
+        let mut size = 0;
+        loop {
+            size += (width * height) as usize * texel_size;
+            if width == 1 && height == 1 {
+                return size;
+            }
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+        }
+    }
+
+    fn resize(&self, width: u32, height: u32) -> ImageData {
+        use image::imageops::{FilterType, resize};
+        match self {
+            ImageData::Float(img) => {
+                ImageData::Float(resize(img, width, height, FilterType::Triangle))
+            }
+            ImageData::FloatRgb(img) => {
+                ImageData::FloatRgb(resize(img, width, height, FilterType::Triangle))
+            }
+            ImageData::UnormRgb(img) => {
+                ImageData::UnormRgb(resize(img, width, height, FilterType::Triangle))
+            }
+            ImageData::Srgb(img) => {
+                // filter in linear space, as for mips
+                let linear = Rgba32FImage::from_fn(img.width(), img.height(), |x, y| {
+                    let [r, g, b, a] = img.get_pixel(x, y).0.map(|v| v as f32 / 255.0);
+                    image::Rgba([srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), a])
+                });
+                let linear = resize(&linear, width, height, FilterType::Triangle);
+                ImageData::Srgb(RgbaImage::from_fn(width, height, |x, y| {
+                    let [r, g, b, a] = linear.get_pixel(x, y).0;
+                    let rgba = [linear_to_srgb(r), linear_to_srgb(g), linear_to_srgb(b), a];
+                    image::Rgba(rgba.map(|v| (v.clamp(0.0, 1.0) * 255.0).round() as u8))
+                }))
+            }
+            ImageData::Compressed(img) => {
+                // drop mip levels, as long as the new top level is still a whole number of blocks
+                let mut img = CompressedImage {
+                    levels: img.levels.clone(),
+                    ..*img
+                };
+                while (img.width > width || img.height > height) && img.can_drop_level() {
+                    img.levels.remove(0);
+                    img.width /= 2;
+                    img.height /= 2;
+                }
+                ImageData::Compressed(img)
+            }
+        }
+    }
+
+    fn can_downscale(&self) -> bool {
+        match self {
+            ImageData::Compressed(img) => img.can_drop_level(),
+            _ => self.dimensions() != (1, 1),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            ImageData::Float(img) => std::mem::size_of_val(img.as_raw().as_slice()),
+            ImageData::FloatRgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
+            ImageData::UnormRgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
+            ImageData::Srgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
+            ImageData::Compressed(img) => img.levels[0].len(),
+        }
+    }
+}
+
+impl CompressedImage {
+    fn can_drop_level(&self) -> bool {
+        self.levels.len() > 1 && self.width.is_multiple_of(8) && self.height.is_multiple_of(8)
+    }
+
+    // texels of block (bx, by) of the full resolution level, with the values of the image it was
+    // compressed from
+    fn block(&self, bx: u32, by: u32) -> [Vec4; 16] {
+        let i = (by * self.width.div_ceil(4) + bx) as usize * 16;
+        let block = &self.levels[0][i..i + 16];
+        match self.format {
+            wgpu::TextureFormat::Bc6hRgbUfloat => compress::decode_bc6h(block),
+            _ => compress::decode_bc7(block),
+        }
+    }
 }
 
 impl Scene {
@@ -124,11 +435,27 @@ impl Scene {
         println!("Shapes");
         println!("  Spheres           {}", human_size_of(&self.spheres));
         println!("  Triangles         {}", human_size_of(&self.triangles));
+        println!("  Splats            {}", human_size_of(&self.splats));
+        println!("  SDFs              {}", human_size_of(&self.sdfs));
+        println!("  SDF nodes         {}", human_size_of(&self.sdf_nodes));
+        println!("  CSGs              {}", human_size_of(&self.csgs));
+        println!("  CSG nodes         {}", human_size_of(&self.csg_nodes));
+        println!("  Heightfields      {}", human_size_of(&self.heightfields));
         println!("  Tri verts         {}", human_size_of(&self.triangle_vertices));
+        println!("  Packed verts      {}", human_size_of(&self.packed_vertices));
+        println!("  Position grids    {}", human_size_of(&self.position_grids));
+        println!(
+            "  Weld saved        {} ({} verts)",
+            human_size(self.welded_vertices * size_of::<TriVertex>()),
+            self.welded_vertices
+        );
         println!("Scene geometry");
         println!("  Primitives        {}", human_size_of(&self.primitive_nodes));
         println!("  Transforms        {}", human_size_of(&self.transform_nodes));
+        println!("  Animated          {}", human_size_of(&self.animated_transforms));
         println!("  BVH               {}", human_size_of(&self.bvh_nodes));
+        println!("  Wide BVH          {}", human_size_of(&self.wide_bvh.nodes));
+        println!("  Leaf prims        {}", human_size_of(&self.wide_bvh.leaf_primitives));
         println!("Texture Metadata");
         println!("  Constant          {}", human_size_of(&self.constant_tex));
         println!("  Float image       {}", human_size_of(&self.image_float_tex));
@@ -137,12 +464,15 @@ impl Scene {
         println!("  Mix               {}", human_size_of(&self.mix_tex));
         println!("  Checkerboard      {}", human_size_of(&self.mix_tex));
         println!("  Conductor Refl    {}", human_size_of(&self.conductor_refl_tex));
-        println!("  Image data        {}", human_size(self.images.iter().map(|img| match img {
-            ImageData::Float(img) => std::mem::size_of_val(img.as_raw().as_slice()),
-            ImageData::FloatRgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
-            ImageData::UnormRgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
-            ImageData::Srgb(img) => std::mem::size_of_val(img.as_raw().as_slice()),
-        }).sum()));
+        println!("  Wireframe         {}", human_size_of(&self.wireframe_tex));
+        println!("  UV Checker        {}", human_size_of(&self.uv_checker_tex));
+        println!("  Image data        {}", human_size(self.images.iter().map(ImageData::size).sum()));
+        println!("  Image dedup saved {} ({} loads)", human_size(self.image_cache_saved), self.image_cache_hits);
+        println!("  Image GPU memory  {}", human_size(self.images.iter().map(ImageData::gpu_size).sum()));
+        for (img, path) in self.images.iter().zip(&self.image_paths) {
+            let (width, height) = img.dimensions();
+            println!("    {} {width}x{height} {}", human_size(img.gpu_size()), path.display());
+        }
         println!("Materials");
         println!("  Diffuse           {}", human_size_of(&self.diffuse_mat));
         println!("  Diffuse Transmit  {}", human_size_of(&self.diffuse_transmit_mat));
@@ -151,9 +481,12 @@ impl Scene {
         println!("  Thin Dielectric   {}", human_size_of(&self.thin_dielectric_mat));
         println!("  Metallic Workflow {}", human_size_of(&self.metallic_workflow_mat));
         println!("  Mix               {}", human_size_of(&self.mix_mat));
+        println!("  Measured          {}", human_size_of(&self.measured_mat));
+        println!("  Principled        {}", human_size_of(&self.principled_mat));
         println!("Lights");
         println!("  Inf Uniform       {}", human_size_of(&self.uniform_lights));
         println!("  Inf Image         {}", human_size_of(&self.image_lights));
+        println!("  Inf Portal        {}", human_size_of(&self.portal_lights));
         println!("  Area              {}", human_size_of(&self.area_lights));
         println!("  Inf Light List    {}", human_size_of(&self.infinite_lights));
         println!("Light Samplers");
@@ -161,6 +494,12 @@ impl Scene {
         println!("  Uniform Data      {}", human_size_of(&self.uniform_light_sampler_data));
         println!("  Power             {}", human_size_of(&self.power_light_samplers));
         println!("  Power Data        {}", human_size_of(&self.power_light_sampler_data));
+        for h in self.power_light_sampler_health() {
+            println!("  Power entropy     {:.2} bits ({:.1} effective of {} lights)", h.entropy, h.entropy.exp2(), h.lights);
+            println!("  Power min q       {:.4} (max relative alias error {:.1e})", h.min_q, h.max_error);
+            println!("  Zero-power lights {}", h.zero_power);
+            println!("  Unsampled lights  {}", h.unsampled);
+        }
         println!("Spectra");
         println!("  Table             {}", human_size_of(&self.table_spectra));
         println!("  Constant          {}", human_size_of(&self.constant_spectra));
@@ -178,10 +517,19 @@ impl Scene {
                 storage_buffer_entry(0),
                 storage_buffer_entry(1),
                 storage_buffer_entry(2),
+                storage_buffer_entry(3),
+                storage_buffer_entry(4),
+                storage_buffer_entry(5),
+                storage_buffer_entry(6),
+                storage_buffer_entry(7),
+                storage_buffer_entry(8),
+                storage_buffer_entry(9),
                 storage_buffer_entry(32),
                 storage_buffer_entry(33),
                 storage_buffer_entry(34),
                 storage_buffer_entry(35),
+                storage_buffer_entry(36),
+                storage_buffer_entry(37),
                 storage_buffer_entry(64),
                 storage_buffer_entry(66),
                 storage_buffer_entry(67),
@@ -201,6 +549,8 @@ impl Scene {
                 storage_buffer_entry(70),
                 storage_buffer_entry(71),
                 storage_buffer_entry(72),
+                storage_buffer_entry(73),
+                storage_buffer_entry(74),
                 storage_buffer_entry(96),
                 storage_buffer_entry(97),
                 storage_buffer_entry(98),
@@ -208,10 +558,13 @@ impl Scene {
                 storage_buffer_entry(100),
                 storage_buffer_entry(101),
                 storage_buffer_entry(102),
+                storage_buffer_entry(103),
+                storage_buffer_entry(104),
                 storage_buffer_entry(128),
                 storage_buffer_entry(129),
                 storage_buffer_entry(130),
                 storage_buffer_entry(131),
+                storage_buffer_entry(132),
                 storage_buffer_entry(160),
                 storage_buffer_entry(161),
                 storage_buffer_entry(162),
@@ -228,229 +581,216 @@ impl Scene {
         })
     }
 
-    pub fn make_bind_group(
-        &self,
-        device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        layout: &wgpu::BindGroupLayout,
-    ) -> wgpu::BindGroup {
-        let spheres = make_buffer(device, &self.spheres);
-        let triangles = make_buffer(device, &self.triangles);
-
-        let triangle_vertices = make_buffer(device, &self.triangle_vertices);
-
-        let bvh = make_buffer(device, &self.bvh_nodes);
-        let transform = make_buffer(device, &self.transform_nodes);
-        let primitive = make_buffer(device, &self.primitive_nodes);
-
-        let constant_tex = make_buffer(device, &self.constant_tex);
-        let image_float_tex = make_buffer(device, &self.image_float_tex);
-        let image_rgb_tex = make_buffer(device, &self.image_rgb_tex);
-        let scale_tex = make_buffer(device, &self.scale_tex);
-        let mix_tex = make_buffer(device, &self.mix_tex);
-        let checkerboard_tex = make_buffer(device, &self.checkerboard_tex);
-        let conductor_refl_tex = make_buffer(device, &self.conductor_refl_tex);
-
-        let diffuse_mat = make_buffer(device, &self.diffuse_mat);
-        let diffuse_transmit_mat = make_buffer(device, &self.diffuse_transmit_mat);
-        let conductor_mat = make_buffer(device, &self.conductor_mat);
-        let dielectric_mat = make_buffer(device, &self.dielectric_mat);
-        let thin_dielectric_mat = make_buffer(device, &self.thin_dielectric_mat);
-        let metallic_workflow_mat = make_buffer(device, &self.metallic_workflow_mat);
-        let mix_mat = make_buffer(device, &self.mix_mat);
-
-        let infinite_lights = make_buffer(device, &self.infinite_lights);
-
-        let uniform_lights = make_buffer(device, &self.uniform_lights);
-        let image_lights = make_buffer(device, &self.image_lights);
-        let area_lights = make_buffer(device, &self.area_lights);
-
-        let table_spectra = make_buffer(device, &self.table_spectra);
-        let constant_spectra = make_buffer(device, &self.constant_spectra);
-        let rgb_albedo_spectra = make_buffer(device, &self.rgb_albedo_spectra);
-        let rgb_illuminant_spectra = make_buffer(device, &self.rgb_illuminant_spectra);
-        let blackbody_spectra = make_buffer(device, &self.blackbody_spectra);
-        let piecewise_linear_spectra = make_buffer(device, &self.piecewise_linear_spectra);
-
-        let float_data = make_buffer(device, &self.float_data);
-
-        let uniform_light_samplers = make_buffer(device, &self.uniform_light_samplers);
-        let uniform_light_sampler_data = make_buffer(device, &self.uniform_light_sampler_data);
-        let power_light_samplers = make_buffer(device, &self.power_light_samplers);
-        let power_light_sampler_data = make_buffer(device, &self.power_light_sampler_data);
-
-        let root = make_buffer(device, &[self.root.unwrap()]);
-        let root_ls = make_buffer(device, &[self.root_ls.unwrap()]);
-
-        let empty = [ImageData::Srgb(RgbaImage::new(1, 1))];
-        let images = match self.images.is_empty() {
-            true => empty.iter(),
-            false => self.images.iter(),
-        };
+    // Queue an image to be decoded on the thread pool. Missing files and unreadable headers are
+    // reported immediately so that callers can fall back, but errors in the pixel data only show
+    // up in `resolve_images`.
+    pub fn add_image(&mut self, path: &Path, float: bool, no_gamma: bool) -> Option<u32> {
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let key = (canonical, float, no_gamma);
+        if let Some(&id) = self.image_cache.get(&key) {
+            self.image_cache_hits += 1;
+            match self.pending_images.iter_mut().find(|p| p.id == id) {
+                Some(pending) => pending.hits += 1,
+                None => self.image_cache_saved += self.images[id as usize].size(),
+            }
+            return Some(id);
+        }
 
-        let views: Vec<_> = images
-            .map(|img| {
-                let (width, height, format, data) = match img {
-                    ImageData::Float(img) => (
-                        img.width(),
-                        img.height(),
-                        wgpu::TextureFormat::R32Float,
-                        bytemuck::cast_slice(&img),
-                    ),
-                    ImageData::FloatRgb(img) => (
-                        img.width(),
-                        img.height(),
-                        wgpu::TextureFormat::Rgba32Float,
-                        bytemuck::cast_slice(&img),
-                    ),
-                    ImageData::Srgb(img) => (
-                        img.width(),
-                        img.height(),
-                        wgpu::TextureFormat::Rgba8UnormSrgb,
-                        bytemuck::cast_slice(&img),
-                    ),
-                    ImageData::UnormRgb(img) => (
-                        img.width(),
-                        img.height(),
-                        wgpu::TextureFormat::Rgba8Unorm,
-                        bytemuck::cast_slice(&img),
-                    ),
-                };
+        if let Err(e) = check_image_header(path) {
+            println!("Could not load image {}: {e}", path.display());
+            return None;
+        }
+        let id = self.images.len() as u32;
+        self.images.push(ImageData::placeholder(float));
+        self.image_paths.push(path.to_path_buf());
+        self.image_cache.insert(key, id);
 
-                let texture = device.create_texture_with_data(
-                    queue,
-                    &wgpu::TextureDescriptor {
-                        label: None,
-                        size: wgpu::Extent3d {
-                            width,
-                            height,
-                            depth_or_array_layers: 1,
-                        },
-                        mip_level_count: 1,
-                        sample_count: 1,
-                        dimension: wgpu::TextureDimension::D2,
-                        format,
-                        usage: wgpu::TextureUsages::TEXTURE_BINDING,
-                        view_formats: &[],
-                    },
-                    wgpu::util::TextureDataOrder::LayerMajor,
-                    data,
-                );
+        let (send, result) = mpsc::sync_channel(1);
+        let path = path.to_path_buf();
+        rayon::spawn(move || {
+            let _ = send.send(decode_image(&path, float, no_gamma));
+        });
+        self.pending_images.push(PendingImage {
+            id,
+            hits: 0,
+            result,
+        });
+        Some(id)
+    }
 
-                texture.create_view(&Default::default())
-            })
-            .collect();
-        let views_refs: Vec<_> = views.iter().collect();
+    // Load an image which is needed right away, such as for building a light's sampling
+    // distribution. Unlike `add_image`, this fails on decoding errors.
+    pub fn add_image_now(&mut self, path: &Path, float: bool, no_gamma: bool) -> Option<u32> {
+        let id = self.add_image(path, float, no_gamma)?;
+        if let Some(i) = self.pending_images.iter().position(|p| p.id == id) {
+            let pending = self.pending_images.remove(i);
+            if !self.finish_image(pending) {
+                self.image_cache.retain(|_, &mut v| v != id);
+                return None;
+            }
+        }
+        Some(id)
+    }
 
-        device.create_bind_group(&wgpu::BindGroupDescriptor {
-            label: Some("scene"),
-            layout,
-            entries: &[
-                make_entry(0, &spheres),
-                make_entry(1, &triangles),
-                make_entry(2, &triangle_vertices),
-                make_entry(32, &root),
-                make_entry(33, &bvh),
-                make_entry(34, &transform),
-                make_entry(35, &primitive),
-                make_entry(64, &constant_tex),
-                make_entry(66, &image_float_tex),
-                make_entry(67, &image_rgb_tex),
-                wgpu::BindGroupEntry {
-                    binding: 68,
-                    resource: wgpu::BindingResource::TextureViewArray(&views_refs),
-                },
-                make_entry(69, &scale_tex),
-                make_entry(70, &mix_tex),
-                make_entry(71, &checkerboard_tex),
-                make_entry(72, &conductor_refl_tex),
-                make_entry(96, &diffuse_mat),
-                make_entry(97, &diffuse_transmit_mat),
-                make_entry(98, &conductor_mat),
-                make_entry(99, &dielectric_mat),
-                make_entry(100, &thin_dielectric_mat),
-                make_entry(101, &metallic_workflow_mat),
-                make_entry(102, &mix_mat),
-                make_entry(128, &infinite_lights),
-                make_entry(129, &uniform_lights),
-                make_entry(130, &image_lights),
-                make_entry(131, &area_lights),
-                make_entry(160, &table_spectra),
-                make_entry(161, &constant_spectra),
-                make_entry(162, &rgb_albedo_spectra),
-                make_entry(163, &rgb_illuminant_spectra),
-                make_entry(164, &blackbody_spectra),
-                make_entry(165, &piecewise_linear_spectra),
-                make_entry(192, &float_data),
-                make_entry(224, &root_ls),
-                make_entry(225, &uniform_light_samplers),
-                make_entry(226, &uniform_light_sampler_data),
-                make_entry(227, &power_light_samplers),
-                make_entry(228, &power_light_sampler_data),
-            ],
-        })
+    // Decode a new image into the slot of `image`, keeping how it was originally loaded
+    pub fn replace_image(&mut self, image: u32, path: &Path) -> image::ImageResult<()> {
+        self.resolve_images();
+        let &(_, float, no_gamma) = self
+            .image_cache
+            .iter()
+            .find(|&(_, &id)| id == image)
+            .unwrap()
+            .0;
+        self.images[image as usize] = decode_image(path, float, no_gamma)?;
+        self.image_paths[image as usize] = path.to_path_buf();
+        self.image_cache.retain(|_, &mut id| id != image);
+        let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        self.image_cache.insert((canonical, float, no_gamma), image);
+        self.dirty.images.insert(image);
+        Ok(())
     }
 
-    pub fn add_image(&mut self, path: &Path, float: bool, no_gamma: bool) -> Option<u32> {
-        let img = match path.extension().and_then(|s| s.to_str()) {
-            Some("pfm") => load_pfm_image(path),
-            _ => image::open(path),
-        };
-        let Ok(img) = img.inspect_err(|e| println!("Could not load image {}: {e}", path.display()))
-        else {
-            return None;
-        };
-        let id = self.images.len() as u32;
-        self.images.push(match img {
-            _ if float && img.has_alpha() => {
-                let data = img.to_luma_alpha32f();
-                let data = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
-                    Luma([data.get_pixel(x, y).alpha()])
-                });
-                ImageData::Float(data)
+    // Wait for the queued images to be decoded. Images which fail keep their placeholder.
+    pub fn resolve_images(&mut self) {
+        for pending in std::mem::take(&mut self.pending_images) {
+            self.finish_image(pending);
+        }
+    }
+
+    fn finish_image(&mut self, pending: PendingImage) -> bool {
+        let path = &self.image_paths[pending.id as usize];
+        match pending.result.recv() {
+            Ok(Ok(img)) => {
+                self.image_cache_saved += pending.hits * img.size();
+                self.images[pending.id as usize] = img;
+                true
             }
-            DynamicImage::ImageLuma16(_) | DynamicImage::ImageLuma8(_) if float => {
-                ImageData::Float(img.to_luma32f())
+            Ok(Err(e)) => {
+                println!("Could not load image {}: {e}", path.display());
+                false
             }
-            _ if float => {
-                println!(
-                    "creating float texture from color image without alpha is suspect ({})",
-                    path.display()
-                );
-                let data = img.to_rgba32f();
-                let data = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
-                    Luma([data.get_pixel(x, y).0[0]])
+            Err(_) => panic!("decoding {} panicked", path.display()),
+        }
+    }
+
+    // Block compress 8-bit images to BC7 and HDR images to BC6H. Float images are left alone, as are
+    // images whose size is not a whole number of blocks. Returns whether anything was compressed.
+    pub fn compress_textures(&mut self) -> bool {
+        let before: usize = self.images.iter().map(ImageData::gpu_size).sum();
+        let count = self
+            .images
+            .par_iter_mut()
+            .filter(|img| {
+                let (width, height) = img.dimensions();
+                width.is_multiple_of(4) && height.is_multiple_of(4)
+            })
+            .filter_map(|img| {
+                let (width, height) = img.dimensions();
+                let srgb = matches!(img, ImageData::Srgb(_));
+                let (format, levels) = match &*img {
+                    ImageData::Srgb(data) | ImageData::UnormRgb(data) => {
+                        let levels = mip_levels(width, height, rgba8_mip_chain(data, srgb))
+                            .map(|(w, h, level)| compress::encode_bc7(w, h, &level))
+                            .collect();
+                        let format = match srgb {
+                            true => wgpu::TextureFormat::Bc7RgbaUnormSrgb,
+                            false => wgpu::TextureFormat::Bc7RgbaUnorm,
+                        };
+                        (format, levels)
+                    }
+                    ImageData::FloatRgb(data) => {
+                        let mips = mip_chain(width, height, 4, data.to_vec());
+                        let levels = mip_levels(width, height, mips)
+                            .map(|(w, h, level)| compress::encode_bc6h(w, h, &level))
+                            .collect();
+                        (wgpu::TextureFormat::Bc6hRgbUfloat, levels)
+                    }
+                    ImageData::Float(_) | ImageData::Compressed(_) => return None,
+                };
+                *img = ImageData::Compressed(CompressedImage {
+                    format,
+                    width,
+                    height,
+                    levels,
                 });
-                ImageData::Float(data)
-            }
-            _ if img.as_flat_samples_f32().is_some() => ImageData::FloatRgb(img.to_rgba32f()),
-            _ if no_gamma => ImageData::UnormRgb(img.to_rgba8()),
-            _ => ImageData::Srgb(img.to_rgba8()),
-        });
-        Some(id)
+                Some(())
+            })
+            .count();
+
+        if count > 0 {
+            let after: usize = self.images.iter().map(ImageData::gpu_size).sum();
+            println!(
+                "Compressed {count} textures, {} -> {}",
+                human_size(before).trim(),
+                human_size(after).trim()
+            );
+        }
+        count > 0
     }
 
-    pub fn image_sampling_distribution(&mut self, image: u32) -> TableSampler2d {
-        let (width, height, f) = match &self.images[image as usize] {
-            ImageData::Float(img) => (img.width(), img.height(), img.to_vec()),
-            ImageData::FloatRgb(img) => (
-                img.width(),
-                img.height(),
-                img.pixels().map(|c| c.to_luma().0[0]).collect::<Vec<_>>(),
-            ),
-            ImageData::Srgb(img) => (
-                img.width(),
-                img.height(),
-                img.pixels().map(|c| c.to_luma().0[0] as f32).collect(),
-            ),
-            ImageData::UnormRgb(img) => (
-                img.width(),
-                img.height(),
-                img.pixels().map(|c| c.to_luma().0[0] as f32).collect(),
-            ),
-        };
+    // Downscale images to at most `max_res` pixels on a side, then keep halving the largest image
+    // until the total GPU memory fits in `budget` bytes
+    pub fn limit_texture_memory(&mut self, max_res: Option<u32>, budget: Option<usize>) {
+        let before: usize = self.images.iter().map(ImageData::gpu_size).sum();
+        let mut scaled = vec![false; self.images.len()];
+
+        if let Some(max_res) = max_res {
+            for (img, scaled) in self.images.iter_mut().zip(&mut scaled) {
+                let (width, height) = img.dimensions();
+                if width.max(height) > max_res {
+                    let f = max_res as f64 / width.max(height) as f64;
+                    let width = ((width as f64 * f).round() as u32).max(1);
+                    let height = ((height as f64 * f).round() as u32).max(1);
+                    *img = img.resize(width, height);
+                    *scaled = true;
+                }
+            }
+        }
+
+        if let Some(budget) = budget {
+            let mut total: usize = self.images.iter().map(ImageData::gpu_size).sum();
+            while total > budget {
+                let Some(i) = (0..self.images.len())
+                    .filter(|&i| self.images[i].can_downscale())
+                    .max_by_key(|&i| self.images[i].gpu_size())
+                else {
+                    break;
+                };
+                let (width, height) = self.images[i].dimensions();
+                let resized = self.images[i].resize((width / 2).max(1), (height / 2).max(1));
+                total = total - self.images[i].gpu_size() + resized.gpu_size();
+                self.images[i] = resized;
+                scaled[i] = true;
+            }
+            if total > budget {
+                println!("Warning: textures do not fit in the memory budget");
+            }
+        }
+
+        let count = scaled.iter().filter(|&&s| s).count();
+        if count > 0 {
+            let after: usize = self.images.iter().map(ImageData::gpu_size).sum();
+            println!(
+                "Downscaled {count} textures, {} -> {}",
+                human_size(before).trim(),
+                human_size(after).trim()
+            );
+        }
+    }
 
-        self.add_2d_table_sampler(0.0, 1.0, 0.0, 1.0, width, height, &f)
+    pub fn image_luminance(&mut self, image: u32) -> (u32, u32, Vec<f32>) {
+        if let Some(i) = self.pending_images.iter().position(|p| p.id == image) {
+            let pending = self.pending_images.remove(i);
+            self.finish_image(pending);
+        }
+        let img = &self.images[image as usize];
+        let (width, height) = img.dimensions();
+        let mut luminance = vec![0.0; width as usize * height as usize];
+        luminance
+            .par_chunks_mut(width as usize)
+            .enumerate()
+            .for_each(|(y, row)| img.luminance_row(y as u32, row));
+        (width, height, luminance)
     }
 
     pub fn add_float_data(&mut self, data: &[f32]) -> u32 {
@@ -460,22 +800,80 @@ impl Scene {
     }
 }
 
-fn make_buffer<T: NoUninit>(device: &wgpu::Device, data: &[T]) -> wgpu::Buffer {
-    let empty = vec![0; std::mem::size_of::<T>()];
-    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
-        label: Some(std::any::type_name::<T>()),
-        contents: match data.is_empty() {
-            true => &empty,
-            false => bytemuck::cast_slice(data),
-        },
-        usage: wgpu::BufferUsages::STORAGE,
-    })
+// Mip levels of an 8-bit image, filtered in linear space for sRGB images so that mips don't darken
+fn rgba8_mip_chain(img: &RgbaImage, srgb: bool) -> Vec<Vec<u8>> {
+    let linear = img
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| match i % 4 {
+            _ if !srgb => v as f32 / 255.0,
+            3 => v as f32 / 255.0,
+            _ => srgb_to_linear(v as f32 / 255.0),
+        })
+        .collect();
+    mip_chain(img.width(), img.height(), 4, linear)
+        .into_iter()
+        .map(|level| {
+            level
+                .iter()
+                .enumerate()
+                .map(|(i, &v)| match i % 4 {
+                    _ if !srgb => (v * 255.0).round() as u8,
+                    3 => (v * 255.0).round() as u8,
+                    _ => (linear_to_srgb(v) * 255.0).round() as u8,
+                })
+                .collect()
+        })
+        .collect()
 }
 
-fn make_entry(binding: u32, buffer: &wgpu::Buffer) -> wgpu::BindGroupEntry<'_> {
-    wgpu::BindGroupEntry {
-        binding,
-        resource: buffer.as_entire_binding(),
+// Pairs each mip level with its dimensions
+fn mip_levels<T>(width: u32, height: u32, levels: Vec<T>) -> impl Iterator<Item = (u32, u32, T)> {
+    levels
+        .into_iter()
+        .enumerate()
+        .map(move |(i, level)| ((width >> i).max(1), (height >> i).max(1), level))
+}
+
+// Box-filtered mip levels of an image with `channels` interleaved values per pixel, starting with
+// the image itself
+fn mip_chain(width: u32, height: u32, channels: usize, data: Vec<f32>) -> Vec<Vec<f32>> {
+    let (mut width, mut height) = (width as usize, height as usize);
+    let mut levels = vec![data];
+    while width > 1 || height > 1 {
+        let prev = levels.last().unwrap();
+        let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+        let mut next = Vec::with_capacity(w * h * channels);
+        for y in 0..h {
+            for x in 0..w {
+                for c in 0..channels {
+                    let mut sum = 0.0;
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (2 * x + dx).min(width - 1);
+                        let sy = (2 * y + dy).min(height - 1);
+                        sum += prev[(sy * width + sx) * channels + c];
+                    }
+                    next.push(sum / 4.0);
+                }
+            }
+        }
+        levels.push(next);
+        (width, height) = (w, h);
+    }
+    levels
+}
+
+fn srgb_to_linear(v: f32) -> f32 {
+    match v <= 0.04045 {
+        true => v / 12.92,
+        false => ((v + 0.055) / 1.055).powf(2.4),
+    }
+}
+
+fn linear_to_srgb(v: f32) -> f32 {
+    match v <= 0.0031308 {
+        true => v * 12.92,
+        false => v.powf(1.0 / 2.4) * 1.055 - 0.055,
     }
 }
 
@@ -486,7 +884,7 @@ pub struct Bounds {
 }
 
 impl Bounds {
-    fn from_points(mut points: impl Iterator<Item = Vec3>) -> Self {
+    pub fn from_points(mut points: impl Iterator<Item = Vec3>) -> Self {
         let first = points.next().unwrap();
         let mut this = Bounds {
             min: first,
@@ -511,6 +909,14 @@ impl Bounds {
         }
     }
 
+    fn intersection(&self, other: &Bounds) -> Option<Bounds> {
+        let bounds = Bounds {
+            min: self.min.max(other.min),
+            max: self.max.min(other.max),
+        };
+        bounds.min.cmple(bounds.max).all().then_some(bounds)
+    }
+
     fn size(&self) -> Vec3 {
         self.max - self.min
     }
@@ -519,7 +925,7 @@ impl Bounds {
         (self.min + self.max) * 0.5
     }
 
-    fn corners(&self) -> [Vec3; 8] {
+    pub fn corners(&self) -> [Vec3; 8] {
         [0, 1, 2, 3, 4, 5, 6, 7].map(|i| {
             Vec3::select(
                 BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0),
@@ -530,6 +936,83 @@ impl Bounds {
     }
 }
 
+fn decode_image(path: &Path, float: bool, no_gamma: bool) -> image::ImageResult<ImageData> {
+    let img = load_image(path)?;
+    Ok(match img {
+        _ if float && img.has_alpha() => {
+            let data = img.to_luma_alpha32f();
+            let data = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                Luma([data.get_pixel(x, y).alpha()])
+            });
+            ImageData::Float(data)
+        }
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageLuma8(_) if float => {
+            ImageData::Float(img.to_luma32f())
+        }
+        // single channel PFM and EXR images are loaded as gray
+        DynamicImage::ImageRgb32F(data)
+            if float && data.pixels().all(|p| p[0] == p[1] && p[1] == p[2]) =>
+        {
+            ImageData::Float(ImageBuffer::from_fn(data.width(), data.height(), |x, y| {
+                Luma([data.get_pixel(x, y)[0]])
+            }))
+        }
+        _ if float => {
+            println!(
+                "creating float texture from color image without alpha is suspect ({})",
+                path.display()
+            );
+            let data = img.to_rgba32f();
+            let data = ImageBuffer::from_fn(img.width(), img.height(), |x, y| {
+                Luma([data.get_pixel(x, y).0[0]])
+            });
+            ImageData::Float(data)
+        }
+        _ if img.as_flat_samples_f32().is_some() => ImageData::FloatRgb(img.to_rgba32f()),
+        _ if no_gamma => ImageData::UnormRgb(img.to_rgba8()),
+        _ => ImageData::Srgb(img.to_rgba8()),
+    })
+}
+
+// Reads just enough of an image to know that it can be decoded
+fn check_image_header(path: &Path) -> image::ImageResult<()> {
+    use image::error::*;
+
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("pfm") => {
+            let mut magic = [0; 2];
+            File::open(path)?.read_exact(&mut magic)?;
+            match &magic {
+                b"PF" | b"Pf" => Ok(()),
+                _ => Err(ImageError::Decoding(DecodingError::new(
+                    ImageFormatHint::Name("PFM".to_string()),
+                    "invalid pfm type",
+                ))),
+            }
+        }
+        Some("exr") => exr::meta::MetaData::read_from_file(path, false)
+            .map(|_| ())
+            .map_err(|e| {
+                ImageError::Decoding(DecodingError::new(
+                    ImageFormatHint::Name("EXR".to_string()),
+                    e.to_string(),
+                ))
+            }),
+        _ => image::ImageReader::open(path)?
+            .with_guessed_format()?
+            .into_dimensions()
+            .map(|_| ()),
+    }
+}
+
+pub fn load_image(path: &Path) -> image::ImageResult<DynamicImage> {
+    match path.extension().and_then(|s| s.to_str()) {
+        Some("pfm") => load_pfm_image(path),
+        Some("exr") => load_exr_image(path),
+        _ => image::open(path),
+    }
+}
+
 fn load_pfm_image(path: &Path) -> image::ImageResult<DynamicImage> {
     use image::error::*;
 
@@ -607,11 +1090,76 @@ fn load_pfm_image(path: &Path) -> image::ImageResult<DynamicImage> {
     })
 }
 
+// First layer of an OpenEXR file. Uses the R, G, B and A channels if present, otherwise Y or the
+// only channel as a gray image. Half and integer samples are converted to f32.
+fn load_exr_image(path: &Path) -> image::ImageResult<DynamicImage> {
+    use image::error::*;
+
+    let error = |e: &dyn std::fmt::Display| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Name("EXR".to_string()),
+            e.to_string(),
+        ))
+    };
+
+    let exr = exr::prelude::read_first_flat_layer_from_file(path).map_err(|e| error(&e))?;
+    let layer = exr.layer_data;
+    let (width, height) = (layer.size.x() as u32, layer.size.y() as u32);
+    let channels = &layer.channel_data.list;
+
+    // channels of named layers are prefixed, e.g. `diffuse.R`
+    let find = |name: &str| {
+        channels.iter().find(|c| {
+            let full = c.name.to_string();
+            full == name || full.ends_with(&format!(".{name}"))
+        })
+    };
+    if let Some(c) = channels
+        .iter()
+        .find(|c| c.sampling != exr::math::Vec2(1, 1))
+    {
+        return Err(error(&format!(
+            "subsampled channel {} is not supported",
+            c.name
+        )));
+    }
+    let values = |c: &exr::image::AnyChannel<exr::image::FlatSamples>| {
+        c.sample_data.values_as_f32().collect::<Vec<_>>()
+    };
+
+    let [r, g, b] = match (find("R"), find("G"), find("B")) {
+        (Some(r), Some(g), Some(b)) => [values(r), values(g), values(b)],
+        _ => {
+            let y = match find("Y") {
+                Some(y) => y,
+                None if channels.len() == 1 => &channels[0],
+                None => return Err(error(&"no RGB, Y or single channel")),
+            };
+            let y = values(y);
+            [y.clone(), y.clone(), y]
+        }
+    };
+
+    Ok(match find("A") {
+        Some(a) => {
+            let a = values(a);
+            let data = (0..r.len())
+                .flat_map(|i| [r[i], g[i], b[i], a[i]])
+                .collect();
+            Rgba32FImage::from_vec(width, height, data).unwrap().into()
+        }
+        None => {
+            let data = (0..r.len()).flat_map(|i| [r[i], g[i], b[i]]).collect();
+            Rgb32FImage::from_vec(width, height, data).unwrap().into()
+        }
+    })
+}
+
 fn human_size_of<T>(data: &[T]) -> String {
     human_size(std::mem::size_of_val(data))
 }
 
-fn human_size(size: usize) -> String {
+pub fn human_size(size: usize) -> String {
     let size = size as f64;
     let kib = size / 1024.0;
     let mib = kib / 1024.0;
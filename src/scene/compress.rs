@@ -0,0 +1,288 @@
+use glam::{Vec3, Vec4};
+
+// Single-subset block encoders: BC7 mode 6 for 8-bit RGBA and BC6H mode 11 for unsigned HDR RGB.
+// Endpoints are the extremes of the block along its principal axis, which is fast and good enough
+// for textures that are minified most of the time.
+
+const WEIGHTS: [u32; 16] = [0, 4, 9, 13, 17, 21, 26, 30, 34, 38, 43, 47, 51, 55, 60, 64];
+
+// `data` holds `width * height` RGBA texels; returns 16 bytes per 4x4 block in row order
+pub fn encode_bc7(width: u32, height: u32, data: &[u8]) -> Vec<u8> {
+    encode_blocks(width, height, |x, y| {
+        let i = (y * width + x) as usize * 4;
+        Vec4::from_array(std::array::from_fn(|c| data[i + c] as f32))
+    })
+    .flat_map(|block| bc7_block(&block))
+    .collect()
+}
+
+// `data` holds `width * height` RGBA texels; alpha is dropped and negative values are clamped to 0
+pub fn encode_bc6h(width: u32, height: u32, data: &[f32]) -> Vec<u8> {
+    encode_blocks(width, height, |x, y| {
+        let i = (y * width + x) as usize * 4;
+        // interpolation happens on the bits of the half float, so fit the endpoints there too
+        let h = Vec3::from_slice(&data[i..i + 3]).map(half_bits);
+        (h * 64.0 / 31.0).extend(0.0)
+    })
+    .flat_map(|block| bc6h_block(&block))
+    .collect()
+}
+
+// Texels of a block as `encode_bc7` writes them, as 8-bit values in row order. Other modes are
+// never written and decode as black.
+pub fn decode_bc7(block: &[u8]) -> [Vec4; 16] {
+    let mut bits = BitReader::new(block);
+    if bits.read(7) != 1 << 6 {
+        return [Vec4::ZERO; 16];
+    }
+    let (mut e0, mut e1) = (Vec4::ZERO, Vec4::ZERO);
+    for c in 0..4 {
+        e0[c] = bits.read(7) as f32;
+        e1[c] = bits.read(7) as f32;
+    }
+    let (p0, p1) = (bits.read(1) as f32, bits.read(1) as f32);
+    let (v0, v1) = (e0 * 2.0 + p0, e1 * 2.0 + p1);
+    bits.read_indices().map(|i| {
+        let w = WEIGHTS[i as usize] as f32;
+        ((v0 * (64.0 - w) + v1 * w + 32.0) / 64.0).floor()
+    })
+}
+
+// Texels of a block as `encode_bc6h` writes them, as linear values with an alpha of 1 in row
+// order. Other modes are never written and decode as black.
+pub fn decode_bc6h(block: &[u8]) -> [Vec4; 16] {
+    let mut bits = BitReader::new(block);
+    if bits.read(5) != 0b00011 {
+        return [Vec4::ZERO.with_w(1.0); 16];
+    }
+    let mut endpoints = [[0u32; 3]; 2];
+    for endpoint in &mut endpoints {
+        for c in endpoint.iter_mut() {
+            *c = match bits.read(10) {
+                0 => 0,
+                1023 => 0xffff,
+                q => ((q << 16) + 0x8000) >> 10,
+            };
+        }
+    }
+    let [u0, u1] = endpoints;
+    bits.read_indices().map(|i| {
+        let w = WEIGHTS[i as usize];
+        let rgb: [f32; 3] = std::array::from_fn(|c| {
+            let v = (u0[c] * (64 - w) + u1[c] * w + 32) >> 6;
+            half::f16::from_bits(((v * 31) >> 6) as u16).to_f32()
+        });
+        Vec3::from(rgb).extend(1.0)
+    })
+}
+
+// texels of each block, with edge texels repeated for images smaller than a block
+fn encode_blocks(
+    width: u32,
+    height: u32,
+    texel: impl Fn(u32, u32) -> Vec4,
+) -> impl Iterator<Item = [Vec4; 16]> {
+    let blocks_x = width.div_ceil(4);
+    let blocks_y = height.div_ceil(4);
+    (0..blocks_y * blocks_x).map(move |b| {
+        let (bx, by) = (b % blocks_x * 4, b / blocks_x * 4);
+        std::array::from_fn(|i| {
+            let x = (bx + i as u32 % 4).min(width - 1);
+            let y = (by + i as u32 / 4).min(height - 1);
+            texel(x, y)
+        })
+    })
+}
+
+// extremes of the block along the direction of greatest variance
+fn fit_endpoints(block: &[Vec4; 16]) -> (Vec4, Vec4) {
+    let mean = block.iter().sum::<Vec4>() / 16.0;
+    let mut axis = block
+        .iter()
+        .map(|&p| (p - mean).abs())
+        .fold(Vec4::ZERO, Vec4::max);
+    for _ in 0..8 {
+        let next = block
+            .iter()
+            .map(|&p| (p - mean) * (p - mean).dot(axis))
+            .sum::<Vec4>();
+        if next.length_squared() < 1e-12 {
+            break;
+        }
+        axis = next.normalize();
+    }
+    if axis.length_squared() < 1e-12 {
+        return (mean, mean);
+    }
+    let axis = axis.normalize();
+    let t = block.iter().map(|&p| (p - mean).dot(axis));
+    let min = t.clone().fold(f32::INFINITY, f32::min);
+    let max = t.fold(f32::NEG_INFINITY, f32::max);
+    (mean + axis * min, mean + axis * max)
+}
+
+// index of the closest palette entry for each texel
+fn select_indices(block: &[Vec4; 16], palette: &[Vec4; 16]) -> [u32; 16] {
+    block.map(|p| {
+        (0..16)
+            .min_by(|&a, &b| {
+                let da = (palette[a] - p).length_squared();
+                let db = (palette[b] - p).length_squared();
+                da.total_cmp(&db)
+            })
+            .unwrap() as u32
+    })
+}
+
+struct BitWriter {
+    bits: u128,
+    len: u32,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        BitWriter { bits: 0, len: 0 }
+    }
+
+    fn write(&mut self, value: u32, bits: u32) {
+        self.bits |= ((value & ((1 << bits) - 1)) as u128) << self.len;
+        self.len += bits;
+    }
+
+    // texel 0 is the anchor, whose most significant index bit is implicitly zero
+    fn write_indices(&mut self, indices: &[u32; 16]) {
+        for (i, &index) in indices.iter().enumerate() {
+            self.write(index, if i == 0 { 3 } else { 4 });
+        }
+    }
+
+    fn finish(self) -> [u8; 16] {
+        debug_assert_eq!(self.len, 128);
+        self.bits.to_le_bytes()
+    }
+}
+
+struct BitReader {
+    bits: u128,
+}
+
+impl BitReader {
+    fn new(block: &[u8]) -> Self {
+        BitReader {
+            bits: u128::from_le_bytes(block.try_into().unwrap()),
+        }
+    }
+
+    fn read(&mut self, bits: u32) -> u32 {
+        let value = (self.bits & ((1 << bits) - 1)) as u32;
+        self.bits >>= bits;
+        value
+    }
+
+    fn read_indices(&mut self) -> [u32; 16] {
+        let mut indices = [0; 16];
+        for (i, index) in indices.iter_mut().enumerate() {
+            *index = self.read(if i == 0 { 3 } else { 4 });
+        }
+        indices
+    }
+}
+
+fn bc7_block(block: &[Vec4; 16]) -> [u8; 16] {
+    let (e0, e1) = fit_endpoints(block);
+
+    // 7 bits per channel plus a shared low bit per endpoint, chosen to minimize the error
+    let quantize = |e: Vec4| {
+        [0, 1]
+            .map(|p| {
+                let q = ((e - p as f32) / 2.0)
+                    .round()
+                    .clamp(Vec4::ZERO, Vec4::splat(127.0));
+                let v = q * 2.0 + p as f32;
+                ((v - e).length_squared(), q.as_uvec4(), p, v)
+            })
+            .into_iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap()
+    };
+    let (_, mut q0, mut p0, v0) = quantize(e0);
+    let (_, mut q1, mut p1, v1) = quantize(e1);
+
+    let palette = WEIGHTS.map(|w| {
+        let w = w as f32;
+        ((v0 * (64.0 - w) + v1 * w + 32.0) / 64.0).floor()
+    });
+    let mut indices = select_indices(block, &palette);
+    if indices[0] >= 8 {
+        (q0, q1, p0, p1) = (q1, q0, p1, p0);
+        indices = indices.map(|i| 15 - i);
+    }
+
+    let mut out = BitWriter::new();
+    out.write(1 << 6, 7);
+    for c in 0..4 {
+        out.write(q0[c], 7);
+        out.write(q1[c], 7);
+    }
+    out.write(p0, 1);
+    out.write(p1, 1);
+    out.write_indices(&indices);
+    out.finish()
+}
+
+fn bc6h_block(block: &[Vec4; 16]) -> [u8; 16] {
+    let (e0, e1) = fit_endpoints(block);
+
+    // 10 bit endpoints, expanded to 16 bits as the decoder does
+    let quantize = |e: Vec4| {
+        ((e.truncate() - 32.0) / 64.0)
+            .round()
+            .clamp(Vec3::ZERO, Vec3::splat(1023.0))
+            .as_uvec3()
+    };
+    let unquantize = |q: glam::UVec3| {
+        q.map(|c| match c {
+            0 => 0,
+            1023 => 0xffff,
+            c => ((c << 16) + 0x8000) >> 10,
+        })
+        .as_vec3()
+    };
+    let (mut q0, mut q1) = (quantize(e0), quantize(e1));
+    let (u0, u1) = (unquantize(q0), unquantize(q1));
+
+    let palette = WEIGHTS.map(|w| {
+        let w = w as f32;
+        ((u0 * (64.0 - w) + u1 * w + 32.0) / 64.0)
+            .floor()
+            .extend(0.0)
+    });
+    let mut indices = select_indices(block, &palette);
+    if indices[0] >= 8 {
+        (q0, q1) = (q1, q0);
+        indices = indices.map(|i| 15 - i);
+    }
+
+    let mut out = BitWriter::new();
+    out.write(0b00011, 5);
+    for q in [q0, q1] {
+        for c in 0..3 {
+            out.write(q[c], 10);
+        }
+    }
+    out.write_indices(&indices);
+    out.finish()
+}
+
+// bits of the nearest non-negative half float, as a number
+fn half_bits(v: f32) -> f32 {
+    if v.is_nan() || v <= 0.0 {
+        return 0.0;
+    }
+    if v < 2.0f32.powi(-14) {
+        // subnormal
+        return (v * 2.0f32.powi(24)).round();
+    }
+    let bits = (v.to_bits() - ((127 - 15) << 23) + 0x1000) >> 13;
+    bits.min(0x7bff) as f32
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeSet;
+
+use bytemuck::NoUninit;
+use wgpu::util::DeviceExt;
+
+use crate::scene::{ImageData, Scene, mip_chain, rgba8_mip_chain, upload_in_chunks};
+
+// The scene's buffers and textures on the GPU, kept so that edits to the scene can be uploaded
+// without recreating everything else
+pub struct GpuScene {
+    buffers: Vec<(u32, wgpu::Buffer)>,
+    views: Vec<wgpu::TextureView>,
+    pub bind_group: wgpu::BindGroup,
+}
+
+// Parts of the scene edited since they were last uploaded
+#[derive(Default)]
+pub struct Dirty {
+    pub bvh_nodes: bool,
+    pub transform_nodes: bool,
+    pub primitive_nodes: bool,
+    // light parameters, their spectra, the float data of their sampling tables and the light
+    // samplers
+    pub lights: bool,
+    pub images: BTreeSet<u32>,
+}
+
+struct BufferContents<'a> {
+    binding: u32,
+    label: &'static str,
+    data: &'a [u8],
+    element_size: usize,
+}
+
+fn contents<T: NoUninit>(binding: u32, data: &[T]) -> BufferContents<'_> {
+    BufferContents {
+        binding,
+        label: std::any::type_name::<T>(),
+        data: bytemuck::cast_slice(data),
+        element_size: std::mem::size_of::<T>(),
+    }
+}
+
+impl Scene {
+    fn buffer_contents(&self) -> Vec<BufferContents<'_>> {
+        let vertices = match self.compress_geometry {
+            true => contents(2, &self.packed_vertices),
+            false => contents(2, &self.triangle_vertices),
+        };
+        vec![
+            contents(0, &self.spheres),
+            contents(1, &self.triangles),
+            vertices,
+            contents(3, &self.splats),
+            contents(4, &self.sdfs),
+            contents(5, &self.sdf_nodes),
+            contents(6, &self.csgs),
+            contents(7, &self.csg_nodes),
+            contents(8, &self.heightfields),
+            contents(9, &self.position_grids),
+            contents(
+                32,
+                std::slice::from_ref(self.wide_bvh.root.as_ref().unwrap()),
+            ),
+            contents(33, &self.wide_bvh.nodes),
+            contents(34, &self.wide_bvh.transform_nodes),
+            contents(35, &self.primitive_nodes),
+            contents(36, &self.animated_transforms),
+            contents(37, &self.wide_bvh.leaf_primitives),
+            contents(64, &self.constant_tex),
+            contents(66, &self.image_float_tex),
+            contents(67, &self.image_rgb_tex),
+            contents(69, &self.scale_tex),
+            contents(70, &self.mix_tex),
+            contents(71, &self.checkerboard_tex),
+            contents(72, &self.conductor_refl_tex),
+            contents(73, &self.wireframe_tex),
+            contents(74, &self.uv_checker_tex),
+            contents(96, &self.diffuse_mat),
+            contents(97, &self.diffuse_transmit_mat),
+            contents(98, &self.conductor_mat),
+            contents(99, &self.dielectric_mat),
+            contents(100, &self.thin_dielectric_mat),
+            contents(101, &self.metallic_workflow_mat),
+            contents(102, &self.mix_mat),
+            contents(103, &self.measured_mat),
+            contents(104, &self.principled_mat),
+            contents(128, &self.infinite_lights),
+            contents(129, &self.uniform_lights),
+            contents(130, &self.image_lights),
+            contents(131, &self.area_lights),
+            contents(132, &self.portal_lights),
+            contents(160, &self.table_spectra),
+            contents(161, &self.constant_spectra),
+            contents(162, &self.rgb_albedo_spectra),
+            contents(163, &self.rgb_illuminant_spectra),
+            contents(164, &self.blackbody_spectra),
+            contents(165, &self.piecewise_linear_spectra),
+            contents(192, &self.float_data),
+            contents(224, std::slice::from_ref(self.root_ls.as_ref().unwrap())),
+            contents(225, &self.uniform_light_samplers),
+            contents(226, &self.uniform_light_sampler_data),
+            contents(227, &self.power_light_samplers),
+            contents(228, &self.power_light_sampler_data),
+        ]
+    }
+
+    // the name and size in bytes of the scene's largest buffer
+    pub fn largest_buffer(&self) -> (&'static str, usize) {
+        let contents = self.buffer_contents();
+        let largest = contents.iter().max_by_key(|c| c.data.len()).unwrap();
+        let name = largest.label.rsplit("::").next().unwrap();
+        (name, largest.data.len())
+    }
+
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> GpuScene {
+        if self.wide_bvh.root.is_none() || self.dirty.bvh_nodes || self.dirty.transform_nodes {
+            self.collapse_bvh();
+        }
+        let buffers: Vec<_> = self
+            .buffer_contents()
+            .iter()
+            .map(|contents| {
+                let buffer = match contents.binding {
+                    1 | 2 if self.triangles.is_spilled() => {
+                        upload_in_chunks(device, queue, contents.label, contents.data)
+                    }
+                    _ => make_buffer(device, contents),
+                };
+                (contents.binding, buffer)
+            })
+            .collect();
+
+        let empty = [ImageData::Srgb(image::RgbaImage::new(1, 1))];
+        let images = match self.images.is_empty() {
+            true => &empty[..],
+            false => &self.images,
+        };
+        let views: Vec<_> = images
+            .iter()
+            .map(|img| make_view(device, queue, img))
+            .collect();
+
+        self.dirty = Dirty::default();
+        let bind_group = make_bind_group(device, layout, &buffers, &views);
+        GpuScene {
+            buffers,
+            views,
+            bind_group,
+        }
+    }
+}
+
+impl GpuScene {
+    // bytes of buffers and textures
+    pub fn memory(&self, scene: &Scene) -> usize {
+        let buffers: u64 = self.buffers.iter().map(|(_, b)| b.size()).sum();
+        buffers as usize + scene.images.iter().map(ImageData::gpu_size).sum::<usize>()
+    }
+
+    // Upload the parts of the scene edited since the last upload. Returns whether anything changed.
+    pub fn update(
+        &mut self,
+        scene: &mut Scene,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        layout: &wgpu::BindGroupLayout,
+    ) -> bool {
+        let dirty = std::mem::take(&mut scene.dirty);
+        let mut changed = false;
+        let mut rebind = false;
+        // the wide nodes point into both
+        if dirty.bvh_nodes || dirty.transform_nodes {
+            scene.collapse_bvh();
+        }
+
+        for contents in scene.buffer_contents() {
+            let edited = match contents.binding {
+                32 | 33 | 37 => dirty.bvh_nodes,
+                34 => dirty.transform_nodes,
+                35 => dirty.primitive_nodes,
+                128..=132 | 160..=165 | 192 | 224..=228 => dirty.lights,
+                _ => false,
+            };
+            if !edited {
+                continue;
+            }
+            let (_, buffer) = self
+                .buffers
+                .iter_mut()
+                .find(|&&mut (b, _)| b == contents.binding)
+                .unwrap();
+            // edits happen in place, so the buffer can almost always be reused
+            if buffer.size() == contents.data.len() as u64 {
+                queue.write_buffer(buffer, 0, contents.data);
+            } else {
+                *buffer = make_buffer(device, &contents);
+                rebind = true;
+            }
+            changed = true;
+        }
+
+        for &image in &dirty.images {
+            self.views[image as usize] = make_view(device, queue, &scene.images[image as usize]);
+            rebind = true;
+            changed = true;
+        }
+
+        if rebind {
+            self.bind_group = make_bind_group(device, layout, &self.buffers, &self.views);
+        }
+        changed
+    }
+}
+
+fn make_buffer(device: &wgpu::Device, contents: &BufferContents) -> wgpu::Buffer {
+    // bindings can't be empty
+    let empty = vec![0; contents.element_size];
+    device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some(contents.label),
+        contents: match contents.data.is_empty() {
+            true => &empty,
+            false => contents.data,
+        },
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+    })
+}
+
+fn make_view(device: &wgpu::Device, queue: &wgpu::Queue, img: &ImageData) -> wgpu::TextureView {
+    let (width, height, format, levels, data) = match img {
+        ImageData::Float(img) => {
+            let mips = mip_chain(img.width(), img.height(), 1, img.to_vec());
+            let data = bytemuck::cast_slice(&mips.concat()).to_vec();
+            let format = wgpu::TextureFormat::R32Float;
+            (img.width(), img.height(), format, mips.len(), data)
+        }
+        ImageData::FloatRgb(img) => {
+            let mips = mip_chain(img.width(), img.height(), 4, img.to_vec());
+            let data = bytemuck::cast_slice(&mips.concat()).to_vec();
+            let format = wgpu::TextureFormat::Rgba32Float;
+            (img.width(), img.height(), format, mips.len(), data)
+        }
+        ImageData::Srgb(img) => {
+            let mips = rgba8_mip_chain(img, true);
+            let format = wgpu::TextureFormat::Rgba8UnormSrgb;
+            (img.width(), img.height(), format, mips.len(), mips.concat())
+        }
+        ImageData::UnormRgb(img) => {
+            let mips = rgba8_mip_chain(img, false);
+            let format = wgpu::TextureFormat::Rgba8Unorm;
+            (img.width(), img.height(), format, mips.len(), mips.concat())
+        }
+        ImageData::Compressed(img) => {
+            let data = img.levels.concat();
+            (img.width, img.height, img.format, img.levels.len(), data)
+        }
+    };
+
+    let texture = device.create_texture_with_data(
+        queue,
+        &wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: levels as u32,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        },
+        wgpu::util::TextureDataOrder::LayerMajor,
+        &data,
+    );
+
+    texture.create_view(&Default::default())
+}
+
+fn make_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    buffers: &[(u32, wgpu::Buffer)],
+    views: &[wgpu::TextureView],
+) -> wgpu::BindGroup {
+    let views: Vec<_> = views.iter().collect();
+    let mut entries: Vec<_> = buffers
+        .iter()
+        .map(|(binding, buffer)| wgpu::BindGroupEntry {
+            binding: *binding,
+            resource: buffer.as_entire_binding(),
+        })
+        .collect();
+    entries.push(wgpu::BindGroupEntry {
+        binding: 68,
+        resource: wgpu::BindingResource::TextureViewArray(&views),
+    });
+
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        label: Some("scene"),
+        layout,
+        entries: &entries,
+    })
+}
@@ -1,10 +1,12 @@
-use bytemuck::NoUninit;
-use glam::DMat4;
+use bytemuck::{CheckedBitPattern, NoUninit, Pod, Zeroable};
+use glam::{DMat4, DVec3, Mat4, Vec2, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
 
 use crate::Transform;
-use crate::scene::{NodeId, Scene, ShapeId, SpectrumId, TableSampler2d, TextureId};
+use crate::options::EnvironmentOverride;
+use crate::scene::{NodeId, Scene, ShapeId, SpectrumId, TableSampler1d, TableSampler2d, TextureId};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 pub struct LightId(u32);
 
@@ -14,6 +16,7 @@ enum LightType {
     Uniform = 0 << LightId::TAG_SHIFT,
     Image = 1 << LightId::TAG_SHIFT,
     Area = 2 << LightId::TAG_SHIFT,
+    Portal = 3 << LightId::TAG_SHIFT,
 }
 
 #[allow(unused)]
@@ -47,15 +50,28 @@ impl LightId {
             LightType::Uniform => true,
             LightType::Image => true,
             LightType::Area => false,
+            LightType::Portal => true,
         }
     }
 }
 
 impl Scene {
+    // parameters of a light, for inspecting the scene
+    pub fn describe_light(&self, light: LightId) -> String {
+        let i = light.idx();
+        match light.ty() {
+            LightType::Uniform => format!("{:?}", self.uniform_lights[i]),
+            LightType::Image => format!("{:?}", self.image_lights[i]),
+            LightType::Area => format!("{:?}", self.area_lights[i]),
+            LightType::Portal => format!("{:?}", self.portal_lights[i]),
+        }
+    }
+
     pub fn light_power(&self, light: LightId) -> f32 {
         match light.ty() {
             LightType::Uniform => 0.0,
             LightType::Image => 0.0,
+            LightType::Portal => 0.0,
             LightType::Area => {
                 let light = &self.area_lights[light.idx()];
                 let luminance = self.spectrum_power(light.spectrum);
@@ -94,6 +110,102 @@ impl Scene {
         id
     }
 
+    // Image light seen through the rectangular window `portal`, which must be wound so that the
+    // environment is on the side of (p3 - p0) x (p1 - p0), as in pbrt
+    pub fn add_portal_light(
+        &mut self,
+        transform: DMat4,
+        image: u32,
+        scale: f32,
+        portal: [DVec3; 4],
+    ) -> LightId {
+        let x = portal[3] - portal[0];
+        let y = portal[1] - portal[0];
+        let frame = DMat4::from_cols(
+            x.normalize().extend(0.0),
+            y.normalize().extend(0.0),
+            x.cross(y).normalize().extend(0.0),
+            portal[0].extend(1.0),
+        );
+        let size = Vec2::new(x.length() as f32, y.length() as f32);
+
+        let light_from_portal = (transform.inverse() * frame).as_mat4();
+        let (resolution, sat) = self.portal_sampling_table(image, light_from_portal);
+        let sat_ptr = self.add_float_data(&sat);
+
+        let id = LightId::new(LightType::Portal, self.portal_lights.len());
+        self.infinite_lights.push(id);
+        self.portal_lights.push(PortalLight {
+            transform: Transform {
+                m: transform.as_mat4(),
+                m_inv: transform.inverse().as_mat4(),
+            },
+            portal: Transform {
+                m: frame.as_mat4(),
+                m_inv: frame.inverse().as_mat4(),
+            },
+            size,
+            image,
+            scale,
+            light_sampling_path: u32::MAX,
+            sat_ptr,
+            resolution,
+            _padding: 0,
+        });
+        id
+    }
+
+    const PORTAL_RESOLUTION: u32 = 256;
+
+    fn portal_sampling_table(&mut self, image: u32, light_from_portal: Mat4) -> (u32, Vec<f32>) {
+        let (width, height, luminance) = self.image_luminance(image);
+        let resolution = height.clamp(16, Self::PORTAL_RESOLUTION);
+        let sat = portal_summed_area_table(resolution, light_from_portal, |uv| {
+            let texel = (uv.fract() * Vec2::new(width as f32, height as f32)).as_uvec2();
+            luminance[(texel.y * width + texel.x) as usize]
+        });
+        (resolution, sat)
+    }
+
+    // Takes the infinite lights from the rotation and intensity of one environment override to
+    // those of another, for drawing a new environment each frame
+    pub fn change_environment(&mut self, from: EnvironmentOverride, to: EnvironmentOverride) {
+        let rotation = Mat4::from_rotation_z((to.rotation - from.rotation).to_radians());
+        let rotate = |transform: &mut Transform| {
+            transform.m *= rotation;
+            transform.m_inv = rotation.inverse() * transform.m_inv;
+        };
+        let factor = to.scale / from.scale;
+
+        for light in &mut self.image_lights {
+            rotate(&mut light.transform);
+            light.scale *= factor;
+        }
+        for i in 0..self.portal_lights.len() {
+            let light = &mut self.portal_lights[i];
+            rotate(&mut light.transform);
+            light.scale *= factor;
+            let light = self.portal_lights[i];
+            let light_from_portal = light.transform.m_inv * light.portal.m;
+            let (_, sat) = self.portal_sampling_table(light.image, light_from_portal);
+            let start = light.sat_ptr as usize;
+            self.float_data[start..start + sat.len()].copy_from_slice(&sat);
+        }
+        let mut unscaled = 0;
+        for i in 0..self.uniform_lights.len() {
+            if !self.scale_spectrum(self.uniform_lights[i].spectrum, factor) {
+                unscaled += 1;
+            }
+        }
+        if unscaled > 0 {
+            println!(
+                "Warning: the intensity of {unscaled} uniform lights with tabulated spectra \
+                 can't be changed"
+            );
+        }
+        self.dirty.lights = true;
+    }
+
     pub fn add_area_light(
         &mut self,
         shape: ShapeId,
@@ -113,6 +225,85 @@ impl Scene {
         id
     }
 
+    // Wavelength distribution for scenes lit by spectra far from white, such as blackbody and
+    // measured lights, which would otherwise leave many wavelength samples carrying almost no
+    // light. Mixes each area light's own distribution by its power with the default visible
+    // distribution, which is kept as a floor so other spectra are still sampled well. Infinite
+    // lights count with the power they send into the scene's bounding sphere; those lit by
+    // images only add to the floor.
+    pub fn add_wavelength_sampler(&mut self) -> Option<TableSampler1d> {
+        const VISIBLE_FRACTION: f32 = 0.25;
+
+        let visible: Vec<f32> = (360..831)
+            .map(|wl| {
+                // density of the default distribution in film.wgsl
+                let d = (0.0072 * (wl as f32 + 0.5 - 538.0)).cosh();
+                0.003938911 / (d * d)
+            })
+            .collect();
+
+        // power of each light and its own distribution, if it has one
+        let mut lights = vec![];
+        for light in 0..self.area_lights.len() {
+            let power = self.light_power(LightId::new(LightType::Area, light));
+            lights.push((power, self.area_lights[light].spectrum));
+        }
+        let bounds = self.node_bounds(self.root.unwrap());
+        let radius = (bounds.max - bounds.min).length() / 2.0;
+        let sphere = 4.0 * std::f32::consts::PI * radius * radius;
+        let mut image_power = 0.0;
+        for light in self.infinite_lights.clone() {
+            let i = light.idx();
+            match light.ty() {
+                LightType::Uniform => {
+                    let spectrum = self.uniform_lights[i].spectrum;
+                    lights.push((self.spectrum_power(spectrum) * sphere, spectrum));
+                }
+                LightType::Image | LightType::Portal => {
+                    let (image, scale) = match light.ty() {
+                        LightType::Image => {
+                            (self.image_lights[i].image, self.image_lights[i].scale)
+                        }
+                        _ => (self.portal_lights[i].image, self.portal_lights[i].scale),
+                    };
+                    let (_, _, luminance) = self.image_luminance(image);
+                    let mean = luminance.iter().sum::<f32>() / luminance.len() as f32;
+                    let power = scale * mean * sphere;
+                    if power.is_finite() && power > 0.0 {
+                        image_power += power;
+                    }
+                }
+                LightType::Area => unreachable!(),
+            }
+        }
+
+        let mut density = vec![0.0; visible.len()];
+        let mut total_power = image_power;
+        let mut sampled_power = 0.0;
+        for (power, spectrum) in lights {
+            if !(power.is_finite() && power > 0.0) {
+                continue;
+            }
+            total_power += power;
+            if let Some(light_density) = self.wavelength_density(spectrum) {
+                sampled_power += power;
+                for (d, l) in density.iter_mut().zip(light_density) {
+                    *d += power * l;
+                }
+            }
+        }
+        if sampled_power == 0.0 {
+            return None;
+        }
+
+        let visible_weight =
+            VISIBLE_FRACTION + (1.0 - VISIBLE_FRACTION) * (1.0 - sampled_power / total_power);
+        for (d, v) in density.iter_mut().zip(&visible) {
+            *d = visible_weight * v + (1.0 - VISIBLE_FRACTION) * *d / total_power;
+        }
+        Some(self.add_1d_table_sampler(360.0, 831.0, &density))
+    }
+
     pub fn set_area_light_transform(&mut self, light: LightId, transform: NodeId) {
         self.area_lights[light.idx()].transform_node = transform;
     }
@@ -122,18 +313,19 @@ impl Scene {
             LightType::Uniform => self.uniform_lights[light.idx()].light_sampling_path = path,
             LightType::Image => self.image_lights[light.idx()].light_sampling_path = path,
             LightType::Area => self.area_lights[light.idx()].light_sampling_path = path,
+            LightType::Portal => self.portal_lights[light.idx()].light_sampling_path = path,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct UniformLight {
     pub spectrum: SpectrumId,
     pub light_sampling_path: u32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ImageLight {
     pub transform: Transform,
@@ -144,7 +336,22 @@ pub struct ImageLight {
     pub _padding: [u32; 2],
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct PortalLight {
+    pub transform: Transform,
+    // frame with the portal spanning [0, size] in the xy plane and the environment towards +z
+    pub portal: Transform,
+    pub size: Vec2,
+    pub image: u32,
+    pub scale: f32,
+    pub light_sampling_path: u32,
+    pub sat_ptr: u32,
+    pub resolution: u32,
+    pub _padding: u32,
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct AreaLight {
     pub spectrum: SpectrumId,
@@ -154,3 +361,61 @@ pub struct AreaLight {
     pub two_sided: u32,
     pub light_sampling_path: u32,
 }
+
+// Summed area table of the environment's luminance over the rectified parameterization of the
+// hemisphere outside a portal used by shaders/light/portal.wgsl, weighted by solid angle so that
+// sampling is proportional to radiance. Entry (x, y) of the (n + 1)^2 table is the integral over
+// [0, x/n] x [0, y/n].
+fn portal_summed_area_table(
+    n: u32,
+    light_from_portal: Mat4,
+    radiance: impl Fn(Vec2) -> f32,
+) -> Vec<f32> {
+    const SUBSAMPLES: u32 = 4;
+    let n = n as usize;
+    let row = n + 1;
+    let mut sat = vec![0.0f64; row * row];
+    for y in 0..n {
+        for x in 0..n {
+            let mut sum = 0.0;
+            for i in 0..SUBSAMPLES * SUBSAMPLES {
+                let offset = Vec2::new((i % SUBSAMPLES) as f32, (i / SUBSAMPLES) as f32) + 0.5;
+                let uv = (Vec2::new(x as f32, y as f32) + offset / SUBSAMPLES as f32) / n as f32;
+                let w = ((uv - 0.5) * std::f32::consts::PI)
+                    .map(f32::tan)
+                    .extend(1.0)
+                    .normalize();
+                let jacobian =
+                    std::f32::consts::PI.powi(2) * (1.0 - w.x * w.x) * (1.0 - w.y * w.y) / w.z;
+                let dir = light_from_portal * Vec4::from((w, 0.0));
+                sum += radiance(equal_area_dir_to_square(dir.truncate().normalize())) * jacobian;
+            }
+            let f = sum / (SUBSAMPLES * SUBSAMPLES) as f32 / (n * n) as f32;
+            sat[(y + 1) * row + x + 1] =
+                f as f64 + sat[y * row + x + 1] + sat[(y + 1) * row + x] - sat[y * row + x];
+        }
+    }
+    sat.into_iter().map(|v| v as f32).collect()
+}
+
+// matches equal_area_dir_to_square in shaders/util/spherical.wgsl
+fn equal_area_dir_to_square(dir: Vec3) -> Vec2 {
+    let d = dir.abs();
+    let r = (1.0 - d.z).max(0.0).sqrt();
+    let a = d.x.max(d.y);
+    let b = d.x.min(d.y);
+    let mut phi = b.atan2(a) * 2.0 / std::f32::consts::PI;
+    if d.x < d.y {
+        phi = 1.0 - phi;
+    }
+    let mut p = Vec2::new(r - phi * r, phi * r);
+    if dir.z < 0.0 {
+        p = Vec2::ONE - Vec2::new(p.y, p.x);
+    }
+    let sign = Vec2::select(
+        dir.truncate().cmpeq(Vec2::ZERO),
+        Vec2::ONE,
+        dir.truncate().signum(),
+    );
+    (p * sign + 1.0) * 0.5
+}
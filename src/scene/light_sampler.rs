@@ -1,8 +1,13 @@
-use bytemuck::NoUninit;
+use std::collections::HashSet;
 
-use crate::scene::{LightId, Scene};
+use bytemuck::{CheckedBitPattern, NoUninit};
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+use crate::scene::{LightId, NodeId, Scene};
+
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit, CheckedBitPattern, Serialize, Deserialize,
+)]
 #[repr(C)]
 pub struct LightSamplerId(u32);
 
@@ -139,23 +144,118 @@ impl Scene {
 
         id
     }
+
+    // Takes area lights out of NEE and rebuilds the root light sampler without them, for lights
+    // that moved with the instance paired with them. Marking them as transformed makes the
+    // shaders leave them to BSDF sampling.
+    pub fn stop_sampling_lights(&mut self, lights: &[(LightId, NodeId)]) {
+        for &(light, instance) in lights {
+            self.set_area_light_transform(light, instance);
+            self.set_light_sampling_path(light, u32::MAX);
+        }
+        let Some(root) = self.root_ls else {
+            return;
+        };
+        let sampled: Vec<LightId> = match root.ty() {
+            LightSamplerType::Uniform => {
+                let ls = &self.uniform_light_samplers[root.idx()];
+                self.uniform_light_sampler_data[ls.ptr as usize..(ls.ptr + ls.count) as usize]
+                    .to_vec()
+            }
+            LightSamplerType::Power => {
+                let ls = &self.power_light_samplers[root.idx()];
+                self.power_light_sampler_data[ls.ptr as usize..(ls.ptr + ls.count) as usize]
+                    .iter()
+                    .map(|bucket| bucket.light)
+                    .collect()
+            }
+        };
+        let moved: HashSet<_> = lights.iter().map(|&(light, _)| light).collect();
+        let remaining: Vec<_> = sampled
+            .into_iter()
+            .filter(|light| !moved.contains(light))
+            .collect();
+        self.root_ls = Some(self.add_power_light_sampler(&remaining));
+        self.dirty.lights = true;
+    }
+
+    pub fn power_light_sampler_health(&self) -> Vec<PowerSamplerHealth> {
+        self.power_light_samplers
+            .iter()
+            .filter(|ls| ls.count > 0)
+            .map(|ls| {
+                let table =
+                    &self.power_light_sampler_data[ls.ptr as usize..(ls.ptr + ls.count) as usize];
+                let n = table.len();
+
+                let entropy: f32 = table
+                    .iter()
+                    .filter(|b| b.pmf > 0.0)
+                    .map(|b| b.pmf * b.pmf.recip().log2())
+                    .sum();
+
+                // probability of each light implied by the table, which should match the power
+                // distribution it was built from
+                let mut implied = vec![0.0f64; n];
+                for (i, b) in table.iter().enumerate() {
+                    implied[i] += b.q as f64 / n as f64;
+                    if b.alias != u32::MAX {
+                        implied[b.alias as usize] += (1.0 - b.q as f64) / n as f64;
+                    }
+                }
+                let max_error = implied
+                    .iter()
+                    .zip(table)
+                    .filter(|(_, b)| b.pmf > 0.0)
+                    .map(|(&p, b)| ((p - b.pmf as f64).abs() / b.pmf as f64) as f32)
+                    .fold(0.0, f32::max);
+
+                PowerSamplerHealth {
+                    lights: n,
+                    entropy,
+                    min_q: table.iter().map(|b| b.q).fold(1.0, f32::min),
+                    max_error,
+                    zero_power: table.iter().filter(|b| b.pmf == 0.0).count(),
+                    unsampled: table
+                        .iter()
+                        .zip(&implied)
+                        .filter(|&(b, &p)| b.pmf > 0.0 && p == 0.0)
+                        .count(),
+                }
+            })
+            .collect()
+    }
+}
+
+// Quality of a power light sampler's alias table
+pub struct PowerSamplerHealth {
+    pub lights: usize,
+    // bits; 2^entropy is the number of equally likely lights with the same spread
+    pub entropy: f32,
+    pub min_q: f32,
+    // largest difference between a light's pmf and the probability implied by the table,
+    // relative to the pmf
+    pub max_error: f32,
+    pub zero_power: usize,
+    // lights with nonzero pmf which the table can never pick
+    pub unsampled: usize,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct UniformLightSampler {
     ptr: u32,
     count: u32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct PowerLightSampler {
     ptr: u32,
     count: u32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct PlsAliasBucket {
     light: LightId,
@@ -1,8 +1,9 @@
-use bytemuck::NoUninit;
+use bytemuck::{CheckedBitPattern, NoUninit, Pod, Zeroable};
+use serde::{Deserialize, Serialize};
 
-use crate::scene::{Scene, SpectrumId, TextureId};
+use crate::scene::{PiecewiseLinear2d, Scene, SpectrumId, TextureId};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 pub struct MaterialId(u32);
 
@@ -16,11 +17,13 @@ enum MaterialType {
     ThinDielectric = 4 << MaterialId::TAG_SHIFT,
     MetallicWorkflow = 5 << MaterialId::TAG_SHIFT,
     Mix = 6 << MaterialId::TAG_SHIFT,
+    Measured = 7 << MaterialId::TAG_SHIFT,
+    Principled = 8 << MaterialId::TAG_SHIFT,
 }
 
 #[allow(unused)]
 impl MaterialId {
-    const TAG_BITS: u32 = 3;
+    const TAG_BITS: u32 = 4;
     const TAG_SHIFT: u32 = 32 - Self::TAG_BITS;
     const IDX_MASK: u32 = (1 << Self::TAG_SHIFT) - 1;
     const TAG_MASK: u32 = !Self::IDX_MASK;
@@ -41,9 +44,40 @@ impl MaterialId {
     fn idx(self) -> usize {
         (self.0 & Self::IDX_MASK) as usize
     }
+
+    // the pbrt name of the kind of material, for reports grouped by it
+    pub fn type_name(self) -> &'static str {
+        match self.ty() {
+            MaterialType::Diffuse => "diffuse",
+            MaterialType::DiffuseTransmit => "diffusetransmission",
+            MaterialType::Conductor => "conductor",
+            MaterialType::Dielectric => "dielectric",
+            MaterialType::ThinDielectric => "thindielectric",
+            MaterialType::MetallicWorkflow => "metallicworkflow",
+            MaterialType::Mix => "mix",
+            MaterialType::Measured => "measured",
+            MaterialType::Principled => "principled",
+        }
+    }
 }
 
 impl Scene {
+    // parameters of a material, for inspecting the scene
+    pub fn describe_material(&self, material: MaterialId) -> String {
+        let i = material.idx();
+        match material.ty() {
+            MaterialType::Diffuse => format!("{:?}", self.diffuse_mat[i]),
+            MaterialType::DiffuseTransmit => format!("{:?}", self.diffuse_transmit_mat[i]),
+            MaterialType::Conductor => format!("{:?}", self.conductor_mat[i]),
+            MaterialType::Dielectric => format!("{:?}", self.dielectric_mat[i]),
+            MaterialType::ThinDielectric => format!("{:?}", self.thin_dielectric_mat[i]),
+            MaterialType::MetallicWorkflow => format!("{:?}", self.metallic_workflow_mat[i]),
+            MaterialType::Mix => format!("{:?}", self.mix_mat[i]),
+            MaterialType::Measured => format!("{:?}", self.measured_mat[i]),
+            MaterialType::Principled => format!("{:?}", self.principled_mat[i]),
+        }
+    }
+
     pub fn add_diffuse_material(
         &mut self,
         texture: TextureId,
@@ -158,16 +192,60 @@ impl Scene {
         self.mix_mat.push(MixMaterial { m1, m2, amount });
         id
     }
+
+    // the two materials a mix chooses between, or None if `material` isn't a mix
+    pub fn mix_materials(&self, material: MaterialId) -> Option<[MaterialId; 2]> {
+        match material.ty() {
+            MaterialType::Mix => {
+                let mix = &self.mix_mat[material.idx()];
+                Some([mix.m1, mix.m2])
+            }
+            _ => None,
+        }
+    }
+
+    pub fn set_mix_materials(&mut self, mix: MaterialId, [m1, m2]: [MaterialId; 2]) {
+        assert!(matches!(mix.ty(), MaterialType::Mix));
+        let mix = &mut self.mix_mat[mix.idx()];
+        mix.m1 = m1;
+        mix.m2 = m2;
+    }
+
+    pub fn add_measured_material(
+        &mut self,
+        material: MeasuredMaterial,
+        normal_map: Option<u32>,
+    ) -> MaterialId {
+        let id = MaterialId::new(MaterialType::Measured, self.measured_mat.len());
+        self.measured_mat.push(MeasuredMaterial {
+            normal_map: normal_map.unwrap_or(u32::MAX),
+            ..material
+        });
+        id
+    }
+
+    pub fn add_principled_material(
+        &mut self,
+        material: PrincipledMaterial,
+        normal_map: Option<u32>,
+    ) -> MaterialId {
+        let id = MaterialId::new(MaterialType::Principled, self.principled_mat.len());
+        self.principled_mat.push(PrincipledMaterial {
+            normal_map: normal_map.unwrap_or(u32::MAX),
+            ..material
+        });
+        id
+    }
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct DiffuseMaterial {
     pub normal_map: u32,
     pub texture: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct DiffuseTransmitMaterial {
     pub normal_map: u32,
@@ -176,7 +254,7 @@ pub struct DiffuseTransmitMaterial {
     pub scale: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ConductorMaterial {
     pub normal_map: u32,
@@ -186,7 +264,7 @@ pub struct ConductorMaterial {
     pub v_roughness: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct DielectricMaterial {
     pub normal_map: u32,
@@ -195,14 +273,14 @@ pub struct DielectricMaterial {
     pub v_roughness: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ThinDielectricMaterial {
     pub normal_map: u32,
     pub ior: SpectrumId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct MetallicWorkflowMaterial {
     pub normal_map: u32,
@@ -212,10 +290,37 @@ pub struct MetallicWorkflowMaterial {
     pub v_roughness: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct MixMaterial {
     pub m1: MaterialId,
     pub m2: MaterialId,
     pub amount: TextureId,
 }
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct MeasuredMaterial {
+    pub normal_map: u32,
+    pub isotropic: u32,
+    pub ndf: PiecewiseLinear2d,
+    pub sigma: PiecewiseLinear2d,
+    pub vndf: PiecewiseLinear2d,
+    pub luminance: PiecewiseLinear2d,
+    pub spectra: PiecewiseLinear2d,
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct PrincipledMaterial {
+    pub normal_map: u32,
+    pub base_color: TextureId,
+    pub metallic: TextureId,
+    pub roughness: TextureId,
+    pub specular_tint: TextureId,
+    pub clearcoat: TextureId,
+    pub clearcoat_roughness: TextureId,
+    pub sheen: TextureId,
+    pub transmission: TextureId,
+    pub eta: TextureId,
+}
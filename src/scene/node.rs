@@ -1,13 +1,20 @@
+use std::collections::{HashMap, HashSet};
 use std::time::Instant;
 
-use bytemuck::NoUninit;
-use glam::Vec3;
+use bytemuck::{CheckedBitPattern, NoUninit};
+use glam::{DMat4, Mat4, Vec3};
 use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::Transform;
-use crate::scene::{Bounds, LightId, MaterialId, Scene, ShapeId, TextureId};
+use crate::options::BvhQuality;
+use crate::scene::{
+    Bounds, ClipShapes, LightId, MaterialId, Scene, ShapeId, SplitShape, TextureId,
+};
+use crate::{AnimatedTransform, Transform};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit, CheckedBitPattern, Serialize, Deserialize,
+)]
 #[repr(C)]
 pub struct NodeId(u32);
 
@@ -17,6 +24,8 @@ enum NodeType {
     Bvh = 0 << NodeId::TAG_SHIFT,
     Transform = 1 << NodeId::TAG_SHIFT,
     Primitive = 2 << NodeId::TAG_SHIFT,
+    // primitives tested together, only in the wide BVH
+    Leaf = 3 << NodeId::TAG_SHIFT,
 }
 
 #[allow(unused)]
@@ -58,88 +67,315 @@ impl Scene {
         self.transform_nodes.push(TransformNode {
             transform,
             object: node,
-            _padding: [0; 3],
+            motion: u32::MAX,
+            _padding: [0; 2],
         });
         id
     }
 
+    // Transform node whose transform to world space is `motion` after `transform`, which moves
+    // the object while the shutter is open
+    pub fn add_moving_transform(
+        &mut self,
+        transform: Transform,
+        motion: AnimatedTransform,
+        node: NodeId,
+    ) -> NodeId {
+        let id = NodeId::new(NodeType::Transform, self.transform_nodes.len());
+        self.transform_nodes.push(TransformNode {
+            transform,
+            object: node,
+            motion: self.animated_transforms.len() as u32,
+            _padding: [0; 2],
+        });
+        self.animated_transforms.push(motion);
+        id
+    }
+
+    // the transform of a transform node from its object to its parent at `time` of the shutter
+    // interval
+    fn node_transform(&self, node: &TransformNode, time: f32) -> Mat4 {
+        match self.animated_transforms.get(node.motion as usize) {
+            Some(motion) => motion.at(time) * node.transform.m_inv,
+            None => node.transform.m_inv,
+        }
+    }
+
     pub fn add_bvh(&mut self, nodes: &[NodeId]) -> NodeId {
         let t = Instant::now();
 
         let mut bounded_objects: Vec<_> =
             nodes.iter().map(|&id| (id, self.node_bounds(id))).collect();
-        let result = self.build_bvh(&mut bounded_objects);
+        let base = self.bvh_nodes.len();
+        if self.bvh_quality == BvhQuality::High {
+            let splitter = Splitter {
+                primitives: &self.primitive_nodes,
+                shapes: self.clip_shapes(),
+                root_area: bounded_objects
+                    .iter()
+                    .fold(bounded_objects[0].1.clone(), |acc, (_, bb)| acc.union(bb))
+                    .surface_area(),
+            };
+            let objects = bounded_objects.len();
+            let budget = (SPATIAL_BUDGET * objects as f32) as usize;
+            let nodes = build_sbvh(&splitter, bounded_objects, budget, 0);
+            println!(
+                "Build BVH in {:.3?}, {} leaves for {objects} objects",
+                t.elapsed(),
+                nodes.len().div_ceil(2),
+            );
+            self.bvh_nodes.extend(nodes);
+            offset_bvh(&mut self.bvh_nodes[base..], base);
+            return NodeId::new(NodeType::Bvh, base);
+        }
+
+        // a leaf node per object and a node per split
+        self.bvh_nodes
+            .resize(base + 2 * bounded_objects.len() - 1, BvhNode::EMPTY);
+        let result = build_bvh(&mut self.bvh_nodes[base..], base, &mut bounded_objects);
 
         println!("Build BVH in {:.3?}", t.elapsed());
 
         result
     }
 
-    fn build_bvh(&mut self, objs: &mut [(NodeId, Bounds)]) -> NodeId {
-        assert!(!objs.is_empty());
-
-        let idx = self.bvh_nodes.len();
-        self.bvh_nodes.push(BvhNode {
-            min: Vec3::ZERO,
-            flags: 0,
-            max: Vec3::ZERO,
-            far_node: NodeId(0),
-        });
-
-        if let &mut [(node, ref bounds)] = objs {
-            self.bvh_nodes[idx].min = bounds.min;
-            self.bvh_nodes[idx].max = bounds.max;
-            self.bvh_nodes[idx].far_node = node;
-            self.bvh_nodes[idx].flags = 0;
-        } else {
-            let total_bounds = objs
-                .iter()
-                .fold(objs[0].1.clone(), |acc, (_, bb)| acc.union(bb));
+    // The nodes of `bvh`, which has to be the BVH added last, over the primitives from `prims`
+    // with indices relative to its root and the first primitive, for `add_relative_bvh`
+    pub fn relative_bvh(&self, bvh: NodeId, prims: &[NodeId]) -> Vec<BvhNode> {
+        let base = bvh.idx();
+        let first = prims[0].idx();
+        self.bvh_nodes[base..]
+            .iter()
+            .map(|&node| BvhNode {
+                far_node: match node.flags {
+                    0 => NodeId::new(NodeType::Primitive, node.far_node.idx() - first),
+                    _ => NodeId::new(NodeType::Bvh, node.far_node.idx() - base),
+                },
+                ..node
+            })
+            .collect()
+    }
 
-            let axis = total_bounds.size().max_position();
-            objs.par_sort_unstable_by_key(|(_, bb)| {
-                ordered_float::OrderedFloat(bb.centroid()[axis])
+    // Adds a BVH made by `relative_bvh` over the primitives `prims`. Returns none if the nodes
+    // don't form a BVH over that many primitives, which can't be trusted since they come from a
+    // file.
+    pub fn add_relative_bvh(&mut self, nodes: &[BvhNode], prims: &[NodeId]) -> Option<NodeId> {
+        let is = |id: NodeId, ty: NodeType| id.0 & NodeId::TAG_MASK == ty as u32;
+        // children come after their parent, so following them always ends
+        let valid = !nodes.is_empty()
+            && nodes.iter().enumerate().all(|(i, node)| match node.flags {
+                0 => is(node.far_node, NodeType::Primitive) && node.far_node.idx() < prims.len(),
+                1 | 2 | 4 => {
+                    let far = node.far_node.idx();
+                    is(node.far_node, NodeType::Bvh) && i + 1 < far && far < nodes.len()
+                }
+                _ => false,
             });
+        if !valid {
+            return None;
+        }
 
-            let mut costs = vec![0.0; objs.len() - 1];
+        let base = self.bvh_nodes.len();
+        self.bvh_nodes.extend(nodes.iter().map(|&node| BvhNode {
+            far_node: match node.flags {
+                0 => prims[node.far_node.idx()],
+                _ => NodeId::new(NodeType::Bvh, node.far_node.idx() + base),
+            },
+            ..node
+        }));
+        Some(NodeId::new(NodeType::Bvh, base))
+    }
 
-            let mut bb = objs[0].1.clone();
-            for i in 1..objs.len() {
-                costs[i - 1] += i as f32 * bb.surface_area();
-                bb = bb.union(&objs[i].1);
+    // Use `new` in place of `old` on every primitive. Returns the number of primitives changed.
+    pub fn replace_material(&mut self, old: MaterialId, new: MaterialId) -> usize {
+        let mut count = 0;
+        for prim in &mut self.primitive_nodes {
+            if prim.material == old {
+                prim.material = new;
+                count += 1;
             }
+        }
+        self.dirty.primitive_nodes |= count > 0;
+        count
+    }
+
+    // object to world transform of an instance made by `add_transform`, when the shutter opens
+    pub fn instance_transform(&self, instance: NodeId) -> DMat4 {
+        assert!(matches!(instance.ty(), NodeType::Transform));
+        self.node_transform(&self.transform_nodes[instance.idx()], 0.0)
+            .as_dmat4()
+    }
+
+    // Move instances in the root BVH, then refit or rebuild the top level BVH over them. Only the
+    // top level is touched, so this costs about the number of instances however big their objects
+    // are, apart from finding the area lights in an instance the first time it moves. NEE samples
+    // area lights where their shapes were defined, so those are left to BSDF sampling from then on.
+    // The instances stop moving while the shutter is open, if they did.
+    pub fn set_instance_transforms(&mut self, moves: &[(NodeId, DMat4)]) {
+        for &(instance, transform) in moves {
+            assert!(matches!(instance.ty(), NodeType::Transform));
+            let node = &mut self.transform_nodes[instance.idx()];
+            node.transform = Transform {
+                m: transform.inverse().as_mat4(),
+                m_inv: transform.as_mat4(),
+            };
+            node.motion = u32::MAX;
+        }
+        self.dirty.transform_nodes = true;
+
+        if self.top_level.is_none() {
+            self.split_top_level();
+        }
+        let top = self.top_level.as_ref().unwrap();
+        let outside = moves
+            .iter()
+            .filter(|(instance, _)| {
+                top.objects
+                    .binary_search_by_key(&instance.0, |n| n.0)
+                    .is_err()
+            })
+            .count();
+        if outside > 0 {
+            println!("Warning: {outside} moved instances are not in the root BVH");
+        }
+        let built_cost = top.built_cost;
+        if self.refit_top_level() > TOP_LEVEL_REBUILD_COST * built_cost {
+            self.build_top_level();
+        }
+        self.dirty.bvh_nodes = true;
 
-            let mut bb = objs.last().unwrap().1.clone();
-            for i in 1..objs.len() {
-                costs[objs.len() - 1 - i] += i as f32 * bb.surface_area();
-                bb = bb.union(&objs[objs.len() - 1 - i].1);
+        let top = self.top_level.as_mut().unwrap();
+        let first_moves: Vec<_> = moves
+            .iter()
+            .map(|&(instance, _)| instance)
+            .filter(|&instance| top.unsampled.insert(instance))
+            .collect();
+        let mut lights = vec![];
+        for instance in first_moves {
+            let mut stack = vec![instance];
+            while let Some(node) = stack.pop() {
+                if let NodeType::Primitive = node.ty() {
+                    let light = self.primitive_nodes[node.idx()].light;
+                    if light != LightId::ZERO {
+                        lights.push((light, instance));
+                    }
+                }
+                stack.extend(self.node_children(node).into_iter().map(|(child, _)| child));
             }
+        }
+        if !lights.is_empty() {
+            self.stop_sampling_lights(&lights);
+        }
+    }
 
-            let split = 1 + costs
-                .iter()
-                .enumerate()
-                .min_by_key(|&(_, &cost)| ordered_float::OrderedFloat(cost))
-                .unwrap()
-                .0;
+    // Splits the root BVH into a top level over its instances and a BVH of everything else in
+    // it, so that moving instances doesn't touch the rest of the scene
+    fn split_top_level(&mut self) {
+        // the root BVH is built last, so all of its nodes come after its root
+        let root = self.root.unwrap().idx();
+        let mut objects: Vec<_> = self.bvh_nodes[root..]
+            .iter()
+            .filter(|node| node.flags == 0)
+            .map(|node| node.far_node)
+            .collect();
+        // spatial splits can put an object in several leaves
+        objects.sort_by_key(|node| node.0);
+        objects.dedup();
+        self.bvh_nodes.truncate(root);
 
-            let (left, right) = objs.split_at_mut(split);
+        let (mut top, rest): (Vec<_>, Vec<_>) = objects
+            .into_iter()
+            .partition(|node| matches!(node.ty(), NodeType::Transform));
+        if !rest.is_empty() {
+            top.push(self.add_bvh(&rest));
+            top.sort_by_key(|node| node.0);
+        }
+        self.top_level = Some(TopLevel {
+            start: self.bvh_nodes.len(),
+            objects: top,
+            built_cost: 0.0,
+            unsampled: HashSet::new(),
+        });
+        self.build_top_level();
+        // the root's objects are in a new BVH, which isn't collapsed yet
+        self.wide_bvh = WideBvh::default();
+    }
 
-            let left_node = self.build_bvh(left);
-            assert_eq!(idx + 1, left_node.idx());
-            let right_node = self.build_bvh(right);
+    fn build_top_level(&mut self) {
+        let top = self.top_level.as_ref().unwrap();
+        let start = top.start;
+        let mut objects: Vec<_> = top
+            .objects
+            .iter()
+            .map(|&id| (id, self.node_bounds(id)))
+            .collect();
+        self.bvh_nodes.truncate(start);
+        self.bvh_nodes
+            .resize(start + 2 * objects.len() - 1, BvhNode::EMPTY);
+        self.root = Some(build_bvh(&mut self.bvh_nodes[start..], start, &mut objects));
+        self.top_level.as_mut().unwrap().built_cost = top_level_cost(&self.bvh_nodes[start..]);
+    }
 
-            self.bvh_nodes[idx].min = total_bounds.min;
-            self.bvh_nodes[idx].max = total_bounds.max;
-            self.bvh_nodes[idx].far_node = right_node;
-            self.bvh_nodes[idx].flags = 1 << axis;
+    // Refits the top level from its leaves up, since children come after their parents. Returns
+    // the cost of the refitted top level.
+    fn refit_top_level(&mut self) -> f32 {
+        let start = self.top_level.as_ref().unwrap().start;
+        for idx in (start..self.bvh_nodes.len()).rev() {
+            let node = self.bvh_nodes[idx];
+            let bounds = match node.flags {
+                0 => self.node_bounds(node.far_node),
+                _ => self
+                    .node_bounds(NodeId::new(NodeType::Bvh, idx + 1))
+                    .union(&self.node_bounds(node.far_node)),
+            };
+            self.bvh_nodes[idx].min = bounds.min;
+            self.bvh_nodes[idx].max = bounds.max;
         }
+        top_level_cost(&self.bvh_nodes[start..])
+    }
+
+    // transform node index of an instance, as the GPU reports it
+    pub fn instance_index(&self, instance: NodeId) -> u32 {
+        assert!(matches!(instance.ty(), NodeType::Transform));
+        instance.idx() as u32
+    }
 
-        NodeId::new(NodeType::Bvh, idx)
+    // name of the object of a top level instance, by its transform node index
+    pub fn instance_name(&self, instance: u32) -> Option<&str> {
+        let id = NodeId::new(NodeType::Transform, instance as usize);
+        self.named_instances
+            .iter()
+            .find(|(_, instances)| instances.contains(&id))
+            .map(|(name, _)| name.as_str())
+    }
+
+    // children of a node with the transform from their space to the node's, for walking the
+    // hierarchy on the CPU
+    pub fn node_children(&self, node: NodeId) -> Vec<(NodeId, Mat4)> {
+        match node.ty() {
+            NodeType::Primitive => vec![],
+            NodeType::Leaf => unreachable!("leaves are only in the wide BVH"),
+            NodeType::Bvh => {
+                let bvh = &self.bvh_nodes[node.idx()];
+                match bvh.flags {
+                    0 => vec![(bvh.far_node, Mat4::IDENTITY)],
+                    _ => vec![
+                        (NodeId::new(NodeType::Bvh, node.idx() + 1), Mat4::IDENTITY),
+                        (bvh.far_node, Mat4::IDENTITY),
+                    ],
+                }
+            }
+            NodeType::Transform => {
+                let node = &self.transform_nodes[node.idx()];
+                vec![(node.object, self.node_transform(node, 0.0))]
+            }
+        }
     }
 
     pub fn node_bounds(&self, node: NodeId) -> Bounds {
         match node.ty() {
             NodeType::Primitive => self.shape_bounds(self.primitive_nodes[node.idx()].shape),
+            NodeType::Leaf => unreachable!("leaves are only in the wide BVH"),
             NodeType::Bvh => {
                 let bvh = &self.bvh_nodes[node.idx()];
                 Bounds {
@@ -150,18 +386,824 @@ impl Scene {
             NodeType::Transform => {
                 let node = &self.transform_nodes[node.idx()];
                 let bounds = self.node_bounds(node.object);
-                Bounds::from_points(
-                    bounds
-                        .corners()
-                        .into_iter()
-                        .map(|p| node.transform.m_inv.transform_point3(p)),
-                )
+                if node.motion == u32::MAX {
+                    return Bounds::from_points(
+                        bounds
+                            .corners()
+                            .into_iter()
+                            .map(|p| node.transform.m_inv.transform_point3(p)),
+                    );
+                }
+                // the bounds at many times while the shutter is open, grown by how far a
+                // rotation can bulge out between them
+                const STEPS: usize = 64;
+                let points = (0..=STEPS).flat_map(|i| {
+                    let m = self.node_transform(node, i as f32 / STEPS as f32);
+                    bounds.corners().map(|p| m.transform_point3(p))
+                });
+                let moved = Bounds::from_points(points);
+                let margin = moved.size().max_element() * 1e-3;
+                Bounds {
+                    min: moved.min - margin,
+                    max: moved.max + margin,
+                }
             }
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+// Splits are only looked for at the edges of this many bins along each axis
+const SAH_BINS: usize = 32;
+// Nodes this small are split between any two objects, which costs less than filling the bins
+const SWEEP_THRESHOLD: usize = 64;
+// Smaller subtrees are built on the current thread, and binned serially
+const PARALLEL_THRESHOLD: usize = 4096;
+
+// Depth first layout with the near child right after its parent, so `nodes` holds the whole
+// subtree of `objs` and starts at index `base` of the scene's BVH nodes
+fn build_bvh(nodes: &mut [BvhNode], base: usize, objs: &mut [(NodeId, Bounds)]) -> NodeId {
+    assert_eq!(nodes.len(), 2 * objs.len() - 1);
+
+    if let &mut [(node, ref bounds)] = objs {
+        nodes[0] = BvhNode {
+            min: bounds.min,
+            flags: 0,
+            max: bounds.max,
+            far_node: node,
+        };
+        return NodeId::new(NodeType::Bvh, base);
+    }
+
+    let ObjectSplit {
+        bounds: total_bounds,
+        axis,
+        left: split,
+        ..
+    } = find_split(objs);
+    let parallel = objs.len() > PARALLEL_THRESHOLD;
+
+    let (left, right) = objs.split_at_mut(split);
+    let (root, children) = nodes.split_first_mut().unwrap();
+    let (left_nodes, right_nodes) = children.split_at_mut(2 * split - 1);
+    let right_node = match parallel {
+        true => {
+            rayon::join(
+                || build_bvh(left_nodes, base + 1, left),
+                || build_bvh(right_nodes, base + 2 * split, right),
+            )
+            .1
+        }
+        false => {
+            build_bvh(left_nodes, base + 1, left);
+            build_bvh(right_nodes, base + 2 * split, right)
+        }
+    };
+
+    *root = BvhNode {
+        min: total_bounds.min,
+        flags: 1 << axis,
+        max: total_bounds.max,
+        far_node: right_node,
+    };
+    NodeId::new(NodeType::Bvh, base)
+}
+
+// Most bins per axis for spatial splits, which are spread over the node instead of the
+// centroids. Nodes with fewer objects get one per object, since finer bins mostly add work.
+const SPATIAL_BINS: usize = 32;
+// Spatial splits are only tried where the children of the object split overlap by more than this
+// fraction of the surface area of the whole BVH, as in Stich et al. 2009
+const SPATIAL_MIN_OVERLAP: f32 = 1e-5;
+// deeper than this, objects aren't split any further, so degenerate ones can't recurse forever
+const SPATIAL_MAX_DEPTH: u32 = 64;
+// splits may add at most this many references per object
+const SPATIAL_BUDGET: f32 = 1.0;
+
+// Like `build_bvh`, but shapes may also be split by a plane between the two children, and go in
+// both of them with the bounds of their part on each side. Returns the nodes with indices
+// relative to the root, since the number of nodes isn't known up front.
+fn build_sbvh(
+    splitter: &Splitter,
+    mut refs: Vec<(NodeId, Bounds)>,
+    budget: usize,
+    depth: u32,
+) -> Vec<BvhNode> {
+    if let [(node, ref bounds)] = refs[..] {
+        return vec![BvhNode {
+            min: bounds.min,
+            flags: 0,
+            max: bounds.max,
+            far_node: node,
+        }];
+    }
+
+    let count = refs.len();
+    let object = find_split(&mut refs);
+    let overlap = || {
+        let side = |refs: &[(NodeId, Bounds)]| {
+            refs.iter()
+                .fold(refs[0].1.clone(), |acc, (_, bb)| acc.union(bb))
+        };
+        let (left, right) = refs.split_at(object.left);
+        side(left)
+            .intersection(&side(right))
+            .map_or(0.0, |bb| bb.surface_area())
+    };
+    let min_overlap = SPATIAL_MIN_OVERLAP * splitter.root_area;
+    let spatial = match budget > 0 && depth < SPATIAL_MAX_DEPTH && overlap() > min_overlap {
+        true => splitter.find_split(&refs, &object.bounds),
+        false => None,
+    };
+
+    let (axis, left, right) = match spatial {
+        Some((cost, axis, plane)) if cost < object.cost => {
+            match splitter.partition(&refs, axis, plane) {
+                Some((left, right)) if left.len() + right.len() <= refs.len() + budget => {
+                    (axis, left, right)
+                }
+                _ => {
+                    let right = refs.split_off(object.left);
+                    (object.axis, refs, right)
+                }
+            }
+        }
+        _ => {
+            let right = refs.split_off(object.left);
+            (object.axis, refs, right)
+        }
+    };
+
+    // what's left of the budget is shared by the size of each side
+    let total = left.len() + right.len();
+    let budget = (budget + count).saturating_sub(total);
+    let left_budget = budget * left.len() / total;
+    let right_budget = budget - left_budget;
+    let build = |refs, budget| build_sbvh(splitter, refs, budget, depth + 1);
+    let (mut left, mut right) = match total > PARALLEL_THRESHOLD {
+        true => rayon::join(|| build(left, left_budget), || build(right, right_budget)),
+        false => (build(left, left_budget), build(right, right_budget)),
+    };
+
+    let mut nodes = Vec::with_capacity(1 + left.len() + right.len());
+    nodes.push(BvhNode {
+        min: object.bounds.min,
+        flags: 1 << axis,
+        max: object.bounds.max,
+        far_node: NodeId::new(NodeType::Bvh, 1 + left.len()),
+    });
+    offset_bvh(&mut left, 1);
+    offset_bvh(&mut right, 1 + left.len());
+    nodes.append(&mut left);
+    nodes.append(&mut right);
+    nodes
+}
+
+// Moves the BVH nodes of a subtree `offset` places further along
+fn offset_bvh(nodes: &mut [BvhNode], offset: usize) {
+    for node in nodes {
+        // leaves point to their object instead
+        if node.flags != 0 {
+            node.far_node = NodeId::new(NodeType::Bvh, node.far_node.idx() + offset);
+        }
+    }
+}
+
+struct Splitter<'a> {
+    primitives: &'a [PrimitiveNode],
+    shapes: ClipShapes<'a>,
+    // of the whole BVH
+    root_area: f32,
+}
+
+impl Splitter<'_> {
+    // Only primitives are split, since instances and their BVHs are moved by refitting, which
+    // has to find them in one place
+    fn splittable(&self, node: NodeId) -> bool {
+        matches!(node.ty(), NodeType::Primitive)
+    }
+
+    fn shape(&self, node: NodeId) -> SplitShape {
+        self.shapes.get(self.primitives[node.idx()].shape)
+    }
+
+    // The plane with the lowest surface area heuristic cost among the bin edges of each axis,
+    // with the shapes straddling it counted on both sides
+    fn find_split(&self, refs: &[(NodeId, Bounds)], bounds: &Bounds) -> Option<(f32, usize, f32)> {
+        let mut best: Option<(f32, usize, f32)> = None;
+        let count = refs.len().min(SPATIAL_BINS);
+        for axis in 0..3 {
+            let width = bounds.size()[axis] / count as f32;
+            if width <= 0.0 {
+                continue;
+            }
+            let edge = |i: usize| bounds.min[axis] + i as f32 * width;
+            let bin_of = |x: f32| (((x - bounds.min[axis]) / width) as usize).min(count - 1);
+
+            // the count of each bin is the shapes starting in it
+            let mut bins = [Bin::EMPTY; SPATIAL_BINS];
+            let mut ends = [0; SPATIAL_BINS];
+            for (node, bb) in refs {
+                if !self.splittable(*node) {
+                    let b = bin_of(bb.centroid()[axis]);
+                    bins[b].add(bb.min, bb.max);
+                    ends[b] += 1;
+                    continue;
+                }
+                let first = bin_of(bb.min[axis]);
+                let last = bin_of(bb.max[axis]);
+                bins[first].count += 1;
+                ends[last] += 1;
+                if first == last {
+                    bins[first].grow(bb);
+                    continue;
+                }
+                // chop off the part in each bin in turn
+                let shape = self.shape(*node);
+                let mut rest = Some(bb.clone());
+                for (b, bin) in bins[..last].iter_mut().enumerate().skip(first) {
+                    let Some(part) = rest else {
+                        break;
+                    };
+                    let (below, above) = shape.split_bounds(&part, axis, edge(b + 1));
+                    if let Some(below) = below {
+                        bin.grow(&below);
+                    }
+                    rest = above;
+                }
+                if let Some(rest) = rest {
+                    bins[last].grow(&rest);
+                }
+            }
+
+            let mut right_costs = [0.0; SPATIAL_BINS];
+            let mut right_counts = [0; SPATIAL_BINS];
+            let mut right = Bin::EMPTY;
+            for i in (1..count).rev() {
+                right.merge(&Bin {
+                    count: ends[i],
+                    ..bins[i]
+                });
+                right_costs[i] = right.cost();
+                right_counts[i] = right.count;
+            }
+            let mut left = Bin::EMPTY;
+            for i in 1..count {
+                left.merge(&bins[i - 1]);
+                if left.count == 0 || right_counts[i] == 0 {
+                    continue;
+                }
+                let cost = left.cost() + right_costs[i];
+                if best.is_none_or(|(best, _, _)| cost < best) {
+                    best = Some((cost, axis, edge(i)));
+                }
+            }
+        }
+        best
+    }
+
+    // Splits `refs` by the plane, or none if everything ends up on one side
+    #[allow(clippy::type_complexity)]
+    fn partition(
+        &self,
+        refs: &[(NodeId, Bounds)],
+        axis: usize,
+        plane: f32,
+    ) -> Option<(Vec<(NodeId, Bounds)>, Vec<(NodeId, Bounds)>)> {
+        let mut left = vec![];
+        let mut right = vec![];
+        for (node, bb) in refs {
+            if !self.splittable(*node) {
+                match bb.centroid()[axis] < plane {
+                    true => left.push((*node, bb.clone())),
+                    false => right.push((*node, bb.clone())),
+                }
+            } else if bb.max[axis] <= plane {
+                left.push((*node, bb.clone()));
+            } else if bb.min[axis] >= plane {
+                right.push((*node, bb.clone()));
+            } else {
+                let (below, above) = self.shape(*node).split_bounds(bb, axis, plane);
+                left.extend(below.map(|bb| (*node, bb)));
+                right.extend(above.map(|bb| (*node, bb)));
+            }
+        }
+        (!left.is_empty() && !right.is_empty()).then_some((left, right))
+    }
+}
+
+struct ObjectSplit {
+    // of all the objects
+    bounds: Bounds,
+    axis: usize,
+    // number of objects on the left side, which come first
+    left: usize,
+    cost: f32,
+}
+
+// Picks the binned split with the lowest surface area heuristic cost and partitions `objs` by it
+fn find_split(objs: &mut [(NodeId, Bounds)]) -> ObjectSplit {
+    let parallel = objs.len() > PARALLEL_THRESHOLD;
+
+    let (total, centroids) = map_reduce(
+        objs,
+        parallel,
+        || (Bin::EMPTY, Bin::EMPTY),
+        |(total, centroids), (_, bb)| {
+            total.add(bb.min, bb.max);
+            centroids.add(bb.centroid(), bb.centroid());
+        },
+        |(total, centroids), (t, c)| {
+            total.merge(&t);
+            centroids.merge(&c);
+        },
+    );
+    let total_bounds = Bounds {
+        min: total.min,
+        max: total.max,
+    };
+    if objs.len() <= SWEEP_THRESHOLD {
+        let (axis, left, cost) = sweep_split(objs, &total_bounds);
+        return ObjectSplit {
+            bounds: total_bounds,
+            axis,
+            left,
+            cost,
+        };
+    }
+
+    // objects with the same centroid on an axis all go in its first bin
+    let extent = centroids.max - centroids.min;
+    let scale = Vec3::select(
+        extent.cmpgt(Vec3::ZERO),
+        SAH_BINS as f32 / extent,
+        Vec3::ZERO,
+    );
+    let bin_of = |bb: &Bounds, axis: usize| {
+        let offset = (bb.centroid()[axis] - centroids.min[axis]) * scale[axis];
+        (offset as usize).min(SAH_BINS - 1)
+    };
+
+    let bins = map_reduce(
+        objs,
+        parallel,
+        || [[Bin::EMPTY; SAH_BINS]; 3],
+        |bins, (_, bb)| {
+            for (axis, bins) in bins.iter_mut().enumerate() {
+                bins[bin_of(bb, axis)].add(bb.min, bb.max);
+            }
+        },
+        |bins, other| {
+            for (bins, other) in bins.iter_mut().zip(&other) {
+                for (bin, other) in bins.iter_mut().zip(other) {
+                    bin.merge(other);
+                }
+            }
+        },
+    );
+
+    let mut best: Option<(f32, usize, usize)> = None;
+    for (axis, bins) in bins.iter().enumerate() {
+        // cost of everything right of each bin edge
+        let mut right_costs = [0.0; SAH_BINS];
+        let mut right = Bin::EMPTY;
+        for i in (1..SAH_BINS).rev() {
+            right.merge(&bins[i]);
+            right_costs[i] = right.cost();
+        }
+        let mut left = Bin::EMPTY;
+        for i in 1..SAH_BINS {
+            left.merge(&bins[i - 1]);
+            if left.count == 0 || left.count == objs.len() {
+                continue;
+            }
+            let cost = left.cost() + right_costs[i];
+            if best.is_none_or(|(best, _, _)| cost < best) {
+                best = Some((cost, axis, i));
+            }
+        }
+    }
+
+    let Some((cost, axis, split_bin)) = best else {
+        // all the centroids are in one bin, so split in the middle of the longest axis
+        let axis = total_bounds.size().max_position();
+        let mid = objs.len() / 2;
+        objs.select_nth_unstable_by_key(mid, |(_, bb)| {
+            ordered_float::OrderedFloat(bb.centroid()[axis])
+        });
+        return ObjectSplit {
+            bounds: total_bounds,
+            axis,
+            left: mid,
+            // as bad as it gets, since the sides may overlap entirely
+            cost: f32::INFINITY,
+        };
+    };
+
+    let mut split = 0;
+    for i in 0..objs.len() {
+        if bin_of(&objs[i].1, axis) < split_bin {
+            objs.swap(split, i);
+            split += 1;
+        }
+    }
+    ObjectSplit {
+        bounds: total_bounds,
+        axis,
+        left: split,
+        cost,
+    }
+}
+
+// Sorts `objs` along the longest axis and returns it and the split with the lowest cost
+fn sweep_split(objs: &mut [(NodeId, Bounds)], total_bounds: &Bounds) -> (usize, usize, f32) {
+    let axis = total_bounds.size().max_position();
+    objs.sort_unstable_by_key(|(_, bb)| ordered_float::OrderedFloat(bb.centroid()[axis]));
+
+    let mut costs = vec![0.0; objs.len() - 1];
+    let mut right = Bin::EMPTY;
+    for i in (1..objs.len()).rev() {
+        right.add(objs[i].1.min, objs[i].1.max);
+        costs[i - 1] = right.cost();
+    }
+    let mut left = Bin::EMPTY;
+    for i in 1..objs.len() {
+        left.add(objs[i - 1].1.min, objs[i - 1].1.max);
+        costs[i - 1] += left.cost();
+    }
+
+    let (split, &cost) = costs
+        .iter()
+        .enumerate()
+        .min_by_key(|&(_, &cost)| ordered_float::OrderedFloat(cost))
+        .unwrap();
+    (axis, 1 + split, cost)
+}
+
+// Accumulates `objs` into a `T`, in chunks over all threads if `parallel`
+fn map_reduce<T: Send>(
+    objs: &[(NodeId, Bounds)],
+    parallel: bool,
+    init: impl Fn() -> T + Sync + Send,
+    add: impl Fn(&mut T, &(NodeId, Bounds)) + Sync + Send,
+    merge: impl Fn(&mut T, T) + Sync + Send,
+) -> T {
+    let accumulate = |chunk: &[(NodeId, Bounds)]| {
+        let mut acc = init();
+        for obj in chunk {
+            add(&mut acc, obj);
+        }
+        acc
+    };
+    match parallel {
+        true => objs
+            .par_chunks(1024)
+            .map(accumulate)
+            .reduce_with(|mut a, b| {
+                merge(&mut a, b);
+                a
+            })
+            .unwrap(),
+        false => accumulate(objs),
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Bin {
+    min: Vec3,
+    max: Vec3,
+    count: usize,
+}
+
+impl Bin {
+    const EMPTY: Bin = Bin {
+        min: Vec3::INFINITY,
+        max: Vec3::NEG_INFINITY,
+        count: 0,
+    };
+
+    fn add(&mut self, min: Vec3, max: Vec3) {
+        self.min = self.min.min(min);
+        self.max = self.max.max(max);
+        self.count += 1;
+    }
+
+    // takes in more of an object already counted
+    fn grow(&mut self, bounds: &Bounds) {
+        self.min = self.min.min(bounds.min);
+        self.max = self.max.max(bounds.max);
+    }
+
+    fn merge(&mut self, other: &Bin) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.count += other.count;
+    }
+
+    // surface area times the number of objects, as the leaves all hold one
+    fn cost(&self) -> f32 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let bounds = Bounds {
+            min: self.min,
+            max: self.max,
+        };
+        bounds.surface_area() * self.count as f32
+    }
+}
+
+// The top level of the root BVH is refitted as instances move until its cost grows past this
+// many times its cost when it was built
+const TOP_LEVEL_REBUILD_COST: f32 = 1.5;
+
+// The root BVH once it's split for moving instances, with the instances and a BVH of everything
+// else in the root as its objects
+pub struct TopLevel {
+    // the first of its nodes, which are the last BVH nodes
+    start: usize,
+    // sorted by id
+    objects: Vec<NodeId>,
+    built_cost: f32,
+    // instances moved since loading, whose area lights are no longer sampled
+    unsampled: HashSet<NodeId>,
+}
+
+// Surface area of the inner nodes relative to the root, as in the SAH
+fn top_level_cost(nodes: &[BvhNode]) -> f32 {
+    let area = |node: &BvhNode| {
+        Bounds {
+            min: node.min,
+            max: node.max,
+        }
+        .surface_area()
+    };
+    let inner: f32 = nodes.iter().filter(|n| n.flags != 0).map(area).sum();
+    inner / area(&nodes[0])
+}
+
+// Children per node of the BVHs as the GPU traverses them
+const WIDE_BVH_WIDTH: usize = 4;
+
+// Most primitives in a leaf of the wide BVH, which its id holds less one in its lowest bits
+const MAX_LEAF_PRIMITIVES: usize = 4;
+const LEAF_COUNT_BITS: u32 = 2;
+
+// Costs of traversing a binary node and testing a primitive, relative to each other, for deciding
+// when a subtree is cheaper as a leaf
+const NODE_COST: f32 = 1.0;
+const PRIMITIVE_COST: f32 = 1.0;
+
+// The scene's BVHs collapsed into wide nodes for the GPU, with the root and transform nodes
+// pointing into them instead
+#[derive(Default)]
+pub struct WideBvh {
+    pub nodes: Vec<WideBvhNode>,
+    pub root: Option<NodeId>,
+    pub transform_nodes: Vec<TransformNode>,
+    // indices of the primitives of the leaves, which are ranges of it
+    pub leaf_primitives: Vec<u32>,
+    roots: HashMap<NodeId, NodeId>,
+    // the root BVH's nodes and leaf primitives are last, from here on
+    root_start: usize,
+    root_leaf_start: usize,
+}
+
+impl Scene {
+    // Collapse the BVHs into `wide_bvh`, which the GPU gets in place of the binary ones. Once
+    // they're collapsed, only the root BVH is collapsed again, since only its nodes are edited.
+    pub fn collapse_bvh(&mut self) {
+        let wide = std::mem::take(&mut self.wide_bvh);
+        let full = wide.root.is_none();
+        let mut collapser = Collapser {
+            bvh_nodes: &self.bvh_nodes,
+            nodes: wide.nodes,
+            leaf_primitives: wide.leaf_primitives,
+            roots: wide.roots,
+        };
+        if full {
+            // everything the root refers to goes first, so the root's nodes can be replaced
+            for node in &self.transform_nodes {
+                collapser.remap(node.object);
+            }
+            for &object in self.top_level.iter().flat_map(|top| &top.objects) {
+                collapser.remap(object);
+            }
+        } else {
+            collapser.nodes.truncate(wide.root_start);
+            collapser.leaf_primitives.truncate(wide.root_leaf_start);
+            collapser.roots.retain(|_, w| w.idx() < wide.root_start);
+        }
+        let root_start = collapser.nodes.len();
+        let root_leaf_start = collapser.leaf_primitives.len();
+        let root = collapser.collapse(self.root.unwrap().idx());
+        let transform_nodes = self
+            .transform_nodes
+            .iter()
+            .map(|node| TransformNode {
+                object: collapser.remap(node.object),
+                ..*node
+            })
+            .collect();
+        self.wide_bvh = WideBvh {
+            nodes: collapser.nodes,
+            root: Some(root),
+            transform_nodes,
+            leaf_primitives: collapser.leaf_primitives,
+            roots: collapser.roots,
+            root_start,
+            root_leaf_start,
+        };
+    }
+}
+
+struct Collapser<'a> {
+    bvh_nodes: &'a [BvhNode],
+    nodes: Vec<WideBvhNode>,
+    leaf_primitives: Vec<u32>,
+    // wide node of each binary BVH collapsed so far, as several instances share one
+    roots: HashMap<NodeId, NodeId>,
+}
+
+// A child of a wide node: an inner node of the binary BVH being collapsed, which can be opened
+// into its own children, an inner node whose primitives make a leaf, or an object, which may be
+// another BVH
+#[derive(Copy, Clone)]
+enum Child {
+    Inner(usize),
+    Leaf(usize),
+    Object(NodeId),
+}
+
+impl Collapser<'_> {
+    // the node the GPU sees in place of `node`
+    fn remap(&mut self, node: NodeId) -> NodeId {
+        if !matches!(node.ty(), NodeType::Bvh) {
+            return node;
+        }
+        if let Some(&wide) = self.roots.get(&node) {
+            return wide;
+        }
+        let wide = self.collapse(node.idx());
+        self.roots.insert(node, wide);
+        wide
+    }
+
+    fn child(&self, idx: usize) -> (Child, Bounds) {
+        let node = &self.bvh_nodes[idx];
+        let bounds = Bounds {
+            min: node.min,
+            max: node.max,
+        };
+        match node.flags {
+            0 => (Child::Object(node.far_node), bounds),
+            _ if self.leaf(idx).is_some() => (Child::Leaf(idx), bounds),
+            _ => (Child::Inner(idx), bounds),
+        }
+    }
+
+    // The primitives under an inner node, if there are few enough for a leaf and testing them all
+    // is cheaper than traversing the subtree by the SAH
+    fn leaf(&self, idx: usize) -> Option<Vec<u32>> {
+        let area = |node: &BvhNode| {
+            Bounds {
+                min: node.min,
+                max: node.max,
+            }
+            .surface_area()
+        };
+        let mut prims = vec![];
+        let mut subtree_cost = 0.0;
+        let mut stack = vec![idx];
+        while let Some(i) = stack.pop() {
+            let node = &self.bvh_nodes[i];
+            if node.flags != 0 {
+                subtree_cost += area(node) * NODE_COST;
+                stack.extend([i + 1, node.far_node.idx()]);
+                continue;
+            }
+            if !matches!(node.far_node.ty(), NodeType::Primitive)
+                || prims.len() == MAX_LEAF_PRIMITIVES
+            {
+                return None;
+            }
+            subtree_cost += area(node) * PRIMITIVE_COST;
+            prims.push(node.far_node.idx() as u32);
+        }
+        // spatial splits can put a primitive under both sides
+        prims.sort_unstable();
+        prims.dedup();
+        let leaf_cost = prims.len() as f32 * area(&self.bvh_nodes[idx]) * PRIMITIVE_COST;
+        (leaf_cost <= subtree_cost).then_some(prims)
+    }
+
+    fn add_leaf(&mut self, idx: usize) -> NodeId {
+        let prims = self.leaf(idx).unwrap();
+        let first = self.leaf_primitives.len();
+        assert!(
+            first < 1 << (NodeId::TAG_SHIFT - LEAF_COUNT_BITS),
+            "too many primitives in leaves"
+        );
+        self.leaf_primitives.extend(&prims);
+        NodeId::new(NodeType::Leaf, first << LEAF_COUNT_BITS | (prims.len() - 1))
+    }
+
+    // Opens the largest inner node among the children until the node is full, so the wide
+    // node's children are the binary subtree's most likely hit nodes
+    fn collapse(&mut self, idx: usize) -> NodeId {
+        let mut children = vec![self.child(idx)];
+        while children.len() < WIDE_BVH_WIDTH {
+            let Some(open) = (0..children.len())
+                .filter(|&i| matches!(children[i].0, Child::Inner(_)))
+                .max_by_key(|&i| ordered_float::OrderedFloat(children[i].1.surface_area()))
+            else {
+                break;
+            };
+            let Child::Inner(inner) = children[open].0 else {
+                unreachable!()
+            };
+            children[open] = self.child(inner + 1);
+            children.push(self.child(self.bvh_nodes[inner].far_node.idx()));
+        }
+
+        let wide = self.nodes.len();
+        self.nodes.push(WideBvhNode::new(&children));
+        for (i, &(child, _)) in children.iter().enumerate() {
+            let id = match child {
+                Child::Inner(inner) => self.collapse(inner),
+                Child::Leaf(inner) => self.add_leaf(inner),
+                Child::Object(object) => self.remap(object),
+            };
+            self.nodes[wide].children[i] = id.0;
+        }
+        NodeId::new(NodeType::Bvh, wide)
+    }
+}
+
+impl WideBvhNode {
+    // Bounds are stored as steps of a power of two from the minimum of the node, rounding out so
+    // the GPU's boxes hold the children's bounds. The children's ids are left unused.
+    fn new(children: &[(Child, Bounds)]) -> Self {
+        let bounds = children
+            .iter()
+            .fold(children[0].1.clone(), |acc, (_, bb)| acc.union(bb));
+        let origin = bounds.min;
+        let mut node = WideBvhNode {
+            origin,
+            exponents: 0,
+            children: [u32::MAX; WIDE_BVH_WIDTH],
+            bounds: [0; 6],
+            _padding: [0; 2],
+        };
+        for axis in 0..3 {
+            let exponent = grid_exponent(origin[axis], bounds.max[axis]);
+            let scale = f32::from_bits(exponent << 23);
+            node.exponents |= exponent << (8 * axis);
+            for (i, (_, bb)) in children.iter().enumerate() {
+                let lo = quantize(origin[axis], scale, bb.min[axis], false);
+                let hi = quantize(origin[axis], scale, bb.max[axis], true);
+                node.bounds[axis] |= lo << (8 * i);
+                node.bounds[3 + axis] |= hi << (8 * i);
+            }
+        }
+        node
+    }
+}
+
+// Biased exponent of the smallest power of two that gets from `min` to `max` in 255 steps
+fn grid_exponent(min: f32, max: f32) -> u32 {
+    // the exponent of the step, plus one unless it's a power of two already
+    let step = ((max - min) / 255.0).to_bits();
+    let mut exponent = ((step >> 23) + (step & 0x7fffff != 0) as u32).clamp(1, 254);
+    while exponent < 254 && min + 255.0 * f32::from_bits(exponent << 23) < max {
+        exponent += 1;
+    }
+    exponent
+}
+
+// Step of the grid at or beyond `x` in the direction of `up`. The steps themselves are exact, but
+// adding the origin rounds, so this checks the point as the GPU computes it.
+fn quantize(origin: f32, scale: f32, x: f32, up: bool) -> u32 {
+    let at = |q: u32| origin + q as f32 * scale;
+    let steps = (x - origin) * scale.recip();
+    match up {
+        true => {
+            let mut q = steps.ceil().clamp(0.0, 255.0) as u32;
+            while q < 255 && at(q) < x {
+                q += 1;
+            }
+            q
+        }
+        false => {
+            let mut q = steps.floor().clamp(0.0, 255.0) as u32;
+            while q > 0 && at(q) > x {
+                q -= 1;
+            }
+            q
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct BvhNode {
     pub min: Vec3,
@@ -170,15 +1212,41 @@ pub struct BvhNode {
     pub far_node: NodeId,
 }
 
+impl BvhNode {
+    const EMPTY: BvhNode = BvhNode {
+        min: Vec3::ZERO,
+        flags: 0,
+        max: Vec3::ZERO,
+        far_node: NodeId(0),
+    };
+}
+
+// A node of the BVHs as the GPU traverses them. Each byte of the bounds is a child's bound on
+// an axis, as a step of the axis' grid.
 #[derive(Copy, Clone, Debug, NoUninit)]
 #[repr(C)]
+pub struct WideBvhNode {
+    pub origin: Vec3,
+    // biased exponent of the grid's step on each axis, a byte each
+    pub exponents: u32,
+    // ids of the child nodes, with unused children last and u32::MAX
+    pub children: [u32; WIDE_BVH_WIDTH],
+    // lower bounds on x, y and z, then upper bounds
+    pub bounds: [u32; 6],
+    pub _padding: [u32; 2],
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
 pub struct TransformNode {
     pub transform: Transform,
     pub object: NodeId,
-    pub _padding: [u32; 3],
+    // index of the node's animated transform, or u32::MAX if it doesn't move
+    pub motion: u32,
+    pub _padding: [u32; 2],
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct PrimitiveNode {
     pub shape: ShapeId,
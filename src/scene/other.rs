@@ -1,4 +1,7 @@
-use bytemuck::NoUninit;
+use anyhow::ensure;
+use bytemuck::{CheckedBitPattern, NoUninit, Zeroable};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::scene::Scene;
 
@@ -17,45 +20,121 @@ impl Scene {
         }
     }
 
-    pub fn add_2d_table_sampler(
+    // Distribution of an image's luminance over [0,1]^2, built straight from the rows of the image
+    // since environment maps can be 8K or more
+    pub fn image_sampling_distribution(&mut self, image: u32) -> TableSampler2d {
+        self.resolve_images();
+        let img = &self.images[image as usize];
+        let (width, height) = img.dimensions();
+        let cdfs = table_2d_cdfs(width, height, |y, row| img.luminance_row(y, row));
+
+        let cdf_ptr = self.add_float_data(&cdfs);
+        TableSampler2d {
+            min_x: 0.0,
+            max_x: 1.0,
+            min_y: 0.0,
+            max_y: 1.0,
+            cdf_ptr,
+            width,
+            height,
+        }
+    }
+
+    // Piecewise bilinear distribution over [0,1]^2, linearly interpolated between slices
+    // of up to three additional parameters. `data` is laid out as
+    // [params[0]][params[1]][params[2]][height][width].
+    pub fn add_piecewise_linear_2d(
         &mut self,
-        min_x: f32,
-        max_x: f32,
-        min_y: f32,
-        max_y: f32,
-        width: u32,
-        height: u32,
-        f: &[f32],
-    ) -> TableSampler2d {
-        let width = width as usize;
-        let height = height as usize;
-        assert_eq!(width * height, f.len());
-        let oned_size = (width + 1) * height;
+        width: usize,
+        height: usize,
+        params: &[&[f32]],
+        data: &[f32],
+        normalize: bool,
+    ) -> anyhow::Result<PiecewiseLinear2d> {
+        ensure!(
+            width >= 2 && height >= 2,
+            "table of {width}x{height} is too small to interpolate"
+        );
+        ensure!(params.len() <= 3, "too many table parameters");
+        ensure!(
+            params.iter().all(|p| !p.is_empty()),
+            "empty table parameter"
+        );
+        let n = width * height;
+        let len = params
+            .iter()
+            .try_fold(n, |len: usize, p| len.checked_mul(p.len()));
+        ensure!(
+            len == Some(data.len()),
+            "table has {} values, which doesn't match its shape",
+            data.len()
+        );
+        let slices = params.iter().map(|p| p.len()).product::<usize>();
 
-        let mut cdfs = vec![0.0; (oned_size + height + 1) as usize];
-        for (cdf, f) in cdfs.chunks_mut(width + 1).zip(f.chunks(width)) {
-            for i in 0..f.len() {
-                cdf[i + 1] = cdf[i] + f[i].abs();
+        let mut data = data.to_vec();
+        let mut marginal_cdf = vec![];
+        let mut conditional_cdf = vec![];
+        if normalize {
+            marginal_cdf = vec![0.0; slices * height];
+            conditional_cdf = vec![0.0; slices * n];
+            for ((data, marginal), conditional) in data
+                .chunks_mut(n)
+                .zip(marginal_cdf.chunks_mut(height))
+                .zip(conditional_cdf.chunks_mut(n))
+            {
+                let mut sum = 0.0;
+                for y in 0..height - 1 {
+                    for x in 0..width - 1 {
+                        let i = y * width + x;
+                        sum +=
+                            0.25 * (data[i] + data[i + 1] + data[i + width] + data[i + width + 1]);
+                    }
+                }
+                let normalization = match sum > 0.0 {
+                    true => ((width - 1) * (height - 1)) as f32 / sum,
+                    false => 0.0,
+                };
+                data.iter_mut().for_each(|v| *v *= normalization);
+
+                for (row, cdf) in data.chunks(width).zip(conditional.chunks_mut(width)) {
+                    for x in 0..width - 1 {
+                        cdf[x + 1] = cdf[x] + 0.5 * (row[x] + row[x + 1]);
+                    }
+                }
+                for y in 0..height - 1 {
+                    marginal[y + 1] = marginal[y]
+                        + 0.5
+                            * (conditional[(y + 1) * width - 1] + conditional[(y + 2) * width - 1]);
+                }
             }
         }
-        for y in 0..height {
-            cdfs[oned_size + y + 1] = cdfs[oned_size + y] + cdfs[y * (width + 1) + width];
+
+        let mut param_size = [1; 3];
+        let mut param_values_ptr = [0; 3];
+        let mut param_strides = [0; 3];
+        let mut stride = 1;
+        for (d, values) in params.iter().enumerate().rev() {
+            param_size[d] = values.len() as u32;
+            param_values_ptr[d] = self.add_float_data(values);
+            param_strides[d] = if values.len() > 1 { stride } else { 0 };
+            stride *= values.len() as u32;
         }
 
-        let cdf_ptr = self.add_float_data(&cdfs);
-        TableSampler2d {
-            min_x,
-            max_x,
-            min_y,
-            max_y,
-            cdf_ptr,
+        Ok(PiecewiseLinear2d {
             width: width as u32,
             height: height as u32,
-        }
+            data_ptr: self.add_float_data(&data),
+            marginal_cdf_ptr: self.add_float_data(&marginal_cdf),
+            conditional_cdf_ptr: self.add_float_data(&conditional_cdf),
+            dims: params.len() as u32,
+            param_size,
+            param_values_ptr,
+            param_strides,
+        })
     }
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, Zeroable, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct TableSampler1d {
     min_x: f32,
@@ -64,7 +143,30 @@ pub struct TableSampler1d {
     len: u32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+// The cdf of each row of a table followed by the cdf of the rows' totals, as sampled by
+// `table_2d_sample`. `row` writes the values of a row, and the rows are built in parallel.
+fn table_2d_cdfs(width: u32, height: u32, row: impl Fn(u32, &mut [f32]) + Sync) -> Vec<f32> {
+    let (width, height) = (width as usize, height as usize);
+    let oned_size = (width + 1) * height;
+
+    let mut cdfs = vec![0.0; oned_size + height + 1];
+    cdfs[..oned_size]
+        .par_chunks_mut(width + 1)
+        .enumerate()
+        .for_each(|(y, cdf)| {
+            // the values go after the leading zero, then are summed in place
+            row(y as u32, &mut cdf[1..]);
+            for i in 1..cdf.len() {
+                cdf[i] = cdf[i - 1] + cdf[i].abs();
+            }
+        });
+    for y in 0..height {
+        cdfs[oned_size + y + 1] = cdfs[oned_size + y] + cdfs[y * (width + 1) + width];
+    }
+    cdfs
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct TableSampler2d {
     min_x: f32,
@@ -75,3 +177,17 @@ pub struct TableSampler2d {
     width: u32,
     height: u32,
 }
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct PiecewiseLinear2d {
+    width: u32,
+    height: u32,
+    data_ptr: u32,
+    marginal_cdf_ptr: u32,
+    conditional_cdf_ptr: u32,
+    dims: u32,
+    param_size: [u32; 3],
+    param_values_ptr: [u32; 3],
+    param_strides: [u32; 3],
+}
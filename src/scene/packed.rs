@@ -0,0 +1,231 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec2, Vec2Swizzles, Vec3, Vec3Swizzles};
+use half::f16;
+use serde::{Deserialize, Serialize};
+
+use crate::scene::{Scene, SpillVec, TriVertex};
+
+// A mesh's vertices are stored as steps of a grid of their own, 16 bits per axis
+const GRID_STEPS: u32 = u16::MAX as u32;
+const MAX_GRIDS: usize = 1 << 16;
+
+// half floats are spaced more than a whole texture apart past this
+const MAX_PACKED_UV: f32 = 2048.0;
+
+// octahedral normals never use -32768, which snorm16 reads as -1 too
+const NO_NORMAL: u32 = 0x8000_8000;
+
+// TriVertex in half the space: the position as steps of its mesh's grid, the normal as a point on
+// an octahedron and the texture coordinates as half floats
+#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[repr(C)]
+pub struct PackedVertex {
+    // x steps, then y steps in the upper half
+    xy: u32,
+    // z steps, then the index of the grid in the upper half
+    z_grid: u32,
+    // two snorm16, or NO_NORMAL
+    n: u32,
+    // two half floats
+    uv: u32,
+}
+
+impl PackedVertex {
+    fn position(&self, grids: &[PositionGrid]) -> Vec3 {
+        let grid = &grids[(self.z_grid >> 16) as usize];
+        grid.position([self.xy & 0xFFFF, self.xy >> 16, self.z_grid & 0xFFFF])
+    }
+}
+
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct PositionGrid {
+    origin: Vec3,
+    // the grid's vertices run from here up to the next grid's first
+    first_vertex: u32,
+    step: Vec3,
+    _padding: u32,
+}
+
+impl PositionGrid {
+    // The step on each axis is the power of two that fits the mesh in `GRID_STEPS` steps, but no
+    // finer than the spacing of floats at the mesh's coordinates, and the origin is a multiple of
+    // it. `origin + q * step` is then exact, on the CPU and the GPU alike.
+    fn new(verts: &[TriVertex], first_vertex: u32) -> Self {
+        let (mut min, mut max) = (Vec3::INFINITY, Vec3::NEG_INFINITY);
+        for v in verts.iter().filter(|v| v.p.is_finite()) {
+            min = min.min(v.p);
+            max = max.max(v.p);
+        }
+        if min.cmpgt(max).any() {
+            (min, max) = (Vec3::ZERO, Vec3::ZERO);
+        }
+
+        let mut origin = Vec3::ZERO;
+        let mut step = Vec3::ZERO;
+        for axis in 0..3 {
+            let (min, max) = (min[axis], max[axis]);
+            let largest = min.abs().max(max.abs()).to_bits() >> 23;
+            let needed = ((max - min) / GRID_STEPS as f32).to_bits() >> 23;
+            let mut exponent = largest.saturating_sub(23).max(needed).clamp(1, 254);
+            loop {
+                step[axis] = f32::from_bits(exponent << 23);
+                origin[axis] = (min / step[axis]).floor() * step[axis];
+                if exponent == 254 || origin[axis] + GRID_STEPS as f32 * step[axis] >= max {
+                    break;
+                }
+                exponent += 1;
+            }
+        }
+
+        PositionGrid {
+            origin,
+            first_vertex,
+            step,
+            _padding: 0,
+        }
+    }
+
+    fn steps(&self, p: Vec3) -> [u32; 3] {
+        let steps = ((p - self.origin) / self.step).round();
+        steps
+            .to_array()
+            .map(|s| s.clamp(0.0, GRID_STEPS as f32) as u32)
+    }
+
+    fn position(&self, steps: [u32; 3]) -> Vec3 {
+        self.origin + Vec3::from_array(steps.map(|s| s as f32)) * self.step
+    }
+}
+
+fn pack_normal(n: Vec3) -> u32 {
+    if n == Vec3::ZERO {
+        return NO_NORMAL;
+    }
+    let n = n / n.abs().element_sum();
+    let mut p = n.xy();
+    if n.z < 0.0 {
+        // the lower half folds over the diagonals
+        let sign = Vec2::select(p.cmpge(Vec2::ZERO), Vec2::ONE, Vec2::NEG_ONE);
+        p = (1.0 - p.yx().abs()) * sign;
+    }
+    let [x, y] = p
+        .to_array()
+        .map(|c| (c.clamp(-1.0, 1.0) * 32767.0).round() as i16 as u16);
+    x as u32 | (y as u32) << 16
+}
+
+// as the shader's `unpack_normal`
+fn unpack_normal(bits: u32) -> Vec3 {
+    if bits == NO_NORMAL {
+        return Vec3::ZERO;
+    }
+    let snorm = |c: u32| (c as u16 as i16 as f32 / 32767.0).max(-1.0);
+    let p = Vec2::new(snorm(bits), snorm(bits >> 16));
+    let z = 1.0 - p.x.abs() - p.y.abs();
+    let mut n = p.extend(z);
+    if z < 0.0 {
+        let sign = Vec2::select(p.cmpge(Vec2::ZERO), Vec2::ONE, Vec2::NEG_ONE);
+        n = ((1.0 - p.yx().abs()) * sign).extend(z);
+    }
+    n.normalize()
+}
+
+fn pack_uv(u: f32, v: f32) -> u32 {
+    let half = |x: f32| f16::from_f32(x.clamp(f16::MIN.to_f32(), f16::MAX.to_f32())).to_bits();
+    half(u) as u32 | (half(v) as u32) << 16
+}
+
+fn unpack_uv(bits: u32) -> (f32, f32) {
+    let float = |x: u32| f16::from_bits(x as u16).to_f32();
+    (float(bits), float(bits >> 16))
+}
+
+// whether packing keeps the texture coordinates of the vertices usable
+pub(super) fn uvs_packable(verts: &[TriVertex]) -> bool {
+    verts
+        .iter()
+        .all(|v| v.u.abs() <= MAX_PACKED_UV && v.v.abs() <= MAX_PACKED_UV)
+}
+
+impl Scene {
+    // Adds the vertices of a mesh as they will be after packing, so that the BVH and lights are
+    // made from the positions the GPU decodes. Returns false once there are too many meshes.
+    pub(super) fn add_packed_vertices(&mut self, verts: &[TriVertex]) -> std::io::Result<bool> {
+        if verts.is_empty() {
+            return Ok(true);
+        }
+        if self.position_grids.len() == MAX_GRIDS {
+            return Ok(false);
+        }
+        let grid = PositionGrid::new(verts, self.triangle_vertices.len() as u32);
+        self.position_grids.push(grid);
+        self.triangle_vertices.extend(verts.iter().map(|vert| {
+            let (u, v) = unpack_uv(pack_uv(vert.u, vert.v));
+            TriVertex {
+                p: grid.position(grid.steps(vert.p)),
+                u,
+                n: unpack_normal(pack_normal(vert.n)),
+                v,
+            }
+        }))?;
+        Ok(true)
+    }
+
+    // whether the grids cover the vertices in order, as `add_packed_vertices` leaves them
+    pub fn position_grids_valid(&self) -> bool {
+        let starts = || {
+            self.position_grids
+                .iter()
+                .map(|grid| grid.first_vertex as usize)
+        };
+        let covered = match self.compress_geometry {
+            true => starts().next().unwrap_or(0) == 0 || self.triangle_vertices.is_empty(),
+            false => true,
+        };
+        self.position_grids.len() <= MAX_GRIDS
+            && covered
+            && starts().is_sorted()
+            && starts().all(|start| start <= self.triangle_vertices.len())
+    }
+
+    // The corners of a triangle, read from the packed vertices once the unpacked ones are dropped
+    pub(super) fn triangle_positions(&self, tri: usize) -> [Vec3; 3] {
+        let vertices = self.triangles[tri].vertices;
+        match self.packed_vertices.is_empty() {
+            true => vertices.map(|i| self.triangle_vertices[i as usize].p),
+            false => {
+                vertices.map(|i| self.packed_vertices[i as usize].position(&self.position_grids))
+            }
+        }
+    }
+
+    // Packs the vertices for uploading, if geometry is compressed, and drops the unpacked ones
+    pub fn pack_vertices(&mut self) -> std::io::Result<()> {
+        if !self.compress_geometry {
+            return Ok(());
+        }
+        let ends = self
+            .position_grids
+            .iter()
+            .skip(1)
+            .map(|grid| grid.first_vertex as usize)
+            .chain([self.triangle_vertices.len()]);
+        self.packed_vertices.clear();
+        self.packed_vertices.reserve(self.triangle_vertices.len())?;
+        for (i, (grid, end)) in self.position_grids.iter().zip(ends).enumerate() {
+            let verts = &self.triangle_vertices[grid.first_vertex as usize..end];
+            self.packed_vertices.extend(verts.iter().map(|v| {
+                let [x, y, z] = grid.steps(v.p);
+                PackedVertex {
+                    xy: x | y << 16,
+                    z_grid: z | (i as u32) << 16,
+                    n: pack_normal(v.n),
+                    uv: pack_uv(v.u, v.v),
+                }
+            }))?;
+        }
+        self.triangle_vertices = SpillVec::default();
+        Ok(())
+    }
+}
@@ -1,9 +1,13 @@
-use bytemuck::{NoUninit, Pod, Zeroable};
-use glam::Vec3;
+use bytemuck::{CheckedBitPattern, NoUninit, Pod, Zeroable};
+use glam::{BVec3, Mat3, Mat4, Vec3, Vec4};
+use serde::{Deserialize, Serialize};
 
-use crate::scene::{Bounds, Scene};
+use crate::Transform;
+use crate::scene::{Bounds, Scene, uvs_packable};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit, CheckedBitPattern, Serialize, Deserialize,
+)]
 #[repr(C)]
 pub struct ShapeId(u32);
 
@@ -12,11 +16,15 @@ pub struct ShapeId(u32);
 enum ShapeType {
     Sphere = 0 << ShapeId::TAG_SHIFT,
     Triangle = 1 << ShapeId::TAG_SHIFT,
+    Splat = 2 << ShapeId::TAG_SHIFT,
+    Sdf = 3 << ShapeId::TAG_SHIFT,
+    Csg = 4 << ShapeId::TAG_SHIFT,
+    Heightfield = 5 << ShapeId::TAG_SHIFT,
 }
 
 #[allow(unused)]
 impl ShapeId {
-    const TAG_BITS: u32 = 1;
+    const TAG_BITS: u32 = 3;
     const TAG_SHIFT: u32 = 32 - Self::TAG_BITS;
     const IDX_MASK: u32 = (1 << Self::TAG_SHIFT) - 1;
     const TAG_MASK: u32 = !Self::IDX_MASK;
@@ -39,18 +47,113 @@ impl ShapeId {
     }
 }
 
+// The shapes which can be clipped for splitting them between BVH nodes, borrowed apart from the
+// rest of the scene so that they can be shared between threads
+#[derive(Copy, Clone)]
+pub struct ClipShapes<'a> {
+    triangles: &'a [Triangle],
+    vertices: &'a [TriVertex],
+}
+
+impl ClipShapes<'_> {
+    pub fn get(&self, shape: ShapeId) -> SplitShape {
+        match shape.ty() {
+            ShapeType::Triangle => {
+                let tri = &self.triangles[shape.idx()];
+                SplitShape(Some(tri.vertices.map(|i| self.vertices[i as usize].p)))
+            }
+            _ => SplitShape(None),
+        }
+    }
+}
+
+// The corners of a triangle, which is split exactly, or none for other shapes, which just get
+// their bounds cut
+#[derive(Copy, Clone)]
+pub struct SplitShape(Option<[Vec3; 3]>);
+
+impl SplitShape {
+    // Bounds of the parts of the shape with `bounds` below and above `plane` on `axis`
+    pub fn split_bounds(
+        &self,
+        bounds: &Bounds,
+        axis: usize,
+        plane: f32,
+    ) -> (Option<Bounds>, Option<Bounds>) {
+        let mut below = bounds.clone();
+        below.max[axis] = below.max[axis].min(plane);
+        let mut above = bounds.clone();
+        above.min[axis] = above.min[axis].max(plane);
+        let Some(corners) = self.0 else {
+            return (bounds.intersection(&below), bounds.intersection(&above));
+        };
+
+        let mut tri_below: Option<Bounds> = None;
+        let mut tri_above: Option<Bounds> = None;
+        let add = |bounds: &mut Option<Bounds>, p: Vec3| {
+            *bounds = Some(match bounds {
+                Some(bb) => Bounds {
+                    min: bb.min.min(p),
+                    max: bb.max.max(p),
+                },
+                None => Bounds { min: p, max: p },
+            });
+        };
+        for i in 0..3 {
+            let (a, b) = (corners[i], corners[(i + 1) % 3]);
+            if a[axis] <= plane {
+                add(&mut tri_below, a);
+            }
+            if a[axis] >= plane {
+                add(&mut tri_above, a);
+            }
+            if (a[axis] < plane && b[axis] > plane) || (a[axis] > plane && b[axis] < plane) {
+                let mut p = a.lerp(b, (plane - a[axis]) / (b[axis] - a[axis]));
+                p[axis] = plane;
+                add(&mut tri_below, p);
+                add(&mut tri_above, p);
+            }
+        }
+        // the triangle may have been split before, and rounding can leave the points slightly
+        // outside
+        (
+            tri_below.and_then(|bb| bb.intersection(&below)),
+            tri_above.and_then(|bb| bb.intersection(&above)),
+        )
+    }
+}
+
 impl Scene {
     pub fn shape_bounds(&self, shape: ShapeId) -> Bounds {
         match shape.ty() {
             ShapeType::Sphere => self.spheres[shape.idx()].bounds(),
-            ShapeType::Triangle => self.triangles[shape.idx()].bounds(&self.triangle_vertices),
+            ShapeType::Triangle => {
+                Bounds::from_points(self.triangle_positions(shape.idx()).into_iter())
+            }
+            ShapeType::Splat => self.splats[shape.idx()].bounds(),
+            ShapeType::Sdf => self.sdfs[shape.idx()].bounds(),
+            ShapeType::Csg => self.csgs[shape.idx()].bounds(),
+            ShapeType::Heightfield => self.heightfields[shape.idx()].bounds(),
+        }
+    }
+
+    pub fn clip_shapes(&self) -> ClipShapes<'_> {
+        ClipShapes {
+            triangles: &self.triangles,
+            vertices: &self.triangle_vertices,
         }
     }
 
     pub fn shape_area(&self, shape: ShapeId) -> f32 {
         match shape.ty() {
             ShapeType::Sphere => self.spheres[shape.idx()].area(),
-            ShapeType::Triangle => self.triangles[shape.idx()].area(&self.triangle_vertices),
+            ShapeType::Triangle => {
+                let [p0, p1, p2] = self.triangle_positions(shape.idx());
+                (p1 - p0).cross(p2 - p0).length() / 2.0
+            }
+            ShapeType::Splat => self.splats[shape.idx()].area(),
+            // sdf, csg and heightfield sampling not supported
+            ShapeType::Sdf | ShapeType::Csg | ShapeType::Heightfield => 0.0,
         }
     }
 
@@ -64,21 +167,194 @@ impl Scene {
         &mut self,
         verts: &[TriVertex],
         tris: &[[u32; 3]],
-    ) -> impl Iterator<Item = ShapeId> + use<> {
+    ) -> std::io::Result<impl Iterator<Item = ShapeId> + use<>> {
         let base_index = self.triangle_vertices.len();
-        self.triangle_vertices.extend(verts);
+        if self.compress_geometry && !uvs_packable(verts) {
+            println!(
+                "Warning: texture coordinates too large for half floats, leaving geometry uncompressed"
+            );
+            self.compress_geometry = false;
+        }
+        if self.compress_geometry && !self.add_packed_vertices(verts)? {
+            println!("Warning: too many meshes to compress geometry, leaving it uncompressed");
+            self.compress_geometry = false;
+        }
+        if !self.compress_geometry {
+            self.triangle_vertices.extend_from_slice(verts)?;
+        }
 
         let base_idx = self.triangles.len();
         self.triangles.extend(tris.iter().map(|idx| Triangle {
             vertices: idx.map(|i| i + base_index as u32),
-        }));
+        }))?;
         let end_idx = self.triangles.len();
 
-        (base_idx..end_idx).map(|idx| ShapeId::new(ShapeType::Triangle, idx))
+        Ok((base_idx..end_idx).map(|idx| ShapeId::new(ShapeType::Triangle, idx)))
+    }
+
+    pub fn add_splats(&mut self, splats: &[Splat]) -> impl Iterator<Item = ShapeId> + use<> {
+        let base_idx = self.splats.len();
+        self.splats.extend_from_slice(splats);
+        let end_idx = self.splats.len();
+
+        (base_idx..end_idx).map(|idx| ShapeId::new(ShapeType::Splat, idx))
+    }
+
+    // `ops` must be a valid postfix program, see `SdfOp::stack_depth`
+    pub fn add_sdf(&mut self, ops: &[SdfOp]) -> ShapeId {
+        let mut stack = vec![];
+        for op in ops {
+            let bounds = match *op {
+                SdfOp::Sphere { center, radius } => Bounds {
+                    min: center - radius,
+                    max: center + radius,
+                },
+                SdfOp::Box {
+                    center,
+                    half_extents,
+                    ..
+                } => Bounds {
+                    min: center - half_extents,
+                    max: center + half_extents,
+                },
+                SdfOp::Torus {
+                    center,
+                    major,
+                    minor,
+                } => {
+                    let extent = Vec3::new(major + minor, major + minor, minor);
+                    Bounds {
+                        min: center - extent,
+                        max: center + extent,
+                    }
+                }
+                SdfOp::Union(k) => {
+                    let b = stack.pop().unwrap();
+                    let a: Bounds = stack.pop().unwrap();
+                    // smooth blends bulge out by up to a quarter of the smoothness
+                    Bounds {
+                        min: a.min.min(b.min) - k / 4.0,
+                        max: a.max.max(b.max) + k / 4.0,
+                    }
+                }
+                SdfOp::Intersection(_) => {
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    Bounds {
+                        min: a.min.max(b.min),
+                        max: a.max.min(b.max).max(a.min.max(b.min)),
+                    }
+                }
+                SdfOp::Subtraction(_) => {
+                    stack.pop().unwrap();
+                    stack.pop().unwrap()
+                }
+            };
+            stack.push(bounds);
+        }
+        let [bounds] = &stack[..] else {
+            panic!("sdf program leaves {} values on the stack", stack.len());
+        };
+
+        let start = self.sdf_nodes.len() as u32;
+        self.sdf_nodes.extend(ops.iter().map(SdfOp::node));
+
+        // a little margin so surfaces touching the bounds aren't clipped
+        let margin = (bounds.max - bounds.min).length() * 1e-4;
+        let id = ShapeId::new(ShapeType::Sdf, self.sdfs.len());
+        self.sdfs.push(Sdf {
+            min: bounds.min - margin,
+            start,
+            max: bounds.max + margin,
+            len: ops.len() as u32,
+        });
+        id
+    }
+
+    // `ops` must be a valid postfix program, see `CsgOp::stack_depth`
+    pub fn add_csg(&mut self, ops: &[CsgOp]) -> ShapeId {
+        let mut stack = vec![];
+        for op in ops {
+            let bounds = match op.leaf_to_shape() {
+                Some(m) => Bounds::from_points((0..8).map(|i| {
+                    let corner = BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0);
+                    m.transform_point3(Vec3::select(corner, Vec3::ONE, Vec3::NEG_ONE))
+                })),
+                None => {
+                    let b = stack.pop().unwrap();
+                    let a: Bounds = stack.pop().unwrap();
+                    match op {
+                        CsgOp::Union => Bounds {
+                            min: a.min.min(b.min),
+                            max: a.max.max(b.max),
+                        },
+                        CsgOp::Intersection => Bounds {
+                            min: a.min.max(b.min),
+                            max: a.max.min(b.max).max(a.min.max(b.min)),
+                        },
+                        _ => a,
+                    }
+                }
+            };
+            stack.push(bounds);
+        }
+        let [bounds] = &stack[..] else {
+            panic!("csg program leaves {} values on the stack", stack.len());
+        };
+
+        let start = self.csg_nodes.len() as u32;
+        self.csg_nodes.extend(ops.iter().map(CsgOp::node));
+
+        let margin = (bounds.max - bounds.min).length() * 1e-4;
+        let id = ShapeId::new(ShapeType::Csg, self.csgs.len());
+        self.csgs.push(Csg {
+            min: bounds.min - margin,
+            start,
+            max: bounds.max + margin,
+            len: ops.len() as u32,
+        });
+        id
+    }
+
+    // `heights` has `nx * ny` values in rows of `nx`, with at least two rows and columns
+    pub fn add_heightfield(&mut self, nx: u32, ny: u32, heights: &[f32]) -> ShapeId {
+        assert!(nx >= 2 && ny >= 2 && heights.len() == (nx * ny) as usize);
+        let size = Heightfield::BLOCK_SIZE;
+        let (bx, by) = ((nx - 1).div_ceil(size), (ny - 1).div_ceil(size));
+
+        // height range of the corners of the cells in each block
+        let mut blocks = Vec::with_capacity((bx * by * 2) as usize);
+        for y in 0..by {
+            for x in 0..bx {
+                let (x0, y0) = (x * size, y * size);
+                let (x1, y1) = ((x0 + size).min(nx - 1), (y0 + size).min(ny - 1));
+                let (min, max) = (y0..=y1)
+                    .flat_map(|y| (x0..=x1).map(move |x| heights[(y * nx + x) as usize]))
+                    .fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), z| {
+                        (lo.min(z), hi.max(z))
+                    });
+                blocks.extend([min, max]);
+            }
+        }
+
+        let min_z = heights.iter().copied().fold(f32::INFINITY, f32::min);
+        let max_z = heights.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let id = ShapeId::new(ShapeType::Heightfield, self.heightfields.len());
+        let heights = self.add_float_data(heights);
+        let blocks = self.add_float_data(&blocks);
+        self.heightfields.push(Heightfield {
+            nx,
+            ny,
+            heights,
+            blocks,
+            min_z,
+            max_z,
+        });
+        id
     }
 }
 
-#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Sphere {
     pub z_min: f32,
@@ -100,7 +376,7 @@ impl Sphere {
     }
 }
 
-#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 pub struct TriVertex {
     pub p: Vec3,
@@ -109,19 +385,274 @@ pub struct TriVertex {
     pub v: f32,
 }
 
-#[derive(Copy, Clone, Debug, Zeroable, Pod)]
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
 #[repr(C)]
 pub struct Triangle {
     pub vertices: [u32; 3],
 }
 
-impl Triangle {
-    fn bounds(&self, verts: &[TriVertex]) -> Bounds {
-        Bounds::from_points(self.vertices.iter().map(|&id| verts[id as usize].p))
+// Disc of a point cloud, facing the ray if `n` is zero
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Splat {
+    pub p: Vec3,
+    pub radius: f32,
+    pub n: Vec3,
+    // sRGB encoded rgba8
+    pub color: u32,
+}
+
+impl Splat {
+    fn bounds(&self) -> Bounds {
+        // a disc extends radius * sin(angle to the normal) along each axis
+        let extent = match self.n == Vec3::ZERO {
+            true => Vec3::splat(self.radius),
+            false => (1.0 - self.n * self.n).max(Vec3::ZERO).powf(0.5) * self.radius,
+        };
+        Bounds {
+            min: self.p - extent,
+            max: self.p + extent,
+        }
+    }
+
+    fn area(&self) -> f32 {
+        match self.n == Vec3::ZERO {
+            true => 0.0,
+            false => std::f32::consts::PI * self.radius * self.radius,
+        }
     }
+}
+
+// Signed distance function evaluated from a postfix program of nodes
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Sdf {
+    pub min: Vec3,
+    pub start: u32,
+    pub max: Vec3,
+    pub len: u32,
+}
 
-    fn area(&self, verts: &[TriVertex]) -> f32 {
-        let [p0, p1, p2] = self.vertices.map(|i| verts[i as usize].p);
-        (p1 - p0).cross(p2 - p0).length() / 2.0
+impl Sdf {
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct SdfNode {
+    op: u32,
+    smoothness: f32,
+    _padding: [u32; 2],
+    a: Vec4,
+    b: Vec4,
+}
+
+// Primitives push their distance and operators combine the top two values of the stack, blending
+// smoothly over the given distance
+#[derive(Copy, Clone, Debug)]
+pub enum SdfOp {
+    Sphere {
+        center: Vec3,
+        radius: f32,
+    },
+    Box {
+        center: Vec3,
+        half_extents: Vec3,
+        rounding: f32,
+    },
+    // around the z axis
+    Torus {
+        center: Vec3,
+        major: f32,
+        minor: f32,
+    },
+    Union(f32),
+    Intersection(f32),
+    // the second value carved out of the first
+    Subtraction(f32),
+}
+
+impl SdfOp {
+    // matches the stack in shaders/shapes/sdf.wgsl
+    pub const STACK_SIZE: usize = 8;
+
+    // largest stack depth while evaluating `ops`, or None if an operator is missing operands or
+    // the program does not leave exactly one value
+    pub fn stack_depth(ops: &[SdfOp]) -> Option<usize> {
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+        for op in ops {
+            match op {
+                SdfOp::Sphere { .. } | SdfOp::Box { .. } | SdfOp::Torus { .. } => depth += 1,
+                _ => depth = depth.checked_sub(2)? + 1,
+            }
+            max_depth = max_depth.max(depth);
+        }
+        (depth == 1).then_some(max_depth)
+    }
+
+    fn node(&self) -> SdfNode {
+        let (op, smoothness, a, b) = match *self {
+            SdfOp::Sphere { center, radius } => (0, 0.0, center.extend(radius), Vec4::ZERO),
+            SdfOp::Box {
+                center,
+                half_extents,
+                rounding,
+            } => (1, 0.0, center.extend(rounding), half_extents.extend(0.0)),
+            SdfOp::Torus {
+                center,
+                major,
+                minor,
+            } => (
+                2,
+                0.0,
+                center.extend(0.0),
+                Vec4::new(major, minor, 0.0, 0.0),
+            ),
+            SdfOp::Union(k) => (3, k, Vec4::ZERO, Vec4::ZERO),
+            SdfOp::Intersection(k) => (4, k, Vec4::ZERO, Vec4::ZERO),
+            SdfOp::Subtraction(k) => (5, k, Vec4::ZERO, Vec4::ZERO),
+        };
+        SdfNode {
+            op,
+            smoothness,
+            _padding: [0; 2],
+            a,
+            b,
+        }
+    }
+}
+
+// Solids combined with boolean operators, evaluated from a postfix program of nodes
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Csg {
+    pub min: Vec3,
+    pub start: u32,
+    pub max: Vec3,
+    pub len: u32,
+}
+
+impl Csg {
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: self.min,
+            max: self.max,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct CsgNode {
+    op: u32,
+    _padding: [u32; 3],
+    transform: Transform,
+}
+
+// Solids push the spans of the ray inside them and operators combine the top two entries of the
+// stack
+#[derive(Copy, Clone, Debug)]
+pub enum CsgOp {
+    Sphere { center: Vec3, radius: f32 },
+    Cylinder { p0: Vec3, p1: Vec3, radius: f32 },
+    Box { min: Vec3, max: Vec3 },
+    Union,
+    Intersection,
+    // the second solid carved out of the first
+    Difference,
+}
+
+impl CsgOp {
+    // matches the stack in shaders/shapes/csg.wgsl
+    pub const STACK_SIZE: usize = 4;
+
+    // largest stack depth while evaluating `ops`, or None if an operator is missing operands or
+    // the program does not leave exactly one solid
+    pub fn stack_depth(ops: &[CsgOp]) -> Option<usize> {
+        let mut depth = 0usize;
+        let mut max_depth = 0;
+        for op in ops {
+            match op.leaf_to_shape() {
+                Some(_) => depth += 1,
+                None => depth = depth.checked_sub(2)? + 1,
+            }
+            max_depth = max_depth.max(depth);
+        }
+        (depth == 1).then_some(max_depth)
+    }
+
+    // solids with no volume, which can't be transformed to their unit solid
+    pub fn is_degenerate(&self) -> bool {
+        self.leaf_to_shape()
+            .is_some_and(|m| !m.determinant().is_normal())
+    }
+
+    // from the unit solid of a leaf, see shaders/shapes/csg.wgsl
+    fn leaf_to_shape(&self) -> Option<Mat4> {
+        match *self {
+            CsgOp::Sphere { center, radius } => {
+                Some(Mat4::from_translation(center) * Mat4::from_scale(Vec3::splat(radius)))
+            }
+            CsgOp::Cylinder { p0, p1, radius } => {
+                let axis = (p1 - p0) / 2.0;
+                let (u, v) = axis.normalize().any_orthonormal_pair();
+                let m = Mat3::from_cols(u * radius, v * radius, axis);
+                Some(Mat4::from_mat3_translation(m, (p0 + p1) / 2.0))
+            }
+            CsgOp::Box { min, max } => Some(
+                Mat4::from_translation((min + max) / 2.0) * Mat4::from_scale((max - min) / 2.0),
+            ),
+            _ => None,
+        }
+    }
+
+    fn node(&self) -> CsgNode {
+        let op = match self {
+            CsgOp::Sphere { .. } => 0,
+            CsgOp::Cylinder { .. } => 1,
+            CsgOp::Box { .. } => 2,
+            CsgOp::Union => 3,
+            CsgOp::Intersection => 4,
+            CsgOp::Difference => 5,
+        };
+        let transform = match self.leaf_to_shape() {
+            Some(m) => Transform::from_mat4_inverse(m),
+            None => Transform::from_mat4(Mat4::IDENTITY),
+        };
+        CsgNode {
+            op,
+            _padding: [0; 3],
+            transform,
+        }
+    }
+}
+
+// Grid of heights over [0,1]^2 stored in the float data, followed by the height range of each block
+// of cells
+#[derive(Copy, Clone, Debug, Zeroable, Pod, Serialize, Deserialize)]
+#[repr(C)]
+pub struct Heightfield {
+    pub nx: u32,
+    pub ny: u32,
+    pub heights: u32,
+    pub blocks: u32,
+    pub min_z: f32,
+    pub max_z: f32,
+}
+
+impl Heightfield {
+    const BLOCK_SIZE: u32 = 16;
+
+    fn bounds(&self) -> Bounds {
+        Bounds {
+            min: Vec3::new(0.0, 0.0, self.min_z),
+            max: Vec3::new(1.0, 1.0, self.max_z),
+        }
     }
 }
@@ -1,9 +1,13 @@
-use bytemuck::NoUninit;
-use glam::{FloatExt, Vec3};
+use bytemuck::{CheckedBitPattern, NoUninit};
+use glam::{FloatExt, USizeVec3, Vec3};
+use serde::{Deserialize, Serialize};
 
 use crate::scene::Scene;
+use crate::spectrum::RGB_COEFF_N;
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit, CheckedBitPattern, Serialize, Deserialize,
+)]
 #[repr(C)]
 pub struct SpectrumId(u32);
 
@@ -46,6 +50,19 @@ impl SpectrumId {
 }
 
 impl Scene {
+    // Multiplies a spectrum by `factor` in place, which only some kinds of spectra can be. Returns
+    // whether it could be.
+    pub fn scale_spectrum(&mut self, spectrum: SpectrumId, factor: f32) -> bool {
+        let i = spectrum.idx();
+        match spectrum.ty() {
+            SpectrumType::Constant => self.constant_spectra[i].value *= factor,
+            SpectrumType::RgbIlluminant => self.rgb_illuminant_spectra[i].rgb *= factor,
+            SpectrumType::Blackbody => self.blackbody_spectra[i].scale *= factor,
+            _ => return false,
+        }
+        true
+    }
+
     pub fn spectrum_power(&self, spectrum: SpectrumId) -> f32 {
         match spectrum.ty() {
             SpectrumType::Table => self.table_spectra[spectrum.idx()].data.iter().sum(),
@@ -86,6 +103,81 @@ impl Scene {
         }
     }
 
+    // the color of spectra defined by one, as linear sRGB
+    pub fn spectrum_rgb(&self, spectrum: SpectrumId) -> Option<Vec3> {
+        match spectrum.ty() {
+            SpectrumType::Constant => {
+                Some(Vec3::splat(self.constant_spectra[spectrum.idx()].value))
+            }
+            SpectrumType::RgbAlbedo => Some(self.rgb_albedo_spectra[spectrum.idx()].rgb),
+            _ => None,
+        }
+    }
+
+    // CPU counterpart of `spectrum_sample` in spectrum.wgsl
+    pub fn spectrum_value(&self, spectrum: SpectrumId, wl: f32, rgb_coeffs: &[[f32; 4]]) -> f32 {
+        match spectrum.ty() {
+            SpectrumType::Table => table_value(&self.table_spectra[spectrum.idx()], wl),
+            SpectrumType::Constant => self.constant_spectra[spectrum.idx()].value,
+            SpectrumType::RgbAlbedo => {
+                rgb_albedo_value(self.rgb_albedo_spectra[spectrum.idx()].rgb, wl, rgb_coeffs)
+            }
+            SpectrumType::RgbIlluminant => {
+                let spectrum = &self.rgb_illuminant_spectra[spectrum.idx()];
+                let scale = spectrum.rgb.max_element() * 2.0;
+                if scale == 0.0 {
+                    return 0.0;
+                }
+                let illuminant = &self.table_spectra[spectrum.illuminant.idx()];
+                rgb_albedo_value(spectrum.rgb / scale, wl, rgb_coeffs)
+                    * table_value(illuminant, wl)
+                    * scale
+            }
+            SpectrumType::Blackbody => {
+                let bb = &self.blackbody_spectra[spectrum.idx()];
+                bb.scale * blackbody(wl, bb.temperature)
+            }
+            SpectrumType::PiecewiseLinear => {
+                let pwl = &self.piecewise_linear_spectra[spectrum.idx()];
+                let points: &[[f32; 2]] = bytemuck::cast_slice(
+                    &self.float_data[pwl.ptr as usize..pwl.ptr as usize + 2 * pwl.entries as usize],
+                );
+                let i = points.partition_point(|&[l, _]| l <= wl);
+                match i {
+                    0 => points[0][1],
+                    i if i == points.len() => points[i - 1][1],
+                    i => {
+                        let [l0, v0] = points[i - 1];
+                        let [l1, v1] = points[i];
+                        v0.lerp(v1, (wl - l0) / (l1 - l0))
+                    }
+                }
+            }
+        }
+    }
+
+    // Density for sampling wavelengths seen under this spectrum, proportional to it weighted by
+    // the CIE matching functions, at each 1 nm bin of the visible range. None for RGB spectra,
+    // which the default visible distribution already suits.
+    pub fn wavelength_density(&self, spectrum: SpectrumId) -> Option<Vec<f32>> {
+        if !matches!(
+            spectrum.ty(),
+            SpectrumType::Blackbody | SpectrumType::PiecewiseLinear | SpectrumType::Table
+        ) {
+            return None;
+        }
+        let density: Vec<f32> = (360..831)
+            .map(|wl| {
+                let i = wl - 360;
+                let matching: f32 = (0..3).map(|c| self.table_spectra[c].data[i]).sum();
+                let value = self.spectrum_value(spectrum, wl as f32 + 0.5, &[]);
+                value.max(0.0) * matching
+            })
+            .collect();
+        let total: f32 = density.iter().sum();
+        (total > 0.0).then(|| density.iter().map(|d| d / total).collect())
+    }
+
     pub fn add_table_spectrum(&mut self, spectrum: TableSpectrum) -> SpectrumId {
         let id = SpectrumId::new(SpectrumType::Table, self.table_spectra.len());
         self.table_spectra.push(spectrum);
@@ -154,46 +246,99 @@ impl Scene {
     }
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct TableSpectrum {
+    #[serde(with = "table_data")]
     pub data: [f32; 471],
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+// serde only implements arrays of up to 32 elements
+mod table_data {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer, const N: usize>(
+        data: &[f32; N],
+        s: S,
+    ) -> Result<S::Ok, S::Error> {
+        data.as_slice().serialize(s)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, const N: usize>(
+        d: D,
+    ) -> Result<[f32; N], D::Error> {
+        let data = Vec::<f32>::deserialize(d)?;
+        let len = data.len();
+        data.try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"a full spectrum table"))
+    }
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ConstantSpectrum {
     pub value: f32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct RgbAlbedoSpectrum {
     pub rgb: Vec3,
     pub _padding: u32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct RgbIlluminantSpectrum {
     pub rgb: Vec3,
     pub illuminant: SpectrumId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct BlackbodySpectrum {
     pub temperature: f32,
     pub scale: f32,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct PiecewiseLinearSpectrum {
     pub ptr: u32,
     pub entries: u32,
 }
 
+fn table_value(table: &TableSpectrum, wl: f32) -> f32 {
+    // tables are extended with their end values outside the visible range
+    table.data[(wl.clamp(360.0, 830.0) - 360.0) as usize]
+}
+
+fn rgb_albedo_value(rgb: Vec3, wl: f32, rgb_coeffs: &[[f32; 4]]) -> f32 {
+    let coeffs = rgb_to_coeffs(rgb, rgb_coeffs);
+    let l = ((wl - 360.0) / (831.0 - 360.0)).clamp(0.0, 1.0);
+    let poly = coeffs.x * l * l + coeffs.y * l + coeffs.z;
+    0.5 + poly / (2.0 * (1.0 + poly * poly).sqrt())
+}
+
+// trilinear lookup matching the linearly filtered, clamped texture the shaders sample
+fn rgb_to_coeffs(rgb: Vec3, rgb_coeffs: &[[f32; 4]]) -> Vec3 {
+    let n = RGB_COEFF_N as usize;
+    let texel = (rgb * n as f32 - 0.5).clamp(Vec3::ZERO, Vec3::splat(n as f32 - 1.0));
+    let base = texel.floor().as_usizevec3();
+    let t = texel - texel.floor();
+
+    let mut result = Vec3::ZERO;
+    for corner in 0..8 {
+        let offset = USizeVec3::new(corner & 1, corner >> 1 & 1, corner >> 2);
+        let p = (base + offset).min(USizeVec3::splat(n - 1));
+        let weight = Vec3::select(offset.cmpeq(USizeVec3::ZERO), 1.0 - t, t).element_product();
+        let [x, y, z, _] = rgb_coeffs[p.x + p.y * n + p.z * n * n];
+        result += weight * Vec3::new(x, y, z);
+    }
+    result
+}
+
 fn blackbody(lambda: f32, temperature: f32) -> f32 {
     const C: f32 = 299_792_458.0;
     const H: f32 = 6.62606957e-34;
@@ -0,0 +1,223 @@
+use std::fs::File;
+use std::io::ErrorKind;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+use bytemuck::NoUninit;
+use memmap2::MmapMut;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::scene::Scene;
+
+// A growable array which can be moved into a memory mapped temporary file, so that the OS can page
+// it out instead of holding it alongside the textures and the GPU copy. Elements added after that
+// go straight to the file.
+pub struct SpillVec<T> {
+    data: Vec<T>,
+    file: Option<SpillFile>,
+}
+
+struct SpillFile {
+    path: PathBuf,
+    file: File,
+    // none while the file is empty, since empty files can't be mapped
+    map: Option<MmapMut>,
+    len: usize,
+}
+
+impl<T: NoUninit> SpillVec<T> {
+    // the file grows by at least this many bytes at a time
+    const MIN_GROWTH: usize = 1 << 20;
+
+    pub fn is_spilled(&self) -> bool {
+        self.file.is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.data.clear();
+        if let Some(file) = &mut self.file {
+            file.len = 0;
+        }
+    }
+
+    pub fn reserve(&mut self, additional: usize) -> std::io::Result<()> {
+        let Some(file) = &mut self.file else {
+            self.data.reserve(additional);
+            return Ok(());
+        };
+        let capacity = file
+            .map
+            .as_ref()
+            .map_or(0, |map| map.len() / size_of::<T>());
+        let needed = file.len + additional;
+        if needed <= capacity {
+            return Ok(());
+        }
+        let capacity = needed
+            .max(capacity * 2)
+            .max(Self::MIN_GROWTH / size_of::<T>());
+        file.file.set_len((capacity * size_of::<T>()) as u64)?;
+        // the file is only ever touched through this mapping
+        file.map = Some(unsafe { MmapMut::map_mut(&file.file)? });
+        Ok(())
+    }
+
+    pub fn push(&mut self, value: T) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.data.push(value);
+            return Ok(());
+        }
+        self.reserve(1)?;
+        let file = self.file.as_mut().unwrap();
+        let offset = file.len * size_of::<T>();
+        let map = file.map.as_mut().unwrap();
+        map[offset..offset + size_of::<T>()].copy_from_slice(bytemuck::bytes_of(&value));
+        file.len += 1;
+        Ok(())
+    }
+
+    pub fn extend(&mut self, iter: impl IntoIterator<Item = T>) -> std::io::Result<()> {
+        if self.file.is_none() {
+            self.data.extend(iter);
+            return Ok(());
+        }
+        let mut iter = iter.into_iter();
+        self.reserve(iter.size_hint().0)?;
+        iter.try_for_each(|value| self.push(value))
+    }
+
+    pub fn extend_from_slice(&mut self, values: &[T]) -> std::io::Result<()> {
+        self.extend(values.iter().copied())
+    }
+
+    // Moves the elements to a temporary file named after `name`, if they aren't in one already
+    pub fn spill(&mut self, name: &str) -> std::io::Result<()> {
+        if self.is_spilled() {
+            return Ok(());
+        }
+        // never reuse an existing file, which could be a link planted by someone else
+        let mut attempt = 0;
+        let (path, file) = loop {
+            let path = std::env::temp_dir().join(format!(
+                "pbr-gpu-{}-{name}-{attempt}.geometry",
+                std::process::id()
+            ));
+            match File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => break (path, file),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => attempt += 1,
+                Err(e) => return Err(e),
+            }
+        };
+        self.file = Some(SpillFile {
+            path,
+            file,
+            map: None,
+            len: 0,
+        });
+        let data = std::mem::take(&mut self.data);
+        self.extend(data)
+    }
+}
+
+impl<T: NoUninit> Deref for SpillVec<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match &self.file {
+            None => &self.data,
+            Some(SpillFile { map: None, .. }) => &[],
+            // the mapping is page aligned and only holds values written by `push`
+            Some(SpillFile {
+                map: Some(map),
+                len,
+                ..
+            }) => unsafe { std::slice::from_raw_parts(map.as_ptr().cast(), *len) },
+        }
+    }
+}
+
+impl<T> Default for SpillVec<T> {
+    fn default() -> Self {
+        SpillVec {
+            data: vec![],
+            file: None,
+        }
+    }
+}
+
+// Cached scenes are loaded into memory and spilled again afterwards
+impl<T: NoUninit + Serialize> Serialize for SpillVec<T> {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(s)
+    }
+}
+
+impl<'de, T: Deserialize<'de>> Deserialize<'de> for SpillVec<T> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        Ok(SpillVec {
+            data: Vec::deserialize(d)?,
+            file: None,
+        })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        self.map = None;
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// Uploads data a chunk at a time, so that spilled geometry is paged in gradually instead of being
+// copied to a staging buffer all at once
+pub fn upload_in_chunks(
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    label: &str,
+    data: &[u8],
+) -> wgpu::Buffer {
+    // bytes uploaded at a time
+    const CHUNK_SIZE: usize = 16 << 20;
+
+    let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some(label),
+        // bindings can't be empty
+        size: (data.len() as u64).max(16),
+        usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    for (i, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        queue.write_buffer(&buffer, (i * CHUNK_SIZE) as u64, chunk);
+        // wait for the staging copy of each chunk to be released before making the next
+        queue.submit([]);
+        device.poll(wgpu::PollType::wait_indefinitely()).unwrap();
+    }
+    buffer
+}
+
+impl Scene {
+    // Keeps the triangles and their vertices in temporary files from now on. The loader does this
+    // before adding any shapes with --low-memory, so that meshes never have to fit in memory.
+    pub fn spill_geometry(&mut self) -> std::io::Result<()> {
+        self.triangles.spill("triangles")?;
+        self.triangle_vertices.spill("vertices")?;
+        self.packed_vertices.spill("packed-vertices")
+    }
+
+    // bytes of geometry kept in temporary files
+    pub fn spilled_size(&self) -> usize {
+        match self.triangles.is_spilled() {
+            true => {
+                size_of_val(&*self.triangles)
+                    + size_of_val(&*self.triangle_vertices)
+                    + size_of_val(&*self.packed_vertices)
+            }
+            false => 0,
+        }
+    }
+}
@@ -1,9 +1,19 @@
-use bytemuck::NoUninit;
-use glam::Vec2;
+use std::collections::HashMap;
+use std::path::PathBuf;
 
-use crate::scene::{Scene, SpectrumId};
+use bytemuck::{CheckedBitPattern, NoUninit};
+use glam::{Mat4, Vec2, Vec3};
+use image::{ImageBuffer, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit)]
+use crate::scene::{ImageData, Scene, SpectrumId, linear_to_srgb};
+
+// scale, mix and checkerboard nodes a texture network needs before `bake_textures` flattens it
+const BAKE_MIN_NODES: usize = 4;
+
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, NoUninit, CheckedBitPattern, Serialize, Deserialize,
+)]
 #[repr(C)]
 pub struct TextureId(u32);
 
@@ -11,17 +21,20 @@ pub struct TextureId(u32);
 #[repr(u32)]
 enum TextureType {
     Constant = 0 << TextureId::TAG_SHIFT,
+    Wireframe = 1 << TextureId::TAG_SHIFT,
     ImageFloat = 2 << TextureId::TAG_SHIFT,
     ImageRgb = 3 << TextureId::TAG_SHIFT,
     Scale = 4 << TextureId::TAG_SHIFT,
     Mix = 5 << TextureId::TAG_SHIFT,
     Checkerboard = 6 << TextureId::TAG_SHIFT,
     ConductorRefl = 7 << TextureId::TAG_SHIFT,
+    UvChecker = 8 << TextureId::TAG_SHIFT,
+    VertexColor = 9 << TextureId::TAG_SHIFT,
 }
 
 #[allow(unused)]
 impl TextureId {
-    const TAG_BITS: u32 = 3;
+    const TAG_BITS: u32 = 4;
     const TAG_SHIFT: u32 = 32 - Self::TAG_BITS;
     const IDX_MASK: u32 = (1 << Self::TAG_SHIFT) - 1;
     const TAG_MASK: u32 = !Self::IDX_MASK;
@@ -56,15 +69,16 @@ impl Scene {
         image: u32,
         scale: f32,
         invert: bool,
-        uv_map: UvMappingParams,
+        wrap: WrapMode,
+        mapping: TextureMapping,
     ) -> TextureId {
         let id = TextureId::new(TextureType::ImageRgb, self.image_rgb_tex.len());
         self.image_rgb_tex.push(ImageRgbTexture {
             image,
             scale,
             invert: invert as u32,
-            uv_map,
-            _padding: 0,
+            wrap,
+            mapping,
         });
         id
     }
@@ -74,19 +88,25 @@ impl Scene {
         image: u32,
         scale: f32,
         invert: bool,
-        uv_map: UvMappingParams,
+        wrap: WrapMode,
+        mapping: TextureMapping,
     ) -> TextureId {
         let id = TextureId::new(TextureType::ImageFloat, self.image_float_tex.len());
         self.image_float_tex.push(ImageFloatTexture {
             image,
             scale,
             invert: invert as u32,
-            uv_map,
-            _padding: 0,
+            wrap,
+            mapping,
         });
         id
     }
 
+    // color of the point cloud splat that was hit, white on other shapes
+    pub fn add_vertex_color_texture(&mut self) -> TextureId {
+        TextureId::new(TextureType::VertexColor, 0)
+    }
+
     pub fn add_scale_texture(&mut self, left: TextureId, right: TextureId) -> TextureId {
         let id = TextureId::new(TextureType::Scale, self.scale_tex.len());
         self.scale_tex.push(ScaleTexture { left, right });
@@ -108,11 +128,42 @@ impl Scene {
         &mut self,
         even: TextureId,
         odd: TextureId,
-        uv_map: UvMappingParams,
+        mapping: TextureMapping,
     ) -> TextureId {
         let id = TextureId::new(TextureType::Checkerboard, self.checkerboard_tex.len());
-        self.checkerboard_tex
-            .push(CheckerboardTexture { even, odd, uv_map });
+        self.checkerboard_tex.push(CheckerboardTexture {
+            even,
+            odd,
+            _padding: [0; 2],
+            mapping,
+        });
+        id
+    }
+
+    pub fn add_wireframe_texture(
+        &mut self,
+        wire: TextureId,
+        fill: TextureId,
+        width: f32,
+        pixel_width: f32,
+    ) -> TextureId {
+        let id = TextureId::new(TextureType::Wireframe, self.wireframe_tex.len());
+        self.wireframe_tex.push(WireframeTexture {
+            wire,
+            fill,
+            width,
+            pixel_width,
+        });
+        id
+    }
+
+    pub fn add_uv_checker_texture(&mut self, cells: f32, mapping: TextureMapping) -> TextureId {
+        let id = TextureId::new(TextureType::UvChecker, self.uv_checker_tex.len());
+        self.uv_checker_tex.push(UvCheckerTexture {
+            cells,
+            _padding: [0; 3],
+            mapping,
+        });
         id
     }
 
@@ -121,49 +172,330 @@ impl Scene {
         self.conductor_refl_tex.push(ConductorReflTexture { tex });
         id
     }
+
+    // Flattens the texture networks of materials with many nodes into an image each, evaluated
+    // over the unit square of uv space at `resolution` texels on a side, so shading does a single
+    // lookup instead of walking the network. Only networks of uv mapped images, checkerboards,
+    // and constant and RGB spectra which repeat outside of the unit square can be baked.
+    // Colors are combined in RGB rather than per wavelength. Returns the number of networks baked.
+    pub fn bake_textures(&mut self, resolution: u32) -> usize {
+        self.resolve_images();
+        let mut baked = HashMap::new();
+        let textures: Vec<TextureId> = self.material_textures().into_iter().map(|t| *t).collect();
+        for texture in textures {
+            if baked.contains_key(&texture) {
+                continue;
+            }
+            let Some((nodes, gray)) = self.bakeable(texture) else {
+                continue;
+            };
+            if nodes < BAKE_MIN_NODES {
+                continue;
+            }
+            let flat = self.bake_texture(texture, resolution, gray);
+            baked.insert(texture, flat);
+        }
+        for slot in self.material_textures() {
+            if let Some(&flat) = baked.get(slot) {
+                *slot = flat;
+            }
+        }
+        baked.len()
+    }
+
+    // the textures materials evaluate directly, including those inside conductor reflectances
+    fn material_textures(&mut self) -> Vec<&mut TextureId> {
+        let mut slots = vec![];
+        for m in &mut self.diffuse_mat {
+            slots.push(&mut m.texture);
+        }
+        for m in &mut self.diffuse_transmit_mat {
+            slots.extend([&mut m.reflectance, &mut m.transmittance, &mut m.scale]);
+        }
+        for m in &mut self.conductor_mat {
+            slots.extend([
+                &mut m.ior_re,
+                &mut m.ior_im,
+                &mut m.u_roughness,
+                &mut m.v_roughness,
+            ]);
+        }
+        for m in &mut self.dielectric_mat {
+            slots.extend([&mut m.u_roughness, &mut m.v_roughness]);
+        }
+        for m in &mut self.metallic_workflow_mat {
+            slots.extend([
+                &mut m.base_color,
+                &mut m.metallic,
+                &mut m.u_roughness,
+                &mut m.v_roughness,
+            ]);
+        }
+        for m in &mut self.mix_mat {
+            slots.push(&mut m.amount);
+        }
+        for m in &mut self.principled_mat {
+            slots.extend([
+                &mut m.base_color,
+                &mut m.metallic,
+                &mut m.roughness,
+                &mut m.specular_tint,
+                &mut m.clearcoat,
+                &mut m.clearcoat_roughness,
+                &mut m.sheen,
+                &mut m.transmission,
+                &mut m.eta,
+            ]);
+        }
+        for t in &mut self.conductor_refl_tex {
+            slots.push(&mut t.tex);
+        }
+        slots
+    }
+
+    // the number of scale, mix and checkerboard nodes in a network that can be baked, and whether
+    // its values are all gray
+    fn bakeable(&self, texture: TextureId) -> Option<(usize, bool)> {
+        let i = texture.idx();
+        // whether a uv mapping of a pattern which repeats `frequency` times per unit of the mapped
+        // coordinates repeats every 1 in uv like the baked image does
+        let repeats = |mapping: &TextureMapping, frequency: f32| {
+            let n = mapping.scale * frequency;
+            matches!(mapping.ty, MappingType::Uv)
+                && mapping.delta == Vec2::ZERO
+                && n == n.round()
+        };
+        let image = |image: u32, wrap: WrapMode| {
+            !matches!(self.images[image as usize], ImageData::Compressed(_))
+                && matches!(wrap, WrapMode::Repeat)
+        };
+        match texture.ty() {
+            TextureType::Constant => {
+                let rgb = self.spectrum_rgb(self.constant_tex[i].spectrum)?;
+                Some((0, rgb.min_element() == rgb.max_element()))
+            }
+            TextureType::ImageFloat => {
+                let tex = &self.image_float_tex[i];
+                (repeats(&tex.mapping, 1.0) && image(tex.image, tex.wrap)).then_some((0, true))
+            }
+            TextureType::ImageRgb => {
+                let tex = &self.image_rgb_tex[i];
+                (repeats(&tex.mapping, 1.0) && image(tex.image, tex.wrap)).then_some((0, false))
+            }
+            TextureType::UvChecker => {
+                // the colors repeat every 1 and the checks every other cell
+                let tex = &self.uv_checker_tex[i];
+                let checks = repeats(&tex.mapping, tex.cells / 2.0);
+                (repeats(&tex.mapping, 1.0) && checks).then_some((0, false))
+            }
+            TextureType::Scale => {
+                let tex = self.scale_tex[i];
+                let (a, a_gray) = self.bakeable(tex.left)?;
+                let (b, b_gray) = self.bakeable(tex.right)?;
+                Some((a + b + 1, a_gray && b_gray))
+            }
+            TextureType::Mix => {
+                let tex = self.mix_tex[i];
+                let (a, a_gray) = self.bakeable(tex.tex1)?;
+                let (b, b_gray) = self.bakeable(tex.tex2)?;
+                let (c, c_gray) = self.bakeable(tex.amount)?;
+                Some((a + b + c + 1, a_gray && b_gray && c_gray))
+            }
+            TextureType::Checkerboard => {
+                // odd scales put the opposite check at the same place in the next square
+                let tex = self.checkerboard_tex[i];
+                if !repeats(&tex.mapping, 0.5) {
+                    return None;
+                }
+                let (a, a_gray) = self.bakeable(tex.even)?;
+                let (b, b_gray) = self.bakeable(tex.odd)?;
+                Some((a + b + 1, a_gray && b_gray))
+            }
+            // depend on more than the uv coordinates, or on the wavelength
+            TextureType::Wireframe | TextureType::VertexColor | TextureType::ConductorRefl => None,
+        }
+    }
+
+    fn bake_texture(&mut self, texture: TextureId, resolution: u32, gray: bool) -> TextureId {
+        // the mean of a 2x2 grid of samples in each texel
+        let size = resolution as f32;
+        let texels = (0..resolution * resolution).map(|i| {
+            let (x, y) = ((i % resolution) as f32, (i / resolution) as f32);
+            let mut sum = Vec3::ZERO;
+            for dx in [0.25, 0.75] {
+                for dy in [0.25, 0.75] {
+                    let st = Vec2::new(x + dx, size - (y + dy)) / size;
+                    sum += self.evaluate_texture(texture, st);
+                }
+            }
+            sum / 4.0
+        });
+
+        let image = match gray {
+            true => ImageData::Float(
+                ImageBuffer::from_vec(resolution, resolution, texels.map(|c| c.x).collect())
+                    .unwrap(),
+            ),
+            false => {
+                let texels: Vec<Vec3> = texels.collect();
+                match texels.iter().all(|c| c.max_element() <= 1.0) {
+                    true => ImageData::Srgb(RgbaImage::from_fn(resolution, resolution, |x, y| {
+                        let c = texels[(y * resolution + x) as usize];
+                        let [r, g, b] = c
+                            .to_array()
+                            .map(|v| (linear_to_srgb(v.max(0.0)) * 255.0).round() as u8);
+                        Rgba([r, g, b, 255])
+                    })),
+                    false => {
+                        ImageData::FloatRgb(ImageBuffer::from_fn(resolution, resolution, |x, y| {
+                            Rgba(texels[(y * resolution + x) as usize].extend(1.0).to_array())
+                        }))
+                    }
+                }
+            }
+        };
+        let id = self.images.len() as u32;
+        self.images.push(image);
+        self.image_paths.push(PathBuf::from("(baked texture)"));
+
+        let mapping = TextureMapping {
+            world_to_texture: Mat4::IDENTITY,
+            v1: Vec3::X,
+            ty: MappingType::Uv,
+            v2: Vec3::Y,
+            _padding: 0,
+            scale: Vec2::ONE,
+            delta: Vec2::ZERO,
+        };
+        match gray {
+            true => self.add_float_image_texture(id, 1.0, false, WrapMode::Repeat, mapping),
+            false => self.add_rgb_image_texture(id, 1.0, false, WrapMode::Repeat, mapping),
+        }
+    }
+
+    // CPU counterpart of `texture_evaluate` in texture.wgsl, in RGB, for networks which are bakeable
+    fn evaluate_texture(&self, texture: TextureId, st: Vec2) -> Vec3 {
+        let i = texture.idx();
+        let map = |mapping: &TextureMapping| st * mapping.scale + mapping.delta;
+        match texture.ty() {
+            TextureType::Constant => self.spectrum_rgb(self.constant_tex[i].spectrum).unwrap(),
+            TextureType::ImageFloat => {
+                let tex = &self.image_float_tex[i];
+                let image = &self.images[tex.image as usize];
+                let value = image.sample(tex.wrap, map(&tex.mapping)).x * tex.scale;
+                Vec3::splat(match tex.invert != 0 {
+                    true => (1.0 - value).max(0.0),
+                    false => value,
+                })
+            }
+            TextureType::ImageRgb => {
+                let tex = &self.image_rgb_tex[i];
+                let image = &self.images[tex.image as usize];
+                let rgb = image.sample(tex.wrap, map(&tex.mapping)).truncate() * tex.scale;
+                match tex.invert != 0 {
+                    true => (1.0 - rgb).max(Vec3::ZERO),
+                    false => rgb,
+                }
+            }
+            TextureType::UvChecker => {
+                let tex = &self.uv_checker_tex[i];
+                let st = map(&tex.mapping);
+                let cell = (st * tex.cells).floor();
+                let odd = (cell.x + cell.y).rem_euclid(2.0) != 0.0;
+                let f = st - st.floor();
+                let rgb = Vec3::splat(0.1) + Vec3::splat(0.8) * f.extend(0.5);
+                rgb * if odd { 0.5 } else { 1.0 }
+            }
+            TextureType::Scale => {
+                let tex = self.scale_tex[i];
+                self.evaluate_texture(tex.left, st) * self.evaluate_texture(tex.right, st)
+            }
+            TextureType::Mix => {
+                let tex = self.mix_tex[i];
+                let a = self.evaluate_texture(tex.tex1, st);
+                let b = self.evaluate_texture(tex.tex2, st);
+                a + (b - a) * self.evaluate_texture(tex.amount, st)
+            }
+            TextureType::Checkerboard => {
+                let tex = self.checkerboard_tex[i];
+                let cell = map(&tex.mapping).floor();
+                match (cell.x + cell.y).rem_euclid(2.0) != 0.0 {
+                    true => self.evaluate_texture(tex.odd, st),
+                    false => self.evaluate_texture(tex.even, st),
+                }
+            }
+            TextureType::Wireframe | TextureType::VertexColor | TextureType::ConductorRefl => {
+                unreachable!()
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(u32)]
+pub enum WrapMode {
+    Repeat = 0,
+    Clamp = 1,
+    Black = 2,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
-#[repr(C, align(8))]
-pub struct UvMappingParams {
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(u32)]
+pub enum MappingType {
+    Uv = 0,
+    Spherical = 1,
+    Cylindrical = 2,
+    Planar = 3,
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C, align(16))]
+pub struct TextureMapping {
+    pub world_to_texture: Mat4,
+    // planar mapping axes
+    pub v1: Vec3,
+    pub ty: MappingType,
+    pub v2: Vec3,
+    pub _padding: u32,
+    // applied to the result of every mapping type
     pub scale: Vec2,
     pub delta: Vec2,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ConstantTexture {
     pub spectrum: SpectrumId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ImageRgbTexture {
     pub image: u32,
     pub scale: f32,
     pub invert: u32,
-    pub _padding: u32,
-    pub uv_map: UvMappingParams,
+    pub wrap: WrapMode,
+    pub mapping: TextureMapping,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ImageFloatTexture {
     pub image: u32,
     pub scale: f32,
     pub invert: u32,
-    pub _padding: u32,
-    pub uv_map: UvMappingParams,
+    pub wrap: WrapMode,
+    pub mapping: TextureMapping,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ScaleTexture {
     pub left: TextureId,
     pub right: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct MixTexture {
     pub tex1: TextureId,
@@ -171,16 +503,36 @@ pub struct MixTexture {
     pub amount: TextureId,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct CheckerboardTexture {
     pub even: TextureId,
     pub odd: TextureId,
-    pub uv_map: UvMappingParams,
+    pub _padding: [u32; 2],
+    pub mapping: TextureMapping,
 }
 
-#[derive(Copy, Clone, Debug, NoUninit)]
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
 #[repr(C)]
 pub struct ConductorReflTexture {
     pub tex: TextureId,
 }
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct WireframeTexture {
+    pub wire: TextureId,
+    pub fill: TextureId,
+    // line width in world units, or in multiples of the pixel footprint if that is wider
+    pub width: f32,
+    pub pixel_width: f32,
+}
+
+#[derive(Copy, Clone, Debug, NoUninit, CheckedBitPattern, Serialize, Deserialize)]
+#[repr(C)]
+pub struct UvCheckerTexture {
+    // checks per unit of the mapped coordinates
+    pub cells: f32,
+    pub _padding: [u32; 3],
+    pub mapping: TextureMapping,
+}
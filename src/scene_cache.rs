@@ -0,0 +1,240 @@
+use std::fs::File;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use anyhow::{Context, bail};
+use rayon::prelude::*;
+
+use crate::loader::diagnostics::{Diagnostics, Severity};
+use crate::loader::pbrt::LoadedScene;
+use crate::options::RenderOptions;
+use crate::scene::{BvhNode, Scene};
+use crate::spectrum::SpectrumData;
+use crate::write_atomic;
+
+const MAGIC: &[u8; 8] = b"PBRSCENE";
+const BVH_MAGIC: &[u8; 8] = b"PBRBVH\0\0";
+const VERSION: u32 = 4;
+
+// Fully built scenes kept in a directory so that later renders skip parsing, loading meshes and
+// building BVHs. Entries are named by a hash of the scene path, the loader settings and the
+// executable, and start with the content hash of every file the loader read; the entry is rebuilt
+// when any of them changes. The rest is the loaded scene serialized with bincode.
+pub struct SceneCache {
+    path: PathBuf,
+}
+
+impl SceneCache {
+    // `settings` describes everything besides the files which changes what the loader builds
+    pub fn new(dir: &Path, scene: &Path, settings: &str) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        let mut hasher = DefaultHasher::new();
+        VERSION.hash(&mut hasher);
+        scene
+            .canonicalize()
+            .unwrap_or_else(|_| scene.to_path_buf())
+            .hash(&mut hasher);
+        settings.hash(&mut hasher);
+        hash_executable(&mut hasher);
+        Ok(SceneCache {
+            path: dir.join(format!("{:016x}.scene", hasher.finish())),
+        })
+    }
+
+    // The cached scene, or none if there is no entry or it is out of date
+    pub fn load(&self, spectrum_data: &SpectrumData) -> Option<LoadedScene> {
+        let file = File::open(&self.path).ok()?;
+        let t = Instant::now();
+        match read(&mut BufReader::new(file), spectrum_data) {
+            Ok(Some(loaded)) => {
+                eprintln!("Load scene from cache in {:.3?}", t.elapsed());
+                loaded.diagnostics.print_summary();
+                Some(loaded)
+            }
+            Ok(None) => {
+                eprintln!("Scene files have changed since they were cached; rebuilding");
+                None
+            }
+            Err(e) => {
+                eprintln!(
+                    "Warning: ignoring scene cache {}: {e:#}",
+                    self.path.display()
+                );
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, loaded: &LoadedScene) -> anyhow::Result<()> {
+        // the files which failed might turn up, and the error should be reported again until then
+        if loaded.diagnostics.count(Severity::Error) > 0 {
+            return Ok(());
+        }
+        let t = Instant::now();
+        write_atomic(&self.path, |tmp| {
+            let mut w = BufWriter::new(File::create(tmp)?);
+            write(&mut w, loaded)?;
+            Ok(w.flush()?)
+        })
+        .with_context(|| format!("failed to write scene cache {}", self.path.display()))?;
+        eprintln!("Save scene cache in {:.3?}", t.elapsed());
+        Ok(())
+    }
+}
+
+// The BVHs of single meshes, kept in a directory so that editing the rest of the scene doesn't
+// rebuild the BVHs of its large meshes. Entries are named by the content hash of the mesh file,
+// the settings it was loaded with and the executable, and hold the nodes relative to the mesh's
+// first primitive as `Scene::relative_bvh` makes them.
+pub struct BvhCache {
+    dir: PathBuf,
+}
+
+impl BvhCache {
+    pub fn new(dir: &Path) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        Ok(BvhCache {
+            dir: dir.to_path_buf(),
+        })
+    }
+
+    // `settings` describes everything besides the file which changes the mesh's shapes
+    pub fn entry(&self, mesh: &Path, settings: &str) -> PathBuf {
+        let mut hasher = DefaultHasher::new();
+        VERSION.hash(&mut hasher);
+        hash_file(mesh).hash(&mut hasher);
+        settings.hash(&mut hasher);
+        hash_executable(&mut hasher);
+        self.dir.join(format!("{:016x}.bvh", hasher.finish()))
+    }
+
+    // The cached nodes of a BVH over `prims` primitives, or none if there is no entry for it.
+    // Fails if the entry can't be used.
+    pub fn load(&self, entry: &Path, prims: usize) -> anyhow::Result<Option<Vec<BvhNode>>> {
+        let Ok(file) = File::open(entry) else {
+            return Ok(None);
+        };
+        let read = |r: &mut BufReader<File>| -> anyhow::Result<Vec<BvhNode>> {
+            let mut magic = [0; 8];
+            r.read_exact(&mut magic)?;
+            if &magic != BVH_MAGIC {
+                bail!("not a BVH cache");
+            }
+            let version: u32 = bincode::deserialize_from(&mut *r)?;
+            if version != VERSION {
+                bail!("unsupported version {version}, expected {VERSION}");
+            }
+            let count: u64 = bincode::deserialize_from(&mut *r)?;
+            if count != prims as u64 {
+                bail!("BVH over {count} primitives, expected {prims}");
+            }
+            Ok(bincode::deserialize_from(r)?)
+        };
+        read(&mut BufReader::new(file))
+            .map(Some)
+            .with_context(|| format!("ignoring BVH cache {}", entry.display()))
+    }
+
+    pub fn save(&self, entry: &Path, nodes: &[BvhNode], prims: usize) -> anyhow::Result<()> {
+        write_atomic(entry, |tmp| {
+            let mut w = BufWriter::new(File::create(tmp)?);
+            w.write_all(BVH_MAGIC)?;
+            bincode::serialize_into(&mut w, &(VERSION, prims as u64, nodes))?;
+            Ok(w.flush()?)
+        })
+        .with_context(|| format!("failed to write BVH cache {}", entry.display()))
+    }
+}
+
+// A rebuilt loader may build something else from the same files
+fn hash_executable(hasher: &mut DefaultHasher) {
+    if let Ok(exe) = std::env::current_exe().and_then(std::fs::metadata) {
+        exe.len().hash(hasher);
+        exe.modified().ok().hash(hasher);
+    }
+}
+
+// Content hash of a file, or a hash for it not existing, so that creating it invalidates the entry
+fn hash_file(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let Ok(mut file) = File::open(path) else {
+        return hasher.finish();
+    };
+    hasher.write_u8(1);
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => hasher.write(&buf[..n]),
+            // unreadable files never match, so the entry is rebuilt
+            Err(_) => return !hasher.finish(),
+        }
+    }
+    hasher.finish()
+}
+
+fn write(w: &mut impl Write, loaded: &LoadedScene) -> anyhow::Result<()> {
+    w.write_all(MAGIC)?;
+    bincode::serialize_into(&mut *w, &VERSION)?;
+
+    let hashes: Vec<_> = loaded.inputs.par_iter().map(|p| hash_file(p)).collect();
+    bincode::serialize_into(&mut *w, &(&loaded.inputs, hashes))?;
+
+    let LoadedScene {
+        render_options,
+        scene,
+        diagnostics,
+        inputs: _,
+    } = loaded;
+    Ok(bincode::serialize_into(
+        w,
+        &(render_options, diagnostics, scene),
+    )?)
+}
+
+fn read(r: &mut impl Read, spectrum_data: &SpectrumData) -> anyhow::Result<Option<LoadedScene>> {
+    let mut magic = [0; 8];
+    r.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("not a scene cache");
+    }
+    let version: u32 = bincode::deserialize_from(&mut *r)?;
+    if version != VERSION {
+        bail!("unsupported version {version}, expected {VERSION}");
+    }
+
+    let (inputs, hashes): (Vec<PathBuf>, Vec<u64>) = bincode::deserialize_from(&mut *r)?;
+    let changed = inputs.len() != hashes.len()
+        || inputs
+            .par_iter()
+            .zip(&hashes)
+            .any(|(path, &hash)| hash_file(path) != hash);
+    if changed {
+        return Ok(None);
+    }
+
+    let (render_options, diagnostics, mut scene): (RenderOptions, Diagnostics, Scene) =
+        bincode::deserialize_from(r)?;
+    // the named builtin spectra come first in every scene
+    scene.named_spectra = Scene::new(spectrum_data).named_spectra;
+    if !scene.position_grids_valid() {
+        bail!("position grids out of range");
+    }
+    if scene
+        .image_cache
+        .values()
+        .any(|&id| id as usize >= scene.images.len())
+    {
+        bail!("image index out of range");
+    }
+    Ok(Some(LoadedScene {
+        render_options,
+        scene,
+        diagnostics,
+        inputs,
+    }))
+}
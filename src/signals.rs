@@ -0,0 +1,49 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::control::Command;
+
+static CANCEL: AtomicBool = AtomicBool::new(false);
+static PAUSE: AtomicBool = AtomicBool::new(false);
+static RESUME: AtomicBool = AtomicBool::new(false);
+
+// Headless control of a render: SIGINT or SIGTERM stops it and writes the output as `stop` does,
+// a second SIGINT kills it, and SIGUSR1 and SIGUSR2 pause and resume it
+#[cfg(unix)]
+pub fn install() {
+    extern "C" fn handle(signal: libc::c_int) {
+        match signal {
+            libc::SIGUSR1 => PAUSE.store(true, Ordering::Relaxed),
+            libc::SIGUSR2 => RESUME.store(true, Ordering::Relaxed),
+            _ => {
+                if CANCEL.swap(true, Ordering::Relaxed) && signal == libc::SIGINT {
+                    // both are async-signal-safe
+                    unsafe {
+                        libc::signal(libc::SIGINT, libc::SIG_DFL);
+                        libc::raise(libc::SIGINT);
+                    }
+                }
+            }
+        }
+    }
+
+    let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    for signal in [libc::SIGINT, libc::SIGTERM, libc::SIGUSR1, libc::SIGUSR2] {
+        unsafe {
+            libc::signal(signal, handler);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn install() {}
+
+// commands for the signals received since the last call
+pub fn pending() -> impl Iterator<Item = Command> {
+    [
+        (PAUSE.swap(false, Ordering::Relaxed), Command::Pause),
+        (RESUME.swap(false, Ordering::Relaxed), Command::Resume),
+        (CANCEL.load(Ordering::Relaxed), Command::Stop),
+    ]
+    .into_iter()
+    .filter_map(|(received, command)| received.then_some(command))
+}
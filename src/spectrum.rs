@@ -3,14 +3,22 @@ use std::error::Error;
 use std::io::Read;
 use std::path::Path;
 
-use glam::{DMat3, DVec3, FloatExt, Mat3, USizeVec3, Vec3};
+use anyhow::Context;
+use glam::{DMat3, DVec3, FloatExt, Mat3, USizeVec3, Vec3, Vec4, Vec4Swizzles};
 use ordered_float::OrderedFloat;
-use rayon::prelude::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+use rayon::prelude::{
+    IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator,
+};
 
 use crate::scene::TableSpectrum;
+use crate::write_atomic;
 
 pub const RGB_COEFF_N: u32 = 64;
 const RGB_COEFF_SIZE: usize = (RGB_COEFF_N as usize).pow(3);
+const RGB_CACHE_PATH: &str = ".rgbcache";
+// squared error in the reproduced color above which a coefficient has not converged
+const RGB_COEFF_TOLERANCE: f64 = 1e-3;
+const RGB_COEFF_ITERATIONS: u32 = 16;
 
 pub struct SpectrumData {
     pub cie_x: Box<TableSpectrum>,
@@ -82,8 +90,7 @@ pub fn load_data() -> Result<SpectrumData, Box<dyn Error>> {
     let data = annotate_path("spectrum/glass-SF11-SCHOTT.csv".as_ref(), load_csv::<1>)?;
     iors.insert("glass-F11", data.iter().map(|&(wl, [eta])| [wl, eta]).collect());
 
-    let rgb_cache_path = ".rgbcache";
-    let rgb_coeffs = std::fs::File::open(rgb_cache_path)
+    let rgb_coeffs = std::fs::File::open(RGB_CACHE_PATH)
         .and_then(|mut file| {
             let mut data = vec![[0.0; 4]; RGB_COEFF_SIZE];
             file.read_exact(bytemuck::cast_slice_mut(&mut data))?;
@@ -92,7 +99,7 @@ pub fn load_data() -> Result<SpectrumData, Box<dyn Error>> {
         .unwrap_or_else(|e| {
             println!("Could not load RGB coefficients ({e}), will recompute");
             let data = compute_rgb_coeffs(&cie_x, &cie_y, &cie_z, &d65);
-            if let Err(e) = std::fs::write(rgb_cache_path, bytemuck::cast_slice(&data)) {
+            if let Err(e) = std::fs::write(RGB_CACHE_PATH, bytemuck::cast_slice(&data)) {
                 println!("Failed to save RGB coefficients ({e})");
             }
             data
@@ -176,12 +183,7 @@ fn piecewise_to_densely_sampled<const N: usize>(
         .unwrap()
 }
 
-fn compute_rgb_coeffs(
-    x: &TableSpectrum,
-    y: &TableSpectrum,
-    z: &TableSpectrum,
-    white: &TableSpectrum,
-) -> Vec<[f32; 4]> {
+fn srgb_matching(x: &TableSpectrum, y: &TableSpectrum, z: &TableSpectrum) -> Vec<Vec3> {
     const SRGB_TO_XYZ_T: Mat3 = Mat3::from_cols_array_2d(&[
         [0.4124, 0.3576, 0.1805],
         [0.2126, 0.7152, 0.0722],
@@ -189,32 +191,135 @@ fn compute_rgb_coeffs(
     ]);
     let xyz_to_srgb = SRGB_TO_XYZ_T.transpose().inverse();
 
-    let srgb_matching = x
-        .data
+    x.data
         .iter()
         .zip(&y.data)
         .zip(&z.data)
         .map(|((&x, &y), &z)| xyz_to_srgb * Vec3::new(x, y, z))
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+// the color the coefficients of a table cell are solved for
+fn rgb_coeff_cell_color(i: usize) -> DVec3 {
+    let r = i % RGB_COEFF_N as usize;
+    let g = i / RGB_COEFF_N as usize % RGB_COEFF_N as usize;
+    let b = i / RGB_COEFF_N as usize / RGB_COEFF_N as usize;
+    (USizeVec3::new(r, g, b).as_dvec3() + 0.5) / RGB_COEFF_N as f64
+}
+
+fn compute_rgb_coeffs(
+    x: &TableSpectrum,
+    y: &TableSpectrum,
+    z: &TableSpectrum,
+    white: &TableSpectrum,
+) -> Vec<[f32; 4]> {
+    let srgb_matching = srgb_matching(x, y, z);
 
     let mut data = vec![];
     (0..RGB_COEFF_SIZE)
         .into_par_iter()
         .map(|i| {
-            let r = i % RGB_COEFF_N as usize;
-            let g = i / RGB_COEFF_N as usize % RGB_COEFF_N as usize;
-            let b = i / RGB_COEFF_N as usize / RGB_COEFF_N as usize;
-            compute_rgb_coefficient(
-                &srgb_matching,
-                white,
-                (USizeVec3::new(r, g, b).as_dvec3() + 0.5) / 64.0,
-            )
+            let rgb = rgb_coeff_cell_color(i);
+            let (coeffs, err) =
+                compute_rgb_coefficient(&srgb_matching, white, rgb, RGB_COEFF_ITERATIONS);
+            if err > RGB_COEFF_TOLERANCE {
+                let coeffs = Vec4::from_array(coeffs).xyz().as_dvec3();
+                println!(
+                    "{rgb} approximated as {}",
+                    compute_color(&srgb_matching, white, coeffs)
+                );
+            }
+            coeffs
         })
         .collect_into_vec(&mut data);
     data
 }
 
-fn compute_rgb_coefficient(matching: &[Vec3], white: &TableSpectrum, rgb: DVec3) -> [f32; 4] {
+#[derive(clap::Args)]
+pub struct RgbAuditOptions {
+    // squared error of the reproduced color above which a cell is reported
+    #[clap(long, default_value_t = RGB_COEFF_TOLERANCE)]
+    threshold: f64,
+    // re-solve the reported cells with this many iterations and update the cache
+    #[clap(long)]
+    fix: Option<u32>,
+}
+
+// Checks how well each cell of the RGB to spectrum coefficient table reproduces its color
+pub fn audit_rgb_coeffs(options: &RgbAuditOptions, data: &SpectrumData) -> anyhow::Result<()> {
+    let matching = srgb_matching(&data.cie_x, &data.cie_y, &data.cie_z);
+    let error = |i: usize, coeffs: [f32; 4]| {
+        let coeffs = Vec4::from_array(coeffs).xyz().as_dvec3();
+        let color = compute_color(&matching, &data.d65, coeffs);
+        rgb_coeff_cell_color(i).distance_squared(color)
+    };
+
+    let errors: Vec<f64> = (0..RGB_COEFF_SIZE)
+        .into_par_iter()
+        .map(|i| error(i, data.rgb_coeffs[i]))
+        .collect();
+    let mut bad: Vec<usize> = (0..RGB_COEFF_SIZE)
+        .filter(|&i| errors[i].is_nan() || errors[i] > options.threshold)
+        .collect();
+    bad.sort_by(|&a, &b| errors[b].total_cmp(&errors[a]));
+
+    let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+    println!("Checked {RGB_COEFF_SIZE} cells, mean squared error {mean:.3e}");
+    println!("{} cells above {:e}", bad.len(), options.threshold);
+    for &i in bad.iter().take(10) {
+        println!(
+            "  {:.4} has error {:.3e}",
+            rgb_coeff_cell_color(i),
+            errors[i]
+        );
+    }
+    if bad.len() > 10 {
+        println!("  ...");
+    }
+
+    let Some(iterations) = options.fix else {
+        return Ok(());
+    };
+    let fixed: Vec<_> = bad
+        .par_iter()
+        .map(|&i| {
+            let rgb = rgb_coeff_cell_color(i);
+            compute_rgb_coefficient(&matching, &data.d65, rgb, iterations)
+        })
+        .collect();
+
+    let mut rgb_coeffs = data.rgb_coeffs.clone();
+    let mut improved = 0;
+    let mut remaining = 0;
+    for (&i, &(coeffs, err)) in bad.iter().zip(&fixed) {
+        // the solver is deterministic, so only keep improvements
+        if err < errors[i] {
+            rgb_coeffs[i] = coeffs;
+            improved += 1;
+        }
+        if err.min(errors[i]) > options.threshold {
+            remaining += 1;
+        }
+    }
+    println!("Improved {improved} cells, {remaining} still above the threshold");
+
+    if improved > 0 {
+        write_atomic(RGB_CACHE_PATH.as_ref(), |tmp| {
+            Ok(std::fs::write(tmp, bytemuck::cast_slice(&rgb_coeffs))?)
+        })
+        .with_context(|| format!("failed to write {RGB_CACHE_PATH}"))?;
+        println!("Updated {RGB_CACHE_PATH}");
+    }
+    Ok(())
+}
+
+// Coefficients best reproducing `rgb` within the iteration limit, and their squared error
+fn compute_rgb_coefficient(
+    matching: &[Vec3],
+    white: &TableSpectrum,
+    rgb: DVec3,
+    iterations: u32,
+) -> ([f32; 4], f64) {
     let mut coeffs = DVec3::ZERO;
     let mut best = coeffs;
     let mut best_err = f64::INFINITY;
@@ -229,13 +334,7 @@ fn compute_rgb_coefficient(matching: &[Vec3], white: &TableSpectrum, rgb: DVec3)
             best = coeffs;
         }
 
-        if err < 1e-6 || i > 15 {
-            if best_err > 1e-3 {
-                println!(
-                    "{rgb} approximated as {}",
-                    compute_color(matching, white, best)
-                );
-            }
+        if err < 1e-6 || i >= iterations {
             break;
         }
 
@@ -266,7 +365,7 @@ fn compute_rgb_coefficient(matching: &[Vec3], white: &TableSpectrum, rgb: DVec3)
         }
     }
 
-    best.extend(0.0).as_vec4().to_array()
+    (best.extend(0.0).as_vec4().to_array(), best_err)
 }
 
 fn compute_color(matching: &[Vec3], white: &TableSpectrum, coeffs: DVec3) -> DVec3 {